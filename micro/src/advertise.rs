@@ -0,0 +1,77 @@
+use std::net::IpAddr;
+
+const DEFAULT_PUBLIC_IP_URL: &str = "http://api.ipify.org";
+
+/// Which host a service registers for the gateway to reach it on.
+#[derive(Debug, Clone, Default)]
+pub enum AdvertiseAddr {
+    // the address of the default route interface.
+    #[default]
+    LocalIp,
+    LocalIpv6,
+    // the address of a named interface, e.g. `eth1`; ipv4 first.
+    Interface(String),
+    // used as is, a hostname or a fixed ip.
+    Host(String),
+    // asked from the configured public_ip_url (default api.ipify.org), which
+    // answers with the caller's ip as plain text.
+    PublicIp,
+}
+
+impl AdvertiseAddr {
+    pub async fn resolve(&self) -> anyhow::Result<String> {
+        let ip = match self {
+            AdvertiseAddr::LocalIp => local_ip_address::local_ip()?,
+            AdvertiseAddr::LocalIpv6 => local_ip_address::local_ipv6()?,
+            AdvertiseAddr::Interface(name) => {
+                let ips = local_ip_address::list_afinet_netifas()?
+                    .into_iter()
+                    .filter(|(iface, _)| iface == name)
+                    .map(|(_, ip)| ip)
+                    .collect::<Vec<_>>();
+                match ips.iter().find(|ip| ip.is_ipv4()).or(ips.first()) {
+                    Some(ip) => *ip,
+                    None => anyhow::bail!("interface {} has no address", name),
+                }
+            }
+            AdvertiseAddr::Host(host) => return Ok(host.clone()),
+            AdvertiseAddr::PublicIp => public_ip().await?,
+        };
+        Ok(ip.to_string())
+    }
+}
+
+async fn public_ip() -> anyhow::Result<IpAddr> {
+    let url = crate::config::current()
+        .service
+        .public_ip_url
+        .clone()
+        .unwrap_or_else(|| DEFAULT_PUBLIC_IP_URL.into());
+
+    let res = hyper::Client::new().get(url.parse()?).await?;
+    let body = hyper::body::to_bytes(res.into_body()).await?;
+    Ok(std::str::from_utf8(&body)?.trim().parse()?)
+}
+
+// `host:port`, with ipv6 hosts in brackets.
+pub(crate) fn join_host_port(host: &str, port: u16) -> String {
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V6(ip)) => format!("[{}]:{}", ip, port),
+        _ => format!("{}:{}", host, port),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn host_is_used_as_is() {
+        let host = AdvertiseAddr::Host("api.internal".into());
+        assert_eq!(host.resolve().await.unwrap(), "api.internal");
+
+        assert_eq!(join_host_port("api.internal", 80), "api.internal:80");
+        assert_eq!(join_host_port("10.0.0.1", 80), "10.0.0.1:80");
+        assert_eq!(join_host_port("fe80::1", 80), "[fe80::1]:80");
+    }
+}
@@ -0,0 +1,552 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hyper::body::Bytes;
+use hyper::header::{CONTENT_TYPE, LOCATION};
+use hyper::{Body, Request, Response, StatusCode};
+use net::{Phase, Shutdown};
+use once_cell::sync::Lazy;
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::{self, CertifiedKey};
+use tokio_rustls::rustls::{self, Certificate, PrivateKey};
+use tokio_rustls::TlsAcceptor;
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+use super::tls::ACME_TLS_ALPN;
+use crate::config::AcmeConfig;
+
+const HTTP_CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
+const BAD_NONCE: &str = "urn:ietf:params:acme:error:badNonce";
+
+// how often a pending authorization or order is checked, and how many times.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const POLL_ATTEMPTS: usize = 60;
+
+// how soon a failed issuance is tried again.
+const RETRY: Duration = Duration::from_secs(10 * 60);
+// how often the expiry of the certificate is looked at.
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 3600);
+
+// key authorizations of the pending http-01 challenges, by token.
+static HTTP_CHALLENGES: Lazy<RwLock<HashMap<String, String>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
+fn b64(bytes: &[u8]) -> String {
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+// the certificate the tls listener presents, swapped in when renewed, and
+// the ones answering tls-alpn-01 challenges, by domain.
+#[derive(Default)]
+struct Certs {
+    current: RwLock<Option<Arc<CertifiedKey>>>,
+    challenges: RwLock<HashMap<String, Arc<CertifiedKey>>>,
+}
+
+impl ResolvesServerCert for Certs {
+    fn resolve(&self, hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let challenge = hello
+            .alpn()
+            .is_some_and(|mut protocols| protocols.any(|p| p == ACME_TLS_ALPN));
+        match challenge {
+            true => {
+                let domain = hello.server_name()?;
+                self.challenges.read().unwrap().get(domain).cloned()
+            }
+            false => self.current.read().unwrap().clone(),
+        }
+    }
+}
+
+fn certified_key(chain: Vec<Vec<u8>>, key: Vec<u8>) -> anyhow::Result<CertifiedKey> {
+    let key = sign::any_supported_type(&PrivateKey(key))?;
+    Ok(CertifiedKey::new(
+        chain.into_iter().map(Certificate).collect(),
+        key,
+    ))
+}
+
+// unix seconds the leaf of `key` expires at, when it names every domain.
+fn expiry(key: &CertifiedKey, domains: &[String]) -> Option<i64> {
+    let (_, leaf) = X509Certificate::from_der(&key.cert.first()?.0).ok()?;
+    let san = leaf.subject_alternative_name().ok()??;
+    let names = san
+        .value
+        .general_names
+        .iter()
+        .filter_map(|name| match name {
+            GeneralName::DNSName(name) => Some(*name),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    match domains.iter().all(|d| names.contains(&d.as_str())) {
+        true => Some(leaf.validity().not_after.timestamp()),
+        false => None,
+    }
+}
+
+// the certificate kept in the cache from the last run.
+fn load(cache_dir: &Path) -> anyhow::Result<CertifiedKey> {
+    let chain = rustls_pemfile::certs(&mut std::fs::read(cache_dir.join("cert.pem"))?.as_slice())?;
+    let key = rustls_pemfile::pkcs8_private_keys(
+        &mut std::fs::read(cache_dir.join("key.pem"))?.as_slice(),
+    )?;
+    let key = key
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("no private key"))?;
+    certified_key(chain, key)
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+#[derive(Deserialize)]
+struct Order {
+    status: String,
+    #[serde(default)]
+    authorizations: Vec<String>,
+    finalize: String,
+    certificate: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Authorization {
+    status: String,
+    identifier: Identifier,
+    challenges: Vec<Challenge>,
+}
+
+#[derive(Deserialize)]
+struct Identifier {
+    value: String,
+}
+
+#[derive(Deserialize)]
+struct Challenge {
+    #[serde(rename = "type")]
+    kind: String,
+    url: String,
+    #[serde(default)]
+    token: String,
+}
+
+struct Reply {
+    location: Option<String>,
+    body: Bytes,
+}
+
+// an account of an ACME CA, talking RFC 8555 with requests signed by its
+// key.
+struct Account {
+    directory: Directory,
+    key: EcdsaKeyPair,
+    rng: SystemRandom,
+    // the account url, once registered.
+    kid: Option<String>,
+    nonce: Option<String>,
+}
+
+// the response of `req`, an error unless it succeeded.
+async fn send(req: Request<Body>) -> anyhow::Result<(Response<()>, Bytes)> {
+    let url = req.uri().to_string();
+    let res = net::get_tls_proxy_client(None)
+        .client()
+        .request(req)
+        .await?;
+    let (parts, body) = res.into_parts();
+    let body = hyper::body::to_bytes(body).await?;
+    let bad_nonce = parts.status == StatusCode::BAD_REQUEST
+        && String::from_utf8_lossy(&body).contains(BAD_NONCE);
+    if bad_nonce {
+        return Err(BadNonce(replay_nonce(&Response::from_parts(parts, ()))).into());
+    }
+    if !parts.status.is_success() {
+        anyhow::bail!(
+            "{} answered {}: {}",
+            url,
+            parts.status,
+            String::from_utf8_lossy(&body)
+        );
+    }
+    Ok((Response::from_parts(parts, ()), body))
+}
+
+// a nonce the CA no longer accepts, with the fresh one it sent.
+#[derive(Debug, thiserror::Error)]
+#[error("acme nonce rejected")]
+struct BadNonce(Option<String>);
+
+fn replay_nonce(res: &Response<()>) -> Option<String> {
+    let nonce = res.headers().get("replay-nonce")?.to_str().ok()?;
+    Some(nonce.to_string())
+}
+
+impl Account {
+    // registers the key kept in `cache_dir`, a new one on the first run;
+    // registering a known key again returns its account.
+    async fn new(config: &AcmeConfig) -> anyhow::Result<Self> {
+        let (_, body) = send(Request::get(&config.directory).body(Body::empty())?).await?;
+        let directory: Directory = serde_json::from_slice(&body)?;
+
+        let rng = SystemRandom::new();
+        let path = Path::new(&config.cache_dir).join("account.key");
+        let pkcs8 = match std::fs::read(&path) {
+            Ok(pkcs8) => pkcs8,
+            Err(_) => {
+                let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng)
+                    .map_err(|_| anyhow::anyhow!("generate account key failed"))?;
+                std::fs::create_dir_all(&config.cache_dir)?;
+                std::fs::write(&path, pkcs8.as_ref())?;
+                pkcs8.as_ref().to_vec()
+            }
+        };
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &pkcs8, &rng)
+            .map_err(|e| anyhow::anyhow!("invalid account key {}: {}", path.display(), e))?;
+
+        let mut account = Self {
+            directory,
+            key,
+            rng,
+            kid: None,
+            nonce: None,
+        };
+        let new_account = account.directory.new_account.clone();
+        let payload = json!({ "termsOfServiceAgreed": true, "contact": config.contact });
+        let reply = account.post(&new_account, Some(payload)).await?;
+        account.kid = Some(
+            reply
+                .location
+                .ok_or_else(|| anyhow::anyhow!("no account url"))?,
+        );
+        Ok(account)
+    }
+
+    fn jwk(&self) -> Value {
+        // uncompressed point: 0x04, x, y.
+        let point = self.key.public_key().as_ref();
+        json!({
+            "crv": "P-256",
+            "kty": "EC",
+            "x": b64(&point[1..33]),
+            "y": b64(&point[33..65]),
+        })
+    }
+
+    // RFC 7638, the members in lexicographic order.
+    fn thumbprint(&self) -> String {
+        let point = self.key.public_key().as_ref();
+        let jwk = format!(
+            r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#,
+            b64(&point[1..33]),
+            b64(&point[33..65])
+        );
+        b64(ring::digest::digest(&ring::digest::SHA256, jwk.as_bytes()).as_ref())
+    }
+
+    fn key_authorization(&self, token: &str) -> String {
+        format!("{}.{}", token, self.thumbprint())
+    }
+
+    async fn nonce(&mut self) -> anyhow::Result<String> {
+        if let Some(nonce) = self.nonce.take() {
+            return Ok(nonce);
+        }
+        let req = Request::head(&self.directory.new_nonce).body(Body::empty())?;
+        let (res, _) = send(req).await?;
+        replay_nonce(&res).ok_or_else(|| anyhow::anyhow!("no replay-nonce"))
+    }
+
+    fn jws(&self, url: &str, nonce: &str, payload: Option<&Value>) -> anyhow::Result<Value> {
+        let mut protected = json!({ "alg": "ES256", "nonce": nonce, "url": url });
+        match &self.kid {
+            Some(kid) => protected["kid"] = json!(kid),
+            None => protected["jwk"] = self.jwk(),
+        }
+        let protected = b64(protected.to_string().as_bytes());
+        // empty for a POST-as-GET.
+        let payload = payload.map_or(String::new(), |p| b64(p.to_string().as_bytes()));
+        let signature = self
+            .key
+            .sign(&self.rng, format!("{}.{}", protected, payload).as_bytes())
+            .map_err(|_| anyhow::anyhow!("sign acme request failed"))?;
+        Ok(json!({
+            "protected": protected,
+            "payload": payload,
+            "signature": b64(signature.as_ref()),
+        }))
+    }
+
+    async fn post(&mut self, url: &str, payload: Option<Value>) -> anyhow::Result<Reply> {
+        let mut retried = false;
+        let (res, body) = loop {
+            let nonce = self.nonce().await?;
+            let body = self.jws(url, &nonce, payload.as_ref())?;
+            let req = Request::post(url)
+                .header(CONTENT_TYPE, "application/jose+json")
+                .body(Body::from(body.to_string()))?;
+            match send(req).await {
+                Ok(sent) => break sent,
+                // once more with the nonce it came with.
+                Err(e) if !retried && e.is::<BadNonce>() => {
+                    self.nonce = e.downcast::<BadNonce>()?.0;
+                    retried = true;
+                }
+                Err(e) => return Err(e),
+            }
+        };
+        self.nonce = replay_nonce(&res);
+        let location = res
+            .headers()
+            .get(LOCATION)
+            .and_then(|l| l.to_str().ok())
+            .map(str::to_string);
+        Ok(Reply { location, body })
+    }
+
+    async fn get<T: DeserializeOwned>(&mut self, url: &str) -> anyhow::Result<T> {
+        Ok(serde_json::from_slice(&self.post(url, None).await?.body)?)
+    }
+
+    // answers the challenge of `authz` of the kind configured, and waits
+    // until the CA has validated it.
+    async fn authorize(
+        &mut self,
+        url: &str,
+        config: &AcmeConfig,
+        certs: &Certs,
+    ) -> anyhow::Result<()> {
+        let authz: Authorization = self.get(url).await?;
+        if authz.status == "valid" {
+            return Ok(());
+        }
+        let domain = authz.identifier.value;
+        let challenge = authz
+            .challenges
+            .into_iter()
+            .find(|c| c.kind == config.challenge)
+            .ok_or_else(|| {
+                anyhow::anyhow!("{} offers no {} challenge", domain, config.challenge)
+            })?;
+
+        let key_authorization = self.key_authorization(&challenge.token);
+        match config.challenge.as_str() {
+            "http-01" => {
+                let mut challenges = HTTP_CHALLENGES.write().unwrap();
+                challenges.insert(challenge.token.clone(), key_authorization);
+            }
+            _ => {
+                let cert = alpn_challenge_cert(&domain, &key_authorization)?;
+                let mut challenges = certs.challenges.write().unwrap();
+                challenges.insert(domain.clone(), Arc::new(cert));
+            }
+        }
+
+        // ready to be validated.
+        self.post(&challenge.url, Some(json!({}))).await?;
+        let validated = async {
+            for _ in 0..POLL_ATTEMPTS {
+                tokio::time::sleep(POLL_INTERVAL).await;
+                let authz: Authorization = self.get(url).await?;
+                match authz.status.as_str() {
+                    "valid" => return Ok(()),
+                    "pending" => continue,
+                    status => anyhow::bail!("authorization of {} is {}", domain, status),
+                }
+            }
+            anyhow::bail!("authorization of {} still pending", domain)
+        }
+        .await;
+
+        HTTP_CHALLENGES.write().unwrap().remove(&challenge.token);
+        certs.challenges.write().unwrap().remove(&domain);
+        validated
+    }
+
+    // a new certificate for the domains of `config`, kept in its cache.
+    async fn issue(&mut self, config: &AcmeConfig, certs: &Certs) -> anyhow::Result<CertifiedKey> {
+        let identifiers = config
+            .domains
+            .iter()
+            .map(|d| json!({ "type": "dns", "value": d }))
+            .collect::<Vec<_>>();
+        let new_order = self.directory.new_order.clone();
+        let reply = self
+            .post(&new_order, Some(json!({ "identifiers": identifiers })))
+            .await?;
+        let order_url = reply
+            .location
+            .ok_or_else(|| anyhow::anyhow!("no order url"))?;
+        let order: Order = serde_json::from_slice(&reply.body)?;
+
+        for authz in &order.authorizations {
+            self.authorize(authz, config, certs).await?;
+        }
+
+        let mut params = rcgen::CertificateParams::new(config.domains.clone());
+        params.distinguished_name = rcgen::DistinguishedName::new();
+        let key = rcgen::Certificate::from_params(params)?;
+        let csr = key.serialize_request_der()?;
+        self.post(&order.finalize, Some(json!({ "csr": b64(&csr) })))
+            .await?;
+
+        let mut certificate = None;
+        for _ in 0..POLL_ATTEMPTS {
+            let order: Order = self.get(&order_url).await?;
+            match order.status.as_str() {
+                "valid" => {
+                    certificate = order.certificate;
+                    break;
+                }
+                "processing" | "ready" | "pending" => tokio::time::sleep(POLL_INTERVAL).await,
+                status => anyhow::bail!("order is {}", status),
+            }
+        }
+        let url = certificate.ok_or_else(|| anyhow::anyhow!("order not issued in time"))?;
+        let pem = self.post(&url, None).await?.body;
+
+        let chain = rustls_pemfile::certs(&mut pem.as_ref())?;
+        let cert = certified_key(chain, key.serialize_private_key_der())?;
+        let cache_dir = Path::new(&config.cache_dir);
+        std::fs::create_dir_all(cache_dir)?;
+        std::fs::write(cache_dir.join("cert.pem"), &pem)?;
+        std::fs::write(cache_dir.join("key.pem"), key.serialize_private_key_pem())?;
+        Ok(cert)
+    }
+}
+
+// RFC 8737, a self signed certificate for `domain` carrying the digest of
+// the key authorization.
+fn alpn_challenge_cert(domain: &str, key_authorization: &str) -> anyhow::Result<CertifiedKey> {
+    let digest = ring::digest::digest(&ring::digest::SHA256, key_authorization.as_bytes());
+    let mut params = rcgen::CertificateParams::new(vec![domain.to_string()]);
+    params.custom_extensions = vec![rcgen::CustomExtension::new_acme_identifier(digest.as_ref())];
+    let cert = rcgen::Certificate::from_params(params)?;
+    certified_key(
+        vec![cert.serialize_der()?],
+        cert.serialize_private_key_der(),
+    )
+}
+
+// issues a certificate unless the current one names every domain and is
+// valid for longer than `renew_before_days`; how long until the next look.
+async fn renew_if_due(config: &AcmeConfig, certs: &Certs) -> anyhow::Result<Duration> {
+    let expires = certs
+        .current
+        .read()
+        .unwrap()
+        .as_ref()
+        .and_then(|c| expiry(c, &config.domains));
+    if let Some(expires) = expires {
+        let renew_at = expires - i64::from(config.renew_before_days) * 86400;
+        let left = renew_at - chrono::Utc::now().timestamp();
+        if left > 0 {
+            return Ok(Duration::from_secs(left as u64).min(CHECK_INTERVAL));
+        }
+    }
+
+    tracing::info!(domains = ?config.domains, "requesting acme certificate");
+    let mut account = Account::new(config).await?;
+    let cert = account.issue(config, certs).await?;
+    *certs.current.write().unwrap() = Some(Arc::new(cert));
+    tracing::info!(domains = ?config.domains, "acme certificate installed");
+    Ok(CHECK_INTERVAL)
+}
+
+// the tls listener presents the certificate of `config`, the cached one at
+// first; it is issued and renewed in the background until `shutdown`.
+pub(super) fn acceptor(config: &AcmeConfig, shutdown: &Shutdown) -> TlsAcceptor {
+    let certs = Arc::new(Certs::default());
+    match load(Path::new(&config.cache_dir)) {
+        Ok(cert) => *certs.current.write().unwrap() = Some(Arc::new(cert)),
+        Err(e) => tracing::info!(error = %e, "no cached acme certificate"),
+    }
+
+    let (config, resolver, shutdown) = (config.clone(), certs.clone(), shutdown.clone());
+    tokio::spawn(async move {
+        loop {
+            let wait = match renew_if_due(&config, &resolver).await {
+                Ok(wait) => wait,
+                Err(e) => {
+                    tracing::error!(error = %format!("{:#}", e), "acme certificate request failed");
+                    RETRY
+                }
+            };
+            tokio::select! {
+                _ = tokio::time::sleep(wait) => {}
+                _ = shutdown.reached(Phase::StopAccepting) => return,
+            }
+        }
+    });
+
+    let mut server = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_cert_resolver(certs);
+    server.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec(), ACME_TLS_ALPN.to_vec()];
+    TlsAcceptor::from(Arc::new(server))
+}
+
+// the answer to an http-01 challenge, None for the other requests.
+pub(super) fn challenge(req: &Request<Body>) -> Option<Response<Body>> {
+    let token = req.uri().path().strip_prefix(HTTP_CHALLENGE_PATH)?;
+    let mut res = Response::new(Body::empty());
+    match HTTP_CHALLENGES.read().unwrap().get(token) {
+        Some(key_authorization) => *res.body_mut() = Body::from(key_authorization.clone()),
+        None => *res.status_mut() = StatusCode::NOT_FOUND,
+    }
+    Some(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn challenge_certificates_carry_the_key_authorization() {
+        let cert = alpn_challenge_cert("example.org", "token.thumbprint").unwrap();
+        let (_, leaf) = X509Certificate::from_der(&cert.cert[0].0).unwrap();
+        // id-pe-acmeIdentifier, critical.
+        let ext = leaf
+            .extensions()
+            .iter()
+            .find(|e| e.oid.to_id_string() == "1.3.6.1.5.5.7.1.31")
+            .unwrap();
+        assert!(ext.critical);
+        let digest = ring::digest::digest(&ring::digest::SHA256, b"token.thumbprint");
+        assert!(ext.value.ends_with(digest.as_ref()));
+
+        let domains = vec!["example.org".to_string()];
+        assert!(expiry(&cert, &domains).is_some());
+        assert!(expiry(&cert, &["www.example.org".to_string()]).is_none());
+    }
+
+    #[test]
+    fn answers_http_challenges() {
+        HTTP_CHALLENGES
+            .write()
+            .unwrap()
+            .insert("abc".to_string(), "abc.key".to_string());
+        let req = |path: &str| Request::get(path).body(Body::empty()).unwrap();
+        let known = challenge(&req("/.well-known/acme-challenge/abc")).unwrap();
+        assert_eq!(known.status(), StatusCode::OK);
+        let unknown = challenge(&req("/.well-known/acme-challenge/xyz")).unwrap();
+        assert_eq!(unknown.status(), StatusCode::NOT_FOUND);
+        // the others are redirected.
+        assert!(challenge(&req("/t/ums/users?page=2")).is_none());
+    }
+}
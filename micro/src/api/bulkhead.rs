@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::StreamExt;
+use hyper::{Body, Response};
+use once_cell::sync::Lazy;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::{config, MetricsRegistry};
+
+// the limit a semaphore was made for and the semaphore.
+type Slots = (usize, Arc<Semaphore>);
+
+// by service. Only services the registry or a route resolved get one, so
+// the map is as bounded as they are.
+static SLOTS: Lazy<Mutex<HashMap<String, Slots>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+// a slot of `service`, None when it has no limit. Err once its bulkhead
+// stayed full for `max_wait_ms`.
+pub(super) async fn acquire(service: &str) -> Result<Option<OwnedSemaphorePermit>, ()> {
+    let config = config::current();
+    let wait = Duration::from_millis(config.gateway.bulkhead.max_wait_ms);
+    acquire_with(service, config.max_concurrent(service), wait).await
+}
+
+async fn acquire_with(
+    service: &str,
+    max: usize,
+    wait: Duration,
+) -> Result<Option<OwnedSemaphorePermit>, ()> {
+    let slots = {
+        let mut slots = SLOTS.lock().unwrap();
+        if max == 0 {
+            slots.remove(service);
+            return Ok(None);
+        }
+        // a changed limit starts over, the permits out there release into
+        // the old semaphore.
+        match slots.get(service) {
+            Some((size, slots)) if *size == max => slots.clone(),
+            _ => {
+                let fresh = Arc::new(Semaphore::new(max));
+                slots.insert(service.to_string(), (max, fresh.clone()));
+                fresh
+            }
+        }
+    };
+    let permit = match slots.clone().try_acquire_owned() {
+        Ok(permit) => Some(permit),
+        Err(_) => tokio::time::timeout(wait, slots.acquire_owned())
+            .await
+            .ok()
+            .and_then(Result::ok),
+    };
+
+    match permit {
+        Some(permit) => Ok(Some(permit)),
+        None => {
+            MetricsRegistry::global()
+                .counter(
+                    "crossgate_gateway_bulkhead_rejected_total",
+                    &[("service", service)],
+                )
+                .inc();
+            tracing::warn!(service, max, "bulkhead full, request rejected");
+            Err(())
+        }
+    }
+}
+
+// keeps `guard`, e.g. a permit, until the body of `res` is sent, the
+// connection upstream is in use until then.
+pub(super) fn hold<G: Send + 'static>(res: Response<Body>, guard: Option<G>) -> Response<Body> {
+    let guard = match guard {
+        Some(guard) => guard,
+        None => return res,
+    };
+    let (parts, body) = res.into_parts();
+    // grpc answers end with trailers, relayed as they are.
+    if super::channel::is_grpc(&parts.headers) {
+        return Response::from_parts(parts, super::channel::relay(body, guard));
+    }
+    let body = body.map(move |chunk| {
+        let _ = &guard;
+        chunk
+    });
+    Response::from_parts(parts, Body::wrap_stream(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn rejects_once_full() {
+        let wait = Duration::from_millis(10);
+        let first = acquire_with("/t/full", 1, wait).await.unwrap();
+        assert!(first.is_some());
+        assert!(acquire_with("/t/full", 1, wait).await.is_err());
+
+        drop(first);
+        assert!(acquire_with("/t/full", 1, wait).await.unwrap().is_some());
+        assert!(acquire_with("/t/full", 0, wait).await.unwrap().is_none());
+        assert!(!SLOTS.lock().unwrap().contains_key("/t/full"));
+    }
+
+    #[tokio::test]
+    async fn follows_a_changed_limit() {
+        let wait = Duration::from_millis(10);
+        let _first = acquire_with("/t/resized", 1, wait).await.unwrap();
+        assert!(acquire_with("/t/resized", 1, wait).await.is_err());
+        let _second = acquire_with("/t/resized", 2, wait).await.unwrap();
+        assert_eq!(SLOTS.lock().unwrap()["/t/resized"].0, 2);
+    }
+
+    #[tokio::test]
+    async fn released_with_the_body() {
+        let wait = Duration::from_millis(10);
+        let permit = acquire_with("/t/body", 1, wait).await.unwrap();
+        let res = hold(Response::new(Body::from("done")), permit);
+        assert!(acquire_with("/t/body", 1, wait).await.is_err());
+
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(&body[..], b"done");
+        assert!(acquire_with("/t/body", 1, wait).await.unwrap().is_some());
+    }
+}
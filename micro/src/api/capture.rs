@@ -0,0 +1,222 @@
+use std::collections::VecDeque;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use futures::StreamExt;
+use hyper::body::HttpBody;
+use hyper::header::{
+    HeaderMap, HeaderName, AUTHORIZATION, COOKIE, PROXY_AUTHORIZATION, SET_COOKIE,
+};
+use hyper::{Body, Request, Response};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+use crate::config::{self, CaptureConfig};
+
+/// A request the gateway captured and what it answered, see
+/// `gateway.capture`. Bodies hold their first `max_body_bytes`, read as
+/// utf-8, and fill in while they are streamed.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Capture {
+    pub request_id: String,
+    // unix millis the request arrived at.
+    pub time: u64,
+    pub client_ip: String,
+    pub method: String,
+    pub uri: String,
+    pub version: String,
+    pub request_headers: Vec<(String, String)>,
+    pub request_body: String,
+    // unset until the response is there.
+    pub status: Option<u16>,
+    pub duration_ms: Option<u64>,
+    pub response_headers: Vec<(String, String)>,
+    pub response_body: String,
+    // whether a body was longer than what was kept.
+    pub truncated: bool,
+}
+
+type Shared = Arc<Mutex<Capture>>;
+
+const REDACTED: &str = "[redacted]";
+
+static CAPTURES: Lazy<Mutex<VecDeque<Shared>>> = Lazy::new(|| Mutex::new(VecDeque::new()));
+
+/// The requests captured so far, the latest first. They hold the headers
+/// and bodies as sent, but for the values of credential headers.
+pub fn captures() -> Vec<Capture> {
+    CAPTURES
+        .lock()
+        .unwrap()
+        .iter()
+        .rev()
+        .map(|c| c.lock().unwrap().clone())
+        .collect()
+}
+
+fn selected(config: &CaptureConfig, req: &Request<Body>) -> bool {
+    config
+        .paths
+        .iter()
+        .any(|p| req.uri().path().starts_with(p.as_str()))
+        || config
+            .header
+            .as_ref()
+            .is_some_and(|h| req.headers().contains_key(h.as_str()))
+        || (config.sample_rate > 0.0 && rand::random::<f64>() < config.sample_rate)
+}
+
+// the headers carrying credentials, kept without their values.
+fn credential(name: &HeaderName) -> bool {
+    let auth = &config::current().gateway.auth;
+    [AUTHORIZATION, PROXY_AUTHORIZATION, COOKIE, SET_COOKIE].contains(name)
+        || name.as_str().eq_ignore_ascii_case(&auth.api_key_header)
+        || auth
+            .hmac
+            .as_ref()
+            .is_some_and(|hmac| name.as_str().eq_ignore_ascii_case(&hmac.signature_header))
+}
+
+fn headers(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = match credential(name) {
+                true => REDACTED.to_string(),
+                false => String::from_utf8_lossy(value.as_bytes()).into_owned(),
+            };
+            (name.to_string(), value)
+        })
+        .collect()
+}
+
+// copies the start of `body` into the capture as it is read.
+fn tee(body: Body, capture: Shared, max: usize, response: bool) -> Body {
+    if max == 0 || body.is_end_stream() {
+        return body;
+    }
+    let mut kept = 0;
+    Body::wrap_stream(body.map(move |chunk| {
+        if let Ok(chunk) = &chunk {
+            let n = chunk.len().min(max - kept);
+            let mut capture = capture.lock().unwrap();
+            let part = String::from_utf8_lossy(&chunk[..n]);
+            match response {
+                true => capture.response_body.push_str(&part),
+                false => capture.request_body.push_str(&part),
+            }
+            capture.truncated |= n < chunk.len();
+            kept += n;
+        }
+        chunk
+    }))
+}
+
+/// The capture of one request, from `start` on to its response.
+pub(super) struct Recording {
+    capture: Shared,
+    started: Instant,
+    max_body_bytes: usize,
+}
+
+// starts recording `req` when the gateway captures it, keeping the capture
+// among the latest ones at once.
+pub(super) fn start(
+    request_id: &str,
+    client_ip: IpAddr,
+    req: &mut Request<Body>,
+) -> Option<Recording> {
+    let config = &config::current().gateway.capture;
+    if !config.enabled() || config.keep == 0 || !selected(config, req) {
+        return None;
+    }
+
+    let capture = Arc::new(Mutex::new(Capture {
+        request_id: request_id.to_string(),
+        time: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64),
+        client_ip: client_ip.to_string(),
+        method: req.method().to_string(),
+        uri: req.uri().to_string(),
+        version: format!("{:?}", req.version()),
+        request_headers: headers(req.headers()),
+        ..Default::default()
+    }));
+    {
+        let mut captures = CAPTURES.lock().unwrap();
+        while captures.len() >= config.keep {
+            captures.pop_front();
+        }
+        captures.push_back(capture.clone());
+    }
+
+    let body = std::mem::take(req.body_mut());
+    *req.body_mut() = tee(body, capture.clone(), config.max_body_bytes, false);
+    Some(Recording {
+        capture,
+        started: Instant::now(),
+        max_body_bytes: config.max_body_bytes,
+    })
+}
+
+impl Recording {
+    pub(super) fn finish(self, res: Response<Body>) -> Response<Body> {
+        {
+            let mut capture = self.capture.lock().unwrap();
+            capture.status = Some(res.status().as_u16());
+            capture.duration_ms = Some(self.started.elapsed().as_millis() as u64);
+            capture.response_headers = headers(res.headers());
+        }
+        let (parts, body) = res.into_parts();
+        let body = tee(body, self.capture, self.max_body_bytes, true);
+        Response::from_parts(parts, body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn keeps_the_start_of_bodies() {
+        let capture = Shared::default();
+        let chunks: Vec<Result<_, std::io::Error>> = vec![Ok("hello "), Ok("world")];
+        let body = tee(
+            Body::wrap_stream(futures::stream::iter(chunks)),
+            capture.clone(),
+            8,
+            true,
+        );
+
+        // the body itself is passed on whole.
+        let body = hyper::body::to_bytes(body).await.unwrap();
+        assert_eq!(body, "hello world");
+        let capture = capture.lock().unwrap();
+        assert_eq!(capture.response_body, "hello wo");
+        assert!(capture.truncated);
+    }
+
+    #[test]
+    fn credentials_are_redacted() {
+        let req = Request::get("/t/ums")
+            .header(AUTHORIZATION, "Bearer abc")
+            .header(COOKIE, "session=1")
+            .header("x-api-key", "k1")
+            .header("x-request-id", "r1")
+            .body(Body::empty())
+            .unwrap();
+        let headers = headers(req.headers());
+        let value = |name: &str| {
+            headers
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, v)| v.as_str())
+        };
+        assert_eq!(value("authorization"), Some(REDACTED));
+        assert_eq!(value("cookie"), Some(REDACTED));
+        assert_eq!(value("x-api-key"), Some(REDACTED));
+        assert_eq!(value("x-request-id"), Some("r1"));
+    }
+}
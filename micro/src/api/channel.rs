@@ -0,0 +1,247 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use hyper::body::HttpBody;
+use hyper::client::conn::{self, SendRequest};
+use hyper::header::{HeaderMap, CONTENT_TYPE};
+use hyper::{Body, Request, Response, Version};
+use net::ProxyError;
+use once_cell::sync::Lazy;
+use tokio::net::TcpStream;
+
+use crate::config::{self, GrpcConfig};
+use crate::MetricsRegistry;
+
+// the channels open to each instance, by its address.
+static CHANNELS: Lazy<Mutex<HashMap<String, Vec<Arc<Channel>>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// an http/2 connection to an instance.
+struct Channel {
+    sender: tokio::sync::Mutex<SendRequest<Body>>,
+    streams: Arc<AtomicUsize>,
+    // set once the connection ended, e.g. a ping went unanswered.
+    closed: Arc<AtomicBool>,
+    used: Mutex<Instant>,
+}
+
+impl Channel {
+    fn usable(&self, config: &GrpcConfig) -> bool {
+        !self.closed.load(Ordering::Relaxed)
+            && self.used.lock().unwrap().elapsed() < Duration::from_secs(config.idle_secs)
+    }
+
+    fn streams(&self) -> usize {
+        self.streams.load(Ordering::Relaxed)
+    }
+}
+
+// a stream open on a channel, until dropped.
+struct Stream(Arc<AtomicUsize>);
+
+impl Stream {
+    fn open(channel: &Channel) -> Stream {
+        channel.streams.fetch_add(1, Ordering::Relaxed);
+        *channel.used.lock().unwrap() = Instant::now();
+        Stream(channel.streams.clone())
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// whether `headers` are of a grpc request or response.
+pub(super) fn is_grpc(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/grpc"))
+}
+
+// the least busy channel of `channels` with a stream to spare, or any when
+// no more may be opened; the closed and idle ones are dropped.
+fn pick(channels: &mut Vec<Arc<Channel>>, config: &GrpcConfig) -> Option<Arc<Channel>> {
+    channels.retain(|channel| channel.usable(config));
+    let least = channels.iter().min_by_key(|channel| channel.streams())?;
+    if least.streams() < config.max_streams || channels.len() >= config.max_channels {
+        return Some(least.clone());
+    }
+    None
+}
+
+async fn open(addr: &str, config: &GrpcConfig) -> Result<Arc<Channel>, ProxyError> {
+    let stream = TcpStream::connect(addr).await?;
+    stream.set_nodelay(true)?;
+    let keep_alive = Duration::from_secs(config.keep_alive_secs);
+    let (sender, connection) = conn::Builder::new()
+        .http2_only(true)
+        .http2_keep_alive_interval(keep_alive)
+        .http2_keep_alive_timeout(keep_alive)
+        .http2_keep_alive_while_idle(true)
+        .handshake::<_, Body>(stream)
+        .await?;
+
+    let closed = Arc::new(AtomicBool::new(false));
+    let ended = closed.clone();
+    let target = addr.to_string();
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            tracing::debug!(addr = %target, error = %e, "grpc channel closed");
+        }
+        ended.store(true, Ordering::Relaxed);
+    });
+    MetricsRegistry::global()
+        .counter("crossgate_gateway_grpc_channels_total", &[("addr", addr)])
+        .inc();
+
+    let channel = Arc::new(Channel {
+        sender: tokio::sync::Mutex::new(sender),
+        streams: Arc::new(AtomicUsize::new(0)),
+        closed,
+        used: Mutex::new(Instant::now()),
+    });
+    CHANNELS
+        .lock()
+        .unwrap()
+        .entry(addr.to_string())
+        .or_default()
+        .push(channel.clone());
+    Ok(channel)
+}
+
+// `body`, with `guard` kept until all of it and its trailers are sent.
+pub(super) fn relay<G: Send + 'static>(mut body: Body, guard: G) -> Body {
+    let (mut sender, relayed) = Body::channel();
+    tokio::spawn(async move {
+        let _guard = guard;
+        while let Some(chunk) = body.data().await {
+            let sent = match chunk {
+                Ok(chunk) => sender.send_data(chunk).await.is_ok(),
+                Err(_) => false,
+            };
+            if !sent {
+                sender.abort();
+                return;
+            }
+        }
+        if let Ok(Some(trailers)) = body.trailers().await {
+            let _ = sender.send_trailers(trailers).await;
+        }
+    });
+    relayed
+}
+
+async fn send_with(
+    config: &GrpcConfig,
+    addr: &str,
+    mut req: Request<Body>,
+) -> Result<Response<Body>, ProxyError> {
+    *req.version_mut() = Version::HTTP_2;
+    // a channel found closed is given up for a new one, once.
+    let mut fresh = false;
+    loop {
+        let picked = pick(
+            CHANNELS
+                .lock()
+                .unwrap()
+                .entry(addr.to_string())
+                .or_default(),
+            config,
+        );
+        let channel = match picked {
+            Some(channel) if !fresh => channel,
+            _ => open(addr, config).await?,
+        };
+        let stream = Stream::open(&channel);
+        let mut sender = channel.sender.lock().await;
+        if let Err(e) = futures::future::poll_fn(|cx| sender.poll_ready(cx)).await {
+            channel.closed.store(true, Ordering::Relaxed);
+            match fresh {
+                true => return Err(e.into()),
+                false => {
+                    fresh = true;
+                    continue;
+                }
+            }
+        }
+        let sending = sender.send_request(req);
+        drop(sender);
+        let (parts, body) = sending.await?.into_parts();
+        return Ok(Response::from_parts(parts, relay(body, stream)));
+    }
+}
+
+// sends the grpc request `req` to the instance at `addr` on one of its
+// channels, and answers with its response.
+pub(super) async fn send(addr: &str, req: Request<Body>) -> Result<Response<Body>, ProxyError> {
+    send_with(&config::current().gateway.grpc, addr, req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::Server;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn streams_share_channels_up_to_their_limit() {
+        let make = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+                let (mut sender, body) = Body::channel();
+                let hold = req.uri().path().ends_with("Watch");
+                tokio::spawn(async move {
+                    sender.send_data("hi".into()).await.unwrap();
+                    if hold {
+                        tokio::time::sleep(Duration::from_secs(3600)).await;
+                    }
+                    let mut trailers = HeaderMap::new();
+                    trailers.insert("grpc-status", "0".parse().unwrap());
+                    sender.send_trailers(trailers).await.unwrap();
+                });
+                Ok::<_, Infallible>(Response::new(body))
+            }))
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into())
+            .http2_only(true)
+            .serve(make);
+        let addr = server.local_addr().to_string();
+        tokio::spawn(server);
+
+        let config = GrpcConfig {
+            max_streams: 1,
+            max_channels: 2,
+            ..Default::default()
+        };
+        let call = |method: &str| {
+            let req = Request::post(format!("http://{}/ums.Users/{}", addr, method))
+                .body(Body::empty())
+                .unwrap();
+            send_with(&config, &addr, req)
+        };
+        let channels = || CHANNELS.lock().unwrap()[&addr].len();
+
+        for _ in 0..3 {
+            let mut body = call("Get").await.unwrap().into_body();
+            assert_eq!(body.data().await.unwrap().unwrap(), "hi");
+            assert!(body.data().await.is_none());
+            let trailers = body.trailers().await.unwrap().unwrap();
+            assert_eq!(trailers["grpc-status"], "0");
+        }
+        assert_eq!(channels(), 1);
+
+        // streams still open take a channel each, then share the least busy.
+        let watching = vec![call("Watch").await.unwrap(), call("Watch").await.unwrap()];
+        assert_eq!(channels(), 2);
+        let third = call("Watch").await.unwrap();
+        assert_eq!(channels(), 2);
+        drop((watching, third));
+    }
+}
@@ -0,0 +1,221 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use futures::StreamExt;
+use hyper::body::{Bytes, HttpBody};
+use hyper::header::{
+    HeaderMap, HeaderName, HeaderValue, ACCEPT, ACCEPT_ENCODING, ACCEPT_LANGUAGE, AUTHORIZATION,
+    COOKIE, SET_COOKIE, UPGRADE,
+};
+use hyper::{Body, Method, Request, Response, StatusCode, Version};
+use once_cell::sync::Lazy;
+use tokio::sync::broadcast;
+
+use crate::MetricsRegistry;
+
+// larger responses go to the first request alone, the others send their own.
+const MAX_SHARED_BODY: usize = 4 << 20;
+
+// request headers the response of an upstream may depend on.
+const VARY: [HeaderName; 5] = [
+    ACCEPT,
+    ACCEPT_ENCODING,
+    ACCEPT_LANGUAGE,
+    AUTHORIZATION,
+    COOKIE,
+];
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(super) struct Key {
+    method: Method,
+    addr: String,
+    uri: String,
+    vary: Vec<Option<HeaderValue>>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    status: StatusCode,
+    version: Version,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl Shared {
+    fn response(&self) -> Response<Body> {
+        let mut res = Response::new(Body::from(self.body.clone()));
+        *res.status_mut() = self.status;
+        *res.version_mut() = self.version;
+        *res.headers_mut() = self.headers.clone();
+        res
+    }
+}
+
+// None when the response could not be shared, the waiting requests are then
+// forwarded on their own.
+type Outcome = Option<Arc<Shared>>;
+
+static IN_FLIGHT: Lazy<Mutex<HashMap<Key, broadcast::Sender<Outcome>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// what identifies `req` to `addr` among the requests in flight, None for
+// those that must reach the upstream themselves: anything with a body, not
+// idempotent or upgrading the connection.
+pub(super) fn key(addr: &str, req: &Request<Body>) -> Option<Key> {
+    if !matches!(*req.method(), Method::GET | Method::HEAD)
+        || !req.body().is_end_stream()
+        || req.headers().contains_key(UPGRADE)
+    {
+        return None;
+    }
+
+    Some(Key {
+        method: req.method().clone(),
+        addr: addr.to_string(),
+        uri: req.uri().to_string(),
+        vary: VARY.iter().map(|h| req.headers().get(h).cloned()).collect(),
+    })
+}
+
+// removes the flight when the first request is done or dropped, e.g. because
+// its client went away; the waiting ones are then told to go on their own.
+struct Flight(Option<Key>);
+
+impl Flight {
+    fn land(mut self, outcome: Outcome) {
+        let tx = match self.0.take() {
+            Some(key) => IN_FLIGHT.lock().unwrap().remove(&key),
+            None => None,
+        };
+        if let Some(tx) = tx {
+            // no one may be waiting.
+            let _ = tx.send(outcome);
+        }
+    }
+}
+
+impl Drop for Flight {
+    fn drop(&mut self) {
+        if let Some(key) = self.0.take() {
+            IN_FLIGHT.lock().unwrap().remove(&key);
+        }
+    }
+}
+
+/// Sends `req` with `forward`, unless an identical request is already in
+/// flight: then it waits for that one and answers with a copy of its
+/// response.
+pub(super) async fn coalesce<F, Fut>(
+    key: Key,
+    req: Request<Body>,
+    forward: F,
+) -> anyhow::Result<Response<Body>>
+where
+    F: FnOnce(Request<Body>) -> Fut,
+    Fut: Future<Output = anyhow::Result<Response<Body>>>,
+{
+    let waiting = {
+        let mut in_flight = IN_FLIGHT.lock().unwrap();
+        match in_flight.get(&key) {
+            Some(tx) => Some(tx.subscribe()),
+            None => {
+                in_flight.insert(key.clone(), broadcast::channel(1).0);
+                None
+            }
+        }
+    };
+
+    if let Some(mut rx) = waiting {
+        MetricsRegistry::global()
+            .counter("crossgate_gateway_coalesced_total", &[])
+            .inc();
+        return match rx.recv().await {
+            Ok(Some(shared)) => Ok(shared.response()),
+            _ => forward(req).await,
+        };
+    }
+
+    let flight = Flight(Some(key));
+    let (res, outcome) = buffer(forward(req).await?).await;
+    flight.land(outcome);
+    Ok(res)
+}
+
+// the response read into memory to share it, unless it is too large, breaks
+// off or sets a cookie meant for one client: then the first request gets it
+// as it comes.
+async fn buffer(res: Response<Body>) -> (Response<Body>, Outcome) {
+    let (parts, mut body) = res.into_parts();
+    if body.size_hint().lower() > MAX_SHARED_BODY as u64 || parts.headers.contains_key(SET_COOKIE) {
+        return (Response::from_parts(parts, body), None);
+    }
+
+    let mut buf = Vec::new();
+    while let Some(chunk) = body.data().await {
+        match chunk {
+            Ok(chunk) if buf.len() + chunk.len() <= MAX_SHARED_BODY => {
+                buf.extend_from_slice(&chunk)
+            }
+            chunk => {
+                let read = futures::stream::iter([Ok(Bytes::from(buf)), chunk]);
+                let body = Body::wrap_stream(read.chain(body));
+                return (Response::from_parts(parts, body), None);
+            }
+        }
+    }
+
+    let shared = Arc::new(Shared {
+        status: parts.status,
+        version: parts.version,
+        headers: parts.headers.clone(),
+        body: Bytes::from(buf),
+    });
+    // the extensions stay with the response of the first request, e.g. what
+    // it is retried on.
+    let mut res = shared.response();
+    *res.extensions_mut() = parts.extensions;
+    (res, Some(shared))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn identical_requests_share_one_call() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let request = || Request::get("/t/ums/user/1").body(Body::empty()).unwrap();
+        let forward = |calls: Arc<AtomicUsize>| {
+            move |_: Request<Body>| async move {
+                calls.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                Ok(Response::new(Body::from("user 1")))
+            }
+        };
+
+        let key = key("10.0.0.1:80", &request()).unwrap();
+        let responses = futures::future::join_all(
+            (0..3).map(|_| coalesce(key.clone(), request(), forward(calls.clone()))),
+        )
+        .await;
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        for res in responses {
+            let body = hyper::body::to_bytes(res.unwrap().into_body()).await;
+            assert_eq!(body.unwrap(), "user 1");
+        }
+
+        // done, the next one goes upstream again.
+        coalesce(key, request(), forward(calls.clone()))
+            .await
+            .unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        let post = Request::post("/t/ums/user/1")
+            .body(Body::from("x"))
+            .unwrap();
+        assert!(super::key("10.0.0.1:80", &post).is_none());
+    }
+}
@@ -0,0 +1,126 @@
+use std::time::Duration;
+
+use hyper::http::uri::Authority;
+use hyper::{Body, Request, Response, StatusCode};
+use tokio::net::TcpStream;
+
+use crate::audit::{AuditEvent, AuditKind};
+use crate::config::ConnectConfig;
+use crate::MetricsRegistry;
+
+// whether `pattern`, host:port with `*.` or `*` wildcards, allows `authority`.
+fn matches(pattern: &str, authority: &Authority) -> bool {
+    let (host, port) = match pattern.rsplit_once(':') {
+        Some(parts) => parts,
+        None => return false,
+    };
+    let port_allowed = match port {
+        "*" => true,
+        port => port.parse::<u16>().ok() == authority.port_u16(),
+    };
+    let target = authority.host().to_lowercase();
+    let host = host.to_lowercase();
+    let host_allowed = host == "*"
+        || match host.strip_prefix("*.") {
+            Some(suffix) => target
+                .strip_suffix(suffix)
+                .is_some_and(|sub| sub.len() > 1 && sub.ends_with('.')),
+            None => target == host,
+        };
+    port_allowed && host_allowed
+}
+
+// whether a tunnel to `authority` is allowed, it names its port.
+fn allowed(patterns: &[String], authority: &Authority) -> bool {
+    authority.port_u16().is_some() && patterns.iter().any(|p| matches(p, authority))
+}
+
+fn answer(status: StatusCode) -> Response<Body> {
+    MetricsRegistry::global()
+        .counter(
+            "crossgate_gateway_connect_tunnels_total",
+            &[("status", status.as_str())],
+        )
+        .inc();
+    Response::builder()
+        .status(status)
+        .body(Body::empty())
+        .unwrap()
+}
+
+// answers the CONNECT `req` and, when its destination is allowed and
+// reachable, relays the upgraded connection to it until either side closes.
+pub(super) async fn tunnel(config: &ConnectConfig, req: Request<Body>) -> Response<Body> {
+    if !config.enabled {
+        return answer(StatusCode::METHOD_NOT_ALLOWED);
+    }
+    let authority = match req.uri().authority() {
+        Some(authority) => authority.clone(),
+        None => return answer(StatusCode::BAD_REQUEST),
+    };
+    if !allowed(&config.allow, &authority) {
+        tracing::warn!(destination = %authority, "tunnel refused");
+        crate::audit::record(
+            AuditEvent::new(AuditKind::EgressDenied, "connect")
+                .request(&req)
+                .detail("target", &authority),
+        );
+        return answer(StatusCode::FORBIDDEN);
+    }
+
+    let timeout = Duration::from_secs(config.connect_timeout_secs.max(1));
+    let mut upstream =
+        match tokio::time::timeout(timeout, TcpStream::connect(authority.as_str())).await {
+            Ok(Ok(upstream)) => upstream,
+            Ok(Err(e)) => {
+                tracing::warn!(destination = %authority, error = %e, "tunnel connect failed");
+                return answer(StatusCode::BAD_GATEWAY);
+            }
+            Err(_) => {
+                tracing::warn!(destination = %authority, "tunnel connect timed out");
+                return answer(StatusCode::GATEWAY_TIMEOUT);
+            }
+        };
+
+    tokio::spawn(async move {
+        let mut client = match hyper::upgrade::on(req).await {
+            Ok(client) => client,
+            Err(e) => {
+                tracing::warn!(destination = %authority, error = %e, "tunnel upgrade failed");
+                return;
+            }
+        };
+        match tokio::io::copy_bidirectional(&mut client, &mut upstream).await {
+            Ok((sent, received)) => {
+                tracing::debug!(destination = %authority, sent, received, "tunnel closed")
+            }
+            Err(e) => tracing::debug!(destination = %authority, error = %e, "tunnel broken"),
+        }
+    });
+    answer(StatusCode::OK)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_allowed_destinations_are_tunneled() {
+        let allow = ["*.example.org:443", "10.0.0.5:*", "db.internal:5432"]
+            .iter()
+            .map(|p| p.to_string())
+            .collect::<Vec<_>>();
+        let allowed = |target: &str| allowed(&allow, &target.parse().unwrap());
+
+        assert!(allowed("api.example.org:443"));
+        assert!(allowed("a.b.EXAMPLE.org:443"));
+        assert!(!allowed("example.org:443"));
+        assert!(!allowed("evilexample.org:443"));
+        assert!(!allowed("api.example.org:80"));
+        assert!(allowed("10.0.0.5:22"));
+        assert!(!allowed("10.0.0.50:22"));
+        assert!(allowed("db.internal:5432"));
+        // no port, no tunnel.
+        assert!(!allowed("db.internal"));
+    }
+}
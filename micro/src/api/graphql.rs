@@ -0,0 +1,629 @@
+use std::collections::{BTreeSet, HashMap};
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::RwLock;
+use std::time::Duration;
+
+use graphql_parser::query::{self, Definition, OperationDefinition, Selection, Value};
+use graphql_parser::Pos;
+use hyper::header::{
+    HeaderMap, HeaderValue, ACCEPT_ENCODING, ALLOW, CONTENT_LENGTH, CONTENT_TYPE, HOST,
+};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use net::{Phase, Shutdown};
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use serde_json::{json, Map, Value as Json};
+
+use crate::config;
+use crate::register::DEFAULT_PROTOCOL;
+use crate::Register;
+
+type Document = query::Document<'static, String>;
+type Field = query::Field<'static, String>;
+type Fragment = query::FragmentDefinition<'static, String>;
+type SelectionSet = query::SelectionSet<'static, String>;
+type VariableDefinition = query::VariableDefinition<'static, String>;
+type Directive = query::Directive<'static, String>;
+
+// what a service is asked for the root fields of its schema.
+const ROOT_FIELDS: &str =
+    "{ __schema { queryType { fields { name } } mutationType { fields { name } } } }";
+
+// root fragments spreading further are refused past this, e.g. cycles.
+const MAX_FRAGMENT_DEPTH: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Query,
+    Mutation,
+}
+
+impl Kind {
+    fn type_name(self) -> &'static str {
+        match self {
+            Kind::Query => "Query",
+            Kind::Mutation => "Mutation",
+        }
+    }
+}
+
+// the service of each root field, by the operation it is on.
+#[derive(Debug, Default, Clone)]
+struct Owners {
+    query: HashMap<String, String>,
+    mutation: HashMap<String, String>,
+}
+
+impl Owners {
+    fn of(&self, kind: Kind) -> &HashMap<String, String> {
+        match kind {
+            Kind::Query => &self.query,
+            Kind::Mutation => &self.mutation,
+        }
+    }
+
+    fn of_mut(&mut self, kind: Kind) -> &mut HashMap<String, String> {
+        match kind {
+            Kind::Query => &mut self.query,
+            Kind::Mutation => &mut self.mutation,
+        }
+    }
+}
+
+static OWNERS: Lazy<RwLock<Owners>> = Lazy::new(|| RwLock::new(Owners::default()));
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GraphqlRequest {
+    query: String,
+    #[serde(default)]
+    variables: Option<Map<String, Json>>,
+    #[serde(default)]
+    operation_name: Option<String>,
+}
+
+// the operation of a request, the only one of its document or the one it
+// names.
+struct Operation {
+    kind: Kind,
+    name: Option<String>,
+    variables: Vec<VariableDefinition>,
+    directives: Vec<Directive>,
+    selection_set: SelectionSet,
+}
+
+fn operation(doc: &Document, name: Option<&str>) -> Result<Operation, String> {
+    let operations = doc
+        .definitions
+        .iter()
+        .filter_map(|d| match d {
+            Definition::Operation(op) => Some(op),
+            Definition::Fragment(_) => None,
+        })
+        .collect::<Vec<_>>();
+    let op = match name {
+        Some(name) => operations
+            .into_iter()
+            .find(|op| {
+                let named = match op {
+                    OperationDefinition::SelectionSet(_) => None,
+                    OperationDefinition::Query(q) => q.name.as_deref(),
+                    OperationDefinition::Mutation(m) => m.name.as_deref(),
+                    OperationDefinition::Subscription(s) => s.name.as_deref(),
+                };
+                named == Some(name)
+            })
+            .ok_or_else(|| format!("no operation named `{}`", name))?,
+        None if operations.len() == 1 => operations[0],
+        None => return Err("operationName is required with several operations".to_string()),
+    };
+
+    Ok(match op.clone() {
+        OperationDefinition::SelectionSet(selection_set) => Operation {
+            kind: Kind::Query,
+            name: None,
+            variables: vec![],
+            directives: vec![],
+            selection_set,
+        },
+        OperationDefinition::Query(q) => Operation {
+            kind: Kind::Query,
+            name: q.name,
+            variables: q.variable_definitions,
+            directives: q.directives,
+            selection_set: q.selection_set,
+        },
+        OperationDefinition::Mutation(m) => Operation {
+            kind: Kind::Mutation,
+            name: m.name,
+            variables: m.variable_definitions,
+            directives: m.directives,
+            selection_set: m.selection_set,
+        },
+        OperationDefinition::Subscription(_) => {
+            return Err("subscriptions are not supported by the gateway".to_string())
+        }
+    })
+}
+
+// the fields at the root of `set`, with the fragments there spread out.
+fn root_fields(
+    set: &SelectionSet,
+    fragments: &HashMap<&str, &Fragment>,
+    fields: &mut Vec<Field>,
+    depth: usize,
+) -> Result<(), String> {
+    if depth > MAX_FRAGMENT_DEPTH {
+        return Err("root fragments nest too deep".to_string());
+    }
+    for item in &set.items {
+        match item {
+            Selection::Field(field) => fields.push(field.clone()),
+            Selection::FragmentSpread(spread) if spread.directives.is_empty() => {
+                let fragment = fragments
+                    .get(spread.fragment_name.as_str())
+                    .ok_or_else(|| format!("unknown fragment `{}`", spread.fragment_name))?;
+                root_fields(&fragment.selection_set, fragments, fields, depth + 1)?;
+            }
+            Selection::InlineFragment(inline) if inline.directives.is_empty() => {
+                root_fields(&inline.selection_set, fragments, fields, depth + 1)?;
+            }
+            _ => return Err("directives on root fragments are not supported".to_string()),
+        }
+    }
+    Ok(())
+}
+
+// the name of the field in the response.
+fn response_key(field: &Field) -> &str {
+    field.alias.as_deref().unwrap_or(&field.name)
+}
+
+// the fields each service is asked, in the order it is asked: mutations
+// one after the other, a service again once another one had its turn.
+fn plan(
+    kind: Kind,
+    fields: &[Field],
+    owners: &HashMap<String, String>,
+) -> Result<Vec<(String, Vec<Field>)>, String> {
+    let mut batches: Vec<(String, Vec<Field>)> = vec![];
+    for field in fields {
+        if field.name == "__typename" {
+            continue;
+        }
+        if field.name.starts_with("__") {
+            return Err("introspection is not supported by the gateway".to_string());
+        }
+        let owner = owners.get(&field.name).ok_or_else(|| {
+            format!(
+                "Cannot query field `{}` on type `{}`",
+                field.name,
+                kind.type_name()
+            )
+        })?;
+        let batch = match kind {
+            Kind::Query => batches.iter_mut().find(|(s, _)| s == owner),
+            Kind::Mutation => batches.last_mut().filter(|(s, _)| s == owner),
+        };
+        match batch {
+            Some((_, batch)) => batch.push(field.clone()),
+            None => batches.push((owner.clone(), vec![field.clone()])),
+        }
+    }
+    Ok(batches)
+}
+
+fn value_variables(value: &Value<'static, String>, used: &mut BTreeSet<String>) {
+    match value {
+        Value::Variable(name) => {
+            used.insert(name.clone());
+        }
+        Value::List(items) => items.iter().for_each(|v| value_variables(v, used)),
+        Value::Object(fields) => fields.values().for_each(|v| value_variables(v, used)),
+        _ => {}
+    }
+}
+
+fn directive_variables(directives: &[Directive], used: &mut BTreeSet<String>) {
+    for directive in directives {
+        for (_, value) in &directive.arguments {
+            value_variables(value, used);
+        }
+    }
+}
+
+// the variables and fragments `set` uses, with those of the fragments.
+fn uses(
+    set: &SelectionSet,
+    fragments: &HashMap<&str, &Fragment>,
+    variables: &mut BTreeSet<String>,
+    spread: &mut BTreeSet<String>,
+) {
+    for item in &set.items {
+        match item {
+            Selection::Field(field) => {
+                for (_, value) in &field.arguments {
+                    value_variables(value, variables);
+                }
+                directive_variables(&field.directives, variables);
+                uses(&field.selection_set, fragments, variables, spread);
+            }
+            Selection::FragmentSpread(s) => {
+                directive_variables(&s.directives, variables);
+                if !spread.insert(s.fragment_name.clone()) {
+                    continue;
+                }
+                if let Some(fragment) = fragments.get(s.fragment_name.as_str()) {
+                    directive_variables(&fragment.directives, variables);
+                    uses(&fragment.selection_set, fragments, variables, spread);
+                }
+            }
+            Selection::InlineFragment(inline) => {
+                directive_variables(&inline.directives, variables);
+                uses(&inline.selection_set, fragments, variables, spread);
+            }
+        }
+    }
+}
+
+// the document asking for `fields` of `op` alone, with the variables it
+// uses; services reject variables and fragments they are sent unused.
+fn part(
+    op: &Operation,
+    fields: &[Field],
+    fragments: &HashMap<&str, &Fragment>,
+) -> (Document, BTreeSet<String>) {
+    let selection_set = SelectionSet {
+        span: (Pos::default(), Pos::default()),
+        items: fields.iter().cloned().map(Selection::Field).collect(),
+    };
+    let (mut variables, mut spread) = (BTreeSet::new(), BTreeSet::new());
+    directive_variables(&op.directives, &mut variables);
+    uses(&selection_set, fragments, &mut variables, &mut spread);
+
+    let variable_definitions = op
+        .variables
+        .iter()
+        .filter(|v| variables.contains(&v.name))
+        .cloned()
+        .collect();
+    let definition = match op.kind {
+        Kind::Query => OperationDefinition::Query(query::Query {
+            position: Pos::default(),
+            name: op.name.clone(),
+            variable_definitions,
+            directives: op.directives.clone(),
+            selection_set,
+        }),
+        Kind::Mutation => OperationDefinition::Mutation(query::Mutation {
+            position: Pos::default(),
+            name: op.name.clone(),
+            variable_definitions,
+            directives: op.directives.clone(),
+            selection_set,
+        }),
+    };
+    let mut definitions = vec![Definition::Operation(definition)];
+    definitions.extend(
+        spread
+            .iter()
+            .filter_map(|name| fragments.get(name.as_str()))
+            .map(|fragment| Definition::Fragment((*fragment).clone())),
+    );
+    (Document { definitions }, variables)
+}
+
+// what `service` answers the graphql request `body` with, on one of its
+// instances.
+async fn ask(
+    register: &Register,
+    client_ip: IpAddr,
+    headers: &HeaderMap,
+    service: &str,
+    body: Json,
+) -> anyhow::Result<Json> {
+    let (lba, endpoint) = register.get_web_service(service, DEFAULT_PROTOCOL).await?;
+    let instance = match lba.pick(&endpoint) {
+        Some(instance) => instance,
+        None => anyhow::bail!("no instance"),
+    };
+
+    let service_path = match &config::current().gateway.graphql {
+        Some(graphql) => graphql.service_path.as_str(),
+        None => "/graphql",
+    };
+    let mut req =
+        Request::post(format!("{}{}", service, service_path)).body(Body::from(body.to_string()))?;
+    *req.headers_mut() = headers.clone();
+    // the answer is read here, as it is.
+    for name in [CONTENT_LENGTH, HOST, ACCEPT_ENCODING] {
+        req.headers_mut().remove(name);
+    }
+    req.headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+
+    let timeout = config::current().request_timeout();
+    let res = super::forward(client_ip, instance, req, timeout).await?;
+    let status = res.status();
+    let body = hyper::body::to_bytes(res.into_body()).await?;
+    serde_json::from_slice(&body)
+        .map_err(|_| anyhow::anyhow!("answered {} without a graphql response", status))
+}
+
+async fn execute(
+    register: &Register,
+    client_ip: IpAddr,
+    headers: &HeaderMap,
+    doc: Document,
+    request: GraphqlRequest,
+) -> Result<Json, String> {
+    let fragments = doc
+        .definitions
+        .iter()
+        .filter_map(|d| match d {
+            Definition::Fragment(f) => Some((f.name.as_str(), f)),
+            Definition::Operation(_) => None,
+        })
+        .collect::<HashMap<_, _>>();
+    let op = operation(&doc, request.operation_name.as_deref())?;
+    let mut fields = vec![];
+    root_fields(&op.selection_set, &fragments, &mut fields, 0)?;
+    let batches = plan(op.kind, &fields, OWNERS.read().unwrap().of(op.kind))?;
+
+    let variables = request.variables.unwrap_or_default();
+    let mut asks = vec![];
+    for (service, fields) in &batches {
+        let (part, used) = part(&op, fields, &fragments);
+        let variables = variables
+            .iter()
+            .filter(|(name, _)| used.contains(*name))
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect::<Map<_, _>>();
+        let body = json!({
+            "query": part.to_string(),
+            "variables": variables,
+            "operationName": op.name,
+        });
+        asks.push(ask(register, client_ip, headers, service, body));
+    }
+    let answers = match op.kind {
+        Kind::Query => futures::future::join_all(asks).await,
+        Kind::Mutation => {
+            let mut answers = vec![];
+            for ask in asks {
+                answers.push(ask.await);
+            }
+            answers
+        }
+    };
+
+    let (mut answered, mut errors) = (Map::new(), vec![]);
+    for ((service, fields), answer) in batches.iter().zip(answers) {
+        match answer {
+            Ok(mut answer) => {
+                if let Some(Json::Array(e)) = answer.get_mut("errors").map(Json::take) {
+                    errors.extend(e);
+                }
+                if let Some(Json::Object(data)) = answer.get_mut("data").map(Json::take) {
+                    answered.extend(data);
+                }
+            }
+            Err(e) => errors.extend(fields.iter().map(|field| {
+                json!({
+                    "message": format!("{}: {:#}", service, e),
+                    "path": [response_key(field)],
+                })
+            })),
+        }
+    }
+
+    let mut data = Map::new();
+    for field in &fields {
+        let key = response_key(field);
+        let value = match field.name.as_str() {
+            "__typename" => json!(op.kind.type_name()),
+            _ => answered.get(key).cloned().unwrap_or(Json::Null),
+        };
+        data.insert(key.to_string(), value);
+    }
+    let mut body = json!({ "data": data });
+    if !errors.is_empty() {
+        body["errors"] = Json::Array(errors);
+    }
+    Ok(body)
+}
+
+fn reply(status: StatusCode, body: Json) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+fn failed(message: String) -> Json {
+    json!({ "errors": [{ "message": message }] })
+}
+
+// answers a graphql POST with the answers of the services owning its
+// fields.
+pub(super) async fn serve(
+    register: &Register,
+    client_ip: IpAddr,
+    req: Request<Body>,
+) -> anyhow::Result<Response<Body>> {
+    if req.method() != Method::POST {
+        return Ok(Response::builder()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .header(ALLOW, "POST")
+            .body(Body::empty())
+            .unwrap());
+    }
+
+    let (parts, body) = req.into_parts();
+    let body = hyper::body::to_bytes(body).await?;
+    let request = match serde_json::from_slice::<GraphqlRequest>(&body) {
+        Ok(request) => request,
+        Err(e) => {
+            let message = format!("invalid graphql request: {}", e);
+            return Ok(reply(StatusCode::BAD_REQUEST, failed(message)));
+        }
+    };
+    let doc = match query::parse_query::<String>(&request.query) {
+        Ok(doc) => doc.into_static(),
+        Err(e) => return Ok(reply(StatusCode::BAD_REQUEST, failed(e.to_string()))),
+    };
+
+    let body = execute(register, client_ip, &parts.headers, doc, request)
+        .await
+        .unwrap_or_else(failed);
+    Ok(reply(StatusCode::OK, body))
+}
+
+// asks every service for its root fields; those that do not answer keep
+// the ones they had.
+async fn refresh(register: &Register, services: &[String]) {
+    let before = OWNERS.read().unwrap().clone();
+    let mut owners = Owners::default();
+    let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+    for service in services {
+        let body = json!({ "query": ROOT_FIELDS });
+        let answer = ask(register, localhost, &HeaderMap::new(), service, body).await;
+        let schema = match &answer {
+            Ok(answer) => answer.pointer("/data/__schema"),
+            Err(e) => {
+                tracing::warn!(service, error = %e, "graphql schema refresh failed");
+                None
+            }
+        };
+        for kind in [Kind::Query, Kind::Mutation] {
+            let names = match schema {
+                Some(schema) => {
+                    let pointer = match kind {
+                        Kind::Query => "/queryType/fields",
+                        Kind::Mutation => "/mutationType/fields",
+                    };
+                    schema
+                        .pointer(pointer)
+                        .and_then(Json::as_array)
+                        .into_iter()
+                        .flatten()
+                        .filter_map(|f| f["name"].as_str().map(str::to_string))
+                        .collect::<Vec<_>>()
+                }
+                None => before
+                    .of(kind)
+                    .iter()
+                    .filter(|(_, owner)| *owner == service)
+                    .map(|(name, _)| name.clone())
+                    .collect(),
+            };
+            for name in names {
+                owners
+                    .of_mut(kind)
+                    .entry(name)
+                    .or_insert_with(|| service.clone());
+            }
+        }
+    }
+    *OWNERS.write().unwrap() = owners;
+}
+
+// keeps the root fields of the services current until `shutdown` stops
+// accepting.
+pub(super) fn start(register: &Register, shutdown: &Shutdown) {
+    let graphql = match &config::current().gateway.graphql {
+        Some(graphql) => graphql,
+        None => return,
+    };
+    let every = Duration::from_secs(graphql.refresh_secs);
+    let (register, shutdown) = (register.clone(), shutdown.clone());
+    tokio::spawn(async move {
+        loop {
+            refresh(&register, &graphql.services).await;
+            tokio::select! {
+                _ = tokio::time::sleep(every) => {}
+                _ = shutdown.reached(Phase::StopAccepting) => return,
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn owners(fields: &[(&str, &str)]) -> HashMap<String, String> {
+        fields
+            .iter()
+            .map(|(field, service)| (field.to_string(), service.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn queries_split_by_the_owner_of_each_root_field() {
+        let doc = query::parse_query::<String>(
+            "query Q($id: ID!, $n: Int) { ...Root orders(first: $n) { id } __typename }
+             fragment Root on Query { me: user(id: $id) { ...U } }
+             fragment U on User { name }",
+        )
+        .unwrap()
+        .into_static();
+        let fragments = doc
+            .definitions
+            .iter()
+            .filter_map(|d| match d {
+                Definition::Fragment(f) => Some((f.name.as_str(), f)),
+                Definition::Operation(_) => None,
+            })
+            .collect::<HashMap<_, _>>();
+        let op = operation(&doc, None).unwrap();
+        let mut fields = vec![];
+        root_fields(&op.selection_set, &fragments, &mut fields, 0).unwrap();
+        assert_eq!(
+            fields.iter().map(response_key).collect::<Vec<_>>(),
+            ["me", "orders", "__typename"]
+        );
+
+        let owners = owners(&[("user", "/t/ums"), ("orders", "/t/order")]);
+        let batches = plan(op.kind, &fields, &owners).unwrap();
+        assert_eq!(batches.len(), 2);
+
+        let (ums, used) = part(&op, &batches[0].1, &fragments);
+        let ums = ums.to_string();
+        assert_eq!(used.into_iter().collect::<Vec<_>>(), ["id"]);
+        assert!(ums.contains("query Q($id: ID!)"), "{}", ums);
+        assert!(ums.contains("fragment U on User"), "{}", ums);
+        assert!(!ums.contains("fragment Root"), "{}", ums);
+
+        let (order, used) = part(&op, &batches[1].1, &fragments);
+        let order = order.to_string();
+        assert_eq!(used.into_iter().collect::<Vec<_>>(), ["n"]);
+        assert!(!order.contains("fragment"), "{}", order);
+
+        let unknown = vec![
+            fields[0].clone(),
+            Field {
+                name: "nope".to_string(),
+                ..fields[0].clone()
+            },
+        ];
+        assert!(plan(op.kind, &unknown, &owners).is_err());
+    }
+
+    #[test]
+    fn mutations_keep_their_order() {
+        let doc = query::parse_query::<String>("mutation { a b c }")
+            .unwrap()
+            .into_static();
+        let op = operation(&doc, None).unwrap();
+        let mut fields = vec![];
+        root_fields(&op.selection_set, &HashMap::new(), &mut fields, 0).unwrap();
+        let owners = owners(&[("a", "/t/one"), ("b", "/t/two"), ("c", "/t/one")]);
+        let batches = plan(op.kind, &fields, &owners).unwrap();
+        assert_eq!(
+            batches.iter().map(|(s, _)| s.as_str()).collect::<Vec<_>>(),
+            ["/t/one", "/t/two", "/t/one"]
+        );
+    }
+}
@@ -0,0 +1,211 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use hyper::body::{Bytes, HttpBody};
+use hyper::server::accept::Accept;
+use hyper::{Body, Request};
+use once_cell::sync::Lazy;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use super::tls::{ClientIdentity, Peer};
+use crate::MetricsRegistry;
+
+type BodyError = Box<dyn std::error::Error + Send + Sync>;
+
+// the connections open from each client ip, on all listeners.
+static OPEN: Lazy<Mutex<HashMap<IpAddr, usize>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+// counts a connection from `ip` unless `max` are open already, 0 for no
+// limit.
+fn admit(ip: IpAddr, max: usize) -> bool {
+    let mut open = OPEN.lock().unwrap();
+    let count = open.entry(ip).or_insert(0);
+    if max > 0 && *count >= max {
+        return false;
+    }
+    *count += 1;
+    true
+}
+
+fn release(ip: IpAddr) {
+    let mut open = OPEN.lock().unwrap();
+    if let Some(count) = open.get_mut(&ip) {
+        *count -= 1;
+        if *count == 0 {
+            open.remove(&ip);
+        }
+    }
+}
+
+// a connection counted against its client ip until it is dropped.
+pub(super) struct Counted<S> {
+    inner: S,
+    ip: IpAddr,
+}
+
+impl<S> Drop for Counted<S> {
+    fn drop(&mut self) {
+        release(self.ip);
+    }
+}
+
+impl<S: Peer> Peer for Counted<S> {
+    fn peer(&self) -> SocketAddr {
+        self.inner.peer()
+    }
+
+    fn server_name(&self) -> Option<&str> {
+        self.inner.server_name()
+    }
+
+    fn client_identity(&self) -> Option<ClientIdentity> {
+        self.inner.client_identity()
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+// the connections of `incoming`, those from a client ip with `max` open
+// already closed at once, before any handshake.
+pub(super) struct PerIp<I> {
+    incoming: I,
+    max: usize,
+}
+
+pub(super) fn per_ip<I>(incoming: I, max: usize) -> PerIp<I> {
+    PerIp { incoming, max }
+}
+
+impl<I> Accept for PerIp<I>
+where
+    I: Accept + Unpin,
+    I::Conn: Peer,
+{
+    type Conn = Counted<I::Conn>;
+    type Error = I::Error;
+
+    fn poll_accept(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        loop {
+            let conn = match ready!(Pin::new(&mut self.incoming).poll_accept(cx)) {
+                Some(Ok(conn)) => conn,
+                Some(Err(e)) => return Poll::Ready(Some(Err(e))),
+                None => return Poll::Ready(None),
+            };
+            let ip = conn.peer().ip();
+            if admit(ip, self.max) {
+                return Poll::Ready(Some(Ok(Counted { inner: conn, ip })));
+            }
+            MetricsRegistry::global()
+                .counter(
+                    "crossgate_gateway_connections_refused_total",
+                    &[("limit", "max_connections_per_ip")],
+                )
+                .inc();
+            tracing::debug!(client_ip = %ip, "connection refused, too many open from its ip");
+        }
+    }
+}
+
+pub(super) fn limited(limit: &str) {
+    MetricsRegistry::global()
+        .counter("crossgate_gateway_slow_clients_total", &[("limit", limit)])
+        .inc();
+}
+
+// the body of `req` fails once the client pauses longer than `timeout`
+// between two chunks of it, so that a trickle of bytes cannot hold a
+// connection and an upstream call open.
+pub(super) fn pace(req: &mut Request<Body>, timeout: Duration) {
+    if timeout.is_zero() || req.body().is_end_stream() {
+        return;
+    }
+    let body = std::mem::take(req.body_mut());
+    let chunks = futures::stream::unfold(Some(body), move |body| async move {
+        let mut body = body?;
+        match tokio::time::timeout(timeout, body.data()).await {
+            Ok(Some(Ok(chunk))) => Some((Ok::<Bytes, BodyError>(chunk), Some(body))),
+            Ok(Some(Err(e))) => Some((Err(e.into()), None)),
+            Ok(None) => None,
+            Err(_) => {
+                limited("body_read_timeout");
+                let e = std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    format!("request body paused over {:?}", timeout),
+                );
+                Some((Err(e.into()), None))
+            }
+        }
+    });
+    *req.body_mut() = Body::wrap_stream(chunks);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connections_per_ip() {
+        let ip = "192.0.2.7".parse().unwrap();
+        assert!(admit(ip, 2));
+        let first = Counted { inner: (), ip };
+        assert!(admit(ip, 2));
+        assert!(!admit(ip, 2));
+        drop(first);
+        assert!(admit(ip, 2));
+        assert!(admit(ip, 0));
+        for _ in 0..3 {
+            release(ip);
+        }
+        assert!(!OPEN.lock().unwrap().contains_key(&ip));
+    }
+
+    #[tokio::test]
+    async fn paused_bodies_fail() {
+        let (mut tx, body) = Body::channel();
+        let mut req = Request::post("/t/ums").body(body).unwrap();
+        pace(&mut req, Duration::from_millis(50));
+        tx.send_data(Bytes::from("a")).await.unwrap();
+        let body = req.body_mut();
+        assert_eq!(body.data().await.unwrap().unwrap(), "a");
+        assert!(body.data().await.unwrap().is_err());
+        assert!(body.data().await.is_none());
+        drop(tx);
+
+        let mut req = Request::get("/t/ums").body(Body::empty()).unwrap();
+        pace(&mut req, Duration::from_millis(50));
+        assert!(req.body().is_end_stream());
+    }
+}
@@ -1,17 +1,47 @@
-use crossbeam::sync::WaitGroup;
 use futures::future::BoxFuture;
-use hyper::server::conn::AddrStream;
+use hyper::server::accept::Accept;
+use hyper::server::conn::AddrIncoming;
 use hyper::service::{make_service_fn, service_fn};
+use hyper::{header::HeaderValue, header::RETRY_AFTER, Request, Response, StatusCode};
 use hyper::{Body, Server};
-use hyper::{Request, Response, StatusCode};
-use plugin::get_plugin_type;
-use plugin::PluginType::Mongodb;
-use tokio_context::context::Context;
+use net::{Phase, Shutdown};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tracing::Instrument;
+
+#[cfg(feature = "acme")]
+mod acme;
+mod bulkhead;
+mod capture;
+mod channel;
+mod coalesce;
+mod connect;
+#[cfg(feature = "graphql")]
+mod graphql;
+mod limits;
+mod policy;
+mod redirect;
+mod shed;
+mod status;
+mod tenancy;
+mod tls;
+#[cfg(feature = "transcode")]
+pub(crate) mod transcode;
+mod warmup;
+pub use capture::{captures, Capture};
+pub(crate) use tenancy::route_of;
+pub use tenancy::Tenant;
+pub use tls::ClientIdentity;
+use tls::Peer;
 
 use std::convert::Infallible;
 use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
 
-use crate::{Endpoint, Register};
+use crate::audit::{AuditEvent, AuditKind};
+use crate::config;
+use crate::register::{static_endpoint, DEFAULT_PROTOCOL};
+use crate::task::TRIGGER_PATH;
+use crate::{Endpoint, Instance, LoadBalancerAlgorithm, MetricsRegistry, Register, ServiceError};
 
 static TITLE: &str = r#"
 <html>
@@ -62,6 +92,286 @@ fn default_response() -> Response<Body> {
     Response::new(Body::from(TITLE))
 }
 
+// the endpoint protocol a request is for: the `x-crossgate-protocol` header,
+// else grpc by content type, else the service's main http address.
+fn request_protocol(req: &Request<Body>) -> String {
+    if let Some(protocol) = req
+        .headers()
+        .get("x-crossgate-protocol")
+        .and_then(|v| v.to_str().ok())
+    {
+        return protocol.to_ascii_lowercase();
+    }
+
+    let grpc = req
+        .headers()
+        .get(hyper::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/grpc"));
+    if grpc {
+        return "grpc".to_string();
+    }
+    DEFAULT_PROTOCOL.to_string()
+}
+
+// marks the responses the gateway answers for an instance that did not.
+#[derive(Debug, Clone, Copy)]
+struct Unanswered;
+
+// over https when the instance registered tls, cut off after `timeout`.
+async fn forward(
+    client_ip: IpAddr,
+    instance: &Instance,
+    req: Request<Body>,
+    timeout: Option<Duration>,
+) -> anyhow::Result<Response<Body>> {
+    // the upstream continues the trace of the request.
+    #[cfg(feature = "otel")]
+    let req = {
+        let mut req = req;
+        crate::telemetry::inject(req.headers_mut());
+        req
+    };
+
+    let addr = instance.addr.as_str();
+    let call = async {
+        // in the mesh every instance is reached over mutual tls.
+        if let Some(client) = crate::mesh::client() {
+            return client
+                .call(client_ip, &format!("https://{}", addr), req)
+                .await;
+        }
+        // grpc goes on the long lived channels of the instance.
+        if instance.tls.is_none() && channel::is_grpc(req.headers()) {
+            let req = net::proxied_request(client_ip, &format!("http://{}", addr), req)?;
+            return channel::send(addr, req).await;
+        }
+        match &instance.tls {
+            Some(sni) => {
+                net::get_tls_proxy_client(sni.as_deref())
+                    .call(client_ip, &format!("https://{}", addr), req)
+                    .await
+            }
+            None => {
+                net::get_proxy_client()
+                    .call(client_ip, &format!("http://{}", addr), req)
+                    .await
+            }
+        }
+    };
+    let res = match timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, call).await {
+            Ok(res) => res,
+            Err(_) => {
+                return Ok(Response::builder()
+                    .status(StatusCode::GATEWAY_TIMEOUT)
+                    .extension(Unanswered)
+                    .body(format!("{} did not answer within {:?}", addr, timeout).into())
+                    .unwrap())
+            }
+        },
+        None => call.await,
+    };
+
+    match res {
+        Ok(res) => Ok(res),
+        Err(e) => Ok(Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .extension(Unanswered)
+            .body(format!("gateway error: {:#?}", e).into())
+            .unwrap()),
+    }
+}
+
+// `forward`, sharing the upstream call between identical requests when the
+// gateway coalesces.
+async fn coalesced(
+    client_ip: IpAddr,
+    instance: &Instance,
+    req: Request<Body>,
+    timeout: Option<Duration>,
+) -> anyhow::Result<Response<Body>> {
+    let key = match config::current().gateway.coalesce {
+        true => coalesce::key(&instance.addr, &req),
+        false => None,
+    };
+    match key {
+        Some(key) => {
+            coalesce::coalesce(key, req, |req| forward(client_ip, instance, req, timeout)).await
+        }
+        None => forward(client_ip, instance, req, timeout).await,
+    }
+}
+
+fn not_found(service: &str) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::SERVICE_UNAVAILABLE)
+        .body(format!("{} not found", service).into())
+        .unwrap()
+}
+
+fn bulkhead_full(service: &str) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::SERVICE_UNAVAILABLE)
+        .body(format!("{} has too many requests in flight", service).into())
+        .unwrap()
+}
+
+// `coalesced`, tried again on another instance as often as `policy` allows
+// while the instance picked does not answer.
+async fn retried(
+    client_ip: IpAddr,
+    service: &str,
+    lba: &LoadBalancerAlgorithm,
+    endpoint: &Endpoint,
+    mut req: Request<Body>,
+    policy: &policy::Policy,
+) -> anyhow::Result<Response<Body>> {
+    let mut tried = vec![];
+    loop {
+        let Some(mut instance) = lba.pick(endpoint) else {
+            return Ok(not_found(service));
+        };
+        if tried.contains(&instance.addr.as_str()) {
+            if let Some(other) = endpoint
+                .instances()
+                .iter()
+                .find(|i| !tried.contains(&i.addr.as_str()))
+            {
+                instance = other;
+            }
+        }
+        let again = match tried.len() < policy.retries as usize {
+            true => policy::replay(&req),
+            false => None,
+        };
+        let mut res = coalesced(client_ip, instance, req, policy.timeout).await?;
+        status::observe(service, &instance.addr, res.status());
+        policy.annotate(instance, &mut res);
+        match again {
+            Some(again) if res.extensions().get::<Unanswered>().is_some() => {
+                tracing::debug!(addr = %instance.addr, status = %res.status(), "instance did not answer, retrying");
+                MetricsRegistry::global()
+                    .counter("crossgate_gateway_retries_total", &[])
+                    .inc();
+                tried.push(&instance.addr);
+                req = again;
+            }
+            _ => return Ok(res),
+        }
+    }
+}
+
+async fn forward_task(
+    register: &Register,
+    client_ip: IpAddr,
+    req: Request<Body>,
+) -> anyhow::Result<Response<Body>> {
+    let group = req.uri().path()[TRIGGER_PATH.len()..]
+        .split('/')
+        .next()
+        .unwrap_or("")
+        .to_string();
+
+    let addr = match register.get_backend_trigger(&group).await {
+        Ok(Some(addr)) => addr,
+        _ => {
+            return Ok(Response::builder()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .body(format!("{} has no trigger endpoint", group).into())
+                .unwrap());
+        }
+    };
+
+    match net::get_proxy_client()
+        .call(client_ip, &format!("http://{}", addr), req)
+        .await
+    {
+        Ok(res) => Ok(res),
+        Err(e) => Ok(Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(format!("gateway error: {:#?}", e).into())
+            .unwrap()),
+    }
+}
+
+pub(crate) const REQUEST_ID: &str = "x-request-id";
+
+// the address a request came from, in its extensions.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ClientIp(pub IpAddr);
+
+// the client's x-request-id, else a new one set on the request so that the
+// upstream sees the same id.
+fn request_id(req: &mut Request<Body>) -> String {
+    if let Some(id) = req.headers().get(REQUEST_ID).and_then(|v| v.to_str().ok()) {
+        return id.to_string();
+    }
+    let id = format!("{:016x}", rand::random::<u64>());
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        req.headers_mut().insert(REQUEST_ID, value);
+    }
+    id
+}
+
+// one span per request, the plugin lookups and the upstream call nest in it.
+async fn traced(
+    register: &Register,
+    client_ip: IpAddr,
+    mut req: Request<Body>,
+    intercepters: &'static [Intercepter],
+    self_handle: Option<ServeHTTP>,
+) -> anyhow::Result<Response<Body>> {
+    let request_id = request_id(&mut req);
+    req.extensions_mut().insert(ClientIp(client_ip));
+    #[cfg(feature = "geoip")]
+    crate::geoip::tag(&mut req, client_ip);
+    let tenant = tenancy::identify(&mut req);
+    let recording = capture::start(&request_id, client_ip, &mut req);
+    let span = tracing::info_span!(
+        "gateway.request",
+        request_id = %request_id,
+        method = %req.method(),
+        path = req.uri().path(),
+        client_ip = %client_ip,
+        tenant = tenant.map(|t| t.name.as_str()),
+        service = tracing::field::Empty,
+        status = tracing::field::Empty,
+    );
+    #[cfg(feature = "otel")]
+    crate::telemetry::extract(&span, req.headers());
+
+    let metrics = MetricsRegistry::global();
+    let in_flight = metrics.gauge("crossgate_gateway_requests_in_flight", &[]);
+    in_flight.inc();
+    let res = intercept(register, client_ip, req, intercepters, self_handle)
+        .instrument(span.clone())
+        .await;
+    in_flight.dec();
+
+    let code = res.as_ref().map(|res| res.status());
+    let status = match &code {
+        Ok(code) => code.as_str(),
+        Err(_) => "error",
+    };
+    span.record("status", status);
+    metrics
+        .counter("crossgate_gateway_requests_total", &[("status", status)])
+        .inc();
+    if let Some(tenant) = tenant {
+        metrics
+            .counter(
+                "crossgate_gateway_tenant_requests_total",
+                &[("tenant", &tenant.name), ("status", status)],
+            )
+            .inc();
+    }
+    match recording {
+        Some(recording) => res.map(|res| recording.finish(res)),
+        None => res,
+    }
+}
+
 async fn intercept(
     register: &Register,
     client_ip: IpAddr,
@@ -69,6 +379,42 @@ async fn intercept(
     intercepters: &'static [Intercepter],
     self_handle: Option<ServeHTTP>,
 ) -> anyhow::Result<Response<Body>> {
+    // peers sign in with their own secret, the middleware is not for them.
+    if req.uri().path() == crate::peers::PEERS_PATH {
+        return Ok(crate::peers::answer(req).await);
+    }
+
+    let tenant = tenancy::of(&req);
+    if tenant.is_none() && config::current().gateway.tenancy.required {
+        return Ok(Response::builder()
+            .status(StatusCode::FORBIDDEN)
+            .body("requests are served for tenants only".into())
+            .unwrap());
+    }
+    // before anything else is dispatched, a tenant reaches the services it
+    // routes to and no others.
+    let tenant_route = match tenant {
+        Some(tenant) => match tenancy::route_for(tenant, &req) {
+            Some(route) => Some(route),
+            None => {
+                return Ok(Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .body(format!("{} has no route to {}", tenant.name, req.uri().path()).into())
+                    .unwrap());
+            }
+        },
+        None => None,
+    };
+
+    #[cfg(feature = "geoip")]
+    if let Some(code) = crate::geoip::blocked(&req) {
+        tracing::debug!(code = %code, "client geo blocked");
+        return Ok(Response::builder()
+            .status(StatusCode::FORBIDDEN)
+            .body(Body::empty())
+            .unwrap());
+    }
+
     for intercepter in intercepters {
         let mut res = Response::new(Body::empty());
 
@@ -92,12 +438,65 @@ async fn intercept(
         }
     }
 
-    if req.uri().path() == "/" {
-        return Ok(default_response());
+    // a tunnel for a client past the middleware, the gateway as its proxy.
+    if tenant.is_none() && req.method() == hyper::Method::CONNECT {
+        return Ok(connect::tunnel(&config::current().gateway.connect, req).await);
+    }
+
+    // a host of its own is one service on every path, tenants have their
+    // routes instead.
+    let host_service = req
+        .extensions()
+        .get::<tls::ServerName>()
+        .and_then(|name| config::current().gateway.tls.as_ref()?.host(&name.0))
+        .and_then(|host| host.service.as_deref())
+        .filter(|_| tenant.is_none());
+    // what the gateway serves besides the services, not for tenants.
+    let shared = tenant.is_none() && host_service.is_none();
+
+    // one graphql endpoint over the schemas of the services.
+    #[cfg(feature = "graphql")]
+    if let Some(graphql) = &config::current().gateway.graphql {
+        if shared && req.uri().path() == graphql.path {
+            return graphql::serve(register, client_ip, req).await;
+        }
+    }
+
+    // the methods of grpc services at the paths they annotate.
+    #[cfg(feature = "transcode")]
+    if shared {
+        if let Some((binding, fields)) = transcode::binding(req.method(), req.uri().path()) {
+            return Ok(transcode::serve(register, binding, fields, req).await);
+        }
     }
 
-    //  /t/ums/user/login => /t/ums
-    let service_name = extracting_service(req.uri().path());
+    if shared && req.uri().path() == "/" {
+        let status = &config::current().gateway.status;
+        return match status.enabled {
+            true => Ok(status::page(status, register, &req).await),
+            false => Ok(default_response()),
+        };
+    }
+
+    //  /tasks/{group}/{job} => a backend member serving triggers
+    if shared && req.uri().path().starts_with(TRIGGER_PATH) {
+        return forward_task(register, client_ip, req).await;
+    }
+
+    //  /t/ums/user/login => /t/ums, unless a configured route says otherwise
+    let route = match (host_service, tenant) {
+        (Some(_), _) => None,
+        (None, Some(_)) => tenant_route,
+        (None, None) => config::current().matching_route(req.uri().path()),
+    };
+    let service_name = match host_service.or(route.map(|r| r.service.as_str())) {
+        Some(service) => service.to_string(),
+        None => extracting_service(req.uri().path()),
+    };
+    // the clients of some places go to a service of their own.
+    #[cfg(feature = "geoip")]
+    let service_name = crate::geoip::steer(route, &req).unwrap_or(service_name);
+    tracing::Span::current().record("service", service_name.as_str());
     if service_name == "" {
         return Ok(Response::builder()
             .status(StatusCode::SERVICE_UNAVAILABLE)
@@ -105,8 +504,70 @@ async fn intercept(
             .unwrap());
     }
 
+    // the upstream gets the path the route rewrites it to.
+    if let Some(forwarded) = route.and_then(|r| r.rewrite(req.uri().path(), req.uri().query())) {
+        match forwarded.parse() {
+            Ok(uri) => *req.uri_mut() = uri,
+            Err(_) => {
+                return Ok(Response::builder()
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(format!("route rewrites to an invalid path `{}`", forwarded).into())
+                    .unwrap());
+            }
+        }
+    }
+
+    if route.is_some_and(|r| r.policy.client_cert)
+        && req.extensions().get::<ClientIdentity>().is_none()
+    {
+        return Ok(Response::builder()
+            .status(StatusCode::FORBIDDEN)
+            .body("client certificate required".into())
+            .unwrap());
+    }
+
+    let policy = policy::Policy::resolve(route, tenant);
+    // health checks and the like are answered by intercepters before this.
+    let priority = req
+        .extensions()
+        .get::<config::Priority>()
+        .copied()
+        .unwrap_or(policy.priority);
+    let slot = match shed::admit(priority) {
+        Some(slot) => slot,
+        None => {
+            let message = format!(
+                "gateway is shedding {} priority requests",
+                priority.as_str()
+            );
+            return Ok(Response::builder()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .header(RETRY_AFTER, "1")
+                .body(message.into())
+                .unwrap());
+        }
+    };
+    if !policy.admit() {
+        return Ok(Response::builder()
+            .status(StatusCode::TOO_MANY_REQUESTS)
+            .body(format!("{} is over its rate limit", service_name).into())
+            .unwrap());
+    }
+    if let Err(limit) = policy.limit_body(&mut req) {
+        return Ok(Response::builder()
+            .status(StatusCode::PAYLOAD_TOO_LARGE)
+            .body(format!("request body over {} bytes", limit).into())
+            .unwrap());
+    }
+
+    let protocol = request_protocol(&req);
+    // a route with fixed upstreams does not ask the registry.
+    let upstreams = route
+        .map(|r| r.upstreams.as_slice())
+        .filter(|upstreams| !upstreams.is_empty());
+
     // 如果请求头中有strict，那么直接转发到strict中
-    if let Some(strict) = req.headers().get("strict") {
+    if let (None, Some(strict)) = (upstreams, req.headers().get("strict")) {
         let strict_address = strict.to_str().unwrap_or("").to_string();
 
         if strict_address.is_empty() {
@@ -120,6 +581,7 @@ async fn intercept(
             .get_web_service_by_lba(
                 &service_name,
                 crate::LoadBalancerAlgorithm::Strict(strict_address),
+                &protocol,
             )
             .await
         {
@@ -132,103 +594,264 @@ async fn intercept(
             }
         };
 
-        if endpoint.get_address().is_empty() {
-            return Ok(Response::builder()
-                .status(StatusCode::SERVICE_UNAVAILABLE)
-                .body(format!("{} not found", service_name).into())
-                .unwrap());
-        }
-
-        let forward_addr = format!("http://{}", lba.hash(endpoint.get_address().as_slice()));
+        let Some(instance) = lba.pick(&endpoint) else {
+            return Ok(not_found(&service_name));
+        };
+        let permit = match bulkhead::acquire(&service_name).await {
+            Ok(permit) => permit,
+            Err(()) => return Ok(bulkhead_full(&service_name)),
+        };
+        let res = coalesced(client_ip, instance, req, policy.timeout).await?;
+        status::observe(&service_name, &instance.addr, res.status());
+        return Ok(bulkhead::hold(res, Some((slot, permit))));
+    }
 
-        match net::get_proxy_client()
-            .call(client_ip, &forward_addr, req)
-            .await
-        {
-            Ok(res) => return Ok(res),
-            Err(e) => {
+    let (lba, endpoint) = match upstreams {
+        Some(upstreams) => (config::current().lba(), static_endpoint(upstreams)),
+        None => match register.get_web_service(&service_name, &protocol).await {
+            Ok(endpoint) => endpoint,
+            Err(_) => {
                 return Ok(Response::builder()
                     .status(StatusCode::INTERNAL_SERVER_ERROR)
-                    .body(format!("gateway error: {:#?}", e).into())
+                    .body(Body::empty())
                     .unwrap());
             }
-        }
-    }
-
-    let (lba, endpoint) = match register.get_web_service(&service_name).await {
-        Ok(endpoint) => endpoint,
-        Err(_) => {
-            return Ok(Response::builder()
-                .status(StatusCode::INTERNAL_SERVER_ERROR)
-                .body(Body::empty())
-                .unwrap());
-        }
+        },
     };
 
-    if 0 == endpoint.get_address().len() {
-        return Ok(Response::builder()
-            .status(StatusCode::SERVICE_UNAVAILABLE)
-            .body(format!("{} not found", service_name).into())
-            .unwrap());
-    }
+    let endpoint = warmup::ready(endpoint);
 
-    let forward_addr = format!("http://{}", lba.hash(endpoint.get_address().as_slice()));
+    if endpoint.is_empty() {
+        return Ok(not_found(&service_name));
+    }
+    // only resolved services get a bulkhead.
+    let permit = match bulkhead::acquire(&service_name).await {
+        Ok(permit) => permit,
+        Err(()) => return Ok(bulkhead_full(&service_name)),
+    };
+    // watched for webhooks from now on.
+    if upstreams.is_none() {
+        crate::notify::routed(&service_name);
+    }
 
-    match net::get_proxy_client()
-        .call(client_ip, &forward_addr, req)
+    let lba = policy
+        .requested_lba(&mut req)
+        .or_else(|| policy.lba.clone())
+        .unwrap_or(lba);
+    retried(client_ip, &service_name, &lba, &endpoint, req, &policy)
         .await
-    {
-        Ok(res) => return Ok(res),
-        Err(e) => {
-            return Ok(Response::builder()
-                .status(StatusCode::INTERNAL_SERVER_ERROR)
-                .body(format!("gateway error: {:#?}", e).into())
-                .unwrap());
+        .map(|res| bulkhead::hold(res, Some((slot, permit))))
+}
+
+// fails when the plugin cannot start or an address cannot be served.
+pub async fn run(
+    addr: String,
+    intercepters: &'static [Intercepter],
+    sh: Option<ServeHTTP>,
+) -> Result<(), ServiceError> {
+    serve(vec![addr], intercepters, sh).await
+}
+
+// listens on every configured gateway address.
+pub async fn run_from_config(
+    intercepters: &'static [Intercepter],
+    sh: Option<ServeHTTP>,
+) -> Result<(), ServiceError> {
+    serve(config::current().listen(), intercepters, sh).await
+}
+
+// serves the connections of `incoming` until `shutdown` stops accepting, then
+// finishes the requests in flight.
+async fn serve_on<I>(
+    addr: String,
+    incoming: I,
+    register: Register,
+    intercepters: &'static [Intercepter],
+    sh: Option<ServeHTTP>,
+    shutdown: &Shutdown,
+) -> Result<(), ServiceError>
+where
+    I: Accept,
+    I::Conn: Peer + AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    I::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let listener = &config::current().gateway.listener;
+    let body_read_timeout = Duration::from_secs(listener.body_read_timeout_secs);
+    let max_headers = listener.max_headers;
+    let make_svc = make_service_fn(move |conn: &I::Conn| {
+        let remote_addr = conn.peer().ip();
+        let server_name = conn
+            .server_name()
+            .map(|name| tls::ServerName(name.to_string()));
+        let client_identity = conn.client_identity();
+        let register = register.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |mut req: Request<Body>| {
+                let register = register.clone();
+                if let Some(server_name) = &server_name {
+                    req.extensions_mut().insert(server_name.clone());
+                }
+                if let Some(identity) = &client_identity {
+                    req.extensions_mut().insert(identity.clone());
+                }
+                limits::pace(&mut req, body_read_timeout);
+                let too_many_headers = req.headers().len() > max_headers;
+                async move {
+                    if too_many_headers {
+                        limits::limited("max_headers");
+                        return Ok(Response::builder()
+                            .status(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE)
+                            .body(Body::empty())
+                            .unwrap());
+                    }
+                    traced(&register, remote_addr, req, intercepters, sh).await
+                }
+            }))
         }
+    });
+
+    tracing::info!(addr = %addr, "gateway listening");
+
+    let mut server = Server::builder(incoming).http1_max_buf_size(listener.max_header_bytes);
+    if listener.header_read_timeout_secs > 0 {
+        let timeout = Duration::from_secs(listener.header_read_timeout_secs);
+        server = server.http1_header_read_timeout(timeout);
     }
+    server
+        .serve(make_svc)
+        .with_graceful_shutdown(shutdown.reached(Phase::StopAccepting))
+        .await
+        .map_err(|source| ServiceError::Serve { addr, source })
 }
 
-pub async fn run(addr: String, intercepters: &'static [Intercepter], sh: Option<ServeHTTP>) {
-    dotenv::dotenv().ok();
-
-    let (ctx, handle) = Context::new();
-    let wg = WaitGroup::new();
+// the plain http listeners sending clients on to https: that of
+// `http_redirect` and the one answering the http-01 challenges of `acme`.
+fn redirects(gateway: &config::GatewayConfig) -> Vec<config::HttpRedirectConfig> {
+    let mut redirects = gateway.http_redirect.iter().cloned().collect::<Vec<_>>();
+    if let Some(addr) = gateway.acme.as_ref().and_then(|a| a.http_listen.as_ref()) {
+        if !redirects.iter().any(|r| &r.listen == addr) {
+            redirects.push(config::HttpRedirectConfig {
+                listen: addr.clone(),
+                ..Default::default()
+            });
+        }
+    }
+    redirects
+}
 
-    let register_type_name =
-        ::std::env::var("REGISTER_TYPE").unwrap_or_else(|_| Mongodb.as_str().into());
+async fn serve(
+    addrs: Vec<String>,
+    intercepters: &'static [Intercepter],
+    sh: Option<ServeHTTP>,
+) -> Result<(), ServiceError> {
+    // every address is bound before anything is served.
+    let mut servers = vec![];
+    for addr in addrs {
+        let socket = addr
+            .parse::<SocketAddr>()
+            .map_err(|_| ServiceError::InvalidAddr(addr.clone()))?;
+        let incoming = AddrIncoming::bind(&socket).map_err(|source| ServiceError::Bind {
+            addr: addr.clone(),
+            source,
+        })?;
+        let max_per_ip = config::current().gateway.listener.max_connections_per_ip;
+        servers.push((addr, limits::per_ip(incoming, max_per_ip)));
+    }
 
-    plugin::init_plugin(
-        ctx,
-        wg.clone(),
-        plugin::ServiceType::ApiGateway,
-        get_plugin_type(&register_type_name),
-    )
-    .await;
+    let shutdown = Shutdown::new();
 
-    let serve = async move {
-        let register = &Register {};
-        let make_svc = make_service_fn(|conn: &AddrStream| {
-            let remote_addr = conn.remote_addr().ip();
-            async move {
-                Ok::<_, Infallible>(service_fn(move |req| {
-                    intercept(register, remote_addr, req, intercepters, sh)
-                }))
-            }
-        });
+    #[cfg(feature = "geoip")]
+    if let Some(geoip) = &config::current().gateway.geoip {
+        crate::geoip::load(geoip)?;
+    }
 
-        log::info!("Listening on {}", addr);
+    #[cfg(feature = "transcode")]
+    if let Some(config) = &config::current().gateway.transcode {
+        transcode::load(config)?;
+    }
 
-        Server::bind(&addr.parse::<SocketAddr>().expect("invalid address"))
-            .serve(make_svc)
-            .await
-            .unwrap();
+    // https on every address when configured.
+    let gateway = &config::current().gateway;
+    let tls = match (&gateway.tls, &gateway.acme) {
+        (Some(config), _) => Some(tls::acceptor(config)?),
+        #[cfg(feature = "acme")]
+        (None, Some(config)) => Some(acme::acceptor(config, &shutdown)),
+        _ => None,
     };
+    if tls.is_some() {
+        for redirect in redirects(gateway) {
+            tokio::spawn(redirect::serve(redirect, shutdown.clone())?);
+        }
+    }
 
-    tokio::select! {
-        _ = serve => {},
-        _ = tokio::signal::ctrl_c() => {
-            handle.cancel();
-            wg.wait();
-        },
+    let register = Register::new(
+        plugin::init_plugin_with(
+            shutdown.clone(),
+            plugin::ServiceType::ApiGateway,
+            config::current().plugin_config(),
+        )
+        .await?,
+    );
+    crate::notify::start(&register, &shutdown);
+    let self_register = &config::current().gateway.self_register;
+    if !self_register.service.is_empty() {
+        let listen = servers
+            .iter()
+            .map(|(addr, _)| addr.clone())
+            .collect::<Vec<_>>();
+        let own = register
+            .register_gateway(self_register, &listen, tls.is_some())
+            .await
+            .map_err(|source| ServiceError::Register {
+                name: self_register.service.clone(),
+                source,
+            })?;
+        crate::peers::start(&register, own, &shutdown);
+    }
+    crate::audit::record(
+        AuditEvent::new(AuditKind::RoutesLoaded, "start")
+            .detail("routes", config::current().gateway.routes.len()),
+    );
+    #[cfg(feature = "graphql")]
+    graphql::start(&register, &shutdown);
+    // the trust bundle registrations are verified against.
+    #[cfg(feature = "spiffe")]
+    crate::spiffe::start();
+    if let Some(service) = &config::current().registry.connect {
+        crate::mesh::join(&register, service, &shutdown)
+            .await
+            .map_err(|e| ServiceError::Tls(format!("join mesh as {}: {:#}", service, e)))?;
     }
+
+    let serve = futures::future::try_join_all(servers.into_iter().map(|(addr, incoming)| {
+        let (register, tls) = (register.clone(), tls.clone());
+        let shutdown = &shutdown;
+        let draining = shutdown.guard(Phase::Drain);
+        async move {
+            // in-flight requests finish before the drain phase ends.
+            let served = match tls {
+                Some(acceptor) => {
+                    let incoming = tls::incoming(incoming, acceptor, shutdown.clone());
+                    serve_on(addr, incoming, register, intercepters, sh, shutdown).await
+                }
+                None => serve_on(addr, incoming, register, intercepters, sh, shutdown).await,
+            };
+            drop(draining);
+            served
+        }
+    }));
+
+    let serve = async {
+        let served = tokio::select! {
+            served = serve => served,
+            // past the drain deadline, the remaining requests are dropped.
+            _ = shutdown.reached(Phase::Deregister) => Ok(vec![]),
+        };
+        // a failed server takes the others down with it.
+        shutdown.trigger();
+        served
+    };
+    let (served, ()) = tokio::join!(serve, shutdown.run());
+    served?;
+    Ok(())
 }
@@ -0,0 +1,356 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use futures::StreamExt;
+use hyper::body::HttpBody;
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::header::{CONTENT_LENGTH, UPGRADE};
+use hyper::{Body, Method, Request, Response, Uri};
+use once_cell::sync::Lazy;
+
+use crate::config::{self, Priority, Route, TenantConfig};
+use crate::{Instance, LoadBalancerAlgorithm, MetricsRegistry};
+
+// where a request asks for the algorithm its instance is picked with, see
+// `RoutePolicy::lb_requests`.
+const LB_HEADER: &str = "x-crossgate-lb";
+const LB_PARAM: &str = "crossgate_lb";
+
+// the tokens left of each rate limited route, by its key and tenant, and of
+// each rate limited tenant.
+static BUCKETS: Lazy<Mutex<HashMap<String, Bucket>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+struct Bucket {
+    tokens: f64,
+    at: Instant,
+}
+
+// refilled at `rate` a second up to `rate`, one token a request.
+fn take(bucket: &mut Bucket, rate: u32, now: Instant) -> bool {
+    let refill = now.saturating_duration_since(bucket.at).as_secs_f64() * rate as f64;
+    bucket.tokens = (bucket.tokens + refill).min(rate as f64);
+    bucket.at = now;
+    if bucket.tokens < 1.0 {
+        return false;
+    }
+    bucket.tokens -= 1.0;
+    true
+}
+
+// the policy of the route of a request, resolved once as it comes in.
+pub(super) struct Policy {
+    route: Option<&'static Route>,
+    tenant: Option<&'static TenantConfig>,
+    pub(super) timeout: Option<Duration>,
+    pub(super) retries: u32,
+    pub(super) lba: Option<LoadBalancerAlgorithm>,
+    pub(super) priority: Priority,
+}
+
+impl Policy {
+    pub(super) fn resolve(
+        route: Option<&'static Route>,
+        tenant: Option<&'static TenantConfig>,
+    ) -> Self {
+        let config = config::current();
+        Self {
+            route,
+            tenant,
+            timeout: config.route_timeout(route),
+            // a long poll answered late is not one that failed.
+            retries: route
+                .filter(|r| !r.policy.long_poll)
+                .map_or(0, |r| r.policy.retries),
+            lba: config.route_lba(route),
+            priority: route.map_or(Priority::default(), |r| r.policy.priority),
+        }
+    }
+
+    // false once the tenant or the route used up its rate.
+    pub(super) fn admit(&self) -> bool {
+        let tenant = self.tenant.map_or("", |t| t.name.as_str());
+        let mut limits = vec![];
+        if let Some(t) = self.tenant.filter(|t| t.rate_limit > 0) {
+            limits.push((format!("tenant {}", t.name), t.rate_limit));
+        }
+        if let Some(route) = self.route.filter(|r| r.policy.rate_limit > 0) {
+            limits.push((
+                format!("{} {}", tenant, route.key()),
+                route.policy.rate_limit,
+            ));
+        }
+        let now = Instant::now();
+        let mut buckets = BUCKETS.lock().unwrap();
+        limits.into_iter().all(|(key, rate)| {
+            let bucket = buckets.entry(key).or_insert(Bucket {
+                tokens: rate as f64,
+                at: now,
+            });
+            take(bucket, rate, now)
+        })
+    }
+
+    // Err with the limit when the body of `req` says it is too large;
+    // bodies of no stated length are cut off past it.
+    pub(super) fn limit_body(&self, req: &mut Request<Body>) -> Result<(), u64> {
+        let limit = match self.route.and_then(|r| r.policy.body_limit) {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+        let length = req
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok());
+        match length {
+            Some(length) if length > limit => return Err(limit),
+            Some(_) => return Ok(()),
+            None if req.body().is_end_stream() => return Ok(()),
+            None => {}
+        }
+
+        let body = std::mem::take(req.body_mut());
+        let mut read = 0;
+        let body = body.map(move |chunk| {
+            let chunk = chunk?;
+            read += chunk.len() as u64;
+            if read > limit {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("request body over {} bytes", limit),
+                )
+                .into());
+            }
+            Ok::<_, Box<dyn std::error::Error + Send + Sync>>(chunk)
+        });
+        *req.body_mut() = Body::wrap_stream(body);
+        Ok(())
+    }
+}
+
+impl Policy {
+    // the algorithm `req` asks for when its route allows it; the ask is
+    // taken off the request either way, the upstream does not see it.
+    pub(super) fn requested_lba(&self, req: &mut Request<Body>) -> Option<LoadBalancerAlgorithm> {
+        let header = req
+            .headers_mut()
+            .remove(LB_HEADER)
+            .and_then(|v| v.to_str().ok().map(str::to_string));
+        let param = take_param(req, LB_PARAM);
+        let requested = header.or(param)?;
+        let lba = config::current().requested_lba(self.route, &requested);
+        let status = match lba {
+            Some(_) => "applied",
+            None => "refused",
+        };
+        MetricsRegistry::global()
+            .counter("crossgate_gateway_lb_requests_total", &[("status", status)])
+            .inc();
+        lba
+    }
+}
+
+impl Policy {
+    // sets the `response_headers` of the route on `res` from what
+    // `instance` registered, leaving out those it has no value for.
+    pub(super) fn annotate(&self, instance: &Instance, res: &mut Response<Body>) {
+        let headers = match self.route {
+            Some(route) => &route.policy.response_headers,
+            None => return,
+        };
+        for (name, source) in headers {
+            let value = match upstream_value(instance, source) {
+                Some(value) => value,
+                None => continue,
+            };
+            if let (Ok(name), Ok(value)) = (
+                HeaderName::from_bytes(name.as_bytes()),
+                HeaderValue::from_str(&value),
+            ) {
+                res.headers_mut().insert(name, value);
+            }
+        }
+    }
+}
+
+// what `instance` registered as `source`, None when it is empty.
+fn upstream_value(instance: &Instance, source: &str) -> Option<String> {
+    let value = match source {
+        "addr" => instance.addr.clone(),
+        "version" => instance.version.clone(),
+        "weight" => instance.weight.to_string(),
+        _ => instance
+            .metadata
+            .get(source.strip_prefix("metadata.")?)?
+            .clone(),
+    };
+    (!value.is_empty()).then_some(value)
+}
+
+// `%xx` and `+` of a query value decoded.
+pub(super) fn unescape(s: &str) -> String {
+    let s = s.as_bytes();
+    let mut out = Vec::with_capacity(s.len());
+    let mut i = 0;
+    while i < s.len() {
+        let hex = s
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (s[i], hex) {
+            (b'%', Some(b)) => {
+                out.push(b);
+                i += 3;
+                continue;
+            }
+            (b'+', _) => out.push(b' '),
+            (b, _) => out.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+// removes the parameter `name` from the query of `req`, its value when it
+// had one.
+fn take_param(req: &mut Request<Body>, name: &str) -> Option<String> {
+    let query = req.uri().query()?;
+    let mut value = None;
+    let kept = query
+        .split('&')
+        .filter(|pair| match pair.split_once('=') {
+            Some((k, v)) if k == name => {
+                value = Some(unescape(v));
+                false
+            }
+            _ => *pair != name,
+        })
+        .collect::<Vec<_>>()
+        .join("&");
+    value.as_ref()?;
+
+    let path_and_query = match kept.is_empty() {
+        true => req.uri().path().to_string(),
+        false => format!("{}?{}", req.uri().path(), kept),
+    };
+    let mut parts = req.uri().clone().into_parts();
+    parts.path_and_query = path_and_query.parse().ok();
+    if let Ok(uri) = Uri::from_parts(parts) {
+        *req.uri_mut() = uri;
+    }
+    value
+}
+
+// a copy of `req` to try again with, None unless it can be sent twice.
+pub(super) fn replay(req: &Request<Body>) -> Option<Request<Body>> {
+    if !matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS)
+        || !req.body().is_end_stream()
+        || req.headers().contains_key(UPGRADE)
+    {
+        return None;
+    }
+    let mut copy = Request::new(Body::empty());
+    *copy.method_mut() = req.method().clone();
+    *copy.uri_mut() = req.uri().clone();
+    *copy.version_mut() = req.version();
+    *copy.headers_mut() = req.headers().clone();
+    Some(copy)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lb_requests_are_taken_off_the_query() {
+        let mut req = Request::get("/t/ums/users?a=1&crossgate_lb=consistent_hash%3Auser-42&b=2")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(
+            take_param(&mut req, LB_PARAM).as_deref(),
+            Some("consistent_hash:user-42")
+        );
+        assert_eq!(req.uri(), "/t/ums/users?a=1&b=2");
+        assert_eq!(take_param(&mut req, LB_PARAM), None);
+
+        let mut req = Request::get("/t/ums/users?crossgate_lb=random")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(take_param(&mut req, LB_PARAM).as_deref(), Some("random"));
+        assert_eq!(req.uri(), "/t/ums/users");
+    }
+
+    #[test]
+    fn buckets_refill_at_the_rate() {
+        let start = Instant::now();
+        let mut bucket = Bucket {
+            tokens: 2.0,
+            at: start,
+        };
+        assert!(take(&mut bucket, 2, start));
+        assert!(take(&mut bucket, 2, start));
+        assert!(!take(&mut bucket, 2, start));
+        assert!(take(&mut bucket, 2, start + Duration::from_millis(500)));
+        assert!(!take(&mut bucket, 2, start + Duration::from_millis(600)));
+        // no more than a burst is saved up.
+        let later = start + Duration::from_secs(60);
+        assert!(take(&mut bucket, 2, later));
+        assert!(take(&mut bucket, 2, later));
+        assert!(!take(&mut bucket, 2, later));
+    }
+
+    #[test]
+    fn long_polls_are_not_retried() {
+        let route = |long_poll| -> &'static Route {
+            Box::leak(Box::new(Route {
+                prefix: "/t/feed".to_string(),
+                pattern: None,
+                service: "/t/feed".to_string(),
+                upstreams: vec![],
+                rewrite: None,
+                policy: crate::config::RoutePolicy {
+                    retries: 2,
+                    long_poll,
+                    ..Default::default()
+                },
+            }))
+        };
+        assert_eq!(Policy::resolve(Some(route(false)), None).retries, 2);
+        assert_eq!(Policy::resolve(Some(route(true)), None).retries, 0);
+        assert_eq!(Policy::resolve(None, None).retries, 0);
+    }
+
+    #[test]
+    fn only_bodiless_reads_are_replayed() {
+        let get = Request::get("/t/ums/1")
+            .header("x-a", "1")
+            .body(Body::empty())
+            .unwrap();
+        let copy = replay(&get).unwrap();
+        assert_eq!(copy.uri(), "/t/ums/1");
+        assert_eq!(copy.headers()["x-a"], "1");
+
+        let post = Request::post("/t/ums/1").body(Body::empty()).unwrap();
+        assert!(replay(&post).is_none());
+        let get = Request::get("/t/ums/1").body(Body::from("x")).unwrap();
+        assert!(replay(&get).is_none());
+    }
+
+    #[test]
+    fn response_headers_come_from_the_registration() {
+        let instance = Instance {
+            addr: "10.0.0.1:80".to_string(),
+            weight: 5,
+            metadata: [("track".to_string(), "canary".to_string())].into(),
+            ..Default::default()
+        };
+        let value = |source: &str| upstream_value(&instance, source);
+        assert_eq!(value("addr").as_deref(), Some("10.0.0.1:80"));
+        assert_eq!(value("weight").as_deref(), Some("5"));
+        assert_eq!(value("metadata.track").as_deref(), Some("canary"));
+        // left out when not registered.
+        assert_eq!(value("version"), None);
+        assert_eq!(value("metadata.zone"), None);
+    }
+}
@@ -0,0 +1,138 @@
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+
+use hyper::header::{HOST, LOCATION};
+use hyper::http::uri::Authority;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use net::{Phase, Shutdown};
+
+use crate::config::{self, HttpRedirectConfig};
+use crate::ServiceError;
+
+// the https port clients are sent to: the configured one, else that of the
+// first listen address.
+fn https_port(config: &HttpRedirectConfig) -> u16 {
+    config.https_port.unwrap_or_else(|| {
+        config::current()
+            .listen()
+            .first()
+            .and_then(|addr| addr.parse::<SocketAddr>().ok())
+            .map_or(443, |addr| addr.port())
+    })
+}
+
+// the https url of the host and path of `req`, None without a host.
+fn location(req: &Request<Body>, https_port: u16) -> Option<String> {
+    let authority = match req.headers().get(HOST) {
+        Some(host) => host.to_str().ok()?.parse::<Authority>().ok()?,
+        None => req.uri().authority()?.clone(),
+    };
+    let path = req.uri().path_and_query().map_or("/", |p| p.as_str());
+    Some(match https_port {
+        443 => format!("https://{}{}", authority.host(), path),
+        port => format!("https://{}:{}{}", authority.host(), port, path),
+    })
+}
+
+fn answer(config: &HttpRedirectConfig, https_port: u16, req: &Request<Body>) -> Response<Body> {
+    #[cfg(feature = "acme")]
+    if let Some(res) = super::acme::challenge(req) {
+        return res;
+    }
+
+    let location = location(req, https_port).and_then(|l| l.parse().ok());
+    let status = match req.method() {
+        &Method::GET | &Method::HEAD => {
+            StatusCode::from_u16(config.status).unwrap_or(StatusCode::PERMANENT_REDIRECT)
+        }
+        _ => StatusCode::PERMANENT_REDIRECT,
+    };
+    let mut res = Response::new(Body::empty());
+    match location {
+        Some(location) => {
+            *res.status_mut() = status;
+            res.headers_mut().insert(LOCATION, location);
+        }
+        None => *res.status_mut() = StatusCode::BAD_REQUEST,
+    }
+    res
+}
+
+// the plain http listener of `config`, bound already.
+pub(super) fn serve(
+    config: HttpRedirectConfig,
+    shutdown: Shutdown,
+) -> Result<impl Future<Output = ()>, ServiceError> {
+    let addr = config.listen.clone();
+    let socket = addr
+        .parse::<SocketAddr>()
+        .map_err(|_| ServiceError::InvalidAddr(addr.clone()))?;
+    let server = Server::try_bind(&socket).map_err(|source| ServiceError::Bind {
+        addr: addr.clone(),
+        source,
+    })?;
+    let https_port = https_port(&config);
+    let make_svc = make_service_fn(move |_| {
+        let config = config.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let res = answer(&config, https_port, &req);
+                async move { Ok::<_, Infallible>(res) }
+            }))
+        }
+    });
+
+    tracing::info!(addr = %addr, https_port, "redirecting http to https");
+    Ok(async move {
+        let served = server
+            .serve(make_svc)
+            .with_graceful_shutdown(async move { shutdown.reached(Phase::StopAccepting).await })
+            .await;
+        if let Err(e) = served {
+            tracing::error!(addr = %addr, error = %e, "http redirect listener failed");
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_are_sent_to_the_same_url_over_https() {
+        let config = HttpRedirectConfig {
+            status: 301,
+            ..Default::default()
+        };
+        let req = |method: Method, host: &str| {
+            Request::builder()
+                .method(method)
+                .uri("/t/ums/users?id=1")
+                .header(HOST, host)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let res = answer(&config, 443, &req(Method::GET, "example.org:80"));
+        assert_eq!(res.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(
+            res.headers()[LOCATION],
+            "https://example.org/t/ums/users?id=1"
+        );
+        // a POST keeps its method and body.
+        let res = answer(&config, 8443, &req(Method::POST, "[::1]"));
+        assert_eq!(res.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(
+            res.headers()[LOCATION],
+            "https://[::1]:8443/t/ums/users?id=1"
+        );
+
+        let no_host = Request::new(Body::empty());
+        assert_eq!(
+            answer(&config, 443, &no_host).status(),
+            StatusCode::BAD_REQUEST
+        );
+    }
+}
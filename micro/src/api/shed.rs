@@ -0,0 +1,71 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::config::{self, Priority, SheddingConfig};
+use crate::MetricsRegistry;
+
+// requests forwarded by the gateway and not yet answered in full.
+static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+
+// counts a request in flight until it is dropped, with the body of its
+// response.
+pub(super) struct Slot(());
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        IN_FLIGHT.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+pub(super) fn in_flight() -> usize {
+    IN_FLIGHT.load(Ordering::SeqCst)
+}
+
+// whether a request of `priority` goes on with `in_flight` others.
+fn admitted(config: &SheddingConfig, priority: Priority, in_flight: usize) -> bool {
+    if config.max_in_flight == 0 {
+        return true;
+    }
+    match priority {
+        Priority::Critical => true,
+        Priority::Normal => in_flight < config.max_in_flight,
+        Priority::Low => in_flight * 100 < config.max_in_flight * config.low_percent,
+    }
+}
+
+// the slot of a request of `priority`, None when it is shed.
+pub(super) fn admit(priority: Priority) -> Option<Slot> {
+    let in_flight = IN_FLIGHT.fetch_add(1, Ordering::SeqCst);
+    let slot = Slot(());
+    if admitted(&config::current().gateway.shedding, priority, in_flight) {
+        return Some(slot);
+    }
+    MetricsRegistry::global()
+        .counter(
+            "crossgate_gateway_shed_total",
+            &[("priority", priority.as_str())],
+        )
+        .inc();
+    tracing::debug!(priority = priority.as_str(), in_flight, "request shed");
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn low_priority_goes_first() {
+        let config = SheddingConfig {
+            max_in_flight: 10,
+            low_percent: 70,
+        };
+        assert!(admitted(&config, Priority::Low, 6));
+        assert!(!admitted(&config, Priority::Low, 7));
+        assert!(admitted(&config, Priority::Normal, 9));
+        assert!(!admitted(&config, Priority::Normal, 10));
+        assert!(admitted(&config, Priority::Critical, 1000));
+
+        let off = SheddingConfig::default();
+        assert!(admitted(&off, Priority::Low, 1000));
+    }
+}
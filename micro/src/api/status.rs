@@ -0,0 +1,266 @@
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use hyper::header::{CONTENT_TYPE, WWW_AUTHENTICATE};
+use hyper::{Body, Request, Response, StatusCode};
+use once_cell::sync::Lazy;
+
+use crate::auth::signed_in;
+use crate::config::{self, StatusPageConfig};
+use crate::register::static_endpoint;
+use crate::Register;
+
+// how far back the error rates go.
+const WINDOW: Duration = Duration::from_secs(300);
+
+// outcomes kept per instance, the oldest are dropped beyond.
+const MAX_OUTCOMES: usize = 10_000;
+
+// when each request forwarded to an instance was answered and whether with
+// a server error, by service and address.
+type Outcomes = BTreeMap<String, BTreeMap<String, VecDeque<(Instant, bool)>>>;
+
+static RECENT: Lazy<Mutex<Outcomes>> = Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+// counts the answer of `addr` of `service` towards its error rate.
+pub(super) fn observe(service: &str, addr: &str, status: StatusCode) {
+    if !config::current().gateway.status.enabled {
+        return;
+    }
+    let now = Instant::now();
+    let mut recent = RECENT.lock().unwrap();
+    let outcomes = recent
+        .entry(service.to_string())
+        .or_default()
+        .entry(addr.to_string())
+        .or_default();
+    while outcomes
+        .front()
+        .is_some_and(|(at, _)| now.duration_since(*at) > WINDOW)
+        || outcomes.len() >= MAX_OUTCOMES
+    {
+        outcomes.pop_front();
+    }
+    outcomes.push_back((now, status.is_server_error()));
+}
+
+// requests and server errors of each instance of `service` in the window;
+// instances without any are forgotten.
+fn rates(service: &str) -> BTreeMap<String, (usize, usize)> {
+    let now = Instant::now();
+    let mut recent = RECENT.lock().unwrap();
+    let instances = match recent.get_mut(service) {
+        Some(instances) => instances,
+        None => return BTreeMap::new(),
+    };
+    for outcomes in instances.values_mut() {
+        while outcomes
+            .front()
+            .is_some_and(|(at, _)| now.duration_since(*at) > WINDOW)
+        {
+            outcomes.pop_front();
+        }
+    }
+    instances.retain(|_, outcomes| !outcomes.is_empty());
+    let rates = instances
+        .iter()
+        .map(|(addr, outcomes)| {
+            let errors = outcomes.iter().filter(|(_, error)| *error).count();
+            (addr.clone(), (outcomes.len(), errors))
+        })
+        .collect();
+    if instances.is_empty() {
+        recent.remove(service);
+    }
+    rates
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+// a row of the page, an instance of a service.
+struct Instance {
+    addr: String,
+    health: String,
+    weight: u32,
+    requests: usize,
+    errors: usize,
+}
+
+impl Instance {
+    fn state(&self) -> &'static str {
+        match super::warmup::warming(&self.addr) {
+            Some(true) => "warming up",
+            _ if matches!(self.health.as_str(), "unhealthy" | "draining") => "no traffic",
+            _ => "routed",
+        }
+    }
+
+    fn error_rate(&self) -> String {
+        match self.requests {
+            0 => "-".to_string(),
+            n => format!("{:.1}%", self.errors as f64 * 100.0 / n as f64),
+        }
+    }
+}
+
+async fn instances(register: &Register, service: &str) -> Vec<Instance> {
+    let mut rates = rates(service);
+    let gateway = &config::current().gateway;
+    let upstreams = gateway
+        .routes
+        .iter()
+        .find(|r| r.service == service && !r.upstreams.is_empty());
+    let mut instances = match upstreams {
+        Some(route) => static_endpoint(&route.upstreams)
+            .instances()
+            .iter()
+            .map(|i| (i.addr.clone(), "static".to_string(), i.weight))
+            .collect(),
+        None => match register.plugin() {
+            Ok(plugin) => plugin
+                .get_web_service(service)
+                .await
+                .unwrap_or_default()
+                .into_iter()
+                .map(|c| {
+                    let health = c.health.map_or("unknown".to_string(), |h| {
+                        format!("{:?}", h.status).to_lowercase()
+                    });
+                    (c.addr, health, c.weight)
+                })
+                .collect(),
+            Err(_) => vec![],
+        },
+    }
+    .into_iter()
+    .map(|(addr, health, weight)| {
+        let (requests, errors) = rates.remove(&addr).unwrap_or_default();
+        Instance {
+            addr,
+            health,
+            weight,
+            requests,
+            errors,
+        }
+    })
+    .collect::<Vec<_>>();
+    // answered recently, gone from the registry since.
+    instances.extend(
+        rates
+            .into_iter()
+            .map(|(addr, (requests, errors))| Instance {
+                addr,
+                health: "gone".to_string(),
+                weight: 0,
+                requests,
+                errors,
+            }),
+    );
+    instances
+}
+
+async fn render(register: &Register) -> String {
+    let gateway = &config::current().gateway;
+    let mut services = gateway
+        .routes
+        .iter()
+        .map(|r| r.service.clone())
+        .collect::<BTreeSet<_>>();
+    services.extend(RECENT.lock().unwrap().keys().cloned());
+
+    let mut html = String::from(
+        "<!doctype html>\n<html>\n<head>\n<title>crossgate</title>\n<style>\
+         body { font-family: sans-serif; } table { border-collapse: collapse; } \
+         td, th { padding: 2px 12px; text-align: left; }\
+         </style>\n</head>\n<body>\n<h1>crossgate api gateway</h1>\n",
+    );
+    let _ = writeln!(
+        html,
+        "<p>{} requests in flight, error rates over the last {} minutes.</p>",
+        super::shed::in_flight(),
+        WINDOW.as_secs() / 60
+    );
+    for service in services {
+        let _ = writeln!(html, "<h2>{}</h2>", escape(&service));
+        let instances = instances(register, &service).await;
+        if instances.is_empty() {
+            html.push_str("<p>no instances</p>\n");
+            continue;
+        }
+        html.push_str(
+            "<table>\n<tr><th>instance</th><th>health</th><th>weight</th>\
+             <th>state</th><th>requests</th><th>errors</th></tr>\n",
+        );
+        for instance in instances {
+            let _ = writeln!(
+                html,
+                "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                escape(&instance.addr),
+                escape(&instance.health),
+                instance.weight,
+                instance.state(),
+                instance.requests,
+                instance.error_rate()
+            );
+        }
+        html.push_str("</table>\n");
+    }
+    html.push_str("</body>\n</html>\n");
+    html
+}
+
+// the status page, for those signed in with `config.basic_auth` when it is
+// set; the auth middleware stands before it otherwise.
+pub(super) async fn page(
+    config: &StatusPageConfig,
+    register: &Register,
+    req: &Request<Body>,
+) -> Response<Body> {
+    if let Some(credentials) = &config.basic_auth {
+        if !signed_in(req, credentials) {
+            return Response::builder()
+                .status(StatusCode::UNAUTHORIZED)
+                .header(WWW_AUTHENTICATE, "Basic realm=\"crossgate\"")
+                .body(Body::empty())
+                .unwrap();
+        }
+    }
+    Response::builder()
+        .header(CONTENT_TYPE, "text/html; charset=utf-8")
+        .body(Body::from(render(register).await))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::Engine;
+    use hyper::header::AUTHORIZATION;
+
+    use super::*;
+
+    #[test]
+    fn basic_auth_and_escaping() {
+        let encoded = base64::engine::general_purpose::STANDARD.encode("ops:secret");
+        let req = Request::get("/")
+            .header(AUTHORIZATION, format!("Basic {}", encoded))
+            .body(Body::empty())
+            .unwrap();
+        assert!(signed_in(&req, "ops:secret"));
+        assert!(!signed_in(&req, "ops:other"));
+        let req = Request::get("/").body(Body::empty()).unwrap();
+        assert!(!signed_in(&req, "ops:secret"));
+
+        assert_eq!(
+            escape("<b>\"/t/x\"</b>"),
+            "&lt;b&gt;&quot;/t/x&quot;&lt;/b&gt;"
+        );
+    }
+}
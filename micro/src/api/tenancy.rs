@@ -0,0 +1,197 @@
+use hyper::header::HOST;
+use hyper::http::uri::Authority;
+use hyper::{Body, Request, Uri};
+use sha2::{Digest, Sha256};
+
+use crate::config::{self, Route, TenantConfig};
+
+/// The tenant of a request, in its extensions with `gateway.tenancy`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tenant(pub String);
+
+// whether `key`, as a request carries it, is one of `keys`, as the config
+// lists them.
+fn key_listed(keys: &[String], key: &str) -> bool {
+    let digest = Sha256::digest(key.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+    keys.iter().any(|k| match k.strip_prefix("sha256:") {
+        Some(listed) => listed.eq_ignore_ascii_case(&digest),
+        None => k == key,
+    })
+}
+
+// the path of `path` under `prefix`, None when it is not.
+fn under<'a>(prefix: &str, path: &'a str) -> Option<&'a str> {
+    let rest = path.strip_prefix(prefix.trim_end_matches('/'))?;
+    match rest {
+        "" => Some("/"),
+        rest if rest.starts_with('/') => Some(rest),
+        _ => None,
+    }
+}
+
+// the tenant of `req` among `tenants`: by its api key, else its host, else
+// its path.
+fn find<'a>(tenants: &'a [TenantConfig], req: &Request<Body>) -> Option<&'a TenantConfig> {
+    let auth = &config::current().gateway.auth;
+    let key = req
+        .headers()
+        .get(auth.api_key_header.as_str())
+        .and_then(|v| v.to_str().ok())
+        .filter(|key| !key.is_empty());
+    if let Some(key) = key {
+        if let Some(tenant) = tenants.iter().find(|t| key_listed(&t.api_keys, key)) {
+            return Some(tenant);
+        }
+    }
+
+    let host = match req.headers().get(HOST) {
+        Some(host) => host.to_str().ok().and_then(|h| h.parse::<Authority>().ok()),
+        None => req.uri().authority().cloned(),
+    };
+    if let Some(host) = host.as_ref().map(Authority::host) {
+        let by_host = tenants
+            .iter()
+            .find(|t| t.hosts.iter().any(|h| h.eq_ignore_ascii_case(host)));
+        if by_host.is_some() {
+            return by_host;
+        }
+    }
+
+    tenants.iter().find(|t| {
+        t.path_prefix
+            .as_deref()
+            .is_some_and(|prefix| under(prefix, req.uri().path()).is_some())
+    })
+}
+
+// the tenant of `req`, noted in its extensions, its path prefix taken off
+// the path.
+pub(super) fn identify(req: &mut Request<Body>) -> Option<&'static TenantConfig> {
+    let tenant = find(&config::current().gateway.tenancy.tenants, req)?;
+    if let Some(prefix) = &tenant.path_prefix {
+        if let Some(path) = under(prefix, req.uri().path()) {
+            let path_and_query = match req.uri().query() {
+                Some(query) => format!("{}?{}", path, query),
+                None => path.to_string(),
+            };
+            let mut parts = req.uri().clone().into_parts();
+            parts.path_and_query = path_and_query.parse().ok();
+            if let Ok(uri) = Uri::from_parts(parts) {
+                *req.uri_mut() = uri;
+            }
+        }
+    }
+    req.extensions_mut().insert(Tenant(tenant.name.clone()));
+    Some(tenant)
+}
+
+// the tenant `identify` found for `req`.
+pub(super) fn of(req: &Request<Body>) -> Option<&'static TenantConfig> {
+    let name = &req.extensions().get::<Tenant>()?.0;
+    config::current()
+        .gateway
+        .tenancy
+        .tenants
+        .iter()
+        .find(|t| &t.name == name)
+}
+
+// the route `tenant` has for `req`. Its requests reach the services it
+// routes to and nothing else the gateway serves, e.g. the tasks or
+// graphql, so None refuses them.
+pub(super) fn route_for<'a>(tenant: &'a TenantConfig, req: &Request<Body>) -> Option<&'a Route> {
+    tenant.matching_route(req.uri().path())
+}
+
+// the route of `req`, among those of its tenant when it has one.
+pub(crate) fn route_of(req: &Request<Body>) -> Option<&'static Route> {
+    match of(req) {
+        Some(tenant) => tenant.matching_route(req.uri().path()),
+        None => config::current().matching_route(req.uri().path()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tenants_by_key_host_then_path() {
+        let tenant = |name: &str, key: &str, host: &str, prefix: &str| TenantConfig {
+            name: name.to_string(),
+            api_keys: vec![key.to_string()],
+            hosts: vec![host.to_string()],
+            path_prefix: Some(prefix.to_string()),
+            ..Default::default()
+        };
+        let tenants = vec![
+            tenant("acme", "acme-key", "api.acme.example", "/acme"),
+            // the sha256 of "globex-key".
+            tenant(
+                "globex",
+                "sha256:f2a455b59b858a04c51108416af2042203cb6ac35fa4141fba4148bc856e78d4",
+                "api.globex.example",
+                "/globex",
+            ),
+        ];
+        let req = |path: &str, host: &str, key: Option<&str>| {
+            let mut req = Request::get(path).header(HOST, host);
+            if let Some(key) = key {
+                req = req.header(config::current().gateway.auth.api_key_header.as_str(), key);
+            }
+            req.body(Body::empty()).unwrap()
+        };
+        let name = |req: &Request<Body>| find(&tenants, req).map(|t| t.name.as_str());
+
+        assert_eq!(name(&req("/t/ums", "gw", Some("acme-key"))), Some("acme"));
+        // the key goes before the host and the path.
+        assert_eq!(
+            name(&req(
+                "/globex/t/ums",
+                "api.globex.example",
+                Some("acme-key")
+            )),
+            Some("acme")
+        );
+        assert_eq!(
+            name(&req("/t/ums", "API.globex.example:443", None)),
+            Some("globex")
+        );
+        assert_eq!(name(&req("/globex/t/ums", "gw", None)), Some("globex"));
+        assert_eq!(name(&req("/globexx/t/ums", "gw", None)), None);
+        assert_eq!(
+            name(&req("/t/ums", "gw", Some("globex-key"))),
+            Some("globex")
+        );
+        assert_eq!(name(&req("/t/ums", "gw", Some("other-key"))), None);
+
+        assert_eq!(under("/acme/", "/acme/t/ums"), Some("/t/ums"));
+        assert_eq!(under("/acme", "/acme"), Some("/"));
+    }
+
+    #[test]
+    fn tenants_reach_their_routes_only() {
+        let tenant = TenantConfig {
+            name: "acme".to_string(),
+            routes: vec![Route {
+                prefix: "/t/ums".to_string(),
+                pattern: None,
+                service: "/t/ums".to_string(),
+                upstreams: vec![],
+                rewrite: None,
+                policy: Default::default(),
+            }],
+            ..Default::default()
+        };
+        let req = |path: &str| Request::get(path).body(Body::empty()).unwrap();
+
+        let route = route_for(&tenant, &req("/t/ums/user/login")).unwrap();
+        assert_eq!(route.service, "/t/ums");
+        for path in ["/tasks/jobs", "/tasks/jobs/compact", "/graphql", "/"] {
+            assert!(route_for(&tenant, &req(path)).is_none(), "{}", path);
+        }
+    }
+}
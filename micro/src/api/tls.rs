@@ -0,0 +1,284 @@
+use std::collections::HashMap;
+use std::{fs::File, io::BufReader, net::SocketAddr, pin::Pin, sync::Arc, time::Duration};
+
+use hyper::server::accept::Accept;
+use hyper::server::conn::AddrStream;
+use net::{Phase, Shutdown};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::mpsc;
+use tokio_rustls::rustls::server::{
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, ClientCertVerifier,
+    ClientHello, ResolvesServerCert,
+};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::{rustls, server::TlsStream, TlsAcceptor};
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+use crate::config::{ClientAuthConfig, GatewayTlsConfig};
+use crate::ServiceError;
+
+// the protocol of tls-alpn-01 challenges, RFC 8737.
+pub(super) const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+
+// a client that has not finished the handshake by then is dropped.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// the connections the gateway serves, plain or over tls.
+pub(super) trait Peer {
+    fn peer(&self) -> SocketAddr;
+
+    // the sni the client sent.
+    fn server_name(&self) -> Option<&str> {
+        None
+    }
+
+    // the verified certificate the client presented.
+    fn client_identity(&self) -> Option<ClientIdentity> {
+        None
+    }
+}
+
+impl Peer for AddrStream {
+    fn peer(&self) -> SocketAddr {
+        self.remote_addr()
+    }
+}
+
+impl<S: Peer> Peer for TlsStream<S> {
+    fn peer(&self) -> SocketAddr {
+        self.get_ref().0.peer()
+    }
+
+    fn server_name(&self) -> Option<&str> {
+        self.get_ref().1.server_name()
+    }
+
+    fn client_identity(&self) -> Option<ClientIdentity> {
+        let cert = self.get_ref().1.peer_certificates()?.first()?;
+        ClientIdentity::from_der(&cert.0)
+    }
+}
+
+// the sni of the connection a request came on, in its extensions.
+#[derive(Debug, Clone)]
+pub(super) struct ServerName(pub String);
+
+/// The certificate a client presented on the connection of a request, as
+/// verified against `gateway.tls.client_auth`; in its extensions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientIdentity {
+    // the distinguished name, e.g. CN=billing, O=example.
+    pub subject: String,
+    pub common_name: Option<String>,
+    pub dns_names: Vec<String>,
+    // e.g. a spiffe id.
+    pub uris: Vec<String>,
+    // hex.
+    pub serial: String,
+}
+
+impl ClientIdentity {
+    fn from_der(der: &[u8]) -> Option<Self> {
+        let (_, cert) = X509Certificate::from_der(der).ok()?;
+        let common_name = cert
+            .subject()
+            .iter_common_name()
+            .next()
+            .and_then(|cn| cn.as_str().ok())
+            .map(str::to_string);
+        let (mut dns_names, mut uris) = (vec![], vec![]);
+        if let Ok(Some(san)) = cert.subject_alternative_name() {
+            for name in &san.value.general_names {
+                match name {
+                    GeneralName::DNSName(dns) => dns_names.push(dns.to_string()),
+                    GeneralName::URI(uri) => uris.push(uri.to_string()),
+                    _ => {}
+                }
+            }
+        }
+        Some(Self {
+            subject: cert.subject().to_string(),
+            common_name,
+            dns_names,
+            uris,
+            serial: cert.raw_serial_as_string().replace(':', ""),
+        })
+    }
+}
+
+fn read(path: &str) -> Result<BufReader<File>, ServiceError> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| ServiceError::Tls(format!("open {}: {}", path, e)))
+}
+
+// the chain in the pem file `cert` with its private key in `key`.
+fn certified_key(cert: &str, key_path: &str) -> Result<Arc<CertifiedKey>, ServiceError> {
+    let certs = rustls_pemfile::certs(&mut read(cert)?)
+        .map_err(|e| ServiceError::Tls(format!("read {}: {}", cert, e)))?;
+    if certs.is_empty() {
+        return Err(ServiceError::Tls(format!("no certificate in {}", cert)));
+    }
+
+    let mut keys = read(key_path)?;
+    let key = loop {
+        match rustls_pemfile::read_one(&mut keys) {
+            Ok(Some(
+                rustls_pemfile::Item::PKCS8Key(key)
+                | rustls_pemfile::Item::RSAKey(key)
+                | rustls_pemfile::Item::ECKey(key),
+            )) => break key,
+            Ok(Some(_)) => continue,
+            Ok(None) => return Err(ServiceError::Tls(format!("no private key in {}", key_path))),
+            Err(e) => return Err(ServiceError::Tls(format!("read {}: {}", key_path, e))),
+        }
+    };
+    let key = rustls::sign::any_supported_type(&rustls::PrivateKey(key))
+        .map_err(|e| ServiceError::Tls(format!("{}: {}", key_path, e)))?;
+    Ok(Arc::new(CertifiedKey::new(
+        certs.into_iter().map(rustls::Certificate).collect(),
+        key,
+    )))
+}
+
+// the certificate of the host a client asks for, the default one for
+// clients without sni and names of no host.
+struct Hosts {
+    config: GatewayTlsConfig,
+    default: Arc<CertifiedKey>,
+    // by server name, of the hosts with a certificate.
+    certs: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for Hosts {
+    fn resolve(&self, hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let cert = hello
+            .server_name()
+            .and_then(|name| self.config.host(name))
+            .and_then(|host| self.certs.get(&host.server_name));
+        Some(cert.unwrap_or(&self.default).clone())
+    }
+}
+
+fn client_verifier(config: &ClientAuthConfig) -> Result<Arc<dyn ClientCertVerifier>, ServiceError> {
+    let certs = rustls_pemfile::certs(&mut read(&config.ca)?)
+        .map_err(|e| ServiceError::Tls(format!("read {}: {}", config.ca, e)))?;
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in certs {
+        roots
+            .add(&rustls::Certificate(cert))
+            .map_err(|e| ServiceError::Tls(format!("{}: {}", config.ca, e)))?;
+    }
+    if roots.is_empty() {
+        return Err(ServiceError::Tls(format!(
+            "no certificate in {}",
+            config.ca
+        )));
+    }
+    Ok(match config.required {
+        true => AllowAnyAuthenticatedClient::new(roots).boxed(),
+        false => AllowAnyAnonymousOrAuthenticatedClient::new(roots).boxed(),
+    })
+}
+
+// the files of `config` are read once, at startup.
+pub(super) fn acceptor(config: &GatewayTlsConfig) -> Result<TlsAcceptor, ServiceError> {
+    let mut certs = HashMap::new();
+    for host in &config.hosts {
+        if let (Some(cert), Some(key)) = (&host.cert, &host.key) {
+            certs.insert(host.server_name.clone(), certified_key(cert, key)?);
+        }
+    }
+    let hosts = Hosts {
+        config: config.clone(),
+        default: certified_key(&config.cert, &config.key)?,
+        certs,
+    };
+
+    let server = rustls::ServerConfig::builder().with_safe_defaults();
+    let mut server = match &config.client_auth {
+        Some(client_auth) => server.with_client_cert_verifier(client_verifier(client_auth)?),
+        None => server.with_no_client_auth(),
+    }
+    .with_cert_resolver(Arc::new(hosts));
+    server.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(TlsAcceptor::from(Arc::new(server)))
+}
+
+// the connections of `incoming` once their handshake is done. Handshakes run
+// concurrently, so that a slow client does not hold up the others, and no
+// connection is accepted after `shutdown` stops accepting.
+pub(super) fn incoming<I>(
+    mut incoming: I,
+    acceptor: TlsAcceptor,
+    shutdown: Shutdown,
+) -> impl Accept<Conn = TlsStream<I::Conn>, Error = std::io::Error>
+where
+    I: Accept<Error = std::io::Error> + Unpin + Send + 'static,
+    I::Conn: Peer + AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (tx, mut rx) = mpsc::channel(64);
+
+    tokio::spawn(async move {
+        loop {
+            let accept = futures::future::poll_fn(|cx| Pin::new(&mut incoming).poll_accept(cx));
+            let stream = tokio::select! {
+                stream = accept => stream,
+                _ = shutdown.reached(Phase::StopAccepting) => return,
+                // the server is gone.
+                _ = tx.closed() => return,
+            };
+            let stream = match stream {
+                Some(Ok(stream)) => stream,
+                Some(Err(e)) => {
+                    tracing::warn!(error = %e, "gateway accept failed");
+                    continue;
+                }
+                None => return,
+            };
+
+            let (acceptor, tx) = (acceptor.clone(), tx.clone());
+            tokio::spawn(async move {
+                let peer = stream.peer();
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    // a challenge is done with the handshake.
+                    Ok(Ok(stream)) if stream.get_ref().1.alpn_protocol() == Some(ACME_TLS_ALPN) => {
+                    }
+                    Ok(Ok(stream)) => {
+                        let _ = tx.send(Ok(stream)).await;
+                    }
+                    Ok(Err(e)) => tracing::debug!(peer = %peer, error = %e, "tls handshake failed"),
+                    Err(_) => tracing::debug!(peer = %peer, "tls handshake timed out"),
+                }
+            });
+        }
+    });
+
+    hyper::server::accept::from_stream(futures::stream::poll_fn(move |cx| rx.poll_recv(cx)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn client_identity_of_a_certificate() {
+        let mut params = rcgen::CertificateParams::new(vec!["billing.internal".to_string()]);
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, "billing");
+        params.subject_alt_names.push(rcgen::SanType::URI(
+            "spiffe://example.org/billing".to_string(),
+        ));
+        params.serial_number = Some(rcgen::SerialNumber::from(vec![0x1a, 0x2b]));
+        let cert = rcgen::Certificate::from_params(params).unwrap();
+
+        let identity = ClientIdentity::from_der(&cert.serialize_der().unwrap()).unwrap();
+        assert_eq!(identity.common_name.as_deref(), Some("billing"));
+        assert_eq!(identity.subject, "CN=billing");
+        assert_eq!(identity.dns_names, vec!["billing.internal"]);
+        assert_eq!(identity.uris, vec!["spiffe://example.org/billing"]);
+        assert_eq!(identity.serial, "1a2b");
+        assert!(ClientIdentity::from_der(b"not a certificate").is_none());
+    }
+}
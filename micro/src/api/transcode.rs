@@ -0,0 +1,655 @@
+use std::ops::Range;
+
+use hyper::body::HttpBody;
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use net::ProxyError;
+use once_cell::sync::OnceCell;
+use prost::Message;
+use prost_reflect::{
+    DescriptorPool, DynamicMessage, FieldDescriptor, Kind, MessageDescriptor, MethodDescriptor,
+    Value,
+};
+use serde_json::{json, Value as Json};
+
+use super::policy::unescape;
+use crate::config::{self, TranscodeConfig};
+use crate::{MetricsRegistry, Register, ServiceError};
+
+// the methods of the services, read from their descriptors as the gateway
+// starts.
+static BINDINGS: OnceCell<Vec<Binding>> = OnceCell::new();
+
+// the headers of a client that are not passed on as grpc metadata.
+const DROPPED: &[&str] = &[
+    "host",
+    "content-type",
+    "content-length",
+    "accept",
+    "accept-encoding",
+    "connection",
+    "keep-alive",
+    "te",
+    "transfer-encoding",
+    "upgrade",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    // `*`, one segment.
+    Any,
+    // `**`, the rest of the path.
+    Rest,
+}
+
+impl Segment {
+    fn of(part: &str) -> Option<Segment> {
+        match part {
+            "*" => Some(Segment::Any),
+            "**" => Some(Segment::Rest),
+            "" => None,
+            part if part.contains(['*', '{', '}', '=']) => None,
+            part => Some(Segment::Literal(part.to_string())),
+        }
+    }
+}
+
+// a path template of google.api.http, e.g. `/v1/{name=shelves/*}:publish`.
+#[derive(Debug, Clone)]
+struct Template {
+    segments: Vec<Segment>,
+    // the field each variable sets, and the segments it spans.
+    variables: Vec<(String, Range<usize>)>,
+    verb: Option<String>,
+}
+
+impl Template {
+    fn parse(template: &str) -> Option<Template> {
+        let path = template.strip_prefix('/')?;
+        // the verb follows the last segment, not a colon in a variable.
+        let (mut path, verb) = match path.rsplit_once(':') {
+            Some((path, verb)) if !verb.contains(['/', '}']) => (path, Some(verb.to_string())),
+            _ => (path, None),
+        };
+        let (mut segments, mut variables) = (vec![], vec![]);
+        while !path.is_empty() {
+            match path.strip_prefix('{') {
+                Some(variable) => {
+                    let (variable, rest) = variable.split_once('}')?;
+                    let (field, pattern) = variable.split_once('=').unwrap_or((variable, "*"));
+                    let start = segments.len();
+                    for part in pattern.split('/') {
+                        segments.push(Segment::of(part)?);
+                    }
+                    variables.push((field.to_string(), start..segments.len()));
+                    path = rest;
+                }
+                None => {
+                    let end = path.find('/').unwrap_or(path.len());
+                    segments.push(Segment::of(&path[..end])?);
+                    path = &path[end..];
+                }
+            }
+            path = match path.strip_prefix('/') {
+                Some("") => return None,
+                Some(rest) => rest,
+                None if path.is_empty() => path,
+                None => return None,
+            };
+        }
+        // only the last segment may take the rest of the path.
+        let last = segments.len().saturating_sub(1);
+        if segments[..last].contains(&Segment::Rest) {
+            return None;
+        }
+        Some(Template {
+            segments,
+            variables,
+            verb,
+        })
+    }
+
+    // the field paths and values of the variables in `path`, None when the
+    // template does not match it.
+    fn matches(&self, path: &str) -> Option<Vec<(String, String)>> {
+        let path = path.strip_prefix('/')?;
+        let path = match &self.verb {
+            Some(verb) => path.strip_suffix(verb.as_str())?.strip_suffix(':')?,
+            None => path,
+        };
+        let parts = match path {
+            "" => vec![],
+            path => path.split('/').collect::<Vec<_>>(),
+        };
+        let rest = self.segments.last() == Some(&Segment::Rest);
+        let fixed = self.segments.len() - rest as usize;
+        if parts.len() < fixed || (!rest && parts.len() > fixed) {
+            return None;
+        }
+        for (segment, part) in self.segments.iter().zip(&parts) {
+            let matched = match segment {
+                Segment::Literal(literal) => literal == part,
+                Segment::Any => !part.is_empty(),
+                Segment::Rest => true,
+            };
+            if !matched {
+                return None;
+            }
+        }
+        let values = self
+            .variables
+            .iter()
+            .map(|(field, range)| {
+                let end = match range.end == self.segments.len() && rest {
+                    true => parts.len(),
+                    false => range.end,
+                };
+                (field.clone(), unescape(&parts[range.start..end].join("/")))
+            })
+            .collect();
+        Some(values)
+    }
+}
+
+// a method served at a path.
+#[derive(Debug)]
+pub(super) struct Binding {
+    http: Method,
+    template: Template,
+    // the field the request body is, `*` for the whole request, empty for
+    // none.
+    body: String,
+    // the field of the response answered, empty for the whole response.
+    response_body: String,
+    method: MethodDescriptor,
+    // of the instances, in the registry.
+    service: String,
+}
+
+// the field `path`, e.g. `book.name`, of `message`.
+fn field(message: &MessageDescriptor, path: &str) -> Option<FieldDescriptor> {
+    let (name, rest) = match path.split_once('.') {
+        Some((name, rest)) => (name, Some(rest)),
+        None => (path, None),
+    };
+    let found = message
+        .get_field_by_name(name)
+        .or_else(|| message.get_field_by_json_name(name))?;
+    match (rest, found.kind()) {
+        (None, _) => Some(found),
+        (Some(rest), Kind::Message(inner)) if !found.is_list() => field(&inner, rest),
+        _ => None,
+    }
+}
+
+fn text(rule: &DynamicMessage, name: &str) -> String {
+    rule.get_field_by_name(name)
+        .and_then(|v| v.as_str().map(str::to_string))
+        .unwrap_or_default()
+}
+
+// the binding of `rule`, an HttpRule, for `method`.
+fn bind(
+    rule: &DynamicMessage,
+    method: &MethodDescriptor,
+    service: &str,
+) -> Result<Binding, String> {
+    let verbs = ["get", "put", "post", "delete", "patch"];
+    let (http, path) = match verbs.into_iter().find(|v| rule.has_field_by_name(v)) {
+        Some(verb) => (verb.to_uppercase(), text(rule, verb)),
+        None => {
+            let custom = rule.get_field_by_name("custom");
+            let custom = custom
+                .as_deref()
+                .and_then(Value::as_message)
+                .ok_or("http rule without a pattern")?;
+            (text(custom, "kind"), text(custom, "path"))
+        }
+    };
+    let http =
+        Method::from_bytes(http.as_bytes()).map_err(|_| format!("`{}` is not a method", http))?;
+    let template =
+        Template::parse(&path).ok_or_else(|| format!("`{}` is not a path template", path))?;
+
+    let (input, output) = (method.input(), method.output());
+    for (variable, _) in &template.variables {
+        match field(&input, variable) {
+            Some(f) if !f.is_list() && !matches!(f.kind(), Kind::Message(_)) => {}
+            _ => {
+                return Err(format!(
+                    "`{}` is not a field of {}",
+                    variable,
+                    input.full_name()
+                ))
+            }
+        }
+    }
+    let body = text(rule, "body");
+    if !matches!(body.as_str(), "" | "*") && input.get_field_by_name(&body).is_none() {
+        return Err(format!(
+            "body `{}` is not a field of {}",
+            body,
+            input.full_name()
+        ));
+    }
+    let response_body = text(rule, "response_body");
+    if !response_body.is_empty() && output.get_field_by_name(&response_body).is_none() {
+        return Err(format!(
+            "response_body `{}` is not a field of {}",
+            response_body,
+            output.full_name()
+        ));
+    }
+    Ok(Binding {
+        http,
+        template,
+        body,
+        response_body,
+        method: method.clone(),
+        service: service.to_string(),
+    })
+}
+
+// the bindings of the annotated methods of the services of `config`.
+fn bindings(config: &TranscodeConfig) -> Result<Vec<Binding>, String> {
+    let mut pool = DescriptorPool::new();
+    for path in &config.descriptors {
+        let bytes = std::fs::read(path).map_err(|e| format!("read {}: {}", path, e))?;
+        pool.decode_file_descriptor_set(bytes.as_slice())
+            .map_err(|e| format!("{}: {}", path, e))?;
+    }
+    let http = pool
+        .get_extension_by_name("google.api.http")
+        .ok_or("no descriptor has google.api.http, set them with --include_imports")?;
+
+    let mut bindings = vec![];
+    for (name, service) in &config.services {
+        let grpc = pool
+            .get_service_by_name(name)
+            .ok_or_else(|| format!("no descriptor has the service {}", name))?;
+        for method in grpc.methods() {
+            let options = method.options();
+            if !options.has_extension(&http) {
+                continue;
+            }
+            if method.is_client_streaming() || method.is_server_streaming() {
+                tracing::warn!(
+                    method = method.full_name(),
+                    "streaming methods are not transcoded"
+                );
+                continue;
+            }
+            let rule = options.get_extension(&http);
+            let Some(rule) = rule.as_message() else {
+                continue;
+            };
+            let mut rules = vec![rule.clone()];
+            if let Some(more) = rule.get_field_by_name("additional_bindings") {
+                let more = more.as_list().unwrap_or_default();
+                rules.extend(more.iter().filter_map(Value::as_message).cloned());
+            }
+            for rule in &rules {
+                let binding = bind(rule, &method, service)
+                    .map_err(|e| format!("{}: {}", method.full_name(), e))?;
+                bindings.push(binding);
+            }
+        }
+    }
+    Ok(bindings)
+}
+
+// what is wrong with the descriptors of `config`, for `Config::check_files`.
+pub(crate) fn issue(config: &TranscodeConfig) -> Option<String> {
+    bindings(config).err()
+}
+
+// reads the descriptors of `config`.
+pub(super) fn load(config: &TranscodeConfig) -> Result<(), ServiceError> {
+    let bindings = bindings(config).map_err(ServiceError::Transcode)?;
+    tracing::info!(methods = bindings.len(), "grpc methods transcoded");
+    // loaded once per process.
+    let _ = BINDINGS.set(bindings);
+    Ok(())
+}
+
+// the binding of a request, with the fields its path sets.
+pub(super) fn binding(
+    method: &Method,
+    path: &str,
+) -> Option<(&'static Binding, Vec<(String, String)>)> {
+    BINDINGS.get()?.iter().find_map(|binding| {
+        if binding.http != *method {
+            return None;
+        }
+        Some((binding, binding.template.matches(path)?))
+    })
+}
+
+// the json of `raw` for `field`, as the query or path has it.
+fn scalar(field: &FieldDescriptor, raw: &str) -> Result<Json, String> {
+    match field.kind() {
+        Kind::Bool => raw
+            .parse()
+            .map(Json::Bool)
+            .map_err(|_| format!("{}: `{}` is not a bool", field.name(), raw)),
+        Kind::Double
+        | Kind::Float
+        | Kind::Int32
+        | Kind::Sint32
+        | Kind::Sfixed32
+        | Kind::Uint32
+        | Kind::Fixed32 => raw
+            .parse()
+            .map(Json::Number)
+            .map_err(|_| format!("{}: `{}` is not a number", field.name(), raw)),
+        Kind::Message(_) => Err(format!("{} is a message", field.name())),
+        // 64 bit integers are strings in json, as are bytes and enums.
+        _ => Ok(Json::String(raw.to_string())),
+    }
+}
+
+// sets the field `path` of the json `request` of `message` to `raw`.
+fn set(
+    request: &mut Json,
+    message: &MessageDescriptor,
+    path: &str,
+    raw: &str,
+) -> Result<(), String> {
+    let (mut message, mut request) = (message.clone(), request);
+    let names = path.split('.').collect::<Vec<_>>();
+    for (i, name) in names.iter().enumerate() {
+        let found = message
+            .get_field_by_name(name)
+            .or_else(|| message.get_field_by_json_name(name))
+            .ok_or_else(|| format!("{} has no field {}", message.full_name(), path))?;
+        let object = request
+            .as_object_mut()
+            .ok_or_else(|| format!("{} is not an object", path))?;
+        let key = found.json_name().to_string();
+        if i + 1 == names.len() {
+            let value = scalar(&found, raw)?;
+            match found.is_list() {
+                true => match object.entry(key).or_insert_with(|| json!([])) {
+                    Json::Array(values) => values.push(value),
+                    _ => return Err(format!("{} is not a list", path)),
+                },
+                false => {
+                    object.insert(key, value);
+                }
+            }
+            return Ok(());
+        }
+        message = match found.kind() {
+            Kind::Message(inner) if !found.is_list() => inner,
+            _ => return Err(format!("{} has no field {}", message.full_name(), path)),
+        };
+        request = object.entry(key).or_insert_with(|| json!({}));
+    }
+    Ok(())
+}
+
+// the request message of `binding` from the fields of the path, the query
+// and the body.
+fn request(
+    binding: &Binding,
+    fields: &[(String, String)],
+    query: Option<&str>,
+    body: &[u8],
+) -> Result<DynamicMessage, String> {
+    let body = || match body.is_empty() {
+        true => Ok(json!({})),
+        false => serde_json::from_slice::<Json>(body).map_err(|e| format!("body: {}", e)),
+    };
+    let input = binding.method.input();
+    let mut request = match binding.body.as_str() {
+        "" => json!({}),
+        "*" => body()?,
+        name => {
+            let key = input
+                .get_field_by_name(name)
+                .map(|f| f.json_name().to_string());
+            json!({ key.unwrap_or_default(): body()? })
+        }
+    };
+    for (path, value) in fields {
+        set(&mut request, &input, path, value)?;
+    }
+    // every field not in the path is in a body of `*`, none in the query.
+    if binding.body != "*" {
+        let pairs = query.into_iter().flat_map(|q| q.split('&'));
+        for (name, value) in pairs.filter_map(|pair| pair.split_once('=')) {
+            let name = unescape(name);
+            if fields.iter().any(|(path, _)| *path == name) || field(&input, &name).is_none() {
+                continue;
+            }
+            set(&mut request, &input, &name, &unescape(value))?;
+        }
+    }
+    DynamicMessage::deserialize(input, request).map_err(|e| e.to_string())
+}
+
+// a message in the length prefixed frame of grpc.
+fn frame(message: &[u8]) -> Vec<u8> {
+    let mut framed = Vec::with_capacity(message.len() + 5);
+    framed.push(0);
+    framed.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    framed.extend_from_slice(message);
+    framed
+}
+
+// the message of the first frame of `body`.
+fn unframe(body: &[u8]) -> Result<&[u8], String> {
+    let (flag, len) = match body {
+        [flag, a, b, c, d, ..] => (*flag, u32::from_be_bytes([*a, *b, *c, *d]) as usize),
+        _ => return Err("no message".to_string()),
+    };
+    if flag != 0 {
+        return Err("compressed message".to_string());
+    }
+    body.get(5..5 + len)
+        .ok_or_else(|| "truncated message".to_string())
+}
+
+// the http status of a grpc status code.
+fn http_status(code: u32) -> StatusCode {
+    match code {
+        0 => StatusCode::OK,
+        1 => StatusCode::from_u16(499).unwrap(),
+        3 | 9 | 11 => StatusCode::BAD_REQUEST,
+        4 => StatusCode::GATEWAY_TIMEOUT,
+        5 => StatusCode::NOT_FOUND,
+        6 | 10 => StatusCode::CONFLICT,
+        7 => StatusCode::FORBIDDEN,
+        8 => StatusCode::TOO_MANY_REQUESTS,
+        12 => StatusCode::NOT_IMPLEMENTED,
+        14 => StatusCode::SERVICE_UNAVAILABLE,
+        16 => StatusCode::UNAUTHORIZED,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+fn reply(binding: &Binding, status: StatusCode, body: Json) -> Response<Body> {
+    MetricsRegistry::global()
+        .counter(
+            "crossgate_gateway_transcoded_total",
+            &[
+                ("method", binding.method.full_name()),
+                ("status", status.as_str()),
+            ],
+        )
+        .inc();
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+// an error as google.rpc.Status has it in json.
+fn failed(binding: &Binding, status: StatusCode, code: u32, message: String) -> Response<Body> {
+    reply(binding, status, json!({ "code": code, "message": message }))
+}
+
+// the grpc status of an answer, in its trailers or, without a message, its
+// headers.
+fn grpc_status(headers: &HeaderMap, trailers: &HeaderMap) -> (u32, String) {
+    let get = |name: &str| {
+        trailers
+            .get(name)
+            .or_else(|| headers.get(name))
+            .and_then(|v| v.to_str().ok())
+    };
+    let code = get("grpc-status").and_then(|c| c.parse().ok()).unwrap_or(2);
+    (code, get("grpc-message").map(unescape).unwrap_or_default())
+}
+
+// calls the method of `binding` on an instance of its service with the
+// request of `req`, and answers with its response as json.
+pub(super) async fn serve(
+    register: &Register,
+    binding: &'static Binding,
+    fields: Vec<(String, String)>,
+    req: Request<Body>,
+) -> Response<Body> {
+    let (parts, body) = req.into_parts();
+    let body = match hyper::body::to_bytes(body).await {
+        Ok(body) => body,
+        Err(e) => return failed(binding, StatusCode::BAD_REQUEST, 3, e.to_string()),
+    };
+    let message = match request(binding, &fields, parts.uri.query(), &body) {
+        Ok(message) => message,
+        Err(e) => return failed(binding, StatusCode::BAD_REQUEST, 3, e),
+    };
+
+    let instance = match register.get_web_service(&binding.service, "grpc").await {
+        Ok((lba, endpoint)) => lba.pick(&endpoint).cloned(),
+        Err(_) => None,
+    };
+    let instance = match instance {
+        Some(instance) if instance.tls.is_none() => instance,
+        Some(instance) => {
+            let message = format!("{} serves grpc over tls", instance.addr);
+            return failed(binding, StatusCode::BAD_GATEWAY, 14, message);
+        }
+        None => {
+            let message = format!("{} not found", binding.service);
+            return failed(binding, StatusCode::SERVICE_UNAVAILABLE, 14, message);
+        }
+    };
+
+    let uri = format!(
+        "http://{}/{}/{}",
+        instance.addr,
+        binding.method.parent_service().full_name(),
+        binding.method.name()
+    );
+    let mut call = Request::post(uri)
+        .header(CONTENT_TYPE, "application/grpc")
+        .header("te", "trailers")
+        .body(Body::from(frame(&message.encode_to_vec())))
+        .unwrap();
+    for (name, value) in &parts.headers {
+        if !DROPPED.contains(&name.as_str()) {
+            call.headers_mut().append(name, value.clone());
+        }
+    }
+    let timeout = config::current().request_timeout();
+    if let Some(timeout) = timeout {
+        let millis = format!("{}m", timeout.as_millis());
+        if let Ok(value) = HeaderValue::from_str(&millis) {
+            call.headers_mut()
+                .insert(HeaderName::from_static("grpc-timeout"), value);
+        }
+    }
+
+    let answer = async {
+        let res = super::channel::send(&instance.addr, call).await?;
+        let (head, mut body) = res.into_parts();
+        let mut data = vec![];
+        while let Some(chunk) = body.data().await {
+            data.extend_from_slice(&chunk?);
+        }
+        let trailers = body.trailers().await?.unwrap_or_default();
+        Ok::<_, ProxyError>((head, data, trailers))
+    };
+    let answer = match timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, answer).await {
+            Ok(answer) => answer,
+            Err(_) => {
+                let message = format!("{} did not answer within {:?}", instance.addr, timeout);
+                return failed(binding, StatusCode::GATEWAY_TIMEOUT, 4, message);
+            }
+        },
+        None => answer.await,
+    };
+    let (head, data, trailers) = match answer {
+        Ok(answer) => answer,
+        Err(e) => {
+            let message = format!("{}: {}", instance.addr, e);
+            return failed(binding, StatusCode::BAD_GATEWAY, 14, message);
+        }
+    };
+    let (code, message) = grpc_status(&head.headers, &trailers);
+    let status = http_status(code);
+    super::status::observe(&binding.service, &instance.addr, status);
+    if code != 0 {
+        return failed(binding, status, code, message);
+    }
+
+    let output = binding.method.output();
+    let response = unframe(&data).and_then(|message| {
+        DynamicMessage::decode(output.clone(), message).map_err(|e| e.to_string())
+    });
+    let mut response =
+        match response.and_then(|r| serde_json::to_value(&r).map_err(|e| e.to_string())) {
+            Ok(response) => response,
+            Err(e) => {
+                let message = format!("{} answered {}", instance.addr, e);
+                return failed(binding, StatusCode::BAD_GATEWAY, 13, message);
+            }
+        };
+    if let Some(field) = output.get_field_by_name(&binding.response_body) {
+        response = response
+            .get_mut(field.json_name())
+            .map(Json::take)
+            .unwrap_or(Json::Null);
+    }
+    reply(binding, StatusCode::OK, response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_match_their_templates() {
+        let template = Template::parse("/v1/{name=shelves/*/books/*}:publish").unwrap();
+        assert_eq!(
+            template.matches("/v1/shelves/1/books/a%20b:publish"),
+            Some(vec![(
+                "name".to_string(),
+                "shelves/1/books/a b".to_string()
+            )])
+        );
+        assert_eq!(template.matches("/v1/shelves/1/books/2"), None);
+        assert_eq!(template.matches("/v1/shelves/1:publish"), None);
+
+        let template = Template::parse("/v1/users/{user.id}/files/{path=**}").unwrap();
+        assert_eq!(
+            template.matches("/v1/users/7/files/a/b.txt"),
+            Some(vec![
+                ("user.id".to_string(), "7".to_string()),
+                ("path".to_string(), "a/b.txt".to_string()),
+            ])
+        );
+        assert_eq!(template.matches("/v1/users//files/a"), None);
+        assert!(Template::parse("/v1/{a=**}/b").is_none());
+        assert!(Template::parse("/v1/users/").is_none());
+
+        let message = b"\x0a\x03ann";
+        assert_eq!(unframe(&frame(message)), Ok(&message[..]));
+        assert!(unframe(&frame(message)[..6]).is_err());
+        assert_eq!(http_status(5), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,166 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use hyper::{Body, Request};
+use once_cell::sync::Lazy;
+
+use crate::config::{self, WarmupConfig};
+use crate::{Endpoint, Instance, MetricsRegistry};
+
+// instances not routed to for this long are forgotten, and warmed up again
+// should they come back.
+const FORGET_AFTER: Duration = Duration::from_secs(3600);
+
+// warm-up requests come from the gateway itself.
+const GATEWAY_IP: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+struct Seen {
+    warm: bool,
+    at: Instant,
+}
+
+// the instances the gateway has looked up, by address.
+static SEEN: Lazy<Mutex<HashMap<String, Seen>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+// `endpoint` without its instances still warming up, unless none other is
+// left, and those seen for the first time.
+fn partition(endpoint: Endpoint) -> (Endpoint, Vec<Instance>) {
+    let now = Instant::now();
+    let mut seen = SEEN.lock().unwrap();
+    let mut new = vec![];
+    let mut warm = vec![];
+    for instance in endpoint.instances() {
+        match seen.get_mut(&instance.addr) {
+            Some(seen) => {
+                seen.at = now;
+                warm.push(seen.warm);
+            }
+            None => {
+                new.push(instance.clone());
+                warm.push(false);
+            }
+        }
+    }
+    if !new.is_empty() {
+        seen.retain(|_, instance| now.duration_since(instance.at) < FORGET_AFTER);
+        for instance in &new {
+            seen.insert(
+                instance.addr.clone(),
+                Seen {
+                    warm: false,
+                    at: now,
+                },
+            );
+        }
+    }
+    if !warm.iter().any(|warm| *warm) {
+        return (endpoint, new);
+    }
+    let instances = endpoint
+        .instances()
+        .iter()
+        .zip(warm)
+        .filter(|(_, warm)| *warm)
+        .map(|(instance, _)| instance.clone())
+        .collect();
+    (Endpoint::new(instances), new)
+}
+
+// sets up `config.connections` connections to `instance` in the pool requests
+// are forwarded from, each with a request to `config.path`.
+async fn warm_up(config: &WarmupConfig, instance: Instance) {
+    let addr = &instance.addr;
+    let timeout = Some(Duration::from_secs(config.timeout_secs));
+    let requests = (0..config.connections).map(|_| async {
+        let req = Request::get(config.path.as_str())
+            .header("x-crossgate-warmup", "1")
+            .body(Body::empty())?;
+        let res = super::forward(GATEWAY_IP, &instance, req, timeout).await?;
+        // the connection goes back to the pool once the body is read.
+        let status = res.status();
+        hyper::body::to_bytes(res.into_body()).await?;
+        match status.is_server_error() {
+            true => Err(anyhow::anyhow!("{} answered {}", addr, status)),
+            false => Ok(()),
+        }
+    });
+    let failed = futures::future::join_all(requests)
+        .await
+        .into_iter()
+        .filter_map(Result::err)
+        .collect::<Vec<_>>();
+    let status = match failed.first() {
+        Some(e) => {
+            tracing::debug!(addr = %addr, error = %e, failed = failed.len(), "warm-up failed");
+            "error"
+        }
+        None => "ok",
+    };
+    MetricsRegistry::global()
+        .counter("crossgate_gateway_warmups_total", &[("status", status)])
+        .inc();
+    // routed to either way, its health is up to the registry.
+    if let Some(seen) = SEEN.lock().unwrap().get_mut(addr) {
+        seen.warm = true;
+    }
+}
+
+// whether the instance at `addr` is warming up, None when the gateway has
+// not seen it.
+pub(super) fn warming(addr: &str) -> Option<bool> {
+    SEEN.lock()
+        .unwrap()
+        .get(addr)
+        .map(|instance| !instance.warm)
+}
+
+// `endpoint` without the instances still warming up; those it has that the
+// gateway has not seen before start warming up, with `gateway.warmup`.
+pub(super) fn ready(endpoint: Endpoint) -> Endpoint {
+    let config = &config::current().gateway.warmup;
+    if config.connections == 0 {
+        return endpoint;
+    }
+    let (ready, new) = partition(endpoint);
+    for instance in new {
+        tokio::spawn(warm_up(config, instance));
+    }
+    ready
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn endpoint(addrs: &[&str]) -> Endpoint {
+        Endpoint::new(
+            addrs
+                .iter()
+                .map(|a| Instance::upstream(a.to_string(), false))
+                .collect(),
+        )
+    }
+
+    fn addrs(instances: &[Instance]) -> Vec<&str> {
+        instances.iter().map(|i| i.addr.as_str()).collect()
+    }
+
+    #[test]
+    fn warming_instances_get_no_traffic_unless_alone() {
+        let (first, new) = partition(endpoint(&["10.9.0.1:80"]));
+        assert_eq!(addrs(first.instances()), ["10.9.0.1:80"]);
+        assert_eq!(addrs(&new), ["10.9.0.1:80"]);
+
+        SEEN.lock().unwrap().get_mut("10.9.0.1:80").unwrap().warm = true;
+        let (ready, new) = partition(endpoint(&["10.9.0.1:80", "10.9.0.2:80"]));
+        assert_eq!(addrs(ready.instances()), ["10.9.0.1:80"]);
+        assert_eq!(ready.instances()[0].weight, 1);
+        assert_eq!(addrs(&new), ["10.9.0.2:80"]);
+
+        let (ready, new) = partition(endpoint(&["10.9.0.2:80"]));
+        assert_eq!(addrs(ready.instances()), ["10.9.0.2:80"]);
+        assert!(new.is_empty());
+    }
+}
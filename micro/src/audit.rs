@@ -0,0 +1,313 @@
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+
+use futures::future::BoxFuture;
+use hyper::{header::CONTENT_TYPE, Body, Request};
+use once_cell::sync::{Lazy, OnceCell};
+use serde::Serialize;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+
+use crate::config::{self, AuditSinkConfig};
+use crate::MetricsRegistry;
+
+// syslog facility 13, log audit, at severity 5, notice.
+const SYSLOG_PRI: u8 = 13 * 8 + 5;
+
+/// What an audit event is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditKind {
+    // a request refused by the auth middleware.
+    AuthFailure,
+    // something changed through the admin middleware.
+    AdminChange,
+    // the routes the gateway forwards by, at start.
+    RoutesLoaded,
+    // a service or the gateway taken out of or back into service.
+    Maintenance,
+    // a CONNECT tunnel to a destination not allowed.
+    EgressDenied,
+}
+
+/// A security relevant event of the gateway, appended to the audit sinks as
+/// one line of json.
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditEvent {
+    pub timestamp: String,
+    pub kind: AuditKind,
+    // e.g. revoke or set_color.
+    pub action: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_ip: Option<IpAddr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub details: BTreeMap<String, String>,
+}
+
+impl AuditEvent {
+    pub fn new(kind: AuditKind, action: &str) -> Self {
+        Self {
+            timestamp: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            kind,
+            action: action.to_string(),
+            client_ip: None,
+            request_id: None,
+            path: None,
+            details: BTreeMap::new(),
+        }
+    }
+
+    /// Who sent `req` and where to, for events of a request.
+    pub fn request(mut self, req: &Request<Body>) -> Self {
+        self.client_ip = req
+            .extensions()
+            .get::<crate::api::ClientIp>()
+            .map(|ip| ip.0);
+        self.request_id = req
+            .headers()
+            .get(crate::api::REQUEST_ID)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string);
+        self.path = Some(req.uri().path().to_string());
+        self
+    }
+
+    pub fn detail(mut self, key: &str, value: impl ToString) -> Self {
+        self.details.insert(key.to_string(), value.to_string());
+        self
+    }
+}
+
+/// Where audit events are appended, each as one line of json without the
+/// newline.
+pub trait AuditSink: Send + Sync {
+    fn write<'a>(&'a self, line: &'a str) -> BoxFuture<'a, anyhow::Result<()>>;
+}
+
+/// Appends events to a file, opened again for every event so that rotated
+/// files are picked up.
+pub struct FileSink {
+    path: String,
+}
+
+impl FileSink {
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: path.to_string(),
+        }
+    }
+}
+
+impl AuditSink for FileSink {
+    fn write<'a>(&'a self, line: &'a str) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move {
+            let mut file = tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)
+                .await?;
+            file.write_all(format!("{}\n", line).as_bytes()).await?;
+            file.flush().await?;
+            Ok(())
+        })
+    }
+}
+
+/// Posts every event to an http or https url.
+pub struct HttpSink {
+    url: String,
+}
+
+impl HttpSink {
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.to_string(),
+        }
+    }
+}
+
+impl AuditSink for HttpSink {
+    fn write<'a>(&'a self, line: &'a str) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move {
+            let req = Request::post(&self.url)
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(line.to_string()))?;
+            let res = match self.url.starts_with("https://") {
+                true => {
+                    net::get_tls_proxy_client(None)
+                        .client()
+                        .request(req)
+                        .await?
+                }
+                false => net::get_proxy_client().client().request(req).await?,
+            };
+            if !res.status().is_success() {
+                anyhow::bail!("{} answered {}", self.url, res.status());
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Sends every event to a syslog server over udp, as an RFC 5424 message of
+/// the log audit facility.
+pub struct SyslogSink {
+    addr: String,
+}
+
+impl SyslogSink {
+    // `addr` is host:port.
+    pub fn new(addr: &str) -> Self {
+        Self {
+            addr: addr.to_string(),
+        }
+    }
+}
+
+fn syslog_message(line: &str) -> String {
+    let timestamp = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+    format!(
+        "<{}>1 {} - crossgate {} audit - {}",
+        SYSLOG_PRI,
+        timestamp,
+        std::process::id(),
+        line
+    )
+}
+
+impl AuditSink for SyslogSink {
+    fn write<'a>(&'a self, line: &'a str) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move {
+            let addr = tokio::net::lookup_host(&self.addr)
+                .await?
+                .next()
+                .ok_or_else(|| anyhow::anyhow!("{} resolves to no address", self.addr))?;
+            let local = match addr.is_ipv4() {
+                true => "0.0.0.0:0",
+                false => "[::]:0",
+            };
+            let socket = tokio::net::UdpSocket::bind(local).await?;
+            socket
+                .send_to(syslog_message(line).as_bytes(), addr)
+                .await?;
+            Ok(())
+        })
+    }
+}
+
+fn sink(config: &AuditSinkConfig) -> Option<Arc<dyn AuditSink>> {
+    match config.kind.as_str() {
+        "file" => Some(Arc::new(FileSink::new(config.path.as_deref()?))),
+        "http" => Some(Arc::new(HttpSink::new(config.url.as_deref()?))),
+        "syslog" => Some(Arc::new(SyslogSink::new(config.addr.as_deref()?))),
+        _ => None,
+    }
+}
+
+// a sink and its name in the metrics.
+type NamedSink = (String, Arc<dyn AuditSink>);
+
+// seeded from `gateway.audit.sinks`.
+static SINKS: Lazy<RwLock<Vec<NamedSink>>> = Lazy::new(|| {
+    let sinks = &config::current().gateway.audit.sinks;
+    RwLock::new(
+        sinks
+            .iter()
+            .filter_map(|s| Some((s.kind.clone(), sink(s)?)))
+            .collect(),
+    )
+});
+
+static QUEUE: OnceCell<mpsc::Sender<AuditEvent>> = OnceCell::new();
+
+/// Appends events to `sink` too, named `name` in the metrics.
+pub fn add_sink(name: &str, sink: impl AuditSink + 'static) {
+    SINKS
+        .write()
+        .unwrap()
+        .push((name.to_string(), Arc::new(sink)));
+}
+
+// every sink gets the events in the order they were recorded, one at a
+// time.
+async fn append(mut events: mpsc::Receiver<AuditEvent>) {
+    while let Some(event) = events.recv().await {
+        let line = match serde_json::to_string(&event) {
+            Ok(line) => line,
+            Err(_) => continue,
+        };
+        let sinks = SINKS.read().unwrap().clone();
+        for (name, sink) in sinks {
+            let status = match sink.write(&line).await {
+                Ok(()) => "ok",
+                Err(e) => {
+                    tracing::warn!(sink = %name, error = %e, "audit event not appended");
+                    "error"
+                }
+            };
+            MetricsRegistry::global()
+                .counter(
+                    "crossgate_audit_events_total",
+                    &[("sink", name.as_str()), ("status", status)],
+                )
+                .inc();
+        }
+    }
+}
+
+/// Appends `event` to the audit sinks in the background; dropped, and
+/// counted, when `gateway.audit.queue` events are already waiting. Does
+/// nothing without sinks.
+pub fn record(event: AuditEvent) {
+    if SINKS.read().unwrap().is_empty() {
+        return;
+    }
+    let queue = QUEUE.get_or_init(|| {
+        let (tx, rx) = mpsc::channel(config::current().gateway.audit.queue);
+        tokio::spawn(append(rx));
+        tx
+    });
+    if queue.try_send(event).is_err() {
+        MetricsRegistry::global()
+            .counter("crossgate_audit_dropped_total", &[])
+            .inc();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn events_are_appended_as_json_lines() {
+        let req = Request::post("/admin/colors?service=/t/ums&color=green")
+            .header(crate::api::REQUEST_ID, "abc")
+            .body(Body::empty())
+            .unwrap();
+        let event = AuditEvent::new(AuditKind::AdminChange, "set_color")
+            .request(&req)
+            .detail("service", "/t/ums");
+        let line = serde_json::to_string(&event).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(json["kind"], "admin_change");
+        assert_eq!(json["request_id"], "abc");
+        assert_eq!(json["path"], "/admin/colors");
+        assert_eq!(json["details"]["service"], "/t/ums");
+        assert!(json.get("client_ip").is_none());
+
+        let path = std::env::temp_dir().join(format!("audit-{}.log", std::process::id()));
+        let sink = FileSink::new(path.to_str().unwrap());
+        sink.write(&line).await.unwrap();
+        sink.write("{}").await.unwrap();
+        let written = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(written, format!("{}\n{{}}\n", line));
+
+        assert!(syslog_message("{}").starts_with("<109>1 "));
+    }
+}
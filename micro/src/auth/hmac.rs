@@ -0,0 +1,251 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hyper::body::HttpBody;
+use hyper::header::HeaderMap;
+use hyper::{Body, Method, Request};
+use once_cell::sync::Lazy;
+use ring::hmac;
+use sha2::{Digest, Sha256};
+
+use super::AuthError;
+use crate::config::{self, HmacConfig, Route};
+use crate::signing::{hex, unhex};
+
+// bodies read for the signature on routes without a body_limit, at most.
+const MAX_BODY: u64 = 1024 * 1024;
+
+// the signatures accepted, until their timestamp is out of the window.
+static SEEN: Lazy<Mutex<HashMap<String, u64>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+// what a request is signed over, a line each.
+fn canonical(method: &Method, path_and_query: &str, timestamp: &str, body: &[u8]) -> String {
+    format!(
+        "{}\n{}\n{}\n{}",
+        method,
+        path_and_query,
+        timestamp,
+        hex(&Sha256::digest(body))
+    )
+}
+
+/// The signature of a request for the hmac middleware, as a client with
+/// `secret` sends it.
+pub fn sign(
+    secret: &[u8],
+    method: &Method,
+    path_and_query: &str,
+    timestamp: u64,
+    body: &[u8],
+) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret);
+    let signed = canonical(method, path_and_query, &timestamp.to_string(), body);
+    hex(hmac::sign(&key, signed.as_bytes()).as_ref())
+}
+
+// the signature of a request, when its client signed it with its secret
+// within the window around `now`.
+fn verify(
+    config: &HmacConfig,
+    method: &Method,
+    path_and_query: &str,
+    headers: &HeaderMap,
+    body: &[u8],
+    now: u64,
+) -> Result<String, AuthError> {
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .ok_or_else(|| AuthError::InvalidSignature(format!("{} is required", name)))
+    };
+    let client = header(&config.client_header)?;
+    let timestamp = header(&config.timestamp_header)?;
+    let signature = header(&config.signature_header)?;
+
+    let secret = config
+        .clients
+        .get(client)
+        .ok_or_else(|| AuthError::InvalidSignature(format!("unknown client {}", client)))?;
+    let signed_at = timestamp
+        .parse::<u64>()
+        .map_err(|_| AuthError::InvalidSignature(format!("`{}` is not a unix time", timestamp)))?;
+    if signed_at.abs_diff(now) > config.window_secs {
+        return Err(AuthError::InvalidSignature(
+            "timestamp out of the window".to_string(),
+        ));
+    }
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    let signed = canonical(method, path_and_query, timestamp, body);
+    let matches = unhex(&signature.to_ascii_lowercase())
+        .is_some_and(|tag| hmac::verify(&key, signed.as_bytes(), &tag).is_ok());
+    if !matches {
+        return Err(AuthError::InvalidSignature("mismatch".to_string()));
+    }
+    Ok(signature.to_ascii_lowercase())
+}
+
+// notes `signature` as seen until `expires`; false when it was already.
+fn first_seen(seen: &mut HashMap<String, u64>, signature: String, expires: u64, now: u64) -> bool {
+    seen.retain(|_, expires| *expires >= now);
+    seen.insert(signature, expires).is_none()
+}
+
+pub(super) async fn check(req: &mut Request<Body>) -> Result<(), AuthError> {
+    let config = match &config::current().gateway.auth.hmac {
+        Some(config) => config,
+        None => return Ok(()),
+    };
+    check_route(config, crate::api::route_of(req), req).await
+}
+
+// checks `req` on `route`, when the route wants it signed.
+async fn check_route(
+    config: &HmacConfig,
+    route: Option<&Route>,
+    req: &mut Request<Body>,
+) -> Result<(), AuthError> {
+    let route = match route {
+        Some(route) if route.policy.hmac => route,
+        _ => return Ok(()),
+    };
+
+    let limit = route.policy.body_limit.unwrap_or(MAX_BODY);
+    let mut body = std::mem::take(req.body_mut());
+    let mut read = vec![];
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| AuthError::InvalidSignature(e.to_string()))?;
+        if (read.len() + chunk.len()) as u64 > limit {
+            return Err(AuthError::InvalidSignature(format!(
+                "body over {} bytes",
+                limit
+            )));
+        }
+        read.extend_from_slice(&chunk);
+    }
+
+    let path_and_query = req.uri().path_and_query().map_or("/", |p| p.as_str());
+    let now = now();
+    let verified = verify(
+        config,
+        req.method(),
+        path_and_query,
+        req.headers(),
+        &read,
+        now,
+    );
+    *req.body_mut() = Body::from(read);
+    let signature = verified?;
+    // kept until its timestamp is out of the window for sure, replays are
+    // refused for the timestamp after.
+    let expires = now + 2 * config.window_secs;
+    if !first_seen(&mut SEEN.lock().unwrap(), signature, expires, now) {
+        return Err(AuthError::InvalidSignature("replayed".to_string()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signed_requests_in_the_window() {
+        let config = HmacConfig {
+            clients: [("acme".to_string(), "0123456789abcdef".to_string())]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        };
+        let body = br#"{"amount": 10}"#;
+        let headers = |client: &str, timestamp: u64, signature: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert("x-client-id", client.parse().unwrap());
+            headers.insert("x-signature-timestamp", timestamp.into());
+            headers.insert("x-signature", signature.parse().unwrap());
+            headers
+        };
+        let signature = sign(b"0123456789abcdef", &Method::POST, "/pay?x=1", 1000, body);
+        let verify = |headers: &HeaderMap, path: &str, body: &[u8], now: u64| {
+            verify(&config, &Method::POST, path, headers, body, now).map_err(|e| e.to_string())
+        };
+
+        let signed = headers("acme", 1000, &signature);
+        assert_eq!(
+            verify(&signed, "/pay?x=1", body, 1200),
+            Ok(signature.clone())
+        );
+        assert!(verify(&signed, "/pay?x=2", body, 1000).is_err());
+        assert!(verify(&signed, "/pay?x=1", b"{}", 1000).is_err());
+        assert_eq!(
+            verify(&signed, "/pay?x=1", body, 1301).unwrap_err(),
+            "invalid signature: timestamp out of the window"
+        );
+        assert!(verify(&headers("globex", 1000, &signature), "/pay?x=1", body, 1000).is_err());
+        assert!(verify(&HeaderMap::new(), "/pay?x=1", body, 1000).is_err());
+
+        let mut seen = HashMap::new();
+        assert!(first_seen(&mut seen, signature.clone(), 1600, 1000));
+        assert!(!first_seen(&mut seen, signature.clone(), 1600, 1100));
+        assert!(first_seen(&mut seen, signature, 2200, 1601));
+    }
+
+    #[tokio::test]
+    async fn signed_routes_refuse_unsigned_requests() {
+        let config = HmacConfig {
+            clients: [("acme".to_string(), "0123456789abcdef".to_string())]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        };
+        let route = |hmac| Route {
+            prefix: "/t/pay".to_string(),
+            pattern: None,
+            service: "/t/pay".to_string(),
+            upstreams: vec![],
+            rewrite: None,
+            policy: crate::config::RoutePolicy {
+                hmac,
+                ..Default::default()
+            },
+        };
+        let req = || Request::post("/t/pay").body(Body::from("{}")).unwrap();
+
+        let err = check_route(&config, Some(&route(true)), &mut req())
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid signature: x-client-id is required"
+        );
+        assert!(check_route(&config, Some(&route(false)), &mut req())
+            .await
+            .is_ok());
+
+        let timestamp = now();
+        let signature = sign(
+            b"0123456789abcdef",
+            &Method::POST,
+            "/t/pay",
+            timestamp,
+            b"{}",
+        );
+        let mut signed = Request::post("/t/pay")
+            .header("x-client-id", "acme")
+            .header("x-signature-timestamp", timestamp)
+            .header("x-signature", signature)
+            .body(Body::from("{}"))
+            .unwrap();
+        assert!(check_route(&config, Some(&route(true)), &mut signed)
+            .await
+            .is_ok());
+    }
+}
@@ -0,0 +1,346 @@
+mod hmac;
+#[cfg(feature = "oidc")]
+mod oidc;
+mod store;
+
+pub use hmac::sign;
+#[cfg(feature = "oidc")]
+pub use oidc::Claims;
+#[cfg(feature = "redis")]
+pub use store::RedisStore;
+pub use store::{MemoryStore, RevocationStore};
+
+use std::time::Duration;
+
+use base64::Engine;
+use hyper::header::{AUTHORIZATION, LOCATION, SET_COOKIE, WWW_AUTHENTICATE};
+use hyper::{Body, Request, Response, StatusCode};
+use once_cell::sync::OnceCell;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::audit::{self, AuditEvent, AuditKind};
+use crate::config::{self, AdminConfig};
+use crate::MetricsRegistry;
+
+static STORE: OnceCell<Box<dyn RevocationStore>> = OnceCell::new();
+
+/// Why a request is not forwarded; the sign in variants are the steps of
+/// the code flow, answered by the gateway.
+#[derive(Debug, Error)]
+pub enum AuthError {
+    #[error("credential revoked")]
+    Revoked,
+    #[error("revocation store failed: {0}")]
+    Store(anyhow::Error),
+    #[error("a token is required")]
+    Unauthenticated,
+    #[error("invalid token: {0}")]
+    InvalidToken(String),
+    #[error("identity provider unavailable: {0}")]
+    Provider(anyhow::Error),
+    #[error("sign in at the identity provider")]
+    SignIn { location: String },
+    #[error("signed in")]
+    SignedIn { location: String, cookie: String },
+    #[error("invalid signature: {0}")]
+    InvalidSignature(String),
+    #[error("admin credentials required")]
+    NotAdmin,
+}
+
+impl AuthError {
+    // the reason the request is counted as rejected for, None for the
+    // steps of signing in.
+    fn reason(&self) -> Option<&'static str> {
+        match self {
+            AuthError::Revoked => Some("revoked"),
+            AuthError::Store(_) => Some("store_error"),
+            AuthError::Unauthenticated => Some("unauthenticated"),
+            AuthError::InvalidToken(_) => Some("invalid_token"),
+            AuthError::Provider(_) => Some("provider_error"),
+            AuthError::InvalidSignature(_) => Some("invalid_signature"),
+            AuthError::NotAdmin => Some("not_admin"),
+            AuthError::SignIn { .. } | AuthError::SignedIn { .. } => None,
+        }
+    }
+
+    /// What the gateway answers the request with.
+    pub fn response(&self) -> Response<Body> {
+        let builder = Response::builder();
+        let res = match self {
+            AuthError::SignIn { location } => builder
+                .status(StatusCode::FOUND)
+                .header(LOCATION, location)
+                .body(Body::empty()),
+            AuthError::SignedIn { location, cookie } => builder
+                .status(StatusCode::FOUND)
+                .header(LOCATION, location)
+                .header(SET_COOKIE, cookie)
+                .body(Body::empty()),
+            AuthError::Store(_) | AuthError::Provider(_) => builder
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .body(Body::from(self.to_string())),
+            AuthError::InvalidSignature(_) => builder
+                .status(StatusCode::UNAUTHORIZED)
+                .body(Body::from(self.to_string())),
+            AuthError::NotAdmin => builder
+                .status(StatusCode::UNAUTHORIZED)
+                .header(WWW_AUTHENTICATE, "Basic realm=\"crossgate\"")
+                .body(Body::from(self.to_string())),
+            _ => builder
+                .status(StatusCode::UNAUTHORIZED)
+                .header(WWW_AUTHENTICATE, "Bearer")
+                .body(Body::from(self.to_string())),
+        };
+        res.unwrap_or_else(|_| {
+            let mut res = Response::new(Body::empty());
+            *res.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            res
+        })
+    }
+}
+
+// the token of the authorization header of `req`.
+fn bearer(req: &Request<Body>) -> Option<String> {
+    req.headers()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split_once(' '))
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+        .map(|(_, token)| token.trim())
+        .filter(|token| !token.is_empty())
+        .map(str::to_string)
+}
+
+/// What a request authenticates with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Credential {
+    Bearer(String),
+    ApiKey(String),
+}
+
+impl Credential {
+    /// The bearer token of `req`, else the token of its oidc session, else
+    /// its api key in `gateway.auth.api_key_header`.
+    pub fn of(req: &Request<Body>) -> Option<Self> {
+        if let Some(token) = bearer(req) {
+            return Some(Credential::Bearer(token));
+        }
+        let auth = &config::current().gateway.auth;
+        #[cfg(feature = "oidc")]
+        if let Some(token) = auth.oidc.as_ref().and_then(|o| oidc::session(o, req)) {
+            return Some(Credential::Bearer(token));
+        }
+        req.headers()
+            .get(auth.api_key_header.as_str())
+            .and_then(|v| v.to_str().ok())
+            .filter(|key| !key.is_empty())
+            .map(|key| Credential::ApiKey(key.to_string()))
+    }
+
+    /// What the credential is revoked under, a digest of it so that stores
+    /// never hold the credential itself.
+    pub fn id(&self) -> String {
+        let (kind, secret) = match self {
+            Credential::Bearer(token) => ("bearer", token),
+            Credential::ApiKey(key) => ("key", key),
+        };
+        let digest = Sha256::digest(secret.as_bytes());
+        let hex = digest
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>();
+        format!("{}:{}", kind, hex)
+    }
+}
+
+/// Lists revocations in `store` instead of `gateway.auth.revocation_store`;
+/// false once a store is in use.
+pub fn set_revocation_store(store: impl RevocationStore + 'static) -> bool {
+    STORE.set(Box::new(store)).is_ok()
+}
+
+fn store() -> &'static dyn RevocationStore {
+    STORE
+        .get_or_init(|| {
+            #[cfg(feature = "redis")]
+            {
+                let auth = &config::current().gateway.auth;
+                if let ("redis", Some(url)) = (auth.revocation_store.as_str(), &auth.redis_url) {
+                    match RedisStore::new(url, &auth.redis_prefix) {
+                        Ok(store) => return Box::new(store),
+                        Err(e) => tracing::error!(
+                            error = %e,
+                            "redis revocation store unusable, revocations kept in memory"
+                        ),
+                    }
+                }
+            }
+            Box::new(MemoryStore::default())
+        })
+        .as_ref()
+}
+
+/// Refuses `credential` from now on, for `ttl` or else
+/// `gateway.auth.revocation_ttl_secs`.
+pub async fn revoke(credential: &Credential, ttl: Option<Duration>) -> anyhow::Result<()> {
+    let ttl = ttl
+        .unwrap_or_else(|| Duration::from_secs(config::current().gateway.auth.revocation_ttl_secs));
+    store().revoke(&credential.id(), ttl).await
+}
+
+async fn revoked(req: &Request<Body>) -> Result<(), AuthError> {
+    let credential = match Credential::of(req) {
+        Some(credential) => credential,
+        None => return Ok(()),
+    };
+    match store().is_revoked(&credential.id()).await {
+        Ok(false) => Ok(()),
+        Ok(true) => Err(AuthError::Revoked),
+        Err(e) if config::current().gateway.auth.fail_open => {
+            tracing::warn!(error = %e, "revocation store failed, request let through");
+            Ok(())
+        }
+        Err(e) => {
+            tracing::error!(error = %e, "revocation store failed, request refused");
+            Err(AuthError::Store(e))
+        }
+    }
+}
+
+/// Err when the credential of `req` was revoked, or with
+/// `gateway.auth.oidc` when it has no valid token. Without oidc requests
+/// without a credential pass, whether they need one is up to the services.
+pub async fn check(req: &mut Request<Body>) -> Result<(), AuthError> {
+    let checked = match revoked(req).await {
+        #[cfg(feature = "oidc")]
+        Ok(()) => match &config::current().gateway.auth.oidc {
+            Some(oidc) => oidc::authenticate(oidc, req).await,
+            None => Ok(()),
+        },
+        checked => checked,
+    };
+    if let Err(e) = &checked {
+        rejected(req, e);
+    }
+    checked
+}
+
+/// Err when the route of `req` has `policy.hmac` and `req` is not signed by
+/// a client of `gateway.auth.hmac`, or was seen before.
+pub async fn check_signature(req: &mut Request<Body>) -> Result<(), AuthError> {
+    let checked = hmac::check(req).await;
+    if let Err(e) = &checked {
+        rejected(req, e);
+    }
+    checked
+}
+
+/// Whether `req` signs in with `credentials`, user:password, over basic
+/// auth.
+pub fn signed_in(req: &Request<Body>, credentials: &str) -> bool {
+    let given = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Basic "))
+        .and_then(|v| {
+            base64::engine::general_purpose::STANDARD
+                .decode(v.trim())
+                .ok()
+        });
+    // digests, so that comparing them takes as long whatever was given.
+    given.is_some_and(|given| Sha256::digest(given) == Sha256::digest(credentials.as_bytes()))
+}
+
+/// Err unless `req` signs in with `gateway.admin.basic_auth`, for the admin
+/// endpoints of the gateway; nobody does without it.
+pub fn check_admin(req: &Request<Body>) -> Result<(), AuthError> {
+    let checked = admin(&config::current().gateway.admin, req);
+    if let Err(e) = &checked {
+        rejected(req, e);
+    }
+    checked
+}
+
+fn admin(config: &AdminConfig, req: &Request<Body>) -> Result<(), AuthError> {
+    match &config.basic_auth {
+        Some(credentials) if signed_in(req, credentials) => Ok(()),
+        _ => Err(AuthError::NotAdmin),
+    }
+}
+
+// counts and audits `req` refused for `e`.
+fn rejected(req: &Request<Body>, e: &AuthError) {
+    if let Some(reason) = e.reason() {
+        MetricsRegistry::global()
+            .counter("crossgate_auth_rejected_total", &[("reason", reason)])
+            .inc();
+        audit::record(
+            AuditEvent::new(AuditKind::AuthFailure, reason)
+                .request(req)
+                .detail("error", e),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn revoked_credentials_until_their_ttl() {
+        let req = Request::get("/t/ums")
+            .header(AUTHORIZATION, "bearer abc")
+            .header("x-api-key", "k1")
+            .body(Body::empty())
+            .unwrap();
+        let bearer = Credential::of(&req).unwrap();
+        assert_eq!(bearer, Credential::Bearer("abc".to_string()));
+        assert!(bearer.id().starts_with("bearer:"));
+        assert!(!bearer.id().contains("abc"));
+        let req = Request::get("/t/ums")
+            .header("x-api-key", "k1")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(
+            Credential::of(&req),
+            Some(Credential::ApiKey("k1".to_string()))
+        );
+
+        let store = MemoryStore::default();
+        store
+            .revoke(&bearer.id(), Duration::from_secs(60))
+            .await
+            .unwrap();
+        store.revoke("key:x", Duration::ZERO).await.unwrap();
+        assert!(store.is_revoked(&bearer.id()).await.unwrap());
+        assert!(!store.is_revoked("key:x").await.unwrap());
+        assert!(!store.is_revoked("key:y").await.unwrap());
+    }
+
+    #[test]
+    fn admins_sign_in_with_basic_auth() {
+        let req = |authorization: Option<&str>| {
+            let mut req = Request::post("/admin/revocations");
+            if let Some(credentials) = authorization {
+                let encoded = base64::engine::general_purpose::STANDARD.encode(credentials);
+                req = req.header(AUTHORIZATION, format!("Basic {}", encoded));
+            }
+            req.body(Body::empty()).unwrap()
+        };
+        let config = AdminConfig {
+            basic_auth: Some("ops:secret".to_string()),
+        };
+        assert!(admin(&config, &req(Some("ops:secret"))).is_ok());
+        assert!(admin(&config, &req(Some("ops:other"))).is_err());
+        assert!(admin(&config, &req(None)).is_err());
+        // nobody without credentials configured.
+        assert!(admin(&AdminConfig::default(), &req(Some("ops:secret"))).is_err());
+
+        let res = AuthError::NotAdmin.response();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        assert!(res.headers().contains_key(WWW_AUTHENTICATE));
+    }
+}
@@ -0,0 +1,532 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use hyper::header::{HeaderName, HeaderValue, ACCEPT, CONTENT_TYPE, COOKIE};
+use hyper::{Body, Method, Request, Uri};
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use once_cell::sync::Lazy;
+use rand::Rng;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+use super::AuthError;
+use crate::config::OidcConfig;
+
+// keys are fetched again for a token of an unknown one at most this often,
+// and the provider asked again this long after it failed.
+const MIN_REFETCH: Duration = Duration::from_secs(30);
+// how long a browser has to sign in at the provider.
+const SIGN_IN_TIMEOUT: Duration = Duration::from_secs(600);
+// sign ins pending at once, more are refused until some finish or expire.
+const MAX_PENDING: usize = 10_000;
+
+/// The claims of the token a request was authenticated with, in its
+/// extensions.
+#[derive(Debug, Clone)]
+pub struct Claims(pub Map<String, Value>);
+
+impl Claims {
+    pub fn subject(&self) -> Option<&str> {
+        self.0.get("sub")?.as_str()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct Metadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+struct Provider {
+    metadata: Metadata,
+    keys: JwkSet,
+    // fetched again after this.
+    refresh_at: Instant,
+    // not fetched again for an unknown key before this.
+    refetch_at: Instant,
+}
+
+static PROVIDER: Lazy<tokio::sync::Mutex<Option<Arc<Provider>>>> =
+    Lazy::new(|| tokio::sync::Mutex::new(None));
+
+// a browser sent to sign in, by the state it comes back with.
+struct Pending {
+    return_to: String,
+    nonce: String,
+    at: Instant,
+}
+
+static PENDING: Lazy<Mutex<HashMap<String, Pending>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+async fn fetch(req: Request<Body>) -> anyhow::Result<Value> {
+    let uri = req.uri().clone();
+    let res = match uri.scheme_str() == Some("https") {
+        true => {
+            net::get_tls_proxy_client(None)
+                .client()
+                .request(req)
+                .await?
+        }
+        false => net::get_proxy_client().client().request(req).await?,
+    };
+    let status = res.status();
+    let body = hyper::body::to_bytes(res.into_body()).await?;
+    if !status.is_success() {
+        anyhow::bail!("{} answered {}", uri, status);
+    }
+    Ok(serde_json::from_slice(&body)?)
+}
+
+async fn get<T: DeserializeOwned>(url: &str) -> anyhow::Result<T> {
+    let req = Request::get(url).body(Body::empty())?;
+    Ok(serde_json::from_value(fetch(req).await?)?)
+}
+
+async fn discover(oidc: &OidcConfig) -> anyhow::Result<Provider> {
+    let metadata: Metadata = get(&oidc.discovery_url()).await?;
+    if metadata.issuer.trim_end_matches('/') != oidc.issuer.trim_end_matches('/') {
+        anyhow::bail!(
+            "provider is the issuer {}, not {}",
+            metadata.issuer,
+            oidc.issuer
+        );
+    }
+    let keys = get(&metadata.jwks_uri).await?;
+    let now = Instant::now();
+    Ok(Provider {
+        metadata,
+        keys,
+        refresh_at: now + Duration::from_secs(oidc.refresh_secs),
+        refetch_at: now + MIN_REFETCH,
+    })
+}
+
+// the provider as last fetched; fetched again once it is due, or when
+// `stale` lacks the key of a token.
+async fn provider(
+    oidc: &OidcConfig,
+    stale: Option<&Arc<Provider>>,
+) -> anyhow::Result<Arc<Provider>> {
+    let mut provider = PROVIDER.lock().await;
+    let now = Instant::now();
+    if let Some(current) = provider.as_ref() {
+        let rotated = stale.is_some_and(|s| Arc::ptr_eq(s, current) && now >= current.refetch_at);
+        if now < current.refresh_at && !rotated {
+            return Ok(current.clone());
+        }
+    }
+
+    match discover(oidc).await {
+        Ok(fresh) => {
+            let fresh = Arc::new(fresh);
+            *provider = Some(fresh.clone());
+            Ok(fresh)
+        }
+        // the keys known are used on while the provider cannot be reached.
+        Err(e) => match provider.as_ref() {
+            Some(current) => {
+                tracing::warn!(error = %e, "oidc provider refresh failed");
+                let current = Arc::new(Provider {
+                    metadata: current.metadata.clone(),
+                    keys: current.keys.clone(),
+                    refresh_at: now + MIN_REFETCH,
+                    refetch_at: now + MIN_REFETCH,
+                });
+                *provider = Some(current.clone());
+                Ok(current)
+            }
+            None => Err(e),
+        },
+    }
+}
+
+#[derive(Debug)]
+enum Invalid {
+    UnknownKey,
+    Token(String),
+}
+
+fn verify(
+    keys: &JwkSet,
+    issuer: &str,
+    audiences: &[String],
+    token: &str,
+) -> Result<Claims, Invalid> {
+    let header = jsonwebtoken::decode_header(token).map_err(|e| Invalid::Token(e.to_string()))?;
+    // the keys of a provider are public, a token signed with one as a
+    // shared secret proves nothing.
+    if matches!(
+        header.alg,
+        Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512
+    ) {
+        return Err(Invalid::Token(format!("{:?} is not accepted", header.alg)));
+    }
+    let jwk = match &header.kid {
+        Some(kid) => keys.find(kid),
+        None if keys.keys.len() == 1 => keys.keys.first(),
+        None => None,
+    };
+    let key = DecodingKey::from_jwk(jwk.ok_or(Invalid::UnknownKey)?)
+        .map_err(|e| Invalid::Token(e.to_string()))?;
+
+    let mut validation = Validation::new(header.alg);
+    validation.set_issuer(&[issuer]);
+    validation.set_audience(audiences);
+    let data = jsonwebtoken::decode::<Map<String, Value>>(token, &key, &validation)
+        .map_err(|e| Invalid::Token(e.to_string()))?;
+    Ok(Claims(data.claims))
+}
+
+async fn validate(oidc: &OidcConfig, token: &str) -> Result<Claims, AuthError> {
+    let audiences = oidc.audiences();
+    let mut current = provider(oidc, None).await.map_err(AuthError::Provider)?;
+    let mut verified = verify(&current.keys, &current.metadata.issuer, &audiences, token);
+    if let Err(Invalid::UnknownKey) = verified {
+        current = provider(oidc, Some(&current))
+            .await
+            .map_err(AuthError::Provider)?;
+        verified = verify(&current.keys, &current.metadata.issuer, &audiences, token);
+    }
+    verified.map_err(|e| match e {
+        Invalid::UnknownKey => AuthError::InvalidToken("signed with an unknown key".to_string()),
+        Invalid::Token(e) => AuthError::InvalidToken(e),
+    })
+}
+
+// the value of the session cookie of `req`.
+pub(super) fn session(oidc: &OidcConfig, req: &Request<Body>) -> Option<String> {
+    req.headers()
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .filter_map(|c| c.trim().split_once('='))
+        .find(|(name, _)| *name == oidc.cookie)
+        .map(|(_, value)| value.to_string())
+}
+
+fn encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn decode(s: &str) -> String {
+    let s = s.as_bytes();
+    let mut out = Vec::with_capacity(s.len());
+    let mut i = 0;
+    while i < s.len() {
+        let hex = s
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok());
+        match (s[i], hex.and_then(|h| u8::from_str_radix(h, 16).ok())) {
+            (b'%', Some(b)) => {
+                out.push(b);
+                i += 3;
+                continue;
+            }
+            (b'+', _) => out.push(b' '),
+            (b, _) => out.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn form(pairs: &[(&str, &str)]) -> String {
+    pairs
+        .iter()
+        .map(|(k, v)| format!("{}={}", encode(k), encode(v)))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+fn random() -> String {
+    let bytes = rand::thread_rng().gen::<[u8; 16]>();
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// requests of browsers, sent to sign in rather than refused.
+fn browser(req: &Request<Body>) -> bool {
+    req.method() == Method::GET
+        && req
+            .headers()
+            .get(ACCEPT)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.contains("text/html"))
+}
+
+// sends the browser of `req` to the provider, to come back to where it was.
+async fn sign_in(oidc: &OidcConfig, redirect_uri: &str, req: &Request<Body>) -> AuthError {
+    let provider = match provider(oidc, None).await {
+        Ok(provider) => provider,
+        Err(e) => return AuthError::Provider(e),
+    };
+    let (state, nonce) = (random(), random());
+    {
+        let mut pending = PENDING.lock().unwrap();
+        pending.retain(|_, p| p.at.elapsed() < SIGN_IN_TIMEOUT);
+        if pending.len() >= MAX_PENDING {
+            return AuthError::Unauthenticated;
+        }
+        let return_to = req.uri().path_and_query().map_or("/", |p| p.as_str());
+        pending.insert(
+            state.clone(),
+            Pending {
+                return_to: return_to.to_string(),
+                nonce: nonce.clone(),
+                at: Instant::now(),
+            },
+        );
+    }
+
+    let endpoint = &provider.metadata.authorization_endpoint;
+    let query = form(&[
+        ("response_type", "code"),
+        ("client_id", &oidc.client_id),
+        ("redirect_uri", redirect_uri),
+        ("scope", &oidc.scopes.join(" ")),
+        ("state", &state),
+        ("nonce", &nonce),
+    ]);
+    let separator = if endpoint.contains('?') { '&' } else { '?' };
+    AuthError::SignIn {
+        location: format!("{}{}{}", endpoint, separator, query),
+    }
+}
+
+// the browser back from the provider: its code is exchanged for an id
+// token, kept in the session cookie.
+async fn signed_in(oidc: &OidcConfig, redirect_uri: &str, req: &Request<Body>) -> AuthError {
+    let query = req
+        .uri()
+        .query()
+        .unwrap_or("")
+        .split('&')
+        .filter_map(|kv| kv.split_once('='))
+        .map(|(k, v)| (decode(k), decode(v)))
+        .collect::<HashMap<_, _>>();
+    if let Some(error) = query.get("error") {
+        return AuthError::InvalidToken(format!("sign in failed: {}", error));
+    }
+    let pending = query
+        .get("state")
+        .and_then(|state| PENDING.lock().unwrap().remove(state))
+        .filter(|p| p.at.elapsed() < SIGN_IN_TIMEOUT);
+    let (pending, code) = match (pending, query.get("code")) {
+        (Some(pending), Some(code)) => (pending, code),
+        _ => return AuthError::InvalidToken("unknown or expired sign in".to_string()),
+    };
+
+    let provider = match provider(oidc, None).await {
+        Ok(provider) => provider,
+        Err(e) => return AuthError::Provider(e),
+    };
+    let mut fields = vec![
+        ("grant_type", "authorization_code"),
+        ("code", code.as_str()),
+        ("redirect_uri", redirect_uri),
+        ("client_id", &oidc.client_id),
+    ];
+    if let Some(secret) = &oidc.client_secret {
+        fields.push(("client_secret", secret));
+    }
+    let exchange = Request::post(&provider.metadata.token_endpoint)
+        .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+        .body(Body::from(form(&fields)));
+    let tokens = match exchange.map_err(anyhow::Error::from) {
+        Ok(exchange) => fetch(exchange).await,
+        Err(e) => Err(e),
+    };
+    let id_token = match tokens {
+        Ok(tokens) => match tokens["id_token"].as_str() {
+            Some(id_token) => id_token.to_string(),
+            None => return AuthError::InvalidToken("no id_token from the provider".to_string()),
+        },
+        Err(e) => return AuthError::Provider(e),
+    };
+
+    let claims = match validate(oidc, &id_token).await {
+        Ok(claims) => claims,
+        Err(e) => return e,
+    };
+    if claims.0.get("nonce").and_then(Value::as_str) != Some(pending.nonce.as_str()) {
+        return AuthError::InvalidToken("id_token of another sign in".to_string());
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let max_age = claims
+        .0
+        .get("exp")
+        .and_then(Value::as_u64)
+        .map_or(0, |exp| exp.saturating_sub(now));
+    let secure = if redirect_uri.starts_with("https://") {
+        "; Secure"
+    } else {
+        ""
+    };
+    AuthError::SignedIn {
+        location: pending.return_to,
+        cookie: format!(
+            "{}={}; Path=/; HttpOnly; SameSite=Lax; Max-Age={}{}",
+            oidc.cookie, id_token, max_age, secure
+        ),
+    }
+}
+
+/// Err unless `req` has a valid token, in its authorization header or
+/// session cookie, or is under a public path. Its claims go into the
+/// extensions of `req`.
+pub(super) async fn authenticate(
+    oidc: &OidcConfig,
+    req: &mut Request<Body>,
+) -> Result<(), AuthError> {
+    let subject_header = oidc
+        .subject_header
+        .as_ref()
+        .and_then(|h| HeaderName::from_bytes(h.as_bytes()).ok());
+    if let Some(header) = &subject_header {
+        req.headers_mut().remove(header);
+    }
+
+    let redirect_uri = oidc.redirect_uri.as_deref();
+    let callback = redirect_uri.and_then(|uri| uri.parse::<Uri>().ok());
+    if let (Some(uri), Some(callback)) = (redirect_uri, &callback) {
+        if req.uri().path() == callback.path() {
+            return Err(signed_in(oidc, uri, req).await);
+        }
+    }
+    let path = req.uri().path();
+    let public = oidc.public_paths.iter().any(|p| {
+        let p = p.trim_end_matches('/');
+        path == p
+            || path
+                .strip_prefix(p)
+                .is_some_and(|rest| rest.starts_with('/'))
+    });
+    if public {
+        return Ok(());
+    }
+
+    let (token, from_cookie) = match (super::bearer(req), session(oidc, req)) {
+        (Some(token), _) => (token, false),
+        (None, Some(token)) => (token, true),
+        (None, None) => {
+            return match redirect_uri {
+                Some(uri) if browser(req) => Err(sign_in(oidc, uri, req).await),
+                _ => Err(AuthError::Unauthenticated),
+            }
+        }
+    };
+    let claims = match (validate(oidc, &token).await, redirect_uri) {
+        (Ok(claims), _) => claims,
+        // an expired session signs in again.
+        (Err(AuthError::InvalidToken(_)), Some(uri)) if from_cookie && browser(req) => {
+            return Err(sign_in(oidc, uri, req).await)
+        }
+        (Err(e), _) => return Err(e),
+    };
+
+    let subject = claims
+        .subject()
+        .and_then(|sub| HeaderValue::from_str(sub).ok());
+    if let (Some(header), Some(subject)) = (subject_header, subject) {
+        req.headers_mut().insert(header, subject);
+    }
+    req.extensions_mut().insert(claims);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use base64::Engine;
+    use jsonwebtoken::{EncodingKey, Header};
+
+    #[test]
+    fn tokens_verified_against_the_provider_keys() {
+        let pair = rcgen::KeyPair::generate(&rcgen::PKCS_ECDSA_P256_SHA256).unwrap();
+        let point = pair.public_key_raw();
+        let jwks = serde_json::json!({ "keys": [{
+            "kty": "EC",
+            "crv": "P-256",
+            "kid": "k1",
+            "x": URL_SAFE_NO_PAD.encode(&point[1..33]),
+            "y": URL_SAFE_NO_PAD.encode(&point[33..]),
+        }]});
+        let keys: JwkSet = serde_json::from_value(jwks).unwrap();
+        let key = EncodingKey::from_ec_pem(pair.serialize_pem().as_bytes()).unwrap();
+        let exp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + 60;
+        let sign = |kid: &str, aud: &str, exp: u64| {
+            let mut header = Header::new(Algorithm::ES256);
+            header.kid = Some(kid.to_string());
+            let claims = serde_json::json!({
+                "iss": "https://idp.example.org",
+                "aud": aud,
+                "sub": "u1",
+                "exp": exp,
+            });
+            jsonwebtoken::encode(&header, &claims, &key).unwrap()
+        };
+        let issuer = "https://idp.example.org";
+        let audiences = ["gateway".to_string()];
+
+        let claims = verify(&keys, issuer, &audiences, &sign("k1", "gateway", exp)).unwrap();
+        assert_eq!(claims.subject(), Some("u1"));
+        assert!(matches!(
+            verify(&keys, issuer, &audiences, &sign("k2", "gateway", exp)),
+            Err(Invalid::UnknownKey)
+        ));
+        assert!(matches!(
+            verify(&keys, issuer, &audiences, &sign("k1", "other", exp)),
+            Err(Invalid::Token(_))
+        ));
+        assert!(matches!(
+            verify(
+                &keys,
+                issuer,
+                &audiences,
+                &sign("k1", "gateway", exp - 3600)
+            ),
+            Err(Invalid::Token(_))
+        ));
+        assert!(matches!(
+            verify(
+                &keys,
+                "https://other",
+                &audiences,
+                &sign("k1", "gateway", exp)
+            ),
+            Err(Invalid::Token(_))
+        ));
+    }
+
+    #[test]
+    fn form_encoding_round_trips() {
+        let encoded = form(&[
+            ("redirect_uri", "https://gw/cb?a=1&b=2"),
+            ("scope", "openid email"),
+        ]);
+        assert_eq!(
+            encoded,
+            "redirect_uri=https%3A%2F%2Fgw%2Fcb%3Fa%3D1%26b%3D2&scope=openid%20email"
+        );
+        assert_eq!(decode("https%3A%2F%2Fgw%2Fcb+x%2"), "https://gw/cb x%2");
+    }
+}
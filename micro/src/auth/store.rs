@@ -0,0 +1,94 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use futures::future::BoxFuture;
+
+/// Where revoked credentials are listed, by their `Credential::id`, until
+/// their ttl runs out.
+pub trait RevocationStore: Send + Sync {
+    fn revoke<'a>(&'a self, id: &'a str, ttl: Duration) -> BoxFuture<'a, anyhow::Result<()>>;
+
+    fn is_revoked<'a>(&'a self, id: &'a str) -> BoxFuture<'a, anyhow::Result<bool>>;
+}
+
+/// Revocations kept by this process alone.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    revoked: Mutex<HashMap<String, Instant>>,
+}
+
+impl RevocationStore for MemoryStore {
+    fn revoke<'a>(&'a self, id: &'a str, ttl: Duration) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move {
+            let now = Instant::now();
+            let mut revoked = self.revoked.lock().unwrap();
+            revoked.retain(|_, until| *until > now);
+            revoked.insert(id.to_string(), now + ttl);
+            Ok(())
+        })
+    }
+
+    fn is_revoked<'a>(&'a self, id: &'a str) -> BoxFuture<'a, anyhow::Result<bool>> {
+        Box::pin(async move {
+            let revoked = self.revoked.lock().unwrap();
+            Ok(revoked.get(id).is_some_and(|until| *until > Instant::now()))
+        })
+    }
+}
+
+/// Revocations shared by every gateway on one redis, each a key under
+/// `prefix` expiring with its ttl; other processes may revoke by setting
+/// such keys themselves.
+#[cfg(feature = "redis")]
+pub struct RedisStore {
+    client: redis::Client,
+    prefix: String,
+    conn: tokio::sync::OnceCell<redis::aio::ConnectionManager>,
+}
+
+#[cfg(feature = "redis")]
+impl RedisStore {
+    /// Connects on first use, and again whenever the connection breaks.
+    pub fn new(url: &str, prefix: &str) -> anyhow::Result<Self> {
+        Ok(Self {
+            client: redis::Client::open(url)?,
+            prefix: prefix.to_string(),
+            conn: tokio::sync::OnceCell::new(),
+        })
+    }
+
+    async fn conn(&self) -> anyhow::Result<redis::aio::ConnectionManager> {
+        let conn = self
+            .conn
+            .get_or_try_init(|| redis::aio::ConnectionManager::new(self.client.clone()))
+            .await?;
+        Ok(conn.clone())
+    }
+}
+
+#[cfg(feature = "redis")]
+impl RevocationStore for RedisStore {
+    fn revoke<'a>(&'a self, id: &'a str, ttl: Duration) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move {
+            redis::cmd("SET")
+                .arg(format!("{}{}", self.prefix, id))
+                .arg(1)
+                .arg("EX")
+                .arg(ttl.as_secs().max(1))
+                .query_async::<_, ()>(&mut self.conn().await?)
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn is_revoked<'a>(&'a self, id: &'a str) -> BoxFuture<'a, anyhow::Result<bool>> {
+        Box::pin(async move {
+            let exists = redis::cmd("EXISTS")
+                .arg(format!("{}{}", self.prefix, id))
+                .query_async::<_, bool>(&mut self.conn().await?)
+                .await?;
+            Ok(exists)
+        })
+    }
+}
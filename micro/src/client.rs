@@ -0,0 +1,252 @@
+use std::time::Duration;
+
+use hyper::body::Bytes;
+use hyper::{Body, Method, Request, Response, StatusCode};
+
+use crate::config;
+use crate::register::DEFAULT_PROTOCOL;
+use crate::Register;
+
+const DEFAULT_RETRIES: u32 = 2;
+const DEFAULT_RETRY_BACKOFF: Duration = Duration::from_millis(50);
+
+#[derive(Debug, thiserror::Error)]
+pub enum ClientError {
+    #[error("resolve {service} failed: {source:#}")]
+    Resolve {
+        service: String,
+        #[source]
+        source: anyhow::Error,
+    },
+    #[error("{0} has no instance serving requests")]
+    NoInstance(String),
+    #[error("invalid request: {0}")]
+    InvalidRequest(#[from] hyper::http::Error),
+    #[error("request to {addr} failed: {source}")]
+    Request {
+        addr: String,
+        #[source]
+        source: hyper::Error,
+    },
+    #[error("{addr} did not answer within {timeout:?}")]
+    Timeout { addr: String, timeout: Duration },
+}
+
+/// Calls a service registered in the registry directly, without going
+/// through the gateway: every request resolves the instances of the
+/// service, picks one with the load balancer algorithm they registered and
+/// is retried on another one when it could not be answered.
+///
+/// ```ignore
+/// let ums = micro::Client::for_service("/t/ums").retries(3);
+/// let res = ums.get("/user/1").await?;
+/// ```
+#[derive(Debug, Clone)]
+pub struct Client {
+    service: String,
+    register: Register,
+    protocol: String,
+    timeout: Option<Duration>,
+    retries: u32,
+    retry_backoff: Duration,
+}
+
+impl Client {
+    // per attempt, the timeout is the configured gateway request timeout.
+    pub fn for_service(service: &str) -> Self {
+        Self {
+            service: service.to_string(),
+            register: Register::default(),
+            protocol: DEFAULT_PROTOCOL.to_string(),
+            timeout: config::current().request_timeout(),
+            retries: DEFAULT_RETRIES,
+            retry_backoff: DEFAULT_RETRY_BACKOFF,
+        }
+    }
+
+    // resolve through `register` instead of the process-wide plugin.
+    pub fn register(mut self, register: Register) -> Self {
+        self.register = register;
+        self
+    }
+
+    // call the endpoint the instances publish for `protocol`.
+    pub fn protocol(mut self, protocol: &str) -> Self {
+        self.protocol = protocol.to_string();
+        self
+    }
+
+    // how long one attempt may take, None for no limit.
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    // attempts after the first one, 0 to never retry.
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    // the wait before the first retry, doubled for every further one.
+    pub fn retry_backoff(mut self, backoff: Duration) -> Self {
+        self.retry_backoff = backoff;
+        self
+    }
+
+    pub async fn get(&self, path: &str) -> Result<Response<Body>, ClientError> {
+        self.send(Request::get(path).body(Bytes::new())?).await
+    }
+
+    pub async fn post(
+        &self,
+        path: &str,
+        body: impl Into<Bytes>,
+    ) -> Result<Response<Body>, ClientError> {
+        self.send(Request::post(path).body(body.into())?).await
+    }
+
+    /// Sends `req`, whose uri is the path and query on the service. The
+    /// body is kept to send it again on a retry.
+    #[tracing::instrument(
+        name = "client.request",
+        skip(self, req),
+        fields(service = %self.service, method = %req.method(), path = req.uri().path()),
+        err
+    )]
+    pub async fn send(&self, req: Request<Bytes>) -> Result<Response<Body>, ClientError> {
+        let mut backoff = self.retry_backoff;
+        let mut attempt = 0;
+        loop {
+            let res = self.attempt(&req).await;
+            if attempt == self.retries || !retryable(req.method(), &res) {
+                return res;
+            }
+
+            attempt += 1;
+            match &res {
+                Ok(res) => tracing::debug!(attempt, status = %res.status(), "retrying"),
+                Err(e) => tracing::debug!(attempt, error = %e, "retrying"),
+            }
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+    }
+
+    // one instance, picked anew so that a retry can land on another one.
+    async fn attempt(&self, req: &Request<Bytes>) -> Result<Response<Body>, ClientError> {
+        let (lba, endpoint) = self
+            .register
+            .get_web_service(&self.service, &self.protocol)
+            .await
+            .map_err(|source| ClientError::Resolve {
+                service: self.service.clone(),
+                source,
+            })?;
+        let instance = lba
+            .pick(&endpoint)
+            .ok_or_else(|| ClientError::NoInstance(self.service.clone()))?;
+        let tls = instance.tls.as_ref().map(|sni| sni.as_deref());
+        let uri = format!(
+            "{}://{}{}",
+            if tls.is_some() { "https" } else { "http" },
+            instance.addr,
+            req.uri().path_and_query().map_or("/", |p| p.as_str())
+        );
+        let mut request = Request::builder()
+            .method(req.method())
+            .uri(uri)
+            .version(req.version())
+            .body(Body::from(req.body().clone()))?;
+        *request.headers_mut() = req.headers().clone();
+        // the peer continues the trace of the caller.
+        #[cfg(feature = "otel")]
+        crate::telemetry::inject(request.headers_mut());
+
+        let call = async {
+            match tls {
+                Some(sni) => {
+                    net::get_tls_proxy_client(sni)
+                        .client()
+                        .request(request)
+                        .await
+                }
+                None => net::get_proxy_client().client().request(request).await,
+            }
+        };
+        let res = match self.timeout {
+            Some(timeout) => match tokio::time::timeout(timeout, call).await {
+                Ok(res) => res,
+                Err(_) => {
+                    return Err(ClientError::Timeout {
+                        addr: instance.addr.clone(),
+                        timeout,
+                    })
+                }
+            },
+            None => call.await,
+        };
+        res.map_err(|source| ClientError::Request {
+            addr: instance.addr.clone(),
+            source,
+        })
+    }
+}
+
+// whether another instance may get the request. Requests that never reached
+// a peer can always be sent again, others only when they are idempotent.
+fn retryable(method: &Method, res: &Result<Response<Body>, ClientError>) -> bool {
+    let idempotent = method.is_idempotent();
+    match res {
+        Ok(res) => {
+            idempotent
+                && matches!(
+                    res.status(),
+                    StatusCode::BAD_GATEWAY
+                        | StatusCode::SERVICE_UNAVAILABLE
+                        | StatusCode::GATEWAY_TIMEOUT
+                )
+        }
+        Err(ClientError::NoInstance(_)) | Err(ClientError::Resolve { .. }) => true,
+        Err(ClientError::Request { source, .. }) => idempotent || source.is_connect(),
+        Err(ClientError::Timeout { .. }) => idempotent,
+        Err(ClientError::InvalidRequest(_)) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retries_what_did_not_reach_a_peer() {
+        let status = |code| {
+            Ok(Response::builder()
+                .status(code)
+                .body(Body::empty())
+                .unwrap())
+        };
+        let timeout = || {
+            Err(ClientError::Timeout {
+                addr: "10.0.0.1:80".into(),
+                timeout: Duration::from_secs(1),
+            })
+        };
+
+        assert!(retryable(
+            &Method::GET,
+            &status(StatusCode::SERVICE_UNAVAILABLE)
+        ));
+        assert!(!retryable(&Method::GET, &status(StatusCode::NOT_FOUND)));
+        assert!(!retryable(
+            &Method::POST,
+            &status(StatusCode::SERVICE_UNAVAILABLE)
+        ));
+        assert!(retryable(&Method::PUT, &timeout()));
+        assert!(!retryable(&Method::POST, &timeout()));
+        assert!(retryable(
+            &Method::POST,
+            &Err(ClientError::NoInstance("/t/ums".into()))
+        ));
+    }
+}
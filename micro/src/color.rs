@@ -0,0 +1,110 @@
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use once_cell::sync::Lazy;
+
+use crate::config;
+
+/// Where a service stands in a blue/green switch: the color it routes to
+/// and the one before, to roll back to.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct ColorSwitch {
+    pub active: String,
+    pub previous: Option<String>,
+}
+
+// seeded from `gateway.blue_green.active`.
+static SWITCHES: Lazy<RwLock<HashMap<String, ColorSwitch>>> = Lazy::new(|| {
+    let active = &config::current().gateway.blue_green.active;
+    RwLock::new(
+        active
+            .iter()
+            .map(|(service, color)| {
+                let switch = ColorSwitch {
+                    active: color.clone(),
+                    previous: None,
+                };
+                (service.clone(), switch)
+            })
+            .collect(),
+    )
+});
+
+pub fn active_color(service: &str) -> Option<String> {
+    let switches = SWITCHES.read().unwrap();
+    switches.get(service).map(|s| s.active.clone())
+}
+
+pub fn active_colors() -> HashMap<String, ColorSwitch> {
+    SWITCHES.read().unwrap().clone()
+}
+
+/// Sends all traffic of `service` to its instances labelled `color` from
+/// the next request on, and none to the others.
+pub fn set_active_color(service: &str, color: &str) -> ColorSwitch {
+    let mut switches = SWITCHES.write().unwrap();
+    let previous = switches.get(service).map(|s| s.active.clone());
+    let switch = ColorSwitch {
+        active: color.to_string(),
+        previous: previous.filter(|p| p != color),
+    };
+    tracing::info!(service, color, previous = ?switch.previous, "active color set");
+    switches.insert(service.to_string(), switch.clone());
+    switch
+}
+
+/// Switches `service` back to the color it had before, None when it had
+/// none.
+pub fn rollback_color(service: &str) -> Option<ColorSwitch> {
+    let mut switches = SWITCHES.write().unwrap();
+    let switch = switches.get_mut(service)?;
+    let previous = switch.previous.take()?;
+    switch.previous = Some(std::mem::replace(&mut switch.active, previous));
+    tracing::info!(service, color = %switch.active, "active color rolled back");
+    Some(switch.clone())
+}
+
+// back to all instances of `service`, whatever their color.
+pub fn clear_active_color(service: &str) {
+    SWITCHES.write().unwrap().remove(service);
+}
+
+// whether `content` is in the group `service` routes to.
+pub(crate) fn serves(service: &str, content: &plugin::ServiceContent) -> bool {
+    let switches = SWITCHES.read().unwrap();
+    match switches.get(service) {
+        Some(switch) => {
+            let label = &config::current().gateway.blue_green.label;
+            content.metadata.get(label) == Some(&switch.active)
+        }
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn switch_and_roll_back() {
+        let service = "/t/color-test";
+        let mut content = plugin::ServiceContent::default();
+        content.metadata.insert("color".into(), "blue".into());
+        assert!(serves(service, &content));
+
+        set_active_color(service, "blue");
+        assert!(serves(service, &content));
+        let switch = set_active_color(service, "green");
+        assert_eq!(switch.previous.as_deref(), Some("blue"));
+        assert!(!serves(service, &content));
+
+        let switch = rollback_color(service).unwrap();
+        assert_eq!(switch.active, "blue");
+        assert_eq!(active_color(service).as_deref(), Some("blue"));
+        assert!(serves(service, &content));
+
+        clear_active_color(service);
+        assert_eq!(active_color(service), None);
+        assert_eq!(rollback_color(service), None);
+    }
+}
@@ -0,0 +1,3197 @@
+use std::{collections::HashMap, net::SocketAddr, path::Path, time::Duration};
+
+use hyper::header::HeaderName;
+use once_cell::sync::OnceCell;
+use plugin::{get_plugin_type, PluginConfig, PluginType, VaultProvider};
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
+
+use crate::LoadBalancerAlgorithm;
+
+pub const DEFAULT_LISTEN: &str = "0.0.0.0:8080";
+
+static CONFIG: OnceCell<Config> = OnceCell::new();
+
+/// Everything the gateway, the services and their plugin start with, read
+/// from a toml or yaml file by `Config::load`:
+///
+/// ```toml
+/// [gateway]
+/// listen = ["0.0.0.0:8080"]
+/// request_timeout_secs = 30
+/// listener = { header_read_timeout_secs = 5, max_headers = 50 }
+/// routes = [
+///     { prefix = "/api/users", service = "/t/ums", policy = { timeout_secs = 5, retries = 1 } },
+///     { prefix = "/billing", service = "/legacy/billing", upstreams = ["http://10.0.0.7:8080"] },
+///     { prefix = "/events", service = "/t/events", policy = { long_poll = true } },
+/// ]
+/// middleware = ["health", "metrics", "auth"]
+/// coalesce = true
+/// bulkhead = { max_concurrent = 200, services = { "/t/report" = 20 } }
+/// warmup = { connections = 4, path = "/healthz" }
+/// shedding = { max_in_flight = 2000, low_percent = 70 }
+/// capture = { sample_rate = 0.01, header = "x-debug", max_body_bytes = 4096 }
+/// blue_green = { active = { "/t/ums" = "blue" } }
+/// notify = { webhooks = [{ url = "https://hooks.slack.com/services/T0/B0/x", format = "slack" }] }
+/// audit = { sinks = [{ type = "file", path = "/var/log/crossgate/audit.log" }] }
+/// status = { enabled = true, basic_auth = "ops:secret" }
+/// self_register = { service = "/crossgate/gateway", advertise = "10.0.0.5" }
+/// peers = { enabled = true, secret = "shared-secret" }
+///
+/// [gateway.tls]
+/// cert = "/etc/crossgate/cert.pem"
+/// key = "/etc/crossgate/key.pem"
+/// hosts = [{ server_name = "admin.example.org", service = "/t/admin" }]
+///
+/// [registry]
+/// type = "etcd"
+/// addr = "etcd://http://node1:2379,http://node2:2379"
+/// username = "root"
+/// password = "secret"
+///
+/// [lb]
+/// default = "random"
+///
+/// [telemetry]
+/// otlp_endpoint = "http://otel-collector:4317"
+/// ```
+///
+/// The env vars read before still win over the file, see `Config::apply_env`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub gateway: GatewayConfig,
+    pub registry: RegistryConfig,
+    pub lb: LbConfig,
+    pub service: ServiceConfig,
+    pub telemetry: TelemetryConfig,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GatewayConfig {
+    // `DEFAULT_LISTEN` when empty.
+    pub listen: Vec<String>,
+    pub routes: Vec<Route>,
+    // how long a forwarded request may take, 0 for no limit.
+    pub request_timeout_secs: u64,
+    pub long_poll: LongPollConfig,
+    pub listener: ListenerConfig,
+    // serve https on every listen address when set.
+    pub tls: Option<GatewayTlsConfig>,
+    // like `tls`, with certificates from an ACME CA.
+    pub acme: Option<AcmeConfig>,
+    // a plain http listener sending clients on to https.
+    pub http_redirect: Option<HttpRedirectConfig>,
+    // intercepters the crossgate binary runs on every request, in order.
+    pub middleware: Vec<String>,
+    // identical GET and HEAD requests in flight to the same instance share
+    // one upstream call.
+    pub coalesce: bool,
+    pub bulkhead: BulkheadConfig,
+    pub grpc: GrpcConfig,
+    pub warmup: WarmupConfig,
+    pub shedding: SheddingConfig,
+    pub capture: CaptureConfig,
+    pub blue_green: BlueGreenConfig,
+    pub notify: NotifyConfig,
+    pub graphql: Option<GraphqlConfig>,
+    // REST over the gRPC services, with the transcode feature.
+    pub transcode: Option<TranscodeConfig>,
+    pub auth: AuthConfig,
+    pub audit: AuditConfig,
+    pub status: StatusPageConfig,
+    pub admin: AdminConfig,
+    pub self_register: SelfRegisterConfig,
+    pub peers: PeerSyncConfig,
+    pub connect: ConnectConfig,
+    // where clients are, from a MaxMind database, with the geoip feature.
+    pub geoip: Option<GeoIpConfig>,
+    pub tenancy: TenancyConfig,
+}
+
+/// Customers sharing the gateway. A request is a tenant's when it carries
+/// one of its `api_keys` in `gateway.auth.api_key_header`, else when it is
+/// for one of its `hosts`, else when its path is under its `path_prefix`,
+/// taken off before routing. The requests of a tenant are routed by its own
+/// `routes` only, at most `rate_limit` a second over all of them, and
+/// counted under its name. With `required`, the requests of no tenant are
+/// refused with 403.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TenancyConfig {
+    pub required: bool,
+    pub tenants: Vec<TenantConfig>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TenantConfig {
+    pub name: String,
+    // the keys themselves, or their digests as `sha256:<hex>`.
+    pub api_keys: Vec<String>,
+    pub hosts: Vec<String>,
+    pub path_prefix: Option<String>,
+    pub routes: Vec<Route>,
+    // requests per second, in bursts of as many; 0 for no limit.
+    pub rate_limit: u32,
+}
+
+impl TenantConfig {
+    // the route of the tenant for `path`, as `Config::matching_route`.
+    pub fn matching_route(&self, path: &str) -> Option<&Route> {
+        matching_route(&self.routes, path)
+    }
+}
+
+/// Looks the country and region of every client up in the MaxMind
+/// database at `database`, GeoIP2 or GeoLite2 Country or City. Intercepters
+/// find them in the request extensions, upstreams in `country_header` and
+/// `region_header`, which clients cannot set themselves. Clients from a
+/// country or region in `block`, e.g. `KP` or `UA-43`, are refused with 403;
+/// routes steer the others with `policy.geo_services`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GeoIpConfig {
+    // a .mmdb file.
+    pub database: String,
+    pub block: Vec<String>,
+    pub country_header: String,
+    pub region_header: String,
+}
+
+impl Default for GeoIpConfig {
+    fn default() -> Self {
+        Self {
+            database: String::new(),
+            block: vec![],
+            country_header: "x-client-country".to_string(),
+            region_header: "x-client-region".to_string(),
+        }
+    }
+}
+
+/// The gateway as a forward proxy: CONNECT requests that pass the
+/// middleware are tunneled over tcp to destinations in `allow`, each
+/// `host:port` where the host may start with `*.` and either may be `*`,
+/// e.g. `*.example.org:443` or `10.0.0.5:*`. Off unless enabled.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConnectConfig {
+    pub enabled: bool,
+    pub allow: Vec<String>,
+    pub connect_timeout_secs: u64,
+}
+
+impl Default for ConnectConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            allow: vec![],
+            connect_timeout_secs: 10,
+        }
+    }
+}
+
+/// Gateways registered with `self_register` share the instances they read
+/// from the registry: every `interval_secs` each posts those it read since
+/// to `fanout` of the others and takes back those they read later, so that
+/// one started during a registry outage still routes. Peers sign in with
+/// `secret`. Off unless enabled.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PeerSyncConfig {
+    pub enabled: bool,
+    pub secret: Option<String>,
+    pub interval_secs: u64,
+    pub fanout: usize,
+}
+
+impl Default for PeerSyncConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            secret: None,
+            interval_secs: 5,
+            fanout: 2,
+        }
+    }
+}
+
+/// Registers every gateway instance in the registry under `service`, as a
+/// service of type 3 with one registration per listen address, renewed while
+/// it runs and withdrawn when it shuts down, so that L4 balancers, peers or
+/// a DNS controller can find the live gateway fleet. Off while `service` is
+/// empty.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SelfRegisterConfig {
+    pub service: String,
+    // the host registered, a hostname or ip; the local ip when unset.
+    pub advertise: Option<String>,
+    pub metadata: HashMap<String, String>,
+}
+
+/// An html page at / instead of the title, with the services the gateway
+/// routes to, the health of their instances and their recent error rates.
+/// It shows the topology of the services, so it is served only behind the
+/// auth middleware with `gateway.auth.oidc`, or to those signing in with
+/// `basic_auth`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StatusPageConfig {
+    pub enabled: bool,
+    // user:password.
+    pub basic_auth: Option<String>,
+}
+
+/// Who may use the admin middleware of the crossgate binary, captures,
+/// colors and revocations: those signing in with `basic_auth`. Without it the admin
+/// middleware refuses everyone.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    // user:password.
+    pub basic_auth: Option<String>,
+}
+
+/// Where the gateway appends security relevant events, apart from the access
+/// log: auth failures, changes made through the admin middleware, the routes
+/// it starts with and maintenance toggles. Every sink gets every event as a
+/// line of json, in order. Off without sinks.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditConfig {
+    pub sinks: Vec<AuditSinkConfig>,
+    // events waiting for the sinks, those beyond are dropped.
+    pub queue: usize,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self {
+            sinks: vec![],
+            queue: 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditSinkConfig {
+    // file, http or syslog.
+    #[serde(rename = "type")]
+    pub kind: String,
+    // the file of a file sink.
+    pub path: Option<String>,
+    // where an http sink posts events.
+    pub url: Option<String>,
+    // host:port of the syslog server, over udp.
+    pub addr: Option<String>,
+}
+
+/// What the auth middleware checks credentials against: bearer tokens and
+/// api keys revoked before they expire, e.g. at logout, are refused at
+/// once. The memory store is of one gateway, redis is shared by all of them
+/// with the redis feature.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    // where clients send api keys, bearer tokens come in authorization.
+    pub api_key_header: String,
+    // memory or redis.
+    pub revocation_store: String,
+    pub redis_url: Option<String>,
+    // of the keys revoked credentials are listed under in redis.
+    pub redis_prefix: String,
+    // how long a credential revoked without a ttl stays listed, at least
+    // as long as tokens are valid.
+    pub revocation_ttl_secs: u64,
+    // let requests through when the store cannot be asked.
+    pub fail_open: bool,
+    pub oidc: Option<OidcConfig>,
+    pub hmac: Option<HmacConfig>,
+}
+
+/// Partners signing their requests, on the routes with `policy.hmac`, for
+/// the hmac middleware. A request names its client in `client_header`, the
+/// unix time it was signed at in `timestamp_header`, and carries in
+/// `signature_header` the hex HMAC-SHA256, with the secret of its client,
+/// of its method, path and query, timestamp and the hex SHA-256 of its
+/// body, a line each. Requests signed more than `window_secs` away from the
+/// clock of the gateway are refused, as are signatures seen before.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HmacConfig {
+    // the secret of each client, by its id.
+    pub clients: HashMap<String, String>,
+    pub client_header: String,
+    pub timestamp_header: String,
+    pub signature_header: String,
+    pub window_secs: u64,
+}
+
+impl Default for HmacConfig {
+    fn default() -> Self {
+        Self {
+            clients: HashMap::new(),
+            client_header: "x-client-id".to_string(),
+            timestamp_header: "x-signature-timestamp".to_string(),
+            signature_header: "x-signature".to_string(),
+            window_secs: 300,
+        }
+    }
+}
+
+/// Requires a token of an OpenID Connect provider on every request but
+/// those under `public_paths`, with the oidc feature. The provider is found
+/// from its `issuer`, its keys are fetched again every `refresh_secs` and
+/// when a token is signed with an unknown one. With `redirect_uri`, browsers
+/// without a token are sent to sign in and come back with a session cookie.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OidcConfig {
+    pub issuer: String,
+    // instead of {issuer}/.well-known/openid-configuration.
+    pub discovery_url: Option<String>,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    // the aud tokens must have one of, client_id when empty.
+    pub audiences: Vec<String>,
+    pub refresh_secs: u64,
+    pub public_paths: Vec<String>,
+    // where the provider sends browsers back to, on the gateway; the code
+    // flow is off without it.
+    pub redirect_uri: Option<String>,
+    pub scopes: Vec<String>,
+    pub cookie: String,
+    // the sub of the token is passed upstream in it, clients cannot set it.
+    pub subject_header: Option<String>,
+}
+
+impl Default for OidcConfig {
+    fn default() -> Self {
+        Self {
+            issuer: String::new(),
+            discovery_url: None,
+            client_id: String::new(),
+            client_secret: None,
+            audiences: vec![],
+            refresh_secs: 3600,
+            public_paths: vec![],
+            redirect_uri: None,
+            scopes: vec!["openid".to_string()],
+            cookie: "crossgate_session".to_string(),
+            subject_header: None,
+        }
+    }
+}
+
+impl OidcConfig {
+    pub fn discovery_url(&self) -> String {
+        match &self.discovery_url {
+            Some(url) => url.clone(),
+            None => format!(
+                "{}/.well-known/openid-configuration",
+                self.issuer.trim_end_matches('/')
+            ),
+        }
+    }
+
+    pub fn audiences(&self) -> Vec<String> {
+        match self.audiences.is_empty() {
+            true => vec![self.client_id.clone()],
+            false => self.audiences.clone(),
+        }
+    }
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            api_key_header: "x-api-key".to_string(),
+            revocation_store: "memory".to_string(),
+            redis_url: None,
+            redis_prefix: "crossgate:revoked:".to_string(),
+            revocation_ttl_secs: 86400,
+            fail_open: false,
+            oidc: None,
+            hmac: None,
+        }
+    }
+}
+
+/// One GraphQL endpoint at `path` over the GraphQL APIs of `services`, with
+/// the graphql feature. Each root field of a query or mutation is resolved
+/// by the service whose schema has it, as introspection tells every
+/// `refresh_secs`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GraphqlConfig {
+    pub path: String,
+    // the first one listed wins a field more of them have.
+    pub services: Vec<String>,
+    // where a service serves graphql after its name, e.g. /t/ums/graphql.
+    pub service_path: String,
+    pub refresh_secs: u64,
+}
+
+impl Default for GraphqlConfig {
+    fn default() -> Self {
+        Self {
+            path: "/graphql".to_string(),
+            services: vec![],
+            service_path: "/graphql".to_string(),
+            refresh_secs: 30,
+        }
+    }
+}
+
+/// The unary methods of gRPC services called with json over http, with the
+/// transcode feature. Every method with a `google.api.http` annotation in
+/// `descriptors`, file descriptor sets as `protoc --include_imports
+/// --descriptor_set_out` writes them, is served at the paths it annotates:
+/// its request made of the path, query and body, sent to a grpc instance of
+/// the service of its gRPC service in `services`, and its response answered
+/// as json. Instances registered with tls are not transcoded to.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TranscodeConfig {
+    pub descriptors: Vec<String>,
+    // by the full name of the gRPC service, e.g. `"ums.v1.Users" = "/t/ums"`.
+    pub services: HashMap<String, String>,
+}
+
+/// Webhooks told about discovery problems of the services behind the
+/// gateway: an instance registered, expired or flapping, a service left
+/// without an instance to route to. Off without webhooks, unless flapping
+/// instances are watched for.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotifyConfig {
+    pub webhooks: Vec<Webhook>,
+    // watched besides the services of the routes and tls hosts and those
+    // the gateway has routed requests to.
+    pub services: Vec<String>,
+    pub poll_interval_secs: u64,
+    pub flapping: FlappingConfig,
+}
+
+impl Default for NotifyConfig {
+    fn default() -> Self {
+        Self {
+            webhooks: vec![],
+            services: vec![],
+            poll_interval_secs: 5,
+            flapping: FlappingConfig::default(),
+        }
+    }
+}
+
+/// An instance registering and expiring `transitions` times within
+/// `window_secs` is flapping: it is reported once, and with `damp_secs` left
+/// out of routing until it stays put that long, unless no other instance of
+/// its service is left. Off while `transitions` is 0.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FlappingConfig {
+    pub transitions: usize,
+    pub window_secs: u64,
+    pub damp_secs: u64,
+}
+
+impl Default for FlappingConfig {
+    fn default() -> Self {
+        Self {
+            transitions: 0,
+            window_secs: 300,
+            damp_secs: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Webhook {
+    pub url: String,
+    // json, the event as is, or slack, a message in `text` the way Slack
+    // incoming webhooks take it.
+    pub format: String,
+}
+
+impl Default for Webhook {
+    fn default() -> Self {
+        Self {
+            url: String::new(),
+            format: "json".to_string(),
+        }
+    }
+}
+
+/// Splits the instances of a service into groups by the value of their
+/// `label` metadata, e.g. blue and green, and routes a service in `active`
+/// to its group of that color only. Switched at runtime with
+/// `micro::set_active_color`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BlueGreenConfig {
+    pub label: String,
+    // the color each service starts with, the others get all instances.
+    pub active: HashMap<String, String>,
+}
+
+impl Default for BlueGreenConfig {
+    fn default() -> Self {
+        Self {
+            label: "color".to_string(),
+            active: HashMap::new(),
+        }
+    }
+}
+
+/// Which requests the gateway keeps a copy of, headers and the start of the
+/// bodies, for debugging; see `micro::captures`. Off unless something is
+/// selected.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CaptureConfig {
+    // the share of all requests captured, 0 to 1.
+    pub sample_rate: f64,
+    // requests under these path prefixes are always captured.
+    pub paths: Vec<String>,
+    // requests carrying this header are always captured, e.g. x-debug.
+    pub header: Option<String>,
+    // bytes kept of each body, 0 for the headers only.
+    pub max_body_bytes: usize,
+    // captures kept, the oldest are dropped first.
+    pub keep: usize,
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        Self {
+            sample_rate: 0.0,
+            paths: vec![],
+            header: None,
+            max_body_bytes: 0,
+            keep: 100,
+        }
+    }
+}
+
+impl CaptureConfig {
+    pub fn enabled(&self) -> bool {
+        self.sample_rate > 0.0 || !self.paths.is_empty() || self.header.is_some()
+    }
+}
+
+/// How long the upstream of a route with `policy.long_poll` may hold a
+/// request before it answers, instead of `request_timeout_secs`; 0 for no
+/// limit.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LongPollConfig {
+    pub timeout_secs: u64,
+}
+
+impl Default for LongPollConfig {
+    fn default() -> Self {
+        Self { timeout_secs: 300 }
+    }
+}
+
+/// What the gateway allows the clients of its listeners, so that a trickle
+/// of slow ones cannot hold all of its connections: the head of a request,
+/// the next one on a kept alive connection too, must arrive in full within
+/// `header_read_timeout_secs`, and a request body may not pause longer than
+/// `body_read_timeout_secs` between two chunks. 0 turns a timeout off.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListenerConfig {
+    pub header_read_timeout_secs: u64,
+    pub body_read_timeout_secs: u64,
+    // of the head of a request, answered 431 beyond; at least 8192.
+    pub max_header_bytes: usize,
+    // answered 431 beyond, at most 100.
+    pub max_headers: usize,
+    // connections open at once from one client ip, on all listeners; those
+    // beyond are closed as they are accepted. 0 for no limit.
+    pub max_connections_per_ip: usize,
+}
+
+impl Default for ListenerConfig {
+    fn default() -> Self {
+        Self {
+            header_read_timeout_secs: 10,
+            body_read_timeout_secs: 30,
+            max_header_bytes: 64 * 1024,
+            max_headers: 100,
+            max_connections_per_ip: 0,
+        }
+    }
+}
+
+/// Connections the gateway sets up to an instance the first time it sees
+/// it, each with a GET of `path`, before routing to it, so that the first
+/// requests it gets do not wait for connection and tls setup. An instance
+/// still warming up gets traffic only when its service has no other. Off
+/// with 0 connections.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WarmupConfig {
+    pub connections: usize,
+    pub path: String,
+    // of each warm-up request.
+    pub timeout_secs: u64,
+}
+
+impl Default for WarmupConfig {
+    fn default() -> Self {
+        Self {
+            connections: 0,
+            path: "/".to_string(),
+            timeout_secs: 5,
+        }
+    }
+}
+
+/// Caps the requests in flight to each service, and so the upstream
+/// connections it holds, so that a slow one cannot take all of them.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BulkheadConfig {
+    // per service, 0 for no limit.
+    pub max_concurrent: usize,
+    // how long a request waits for a slot before it is answered with 503,
+    // 0 to answer at once.
+    pub max_wait_ms: u64,
+    // the max_concurrent of single services, by name.
+    pub services: HashMap<String, usize>,
+}
+
+/// The http/2 channels grpc requests go upstream on, long lived and shared
+/// by the requests to an instance: at most `max_streams` of them at a time
+/// on a channel, in at most `max_channels` to an instance before the
+/// streams queue on the least busy one. Channels are pinged every
+/// `keep_alive_secs` and dropped once a ping goes unanswered, or after
+/// `idle_secs` without a request.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GrpcConfig {
+    pub max_streams: usize,
+    pub max_channels: usize,
+    pub keep_alive_secs: u64,
+    pub idle_secs: u64,
+}
+
+impl Default for GrpcConfig {
+    fn default() -> Self {
+        Self {
+            max_streams: 100,
+            max_channels: 4,
+            keep_alive_secs: 30,
+            idle_secs: 300,
+        }
+    }
+}
+
+/// Sheds requests by their `Priority` once too many are in flight through
+/// the gateway: `low` ones past `low_percent` of `max_in_flight`, `normal`
+/// ones past all of it, `critical` ones never. Off at 0.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SheddingConfig {
+    pub max_in_flight: usize,
+    pub low_percent: usize,
+}
+
+impl Default for SheddingConfig {
+    fn default() -> Self {
+        Self {
+            max_in_flight: 0,
+            low_percent: 80,
+        }
+    }
+}
+
+/// How much the requests of a route matter when the gateway sheds load. An
+/// intercepter may insert one into the extensions of a request, it wins over
+/// the one of the route.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    Critical,
+}
+
+impl Priority {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Priority::Low => "low",
+            Priority::Normal => "normal",
+            Priority::Critical => "critical",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GatewayTlsConfig {
+    // pem files, the chain and its private key.
+    pub cert: String,
+    pub key: String,
+    // by the server name clients ask for, the certificate above for the
+    // others.
+    #[serde(default)]
+    pub hosts: Vec<TlsHost>,
+    pub client_auth: Option<ClientAuthConfig>,
+}
+
+/// Client certificates the gateway asks for in the handshake, verified
+/// against the CAs in the pem file `ca`. Unless `required`, clients may come
+/// without one and routes ask for it with `policy.client_cert`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientAuthConfig {
+    pub ca: String,
+    #[serde(default)]
+    pub required: bool,
+}
+
+impl GatewayTlsConfig {
+    // the host `server_name` is served as, by exact name before wildcards.
+    pub fn host(&self, server_name: &str) -> Option<&TlsHost> {
+        let exact = self
+            .hosts
+            .iter()
+            .find(|h| h.server_name.eq_ignore_ascii_case(server_name));
+        exact.or_else(|| {
+            self.hosts.iter().find(|h| {
+                let Some(suffix) = h.server_name.strip_prefix("*.") else {
+                    return false;
+                };
+                // a wildcard covers a single label.
+                server_name.split_once('.').is_some_and(|(label, rest)| {
+                    !label.is_empty() && rest.eq_ignore_ascii_case(suffix)
+                })
+            })
+        })
+    }
+}
+
+/// A domain sharing the https listeners, e.g. `*.example.org`: presented
+/// its own certificate when it has one and, with `service`, routed to that
+/// service on every path.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsHost {
+    pub server_name: String,
+    pub cert: Option<String>,
+    pub key: Option<String>,
+    pub service: Option<String>,
+}
+
+/// Certificates for the https listeners from an ACME CA, Let's Encrypt by
+/// default, with the acme feature: issued for `domains` unless the cached
+/// one still fits, renewed `renew_before_days` before they expire and
+/// swapped in without a restart.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AcmeConfig {
+    pub domains: Vec<String>,
+    // e.g. mailto:ops@example.org.
+    pub contact: Vec<String>,
+    pub directory: String,
+    // the account key and the certificate, kept across restarts.
+    pub cache_dir: String,
+    // tls-alpn-01, answered on the https listeners on port 443, or http-01.
+    pub challenge: String,
+    // plain http address answering http-01 on port 80, it redirects every
+    // other request to https as `gateway.http_redirect` would.
+    pub http_listen: Option<String>,
+    pub renew_before_days: u32,
+}
+
+impl Default for AcmeConfig {
+    fn default() -> Self {
+        Self {
+            domains: vec![],
+            contact: vec![],
+            directory: "https://acme-v02.api.letsencrypt.org/directory".to_string(),
+            cache_dir: "acme".to_string(),
+            challenge: "tls-alpn-01".to_string(),
+            http_listen: None,
+            renew_before_days: 30,
+        }
+    }
+}
+
+/// A plain http listener on `listen` alongside the https ones, answering
+/// every request with a redirect to the same host and path over https, on
+/// `https_port` or the port of the first listen address. GET and HEAD get
+/// `status`, 301 or 308, the other methods 308 so that they are sent again
+/// as they were. ACME http-01 challenges are answered, not redirected.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpRedirectConfig {
+    pub listen: String,
+    pub https_port: Option<u16>,
+    pub status: u16,
+}
+
+impl Default for HttpRedirectConfig {
+    fn default() -> Self {
+        Self {
+            listen: String::new(),
+            https_port: None,
+            status: 308,
+        }
+    }
+}
+
+/// Sends the paths under `prefix`, or those `pattern` matches, to `service`,
+/// instead of the service named by the first two path segments. With
+/// `rewrite` the upstream gets another path: the prefix replaced by it, or
+/// it with the groups of the pattern filled in, e.g. `^/api/v(\d+)/(.*)`
+/// and `/${2}?ver=${1}`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Route {
+    #[serde(default)]
+    pub prefix: String,
+    pub pattern: Option<RoutePattern>,
+    pub service: String,
+    // fixed instances of `service` instead of those in the registry, like
+    // http://10.0.0.7:8080 or https://legacy.example.org, for services that
+    // cannot register themselves.
+    #[serde(default)]
+    pub upstreams: Vec<String>,
+    pub rewrite: Option<String>,
+    #[serde(default)]
+    pub policy: RoutePolicy,
+}
+
+/// What the requests of a route may take, over the gateway-wide settings.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RoutePolicy {
+    // instead of request_timeout_secs, or long_poll.timeout_secs for a long
+    // poll route; 0 for no limit.
+    pub timeout_secs: Option<u64>,
+    // its upstreams hold requests until they have something to answer: they
+    // get the long poll timeout and are not retried.
+    pub long_poll: bool,
+    // further attempts on another instance of GET, HEAD and OPTIONS requests
+    // whose instance did not answer in time or at all.
+    pub retries: u32,
+    // requests per second over the whole route, in bursts of as many; 0 for
+    // no limit.
+    pub rate_limit: u32,
+    // bytes of request body, larger ones are refused.
+    pub body_limit: Option<u64>,
+    // round_robin or random, instead of the algorithm of the service.
+    pub lb_override: Option<String>,
+    // the algorithms a request may ask for instead with its x-crossgate-lb
+    // header or crossgate_lb query parameter: round_robin, random, or
+    // consistent_hash on the key it gives, e.g. `consistent_hash:user-42`.
+    pub lb_requests: Vec<String>,
+    pub priority: Priority,
+    // refused with 403 without a verified client certificate, see
+    // gateway.tls.client_auth.
+    pub client_cert: bool,
+    // headers set on the responses from what the instance that answered
+    // registered, by name: its addr, version, weight or `metadata.<key>`,
+    // e.g. `x-served-by = "addr"`.
+    pub response_headers: HashMap<String, String>,
+    // the service the clients of a country or region are sent to instead,
+    // by its code, the region before its country: e.g. `DE = "/eu/ums"` or
+    // `US-CA = "/us-west/ums"`. See gateway.geoip.
+    pub geo_services: HashMap<String, String>,
+    // a json schema file the bodies of POST, PUT and PATCH requests must
+    // match, refused with 422 by the schema middleware otherwise, which
+    // must be listed.
+    pub schema: Option<String>,
+    // refused with 401 by the hmac middleware, which must be listed, unless
+    // signed by a client of gateway.auth.hmac.
+    pub hmac: bool,
+}
+
+impl Route {
+    // what the route is told apart by, its prefix or pattern.
+    pub fn key(&self) -> &str {
+        match &self.pattern {
+            Some(pattern) => pattern.0.as_str(),
+            None => &self.prefix,
+        }
+    }
+
+    fn matches(&self, path: &str) -> bool {
+        if let Some(pattern) = &self.pattern {
+            return pattern.0.is_match(path);
+        }
+        let prefix = self.prefix.trim_end_matches('/');
+        path == prefix
+            || path
+                .strip_prefix(prefix)
+                .is_some_and(|rest| rest.starts_with('/'))
+    }
+
+    // the path and query a request for `path` is forwarded with, None when
+    // the route keeps it; the query of the request is kept either way.
+    pub fn rewrite(&self, path: &str, query: Option<&str>) -> Option<String> {
+        let rewrite = self.rewrite.as_ref()?;
+        let mut forwarded = match &self.pattern {
+            Some(pattern) => {
+                let mut forwarded = String::new();
+                pattern.0.captures(path)?.expand(rewrite, &mut forwarded);
+                forwarded
+            }
+            None => {
+                let rest = path.strip_prefix(self.prefix.trim_end_matches('/'))?;
+                format!("{}{}", rewrite.trim_end_matches('/'), rest)
+            }
+        };
+        if !forwarded.starts_with('/') {
+            forwarded.insert(0, '/');
+        }
+        if let Some(query) = query.filter(|q| !q.is_empty()) {
+            forwarded.push(if forwarded.contains('?') { '&' } else { '?' });
+            forwarded.push_str(query);
+        }
+        Some(forwarded)
+    }
+}
+
+/// The regex of a route, compiled as the config is read.
+#[derive(Debug, Clone)]
+pub struct RoutePattern(pub Regex);
+
+impl<'de> Deserialize<'de> for RoutePattern {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let pattern = String::deserialize(deserializer)?;
+        Regex::new(&pattern)
+            .map(RoutePattern)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RegistryConfig {
+    // none, etcd, mongodb, consul, kubernetes, file, postgres, gossip or the
+    // name of a plugin registered with `plugin::register_plugin_factory`.
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub addr: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub connect_timeout_secs: Option<u64>,
+    // the service name the gateway joins the Consul Connect mesh as; it then
+    // forwards to every instance over mutual tls with its leaf certificate.
+    pub connect: Option<String>,
+    pub spiffe: SpiffeConfig,
+    pub signing: SigningConfig,
+    pub cache: LookupCacheConfig,
+    // the username and password, or the consul token, read from vault
+    // instead.
+    pub vault: Option<VaultConfig>,
+}
+
+/// A HashiCorp Vault secret the registry credentials are read from before
+/// the plugin connects, e.g. a kv secret at `secret/data/crossgate/registry`
+/// or dynamic credentials at `database/creds/registry`, whose lease is
+/// renewed while the process runs. The token is best left to env
+/// VAULT_TOKEN.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VaultConfig {
+    pub addr: String,
+    pub token: Option<String>,
+    pub path: String,
+    // the fields of the secret the username and password are in.
+    pub username_key: String,
+    pub password_key: String,
+}
+
+impl Default for VaultConfig {
+    fn default() -> Self {
+        Self {
+            addr: "http://127.0.0.1:8200".to_string(),
+            token: None,
+            path: "".to_string(),
+            username_key: "username".to_string(),
+            password_key: "password".to_string(),
+        }
+    }
+}
+
+/// SPIFFE workload identity, with the spiffe feature: services sign their
+/// registrations with the X.509-SVID the Workload API at `socket` issues
+/// them, and the gateway routes a service in `allowed` only to instances
+/// whose signature verifies against the trust bundle and whose SPIFFE ID is
+/// listed. Off while `socket` is unset.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SpiffeConfig {
+    // e.g. unix:///run/spire/sockets/agent.sock.
+    pub socket: Option<String>,
+    // the SPIFFE IDs that may register each service name, a trailing `*`
+    // matches any path, e.g. spiffe://example.org/ns/prod/*.
+    pub allowed: HashMap<String, Vec<String>>,
+}
+
+/// How long the gateway routes by the instances of a service it read from
+/// the registry: for `ttl_ms` as they are, then up to `stale_secs` while
+/// they are read again in the background, so that requests only wait for
+/// the registry on the first lookup of a service or after it was out that
+/// long. Off while `ttl_ms` is 0.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LookupCacheConfig {
+    pub ttl_ms: u64,
+    pub stale_secs: u64,
+}
+
+impl Default for LookupCacheConfig {
+    fn default() -> Self {
+        Self {
+            ttl_ms: 1000,
+            stale_secs: 60,
+        }
+    }
+}
+
+/// Signed registrations: services sign what they register with `key`, and
+/// the gateway routes a service in `keys` only to instances whose signature
+/// verifies with its key, so that no other instance can register as it.
+/// With hmac-sha256 the keys are secrets shared by each service and the
+/// gateway, with ed25519 the gateway holds the public keys only. Keys are
+/// base64.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SigningConfig {
+    // hmac-sha256 or ed25519.
+    pub algorithm: String,
+    // what this process signs its registrations with: the secret, or the
+    // ed25519 private key as pkcs#8 or its seed; best left to env
+    // REGISTER_SIGNING_KEY.
+    pub key: Option<String>,
+    // the key the registrations of each service name verify with.
+    pub keys: HashMap<String, String>,
+}
+
+impl Default for SigningConfig {
+    fn default() -> Self {
+        Self {
+            algorithm: "hmac-sha256".to_string(),
+            key: None,
+            keys: HashMap::new(),
+        }
+    }
+}
+
+impl Default for RegistryConfig {
+    fn default() -> Self {
+        Self {
+            kind: PluginType::Mongodb.as_str().to_string(),
+            addr: "".to_string(),
+            username: None,
+            password: None,
+            connect_timeout_secs: None,
+            connect: None,
+            spiffe: SpiffeConfig::default(),
+            signing: SigningConfig::default(),
+            cache: LookupCacheConfig::default(),
+            vault: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LbConfig {
+    // round_robin or random, for names without their own algorithm.
+    pub default: String,
+    // pins every service to this address, see `Service::strict_address`.
+    pub strict: Option<String>,
+}
+
+impl Default for LbConfig {
+    fn default() -> Self {
+        Self {
+            default: "round_robin".to_string(),
+            strict: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServiceConfig {
+    // the address each executor group serves its triggers on, by group,
+    // see `Executor::trigger_addr`.
+    pub triggers: HashMap<String, String>,
+    // see `AdvertiseAddr::PublicIp`.
+    pub public_ip_url: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TelemetryConfig {
+    // grpc address of an OTLP collector, spans are only exported when set
+    // and the otel feature is on.
+    pub otlp_endpoint: Option<String>,
+    // service.name of the exported spans, the binary name when unset.
+    pub service_name: Option<String>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("read config {path}: {source}")]
+    Io {
+        path: String,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("config {path} is neither .toml, .yaml nor .yml")]
+    UnknownFormat { path: String },
+    #[error("{path}{}: {message}", at_line(.line))]
+    Parse {
+        path: String,
+        line: Option<usize>,
+        message: String,
+    },
+    #[error("invalid config {path}:{}", list(.path, .issues))]
+    Invalid {
+        path: String,
+        issues: Vec<ConfigIssue>,
+    },
+}
+
+/// One invalid value, with the line of the file it was read from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigIssue {
+    // `None` when the value came from env or a default.
+    pub line: Option<usize>,
+    pub field: String,
+    pub message: String,
+}
+
+fn at_line(line: &Option<usize>) -> String {
+    line.map(|line| format!(":{}", line)).unwrap_or_default()
+}
+
+fn list(path: &str, issues: &[ConfigIssue]) -> String {
+    issues
+        .iter()
+        .map(|i| {
+            format!(
+                "\n  {}{}: {}: {}",
+                path,
+                at_line(&i.line),
+                i.field,
+                i.message
+            )
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Toml,
+    Yaml,
+}
+
+impl Format {
+    fn of(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "toml" => Some(Format::Toml),
+            "yaml" | "yml" => Some(Format::Yaml),
+            _ => None,
+        }
+    }
+}
+
+impl Config {
+    // reads `path`, applies env overrides and validates the result and the
+    // files it names.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let name = path.display().to_string();
+        let format =
+            Format::of(path).ok_or_else(|| ConfigError::UnknownFormat { path: name.clone() })?;
+        let source = std::fs::read_to_string(path).map_err(|source| ConfigError::Io {
+            path: name.clone(),
+            source,
+        })?;
+
+        dotenv::dotenv().ok();
+        let mut config = Self::parse(&name, &source, format)?;
+        config.apply_env(|key| std::env::var(key).ok());
+        // the issues with the files along with the others.
+        match (
+            config.validate(&name, &source),
+            config.check_files(&name, &source),
+        ) {
+            (Ok(()), Ok(())) => Ok(config),
+            (
+                Err(ConfigError::Invalid { path, mut issues }),
+                Err(ConfigError::Invalid { issues: files, .. }),
+            ) => {
+                issues.extend(files);
+                Err(ConfigError::Invalid { path, issues })
+            }
+            (Err(e), _) | (_, Err(e)) => Err(e),
+        }
+    }
+
+    // only parses, `name` is the path errors are reported for.
+    pub fn parse(name: &str, source: &str, format: Format) -> Result<Self, ConfigError> {
+        let parse_error = |line, message: String| ConfigError::Parse {
+            path: name.to_string(),
+            line,
+            message,
+        };
+        match format {
+            Format::Toml => toml::from_str(source).map_err(|e| {
+                let line = e.span().map(|span| line_at(source, span.start));
+                parse_error(line, e.message().to_string())
+            }),
+            Format::Yaml => serde_yaml::from_str(source).map_err(|e| {
+                let line = e.location().map(|l| l.line());
+                let message = e.to_string();
+                // the location is reported apart.
+                let message = match message.split_once(" at line ") {
+                    Some((message, _)) => message.to_string(),
+                    None => message,
+                };
+                parse_error(line, message)
+            }),
+        }
+    }
+
+    // defaults overridden by env only, what runs without `init`.
+    pub fn from_env() -> Self {
+        dotenv::dotenv().ok();
+        let mut config = Self::default();
+        config.apply_env(|key| std::env::var(key).ok());
+        config
+    }
+
+    // REGISTER_TYPE, REGISTER_ADDR, REGISTER_USERNAME, REGISTER_PASSWORD,
+    // STRICT, PUBLIC_IP_URL, GATEWAY_LISTEN (comma separated),
+    // OTEL_EXPORTER_OTLP_ENDPOINT and OTEL_SERVICE_NAME replace their value in
+    // the file when set.
+    pub fn apply_env(&mut self, env: impl Fn(&str) -> Option<String>) {
+        let env = |key: &str| env(key).filter(|v| !v.is_empty());
+
+        if let Some(kind) = env("REGISTER_TYPE") {
+            self.registry.kind = kind;
+        }
+        if let Some(addr) = env("REGISTER_ADDR") {
+            self.registry.addr = addr;
+        }
+        if let Some(username) = env("REGISTER_USERNAME") {
+            self.registry.username = Some(username);
+        }
+        if let Some(path) = env("REGISTER_VAULT_PATH") {
+            self.registry
+                .vault
+                .get_or_insert_with(Default::default)
+                .path = path;
+        }
+        if let Some(vault) = &mut self.registry.vault {
+            if let Some(addr) = env("VAULT_ADDR") {
+                vault.addr = addr;
+            }
+            if let Some(token) = env("VAULT_TOKEN") {
+                vault.token = Some(token);
+            }
+        }
+        if let Some(key) = env("REGISTER_SIGNING_KEY") {
+            self.registry.signing.key = Some(key);
+        }
+        if let Some(password) = env("REGISTER_PASSWORD") {
+            self.registry.password = Some(password);
+        }
+        if let Some(strict) = env("STRICT") {
+            self.lb.strict = Some(strict);
+        }
+        if let Some(public_ip_url) = env("PUBLIC_IP_URL") {
+            self.service.public_ip_url = Some(public_ip_url);
+        }
+        if let Some(listen) = env("GATEWAY_LISTEN") {
+            self.gateway.listen = listen.split(',').map(|s| s.trim().to_string()).collect();
+        }
+        if let Some(endpoint) = env("OTEL_EXPORTER_OTLP_ENDPOINT") {
+            self.telemetry.otlp_endpoint = Some(endpoint);
+        }
+        if let Some(service_name) = env("OTEL_SERVICE_NAME") {
+            self.telemetry.service_name = Some(service_name);
+        }
+    }
+
+    // every invalid value at once, each with the line of `source` it is on.
+    pub fn validate(&self, name: &str, source: &str) -> Result<(), ConfigError> {
+        let mut issues = vec![];
+        let mut issue = |field: &str, value: &str, message: String| {
+            issues.push(ConfigIssue {
+                line: line_of(source, value),
+                field: field.to_string(),
+                message,
+            })
+        };
+
+        for (i, listen) in self.gateway.listen.iter().enumerate() {
+            if listen.parse::<SocketAddr>().is_err() {
+                issue(
+                    &format!("gateway.listen[{}]", i),
+                    listen,
+                    format!("`{}` is not an ip:port address", listen),
+                );
+            }
+        }
+        // the routes of the tenants as those of the gateway.
+        let mut route_sets = vec![("gateway.routes".to_string(), &self.gateway.routes)];
+        for (t, tenant) in self.gateway.tenancy.tenants.iter().enumerate() {
+            route_sets.push((
+                format!("gateway.tenancy.tenants[{}].routes", t),
+                &tenant.routes,
+            ));
+        }
+        for (scope, routes) in &route_sets {
+            for (i, route) in routes.iter().enumerate() {
+                match (route.prefix.as_str(), &route.pattern) {
+                    ("", None) => issue(
+                        &format!("{}[{}].prefix", scope, i),
+                        &route.service,
+                        "set prefix or pattern".to_string(),
+                    ),
+                    ("", Some(_)) => {}
+                    (_, Some(_)) => issue(
+                        &format!("{}[{}].pattern", scope, i),
+                        &route.prefix,
+                        "set either prefix or pattern".to_string(),
+                    ),
+                    (prefix, None) if !prefix.starts_with('/') => issue(
+                        &format!("{}[{}].prefix", scope, i),
+                        prefix,
+                        format!("`{}` does not start with /", prefix),
+                    ),
+                    _ => {}
+                }
+                let key = route.key().trim_end_matches('/');
+                if routes[..i].iter().any(|r| {
+                    r.pattern.is_some() == route.pattern.is_some()
+                        && r.key().trim_end_matches('/') == key
+                }) {
+                    issue(
+                        &format!("{}[{}]", scope, i),
+                        route.key(),
+                        format!("`{}` is routed twice", route.key()),
+                    );
+                }
+                if let (Some(pattern), Some(rewrite)) = (&route.pattern, &route.rewrite) {
+                    for group in unknown_groups(&pattern.0, rewrite) {
+                        issue(
+                            &format!("{}[{}].rewrite", scope, i),
+                            rewrite,
+                            format!("`{}` is not a group of the pattern", group),
+                        );
+                    }
+                }
+                let policy = &route.policy;
+                if let Some(lb) = &policy.lb_override {
+                    if parse_lba(lb).is_none() {
+                        issue(
+                            &format!("{}[{}].policy.lb_override", scope, i),
+                            lb,
+                            format!("`{}` is not one of round_robin, random", lb),
+                        );
+                    }
+                }
+                for lb in &policy.lb_requests {
+                    if !LB_REQUESTS.contains(&lb_name(lb).as_str()) {
+                        issue(
+                            &format!("{}[{}].policy.lb_requests", scope, i),
+                            lb,
+                            format!(
+                                "`{}` is not one of round_robin, random, consistent_hash",
+                                lb
+                            ),
+                        );
+                    }
+                }
+                for (header, source) in &policy.response_headers {
+                    let field = format!("{}[{}].policy.response_headers", scope, i);
+                    if HeaderName::from_bytes(header.as_bytes()).is_err() {
+                        issue(&field, header, format!("`{}` is not a header name", header));
+                    }
+                    if !INSTANCE_FIELDS.contains(&source.as_str())
+                        && !matches!(source.strip_prefix("metadata."), Some(key) if !key.is_empty())
+                    {
+                        issue(
+                            &field,
+                            source,
+                            format!(
+                                "`{}` is not one of addr, version, weight, metadata.<key>",
+                                source
+                            ),
+                        );
+                    }
+                }
+                for (code, service) in &policy.geo_services {
+                    let field = format!("{}[{}].policy.geo_services", scope, i);
+                    if !is_geo_code(code) {
+                        issue(
+                            &field,
+                            code,
+                            format!(
+                                "`{}` is not a country or region code like DE or US-CA",
+                                code
+                            ),
+                        );
+                    }
+                    if !service.starts_with('/') {
+                        issue(
+                            &field,
+                            service,
+                            format!("`{}` is not a service name like /t/ums", service),
+                        );
+                    }
+                }
+                if !policy.geo_services.is_empty() && self.gateway.geoip.is_none() {
+                    issue(
+                        &format!("{}[{}].policy.geo_services", scope, i),
+                        "geo_services",
+                        "clients are located with gateway.geoip, set it".to_string(),
+                    );
+                }
+                if policy.long_poll && policy.retries > 0 {
+                    issue(
+                        &format!("{}[{}].policy.retries", scope, i),
+                        "retries",
+                        "long polls are not retried".to_string(),
+                    );
+                }
+                if policy.body_limit == Some(0) {
+                    issue(
+                        &format!("{}[{}].policy.body_limit", scope, i),
+                        "body_limit",
+                        "must be at least 1".to_string(),
+                    );
+                }
+                let client_auth = self
+                    .gateway
+                    .tls
+                    .as_ref()
+                    .and_then(|t| t.client_auth.as_ref());
+                if policy.client_cert && client_auth.is_none() {
+                    issue(
+                        &format!("{}[{}].policy.client_cert", scope, i),
+                        "client_cert",
+                        "requires gateway.tls.client_auth".to_string(),
+                    );
+                }
+                if policy.hmac && self.gateway.auth.hmac.is_none() {
+                    issue(
+                        &format!("{}[{}].policy.hmac", scope, i),
+                        "hmac",
+                        "requires gateway.auth.hmac".to_string(),
+                    );
+                }
+                // unsigned requests would be forwarded without it.
+                if policy.hmac && !self.gateway.middleware.iter().any(|m| m == "hmac") {
+                    issue(
+                        &format!("{}[{}].policy.hmac", scope, i),
+                        "hmac",
+                        "requires the hmac middleware in gateway.middleware".to_string(),
+                    );
+                }
+                // as would bodies not matching the schema.
+                if let Some(schema) = &policy.schema {
+                    if !self.gateway.middleware.iter().any(|m| m == "schema") {
+                        issue(
+                            &format!("{}[{}].policy.schema", scope, i),
+                            schema,
+                            "requires the schema middleware in gateway.middleware".to_string(),
+                        );
+                    }
+                }
+                if !route.service.starts_with('/') {
+                    issue(
+                        &format!("{}[{}].service", scope, i),
+                        &route.service,
+                        format!("`{}` is not a service name like /t/ums", route.service),
+                    );
+                }
+                for (j, upstream) in route.upstreams.iter().enumerate() {
+                    let uri = upstream.parse::<hyper::Uri>();
+                    let valid = uri.is_ok_and(|uri| {
+                        matches!(uri.scheme_str(), Some("http" | "https"))
+                            && uri.host().is_some()
+                            && matches!(uri.path(), "" | "/")
+                            && uri.query().is_none()
+                    });
+                    if !valid {
+                        issue(
+                            &format!("{}[{}].upstreams[{}]", scope, i, j),
+                            upstream,
+                            format!("`{}` is not an http or https url without a path", upstream),
+                        );
+                    }
+                }
+            }
+        }
+
+        let listener = &self.gateway.listener;
+        if listener.max_header_bytes < 8192 {
+            issue(
+                "gateway.listener.max_header_bytes",
+                "max_header_bytes",
+                format!("{} is below 8192", listener.max_header_bytes),
+            );
+        }
+        if !(1..=100).contains(&listener.max_headers) {
+            issue(
+                "gateway.listener.max_headers",
+                "max_headers",
+                format!("{} is not between 1 and 100", listener.max_headers),
+            );
+        }
+
+        let warmup = &self.gateway.warmup;
+        if !warmup.path.starts_with('/') {
+            issue(
+                "gateway.warmup.path",
+                &warmup.path,
+                format!("`{}` is not a path", warmup.path),
+            );
+        }
+        if warmup.timeout_secs == 0 {
+            issue(
+                "gateway.warmup.timeout_secs",
+                "timeout_secs",
+                "must be at least 1".to_string(),
+            );
+        }
+
+        let low_percent = self.gateway.shedding.low_percent;
+        if !(1..=100).contains(&low_percent) {
+            issue(
+                "gateway.shedding.low_percent",
+                "low_percent",
+                format!("{} is not between 1 and 100", low_percent),
+            );
+        }
+
+        let grpc = &self.gateway.grpc;
+        for (field, value) in [
+            ("gateway.grpc.max_streams", grpc.max_streams as u64),
+            ("gateway.grpc.max_channels", grpc.max_channels as u64),
+            ("gateway.grpc.keep_alive_secs", grpc.keep_alive_secs),
+            ("gateway.grpc.idle_secs", grpc.idle_secs),
+        ] {
+            if value == 0 {
+                let name = field.rsplit('.').next().unwrap_or(field);
+                issue(field, name, "must be at least 1".to_string());
+            }
+        }
+
+        let capture = &self.gateway.capture;
+        if !(0.0..=1.0).contains(&capture.sample_rate) {
+            issue(
+                "gateway.capture.sample_rate",
+                "sample_rate",
+                format!("{} is not between 0 and 1", capture.sample_rate),
+            );
+        }
+        if let Some(header) = &capture.header {
+            if HeaderName::from_bytes(header.as_bytes()).is_err() {
+                issue(
+                    "gateway.capture.header",
+                    header,
+                    format!("`{}` is not a header name", header),
+                );
+            }
+        }
+
+        if let Some(graphql) = &self.gateway.graphql {
+            if !cfg!(feature = "graphql") {
+                issue(
+                    "gateway.graphql",
+                    "graphql",
+                    "requires the graphql feature".to_string(),
+                );
+            }
+            for (field, path) in [
+                ("gateway.graphql.path", &graphql.path),
+                ("gateway.graphql.service_path", &graphql.service_path),
+            ] {
+                if !path.starts_with('/') {
+                    issue(field, path, format!("`{}` does not start with /", path));
+                }
+            }
+            if graphql.services.is_empty() {
+                issue(
+                    "gateway.graphql.services",
+                    "services",
+                    "required".to_string(),
+                );
+            }
+            for (i, service) in graphql.services.iter().enumerate() {
+                if !service.starts_with('/') {
+                    issue(
+                        &format!("gateway.graphql.services[{}]", i),
+                        service,
+                        format!("`{}` is not a service name like /t/ums", service),
+                    );
+                }
+            }
+            if graphql.refresh_secs == 0 {
+                issue(
+                    "gateway.graphql.refresh_secs",
+                    "refresh_secs",
+                    "must be at least 1".to_string(),
+                );
+            }
+        }
+
+        if let Some(transcode) = &self.gateway.transcode {
+            if !cfg!(feature = "transcode") {
+                issue(
+                    "gateway.transcode",
+                    "transcode",
+                    "requires the transcode feature".to_string(),
+                );
+            }
+            for (field, empty) in [
+                ("descriptors", transcode.descriptors.is_empty()),
+                ("services", transcode.services.is_empty()),
+            ] {
+                if empty {
+                    issue(
+                        &format!("gateway.transcode.{}", field),
+                        field,
+                        "required".to_string(),
+                    );
+                }
+            }
+            for (grpc, service) in &transcode.services {
+                if !service.starts_with('/') {
+                    issue(
+                        &format!("gateway.transcode.services.{}", grpc),
+                        service,
+                        format!("`{}` is not a service name like /t/ums", service),
+                    );
+                }
+            }
+        }
+
+        let auth = &self.gateway.auth;
+        if HeaderName::from_bytes(auth.api_key_header.as_bytes()).is_err() {
+            issue(
+                "gateway.auth.api_key_header",
+                &auth.api_key_header,
+                format!("`{}` is not a header name", auth.api_key_header),
+            );
+        }
+        match (auth.revocation_store.as_str(), &auth.redis_url) {
+            ("memory", _) => {}
+            ("redis", _) if !cfg!(feature = "redis") => issue(
+                "gateway.auth.revocation_store",
+                "redis",
+                "requires the redis feature".to_string(),
+            ),
+            ("redis", None) => issue(
+                "gateway.auth.redis_url",
+                "redis",
+                "required for the redis store".to_string(),
+            ),
+            ("redis", Some(url))
+                if !url.starts_with("redis://") && !url.starts_with("rediss://") =>
+            {
+                issue(
+                    "gateway.auth.redis_url",
+                    url,
+                    format!("`{}` is not a redis:// or rediss:// url", url),
+                )
+            }
+            ("redis", Some(_)) => {}
+            (store, _) => issue(
+                "gateway.auth.revocation_store",
+                store,
+                format!("`{}` is not one of memory, redis", store),
+            ),
+        }
+        if auth.revocation_ttl_secs == 0 {
+            issue(
+                "gateway.auth.revocation_ttl_secs",
+                "revocation_ttl_secs",
+                "must be at least 1".to_string(),
+            );
+        }
+        if let Some(oidc) = &auth.oidc {
+            if !cfg!(feature = "oidc") {
+                issue(
+                    "gateway.auth.oidc",
+                    "oidc",
+                    "requires the oidc feature".to_string(),
+                );
+            }
+            let urls = [
+                ("gateway.auth.oidc.issuer", Some(&oidc.issuer)),
+                (
+                    "gateway.auth.oidc.discovery_url",
+                    oidc.discovery_url.as_ref(),
+                ),
+                ("gateway.auth.oidc.redirect_uri", oidc.redirect_uri.as_ref()),
+            ];
+            for (field, url) in urls {
+                let url = match url {
+                    Some(url) => url,
+                    None => continue,
+                };
+                let parsed = url.parse::<hyper::Uri>();
+                if !parsed.is_ok_and(|u| matches!(u.scheme_str(), Some("http" | "https"))) {
+                    issue(field, url, format!("`{}` is not an http or https url", url));
+                }
+            }
+            if oidc.client_id.is_empty() {
+                issue(
+                    "gateway.auth.oidc.client_id",
+                    "oidc",
+                    "required".to_string(),
+                );
+            }
+            if oidc.refresh_secs == 0 {
+                issue(
+                    "gateway.auth.oidc.refresh_secs",
+                    "refresh_secs",
+                    "must be at least 1".to_string(),
+                );
+            }
+            if let Some(header) = &oidc.subject_header {
+                if HeaderName::from_bytes(header.as_bytes()).is_err() {
+                    issue(
+                        "gateway.auth.oidc.subject_header",
+                        header,
+                        format!("`{}` is not a header name", header),
+                    );
+                }
+            }
+        }
+
+        if let Some(hmac) = &auth.hmac {
+            if hmac.clients.is_empty() {
+                issue("gateway.auth.hmac.clients", "hmac", "required".to_string());
+            }
+            for (client, secret) in &hmac.clients {
+                if secret.len() < 16 {
+                    issue(
+                        &format!("gateway.auth.hmac.clients.{}", client),
+                        client,
+                        "secret of less than 16 bytes".to_string(),
+                    );
+                }
+            }
+            for (field, header) in [
+                ("gateway.auth.hmac.client_header", &hmac.client_header),
+                ("gateway.auth.hmac.timestamp_header", &hmac.timestamp_header),
+                ("gateway.auth.hmac.signature_header", &hmac.signature_header),
+            ] {
+                if HeaderName::from_bytes(header.as_bytes()).is_err() {
+                    issue(field, header, format!("`{}` is not a header name", header));
+                }
+            }
+            if hmac.window_secs == 0 {
+                issue(
+                    "gateway.auth.hmac.window_secs",
+                    "window_secs",
+                    "must be at least 1".to_string(),
+                );
+            }
+        }
+
+        let notify = &self.gateway.notify;
+        for (i, webhook) in notify.webhooks.iter().enumerate() {
+            let url = webhook.url.parse::<hyper::Uri>();
+            if !url.is_ok_and(|url| matches!(url.scheme_str(), Some("http" | "https"))) {
+                issue(
+                    &format!("gateway.notify.webhooks[{}].url", i),
+                    &webhook.url,
+                    format!("`{}` is not an http or https url", webhook.url),
+                );
+            }
+            if !matches!(webhook.format.as_str(), "json" | "slack") {
+                issue(
+                    &format!("gateway.notify.webhooks[{}].format", i),
+                    &webhook.format,
+                    format!("`{}` is not one of json, slack", webhook.format),
+                );
+            }
+        }
+        for (i, service) in notify.services.iter().enumerate() {
+            if !service.starts_with('/') {
+                issue(
+                    &format!("gateway.notify.services[{}]", i),
+                    service,
+                    format!("`{}` is not a service name like /t/ums", service),
+                );
+            }
+        }
+        if notify.poll_interval_secs == 0 {
+            issue(
+                "gateway.notify.poll_interval_secs",
+                "poll_interval_secs",
+                "must be at least 1".to_string(),
+            );
+        }
+        let flapping = &notify.flapping;
+        if flapping.transitions == 1 {
+            issue(
+                "gateway.notify.flapping.transitions",
+                "transitions",
+                "must be 0, off, or at least 2".to_string(),
+            );
+        }
+        if flapping.transitions > 0 && flapping.window_secs < notify.poll_interval_secs {
+            issue(
+                "gateway.notify.flapping.window_secs",
+                "window_secs",
+                "must be at least gateway.notify.poll_interval_secs".to_string(),
+            );
+        }
+
+        let status = &self.gateway.status;
+        let signs_in =
+            self.gateway.auth.oidc.is_some() && self.gateway.middleware.iter().any(|m| m == "auth");
+        if status.enabled && status.basic_auth.is_none() && !signs_in {
+            issue(
+                "gateway.status.basic_auth",
+                "enabled",
+                "set basic_auth, or sign in with gateway.auth.oidc and the auth middleware"
+                    .to_string(),
+            );
+        }
+        if status
+            .basic_auth
+            .as_ref()
+            .is_some_and(|credentials| !credentials.contains(':'))
+        {
+            issue(
+                "gateway.status.basic_auth",
+                "basic_auth",
+                "is not user:password".to_string(),
+            );
+        }
+
+        let admin = &self.gateway.admin;
+        if admin.basic_auth.is_none() {
+            for middleware in &self.gateway.middleware {
+                if ADMIN_MIDDLEWARE.contains(&middleware.as_str()) {
+                    issue(
+                        "gateway.admin.basic_auth",
+                        middleware,
+                        format!("required by the {} middleware", middleware),
+                    );
+                }
+            }
+        }
+        if admin
+            .basic_auth
+            .as_ref()
+            .is_some_and(|credentials| !credentials.contains(':'))
+        {
+            issue(
+                "gateway.admin.basic_auth",
+                "basic_auth",
+                "is not user:password".to_string(),
+            );
+        }
+
+        let self_register = &self.gateway.self_register;
+        if !self_register.service.is_empty() && !self_register.service.starts_with('/') {
+            issue(
+                "gateway.self_register.service",
+                &self_register.service,
+                format!("`{}` does not start with /", self_register.service),
+            );
+        }
+
+        let peers = &self.gateway.peers;
+        if peers.enabled {
+            if self_register.service.is_empty() {
+                issue(
+                    "gateway.peers.enabled",
+                    "enabled",
+                    "peers find each other by gateway.self_register.service, set it".to_string(),
+                );
+            }
+            if peers.secret.as_deref().unwrap_or_default().is_empty() {
+                issue(
+                    "gateway.peers.secret",
+                    "enabled",
+                    "peers sign in with a secret, set it".to_string(),
+                );
+            }
+            if peers.interval_secs == 0 {
+                issue(
+                    "gateway.peers.interval_secs",
+                    "interval_secs",
+                    "must be at least 1".to_string(),
+                );
+            }
+        }
+
+        if let Some(geoip) = &self.gateway.geoip {
+            if !cfg!(feature = "geoip") {
+                issue(
+                    "gateway.geoip",
+                    "geoip",
+                    "requires the geoip feature".to_string(),
+                );
+            }
+            if geoip.database.is_empty() {
+                issue("gateway.geoip.database", "geoip", "required".to_string());
+            }
+            for code in geoip.block.iter().filter(|code| !is_geo_code(code)) {
+                issue(
+                    "gateway.geoip.block",
+                    code,
+                    format!(
+                        "`{}` is not a country or region code like DE or US-CA",
+                        code
+                    ),
+                );
+            }
+            for (field, header) in [
+                ("gateway.geoip.country_header", &geoip.country_header),
+                ("gateway.geoip.region_header", &geoip.region_header),
+            ] {
+                if HeaderName::from_bytes(header.as_bytes()).is_err() {
+                    issue(field, header, format!("`{}` is not a header name", header));
+                }
+            }
+        }
+
+        let tenancy = &self.gateway.tenancy;
+        if tenancy.required && tenancy.tenants.is_empty() {
+            issue(
+                "gateway.tenancy.required",
+                "required",
+                "every request would be refused, list the tenants".to_string(),
+            );
+        }
+        for (t, tenant) in tenancy.tenants.iter().enumerate() {
+            let field = |name: &str| format!("gateway.tenancy.tenants[{}].{}", t, name);
+            let others = &tenancy.tenants[..t];
+            if tenant.name.is_empty() {
+                issue(&field("name"), "tenants", "required".to_string());
+            } else if others.iter().any(|o| o.name == tenant.name) {
+                issue(
+                    &field("name"),
+                    &tenant.name,
+                    format!("`{}` is a tenant twice", tenant.name),
+                );
+            }
+            if tenant.api_keys.is_empty() && tenant.hosts.is_empty() && tenant.path_prefix.is_none()
+            {
+                issue(
+                    &field("name"),
+                    &tenant.name,
+                    "set api_keys, hosts or path_prefix to tell its requests apart".to_string(),
+                );
+            }
+            for key in &tenant.api_keys {
+                if let Some(digest) = key.strip_prefix("sha256:") {
+                    if digest.len() != 64 || !digest.bytes().all(|b| b.is_ascii_hexdigit()) {
+                        issue(
+                            &field("api_keys"),
+                            key,
+                            "not a sha256 digest in hex".to_string(),
+                        );
+                    }
+                }
+                if others.iter().any(|o| o.api_keys.contains(key)) {
+                    issue(
+                        &field("api_keys"),
+                        key,
+                        "the key of another tenant".to_string(),
+                    );
+                }
+            }
+            for host in &tenant.hosts {
+                if others
+                    .iter()
+                    .any(|o| o.hosts.iter().any(|h| h.eq_ignore_ascii_case(host)))
+                {
+                    issue(
+                        &field("hosts"),
+                        host,
+                        format!("`{}` is another tenant's", host),
+                    );
+                }
+            }
+            if let Some(prefix) = &tenant.path_prefix {
+                if !prefix.starts_with('/') || prefix.trim_end_matches('/').is_empty() {
+                    issue(
+                        &field("path_prefix"),
+                        prefix,
+                        format!("`{}` is not a path like /acme", prefix),
+                    );
+                } else if others
+                    .iter()
+                    .any(|o| o.path_prefix.as_ref() == Some(prefix))
+                {
+                    issue(
+                        &field("path_prefix"),
+                        prefix,
+                        format!("`{}` is another tenant's", prefix),
+                    );
+                }
+            }
+        }
+
+        let connect = &self.gateway.connect;
+        if connect.enabled && connect.allow.is_empty() {
+            issue(
+                "gateway.connect.allow",
+                "enabled",
+                "no destination would be allowed, list them".to_string(),
+            );
+        }
+        for (i, pattern) in connect.allow.iter().enumerate() {
+            let port = pattern.rsplit_once(':').map(|(_, port)| port);
+            if !matches!(port, Some(port) if port == "*" || port.parse::<u16>().is_ok()) {
+                issue(
+                    &format!("gateway.connect.allow[{}]", i),
+                    pattern,
+                    "not host:port, with * for any port".to_string(),
+                );
+            }
+        }
+
+        let audit = &self.gateway.audit;
+        for (i, sink) in audit.sinks.iter().enumerate() {
+            let target = match sink.kind.as_str() {
+                "file" => sink.path.as_ref().map(|_| Ok(())),
+                "http" => sink.url.as_ref().map(|url| {
+                    let uri = url.parse::<hyper::Uri>();
+                    match uri.is_ok_and(|uri| matches!(uri.scheme_str(), Some("http" | "https"))) {
+                        true => Ok(()),
+                        false => Err(format!("`{}` is not an http or https url", url)),
+                    }
+                }),
+                "syslog" => sink.addr.as_ref().map(|addr| {
+                    match addr
+                        .rsplit_once(':')
+                        .is_some_and(|(_, p)| p.parse::<u16>().is_ok())
+                    {
+                        true => Ok(()),
+                        false => Err(format!("`{}` is not a host:port", addr)),
+                    }
+                }),
+                kind => {
+                    issue(
+                        &format!("gateway.audit.sinks[{}].type", i),
+                        kind,
+                        format!("`{}` is not one of file, http, syslog", kind),
+                    );
+                    continue;
+                }
+            };
+            let field = match sink.kind.as_str() {
+                "file" => "path",
+                "http" => "url",
+                _ => "addr",
+            };
+            match target {
+                Some(Ok(())) => {}
+                Some(Err(message)) => issue(
+                    &format!("gateway.audit.sinks[{}].{}", i, field),
+                    field,
+                    message,
+                ),
+                None => issue(
+                    &format!("gateway.audit.sinks[{}].{}", i, field),
+                    &sink.kind,
+                    format!("required with a {} sink", sink.kind),
+                ),
+            }
+        }
+        if audit.queue == 0 {
+            issue(
+                "gateway.audit.queue",
+                "queue",
+                "must be at least 1".to_string(),
+            );
+        }
+
+        if let Some(tls) = &self.gateway.tls {
+            for (field, path) in [
+                ("gateway.tls.cert", &tls.cert),
+                ("gateway.tls.key", &tls.key),
+            ] {
+                if path.is_empty() {
+                    issue(field, "", "required with gateway.tls".to_string());
+                }
+            }
+            for (i, host) in tls.hosts.iter().enumerate() {
+                let field = |name: &str| format!("gateway.tls.hosts[{}].{}", i, name);
+                let name = &host.server_name;
+                let domain = name.strip_prefix("*.").unwrap_or(name);
+                if domain.is_empty() || domain.contains('*') {
+                    issue(
+                        &field("server_name"),
+                        name,
+                        format!("`{}` is not a domain or *.domain", name),
+                    );
+                }
+                if tls.hosts[..i]
+                    .iter()
+                    .any(|h| h.server_name.eq_ignore_ascii_case(name))
+                {
+                    issue(
+                        &field("server_name"),
+                        name,
+                        format!("`{}` is listed twice", name),
+                    );
+                }
+                if host.cert.is_some() != host.key.is_some() {
+                    issue(
+                        &field("key"),
+                        name,
+                        "set both cert and key or neither".to_string(),
+                    );
+                }
+                if let Some(service) = &host.service {
+                    if !service.starts_with('/') {
+                        issue(
+                            &field("service"),
+                            service,
+                            format!("`{}` is not a service name like /t/ums", service),
+                        );
+                    }
+                }
+            }
+            if tls.client_auth.as_ref().is_some_and(|c| c.ca.is_empty()) {
+                issue(
+                    "gateway.tls.client_auth.ca",
+                    "client_auth",
+                    "required".to_string(),
+                );
+            }
+        }
+
+        if let Some(acme) = &self.gateway.acme {
+            if !cfg!(feature = "acme") {
+                issue(
+                    "gateway.acme",
+                    "acme",
+                    "requires the acme feature".to_string(),
+                );
+            }
+            if self.gateway.tls.is_some() {
+                issue(
+                    "gateway.acme",
+                    "acme",
+                    "set either gateway.tls or gateway.acme".to_string(),
+                );
+            }
+            if acme.domains.is_empty() {
+                issue("gateway.acme.domains", "domains", "required".to_string());
+            }
+            match acme.challenge.as_str() {
+                "tls-alpn-01" => {}
+                "http-01" if acme.http_listen.is_some() || self.gateway.http_redirect.is_some() => {
+                }
+                "http-01" => issue(
+                    "gateway.acme.http_listen",
+                    "http-01",
+                    "required for the http-01 challenge, or gateway.http_redirect".to_string(),
+                ),
+                challenge => issue(
+                    "gateway.acme.challenge",
+                    challenge,
+                    format!("`{}` is not one of tls-alpn-01, http-01", challenge),
+                ),
+            }
+            if let Some(addr) = &acme.http_listen {
+                if addr.parse::<SocketAddr>().is_err() {
+                    issue(
+                        "gateway.acme.http_listen",
+                        addr,
+                        format!("`{}` is not an ip:port address", addr),
+                    );
+                }
+            }
+        }
+
+        if let Some(redirect) = &self.gateway.http_redirect {
+            if self.gateway.tls.is_none() && self.gateway.acme.is_none() {
+                issue(
+                    "gateway.http_redirect",
+                    "http_redirect",
+                    "redirects to https, set gateway.tls or gateway.acme".to_string(),
+                );
+            }
+            if redirect.listen.parse::<SocketAddr>().is_err() {
+                issue(
+                    "gateway.http_redirect.listen",
+                    "http_redirect",
+                    format!("`{}` is not an ip:port address", redirect.listen),
+                );
+            } else if self.listen().contains(&redirect.listen) {
+                issue(
+                    "gateway.http_redirect.listen",
+                    &redirect.listen,
+                    "serves https already".to_string(),
+                );
+            }
+            if redirect.status != 301 && redirect.status != 308 {
+                issue(
+                    "gateway.http_redirect.status",
+                    "status",
+                    format!("{} is not one of 301, 308", redirect.status),
+                );
+            }
+        }
+
+        let kind = self.registry.kind.to_lowercase();
+        match kind.as_str() {
+            "none" => {}
+            "etcd" | "mongodb" | "consul" | "file" | "postgres" | "postgresql" | "gossip" => {
+                if kind.starts_with("postgres") && !cfg!(feature = "postgres") {
+                    issue(
+                        "registry.type",
+                        &self.registry.kind,
+                        "requires the postgres feature".to_string(),
+                    );
+                }
+                let schemes: &[&str] = match kind.as_str() {
+                    "etcd" => &["etcd://"],
+                    "consul" => &["consul://"],
+                    "file" => &["file://"],
+                    "postgres" | "postgresql" => &["postgres://", "postgresql://"],
+                    "gossip" => &["gossip://"],
+                    _ => &["mongodb://", "mongodb+srv://"],
+                };
+                let addr = &self.registry.addr;
+                if addr.is_empty() {
+                    issue(
+                        "registry.addr",
+                        "",
+                        format!("required for registry type {}", kind),
+                    );
+                } else if !schemes.iter().any(|s| addr.starts_with(s)) {
+                    issue(
+                        "registry.addr",
+                        addr,
+                        format!("`{}` must start with {}", addr, schemes.join(" or ")),
+                    );
+                } else if let Some(message) = registry_addr_issue(&kind, addr) {
+                    issue("registry.addr", addr, message);
+                }
+            }
+            "kubernetes" => {
+                if !cfg!(feature = "kubernetes") {
+                    issue(
+                        "registry.type",
+                        &self.registry.kind,
+                        "requires the kubernetes feature".to_string(),
+                    );
+                }
+                // the namespace, by default the one the process runs in.
+                let addr = &self.registry.addr;
+                let namespace = addr.strip_prefix("kubernetes://");
+                if !addr.is_empty() && !namespace.is_some_and(is_namespace) {
+                    issue(
+                        "registry.addr",
+                        addr,
+                        format!(
+                            "`{}` is not kubernetes:// and a namespace, like kubernetes://default",
+                            addr
+                        ),
+                    );
+                }
+            }
+            // the address is up to the plugin.
+            kind if plugin::has_plugin_factory(kind) => {}
+            _ => issue(
+                "registry.type",
+                &self.registry.kind,
+                format!(
+                    "`{}` is not one of none, etcd, mongodb, consul, kubernetes, file, postgres, gossip or a registered plugin",
+                    self.registry.kind
+                ),
+            ),
+        }
+        if let (Some(connect), false) = (&self.registry.connect, kind == "consul") {
+            issue(
+                "registry.connect",
+                connect,
+                "requires registry type consul".to_string(),
+            );
+        }
+        if let Some(vault) = &self.registry.vault {
+            let uri = vault.addr.parse::<hyper::Uri>();
+            if !uri.is_ok_and(|uri| matches!(uri.scheme_str(), Some("http" | "https"))) {
+                issue(
+                    "registry.vault.addr",
+                    &vault.addr,
+                    format!("`{}` is not an http or https url", vault.addr),
+                );
+            }
+            if vault.path.trim_matches('/').is_empty() {
+                issue("registry.vault.path", "vault", "required".to_string());
+            }
+            if vault.token.as_deref().unwrap_or_default().is_empty() {
+                issue(
+                    "registry.vault.token",
+                    "vault",
+                    "required, set it or env VAULT_TOKEN".to_string(),
+                );
+            }
+        }
+        let spiffe = &self.registry.spiffe;
+        if let Some(socket) = &spiffe.socket {
+            if !cfg!(feature = "spiffe") {
+                issue(
+                    "registry.spiffe.socket",
+                    socket,
+                    "requires the spiffe feature".to_string(),
+                );
+            } else if !socket.starts_with("unix://") {
+                issue(
+                    "registry.spiffe.socket",
+                    socket,
+                    format!("`{}` is not a unix:// address", socket),
+                );
+            }
+        }
+        // without identities nothing could serve these services.
+        if let (Some(service), None) = (spiffe.allowed.keys().next(), &spiffe.socket) {
+            issue(
+                "registry.spiffe.allowed",
+                service,
+                "requires registry.spiffe.socket".to_string(),
+            );
+        }
+        for (service, ids) in &spiffe.allowed {
+            for id in ids.iter().filter(|id| !id.starts_with("spiffe://")) {
+                issue(
+                    "registry.spiffe.allowed",
+                    id,
+                    format!("`{}` of {} is not a spiffe:// id", id, service),
+                );
+            }
+        }
+        let signing = &self.registry.signing;
+        match crate::signing::Algorithm::parse(&signing.algorithm) {
+            Ok(algorithm) => {
+                if let Some(key) = &signing.key {
+                    if let Err(e) = crate::signing::check_key(algorithm, key, false) {
+                        issue("registry.signing.key", key, e.to_string());
+                    }
+                }
+                for (service, key) in &signing.keys {
+                    if let Err(e) = crate::signing::check_key(algorithm, key, true) {
+                        issue(
+                            "registry.signing.keys",
+                            service,
+                            format!("{}: {}", service, e),
+                        );
+                    }
+                }
+            }
+            Err(e) => issue(
+                "registry.signing.algorithm",
+                &signing.algorithm,
+                e.to_string(),
+            ),
+        }
+        if let (Some(username), None) = (&self.registry.username, &self.registry.password) {
+            issue(
+                "registry.password",
+                username,
+                "required with registry.username".to_string(),
+            );
+        }
+
+        if parse_lba(&self.lb.default).is_none() {
+            issue(
+                "lb.default",
+                &self.lb.default,
+                format!("`{}` is not one of round_robin, random", self.lb.default),
+            );
+        }
+        let mut triggers = self.service.triggers.iter().collect::<Vec<_>>();
+        triggers.sort();
+        for (group, trigger_addr) in triggers {
+            if trigger_addr.parse::<SocketAddr>().is_err() {
+                issue(
+                    &format!("service.triggers.{}", group),
+                    trigger_addr,
+                    format!("`{}` is not an ip:port address", trigger_addr),
+                );
+            }
+        }
+        if let Some(url) = &self.service.public_ip_url {
+            if url.parse::<hyper::Uri>().is_err() {
+                issue(
+                    "service.public_ip_url",
+                    url,
+                    format!("`{}` is not a url", url),
+                );
+            }
+        }
+        if let Some(endpoint) = &self.telemetry.otlp_endpoint {
+            let has_scheme = endpoint
+                .parse::<hyper::Uri>()
+                .is_ok_and(|uri| uri.scheme().is_some());
+            if !has_scheme {
+                issue(
+                    "telemetry.otlp_endpoint",
+                    endpoint,
+                    format!("`{}` is not a url like http://host:4317", endpoint),
+                );
+            }
+        }
+
+        if issues.is_empty() {
+            return Ok(());
+        }
+        Err(ConfigError::Invalid {
+            path: name.to_string(),
+            issues,
+        })
+    }
+
+    // the files the config names, that they can be read and hold what they
+    // should; apart from `validate`, which reads nothing.
+    pub fn check_files(&self, name: &str, source: &str) -> Result<(), ConfigError> {
+        let mut files = vec![];
+        if let Some(tls) = &self.gateway.tls {
+            files.push(("gateway.tls.cert".to_string(), &tls.cert, "CERTIFICATE"));
+            files.push(("gateway.tls.key".to_string(), &tls.key, "PRIVATE KEY"));
+            for (i, host) in tls.hosts.iter().enumerate() {
+                if let (Some(cert), Some(key)) = (&host.cert, &host.key) {
+                    files.push((
+                        format!("gateway.tls.hosts[{}].cert", i),
+                        cert,
+                        "CERTIFICATE",
+                    ));
+                    files.push((format!("gateway.tls.hosts[{}].key", i), key, "PRIVATE KEY"));
+                }
+            }
+            if let Some(client_auth) = &tls.client_auth {
+                files.push((
+                    "gateway.tls.client_auth.ca".to_string(),
+                    &client_auth.ca,
+                    "CERTIFICATE",
+                ));
+            }
+        }
+
+        let mut issues = files
+            .into_iter()
+            .filter(|(_, path, _)| !path.is_empty())
+            .filter_map(|(field, path, label)| {
+                Some(ConfigIssue {
+                    line: line_of(source, path),
+                    field,
+                    message: pem_issue(path, label)?,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let services_file = match self.registry.kind.to_lowercase().as_str() {
+            "file" => self.registry.addr.strip_prefix("file://"),
+            _ => None,
+        };
+        if let Some(path) = services_file.filter(|path| !path.is_empty()) {
+            if let Err(e) = plugin::read_services_file(path) {
+                issues.push(ConfigIssue {
+                    line: line_of(source, &self.registry.addr),
+                    field: "registry.addr".to_string(),
+                    message: e.to_string(),
+                });
+            }
+        }
+
+        let mut schemas = vec![];
+        let mut route_sets = vec![("gateway.routes".to_string(), &self.gateway.routes)];
+        for (t, tenant) in self.gateway.tenancy.tenants.iter().enumerate() {
+            route_sets.push((
+                format!("gateway.tenancy.tenants[{}].routes", t),
+                &tenant.routes,
+            ));
+        }
+        for (scope, routes) in &route_sets {
+            for (i, route) in routes.iter().enumerate() {
+                if let Some(schema) = &route.policy.schema {
+                    schemas.push((format!("{}[{}].policy.schema", scope, i), schema));
+                }
+            }
+        }
+        issues.extend(schemas.into_iter().filter_map(|(field, path)| {
+            Some(ConfigIssue {
+                line: line_of(source, path),
+                field,
+                message: crate::schema::issue(path)?,
+            })
+        }));
+        #[cfg(feature = "transcode")]
+        if let Some(transcode) = &self.gateway.transcode {
+            if let Some(message) = crate::api::transcode::issue(transcode) {
+                issues.push(ConfigIssue {
+                    line: line_of(source, "transcode"),
+                    field: "gateway.transcode".to_string(),
+                    message,
+                });
+            }
+        }
+        if issues.is_empty() {
+            return Ok(());
+        }
+        Err(ConfigError::Invalid {
+            path: name.to_string(),
+            issues,
+        })
+    }
+
+    pub fn listen(&self) -> Vec<String> {
+        if self.gateway.listen.is_empty() {
+            return vec![DEFAULT_LISTEN.to_string()];
+        }
+        self.gateway.listen.clone()
+    }
+
+    // the bulkhead of `service`, 0 for no limit.
+    pub fn max_concurrent(&self, service: &str) -> usize {
+        let bulkhead = &self.gateway.bulkhead;
+        match bulkhead.services.get(service) {
+            Some(max) => *max,
+            None => bulkhead.max_concurrent,
+        }
+    }
+
+    pub fn request_timeout(&self) -> Option<Duration> {
+        match self.gateway.request_timeout_secs {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        }
+    }
+
+    // the service of the route of `path`.
+    pub fn route(&self, path: &str) -> Option<&str> {
+        self.matching_route(path).map(|r| r.service.as_str())
+    }
+
+    // the first pattern route matching `path`, else the longest prefix it is
+    // under.
+    pub fn matching_route(&self, path: &str) -> Option<&Route> {
+        matching_route(&self.gateway.routes, path)
+    }
+
+    // the timeout of requests on `route`, None for no limit.
+    pub fn route_timeout(&self, route: Option<&Route>) -> Option<Duration> {
+        let long_poll = route.is_some_and(|r| r.policy.long_poll);
+        match route.and_then(|r| r.policy.timeout_secs) {
+            Some(0) => None,
+            Some(secs) => Some(Duration::from_secs(secs)),
+            None if long_poll => match self.gateway.long_poll.timeout_secs {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            None => self.request_timeout(),
+        }
+    }
+
+    // the algorithm `route` picks instances with instead of the one of the
+    // service.
+    pub fn route_lba(&self, route: Option<&Route>) -> Option<LoadBalancerAlgorithm> {
+        parse_lba(route?.policy.lb_override.as_ref()?)
+    }
+
+    pub fn plugin_config(&self) -> PluginConfig {
+        let mut config =
+            PluginConfig::new(get_plugin_type(&self.registry.kind), &self.registry.addr);
+        config.username = self.registry.username.clone();
+        config.password = self.registry.password.clone();
+        config.connect_timeout = self.registry.connect_timeout_secs.map(Duration::from_secs);
+        if let Some(vault) = &self.registry.vault {
+            let token = vault.token.as_deref().unwrap_or_default();
+            config = config.secrets(
+                VaultProvider::new(&vault.addr, token, &vault.path)
+                    .keys(&vault.username_key, &vault.password_key),
+            );
+        }
+        config
+    }
+
+    // the algorithm `requested` names, e.g. `consistent_hash:user-42`, when
+    // `route` lets requests ask for it.
+    pub fn requested_lba(
+        &self,
+        route: Option<&Route>,
+        requested: &str,
+    ) -> Option<LoadBalancerAlgorithm> {
+        let (name, key) = match requested.split_once(':') {
+            Some((name, key)) => (lb_name(name), Some(key.trim())),
+            None => (lb_name(requested), None),
+        };
+        if !route?
+            .policy
+            .lb_requests
+            .iter()
+            .any(|lb| lb_name(lb) == name)
+        {
+            return None;
+        }
+        match (name.as_str(), key) {
+            ("consistenthash", Some(key)) if !key.is_empty() => {
+                Some(LoadBalancerAlgorithm::ConsistentHash(key.to_string()))
+            }
+            ("consistenthash", _) => None,
+            (name, _) => parse_lba(name),
+        }
+    }
+
+    pub fn lba(&self) -> LoadBalancerAlgorithm {
+        parse_lba(&self.lb.default).unwrap_or(LoadBalancerAlgorithm::RoundRobin)
+    }
+
+    pub fn trigger_addr(&self, group: &str) -> Option<SocketAddr> {
+        self.service.triggers.get(group)?.parse().ok()
+    }
+}
+
+// the first pattern route of `routes` matching `path`, else the longest
+// prefix it is under.
+fn matching_route<'a>(routes: &'a [Route], path: &str) -> Option<&'a Route> {
+    routes
+        .iter()
+        .find(|r| r.pattern.is_some() && r.matches(path))
+        .or_else(|| {
+            routes
+                .iter()
+                .filter(|r| r.pattern.is_none() && r.matches(path))
+                .max_by_key(|r| r.prefix.trim_end_matches('/').len())
+        })
+}
+
+// an iso 3166-1 country code, or a region of one as iso 3166-2 has it.
+fn is_geo_code(code: &str) -> bool {
+    let (country, region) = match code.split_once('-') {
+        Some((country, region)) => (country, Some(region)),
+        None => (code, None),
+    };
+    country.len() == 2
+        && country.bytes().all(|b| b.is_ascii_uppercase())
+        && region.is_none_or(|r| {
+            (1..=3).contains(&r.len()) && r.bytes().all(|b| b.is_ascii_alphanumeric())
+        })
+}
+
+// what of an instance `RoutePolicy::response_headers` may set, besides its
+// metadata.
+const INSTANCE_FIELDS: [&str; 3] = ["addr", "version", "weight"];
+
+// the algorithms requests may ask for, by `lb_name`.
+const LB_REQUESTS: [&str; 3] = ["roundrobin", "random", "consistenthash"];
+
+// `name` told apart from the way it is written, e.g. round-robin.
+fn lb_name(name: &str) -> String {
+    name.to_lowercase().replace(['_', '-'], "")
+}
+
+fn parse_lba(name: &str) -> Option<LoadBalancerAlgorithm> {
+    match lb_name(name).as_str() {
+        "roundrobin" => Some(LoadBalancerAlgorithm::RoundRobin),
+        "random" => Some(LoadBalancerAlgorithm::Random),
+        _ => None,
+    }
+}
+
+// 1-based line of the byte `offset`.
+fn line_at(source: &str, offset: usize) -> usize {
+    source[..offset.min(source.len())].matches('\n').count() + 1
+}
+
+// the first line `value` is written on, if it came from the file.
+// the groups `rewrite` refers to, `$1`, `${1}`, `$name` or `${name}`, that
+// `pattern` does not have.
+fn unknown_groups(pattern: &Regex, rewrite: &str) -> Vec<String> {
+    let names = pattern.capture_names().flatten().collect::<Vec<_>>();
+    let mut unknown = vec![];
+    let mut rest = rewrite;
+    while let Some(at) = rest.find('$') {
+        rest = &rest[at + 1..];
+        let group = match rest.strip_prefix('{') {
+            Some(braced) => braced.split('}').next().unwrap_or_default(),
+            None if rest.starts_with('$') => {
+                rest = &rest[1..];
+                continue;
+            }
+            None => {
+                let end = rest
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(rest.len());
+                &rest[..end]
+            }
+        };
+        let known = match group.parse::<usize>() {
+            Ok(index) => index < pattern.captures_len(),
+            Err(_) => names.contains(&group),
+        };
+        if !group.is_empty() && !known {
+            unknown.push(group.to_string());
+        }
+    }
+    unknown
+}
+
+// empty, or a dns label like the namespaces of kubernetes.
+fn is_namespace(namespace: &str) -> bool {
+    namespace.len() <= 63
+        && !namespace.starts_with('-')
+        && !namespace.ends_with('-')
+        && namespace
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+}
+
+// what is wrong with the endpoints in `addr` of a registry of type `kind`,
+// past its scheme: etcd://http://node1:2379,http://node2:2379 or
+// consul://http://localhost:8500.
+fn registry_addr_issue(kind: &str, addr: &str) -> Option<String> {
+    let endpoints = match kind {
+        "etcd" => addr["etcd://".len()..].split(',').collect::<Vec<_>>(),
+        "consul" => vec![&addr["consul://".len()..]],
+        "gossip" => return gossip_addr_issue(&addr["gossip://".len()..]),
+        _ => return None,
+    };
+    endpoints.into_iter().find_map(|endpoint| {
+        let uri = endpoint.parse::<hyper::Uri>();
+        let valid = uri.is_ok_and(|uri| {
+            matches!(uri.scheme_str(), Some("http" | "https"))
+                && uri.host().is_some()
+                && (kind == "etcd" || uri.port_u16().is_some())
+        });
+        match valid {
+            true => None,
+            false if kind == "consul" => Some(format!(
+                "`{}` is not an http or https url with a port, like consul://http://localhost:8500",
+                endpoint
+            )),
+            false => Some(format!(
+                "`{}` is not an http or https url, like etcd://http://node1:2379,http://node2:2379",
+                endpoint
+            )),
+        }
+    })
+}
+
+// the address to bind, then the seeds and the advertised address as query
+// parameters.
+fn gossip_addr_issue(addr: &str) -> Option<String> {
+    let (bind, query) = addr.split_once('?').unwrap_or((addr, ""));
+    if bind.parse::<std::net::SocketAddr>().is_err() {
+        return Some(format!(
+            "`{}` is not an ip and a port to bind, like gossip://0.0.0.0:7946",
+            bind
+        ));
+    }
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        match pair.split_once('=') {
+            Some(("seeds", seeds)) => {
+                let port = |seed: &str| seed.rsplit_once(':')?.1.parse::<u16>().ok();
+                if let Some(seed) = seeds.split(',').find(|seed| port(seed).is_none()) {
+                    return Some(format!("seed `{}` is not a host and a port", seed));
+                }
+            }
+            Some(("advertise", advertise)) => {
+                if advertise.parse::<std::net::SocketAddr>().is_err() {
+                    return Some(format!("advertise `{}` is not an ip and a port", advertise));
+                }
+            }
+            _ => {
+                return Some(format!(
+                    "`{}` is not seeds=host:port,... or advertise=ip:port",
+                    pair
+                ))
+            }
+        }
+    }
+    None
+}
+
+// what is wrong with the pem file at `path` that should hold a `label`,
+// e.g. a CERTIFICATE or a PRIVATE KEY.
+fn pem_issue(path: &str, label: &str) -> Option<String> {
+    match std::fs::read_to_string(path) {
+        Err(e) => Some(format!("cannot read `{}`: {}", path, e)),
+        Ok(pem)
+            if !pem
+                .lines()
+                .any(|l| l.starts_with("-----BEGIN ") && l.contains(label)) =>
+        {
+            Some(format!("`{}` holds no pem {}", path, label))
+        }
+        Ok(_) => None,
+    }
+}
+
+fn line_of(source: &str, value: &str) -> Option<usize> {
+    if value.is_empty() {
+        return None;
+    }
+    source
+        .lines()
+        .position(|line| line.contains(value))
+        .map(|i| i + 1)
+}
+
+// the middleware of the crossgate binary only those signing in with
+// gateway.admin.basic_auth may use.
+const ADMIN_MIDDLEWARE: &[&str] = &["captures", "colors", "revocations"];
+
+// makes `config` the one every gateway, service and plugin of the process
+// starts with. Only the first call counts, and only if it comes before
+// anything was started.
+pub fn init(config: Config) {
+    if CONFIG.set(config).is_err() {
+        tracing::warn!("config is already initialized, ignored");
+    }
+}
+
+// the config passed to `init`, else `Config::from_env`.
+pub fn current() -> &'static Config {
+    CONFIG.get_or_init(Config::from_env)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOML: &str = r#"
+[gateway]
+listen = ["0.0.0.0:8080", "[::]:8080"]
+request_timeout_secs = 10
+middleware = ["health"]
+coalesce = true
+bulkhead = { max_concurrent = 100, services = { "/t/ums" = 10 } }
+shedding = { max_in_flight = 500 }
+listener = { body_read_timeout_secs = 0, max_headers = 60, max_connections_per_ip = 64 }
+auth = { api_key_header = "x-key", fail_open = true }
+capture = { sample_rate = 0.5, paths = ["/t/ums"] }
+blue_green = { active = { "/t/ums" = "green" } }
+notify = { webhooks = [{ url = "http://alerts:9000/hook" }], services = ["/t/report"] }
+audit = { sinks = [{ type = "file", path = "audit.log" }, { type = "syslog", addr = "syslog:514" }] }
+self_register = { service = "/crossgate/gateway", metadata = { zone = "a" } }
+peers = { enabled = true, secret = "s" }
+routes = [
+    { prefix = "/api/users", service = "/t/ums" },
+    { prefix = "/api/users/admin", service = "/t/admin", rewrite = "/admin", policy = { timeout_secs = 0, retries = 2, lb_override = "random", lb_requests = ["consistent_hash"], priority = "critical", response_headers = { x-served-by = "addr", x-canary = "metadata.track" } } },
+    { pattern = '^/api/v(\d+)/orders/(.*)', service = "/t/order", rewrite = "/${2}?ver=${1}" },
+    { prefix = "/legacy", service = "/legacy/billing", upstreams = ["http://10.0.0.7:8080", "https://billing.example.org"] },
+    { prefix = "/events", service = "/t/events", policy = { long_poll = true } },
+]
+
+[gateway.tls]
+cert = "cert.pem"
+key = "key.pem"
+hosts = [
+    { server_name = "*.example.org", service = "/t/ums" },
+    { server_name = "admin.example.org", cert = "admin.pem", key = "admin-key.pem" },
+]
+
+[[gateway.tenancy.tenants]]
+name = "acme"
+path_prefix = "/acme"
+rate_limit = 100
+routes = [{ prefix = "/api/users", service = "/acme/ums" }]
+
+[registry]
+type = "etcd"
+addr = "etcd://http://node1:2379"
+
+[lb]
+default = "random"
+"#;
+
+    #[test]
+    fn toml_and_yaml() {
+        let config = Config::parse("a.toml", TOML, Format::Toml).unwrap();
+        config.validate("a.toml", TOML).unwrap();
+        assert_eq!(config.listen().len(), 2);
+        assert_eq!(config.request_timeout(), Some(Duration::from_secs(10)));
+        assert_eq!(config.plugin_config().r#type, PluginType::Etcd);
+        assert!(matches!(config.lba(), LoadBalancerAlgorithm::Random));
+        assert_eq!(config.route("/api/users/1"), Some("/t/ums"));
+        assert_eq!(config.route("/api/users/admin/1"), Some("/t/admin"));
+        assert_eq!(config.route("/api/usersx"), None);
+        let admin = config.matching_route("/api/users/admin");
+        assert_eq!(config.route_timeout(admin), None);
+        assert_eq!(admin.unwrap().policy.retries, 2);
+        assert_eq!(admin.unwrap().policy.priority, Priority::Critical);
+        assert_eq!(
+            admin.unwrap().policy.response_headers["x-canary"],
+            "metadata.track"
+        );
+        assert!(matches!(
+            config.route_lba(admin),
+            Some(LoadBalancerAlgorithm::Random)
+        ));
+        let users = config.matching_route("/api/users");
+        assert_eq!(config.route_timeout(users), Some(Duration::from_secs(10)));
+        let events = config.matching_route("/events");
+        assert_eq!(config.route_timeout(events), Some(Duration::from_secs(300)));
+        assert!(config.route_lba(users).is_none());
+        assert!(matches!(
+            config.requested_lba(admin, "consistent-hash: user-42"),
+            Some(LoadBalancerAlgorithm::ConsistentHash(key)) if key == "user-42"
+        ));
+        assert!(config.requested_lba(admin, "consistent_hash").is_none());
+        assert!(config.requested_lba(admin, "random").is_none());
+        assert!(config
+            .requested_lba(users, "consistent_hash:user-42")
+            .is_none());
+        assert_eq!(users.unwrap().policy.priority, Priority::Normal);
+        assert_eq!(config.gateway.shedding.low_percent, 80);
+        assert_eq!(config.gateway.listener.header_read_timeout_secs, 10);
+        assert_eq!(config.gateway.listener.max_headers, 60);
+        assert_eq!(config.gateway.listener.max_connections_per_ip, 64);
+        assert_eq!(config.gateway.auth.api_key_header, "x-key");
+        assert_eq!(config.gateway.auth.revocation_store, "memory");
+        let acme = &config.gateway.tenancy.tenants[0];
+        assert_eq!(
+            acme.matching_route("/api/users/1").unwrap().service,
+            "/acme/ums"
+        );
+        assert!(acme.matching_route("/legacy").is_none());
+        let rewrite = |path: &str, query| config.matching_route(path)?.rewrite(path, query);
+        assert_eq!(rewrite("/api/users/1", None), None);
+        assert_eq!(rewrite("/api/users/admin", None).as_deref(), Some("/admin"));
+        assert_eq!(
+            rewrite("/api/users/admin/1", Some("a=1")).as_deref(),
+            Some("/admin/1?a=1")
+        );
+        assert_eq!(
+            rewrite("/api/v2/orders/7/items", Some("a=1")).as_deref(),
+            Some("/7/items?ver=2&a=1")
+        );
+        assert_eq!(config.gateway.middleware, ["health"]);
+        assert!(config.gateway.coalesce);
+        assert_eq!(config.max_concurrent("/t/ums"), 10);
+        assert_eq!(config.max_concurrent("/t/admin"), 100);
+        assert!(config.gateway.capture.enabled());
+        assert_eq!(config.gateway.capture.keep, 100);
+        assert_eq!(config.gateway.blue_green.label, "color");
+        assert_eq!(config.gateway.blue_green.active["/t/ums"], "green");
+        assert_eq!(config.gateway.notify.webhooks[0].format, "json");
+        assert_eq!(config.gateway.notify.poll_interval_secs, 5);
+        assert_eq!(config.gateway.audit.sinks[1].kind, "syslog");
+        assert_eq!(config.gateway.audit.queue, 1024);
+        assert_eq!(config.gateway.self_register.service, "/crossgate/gateway");
+        assert!(config.gateway.self_register.advertise.is_none());
+        assert_eq!(config.gateway.peers.fanout, 2);
+        let tls = config.gateway.tls.as_ref().unwrap();
+        assert_eq!(tls.key, "key.pem");
+        assert_eq!(
+            tls.host("Admin.example.org").unwrap().cert.as_deref(),
+            Some("admin.pem")
+        );
+        assert_eq!(
+            tls.host("ums.example.org").unwrap().service.as_deref(),
+            Some("/t/ums")
+        );
+        assert!(tls.host("a.ums.example.org").is_none());
+        assert!(tls.host("example.org").is_none());
+
+        let yaml = "registry:\n  type: none\nlb:\n  strict: 10.0.0.1:80\n";
+        let config = Config::parse("a.yaml", yaml, Format::Yaml).unwrap();
+        config.validate("a.yaml", yaml).unwrap();
+        assert_eq!(config.lb.strict.as_deref(), Some("10.0.0.1:80"));
+        assert_eq!(config.listen(), [DEFAULT_LISTEN]);
+    }
+
+    #[test]
+    fn errors_point_at_lines() {
+        let source = "[registry]\ntype = \"etcd\"\naddr = 1\n";
+        match Config::parse("a.toml", source, Format::Toml) {
+            Err(ConfigError::Parse { line, .. }) => assert_eq!(line, Some(3)),
+            other => panic!("{:?}", other),
+        }
+        let source = "[gateway]\nroutes = [{ pattern = \"(\", service = \"/t/ums\" }]\n";
+        match Config::parse("a.toml", source, Format::Toml) {
+            Err(ConfigError::Parse { line, .. }) => assert_eq!(line, Some(2)),
+            other => panic!("{:?}", other),
+        }
+
+        let source = "gateway:\n  listen:\n    - localhost\nregistry:\n  type: zookeeper\n";
+        let config = Config::parse("a.yaml", source, Format::Yaml).unwrap();
+        let err = config.validate("a.yaml", source).unwrap_err();
+        match &err {
+            ConfigError::Invalid { issues, .. } => {
+                let lines = issues
+                    .iter()
+                    .map(|i| (i.field.as_str(), i.line))
+                    .collect::<Vec<_>>();
+                assert_eq!(
+                    lines,
+                    [("gateway.listen[0]", Some(3)), ("registry.type", Some(5))]
+                );
+            }
+            other => panic!("{:?}", other),
+        }
+        assert!(err.to_string().contains("a.yaml:5: registry.type"));
+    }
+
+    #[test]
+    fn routes_registry_and_files() {
+        let source = r#"
+[gateway]
+routes = [
+    { prefix = "/a", service = "/t/a" },
+    { prefix = "/a/", service = "/t/b" },
+    { pattern = '^/v(\d+)/(?P<rest>.*)', service = "/t/c", rewrite = "/${rest}?v=$1&x=${3}&y=$$2" },
+]
+
+[gateway.tls]
+cert = "/nonexistent/cert.pem"
+key = "/nonexistent/key.pem"
+
+[registry]
+type = "etcd"
+addr = "etcd://http://node1:2379,node2:2379"
+"#;
+        let config = Config::parse("a.toml", source, Format::Toml).unwrap();
+        let issues = |err| match err {
+            ConfigError::Invalid { issues, .. } => issues
+                .into_iter()
+                .map(|i| (i.field, i.line))
+                .collect::<Vec<_>>(),
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(
+            issues(config.validate("a.toml", source).unwrap_err()),
+            [
+                ("gateway.routes[1]".to_string(), Some(5)),
+                ("gateway.routes[2].rewrite".to_string(), Some(6)),
+                ("registry.addr".to_string(), Some(15)),
+            ]
+        );
+        let err = config.check_files("a.toml", source).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("a.toml:10: gateway.tls.cert: cannot read"));
+        assert_eq!(issues(err).len(), 2);
+
+        let consul = "consul://http://localhost:8500";
+        assert_eq!(registry_addr_issue("consul", consul), None);
+        assert!(registry_addr_issue("consul", "consul://localhost").is_some());
+    }
+
+    #[test]
+    fn route_policies_need_their_middleware() {
+        let schema = std::env::temp_dir().join("crossgate-route-policies.json");
+        std::fs::write(&schema, r#"{"type": "object"}"#).unwrap();
+        let source = |middleware: &str| {
+            format!(
+                r#"
+[gateway]
+middleware = [{}]
+routes = [
+    {{ prefix = "/pay", service = "/t/pay", policy = {{ hmac = true }} }},
+    {{ prefix = "/orders", service = "/t/orders", policy = {{ schema = "{}" }} }},
+]
+
+[gateway.auth.hmac]
+clients = {{ acme = "0123456789abcdef" }}
+
+[registry]
+type = "none"
+"#,
+                middleware,
+                schema.display()
+            )
+        };
+        let fields = |source: &str| {
+            let config = Config::parse("a.toml", source, Format::Toml).unwrap();
+            match config.validate("a.toml", source) {
+                Ok(()) => vec![],
+                Err(ConfigError::Invalid { issues, .. }) => {
+                    issues.into_iter().map(|i| i.field).collect::<Vec<_>>()
+                }
+                Err(other) => panic!("{:?}", other),
+            }
+        };
+        assert_eq!(
+            fields(&source("")),
+            [
+                "gateway.routes[0].policy.hmac",
+                "gateway.routes[1].policy.schema"
+            ]
+        );
+        assert_eq!(
+            fields(&source(r#""hmac""#)),
+            ["gateway.routes[1].policy.schema"]
+        );
+        assert!(fields(&source(r#""hmac", "schema""#)).is_empty());
+    }
+
+    #[test]
+    fn admin_middleware_needs_credentials() {
+        let issues = |source: &str| {
+            let config = Config::parse("a.toml", source, Format::Toml).unwrap();
+            match config.validate("a.toml", source) {
+                Ok(()) => vec![],
+                Err(ConfigError::Invalid { issues, .. }) => issues
+                    .into_iter()
+                    .map(|i| (i.field, i.message))
+                    .collect::<Vec<_>>(),
+                Err(other) => panic!("{:?}", other),
+            }
+        };
+        let source = "[gateway]\nmiddleware = [\"revocations\"]\n[registry]\ntype = \"none\"\n";
+        assert_eq!(
+            issues(source),
+            [(
+                "gateway.admin.basic_auth".to_string(),
+                "required by the revocations middleware".to_string()
+            )]
+        );
+        let signed = format!("{}[gateway.admin]\nbasic_auth = \"ops:secret\"\n", source);
+        assert!(issues(&signed).is_empty());
+    }
+
+    #[test]
+    fn env_wins_over_the_file() {
+        let mut config = Config::parse("a.toml", TOML, Format::Toml).unwrap();
+        config.apply_env(|key| match key {
+            "REGISTER_TYPE" => Some("none".to_string()),
+            "GATEWAY_LISTEN" => Some("127.0.0.1:1, 127.0.0.1:2".to_string()),
+            "STRICT" => Some("".to_string()),
+            "OTEL_EXPORTER_OTLP_ENDPOINT" => Some("http://collector:4317".to_string()),
+            "REGISTER_VAULT_PATH" => Some("database/creds/registry".to_string()),
+            "VAULT_TOKEN" => Some("t".to_string()),
+            _ => None,
+        });
+        let vault = config.registry.vault.as_ref().unwrap();
+        assert_eq!(vault.token.as_deref(), Some("t"));
+        assert_eq!(vault.addr, "http://127.0.0.1:8200");
+        assert!(config.plugin_config().secrets.is_some());
+        assert_eq!(config.plugin_config().r#type, PluginType::None);
+        assert_eq!(config.listen(), ["127.0.0.1:1", "127.0.0.1:2"]);
+        assert_eq!(config.lb.strict, None);
+        assert_eq!(
+            config.telemetry.otlp_endpoint.as_deref(),
+            Some("http://collector:4317")
+        );
+    }
+}
@@ -0,0 +1,172 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::{Body, Request};
+use maxminddb::{geoip2, Reader};
+use once_cell::sync::OnceCell;
+
+use crate::config::{self, GeoIpConfig, Route};
+use crate::{MetricsRegistry, ServiceError};
+
+// the database of `gateway.geoip`, read once as the gateway starts.
+static DATABASE: OnceCell<Reader<Vec<u8>>> = OnceCell::new();
+
+/// Where a client is as the GeoIP database has it, in the extensions of its
+/// requests with `gateway.geoip`: the iso codes of its country, e.g. US,
+/// and of the region in it, e.g. CA.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GeoLocation {
+    pub country: Option<String>,
+    pub region: Option<String>,
+}
+
+impl GeoLocation {
+    // the codes the location goes by in the config, the region first, e.g.
+    // US-CA then US.
+    fn codes(&self) -> Vec<String> {
+        let country = match &self.country {
+            Some(country) => country,
+            None => return vec![],
+        };
+        match &self.region {
+            Some(region) => vec![format!("{}-{}", country, region), country.clone()],
+            None => vec![country.clone()],
+        }
+    }
+
+    // the service of the most specific code `services` has for it.
+    fn service<'a>(&self, services: &'a HashMap<String, String>) -> Option<&'a str> {
+        self.codes()
+            .iter()
+            .find_map(|code| services.get(code))
+            .map(String::as_str)
+    }
+
+    // the code it is blocked by, if any.
+    fn blocked_by(&self, block: &[String]) -> Option<String> {
+        self.codes().into_iter().find(|code| block.contains(code))
+    }
+}
+
+// reads the database of `config`.
+pub(crate) fn load(config: &GeoIpConfig) -> Result<(), ServiceError> {
+    let reader = Reader::open_readfile(&config.database)
+        .map_err(|e| ServiceError::GeoIp(format!("read {}: {}", config.database, e)))?;
+    tracing::info!(
+        database = %config.database,
+        kind = %reader.metadata.database_type,
+        "geoip database loaded"
+    );
+    // loaded once per process.
+    let _ = DATABASE.set(reader);
+    Ok(())
+}
+
+fn locate(reader: &Reader<Vec<u8>>, ip: IpAddr) -> GeoLocation {
+    // a country database answers with the fields of a city one it has.
+    let city = match reader.lookup::<geoip2::City>(ip) {
+        Ok(city) => city,
+        Err(_) => return GeoLocation::default(),
+    };
+    GeoLocation {
+        country: city.country.and_then(|c| c.iso_code).map(str::to_string),
+        region: city
+            .subdivisions
+            .and_then(|s| s.into_iter().next())
+            .and_then(|s| s.iso_code)
+            .map(str::to_string),
+    }
+}
+
+// looks the client of `req` up, for the intercepters and the upstream; what
+// the client said of itself in the headers is dropped.
+pub(crate) fn tag(req: &mut Request<Body>, client_ip: IpAddr) {
+    let config = match &config::current().gateway.geoip {
+        Some(config) => config,
+        None => return,
+    };
+    let reader = match DATABASE.get() {
+        Some(reader) => reader,
+        None => return,
+    };
+    let location = locate(reader, client_ip);
+    for (header, value) in [
+        (&config.country_header, &location.country),
+        (&config.region_header, &location.region),
+    ] {
+        let Ok(header) = HeaderName::from_bytes(header.as_bytes()) else {
+            continue;
+        };
+        req.headers_mut().remove(&header);
+        if let Some(value) = value.as_deref().and_then(|v| HeaderValue::from_str(v).ok()) {
+            req.headers_mut().insert(header, value);
+        }
+    }
+    req.extensions_mut().insert(location);
+}
+
+// the code the client of `req` is refused by, after `tag`.
+pub(crate) fn blocked(req: &Request<Body>) -> Option<String> {
+    let config = config::current().gateway.geoip.as_ref()?;
+    let code = req
+        .extensions()
+        .get::<GeoLocation>()?
+        .blocked_by(&config.block)?;
+    MetricsRegistry::global()
+        .counter("crossgate_gateway_geo_blocked_total", &[("code", &code)])
+        .inc();
+    Some(code)
+}
+
+// the service `route` sends the client of `req` to for where it is, after
+// `tag`.
+pub(crate) fn steer(route: Option<&Route>, req: &Request<Body>) -> Option<String> {
+    let services = &route?.policy.geo_services;
+    if services.is_empty() {
+        return None;
+    }
+    let service = req.extensions().get::<GeoLocation>()?.service(services)?;
+    MetricsRegistry::global()
+        .counter(
+            "crossgate_gateway_geo_steered_total",
+            &[("service", service)],
+        )
+        .inc();
+    Some(service.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn regions_go_before_their_country() {
+        let location = |country: Option<&str>, region: Option<&str>| GeoLocation {
+            country: country.map(str::to_string),
+            region: region.map(str::to_string),
+        };
+        let services = [("US", "/us/ums"), ("US-CA", "/us-west/ums")]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<HashMap<_, _>>();
+
+        let california = location(Some("US"), Some("CA"));
+        assert_eq!(california.service(&services), Some("/us-west/ums"));
+        let texas = location(Some("US"), Some("TX"));
+        assert_eq!(texas.service(&services), Some("/us/ums"));
+        assert_eq!(location(Some("DE"), None).service(&services), None);
+        // not in the database, e.g. a private address.
+        assert_eq!(location(None, None).service(&services), None);
+
+        let block = vec!["KP".to_string(), "US-TX".to_string()];
+        assert_eq!(texas.blocked_by(&block).as_deref(), Some("US-TX"));
+        assert_eq!(california.blocked_by(&block), None);
+        assert_eq!(
+            location(Some("KP"), Some("01"))
+                .blocked_by(&block)
+                .as_deref(),
+            Some("KP")
+        );
+    }
+}
@@ -1,4 +1,10 @@
-use rand::{rngs::ThreadRng, Rng};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use rand::Rng;
+
+use crate::{Endpoint, Instance};
 
 pub static DEFAULT_LOAD_BALANCER_ALGORITHM: LoadBalancerAlgorithm =
     LoadBalancerAlgorithm::RoundRobin;
@@ -8,14 +14,17 @@ pub enum LoadBalancerAlgorithm {
     RoundRobin,
     Random,
     Strict(String),
+    // the same instance for the same key for as long as it is there, e.g.
+    // a user id; few keys move when instances come and go.
+    ConsistentHash(String),
 }
 
 impl From<String> for LoadBalancerAlgorithm {
     fn from(s: String) -> Self {
         match s.to_ascii_lowercase().as_str() {
-            "RoundRobin" => LoadBalancerAlgorithm::RoundRobin,
-            "Random" => LoadBalancerAlgorithm::Random,
-            "Strict" => LoadBalancerAlgorithm::Strict("".into()),
+            "roundrobin" => LoadBalancerAlgorithm::RoundRobin,
+            "random" => LoadBalancerAlgorithm::Random,
+            "strict" => LoadBalancerAlgorithm::Strict("".into()),
             _ => LoadBalancerAlgorithm::RoundRobin, //default return rr
         }
     }
@@ -27,28 +36,185 @@ impl std::fmt::Display for LoadBalancerAlgorithm {
             LoadBalancerAlgorithm::RoundRobin => write!(f, "RoundRobin"),
             LoadBalancerAlgorithm::Random => write!(f, "Random"),
             LoadBalancerAlgorithm::Strict(_) => write!(f, "Strict"),
+            LoadBalancerAlgorithm::ConsistentHash(_) => write!(f, "ConsistentHash"),
         }
     }
 }
 
-static mut N: usize = 0;
+static N: AtomicUsize = AtomicUsize::new(0);
+
+fn next() -> usize {
+    N.fetch_add(1, Ordering::Relaxed)
+}
 
 impl LoadBalancerAlgorithm {
     pub fn hash(&self, addrs: &[String]) -> String {
+        self.select(addrs).to_string()
+    }
+
+    // like `hash`, an address with weight 2 gets twice the requests of one
+    // with weight 1. Falls back to `hash` when there are no usable weights.
+    pub fn hash_weighted(&self, addrs: &[String], weights: &[u32]) -> String {
+        self.select_weighted(addrs, weights).to_string()
+    }
+
+    // `hash` without the copy, "" when nothing can be picked.
+    pub fn select<'a>(&self, addrs: &'a [String]) -> &'a str {
+        if addrs.is_empty() {
+            return "";
+        }
         match self {
-            LoadBalancerAlgorithm::RoundRobin => unsafe {
-                N = N + 1;
-                return addrs[(N - 1) % addrs.len()].clone();
+            LoadBalancerAlgorithm::RoundRobin => &addrs[next() % addrs.len()],
+            LoadBalancerAlgorithm::Random => &addrs[rand::thread_rng().gen_range(0..addrs.len())],
+            LoadBalancerAlgorithm::Strict(s) => match addrs.iter().find(|addr| *addr == s) {
+                Some(addr) => addr,
+                None => "",
             },
-            LoadBalancerAlgorithm::Random => {
-                return addrs[rand::thread_rng().gen_range(0..addrs.len())].to_string();
+            LoadBalancerAlgorithm::ConsistentHash(key) => {
+                rendezvous(key, addrs, |addr| (addr.as_str(), 1)).map_or("", |addr| addr.as_str())
             }
-            LoadBalancerAlgorithm::Strict(s) => {
-                if !addrs.contains(s) {
-                    return "".into();
-                }
-                return s.clone();
+        }
+    }
+
+    // `hash_weighted` without the copy.
+    pub fn select_weighted<'a>(&self, addrs: &'a [String], weights: &[u32]) -> &'a str {
+        let total = weights.iter().map(|w| *w as u64).sum::<u64>();
+        if total == 0 || weights.len() != addrs.len() {
+            return self.select(addrs);
+        }
+
+        let n = match self {
+            LoadBalancerAlgorithm::RoundRobin => next() as u64 % total,
+            LoadBalancerAlgorithm::Random => rand::thread_rng().gen_range(0..total),
+            LoadBalancerAlgorithm::Strict(_) => return self.select(addrs),
+            LoadBalancerAlgorithm::ConsistentHash(key) => {
+                let weighted = addrs.iter().zip(weights).collect::<Vec<_>>();
+                return rendezvous(key, &weighted, |(addr, weight)| (addr.as_str(), **weight))
+                    .map_or("", |(addr, _)| addr.as_str());
             }
+        };
+        nth_weighted(addrs, weights.iter().copied(), n).as_str()
+    }
+
+    /// The instance of `endpoint` the next request goes to, by weight; None
+    /// when there is none, or strict names none of them.
+    pub fn pick<'a>(&self, endpoint: &'a Endpoint) -> Option<&'a Instance> {
+        let instances = endpoint.instances();
+        if instances.is_empty() {
+            return None;
         }
+        let total = instances.iter().map(|i| i.weight as u64).sum::<u64>();
+        let n = match (self, total) {
+            (LoadBalancerAlgorithm::Strict(s), _) => {
+                return instances.iter().find(|i| &i.addr == s)
+            }
+            (LoadBalancerAlgorithm::ConsistentHash(key), total) => {
+                return rendezvous(key, instances, |i| {
+                    (i.addr.as_str(), if total == 0 { 1 } else { i.weight })
+                })
+            }
+            (LoadBalancerAlgorithm::RoundRobin, 0) => {
+                return instances.get(next() % instances.len())
+            }
+            (LoadBalancerAlgorithm::Random, 0) => {
+                return instances.get(rand::thread_rng().gen_range(0..instances.len()))
+            }
+            (LoadBalancerAlgorithm::RoundRobin, total) => next() as u64 % total,
+            (LoadBalancerAlgorithm::Random, total) => rand::thread_rng().gen_range(0..total),
+        };
+        Some(nth_weighted(
+            instances,
+            instances.iter().map(|i| i.weight),
+            n,
+        ))
+    }
+}
+
+// the item scoring highest for `key` by weighted rendezvous hashing: each
+// item keeps its keys while others come and go, and gets a share of them
+// by its weight. Items weighted 0 get none.
+fn rendezvous<'a, T>(
+    key: &str,
+    items: &'a [T],
+    addr_weight: impl Fn(&T) -> (&str, u32),
+) -> Option<&'a T> {
+    items
+        .iter()
+        .filter_map(|item| {
+            let (addr, weight) = addr_weight(item);
+            if weight == 0 {
+                return None;
+            }
+            let mut hasher = DefaultHasher::new();
+            (key, addr).hash(&mut hasher);
+            // uniform in (0, 1).
+            let u = ((hasher.finish() >> 11) as f64 + 0.5) / (1u64 << 53) as f64;
+            Some((item, weight as f64 / -u.ln()))
+        })
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(item, _)| item)
+}
+
+// the item whose cumulative weight range holds `n`.
+fn nth_weighted<T>(items: &[T], weights: impl IntoIterator<Item = u32>, mut n: u64) -> &T {
+    for (item, weight) in items.iter().zip(weights) {
+        if n < weight as u64 {
+            return item;
+        }
+        n -= weight as u64;
+    }
+    &items[items.len() - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn weighted_pick_follows_weights() {
+        let addrs = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        let weights = [2, 0, 1];
+
+        let picked = (0..3)
+            .map(|n| nth_weighted(&addrs, weights, n))
+            .collect::<Vec<_>>();
+        assert_eq!(picked, ["a", "a", "c"]);
+
+        // no usable weights: plain `hash`
+        let lba = LoadBalancerAlgorithm::Random;
+        assert!(addrs.contains(&lba.hash_weighted(&addrs, &[0, 0, 0])));
+
+        let endpoint = Endpoint::new(
+            addrs
+                .iter()
+                .zip(weights)
+                .map(|(addr, weight)| Instance {
+                    addr: addr.clone(),
+                    weight,
+                    ..Default::default()
+                })
+                .collect(),
+        );
+        assert_ne!(lba.pick(&endpoint).unwrap().addr, "b");
+        let strict = LoadBalancerAlgorithm::Strict("b".to_string());
+        assert_eq!(strict.pick(&endpoint).unwrap().addr, "b");
+        assert!(LoadBalancerAlgorithm::Strict("d".to_string())
+            .pick(&endpoint)
+            .is_none());
+
+        // the same instance for a key, also once another one is gone.
+        let hash = LoadBalancerAlgorithm::ConsistentHash("user-42".to_string());
+        let picked = hash.pick(&endpoint).unwrap().addr.clone();
+        assert_ne!(picked, "b");
+        assert_eq!(hash.pick(&endpoint).unwrap().addr, picked);
+        let fewer = Endpoint::new(
+            endpoint
+                .instances()
+                .iter()
+                .filter(|i| i.addr == picked || i.addr == "b")
+                .cloned()
+                .collect(),
+        );
+        assert_eq!(hash.pick(&fewer).unwrap().addr, picked);
     }
 }
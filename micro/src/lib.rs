@@ -1,33 +1,113 @@
-#![feature(type_alias_impl_trait)]
-
+mod advertise;
 mod api;
+pub mod audit;
+pub mod auth;
+mod client;
+mod color;
+pub mod config;
+#[cfg(feature = "geoip")]
+mod geoip;
 mod lba;
+mod lookup;
+mod mesh;
+mod metrics;
+mod notify;
+mod peers;
 mod register;
+mod rpc;
+pub mod schema;
+mod signing;
+#[cfg(feature = "spiffe")]
+mod spiffe;
 mod task;
+#[cfg(feature = "otel")]
+pub mod telemetry;
 mod web;
 
-pub use register::Register;
-use serde::Deserialize;
+pub use plugin::{HealthStatus, ServiceHealth};
+pub use register::{MembershipChange, Register, RegisterError};
 
+use futures::future::BoxFuture;
+use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::time::Duration;
 
-pub use api::{run as run_api_server, Intercepter, IntercepterType};
+pub use advertise::AdvertiseAddr;
+pub use api::{
+    captures, run as run_api_server, run_from_config as run_api_server_from_config, Capture,
+    ClientIdentity, Intercepter, IntercepterType, Tenant,
+};
+pub use client::{Client, ClientError};
+pub use color::{
+    active_color, active_colors, clear_active_color, rollback_color, set_active_color, ColorSwitch,
+};
+#[cfg(feature = "geoip")]
+pub use geoip::GeoLocation;
 pub use lba::*;
+pub use mesh::{mesh_identity, mesh_server_config};
+pub use metrics::{Counter, Gauge, MetricValue, MetricsRegistry, Sample};
+pub use rpc::{Rpc, RpcClient, RpcServer, Transport, TransportError, RPC_PROTOCOL};
+#[cfg(feature = "spiffe")]
+pub use spiffe::{current_svid, SpiffeError, Svid};
 
 pub use task::backend_service_run;
 pub use task::Executor;
+pub use task::{
+    schedule_after, schedule_at, Claimed, ExecutorBuilder, ExecutorMetrics, Job, JobOwner,
+    OneShotHandler, PartitionStrategy, Partitioner, Schedule, Scheduler, SupervisionPolicy,
+    TaskQueue, Worker, WorkerExecutor,
+};
 
-pub use web::{web_service_run, ServerRunFn};
+#[cfg(feature = "axum")]
+pub use web::run_axum_service;
+pub use web::{
+    run_web_service, run_web_service_with, web_service_run, ProbeFn, ServerRunFn, ServiceEndpoint,
+    ServiceName, TlsConfig, WebService, WebServiceBuilder,
+};
 
-#[derive(Debug)]
+/// Why a service, executor or the gateway could not start or stopped early.
+/// What fails later in the background is handed to
+/// `Service::on_registry_error` instead.
+#[derive(Debug, thiserror::Error)]
 pub enum ServiceError {
+    #[error("service error: {0}")]
     Other(String),
+    #[error("plugin init failed: {0}")]
+    Plugin(#[from] plugin::PluginError),
+    #[error("register {name} failed: {source:#}")]
+    Register {
+        name: String,
+        #[source]
+        source: anyhow::Error,
+    },
+    #[error("invalid listen address `{0}`")]
+    InvalidAddr(String),
+    #[error("bind {addr} failed: {source}")]
+    Bind {
+        addr: String,
+        #[source]
+        source: hyper::Error,
+    },
+    #[error("server on {addr} failed: {source}")]
+    Serve {
+        addr: String,
+        #[source]
+        source: hyper::Error,
+    },
+    #[error("backend service {0} gave up after repeated failures")]
+    GaveUp(String),
+    #[error("gateway tls: {0}")]
+    Tls(String),
+    #[error("geoip: {0}")]
+    GeoIp(String),
+    #[error("transcode: {0}")]
+    Transcode(String),
 }
 
-impl std::fmt::Display for ServiceError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "ServiceError")
-    }
+// config lb.strict (env STRICT), the default strict address of every
+// service.
+pub(crate) fn default_strict() -> Option<String> {
+    config::current().lb.strict.clone()
 }
 
 // [service] --> [endpoint] --> [address]
@@ -37,70 +117,158 @@ pub trait Service: Sync + Send {
     fn addr(&self) -> SocketAddr;
 
     fn lab(&self) -> LoadBalancerAlgorithm {
-        dotenv::dotenv().ok();
-        // if env STRICT is set, return strict
-        let strict_address = ::std::env::var("STRICT").unwrap_or_else(|_| "".to_string());
-        if !strict_address.is_empty() {
+        // if a strict address is set, return strict
+        if let Some(strict_address) = self.strict_address() {
             return LoadBalancerAlgorithm::Strict(strict_address);
         }
-        return LoadBalancerAlgorithm::RoundRobin;
+        config::current().lba()
     }
-}
 
-#[derive(Debug)]
-pub enum TransportError {
-    Other(String),
-}
-// 中间传输层，可能还存在不合理的地方
-pub trait Transport<'a, S, T>
-where
-    S: serde::Serializer,
-    T: Deserialize<'a>,
-{
-    type Future<'b>: std::future::Future<Output = anyhow::Result<T, TransportError>>
-    where
-        Self: 'b;
+    // the address all names of the service are pinned to, by default the
+    // configured lb.strict. A name with its own `lba` is not affected.
+    fn strict_address(&self) -> Option<String> {
+        default_strict()
+    }
+
+    // every name the service is registered under, by default the
+    // comma-separated `name`, all with `lab`.
+    fn names(&self) -> Vec<ServiceName> {
+        self.name().split(',').map(ServiceName::new).collect()
+    }
+
+    // the host registered for `addr`, unless the name is strict.
+    fn advertise(&self) -> AdvertiseAddr {
+        AdvertiseAddr::default()
+    }
+
+    // None when `addr` serves plain http.
+    fn tls(&self) -> Option<TlsConfig> {
+        None
+    }
+
+    // ports published next to `addr`, which serves plain http.
+    fn endpoints(&self) -> Vec<ServiceEndpoint> {
+        vec![]
+    }
+
+    // false when `addr` serves no http and only `endpoints` can be looked
+    // up, so the gateway does not route to it.
+    fn serves_http(&self) -> bool {
+        true
+    }
+
+    // `run_web_service` registers the service once this is true, by default
+    // as soon as its port accepts connections.
+    fn ready(&self) -> BoxFuture<'_, bool> {
+        Box::pin(web::port_bound(self.addr()))
+    }
+
+    // checked every `liveness_interval` once registered, while false the
+    // gateway stops routing to this instance.
+    fn live(&self) -> BoxFuture<'_, bool> {
+        Box::pin(async { true })
+    }
+
+    fn liveness_interval(&self) -> Duration {
+        Duration::from_secs(5)
+    }
 
-    // default request josn implementation
+    // called once the service is registered under all of its names.
+    fn on_registered(&self) -> BoxFuture<'_, ()> {
+        Box::pin(async {})
+    }
 
-    fn decode<'b>(&'b self, _s: S) -> Self::Future<'b> {
-        unimplemented!()
+    // called after the registration was removed on shutdown.
+    fn on_deregistered(&self) -> BoxFuture<'_, ()> {
+        Box::pin(async {})
     }
-    fn encode<'r>(&'r self, _s: S) -> Self::Future<'r> {
-        unimplemented!()
+
+    // called when registering, renewing or updating the registration fails;
+    // the registry may no longer route to this instance.
+    fn on_registry_error(&self, _error: String) -> BoxFuture<'_, ()> {
+        Box::pin(async {})
     }
 }
 
-#[derive(Debug)]
+/// An instance of a service a request may be sent to, with what it
+/// registered.
+#[derive(Debug, Clone, Default)]
+pub struct Instance {
+    // where it serves the protocol it was looked up for.
+    pub addr: String,
+    pub weight: u32,
+    pub lba: String,
+    pub version: String,
+    // Some when it serves https, with the name to verify it against when
+    // that is not the one of its address.
+    pub tls: Option<Option<String>>,
+    pub metadata: HashMap<String, String>,
+}
+
+impl Instance {
+    // an instance of an upstream outside the registry, weighted 1.
+    fn upstream(addr: String, tls: bool) -> Self {
+        Self {
+            addr,
+            weight: 1,
+            tls: tls.then_some(None),
+            ..Default::default()
+        }
+    }
+}
+
+/// The instances of a service, as the load balancer picks from them.
+#[derive(Debug, Clone, Default)]
 pub struct Endpoint {
-    addr: Vec<String>,
+    instances: Vec<Instance>,
 }
 
 impl Endpoint {
-    fn get_address(&self) -> Vec<String> {
-        self.addr.clone()
+    pub fn new(instances: Vec<Instance>) -> Self {
+        Self { instances }
+    }
+
+    pub fn instances(&self) -> &[Instance] {
+        &self.instances
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.instances.is_empty()
+    }
+
+    pub fn get(&self, addr: &str) -> Option<&Instance> {
+        self.instances.iter().find(|i| i.addr == addr)
     }
 }
 
-pub async fn make_service<T>(s: T) -> T
+pub async fn make_service<T>(s: T) -> Result<T, ServiceError>
 where
     T: Service,
 {
     if let Err(e) = register::Register::default().register_web_service(&s).await {
-        panic!("register service {} error {:?}", s.name(), e);
+        s.on_registry_error(format!("{:?}", e)).await;
+        return Err(ServiceError::Register {
+            name: s.name(),
+            source: e,
+        });
     }
+    s.on_registered().await;
 
-    return s;
+    Ok(s)
 }
 
-pub async fn make_executor<'a, T>(s: &mut T) -> (&mut T, Register)
+pub async fn make_executor<'a, T>(s: &mut T) -> Result<(&mut T, Register), ServiceError>
 where
     T: Executor<'a>,
 {
     let register = register::Register::default();
-    if let Err(e) = register.register_backend_service(s).await {
-        panic!("register backend service {} error {:?}", s.group(), e);
+    // serves no triggers, so none are advertised.
+    if let Err(e) = register.register_backend_service(s, None).await {
+        return Err(ServiceError::Register {
+            name: s.group(),
+            source: e,
+        });
     }
 
-    return (s, register);
+    Ok((s, register))
 }
@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use plugin::ServiceContent;
+
+use crate::config::LookupCacheConfig;
+
+// services not looked up for this long are dropped from the cache.
+const FORGET_AFTER: Duration = Duration::from_secs(600);
+
+struct Cached {
+    contents: Vec<ServiceContent>,
+    at: Instant,
+    used: Instant,
+    refreshing: bool,
+}
+
+/// What the registry last answered for each service, read through by
+/// `Register::get_web_service`.
+#[derive(Clone, Default)]
+pub(crate) struct Lookups {
+    cached: Arc<Mutex<HashMap<String, Cached>>>,
+}
+
+impl std::fmt::Debug for Lookups {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Lookups")
+            .field("services", &self.cached.lock().unwrap().len())
+            .finish()
+    }
+}
+
+impl Lookups {
+    // the instances of `service` unless they are older than `config` lets
+    // them be, and whether the caller is to read them again: the first one
+    // to find them past their ttl is.
+    pub(crate) fn get(
+        &self,
+        config: &LookupCacheConfig,
+        service: &str,
+    ) -> Option<(Vec<ServiceContent>, bool)> {
+        if config.ttl_ms == 0 {
+            return None;
+        }
+        let now = Instant::now();
+        let mut cached = self.cached.lock().unwrap();
+        let entry = cached.get_mut(service)?;
+        let age = now.duration_since(entry.at);
+        if age > Duration::from_millis(config.ttl_ms).max(Duration::from_secs(config.stale_secs)) {
+            return None;
+        }
+        entry.used = now;
+        let refresh = age > Duration::from_millis(config.ttl_ms) && !entry.refreshing;
+        entry.refreshing |= refresh;
+        Some((entry.contents.clone(), refresh))
+    }
+
+    pub(crate) fn store(&self, service: &str, contents: Vec<ServiceContent>) {
+        let now = Instant::now();
+        let mut cached = self.cached.lock().unwrap();
+        if !cached.contains_key(service) {
+            cached.retain(|_, c| now.duration_since(c.used) < FORGET_AFTER);
+        }
+        cached.insert(
+            service.to_string(),
+            Cached {
+                contents,
+                at: now,
+                used: now,
+                refreshing: false,
+            },
+        );
+    }
+
+    // lets the next caller finding `service` stale read it again, after a
+    // refresh failed.
+    pub(crate) fn refresh_failed(&self, service: &str) {
+        if let Some(entry) = self.cached.lock().unwrap().get_mut(service) {
+            entry.refreshing = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stale_answers_are_refreshed_once() {
+        let lookups = Lookups::default();
+        let config = LookupCacheConfig {
+            ttl_ms: 50,
+            stale_secs: 60,
+        };
+        assert!(lookups.get(&config, "/t/ums").is_none());
+
+        lookups.store("/t/ums", vec![ServiceContent::default()]);
+        let (contents, refresh) = lookups.get(&config, "/t/ums").unwrap();
+        assert_eq!((contents.len(), refresh), (1, false));
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(lookups.get(&config, "/t/ums").unwrap().1);
+        // one refresh at a time.
+        assert!(!lookups.get(&config, "/t/ums").unwrap().1);
+        lookups.refresh_failed("/t/ums");
+        assert!(lookups.get(&config, "/t/ums").unwrap().1);
+
+        let off = LookupCacheConfig {
+            ttl_ms: 0,
+            ..config
+        };
+        assert!(lookups.get(&off, "/t/ums").is_none());
+    }
+}
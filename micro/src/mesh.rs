@@ -0,0 +1,99 @@
+use std::sync::RwLock;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use net::{HttpsConnector, MtlsIdentity, Phase, ReverseProxy, Shutdown};
+use once_cell::sync::Lazy;
+use tokio_rustls::rustls::ServerConfig;
+
+use crate::Register;
+
+// how soon a failed renewal is tried again.
+const RETRY: Duration = Duration::from_secs(10);
+
+// the client the gateway forwards with while it is in the mesh.
+static CLIENT: Lazy<RwLock<Option<ReverseProxy<HttpsConnector>>>> = Lazy::new(|| RwLock::new(None));
+
+/// The mesh identity of `service` from the registry, e.g. its Consul Connect
+/// leaf certificate, and when it expires.
+pub async fn mesh_identity(
+    register: &Register,
+    service: &str,
+) -> anyhow::Result<(MtlsIdentity, DateTime<Utc>)> {
+    let certs = register.plugin()?.connect_certs(service).await?;
+    let identity = MtlsIdentity::from_pem(&certs.cert, &certs.key, &certs.roots)?;
+    let valid_before = DateTime::parse_from_rfc3339(&certs.valid_before)?.with_timezone(&Utc);
+    Ok((identity, valid_before))
+}
+
+/// A server config that presents the mesh identity of `service` and only
+/// accepts peers of the same mesh, for services the gateway reaches over
+/// `registry.connect`. Built again once the leaf expires.
+pub async fn mesh_server_config(
+    register: &Register,
+    service: &str,
+) -> anyhow::Result<(ServerConfig, DateTime<Utc>)> {
+    let (identity, valid_before) = mesh_identity(register, service).await?;
+    Ok((identity.server_config()?, valid_before))
+}
+
+pub(crate) fn client() -> Option<ReverseProxy<HttpsConnector>> {
+    CLIENT.read().unwrap().clone()
+}
+
+// renewed halfway to the expiry, the agent hands out the next leaf well
+// before the current one runs out.
+fn renew_in(valid_before: DateTime<Utc>) -> Duration {
+    (valid_before - Utc::now())
+        .to_std()
+        .map_or(RETRY, |left| (left / 2).max(RETRY))
+}
+
+async fn install(register: &Register, service: &str) -> anyhow::Result<DateTime<Utc>> {
+    let (identity, valid_before) = mesh_identity(register, service).await?;
+    let client = net::mtls_proxy_client(&identity)?;
+    *CLIENT.write().unwrap() = Some(client);
+    tracing::info!(service, %valid_before, "mesh certificate installed");
+    Ok(valid_before)
+}
+
+// joins the mesh as `service` before the gateway serves, then keeps the
+// certificate renewed until it shuts down.
+pub(crate) async fn join(
+    register: &Register,
+    service: &str,
+    shutdown: &Shutdown,
+) -> anyhow::Result<()> {
+    let mut valid_before = install(register, service).await?;
+
+    let (register, service, shutdown) = (register.clone(), service.to_string(), shutdown.clone());
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(renew_in(valid_before)) => {}
+                _ = shutdown.reached(Phase::StopAccepting) => return,
+            }
+            match install(&register, &service).await {
+                Ok(renewed) => valid_before = renewed,
+                Err(e) => tracing::warn!(service, error = %e, "mesh certificate renewal failed"),
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renews_halfway_to_expiry() {
+        let in_an_hour = Utc::now() + chrono::Duration::hours(1);
+        let renew = renew_in(in_an_hour);
+        assert!(renew > Duration::from_secs(29 * 60) && renew <= Duration::from_secs(30 * 60));
+
+        // expired or about to, tried again soon.
+        assert_eq!(renew_in(Utc::now() - chrono::Duration::hours(1)), RETRY);
+        assert_eq!(renew_in(Utc::now() + chrono::Duration::seconds(4)), RETRY);
+    }
+}
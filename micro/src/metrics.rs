@@ -0,0 +1,227 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use once_cell::sync::Lazy;
+use prometheus::{core::Collector, proto};
+
+static REGISTRY: Lazy<MetricsRegistry> = Lazy::new(MetricsRegistry::default);
+
+// the global registry is gathered with the metrics of plugin and net.
+static EXPORTED: Lazy<()> = Lazy::new(|| {
+    if let Err(e) = prometheus::default_registry().register(Box::new(Exported(&REGISTRY))) {
+        tracing::error!(error = ?e, "export metrics registry failed");
+    }
+});
+
+#[derive(Debug, Clone, Default)]
+pub struct Counter(Arc<AtomicU64>);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1)
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Gauge(Arc<AtomicI64>);
+
+impl Gauge {
+    pub fn set(&self, v: i64) {
+        self.0.store(v, Ordering::Relaxed);
+    }
+
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dec(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Metric {
+    Counter(Counter),
+    Gauge(Gauge),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricValue {
+    Counter(u64),
+    Gauge(i64),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sample {
+    pub name: String,
+    pub labels: Vec<(String, String)>,
+    pub value: MetricValue,
+}
+
+type MetricKey = (String, Vec<(String, String)>);
+
+/// Process wide set of named counters and gauges. Asking twice for the same
+/// name and labels returns the same metric.
+#[derive(Debug, Default)]
+pub struct MetricsRegistry {
+    metrics: Mutex<BTreeMap<MetricKey, Metric>>,
+}
+
+impl MetricsRegistry {
+    pub fn global() -> &'static MetricsRegistry {
+        Lazy::force(&EXPORTED);
+        &REGISTRY
+    }
+
+    fn key(name: &str, labels: &[(&str, &str)]) -> MetricKey {
+        let mut labels = labels
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<Vec<_>>();
+        labels.sort();
+        (name.to_string(), labels)
+    }
+
+    // if `name` with these labels is already a gauge, the counter returned
+    // works but is not exported.
+    pub fn counter(&self, name: &str, labels: &[(&str, &str)]) -> Counter {
+        let mut metrics = self.metrics.lock().unwrap();
+        match metrics
+            .entry(Self::key(name, labels))
+            .or_insert_with(|| Metric::Counter(Counter::default()))
+        {
+            Metric::Counter(c) => c.clone(),
+            Metric::Gauge(_) => {
+                tracing::error!(metric = name, "metric is a gauge, counter not exported");
+                Counter::default()
+            }
+        }
+    }
+
+    // if `name` with these labels is already a counter, the gauge returned
+    // works but is not exported.
+    pub fn gauge(&self, name: &str, labels: &[(&str, &str)]) -> Gauge {
+        let mut metrics = self.metrics.lock().unwrap();
+        match metrics
+            .entry(Self::key(name, labels))
+            .or_insert_with(|| Metric::Gauge(Gauge::default()))
+        {
+            Metric::Gauge(g) => g.clone(),
+            Metric::Counter(_) => {
+                tracing::error!(metric = name, "metric is a counter, gauge not exported");
+                Gauge::default()
+            }
+        }
+    }
+
+    // sorted by name then labels.
+    pub fn snapshot(&self) -> Vec<Sample> {
+        self.metrics
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|((name, labels), metric)| Sample {
+                name: name.clone(),
+                labels: labels.clone(),
+                value: match metric {
+                    Metric::Counter(c) => MetricValue::Counter(c.get()),
+                    Metric::Gauge(g) => MetricValue::Gauge(g.get()),
+                },
+            })
+            .collect()
+    }
+}
+
+// the names are only known once the metrics are asked for, so this is an
+// unchecked collector without descriptors.
+struct Exported(&'static MetricsRegistry);
+
+impl Collector for Exported {
+    fn desc(&self) -> Vec<&prometheus::core::Desc> {
+        vec![]
+    }
+
+    fn collect(&self) -> Vec<proto::MetricFamily> {
+        let mut families: Vec<proto::MetricFamily> = vec![];
+        // sorted by name, so every family is one run of samples.
+        for sample in self.0.snapshot() {
+            let mut metric = proto::Metric::default();
+            let labels = sample
+                .labels
+                .iter()
+                .map(|(name, value)| {
+                    let mut label = proto::LabelPair::default();
+                    label.set_name(name.clone());
+                    label.set_value(value.clone());
+                    label
+                })
+                .collect::<Vec<_>>();
+            metric.set_label(labels);
+            let kind = match sample.value {
+                MetricValue::Counter(v) => {
+                    let mut counter = proto::Counter::default();
+                    counter.set_value(v as f64);
+                    metric.set_counter(counter);
+                    proto::MetricType::COUNTER
+                }
+                MetricValue::Gauge(v) => {
+                    let mut gauge = proto::Gauge::default();
+                    gauge.set_value(v as f64);
+                    metric.set_gauge(gauge);
+                    proto::MetricType::GAUGE
+                }
+            };
+
+            match families.last_mut() {
+                Some(family) if family.get_name() == sample.name => {
+                    family.mut_metric().push(metric)
+                }
+                _ => {
+                    let mut family = proto::MetricFamily::default();
+                    family.set_help(sample.name.clone());
+                    family.set_name(sample.name);
+                    family.set_field_type(kind);
+                    family.mut_metric().push(metric);
+                    families.push(family);
+                }
+            }
+        }
+        families
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_key_shares_the_metric() {
+        let r = MetricsRegistry::default();
+        r.counter("runs", &[("group", "a"), ("kind", "x")]).inc();
+        r.counter("runs", &[("kind", "x"), ("group", "a")]).add(2);
+        r.gauge("members", &[("group", "a")]).set(3);
+
+        let snapshot = r.snapshot();
+        assert_eq!(snapshot.len(), 2);
+        assert_eq!(snapshot[0].name, "members");
+        assert_eq!(snapshot[0].value, MetricValue::Gauge(3));
+        assert_eq!(snapshot[1].value, MetricValue::Counter(3));
+    }
+}
@@ -0,0 +1,431 @@
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use hyper::{header::CONTENT_TYPE, Body, Request};
+use net::{Phase, Shutdown};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+use crate::config::{self, FlappingConfig, NotifyConfig, Webhook};
+use crate::register::routable;
+use crate::{MetricsRegistry, Register};
+
+// a delivery is tried this often, a second longer apart each time.
+const ATTEMPTS: u32 = 3;
+
+// services the gateway has routed requests to, watched from the next poll.
+static ROUTED: Lazy<Mutex<BTreeSet<String>>> = Lazy::new(|| Mutex::new(BTreeSet::new()));
+
+// when an instance registered or expired lately, by service and address.
+#[derive(Debug, Default)]
+struct Churn {
+    at: VecDeque<Instant>,
+    flapping: bool,
+}
+
+static CHURN: Lazy<Mutex<HashMap<(String, String), Churn>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// what changed in the registry, as posted to the webhooks.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Event {
+    InstanceRegistered {
+        service: String,
+        instance: String,
+    },
+    InstanceExpired {
+        service: String,
+        instance: String,
+    },
+    InstanceFlapping {
+        service: String,
+        instance: String,
+        transitions: usize,
+        window_secs: u64,
+    },
+    // registered instances may be left, none of them routable.
+    EndpointsEmpty {
+        service: String,
+    },
+}
+
+impl Event {
+    fn text(&self) -> String {
+        match self {
+            Event::InstanceRegistered { service, instance } => {
+                format!("crossgate: {} registered instance {}", service, instance)
+            }
+            Event::InstanceExpired { service, instance } => {
+                format!("crossgate: instance {} of {} expired", instance, service)
+            }
+            Event::InstanceFlapping {
+                service,
+                instance,
+                transitions,
+                window_secs,
+            } => format!(
+                "crossgate: instance {} of {} is flapping, {} registrations and expiries in {}s",
+                instance, service, transitions, window_secs
+            ),
+            Event::EndpointsEmpty { service } => {
+                format!("crossgate: {} has no instance left to route to", service)
+            }
+        }
+    }
+
+    fn body(&self, format: &str) -> serde_json::Value {
+        match format {
+            "slack" => serde_json::json!({ "text": self.text() }),
+            _ => {
+                let mut body = serde_json::to_value(self).unwrap_or_default();
+                let timestamp = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_secs());
+                body["timestamp"] = timestamp.into();
+                body
+            }
+        }
+    }
+}
+
+// the registrations of one service at a poll.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Instances {
+    addrs: BTreeSet<String>,
+    routable: usize,
+}
+
+fn changes(service: &str, before: &Instances, after: &Instances) -> Vec<Event> {
+    let mut events = after
+        .addrs
+        .difference(&before.addrs)
+        .map(|addr| Event::InstanceRegistered {
+            service: service.to_string(),
+            instance: addr.clone(),
+        })
+        .collect::<Vec<_>>();
+    events.extend(
+        before
+            .addrs
+            .difference(&after.addrs)
+            .map(|addr| Event::InstanceExpired {
+                service: service.to_string(),
+                instance: addr.clone(),
+            }),
+    );
+    if before.routable > 0 && after.routable == 0 {
+        events.push(Event::EndpointsEmpty {
+            service: service.to_string(),
+        });
+    }
+    events
+}
+
+// counts the registrations and expiries among `events` towards the churn
+// of their instances, and tells which of those instances started flapping.
+fn flaps(config: &FlappingConfig, events: &[Event], now: Instant) -> Vec<Event> {
+    let window = Duration::from_secs(config.window_secs);
+    let kept = window.max(Duration::from_secs(config.damp_secs));
+    let mut churn = CHURN.lock().unwrap();
+    churn.retain(|_, c| c.at.back().is_some_and(|at| now.duration_since(*at) < kept));
+    let mut flaps = vec![];
+    for event in events {
+        let (service, instance, change) = match event {
+            Event::InstanceRegistered { service, instance } => (service, instance, "registered"),
+            Event::InstanceExpired { service, instance } => (service, instance, "expired"),
+            _ => continue,
+        };
+        MetricsRegistry::global()
+            .counter(
+                "crossgate_registration_changes_total",
+                &[("service", service.as_str()), ("change", change)],
+            )
+            .inc();
+        if config.transitions == 0 {
+            continue;
+        }
+        let c = churn
+            .entry((service.clone(), instance.clone()))
+            .or_default();
+        while c
+            .at
+            .front()
+            .is_some_and(|at| now.duration_since(*at) >= window)
+        {
+            c.at.pop_front();
+        }
+        c.at.push_back(now);
+        let flapping = c.at.len() >= config.transitions;
+        if flapping && !c.flapping {
+            MetricsRegistry::global()
+                .counter(
+                    "crossgate_instance_flaps_total",
+                    &[("service", service.as_str())],
+                )
+                .inc();
+            flaps.push(Event::InstanceFlapping {
+                service: service.clone(),
+                instance: instance.clone(),
+                transitions: c.at.len(),
+                window_secs: config.window_secs,
+            });
+        }
+        c.flapping = flapping;
+    }
+    flaps
+}
+
+// whether the instance at `addr` of `service` flapped and has not stayed
+// put for `gateway.notify.flapping.damp_secs` since.
+fn damped(config: &FlappingConfig, service: &str, addr: &str) -> bool {
+    let damp = Duration::from_secs(config.damp_secs);
+    CHURN
+        .lock()
+        .unwrap()
+        .get(&(service.to_string(), addr.to_string()))
+        .is_some_and(|c| c.flapping && c.at.back().is_some_and(|at| at.elapsed() < damp))
+}
+
+// `contents` of `service` without the flapping instances being damped,
+// unless none other is left.
+pub(crate) fn undamped(
+    service: &str,
+    contents: Vec<plugin::ServiceContent>,
+) -> Vec<plugin::ServiceContent> {
+    let config = &config::current().gateway.notify.flapping;
+    if config.transitions == 0 || config.damp_secs == 0 {
+        return contents;
+    }
+    let (damped, steady): (Vec<_>, Vec<_>) = contents
+        .into_iter()
+        .partition(|c| damped(config, service, &c.addr));
+    match steady.is_empty() {
+        true => damped,
+        false => steady,
+    }
+}
+
+// webhooks to notify or flapping instances to watch for.
+fn enabled(notify: &NotifyConfig) -> bool {
+    !notify.webhooks.is_empty() || notify.flapping.transitions > 0
+}
+
+pub(crate) fn routed(service: &str) {
+    if !enabled(&config::current().gateway.notify) {
+        return;
+    }
+    let mut routed = ROUTED.lock().unwrap();
+    if !routed.contains(service) {
+        routed.insert(service.to_string());
+    }
+}
+
+// the services of the routes, tls hosts and notify.services, and those
+// routed to since.
+fn watched() -> BTreeSet<String> {
+    let gateway = &config::current().gateway;
+    let mut services = ROUTED.lock().unwrap().clone();
+    services.extend(
+        gateway
+            .routes
+            .iter()
+            .filter(|r| r.upstreams.is_empty())
+            .map(|r| r.service.clone()),
+    );
+    services.extend(gateway.notify.services.iter().cloned());
+    if let Some(tls) = &gateway.tls {
+        services.extend(tls.hosts.iter().filter_map(|h| h.service.clone()));
+    }
+    services
+}
+
+async fn deliver(webhook: &Webhook, event: &Event) -> anyhow::Result<()> {
+    let req = Request::post(&webhook.url)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(event.body(&webhook.format).to_string()))?;
+    let res = match webhook.url.starts_with("https://") {
+        true => {
+            net::get_tls_proxy_client(None)
+                .client()
+                .request(req)
+                .await?
+        }
+        false => net::get_proxy_client().client().request(req).await?,
+    };
+    if !res.status().is_success() {
+        anyhow::bail!("{} answered {}", webhook.url, res.status());
+    }
+    Ok(())
+}
+
+// every webhook gets `event`, each on its own so that a slow one holds up
+// none of the others.
+fn notify(event: Event) {
+    tracing::info!(event = ?event, "registry event");
+    for webhook in &config::current().gateway.notify.webhooks {
+        let event = event.clone();
+        tokio::spawn(async move {
+            let mut attempt = 1;
+            let delivered = loop {
+                match deliver(webhook, &event).await {
+                    Ok(()) => break "ok",
+                    Err(e) if attempt < ATTEMPTS => {
+                        tracing::debug!(url = %webhook.url, error = %e, attempt, "webhook failed");
+                        tokio::time::sleep(Duration::from_secs(attempt as u64)).await;
+                        attempt += 1;
+                    }
+                    Err(e) => {
+                        tracing::warn!(url = %webhook.url, error = %e, "webhook failed");
+                        break "error";
+                    }
+                }
+            };
+            MetricsRegistry::global()
+                .counter("crossgate_notifications_total", &[("status", delivered)])
+                .inc();
+        });
+    }
+}
+
+async fn poll(register: &Register, known: &mut HashMap<String, Instances>) {
+    let plugin = match register.plugin() {
+        Ok(plugin) => plugin,
+        Err(_) => return,
+    };
+    for service in watched() {
+        let contents = match plugin.get_web_service(&service).await {
+            Ok(contents) => contents,
+            // registry failures are reported where they happen.
+            Err(_) => continue,
+        };
+        let instances = Instances {
+            addrs: contents.iter().map(|c| c.addr.clone()).collect(),
+            routable: contents.iter().filter(|c| routable(c)).count(),
+        };
+        // the first poll of a service is what it starts with.
+        if let Some(before) = known.get(&service) {
+            let changes = changes(&service, before, &instances);
+            let flaps = flaps(
+                &config::current().gateway.notify.flapping,
+                &changes,
+                Instant::now(),
+            );
+            changes.into_iter().chain(flaps).for_each(notify);
+        }
+        known.insert(service, instances);
+    }
+}
+
+// polls the watched services until `shutdown` stops accepting, when there
+// are webhooks to notify or flapping instances to watch for.
+pub(crate) fn start(register: &Register, shutdown: &Shutdown) {
+    let notify = &config::current().gateway.notify;
+    if !enabled(notify) {
+        return;
+    }
+    let interval = Duration::from_secs(notify.poll_interval_secs);
+    let (register, shutdown) = (register.clone(), shutdown.clone());
+    tokio::spawn(async move {
+        let mut known = HashMap::new();
+        loop {
+            poll(&register, &mut known).await;
+            tokio::select! {
+                _ = tokio::time::sleep(interval) => {}
+                _ = shutdown.reached(Phase::StopAccepting) => return,
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn instances(addrs: &[&str], routable: usize) -> Instances {
+        Instances {
+            addrs: addrs.iter().map(|a| a.to_string()).collect(),
+            routable,
+        }
+    }
+
+    #[test]
+    fn instance_changes_and_empty_services() {
+        let before = instances(&["10.0.0.1:80", "10.0.0.2:80"], 2);
+        let after = instances(&["10.0.0.2:80", "10.0.0.3:80"], 2);
+        assert_eq!(
+            changes("/t/ums", &before, &after),
+            [
+                Event::InstanceRegistered {
+                    service: "/t/ums".to_string(),
+                    instance: "10.0.0.3:80".to_string(),
+                },
+                Event::InstanceExpired {
+                    service: "/t/ums".to_string(),
+                    instance: "10.0.0.1:80".to_string(),
+                },
+            ]
+        );
+
+        // still registered, but unhealthy.
+        let unhealthy = instances(&["10.0.0.2:80", "10.0.0.3:80"], 0);
+        assert_eq!(
+            changes("/t/ums", &after, &unhealthy),
+            [Event::EndpointsEmpty {
+                service: "/t/ums".to_string()
+            }]
+        );
+        assert_eq!(changes("/t/ums", &unhealthy, &instances(&[], 0)).len(), 2);
+
+        let event = Event::EndpointsEmpty {
+            service: "/t/ums".to_string(),
+        };
+        assert_eq!(event.body("json")["event"], "endpoints_empty");
+        assert_eq!(
+            event.body("slack")["text"],
+            "crossgate: /t/ums has no instance left to route to"
+        );
+    }
+
+    #[test]
+    fn flapping_instances_are_reported_once_and_damped() {
+        let config = FlappingConfig {
+            transitions: 3,
+            window_secs: 60,
+            damp_secs: 120,
+        };
+        let registered = Event::InstanceRegistered {
+            service: "/t/flap".to_string(),
+            instance: "10.0.0.9:80".to_string(),
+        };
+        let expired = Event::InstanceExpired {
+            service: "/t/flap".to_string(),
+            instance: "10.0.0.9:80".to_string(),
+        };
+        let start = Instant::now();
+        let flap = |event: &Event, at| flaps(&config, std::slice::from_ref(event), at);
+        assert!(flap(&registered, start).is_empty());
+        assert!(flap(&expired, start).is_empty());
+        assert!(!damped(&config, "/t/flap", "10.0.0.9:80"));
+        assert_eq!(
+            flap(&registered, start),
+            [Event::InstanceFlapping {
+                service: "/t/flap".to_string(),
+                instance: "10.0.0.9:80".to_string(),
+                transitions: 3,
+                window_secs: 60,
+            }]
+        );
+        assert!(flap(&expired, start).is_empty());
+        assert!(damped(&config, "/t/flap", "10.0.0.9:80"));
+        assert!(!damped(&config, "/t/flap", "10.0.0.8:80"));
+
+        // calm again once the window is past.
+        let later = start + Duration::from_secs(61);
+        assert!(flap(&registered, later).is_empty());
+        assert!(
+            !CHURN.lock().unwrap()[&("/t/flap".to_string(), "10.0.0.9:80".to_string())].flapping
+        );
+    }
+}
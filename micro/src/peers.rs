@@ -0,0 +1,288 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use net::{Phase, Shutdown};
+use once_cell::sync::Lazy;
+use plugin::ServiceContent;
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::config::{self, PeerSyncConfig};
+use crate::{MetricsRegistry, Register};
+
+/// Where gateways post their syncs to each other.
+pub(crate) const PEERS_PATH: &str = "/_crossgate/peers";
+
+const SECRET_HEADER: &str = "x-crossgate-peer-secret";
+
+// the instances of a service as some gateway last read them from the
+// registry, at unix millis of its clock.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Known {
+    at: u64,
+    contents: Vec<ServiceContent>,
+}
+
+// a sync sends what changed since the last one with the peer and when the
+// sender read each service, the answer what the peer read since.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Sync {
+    digest: HashMap<String, u64>,
+    known: HashMap<String, Known>,
+}
+
+static KNOWN: Lazy<Mutex<HashMap<String, Known>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+fn same(a: &[ServiceContent], b: &[ServiceContent]) -> bool {
+    serde_json::to_string(a).ok() == serde_json::to_string(b).ok()
+}
+
+// keeps what the registry answered for `service`, for the peers.
+pub(crate) fn learned(service: &str, contents: &[ServiceContent]) {
+    if !config::current().gateway.peers.enabled {
+        return;
+    }
+    let mut known = KNOWN.lock().unwrap();
+    if known
+        .get(service)
+        .is_some_and(|k| same(&k.contents, contents))
+    {
+        return;
+    }
+    known.insert(
+        service.to_string(),
+        Known {
+            at: now_millis(),
+            contents: contents.to_vec(),
+        },
+    );
+}
+
+// the instances of `service` the gateway or its peers last read from the
+// registry, for when it is out.
+pub(crate) fn recall(service: &str) -> Option<Vec<ServiceContent>> {
+    let contents = KNOWN.lock().unwrap().get(service)?.contents.clone();
+    MetricsRegistry::global()
+        .counter("crossgate_gateway_peer_fallbacks_total", &[])
+        .inc();
+    tracing::debug!(service = %service, "registry lookup failed, routing by the peer cache");
+    Some(contents)
+}
+
+// keeps the entries of `known` read later than the gateway's own.
+fn merge(known: HashMap<String, Known>) {
+    let mut ours = KNOWN.lock().unwrap();
+    for (service, theirs) in known {
+        if ours.get(&service).is_none_or(|k| k.at < theirs.at) {
+            ours.insert(service, theirs);
+        }
+    }
+}
+
+// the entries read after `since`, and those missing from `digest` or read
+// after it when it is given.
+fn newer(since: u64, digest: Option<&HashMap<String, u64>>) -> HashMap<String, Known> {
+    KNOWN
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(service, k)| match digest {
+            Some(digest) => digest.get(*service).is_none_or(|at| k.at > *at),
+            None => k.at > since,
+        })
+        .map(|(service, k)| (service.clone(), k.clone()))
+        .collect()
+}
+
+fn digest() -> HashMap<String, u64> {
+    KNOWN
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(service, k)| (service.clone(), k.at))
+        .collect()
+}
+
+// digests, so that comparing them takes as long whatever was given.
+fn authorized(req: &Request<Body>, secret: &str) -> bool {
+    req.headers()
+        .get(SECRET_HEADER)
+        .is_some_and(|given| Sha256::digest(given.as_bytes()) == Sha256::digest(secret.as_bytes()))
+}
+
+fn empty(status: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::empty())
+        .unwrap()
+}
+
+// answers the sync a peer posted to `PEERS_PATH`.
+pub(crate) async fn answer(req: Request<Body>) -> Response<Body> {
+    let config = &config::current().gateway.peers;
+    let secret = match (config.enabled, &config.secret) {
+        (true, Some(secret)) => secret,
+        _ => return empty(StatusCode::NOT_FOUND),
+    };
+    if !authorized(&req, secret) {
+        return empty(StatusCode::FORBIDDEN);
+    }
+    if req.method() != Method::POST {
+        return empty(StatusCode::METHOD_NOT_ALLOWED);
+    }
+    let body = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(body) => body,
+        Err(_) => return empty(StatusCode::BAD_REQUEST),
+    };
+    let sync = match serde_json::from_slice::<Sync>(&body) {
+        Ok(sync) => sync,
+        Err(_) => return empty(StatusCode::BAD_REQUEST),
+    };
+    let answer = Sync {
+        digest: HashMap::new(),
+        known: newer(0, Some(&sync.digest)),
+    };
+    merge(sync.known);
+    Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_vec(&answer).unwrap_or_default()))
+        .unwrap()
+}
+
+// sends `peer` what changed since `since` and takes what it read since, the
+// latest entry sent on success.
+async fn sync(config: &PeerSyncConfig, peer: &ServiceContent, since: u64) -> anyhow::Result<u64> {
+    let known = newer(since, None);
+    let sent = known.values().map(|k| k.at).max().unwrap_or(since);
+    let body = serde_json::to_vec(&Sync {
+        digest: digest(),
+        known,
+    })?;
+    let scheme = match peer.tls {
+        true => "https",
+        false => "http",
+    };
+    let req = Request::post(format!("{}://{}{}", scheme, peer.addr, PEERS_PATH))
+        .header(CONTENT_TYPE, "application/json")
+        .header(SECRET_HEADER, config.secret.as_deref().unwrap_or_default())
+        .body(Body::from(body))?;
+    let request = async {
+        match peer.tls {
+            true => net::get_tls_proxy_client(None).client().request(req).await,
+            false => net::get_proxy_client().client().request(req).await,
+        }
+    };
+    let res = tokio::time::timeout(Duration::from_secs(config.interval_secs), request).await??;
+    if !res.status().is_success() {
+        anyhow::bail!("{} answered {}", peer.addr, res.status());
+    }
+    let answer = serde_json::from_slice::<Sync>(&hyper::body::to_bytes(res.into_body()).await?)?;
+    merge(answer.known);
+    Ok(sent)
+}
+
+// syncs with `config.fanout` of the other gateways registered under
+// `service` every `config.interval_secs`, until `shutdown` stops accepting.
+pub(crate) fn start(register: &Register, own: Vec<String>, shutdown: &Shutdown) {
+    let gateway = &config::current().gateway;
+    if !gateway.peers.enabled || gateway.self_register.service.is_empty() {
+        return;
+    }
+    let config = &gateway.peers;
+    let service = gateway.self_register.service.as_str();
+    let interval = Duration::from_secs(config.interval_secs);
+    let (register, shutdown) = (register.clone(), shutdown.clone());
+    tokio::spawn(async move {
+        // the latest entry each peer was sent, by address.
+        let mut sent = HashMap::<String, u64>::new();
+        loop {
+            let mut peers = match register.get_gateways(service).await {
+                Ok(peers) => peers,
+                Err(e) => {
+                    tracing::debug!(error = %e, "gateway peers unknown");
+                    vec![]
+                }
+            };
+            peers.retain(|peer| !own.contains(&peer.addr));
+            sent.retain(|addr, _| peers.iter().any(|peer| &peer.addr == addr));
+            let chosen = peers
+                .choose_multiple(&mut rand::thread_rng(), config.fanout)
+                .cloned()
+                .collect::<Vec<_>>();
+            for peer in chosen {
+                let since = sent.get(&peer.addr).copied().unwrap_or(0);
+                let status = match sync(config, &peer, since).await {
+                    Ok(latest) => {
+                        sent.insert(peer.addr.clone(), latest);
+                        "ok"
+                    }
+                    Err(e) => {
+                        tracing::debug!(peer = %peer.addr, error = %e, "peer sync failed");
+                        "error"
+                    }
+                };
+                MetricsRegistry::global()
+                    .counter("crossgate_gateway_peer_syncs_total", &[("status", status)])
+                    .inc();
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(interval) => {}
+                _ = shutdown.reached(Phase::StopAccepting) => return,
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn instance(addr: &str) -> ServiceContent {
+        ServiceContent {
+            service: "/t/peers".into(),
+            addr: addr.into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn newer_entries_win() {
+        let mut theirs = HashMap::new();
+        theirs.insert(
+            "/t/peers".to_string(),
+            Known {
+                at: 10,
+                contents: vec![instance("10.0.0.1:80")],
+            },
+        );
+        merge(theirs);
+        assert_eq!(recall("/t/peers").unwrap()[0].addr, "10.0.0.1:80");
+
+        let stale = HashMap::from([(
+            "/t/peers".to_string(),
+            Known {
+                at: 5,
+                contents: vec![],
+            },
+        )]);
+        merge(stale);
+        assert_eq!(recall("/t/peers").unwrap().len(), 1);
+
+        assert!(!newer(10, None).contains_key("/t/peers"));
+        assert!(newer(9, None).contains_key("/t/peers"));
+        let digest = HashMap::from([("/t/peers".to_string(), 10)]);
+        assert!(!newer(0, Some(&digest)).contains_key("/t/peers"));
+        assert!(newer(0, Some(&HashMap::new())).contains_key("/t/peers"));
+        assert!(recall("/t/none").is_none());
+    }
+}
@@ -1,4 +1,27 @@
-use crate::{Endpoint, Executor, LoadBalancerAlgorithm, Service};
+use crate::advertise::join_host_port;
+use crate::config;
+use crate::lookup::Lookups;
+use crate::{Endpoint, Executor, Instance, LoadBalancerAlgorithm, Service};
+use futures::Stream;
+use once_cell::sync::Lazy;
+use plugin::{HealthStatus, PluginError, PluginHandle, ServiceHealth};
+use rand::Rng;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+// re-read the members this often even without a watch event, for plugins
+// that have no watch support.
+const MEMBERSHIP_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MembershipChange {
+    Joined(String),
+    Left(String),
+}
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -9,136 +32,609 @@ pub enum RegisterError {
     ServiceError(String),
 }
 
-static REGISTER: Register = Register {};
+// instances that failed their liveness check or are shutting down get no
+// traffic from the gateway.
+pub(crate) fn routable(content: &plugin::ServiceContent) -> bool {
+    !content
+        .health
+        .as_ref()
+        .is_some_and(|h| h.status == HealthStatus::Unhealthy || h.status == HealthStatus::Draining)
+}
+
+// the protocol served on `ServiceContent::addr`.
+pub(crate) const DEFAULT_PROTOCOL: &str = "http";
+
+// where an instance serves `protocol`, None if it does not.
+fn address_for(content: &plugin::ServiceContent, protocol: &str) -> Option<String> {
+    match content.endpoints.iter().find(|e| e.protocol == protocol) {
+        Some(e) => Some(e.addr.clone()),
+        None if protocol == DEFAULT_PROTOCOL && content.http => Some(content.addr.clone()),
+        None => None,
+    }
+}
 
-#[derive(Copy, Clone)]
-pub struct Register;
+// the instance `content` registers for `protocol`, None if it does not
+// serve it.
+fn instance_of(content: &plugin::ServiceContent, protocol: &str) -> Option<Instance> {
+    let addr = address_for(content, protocol)?;
+    // `tls` is about the listener on `addr`, the other endpoints are plain.
+    let tls = content.tls && addr == content.addr;
+    Some(Instance {
+        addr,
+        weight: content.weight,
+        lba: content.lba.clone(),
+        version: content.version.clone(),
+        tls: tls.then(|| Some(content.sni.clone()).filter(|sni| !sni.is_empty())),
+        metadata: content.metadata.clone(),
+    })
+}
+
+// the instances of a route with fixed upstreams, like those of a service
+// in the registry; upstreams that are not urls are left out.
+pub(crate) fn static_endpoint(upstreams: &[String]) -> Endpoint {
+    let mut instances = vec![];
+    for upstream in upstreams {
+        let uri = match upstream.parse::<hyper::Uri>() {
+            Ok(uri) => uri,
+            Err(_) => continue,
+        };
+        let (host, tls) = match (uri.host(), uri.scheme_str()) {
+            (Some(host), Some("https")) => (host, true),
+            (Some(host), Some("http")) => (host, false),
+            _ => continue,
+        };
+        let port = uri.port_u16().unwrap_or(if tls { 443 } else { 80 });
+        let addr = join_host_port(host.trim_start_matches('[').trim_end_matches(']'), port);
+        // verified against the host of the address.
+        instances.push(Instance::upstream(addr, tls));
+    }
+    Endpoint::new(instances)
+}
+
+// the port of a listen address, which may name its host, e.g.
+// `localhost:8080`.
+async fn listen_port(addr: &str) -> Option<u16> {
+    tokio::net::lookup_host(addr)
+        .await
+        .ok()?
+        .next()
+        .map(|addr| addr.port())
+}
+
+// shared by the default registers, like the plugin they work on.
+static DRAINING: Lazy<Arc<AtomicBool>> = Lazy::new(|| Arc::new(AtomicBool::new(false)));
+static HEALTH: Lazy<Arc<Mutex<HashMap<String, ServiceHealth>>>> = Lazy::new(Default::default);
+static LOOKUPS: Lazy<Lookups> = Lazy::new(Lookups::default);
+
+/// The registry as services see it. `Register::default()` works on the
+/// process-wide plugin of `plugin::init_plugin`, `Register::new` on a
+/// handle of its own.
+#[derive(Debug, Clone)]
+pub struct Register {
+    plugin: Option<PluginHandle>,
+    draining: Arc<AtomicBool>,
+    // last health reported per registration, published again once
+    // draining is turned off.
+    health: Arc<Mutex<HashMap<String, ServiceHealth>>>,
+    lookups: Lookups,
+}
 
 impl Default for Register {
     fn default() -> Self {
-        REGISTER.clone()
+        Self {
+            plugin: None,
+            draining: DRAINING.clone(),
+            health: HEALTH.clone(),
+            lookups: LOOKUPS.clone(),
+        }
     }
 }
 
 impl Register {
-    pub(crate) async fn register_web_service(&self, service: &dyn Service) -> anyhow::Result<()> {
-        let lba = service.lab().to_string();
+    pub fn new(plugin: PluginHandle) -> Self {
+        Self {
+            plugin: Some(plugin),
+            draining: Arc::new(AtomicBool::new(false)),
+            health: Default::default(),
+            lookups: Lookups::default(),
+        }
+    }
 
-        dotenv::dotenv().ok();
+    pub fn plugin(&self) -> Result<&PluginHandle, PluginError> {
+        match &self.plugin {
+            Some(plugin) => Ok(plugin),
+            None => plugin::default_plugin(),
+        }
+    }
 
-        let mut addr = format!(
-            "{}:{}",
-            local_ip_address::local_ip()?,
-            service.addr().port()
-        );
+    // what `service` registers under each of its names.
+    async fn web_contents(
+        &self,
+        service: &dyn Service,
+    ) -> anyhow::Result<Vec<plugin::ServiceContent>> {
+        let advertised =
+            join_host_port(&service.advertise().resolve().await?, service.addr().port());
+        let tls = service.tls();
 
-        let strict_address = ::std::env::var("STRICT").unwrap_or("".to_string());
+        let mut contents = vec![];
+        for name in service.names() {
+            let lba = name.lba.unwrap_or_else(|| service.lab());
 
-        if !strict_address.is_empty() {
-            addr = strict_address
-        }
+            // a strict name is registered under its pinned address.
+            let addr = match &lba {
+                LoadBalancerAlgorithm::Strict(strict) if !strict.is_empty() => strict.clone(),
+                _ => advertised.clone(),
+            };
 
-        log::info!(
-            "registry web service is {} ip {} lba {}",
-            service.name(),
-            addr,
-            service.lab()
-        );
+            // the other endpoints live on the same host as `addr`.
+            let host = addr
+                .rsplit_once(':')
+                .map_or(addr.as_str(), |(host, _)| host);
+            let endpoints = service
+                .endpoints()
+                .into_iter()
+                .map(|e| plugin::NamedEndpoint {
+                    name: e.name,
+                    protocol: e.protocol,
+                    addr: format!("{}:{}", host, e.addr.port()),
+                })
+                .collect::<Vec<_>>();
 
-        for name in service.name().split(',').collect::<Vec<&str>>() {
-            let content = plugin::ServiceContent {
-                service: name.to_string(),
-                lba: lba.clone(),
+            contents.push(plugin::ServiceContent {
+                service: name.name.clone(),
+                lba: lba.to_string(),
                 addr: addr.clone(),
                 r#type: 1,
-            };
+                weight: name.weight,
+                version: name.version,
+                metadata: name.metadata,
+                endpoints,
+                tls: tls.is_some(),
+                sni: tls
+                    .as_ref()
+                    .and_then(|tls| tls.sni.clone())
+                    .unwrap_or_default(),
+                http: service.serves_http(),
+                ..Default::default()
+            });
+        }
+        Ok(contents)
+    }
+
+    pub(crate) async fn register_web_service(&self, service: &dyn Service) -> anyhow::Result<()> {
+        for content in self.web_contents(service).await? {
+            tracing::info!(
+                service = %content.service,
+                addr = %content.addr,
+                lba = %content.lba,
+                version = %content.version,
+                "register web service"
+            );
 
-            plugin::register_service(name, content)
+            // carries the workload identity and the registration signature,
+            // when there are.
+            #[cfg(feature = "spiffe")]
+            let content = crate::spiffe::signed(content)?;
+            let content = crate::signing::signed(content)?;
+
+            self.plugin()?
+                .register_service(&content.service.clone(), content)
                 .await
                 .map_err(|e| RegisterError::RegisterError(e.to_string()))?;
         }
         Ok(())
     }
 
+    // signs the registrations of `service` again with the current SVID, once
+    // the previous one is rotated out.
+    #[cfg(feature = "spiffe")]
+    pub(crate) async fn refresh_identity(&self, service: &dyn Service) -> anyhow::Result<()> {
+        for content in self.web_contents(service).await? {
+            if let Some(identity) = crate::spiffe::identity(&content)? {
+                self.set_metadata(&content.service, identity).await?;
+            }
+        }
+        Ok(())
+    }
+
+    // `trigger_addr` is where the triggers of `service` are served, once
+    // bound.
     pub(crate) async fn register_backend_service<'a>(
         &self,
         service: &mut dyn Executor<'a>,
+        trigger_addr: Option<SocketAddr>,
     ) -> anyhow::Result<()> {
+        // backend services have no address unless they serve triggers.
+        let addr = match trigger_addr {
+            Some(addr) => format!("{}:{}", local_ip_address::local_ip()?, addr.port()),
+            None => "".to_string(),
+        };
+
         let content = plugin::ServiceContent {
             service: service.group(),
+            addr,
             r#type: 2,
+            weight: service.weight(),
             ..Default::default()
         };
 
-        plugin::register_service(&service.group(), content)
+        self.plugin()?
+            .register_service(&service.group(), content)
             .await
             .map_err(|e| RegisterError::RegisterError(e.to_string()))?;
 
         Ok(())
     }
 
+    // registers this gateway under `config.service`, once per listen address,
+    // the plugin renews and withdraws the registrations. The addresses
+    // registered.
+    pub(crate) async fn register_gateway(
+        &self,
+        config: &crate::config::SelfRegisterConfig,
+        listen: &[String],
+        tls: bool,
+    ) -> anyhow::Result<Vec<String>> {
+        let host = match &config.advertise {
+            Some(host) => host.clone(),
+            None => local_ip_address::local_ip()?.to_string(),
+        };
+        let mut registered = vec![];
+        for addr in listen {
+            let port = listen_port(addr)
+                .await
+                .ok_or_else(|| RegisterError::RegisterError(addr.clone()))?;
+            let content = plugin::ServiceContent {
+                service: config.service.clone(),
+                addr: join_host_port(&host, port),
+                r#type: 3,
+                metadata: config.metadata.clone(),
+                tls,
+                ..Default::default()
+            };
+            tracing::info!(service = %content.service, addr = %content.addr, "register gateway");
+
+            registered.push(content.addr.clone());
+            self.plugin()?
+                .register_service(&config.service, content)
+                .await
+                .map_err(|e| RegisterError::RegisterError(e.to_string()))?;
+        }
+        Ok(registered)
+    }
+
+    /// The live gateway instances registered under `service`, e.g. for a
+    /// peer or a DNS controller to find the fleet.
+    pub async fn get_gateways(&self, service: &str) -> anyhow::Result<Vec<plugin::ServiceContent>> {
+        self.plugin()?.get_gateways(service).await
+    }
+
     pub async fn get_backend_service(&self, name: &str) -> anyhow::Result<(String, Vec<String>)> {
-        let (id, mut ids) = plugin::get_backend_service(name)
+        let (id, mut ids) = self
+            .plugin()?
+            .get_backend_service(name)
             .await
             .map_err(|_| RegisterError::ServiceError("service not found ".to_string()))?;
         ids.sort();
         Ok((id, ids.to_owned()))
     }
 
+    // the current members are reported as `Joined` first, then every change.
+    pub fn watch_backend_members(
+        &self,
+        group: &str,
+    ) -> impl Stream<Item = MembershipChange> + Send + 'static {
+        struct State {
+            register: Register,
+            group: String,
+            changes: broadcast::Receiver<Option<String>>,
+            members: BTreeSet<String>,
+            pending: VecDeque<MembershipChange>,
+            first: bool,
+        }
+
+        let state = State {
+            register: self.clone(),
+            group: group.to_string(),
+            changes: match self.plugin() {
+                Ok(plugin) => plugin.watch_backend_changes(),
+                Err(_) => plugin::watch_backend_changes(),
+            },
+            members: BTreeSet::new(),
+            pending: VecDeque::new(),
+            first: true,
+        };
+
+        futures::stream::unfold(state, |mut s| async move {
+            loop {
+                if let Some(change) = s.pending.pop_front() {
+                    return Some((change, s));
+                }
+
+                if !s.first {
+                    let changed = tokio::select! {
+                        changed = s.changes.recv() => match changed {
+                            Ok(service) => service.is_none_or(|service| service == s.group),
+                            Err(RecvError::Lagged(_)) => true,
+                            // the plugin is gone, polling still works
+                            Err(RecvError::Closed) => {
+                                tokio::time::sleep(MEMBERSHIP_POLL_INTERVAL).await;
+                                true
+                            }
+                        },
+                        _ = tokio::time::sleep(MEMBERSHIP_POLL_INTERVAL) => true,
+                    };
+                    if !changed {
+                        continue;
+                    }
+                }
+                s.first = false;
+
+                let members = match s.register.get_backend_service(&s.group).await {
+                    Ok((_, members)) => members.into_iter().collect::<BTreeSet<_>>(),
+                    Err(e) => {
+                        tracing::warn!(group = %s.group, error = ?e, "watch backend members failed");
+                        continue;
+                    }
+                };
+
+                s.pending.extend(
+                    members
+                        .difference(&s.members)
+                        .map(|id| MembershipChange::Joined(id.clone())),
+                );
+                s.pending.extend(
+                    s.members
+                        .difference(&members)
+                        .map(|id| MembershipChange::Left(id.clone())),
+                );
+                s.members = members;
+            }
+        })
+    }
+
+    // registered weight of every member, by instance id. Draining members
+    // weigh 0 so that their work moves to the others.
+    pub async fn get_backend_weights(&self, group: &str) -> anyhow::Result<HashMap<String, u32>> {
+        Ok(self
+            .plugin()?
+            .list_backend_service(group)
+            .await
+            .map_err(|_| RegisterError::ServiceError("service not found ".to_string()))?
+            .into_iter()
+            .map(|(id, content)| {
+                let draining = content
+                    .health
+                    .is_some_and(|h| h.status == HealthStatus::Draining);
+                (id, if draining { 0 } else { content.weight })
+            })
+            .collect())
+    }
+
+    // address of a random member of `group` serving triggers.
+    pub(crate) async fn get_backend_trigger(&self, group: &str) -> anyhow::Result<Option<String>> {
+        let addrs = self
+            .plugin()?
+            .list_backend_service(group)
+            .await
+            .map_err(|_| RegisterError::ServiceError("service not found ".to_string()))?
+            .into_iter()
+            .filter(|(_, content)| {
+                !content.addr.is_empty()
+                    && !content
+                        .health
+                        .as_ref()
+                        .is_some_and(|h| h.status == HealthStatus::Draining)
+            })
+            .map(|(_, content)| content.addr)
+            .collect::<Vec<_>>();
+
+        if addrs.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(
+            addrs[rand::thread_rng().gen_range(0..addrs.len())].clone(),
+        ))
+    }
+
+    /// Changes the weight this instance is registered with under `name`, e.g.
+    /// lowered under local resource pressure. Published by the next renewal,
+    /// the gateway rebalances within a heartbeat or two.
+    pub async fn set_weight(&self, name: &str, weight: u32) -> anyhow::Result<()> {
+        self.plugin()?
+            .set_weight(name, weight)
+            .await
+            .map_err(|e| RegisterError::RegisterError(e.to_string()))?;
+        Ok(())
+    }
+
+    /// Merges `metadata` into the registrations of this instance under
+    /// `name` and publishes them at once.
+    pub async fn set_metadata(
+        &self,
+        name: &str,
+        metadata: HashMap<String, String>,
+    ) -> anyhow::Result<()> {
+        self.plugin()?
+            .set_metadata(name, metadata)
+            .await
+            .map_err(|e| RegisterError::RegisterError(e.to_string()))?;
+        Ok(())
+    }
+
+    // flips every registration of this process to draining, so that the
+    // gateway and executor peers stop sending it new work while it finishes
+    // what it has, e.g. during a rolling restart. `false` flips it back to
+    // the health last reported for each registration.
+    pub async fn set_draining(&self, draining: bool) -> anyhow::Result<()> {
+        self.draining.store(draining, Ordering::SeqCst);
+
+        for key in self.plugin()?.registered_services() {
+            let health = match draining {
+                true => ServiceHealth {
+                    status: HealthStatus::Draining,
+                    lag: 0,
+                    last_success: 0,
+                    detail: "draining".into(),
+                },
+                false => self
+                    .health
+                    .lock()
+                    .unwrap()
+                    .get(&key)
+                    .cloned()
+                    .unwrap_or(ServiceHealth {
+                        status: HealthStatus::Healthy,
+                        lag: 0,
+                        last_success: 0,
+                        detail: "".into(),
+                    }),
+            };
+            self.publish_health(&key, health).await?;
+        }
+        Ok(())
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    // publish the health of this instance alongside its `group` registration.
+    // while draining it is only remembered, unless it is a draining notice
+    // itself.
+    pub async fn report_health(&self, group: &str, health: ServiceHealth) -> anyhow::Result<()> {
+        self.health
+            .lock()
+            .unwrap()
+            .insert(group.to_string(), health.clone());
+        if self.is_draining() && health.status != HealthStatus::Draining {
+            return Ok(());
+        }
+        self.publish_health(group, health).await
+    }
+
+    async fn publish_health(&self, group: &str, health: ServiceHealth) -> anyhow::Result<()> {
+        self.plugin()?
+            .report_health(group, health)
+            .await
+            .map_err(|e| RegisterError::RegisterError(e.to_string()))?;
+        Ok(())
+    }
+
+    // (instance id, last reported health) of every member, sorted by id.
+    pub async fn get_backend_health(
+        &self,
+        group: &str,
+    ) -> anyhow::Result<Vec<(String, Option<ServiceHealth>)>> {
+        let mut members = self
+            .plugin()?
+            .list_backend_service(group)
+            .await
+            .map_err(|_| RegisterError::ServiceError("service not found ".to_string()))?
+            .into_iter()
+            .map(|(id, content)| (id, content.health))
+            .collect::<Vec<_>>();
+        members.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(members)
+    }
+
     pub(crate) async fn get_web_service_by_lba<'a>(
         &'a self,
         name: &'a str,
         lba: LoadBalancerAlgorithm,
+        protocol: &'a str,
     ) -> anyhow::Result<(crate::LoadBalancerAlgorithm, Endpoint)> {
-        let contents = plugin::get_web_service(name)
+        let contents = self
+            .plugin()?
+            .get_web_service(name)
             .await
-            .map_err(|_| RegisterError::ServiceError("service not found ".to_string()))?;
+            .map_err(|_| RegisterError::ServiceError("service not found ".to_string()))?
+            .into_iter()
+            .filter(routable)
+            .collect::<Vec<_>>();
 
-        let mut filter_contents = vec![];
+        // only the instances registered for `lba`.
+        let registered_for = |c: &plugin::ServiceContent| match &lba {
+            crate::LoadBalancerAlgorithm::RoundRobin => c.lba == "RoundRobin",
+            crate::LoadBalancerAlgorithm::Random => c.lba == "Random",
+            crate::LoadBalancerAlgorithm::Strict(v) => c.lba == "Strict" && &c.addr == v,
+            crate::LoadBalancerAlgorithm::ConsistentHash(_) => true,
+        };
+        let instances = contents
+            .iter()
+            .filter(|c| registered_for(c))
+            .filter_map(|c| instance_of(c, protocol))
+            .collect::<Vec<_>>();
 
-        match lba.clone() {
-            crate::LoadBalancerAlgorithm::RoundRobin => {
-                filter_contents.extend(
-                    contents
-                        .iter()
-                        .filter(|item| item.lba == "RoundRobin")
-                        .collect::<Vec<&plugin::ServiceContent>>(),
-                );
-            }
-            crate::LoadBalancerAlgorithm::Random => {
-                filter_contents.extend(
-                    contents
-                        .iter()
-                        .filter(|item| item.lba == "Random")
-                        .collect::<Vec<&plugin::ServiceContent>>(),
-                );
-            }
-            crate::LoadBalancerAlgorithm::Strict(v) => {
-                filter_contents.extend(
-                    contents
-                        .iter()
-                        .filter(|item| item.lba == "Strict" && item.addr == v)
-                        .collect::<Vec<&plugin::ServiceContent>>(),
-                );
+        // strict names the instance by its main address, pin the one of the
+        // requested protocol instead.
+        let lba = match (lba, instances.first()) {
+            (crate::LoadBalancerAlgorithm::Strict(_), Some(instance)) => {
+                crate::LoadBalancerAlgorithm::Strict(instance.addr.clone())
             }
+            (lba, _) => lba,
         };
 
-        Ok((
-            lba,
-            crate::Endpoint {
-                addr: filter_contents.iter().map(|c| c.addr.clone()).collect(),
-            },
-        ))
+        Ok((lba, Endpoint::new(instances)))
+    }
+
+    // the instances of `name` as the registry has them now, cached for the
+    // next lookups.
+    async fn read_web_service(&self, name: &str) -> anyhow::Result<Vec<plugin::ServiceContent>> {
+        let contents = self.plugin()?.get_web_service(name).await?;
+        crate::peers::learned(name, &contents);
+        self.lookups.store(name, contents.clone());
+        Ok(contents)
     }
 
     pub(crate) async fn get_web_service(
         &self,
         name: &str,
+        protocol: &str,
     ) -> anyhow::Result<(LoadBalancerAlgorithm, Endpoint)> {
-        if let Ok(contents) = plugin::get_web_service(name).await {
-            let addrs = contents
+        let contents = match self.lookups.get(&config::current().registry.cache, name) {
+            Some((contents, refresh)) => {
+                if refresh {
+                    let (register, name) = (self.clone(), name.to_string());
+                    tokio::spawn(async move {
+                        if register.read_web_service(&name).await.is_err() {
+                            register.lookups.refresh_failed(&name);
+                        }
+                    });
+                }
+                Some(contents)
+            }
+            None => match self.read_web_service(name).await {
+                Ok(contents) => Some(contents),
+                // the registry is out, route by what the gateway peers read
+                // last.
+                Err(_) => crate::peers::recall(name),
+            },
+        };
+        if let Some(contents) = contents {
+            let contents = contents
+                .into_iter()
+                .filter(|c| {
+                    routable(c)
+                        && crate::color::serves(name, c)
+                        && address_for(c, protocol).is_some()
+                })
+                .collect::<Vec<_>>();
+            // only the identities allowed for `name`, when it has a list.
+            #[cfg(feature = "spiffe")]
+            let contents = contents
+                .into_iter()
+                .filter(|c| crate::spiffe::permits(name, c))
+                .collect::<Vec<_>>();
+            // only those signed with the key of `name`, when it has one.
+            let contents = contents
+                .into_iter()
+                .filter(|c| crate::signing::permits(name, c))
+                .collect::<Vec<_>>();
+            let contents = crate::notify::undamped(name, contents);
+            let instances = contents
                 .iter()
-                .map(|c: &plugin::ServiceContent| c.addr.clone())
+                .filter_map(|c| instance_of(c, protocol))
                 .collect();
             let mut lba = "".to_string();
 
@@ -150,7 +646,7 @@ impl Register {
 
             return Ok((
                 crate::LoadBalancerAlgorithm::from(lba),
-                crate::Endpoint { addr: addrs },
+                Endpoint::new(instances),
             ));
         }
 
@@ -159,3 +655,193 @@ impl Register {
         )))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn address_by_protocol() {
+        let content = plugin::ServiceContent {
+            addr: "10.0.0.1:8080".into(),
+            endpoints: vec![plugin::NamedEndpoint {
+                name: "api".into(),
+                protocol: "grpc".into(),
+                addr: "10.0.0.1:9090".into(),
+            }],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            address_for(&content, DEFAULT_PROTOCOL).as_deref(),
+            Some("10.0.0.1:8080")
+        );
+        assert_eq!(
+            address_for(&content, "grpc").as_deref(),
+            Some("10.0.0.1:9090")
+        );
+        assert_eq!(address_for(&content, "metrics"), None);
+
+        // an rpc server is found by rpc clients only.
+        let rpc = plugin::ServiceContent {
+            addr: "10.0.0.1:7000".into(),
+            endpoints: vec![plugin::NamedEndpoint {
+                name: "rpc".into(),
+                protocol: "rpc".into(),
+                addr: "10.0.0.1:7000".into(),
+            }],
+            http: false,
+            ..Default::default()
+        };
+        assert_eq!(address_for(&rpc, DEFAULT_PROTOCOL), None);
+        assert_eq!(address_for(&rpc, "rpc").as_deref(), Some("10.0.0.1:7000"));
+    }
+
+    #[test]
+    fn tls_only_on_the_registered_address() {
+        let content = plugin::ServiceContent {
+            addr: "10.0.0.1:8443".into(),
+            tls: true,
+            sni: "billing.internal".into(),
+            endpoints: vec![
+                plugin::NamedEndpoint {
+                    name: "api".into(),
+                    protocol: "grpc".into(),
+                    addr: "10.0.0.1:9090".into(),
+                },
+                plugin::NamedEndpoint {
+                    name: "metrics".into(),
+                    protocol: "metrics".into(),
+                    addr: "10.0.0.1:9100".into(),
+                },
+            ],
+            ..Default::default()
+        };
+
+        let http = instance_of(&content, DEFAULT_PROTOCOL).unwrap();
+        assert_eq!(http.addr, "10.0.0.1:8443");
+        assert_eq!(http.tls, Some(Some("billing.internal".to_string())));
+        for protocol in ["grpc", "metrics"] {
+            let instance = instance_of(&content, protocol).unwrap();
+            assert_ne!(instance.addr, content.addr);
+            assert_eq!(instance.tls, None);
+        }
+    }
+
+    #[tokio::test]
+    async fn listen_ports() {
+        assert_eq!(listen_port("0.0.0.0:8080").await, Some(8080));
+        assert_eq!(listen_port("[::]:8443").await, Some(8443));
+        assert_eq!(listen_port("localhost:9000").await, Some(9000));
+        assert_eq!(listen_port("8080").await, None);
+    }
+
+    #[test]
+    fn static_upstreams() {
+        let endpoint = static_endpoint(&[
+            "http://10.0.0.7:8080".to_string(),
+            "https://billing.example.org".to_string(),
+            "http://[::1]".to_string(),
+            "billing".to_string(),
+        ]);
+        assert_eq!(
+            endpoint
+                .instances()
+                .iter()
+                .map(|i| i.addr.as_str())
+                .collect::<Vec<_>>(),
+            ["10.0.0.7:8080", "billing.example.org:443", "[::1]:80"]
+        );
+        assert_eq!(
+            endpoint.get("billing.example.org:443").unwrap().tls,
+            Some(None)
+        );
+        assert_eq!(endpoint.get("10.0.0.7:8080").unwrap().tls, None);
+    }
+
+    // remembers the health reported for each registration.
+    struct HealthPlugin(Arc<Mutex<HashMap<String, HealthStatus>>>);
+
+    #[plugin::async_trait]
+    impl plugin::Synchronize for HealthPlugin {
+        async fn gateway_service_handle(&mut self, _shutdown: plugin::Shutdown) {}
+        async fn backend_service_handle(&mut self, _shutdown: plugin::Shutdown) {}
+        async fn web_service_handle(&mut self, _shutdown: plugin::Shutdown) {}
+    }
+
+    #[plugin::async_trait]
+    impl plugin::Plugin for HealthPlugin {
+        async fn register_service(
+            &self,
+            _key: &str,
+            _sc: plugin::ServiceContent,
+        ) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        async fn get_web_service(&self, _key: &str) -> anyhow::Result<Vec<plugin::ServiceContent>> {
+            Ok(vec![])
+        }
+
+        async fn get_backend_service(&self, _key: &str) -> anyhow::Result<(String, Vec<String>)> {
+            Ok((String::new(), vec![]))
+        }
+
+        async fn report_health(&self, key: &str, health: ServiceHealth) -> anyhow::Result<()> {
+            self.0
+                .lock()
+                .unwrap()
+                .insert(key.to_string(), health.status);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn undraining_restores_the_last_health() {
+        let reported = Arc::new(Mutex::new(HashMap::new()));
+        let plugin_reported = reported.clone();
+        plugin::register_plugin_factory("health", move |_, _| {
+            let reported = plugin_reported.clone();
+            async move { Ok(HealthPlugin(reported)) }
+        })
+        .unwrap();
+        let handle = PluginHandle::new(
+            plugin::Shutdown::new(),
+            plugin::ServiceType::WebService,
+            plugin::PluginConfig::new(plugin::get_plugin_type("health"), ""),
+        )
+        .await
+        .unwrap();
+        for key in ["/live", "/dead"] {
+            handle
+                .register_service(key, plugin::ServiceContent::default())
+                .await
+                .unwrap();
+        }
+        let r = Register::new(handle);
+        let health = |status| ServiceHealth {
+            status,
+            lag: 0,
+            last_success: 0,
+            detail: "".into(),
+        };
+        let status = |key: &str| reported.lock().unwrap().get(key).cloned();
+
+        r.report_health("/dead", health(HealthStatus::Unhealthy))
+            .await
+            .unwrap();
+        r.set_draining(true).await.unwrap();
+        assert_eq!(status("/live"), Some(HealthStatus::Draining));
+        assert_eq!(status("/dead"), Some(HealthStatus::Draining));
+
+        // held back while draining, published once it is turned off.
+        r.report_health("/live", health(HealthStatus::Healthy))
+            .await
+            .unwrap();
+        assert_eq!(status("/live"), Some(HealthStatus::Draining));
+
+        r.set_draining(false).await.unwrap();
+        assert_eq!(status("/live"), Some(HealthStatus::Healthy));
+        assert_eq!(status("/dead"), Some(HealthStatus::Unhealthy));
+    }
+}
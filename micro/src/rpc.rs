@@ -0,0 +1,468 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use net::{Connection, ConnectionError, MsgPackFrame, MuxClient, MuxHandler, MuxService};
+use once_cell::sync::Lazy;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::{config, Register, Service, ServiceEndpoint, ServiceError};
+
+/// The protocol rpc servers publish their endpoint under.
+pub const RPC_PROTOCOL: &str = "rpc";
+
+const DEFAULT_RETRIES: u32 = 2;
+const DEFAULT_RETRY_BACKOFF: Duration = Duration::from_millis(50);
+
+// the connections open to each instance, by its address, shared by every
+// client of the process.
+static CONNECTIONS: Lazy<Mutex<HashMap<String, MuxClient<Wire>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// a call or its answer, with the request or response encoded as msgpack.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Message {
+    method: String,
+    #[serde(with = "serde_bytes")]
+    body: Vec<u8>,
+    // set instead of the body when the call failed.
+    error: Option<String>,
+}
+
+type Wire = MsgPackFrame<Message>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum TransportError {
+    #[error("transport error: {0}")]
+    Other(String),
+    #[error("resolve {service} failed: {source:#}")]
+    Resolve {
+        service: String,
+        #[source]
+        source: anyhow::Error,
+    },
+    #[error("{0} has no instance serving rpc")]
+    NoInstance(String),
+    #[error("connect {addr} failed: {source}")]
+    Connect {
+        addr: String,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("call to {addr} failed: {source}")]
+    Call {
+        addr: String,
+        #[source]
+        source: ConnectionError,
+    },
+    #[error("{addr} did not answer within {timeout:?}")]
+    Timeout { addr: String, timeout: Duration },
+    #[error("encode failed: {0}")]
+    Encode(String),
+    #[error("decode failed: {0}")]
+    Decode(String),
+    // the handler of the peer failed, or it has none for the method.
+    #[error("{method} failed: {message}")]
+    Failed { method: String, message: String },
+}
+
+/// A request a service answers, with the method it is sent to and the
+/// response it gets.
+///
+/// ```ignore
+/// #[derive(Serialize, Deserialize)]
+/// struct GetUser { id: u64 }
+///
+/// impl micro::Rpc for GetUser {
+///     const METHOD: &'static str = "users.get";
+///     type Response = User;
+/// }
+/// ```
+pub trait Rpc: Serialize + DeserializeOwned + Send + Sync + 'static {
+    const METHOD: &'static str;
+    type Response: Serialize + DeserializeOwned + Send + 'static;
+}
+
+fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, TransportError> {
+    rmp_serde::to_vec_named(value).map_err(|e| TransportError::Encode(e.to_string()))
+}
+
+fn decode<T: DeserializeOwned>(body: &[u8]) -> Result<T, TransportError> {
+    rmp_serde::from_slice(body).map_err(|e| TransportError::Decode(e.to_string()))
+}
+
+/// Carries encoded calls to a service.
+pub trait Transport: Send + Sync {
+    // sends `body`, an encoded request of `method`, and answers with the
+    // encoded response.
+    fn send<'a>(
+        &'a self,
+        method: &'a str,
+        body: Vec<u8>,
+    ) -> BoxFuture<'a, Result<Vec<u8>, TransportError>>;
+
+    fn call<'a, R: Rpc>(&'a self, request: &R) -> BoxFuture<'a, Result<R::Response, TransportError>>
+    where
+        Self: Sized,
+    {
+        let body = encode(request);
+        Box::pin(async move { decode(&self.send(R::METHOD, body?).await?) })
+    }
+}
+
+type Method = Arc<dyn Fn(Vec<u8>) -> BoxFuture<'static, Result<Vec<u8>, String>> + Send + Sync>;
+
+#[derive(Clone)]
+struct Methods(Arc<HashMap<String, Method>>);
+
+impl MuxService<Wire> for Methods {
+    type Future = BoxFuture<'static, Result<Wire, ConnectionError>>;
+
+    fn call(&self, request: Wire) -> Self::Future {
+        let request = request.into_inner().unwrap_or_default();
+        let method = self.0.get(&request.method).cloned();
+        Box::pin(async move {
+            let answered = match method {
+                Some(method) => method(request.body).await,
+                None => Err(format!("no method {}", request.method)),
+            };
+            let message = match answered {
+                Ok(body) => Message {
+                    body,
+                    ..Default::default()
+                },
+                Err(error) => Message {
+                    error: Some(error),
+                    ..Default::default()
+                },
+            };
+            Ok(MsgPackFrame::new(message))
+        })
+    }
+}
+
+/// Serves the `Rpc` requests of a group on `addr`, registered like a web
+/// service under the group with an endpoint for `RPC_PROTOCOL`.
+///
+/// ```ignore
+/// micro::RpcServer::new("ums", "0.0.0.0:9100".parse()?)
+///     .method(|req: GetUser| async move { users.get(req.id).await })
+///     .run()
+///     .await?;
+/// ```
+pub struct RpcServer {
+    group: String,
+    addr: SocketAddr,
+    methods: HashMap<String, Method>,
+}
+
+impl RpcServer {
+    pub fn new(group: &str, addr: SocketAddr) -> Self {
+        Self {
+            group: group.to_string(),
+            addr,
+            methods: HashMap::new(),
+        }
+    }
+
+    // answers `R` with `handler`, an error is sent back to the caller.
+    pub fn method<R, F, Fut>(mut self, handler: F) -> Self
+    where
+        R: Rpc,
+        F: Fn(R) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = anyhow::Result<R::Response>> + Send + 'static,
+    {
+        let handler = Arc::new(handler);
+        let method: Method = Arc::new(move |body| {
+            let handler = handler.clone();
+            Box::pin(async move {
+                let request = decode::<R>(&body).map_err(|e| e.to_string())?;
+                let response = handler(request).await.map_err(|e| format!("{:#}", e))?;
+                encode(&response).map_err(|e| e.to_string())
+            })
+        });
+        self.methods.insert(R::METHOD.to_string(), method);
+        self
+    }
+
+    /// Serves on `addr` until `shutdown` resolves, without registering.
+    pub async fn serve(self, shutdown: impl Future) -> std::io::Result<()> {
+        let listener = TcpListener::bind(self.addr).await?;
+        let methods = Methods(Arc::new(self.methods));
+        net::run(
+            listener,
+            MuxHandler::new(methods, MsgPackFrame::empty()),
+            shutdown,
+        )
+        .await;
+        Ok(())
+    }
+
+    /// Serves and registers the group like `run_web_service`, until ctrl-c.
+    pub async fn run(self) -> Result<(), ServiceError> {
+        let service = RpcService {
+            group: self.group.clone(),
+            addr: self.addr,
+        };
+        crate::run_web_service_with(&service, |addr, drain| async move {
+            if let Err(e) = self.serve(drain).await {
+                tracing::error!(%addr, error = %e, "rpc server failed");
+            }
+        })
+        .await
+    }
+}
+
+// what an rpc server registers.
+struct RpcService {
+    group: String,
+    addr: SocketAddr,
+}
+
+impl Service for RpcService {
+    fn name(&self) -> String {
+        self.group.clone()
+    }
+
+    fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    fn endpoints(&self) -> Vec<ServiceEndpoint> {
+        vec![ServiceEndpoint::new(RPC_PROTOCOL, RPC_PROTOCOL, self.addr)]
+    }
+
+    // the port speaks msgpack, only rpc clients find it.
+    fn serves_http(&self) -> bool {
+        false
+    }
+}
+
+/// Calls the `Rpc` methods of a service by its name: every call resolves
+/// its instances, picks one with the load balancer algorithm they
+/// registered and goes out on the connection kept open to it. Calls that
+/// did not reach an instance are retried on another one.
+///
+/// ```ignore
+/// use micro::Transport;
+///
+/// let ums = micro::RpcClient::for_service("ums");
+/// let user = ums.call(&GetUser { id: 1 }).await?;
+/// ```
+#[derive(Debug, Clone)]
+pub struct RpcClient {
+    service: String,
+    register: Register,
+    timeout: Option<Duration>,
+    retries: u32,
+    retry_backoff: Duration,
+}
+
+impl RpcClient {
+    // per call, the timeout is the configured gateway request timeout.
+    pub fn for_service(service: &str) -> Self {
+        Self {
+            service: service.to_string(),
+            register: Register::default(),
+            timeout: config::current().request_timeout(),
+            retries: DEFAULT_RETRIES,
+            retry_backoff: DEFAULT_RETRY_BACKOFF,
+        }
+    }
+
+    // resolve through `register` instead of the process-wide plugin.
+    pub fn register(mut self, register: Register) -> Self {
+        self.register = register;
+        self
+    }
+
+    // how long one attempt may take, None for no limit.
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    // attempts after the first one, 0 to never retry.
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    // the wait before the first retry, doubled for every further one.
+    pub fn retry_backoff(mut self, backoff: Duration) -> Self {
+        self.retry_backoff = backoff;
+        self
+    }
+
+    async fn attempt(&self, method: &str, body: Vec<u8>) -> Result<Vec<u8>, TransportError> {
+        let (lba, endpoint) = self
+            .register
+            .get_web_service(&self.service, RPC_PROTOCOL)
+            .await
+            .map_err(|source| TransportError::Resolve {
+                service: self.service.clone(),
+                source,
+            })?;
+        let addr = lba
+            .pick(&endpoint)
+            .ok_or_else(|| TransportError::NoInstance(self.service.clone()))?
+            .addr
+            .clone();
+        let call = call(&addr, method, body);
+        match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, call)
+                .await
+                .map_err(|_| TransportError::Timeout { addr, timeout })?,
+            None => call.await,
+        }
+    }
+}
+
+// sends a call to the instance at `addr`, on the connection kept to it.
+async fn call(addr: &str, method: &str, body: Vec<u8>) -> Result<Vec<u8>, TransportError> {
+    let request = MsgPackFrame::new(Message {
+        method: method.to_string(),
+        body,
+        error: None,
+    });
+    let answer = connection(addr)
+        .await?
+        .call(request)
+        .await
+        .map_err(|source| {
+            // opened again by the next call.
+            CONNECTIONS.lock().unwrap().remove(addr);
+            TransportError::Call {
+                addr: addr.to_string(),
+                source,
+            }
+        })?
+        .into_inner()
+        .unwrap_or_default();
+    match answer.error {
+        Some(message) => Err(TransportError::Failed {
+            method: method.to_string(),
+            message,
+        }),
+        None => Ok(answer.body),
+    }
+}
+
+// the connection to `addr`, opened unless one already is.
+async fn connection(addr: &str) -> Result<MuxClient<Wire>, TransportError> {
+    if let Some(connection) = CONNECTIONS.lock().unwrap().get(addr) {
+        return Ok(connection.clone());
+    }
+    let stream = TcpStream::connect(addr)
+        .await
+        .map_err(|source| TransportError::Connect {
+            addr: addr.to_string(),
+            source,
+        })?;
+    let _ = stream.set_nodelay(true);
+    let connection = MuxClient::new(Connection::new(stream), MsgPackFrame::empty());
+    CONNECTIONS
+        .lock()
+        .unwrap()
+        .insert(addr.to_string(), connection.clone());
+    Ok(connection)
+}
+
+// whether another instance may get the call, only when it never reached one.
+fn retryable(res: &Result<Vec<u8>, TransportError>) -> bool {
+    matches!(
+        res,
+        Err(TransportError::Resolve { .. })
+            | Err(TransportError::NoInstance(_))
+            | Err(TransportError::Connect { .. })
+    )
+}
+
+impl Transport for RpcClient {
+    fn send<'a>(
+        &'a self,
+        method: &'a str,
+        body: Vec<u8>,
+    ) -> BoxFuture<'a, Result<Vec<u8>, TransportError>> {
+        Box::pin(async move {
+            let mut backoff = self.retry_backoff;
+            let mut attempt = 0;
+            loop {
+                let res = self.attempt(method, body.clone()).await;
+                if attempt == self.retries || !retryable(&res) {
+                    return res;
+                }
+
+                attempt += 1;
+                if let Err(e) = &res {
+                    tracing::debug!(attempt, error = %e, "retrying");
+                }
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Add {
+        a: i64,
+        b: i64,
+    }
+
+    impl Rpc for Add {
+        const METHOD: &'static str = "math.add";
+        type Response = i64;
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Div(i64, i64);
+
+    impl Rpc for Div {
+        const METHOD: &'static str = "math.div";
+        type Response = i64;
+    }
+
+    #[tokio::test]
+    async fn typed_calls_are_answered() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let server = RpcServer::new("math", addr)
+            .method(|req: Add| async move { Ok(req.a + req.b) })
+            .method(|Div(a, b)| async move {
+                match b {
+                    0 => Err(anyhow::anyhow!("division by zero")),
+                    b => Ok(a / b),
+                }
+            });
+        tokio::spawn(server.serve(futures::future::pending::<()>()));
+        while TcpStream::connect(addr).await.is_err() {
+            tokio::task::yield_now().await;
+        }
+
+        let addr = addr.to_string();
+        let add = call(&addr, Add::METHOD, encode(&Add { a: 2, b: 3 }).unwrap()).await;
+        assert_eq!(decode::<i64>(&add.unwrap()).unwrap(), 5);
+        let div = call(&addr, Div::METHOD, encode(&Div(1, 0)).unwrap()).await;
+        assert_eq!(
+            div.unwrap_err().to_string(),
+            "math.div failed: division by zero"
+        );
+        let mul = call(&addr, "math.mul", vec![]).await;
+        assert_eq!(
+            mul.unwrap_err().to_string(),
+            "math.mul failed: no method math.mul"
+        );
+        assert_eq!(CONNECTIONS.lock().unwrap().len(), 1);
+    }
+}
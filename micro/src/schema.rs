@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use hyper::body::HttpBody;
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use jsonschema::JSONSchema;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use serde_json::Value;
+use thiserror::Error;
+
+use crate::MetricsRegistry;
+
+// bodies read for validation on routes without a body_limit, at most.
+const MAX_BODY: u64 = 1024 * 1024;
+// violations listed in an answer, at most.
+const MAX_VIOLATIONS: usize = 20;
+
+// the schemas of the routes, by the file they are in, compiled on first use.
+static SCHEMAS: Lazy<Mutex<HashMap<String, Arc<JSONSchema>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Where a request body does not match the schema of its route: a json
+/// pointer into the body and what is wrong there.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Violation {
+    pub path: String,
+    pub message: String,
+}
+
+/// Why a request body is refused.
+#[derive(Debug, Error)]
+pub enum SchemaError {
+    #[error("request body does not match the schema of the route")]
+    Invalid(Vec<Violation>),
+    #[error("request body over {0} bytes")]
+    TooLarge(u64),
+    #[error("request body unreadable: {0}")]
+    Unreadable(String),
+    #[error("schema {path}: {message}")]
+    Schema { path: String, message: String },
+}
+
+impl SchemaError {
+    /// What the gateway answers the request with: 422 and the violations as
+    /// json when the body does not match.
+    pub fn response(&self) -> Response<Body> {
+        let (status, violations) = match self {
+            SchemaError::Invalid(violations) => (StatusCode::UNPROCESSABLE_ENTITY, &violations[..]),
+            SchemaError::TooLarge(_) => (StatusCode::PAYLOAD_TOO_LARGE, &[][..]),
+            SchemaError::Unreadable(_) => (StatusCode::BAD_REQUEST, &[][..]),
+            SchemaError::Schema { .. } => (StatusCode::INTERNAL_SERVER_ERROR, &[][..]),
+        };
+        let body = serde_json::json!({
+            "error": self.to_string(),
+            "violations": violations,
+        });
+        Response::builder()
+            .status(status)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+}
+
+fn compile(path: &str) -> Result<JSONSchema, String> {
+    let source = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let schema = serde_json::from_str::<Value>(&source).map_err(|e| e.to_string())?;
+    JSONSchema::compile(&schema).map_err(|e| e.to_string())
+}
+
+// what is wrong with the schema at `path`, for `Config::check_files`.
+pub(crate) fn issue(path: &str) -> Option<String> {
+    compile(path)
+        .err()
+        .map(|e| format!("`{}` is not a json schema: {}", path, e))
+}
+
+fn schema(path: &str) -> Result<Arc<JSONSchema>, SchemaError> {
+    if let Some(schema) = SCHEMAS.lock().unwrap().get(path) {
+        return Ok(schema.clone());
+    }
+    let schema = Arc::new(compile(path).map_err(|message| SchemaError::Schema {
+        path: path.to_string(),
+        message,
+    })?);
+    SCHEMAS
+        .lock()
+        .unwrap()
+        .insert(path.to_string(), schema.clone());
+    Ok(schema)
+}
+
+// the violations of `body` against `schema`.
+fn violations(schema: &JSONSchema, body: &[u8]) -> Vec<Violation> {
+    let instance = match serde_json::from_slice::<Value>(body) {
+        Ok(instance) => instance,
+        Err(e) => {
+            return vec![Violation {
+                path: String::new(),
+                message: format!("not json: {}", e),
+            }]
+        }
+    };
+    let violations = match schema.validate(&instance) {
+        Ok(()) => vec![],
+        Err(errors) => errors
+            .take(MAX_VIOLATIONS)
+            .map(|e| Violation {
+                path: e.instance_path.to_string(),
+                message: e.to_string(),
+            })
+            .collect(),
+    };
+    violations
+}
+
+/// Err when the route of `req` has a `policy.schema` and the body of a
+/// POST, PUT or PATCH does not match it. The body is read in full, up to
+/// the body limit of the route, and put back for the upstream.
+pub async fn check(req: &mut Request<Body>) -> Result<(), SchemaError> {
+    if !matches!(*req.method(), Method::POST | Method::PUT | Method::PATCH) {
+        return Ok(());
+    }
+    let route = match crate::api::route_of(req) {
+        Some(route) => route,
+        None => return Ok(()),
+    };
+    let path = match &route.policy.schema {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let schema = schema(path)?;
+
+    let limit = route.policy.body_limit.unwrap_or(MAX_BODY);
+    let mut body = std::mem::take(req.body_mut());
+    let mut read = vec![];
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| SchemaError::Unreadable(e.to_string()))?;
+        if (read.len() + chunk.len()) as u64 > limit {
+            return Err(SchemaError::TooLarge(limit));
+        }
+        read.extend_from_slice(&chunk);
+    }
+
+    let violations = violations(&schema, &read);
+    *req.body_mut() = Body::from(read);
+    if violations.is_empty() {
+        return Ok(());
+    }
+    MetricsRegistry::global()
+        .counter(
+            "crossgate_gateway_schema_rejected_total",
+            &[("route", route.key())],
+        )
+        .inc();
+    Err(SchemaError::Invalid(violations))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bodies_are_checked_against_the_schema() {
+        let schema = JSONSchema::compile(&serde_json::json!({
+            "type": "object",
+            "required": ["name"],
+            "properties": {
+                "name": { "type": "string" },
+                "age": { "type": "integer", "minimum": 0 },
+            },
+        }))
+        .unwrap();
+
+        assert!(violations(&schema, br#"{"name": "ann", "age": 3}"#).is_empty());
+        let found = violations(&schema, br#"{"age": -1}"#);
+        assert_eq!(found.len(), 2);
+        assert!(found.iter().any(|v| v.path == "/age"));
+        assert!(found
+            .iter()
+            .any(|v| v.path.is_empty() && v.message.contains("name")));
+        assert!(violations(&schema, b"name=ann")[0]
+            .message
+            .starts_with("not json"));
+
+        let res = SchemaError::Invalid(found).response();
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+}
@@ -0,0 +1,255 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use base64::Engine;
+use once_cell::sync::Lazy;
+use ring::hmac;
+use ring::signature::{Ed25519KeyPair, UnparsedPublicKey, ED25519};
+
+use crate::config;
+
+// the metadata a signed registration carries.
+const SIG_ALG_KEY: &str = "crossgate_sig_alg";
+const SIG_KEY: &str = "crossgate_sig";
+
+// verdicts kept for registrations seen before, the lookups of a service
+// check the same few over and over.
+const MAX_VERIFIED: usize = 10_000;
+
+static VERIFIED: Lazy<Mutex<HashMap<String, bool>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Debug, thiserror::Error)]
+pub enum SigningError {
+    #[error("`{0}` is not one of hmac-sha256, ed25519")]
+    Algorithm(String),
+    #[error("invalid signing key: {0}")]
+    Key(String),
+}
+
+/// How registrations are signed: with a secret the services and the gateway
+/// share, or an ed25519 key pair whose public half the gateway holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    HmacSha256,
+    Ed25519,
+}
+
+impl Algorithm {
+    pub fn parse(name: &str) -> Result<Self, SigningError> {
+        match name.to_lowercase().replace('_', "-").as_str() {
+            "hmac-sha256" => Ok(Algorithm::HmacSha256),
+            "ed25519" => Ok(Algorithm::Ed25519),
+            _ => Err(SigningError::Algorithm(name.to_string())),
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Algorithm::HmacSha256 => "hmac-sha256",
+            Algorithm::Ed25519 => "ed25519",
+        }
+    }
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub(crate) fn unhex(s: &str) -> Option<Vec<u8>> {
+    (0..s.len())
+        .step_by(2)
+        .map(|i| s.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+        .collect()
+}
+
+fn decode(key: &str) -> Result<Vec<u8>, SigningError> {
+    base64::engine::general_purpose::STANDARD
+        .decode(key.trim())
+        .map_err(|e| SigningError::Key(e.to_string()))
+}
+
+// the ed25519 key pair of a pkcs#8 document, as openssl writes it, or of
+// its 32 byte seed.
+fn key_pair(key: &[u8]) -> Result<Ed25519KeyPair, SigningError> {
+    match key.len() {
+        32 => Ed25519KeyPair::from_seed_unchecked(key),
+        _ => Ed25519KeyPair::from_pkcs8_maybe_unchecked(key),
+    }
+    .map_err(|e| SigningError::Key(e.to_string()))
+}
+
+/// Checks that `key`, base64, signs with `algorithm`; a verifying key when
+/// `verifying`, the ed25519 public key rather than the private one.
+pub fn check_key(algorithm: Algorithm, key: &str, verifying: bool) -> Result<(), SigningError> {
+    let key = decode(key)?;
+    match algorithm {
+        Algorithm::HmacSha256 if key.len() < 16 => {
+            Err(SigningError::Key("shorter than 16 bytes".to_string()))
+        }
+        Algorithm::Ed25519 if verifying && key.len() != 32 => Err(SigningError::Key(
+            "an ed25519 public key is 32 bytes".to_string(),
+        )),
+        Algorithm::Ed25519 if !verifying => key_pair(&key).map(|_| ()),
+        _ => Ok(()),
+    }
+}
+
+// what the signature of a registration covers: where the instance serves.
+pub(crate) fn signed_message(content: &plugin::ServiceContent) -> String {
+    let mut endpoints = content
+        .endpoints
+        .iter()
+        .map(|e| format!("{}={}", e.protocol, e.addr))
+        .collect::<Vec<_>>();
+    endpoints.sort();
+    format!(
+        "crossgate registration\n{}\n{}\n{}",
+        content.service,
+        content.addr,
+        endpoints.join(",")
+    )
+}
+
+fn sign(algorithm: Algorithm, key: &[u8], message: &[u8]) -> Result<Vec<u8>, SigningError> {
+    match algorithm {
+        Algorithm::HmacSha256 => {
+            let key = hmac::Key::new(hmac::HMAC_SHA256, key);
+            Ok(hmac::sign(&key, message).as_ref().to_vec())
+        }
+        Algorithm::Ed25519 => Ok(key_pair(key)?.sign(message).as_ref().to_vec()),
+    }
+}
+
+fn verify(algorithm: Algorithm, key: &[u8], message: &[u8], sig: &[u8]) -> bool {
+    match algorithm {
+        Algorithm::HmacSha256 => {
+            hmac::verify(&hmac::Key::new(hmac::HMAC_SHA256, key), message, sig).is_ok()
+        }
+        Algorithm::Ed25519 => UnparsedPublicKey::new(&ED25519, key)
+            .verify(message, sig)
+            .is_ok(),
+    }
+}
+
+// `content` signed with `key`, base64.
+fn sign_with(
+    mut content: plugin::ServiceContent,
+    algorithm: Algorithm,
+    key: &str,
+) -> Result<plugin::ServiceContent, SigningError> {
+    let sig = sign(
+        algorithm,
+        &decode(key)?,
+        signed_message(&content).as_bytes(),
+    )?;
+    content
+        .metadata
+        .insert(SIG_ALG_KEY.to_string(), algorithm.as_str().to_string());
+    content.metadata.insert(SIG_KEY.to_string(), hex(&sig));
+    Ok(content)
+}
+
+// whether `content` carries a signature of `algorithm` that verifies with
+// `key`, base64.
+fn verify_with(content: &plugin::ServiceContent, algorithm: Algorithm, key: &str) -> bool {
+    let field = |key: &str| content.metadata.get(key);
+    if field(SIG_ALG_KEY).map(String::as_str) != Some(algorithm.as_str()) {
+        return false;
+    }
+    let (key, sig) = match (decode(key), field(SIG_KEY).and_then(|s| unhex(s))) {
+        (Ok(key), Some(sig)) => (key, sig),
+        _ => return false,
+    };
+    verify(algorithm, &key, signed_message(content).as_bytes(), &sig)
+}
+
+// `content` signed with `registry.signing.key`, unchanged without one.
+pub(crate) fn signed(
+    content: plugin::ServiceContent,
+) -> Result<plugin::ServiceContent, SigningError> {
+    let signing = &config::current().registry.signing;
+    match &signing.key {
+        Some(key) => sign_with(content, Algorithm::parse(&signing.algorithm)?, key),
+        None => Ok(content),
+    }
+}
+
+// whether `content` may serve `service`: always, unless `service` is in
+// `registry.signing.keys` and the registration is not signed with its key.
+pub(crate) fn permits(service: &str, content: &plugin::ServiceContent) -> bool {
+    let signing = &config::current().registry.signing;
+    let key = match signing.keys.get(service) {
+        Some(key) => key,
+        None => return true,
+    };
+    let algorithm = match Algorithm::parse(&signing.algorithm) {
+        Ok(algorithm) => algorithm,
+        Err(_) => return false,
+    };
+    let claim = format!(
+        "{}\n{}\n{}",
+        signed_message(content),
+        content.metadata.get(SIG_KEY).map_or("", String::as_str),
+        key
+    );
+    if let Some(verified) = VERIFIED.lock().unwrap().get(&claim) {
+        return *verified;
+    }
+
+    let verified = verify_with(content, algorithm, key);
+    if !verified {
+        tracing::warn!(service = %service, addr = %content.addr, "unsigned registration rejected");
+    }
+    let mut cache = VERIFIED.lock().unwrap();
+    if cache.len() >= MAX_VERIFIED {
+        cache.clear();
+    }
+    cache.insert(claim, verified);
+    verified
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn content(service: &str) -> plugin::ServiceContent {
+        plugin::ServiceContent {
+            service: service.to_string(),
+            addr: "10.0.0.1:80".to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn registrations_verify_with_the_key_of_their_service() {
+        let b64 = |key: &[u8]| base64::engine::general_purpose::STANDARD.encode(key);
+
+        let secret = b64(b"a secret of the ums service");
+        let signed = sign_with(content("/t/ums"), Algorithm::HmacSha256, &secret).unwrap();
+        assert!(verify_with(&signed, Algorithm::HmacSha256, &secret));
+        assert!(!verify_with(
+            &signed,
+            Algorithm::HmacSha256,
+            &b64(b"a secret of the other service")
+        ));
+        // a signature for one service does not carry over to another name.
+        let mut stolen = signed.clone();
+        stolen.service = "/t/payments".to_string();
+        assert!(!verify_with(&stolen, Algorithm::HmacSha256, &secret));
+        assert!(!verify_with(
+            &content("/t/ums"),
+            Algorithm::HmacSha256,
+            &secret
+        ));
+
+        let seed = [7u8; 32];
+        let public = b64(ring::signature::KeyPair::public_key(&key_pair(&seed).unwrap()).as_ref());
+        let signed = sign_with(content("/t/ums"), Algorithm::Ed25519, &b64(&seed)).unwrap();
+        assert!(verify_with(&signed, Algorithm::Ed25519, &public));
+        assert!(!verify_with(&signed, Algorithm::HmacSha256, &public));
+        assert!(check_key(Algorithm::Ed25519, &public, true).is_ok());
+        assert!(check_key(Algorithm::Ed25519, &b64(&seed), false).is_ok());
+        assert!(check_key(Algorithm::HmacSha256, &b64(b"short"), true).is_err());
+        assert!(Algorithm::parse("rsa").is_err());
+    }
+}
@@ -0,0 +1,401 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use once_cell::sync::{Lazy, OnceCell};
+use tokio::sync::watch;
+use tokio_rustls::rustls::{sign, PrivateKey, SignatureScheme};
+use tonic::codegen::http::uri::PathAndQuery;
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+use crate::config;
+use crate::signing::{hex, signed_message, unhex};
+
+// how soon a lost Workload API stream is opened again.
+const RECONNECT: Duration = Duration::from_secs(5);
+
+// how long a verified registration is trusted before it is checked again,
+// its svid may have expired by then.
+const VERIFIED_FOR: Duration = Duration::from_secs(60);
+const MAX_VERIFIED: usize = 10_000;
+
+const FETCH_X509_SVID: &str = "/SpiffeWorkloadAPI/FetchX509SVID";
+
+// the metadata a signed registration carries.
+const ID_KEY: &str = "spiffe_id";
+const SVID_KEY: &str = "spiffe_svid";
+const SIG_ALG_KEY: &str = "spiffe_sig_alg";
+const SIG_KEY: &str = "spiffe_sig";
+
+// the messages of the Workload API this client uses, see workload.proto of
+// the SPIFFE spec.
+#[derive(Clone, PartialEq, prost::Message)]
+struct X509SvidRequest {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct X509SvidResponse {
+    #[prost(message, repeated, tag = "1")]
+    svids: Vec<X509Svid>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct X509Svid {
+    #[prost(string, tag = "1")]
+    spiffe_id: String,
+    // der certificates, the leaf first.
+    #[prost(bytes = "vec", tag = "2")]
+    x509_svid: Vec<u8>,
+    // pkcs#8 der.
+    #[prost(bytes = "vec", tag = "3")]
+    x509_svid_key: Vec<u8>,
+    #[prost(bytes = "vec", tag = "4")]
+    bundle: Vec<u8>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SpiffeError {
+    #[error("workload api: {0}")]
+    Transport(#[from] tonic::transport::Error),
+    #[error("workload api: {0}")]
+    Status(Box<tonic::Status>),
+    #[error("the workload api issued no svid")]
+    NoSvid,
+    #[error("invalid svid: {0}")]
+    Invalid(String),
+}
+
+impl From<tonic::Status> for SpiffeError {
+    fn from(status: tonic::Status) -> Self {
+        SpiffeError::Status(Box::new(status))
+    }
+}
+
+fn invalid(e: impl std::fmt::Display) -> SpiffeError {
+    SpiffeError::Invalid(e.to_string())
+}
+
+/// The X.509-SVID of this workload: its SPIFFE ID, the certificate chain
+/// and key proving it, and the trust bundle of its trust domain, all der.
+#[derive(Clone)]
+pub struct Svid {
+    pub spiffe_id: String,
+    pub cert_chain: Vec<Vec<u8>>,
+    key: Vec<u8>,
+    pub bundle: Vec<Vec<u8>>,
+}
+
+impl std::fmt::Debug for Svid {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Svid")
+            .field("spiffe_id", &self.spiffe_id)
+            .finish_non_exhaustive()
+    }
+}
+
+static SVID: Lazy<watch::Sender<Option<Arc<Svid>>>> = Lazy::new(|| watch::channel(None).0);
+static STARTED: OnceCell<()> = OnceCell::new();
+
+// registrations by what they claim, with the SPIFFE ID they proved and when.
+type Verified = HashMap<String, (Option<String>, Instant)>;
+static VERIFIED: Lazy<Mutex<Verified>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// The X.509-SVID the Workload API issued this workload last, None until
+/// it issued one or without `registry.spiffe.socket`.
+pub fn current_svid() -> Option<Arc<Svid>> {
+    SVID.borrow().clone()
+}
+
+// the svid now and at every rotation.
+pub(crate) fn watch_svid() -> watch::Receiver<Option<Arc<Svid>>> {
+    SVID.subscribe()
+}
+
+// follows the Workload API of `registry.spiffe.socket` for the rest of the
+// process, false when there is none.
+pub(crate) fn start() -> bool {
+    let socket = match &config::current().registry.spiffe.socket {
+        Some(socket) => socket.clone(),
+        None => return false,
+    };
+    STARTED.get_or_init(|| {
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = follow(&socket).await {
+                    tracing::warn!(socket, error = %e, "workload api stream lost");
+                }
+                tokio::time::sleep(RECONNECT).await;
+            }
+        });
+    });
+    true
+}
+
+// the certificates of concatenated der.
+fn split_der(mut der: &[u8]) -> Result<Vec<Vec<u8>>, SpiffeError> {
+    let mut certs = vec![];
+    while !der.is_empty() {
+        let (rest, _) = X509Certificate::from_der(der).map_err(invalid)?;
+        certs.push(der[..der.len() - rest.len()].to_vec());
+        der = rest;
+    }
+    Ok(certs)
+}
+
+fn spiffe_id_of(der: &[u8]) -> Option<String> {
+    let (_, cert) = X509Certificate::from_der(der).ok()?;
+    let san = cert.subject_alternative_name().ok()??;
+    san.value.general_names.iter().find_map(|name| match name {
+        GeneralName::URI(uri) if uri.starts_with("spiffe://") => Some(uri.to_string()),
+        _ => None,
+    })
+}
+
+// publishes the svids the Workload API at `socket` streams, the current one
+// and then each rotation, until the stream ends.
+async fn follow(socket: &str) -> Result<(), SpiffeError> {
+    let path = socket.trim_start_matches("unix://").to_string();
+    // every connection goes to the socket, the uri is only a placeholder.
+    let channel = tonic::transport::Endpoint::from_static("http://localhost")
+        .connect_with_connector(tower::service_fn(move |_: tonic::transport::Uri| {
+            tokio::net::UnixStream::connect(path.clone())
+        }))
+        .await?;
+
+    let mut grpc = tonic::client::Grpc::new(channel);
+    grpc.ready().await?;
+    let mut req = tonic::Request::new(X509SvidRequest {});
+    // required by the api, to tell workload requests from forwarded ones.
+    req.metadata_mut().insert(
+        "workload.spiffe.io",
+        tonic::metadata::MetadataValue::from_static("true"),
+    );
+    let codec = tonic::codec::ProstCodec::<X509SvidRequest, X509SvidResponse>::default();
+    let mut stream = grpc
+        .server_streaming(req, PathAndQuery::from_static(FETCH_X509_SVID), codec)
+        .await?
+        .into_inner();
+
+    while let Some(res) = stream.message().await? {
+        // the first is the default identity of the workload.
+        let svid = res.svids.into_iter().next().ok_or(SpiffeError::NoSvid)?;
+        let svid = Svid {
+            spiffe_id: svid.spiffe_id,
+            cert_chain: split_der(&svid.x509_svid)?,
+            key: svid.x509_svid_key,
+            bundle: split_der(&svid.bundle)?,
+        };
+        tracing::info!(spiffe_id = %svid.spiffe_id, "svid issued");
+        // the bundle may have changed with it.
+        VERIFIED.lock().unwrap().clear();
+        SVID.send_replace(Some(Arc::new(svid)));
+    }
+    Ok(())
+}
+
+static SCHEMES: [(SignatureScheme, &str, &webpki::SignatureAlgorithm); 4] = [
+    (
+        SignatureScheme::ECDSA_NISTP256_SHA256,
+        "ecdsa_p256_sha256",
+        &webpki::ECDSA_P256_SHA256,
+    ),
+    (
+        SignatureScheme::ECDSA_NISTP384_SHA384,
+        "ecdsa_p384_sha384",
+        &webpki::ECDSA_P384_SHA384,
+    ),
+    (SignatureScheme::ED25519, "ed25519", &webpki::ED25519),
+    (
+        SignatureScheme::RSA_PSS_SHA256,
+        "rsa_pss_sha256",
+        &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+    ),
+];
+
+static CHAIN_ALGS: &[&webpki::SignatureAlgorithm] = &[
+    &webpki::ECDSA_P256_SHA256,
+    &webpki::ECDSA_P256_SHA384,
+    &webpki::ECDSA_P384_SHA256,
+    &webpki::ECDSA_P384_SHA384,
+    &webpki::ED25519,
+    &webpki::RSA_PKCS1_2048_8192_SHA256,
+    &webpki::RSA_PKCS1_2048_8192_SHA384,
+    &webpki::RSA_PKCS1_2048_8192_SHA512,
+    &webpki::RSA_PKCS1_3072_8192_SHA384,
+];
+
+// the metadata proving the current svid registered `content`, None without
+// an svid.
+pub(crate) fn identity(
+    content: &plugin::ServiceContent,
+) -> Result<Option<HashMap<String, String>>, SpiffeError> {
+    let svid = match current_svid() {
+        Some(svid) => svid,
+        None => return Ok(None),
+    };
+    let key = sign::any_supported_type(&PrivateKey(svid.key.clone())).map_err(invalid)?;
+    let schemes = SCHEMES.iter().map(|(s, _, _)| *s).collect::<Vec<_>>();
+    let signer = key
+        .choose_scheme(&schemes)
+        .ok_or_else(|| invalid("unsupported key type"))?;
+    let alg = SCHEMES
+        .iter()
+        .find(|(s, _, _)| *s == signer.scheme())
+        .map_or("", |(_, name, _)| *name);
+    let sig = signer
+        .sign(signed_message(content).as_bytes())
+        .map_err(invalid)?;
+
+    let chain = svid.cert_chain.iter().map(|c| hex(c)).collect::<Vec<_>>();
+    Ok(Some(HashMap::from([
+        (ID_KEY.to_string(), svid.spiffe_id.clone()),
+        (SVID_KEY.to_string(), chain.join(".")),
+        (SIG_ALG_KEY.to_string(), alg.to_string()),
+        (SIG_KEY.to_string(), hex(&sig)),
+    ])))
+}
+
+// `content` with its identity, unchanged without an svid.
+pub(crate) fn signed(
+    mut content: plugin::ServiceContent,
+) -> Result<plugin::ServiceContent, SpiffeError> {
+    if let Some(identity) = identity(&content)? {
+        content.metadata.extend(identity);
+    }
+    Ok(content)
+}
+
+// the SPIFFE ID whose svid signed `content`, checked against the trust
+// bundle of this workload.
+fn verify(content: &plugin::ServiceContent) -> Result<String, SpiffeError> {
+    let field = |key: &str| {
+        content
+            .metadata
+            .get(key)
+            .ok_or_else(|| invalid(format!("no {}", key)))
+    };
+    let chain = field(SVID_KEY)?
+        .split('.')
+        .map(unhex)
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(|| invalid("svid is not hex"))?;
+    let sig = unhex(field(SIG_KEY)?).ok_or_else(|| invalid("signature is not hex"))?;
+    let alg = field(SIG_ALG_KEY)?;
+    let (_, _, alg) = SCHEMES
+        .iter()
+        .find(|(_, name, _)| name == alg)
+        .ok_or_else(|| invalid(format!("unknown signature algorithm {}", alg)))?;
+
+    let bundle = current_svid().ok_or(SpiffeError::NoSvid)?;
+    let anchors = bundle
+        .bundle
+        .iter()
+        .filter_map(|c| webpki::TrustAnchor::try_from_cert_der(c).ok())
+        .collect::<Vec<_>>();
+    let (leaf, intermediates) = chain.split_first().ok_or_else(|| invalid("empty svid"))?;
+    let intermediates = intermediates.iter().map(Vec::as_slice).collect::<Vec<_>>();
+    let now = webpki::Time::try_from(SystemTime::now()).map_err(|_| invalid("no clock"))?;
+
+    let cert = webpki::EndEntityCert::try_from(leaf.as_slice()).map_err(invalid)?;
+    cert.verify_for_usage(
+        CHAIN_ALGS,
+        &anchors,
+        &intermediates,
+        now,
+        webpki::KeyUsage::client_auth(),
+        &[],
+    )
+    .map_err(invalid)?;
+    cert.verify_signature(alg, signed_message(content).as_bytes(), &sig)
+        .map_err(invalid)?;
+
+    let id = spiffe_id_of(leaf).ok_or_else(|| invalid("no spiffe id"))?;
+    match field(ID_KEY)? == &id {
+        true => Ok(id),
+        false => Err(invalid(format!("signed by {}", id))),
+    }
+}
+
+fn verified_id(content: &plugin::ServiceContent) -> Option<String> {
+    let sig = content.metadata.get(SIG_KEY)?;
+    let claim = format!(
+        "{}\n{}\n{}",
+        signed_message(content),
+        sig,
+        content.metadata.get(SVID_KEY)?
+    );
+    if let Some((id, at)) = VERIFIED.lock().unwrap().get(&claim) {
+        if at.elapsed() < VERIFIED_FOR {
+            return id.clone();
+        }
+    }
+
+    let id = match verify(content) {
+        Ok(id) => Some(id),
+        Err(e) => {
+            tracing::warn!(service = %content.service, addr = %content.addr, error = %e, "registration identity rejected");
+            None
+        }
+    };
+    let mut verified = VERIFIED.lock().unwrap();
+    if verified.len() >= MAX_VERIFIED {
+        verified.clear();
+    }
+    verified.insert(claim, (id.clone(), Instant::now()));
+    id
+}
+
+fn id_matches(pattern: &str, id: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => id.starts_with(prefix),
+        None => pattern == id,
+    }
+}
+
+// whether `content` may serve `service`: always, unless `service` is in
+// `registry.spiffe.allowed` and the registration proves none of its ids.
+pub(crate) fn permits(service: &str, content: &plugin::ServiceContent) -> bool {
+    let allowed = match config::current().registry.spiffe.allowed.get(service) {
+        Some(allowed) => allowed,
+        None => return true,
+    };
+    verified_id(content).is_some_and(|id| allowed.iter().any(|a| id_matches(a, &id)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signed_registrations_verify_against_the_bundle() {
+        let mut ca = rcgen::CertificateParams::new(vec![]);
+        ca.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        let ca = rcgen::Certificate::from_params(ca).unwrap();
+        let mut leaf = rcgen::CertificateParams::new(vec![]);
+        leaf.subject_alt_names = vec![rcgen::SanType::URI("spiffe://example.org/ums".to_string())];
+        let leaf = rcgen::Certificate::from_params(leaf).unwrap();
+
+        SVID.send_replace(Some(Arc::new(Svid {
+            spiffe_id: "spiffe://example.org/ums".to_string(),
+            cert_chain: vec![leaf.serialize_der_with_signer(&ca).unwrap()],
+            key: leaf.serialize_private_key_der(),
+            bundle: vec![ca.serialize_der().unwrap()],
+        })));
+
+        let mut content = plugin::ServiceContent {
+            service: "/t/ums".to_string(),
+            addr: "10.0.0.1:80".to_string(),
+            ..Default::default()
+        };
+        content = signed(content).unwrap();
+        assert_eq!(verify(&content).unwrap(), "spiffe://example.org/ums");
+        assert!(id_matches(
+            "spiffe://example.org/*",
+            "spiffe://example.org/ums"
+        ));
+
+        // another address under the same signature.
+        content.addr = "10.0.0.2:80".to_string();
+        assert!(verify(&content).is_err());
+        assert_eq!(verified_id(&content), None);
+    }
+}
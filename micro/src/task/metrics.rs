@@ -0,0 +1,35 @@
+use std::time::Duration;
+
+use crate::metrics::{Counter, Gauge, MetricsRegistry};
+
+/// Metrics of one executor group in the global registry, labelled
+/// `executor=<group>`. Cheap to create, every instance shares the counters.
+#[derive(Debug, Clone)]
+pub struct ExecutorMetrics {
+    pub iterations: Counter,
+    pub failures: Counter,
+    pub restarts: Counter,
+    // current supervisor backoff in millis, 0 while running.
+    pub backoff: Gauge,
+    pub members: Gauge,
+    pub shards: Gauge,
+}
+
+impl ExecutorMetrics {
+    pub fn new(group: &str) -> Self {
+        let r = MetricsRegistry::global();
+        let labels = [("executor", group)];
+        Self {
+            iterations: r.counter("executor_iterations_total", &labels),
+            failures: r.counter("executor_failures_total", &labels),
+            restarts: r.counter("executor_restarts_total", &labels),
+            backoff: r.gauge("executor_backoff_millis", &labels),
+            members: r.gauge("executor_members", &labels),
+            shards: r.gauge("executor_shards", &labels),
+        }
+    }
+
+    pub(crate) fn set_backoff(&self, backoff: Duration) {
+        self.backoff.set(backoff.as_millis() as i64);
+    }
+}
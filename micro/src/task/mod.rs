@@ -1,16 +1,85 @@
-use crate::{make_executor, Register};
-use crossbeam::sync::WaitGroup;
+use crate::{config, Register, ServiceError};
 use futures::future::BoxFuture;
-use plugin::get_plugin_type;
-use plugin::PluginType::Mongodb;
+use net::{Phase, Shutdown};
+use plugin::{HealthStatus, ServiceHealth};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::sync::watch;
 
 use tokio_context::context::Context;
 
+mod metrics;
+mod oneshot;
+mod partition;
+mod queue;
+mod schedule;
+mod supervisor;
+mod trigger;
+mod worker;
+pub use metrics::ExecutorMetrics;
+use oneshot::run_one_shots;
+use oneshot::OneShotFn;
+pub use oneshot::{schedule_after, schedule_at, OneShotHandler};
+pub use partition::{PartitionStrategy, Partitioner};
+pub use queue::{Claimed, TaskQueue};
+pub use schedule::{Job, JobOwner, Schedule, Scheduler};
+pub use supervisor::SupervisionPolicy;
+use supervisor::{stopped, supervise, Exit};
+pub(crate) use trigger::TRIGGER_PATH;
+use trigger::{bind_triggers, serve_triggers};
+pub use worker::{ExecutorBuilder, Worker, WorkerExecutor};
+
+pub(crate) const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+// added to the drain phase of the shutdown for `Executor::release`.
+const RELEASE_TIMEOUT: Duration = Duration::from_secs(5);
+
 pub trait Executor<'a> {
     fn group(&self) -> String; // register group name
 
+    // scheduled jobs, each fired by a single member of the group.
+    fn jobs(&self) -> Vec<Job> {
+        vec![]
+    }
+
+    // handlers for jobs sent to the group with `schedule_at`/`schedule_after`.
+    fn one_shots(&self) -> Vec<OneShotHandler> {
+        vec![]
+    }
+
+    // where to serve `/tasks/{group}/{job}`, reached through the gateway to
+    // inspect (GET) or trigger (POST) jobs. Defaults to the address configured
+    // for the group in `service.triggers`, disabled when unset.
+    fn trigger_addr(&self) -> Option<SocketAddr> {
+        config::current().trigger_addr(&self.group())
+    }
+
+    // share of the group's shards this instance owns relative to its peers.
+    fn weight(&self) -> u32 {
+        1
+    }
+
+    // how long `start` gets to wind down after its context is cancelled.
+    fn drain_timeout(&self) -> Duration {
+        DEFAULT_DRAIN_TIMEOUT
+    }
+
+    // called once on shutdown after `start` returned or was cut off by the
+    // drain timeout, to checkpoint whatever was left unfinished.
+    fn release<'b>(&'b mut self, _register: &'b Register) -> BoxFuture<'b, anyhow::Result<()>>
+    where
+        'a: 'b,
+    {
+        Box::pin(async { Ok(()) })
+    }
+
+    // what to do when `start` fails or panics.
+    fn supervision(&self) -> SupervisionPolicy {
+        SupervisionPolicy::default()
+    }
+
+    // called again on every restart, so it borrows self only for one run.
     fn start<'b>(
-        &'a mut self,
+        &'b mut self,
         ctx: Context,
         register: &'b Register,
     ) -> BoxFuture<'b, anyhow::Result<()>>
@@ -18,32 +87,129 @@ pub trait Executor<'a> {
         'a: 'b;
 }
 
-pub async fn backend_service_run<'a, T>(e: &'a mut T)
+// fails when the executor cannot be registered or its supervision gave up.
+pub async fn backend_service_run<'a, T>(e: &'a mut T) -> Result<(), ServiceError>
 where
     T: Executor<'a> + Send + Sync + 'a,
 {
-    let (_, mut h) = Context::new();
-    let wg = WaitGroup::new();
+    // the executor gets `drain_timeout` to stop, the rest of the drain phase
+    // is for releasing its shards.
+    let drain_timeout = e.drain_timeout();
+    let shutdown = Shutdown::new().deadline(Phase::Drain, drain_timeout + RELEASE_TIMEOUT);
 
-    let t = ::std::env::var("REGISTER_TYPE").unwrap_or_else(|_| Mongodb.as_str().into());
-
-    let _ = plugin::init_plugin(
-        h.spawn_ctx(),
-        wg.clone(),
+    let handle = plugin::init_plugin_with(
+        shutdown.clone(),
         plugin::ServiceType::BackendService,
-        get_plugin_type(&t),
+        config::current().plugin_config(),
     )
-    .await;
+    .await?;
+
+    tracing::info!(group = %e.group(), "backend service start");
+
+    // bound before registering, only an address served is advertised.
+    let group = e.group();
+    let triggers_listener = e
+        .trigger_addr()
+        .and_then(|addr| bind_triggers(&group, addr));
+    let trigger_addr = triggers_listener
+        .as_ref()
+        .and_then(|listener| listener.local_addr().ok());
+
+    let r = Register::new(handle.clone());
+    if let Err(source) = r.register_backend_service(e, trigger_addr).await {
+        return Err(ServiceError::Register {
+            name: e.group(),
+            source,
+        });
+    }
+    let scheduler = Scheduler::new(&group, e.jobs());
+    let one_shots = e.one_shots();
+    let triggers = (e.jobs(), one_shots.clone());
+    let policy = e.supervision();
 
-    log::info!("backend service {} start", e.group());
+    // the executor, its scheduled jobs and one-shots stop together, either on
+    // ctrl-c or when the executor itself is done.
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let (scheduler_ctx, scheduler_h) = Context::new();
+    let (one_shots_ctx, one_shots_h) = Context::new();
+    let (triggers_ctx, triggers_h) = Context::new();
 
-    let (e, r) = make_executor(e).await;
+    let mut work = Box::pin(async {
+        let supervised = async {
+            let exit = supervise(e, &r, &policy, stop_rx.clone()).await;
+            let _ = stop_tx.send(true);
+            exit
+        };
+        let stopper = async {
+            stopped(&mut stop_rx.clone()).await;
+            scheduler_h.cancel();
+            one_shots_h.cancel();
+            triggers_h.cancel();
+        };
+        let triggers = async {
+            if let Some(listener) = triggers_listener {
+                serve_triggers(triggers_ctx, listener, &group, triggers.0, triggers.1).await;
+            }
+        };
 
-    tokio::select! {
-        _ = e.start(h.spawn_ctx(),&r)  => {},
-        _ = tokio::signal::ctrl_c() => {
-            h.cancel();
-            wg.wait();
+        tokio::join!(
+            supervised,
+            stopper,
+            scheduler.run(scheduler_ctx, &r),
+            run_one_shots(one_shots_ctx, &group, one_shots, handle),
+            triggers,
+        )
+        .0
+    });
+
+    let draining = shutdown.guard(Phase::Drain);
+    let phases = tokio::spawn({
+        let shutdown = shutdown.clone();
+        async move { shutdown.run().await }
+    });
+
+    let exit = tokio::select! {
+        exit = &mut work => {
+            // done on its own, deregister all the same.
+            shutdown.trigger();
+            exit
         },
+        _ = shutdown.reached(Phase::StopAccepting) => {
+            tracing::info!(group = %group, "backend service draining");
+            let _ = stop_tx.send(true);
+            match tokio::time::timeout(drain_timeout, &mut work).await {
+                Ok(exit) => exit,
+                Err(_) => {
+                    tracing::warn!(group = %group, timeout = ?drain_timeout, "backend service drain timeout");
+                    Exit::Stopped
+                }
+            }
+        },
+    };
+    drop(work);
+
+    if exit != Exit::Finished {
+        if let Err(err) = e.release(&r).await {
+            tracing::error!(group = %group, error = ?err, "backend service release failed");
+        }
+
+        // tell peers the shards are free before the registration itself expires.
+        let released = ServiceHealth {
+            status: HealthStatus::Draining,
+            lag: 0,
+            last_success: 0,
+            detail: "released".into(),
+        };
+        if let Err(err) = r.report_health(&group, released).await {
+            tracing::warn!(group = %group, error = ?err, "backend service release notice failed");
+        }
+    }
+    // the plugin deregisters this instance once the drain phase is over.
+    drop(draining);
+    let _ = phases.await;
+
+    match exit {
+        Exit::GaveUp => Err(ServiceError::GaveUp(group)),
+        _ => Ok(()),
     }
 }
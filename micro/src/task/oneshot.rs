@@ -0,0 +1,120 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use futures::future::BoxFuture;
+use plugin::PluginHandle;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio_context::context::Context;
+
+use super::TaskQueue;
+
+pub type OneShotFn =
+    Arc<dyn Fn(serde_json::Value) -> BoxFuture<'static, anyhow::Result<()>> + Send + Sync>;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct OneShot {
+    name: String,
+    payload: serde_json::Value,
+}
+
+fn queue(group: &str) -> TaskQueue<OneShot> {
+    TaskQueue::new(&format!("{}.oneshot", group))
+}
+
+/// Runs `name` once on some member of `group` at `at`. Stored in the
+/// registry backend, so it survives restarts of either side.
+pub async fn schedule_at<T: Serialize>(
+    group: &str,
+    name: &str,
+    payload: &T,
+    at: SystemTime,
+) -> anyhow::Result<String> {
+    let job = OneShot {
+        name: name.to_string(),
+        payload: serde_json::to_value(payload)?,
+    };
+    queue(group).enqueue_at(&job, at).await
+}
+
+pub async fn schedule_after<T: Serialize>(
+    group: &str,
+    name: &str,
+    payload: &T,
+    delay: Duration,
+) -> anyhow::Result<String> {
+    schedule_at(group, name, payload, SystemTime::now() + delay).await
+}
+
+/// Handles the one-shot jobs named `name` sent to the executor's group.
+#[derive(Clone)]
+pub struct OneShotHandler {
+    pub name: String,
+    pub run: OneShotFn,
+}
+
+impl OneShotHandler {
+    pub fn new<T, F, Fut>(name: &str, f: F) -> Self
+    where
+        T: DeserializeOwned + Send + 'static,
+        F: Fn(T) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        let f = Arc::new(f);
+        Self {
+            name: name.to_string(),
+            run: Arc::new(move |payload| {
+                let f = f.clone();
+                Box::pin(async move { f(serde_json::from_value(payload)?).await })
+            }),
+        }
+    }
+}
+
+impl std::fmt::Debug for OneShotHandler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OneShotHandler")
+            .field("name", &self.name)
+            .finish()
+    }
+}
+
+// consumes the group's one-shot queue, stored through `plugin`, until the
+// context is cancelled.
+pub(crate) async fn run_one_shots(
+    mut ctx: Context,
+    group: &str,
+    handlers: Vec<OneShotHandler>,
+    plugin: PluginHandle,
+) {
+    if handlers.is_empty() {
+        ctx.done().await;
+        return;
+    }
+
+    let handlers = handlers
+        .into_iter()
+        .map(|h| (h.name.clone(), h.run))
+        .collect::<HashMap<_, _>>();
+
+    queue(group)
+        .plugin(plugin)
+        .consume(ctx, |job| {
+            let run = handlers.get(&job.name).cloned();
+            async move {
+                match run {
+                    Some(run) => run(job.payload).await,
+                    None => {
+                        // failing would only redeliver it to another member
+                        // with the same handlers.
+                        tracing::error!(job = %job.name, "one-shot job has no handler, dropped");
+                        Ok(())
+                    }
+                }
+            }
+        })
+        .await
+}
@@ -0,0 +1,262 @@
+use std::ops::Range;
+
+use super::ExecutorMetrics;
+use crate::Register;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionStrategy {
+    // hash(key) % members, cheap but most keys move when membership changes.
+    HashMod,
+    // highest random weight, only the keys of the joining/leaving member move.
+    Rendezvous,
+}
+
+/// Splits a keyspace across the members of a backend service group.
+///
+/// Feed it the `(self_id, member_ids)` pair returned by
+/// `Register::get_backend_service`, every instance computes the same
+/// assignment without coordination. Members own keys and shards in
+/// proportion to their registered weight.
+#[derive(Debug, Clone)]
+pub struct Partitioner {
+    strategy: PartitionStrategy,
+    self_id: String,
+    members: Vec<String>,
+    // parallel to `members`, at least 1.
+    weights: Vec<u32>,
+    // set by `refresh`, the only place the group is known.
+    metrics: Option<ExecutorMetrics>,
+}
+
+// fnv-1a, stable across processes and rust versions unlike `DefaultHasher`.
+fn hash(parts: &[&str]) -> u64 {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for part in parts {
+        for b in part.as_bytes() {
+            h ^= *b as u64;
+            h = h.wrapping_mul(0x100000001b3);
+        }
+        // separator, so that ("ab", "c") and ("a", "bc") differ
+        h ^= 0xff;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+    h
+}
+
+impl Partitioner {
+    pub fn new(strategy: PartitionStrategy) -> Self {
+        Self {
+            strategy,
+            self_id: "".into(),
+            members: vec![],
+            weights: vec![],
+            metrics: None,
+        }
+    }
+
+    // returns true when the assignment changed.
+    pub fn update(&mut self, self_id: &str, members: &[String]) -> bool {
+        let members = members.iter().map(|id| (id.clone(), 1)).collect::<Vec<_>>();
+        self.update_weighted(self_id, &members)
+    }
+
+    // like `update` with a weight per member, members of weight 0 (e.g.
+    // draining) own nothing.
+    pub fn update_weighted(&mut self, self_id: &str, members: &[(String, u32)]) -> bool {
+        let mut members = members.to_vec();
+        members.sort();
+        members.dedup_by(|a, b| a.0 == b.0);
+        let (members, weights): (Vec<String>, Vec<u32>) = members
+            .into_iter()
+            .filter(|(_, weight)| *weight > 0)
+            .unzip();
+
+        if self.self_id == self_id && self.members == members && self.weights == weights {
+            return false;
+        }
+        self.self_id = self_id.to_string();
+        self.members = members;
+        self.weights = weights;
+        true
+    }
+
+    pub async fn refresh(&mut self, register: &Register, group: &str) -> anyhow::Result<bool> {
+        let (self_id, members) = register.get_backend_service(group).await?;
+        // plugins that cannot list members leave every weight at 1.
+        let weights = register
+            .get_backend_weights(group)
+            .await
+            .unwrap_or_default();
+        let members = members
+            .into_iter()
+            .map(|id| {
+                let weight = weights.get(&id).copied().unwrap_or(1);
+                (id, weight)
+            })
+            .collect::<Vec<_>>();
+        let changed = self.update_weighted(&self_id, &members);
+        self.metrics
+            .get_or_insert_with(|| ExecutorMetrics::new(group))
+            .members
+            .set(self.members.len() as i64);
+        Ok(changed)
+    }
+
+    pub fn members(&self) -> &[String] {
+        &self.members
+    }
+
+    // the member with the lowest id, agreed on by every instance.
+    pub fn is_leader(&self) -> bool {
+        self.members.first() == Some(&self.self_id)
+    }
+
+    fn total_weight(&self) -> u64 {
+        self.weights.iter().map(|w| *w as u64).sum()
+    }
+
+    // the member whose slice of `0..total_weight` contains `point`.
+    fn index_at(&self, mut point: u64) -> usize {
+        for (i, weight) in self.weights.iter().enumerate() {
+            if point < *weight as u64 {
+                return i;
+            }
+            point -= *weight as u64;
+        }
+        self.weights.len() - 1
+    }
+
+    pub fn owner(&self, key: &str) -> Option<&str> {
+        if self.members.is_empty() {
+            return None;
+        }
+
+        let index = match self.strategy {
+            PartitionStrategy::HashMod => self.index_at(hash(&[key]) % self.total_weight()),
+            PartitionStrategy::Rendezvous => {
+                // weighted rendezvous: -w / ln(u), u uniform in (0, 1).
+                let score = |i: usize| {
+                    let h = hash(&[&self.members[i], key]);
+                    let u = ((h >> 11) as f64 + 0.5) / (1u64 << 53) as f64;
+                    -(self.weights[i] as f64) / u.ln()
+                };
+                (0..self.members.len())
+                    .max_by(|a, b| score(*a).total_cmp(&score(*b)))
+                    .unwrap()
+            }
+        };
+        Some(self.members[index].as_str())
+    }
+
+    pub fn owns(&self, key: &str) -> bool {
+        self.owner(key) == Some(self.self_id.as_str())
+    }
+
+    // the contiguous range of `0..total_shards` owned by this instance, empty
+    // when it is not (yet) a member.
+    pub fn shard_range(&self, total_shards: u32) -> Range<u32> {
+        let index = match self.members.iter().position(|m| *m == self.self_id) {
+            Some(index) => index,
+            None => return 0..0,
+        };
+        let before = self.weights[..index].iter().map(|w| *w as u64).sum::<u64>();
+        let weight = self.weights[index] as u64;
+        let n = self.total_weight();
+        let total = total_shards as u64;
+
+        let range = ((before * total / n) as u32)..(((before + weight) * total / n) as u32);
+        if let Some(metrics) = &self.metrics {
+            metrics.shards.set(range.len() as i64);
+        }
+        range
+    }
+
+    pub fn owns_shard(&self, shard: u32, total_shards: u32) -> bool {
+        match self.strategy {
+            PartitionStrategy::HashMod => self.shard_range(total_shards).contains(&shard),
+            PartitionStrategy::Rendezvous => self.owns(&shard.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn members(n: usize) -> Vec<String> {
+        (0..n).map(|i| format!("member-{}", i)).collect()
+    }
+
+    #[test]
+    fn test_every_key_has_one_owner() {
+        for strategy in [PartitionStrategy::HashMod, PartitionStrategy::Rendezvous] {
+            let ps = members(3)
+                .iter()
+                .map(|id| {
+                    let mut p = Partitioner::new(strategy);
+                    p.update(id, &members(3));
+                    p
+                })
+                .collect::<Vec<_>>();
+
+            for key in 0..100 {
+                let key = key.to_string();
+                assert_eq!(ps.iter().filter(|p| p.owns(&key)).count(), 1);
+            }
+        }
+    }
+
+    #[test]
+    fn test_shard_range_covers_all_shards() {
+        let ranges = members(3)
+            .iter()
+            .map(|id| {
+                let mut p = Partitioner::new(PartitionStrategy::HashMod);
+                p.update(id, &members(3));
+                p.shard_range(10)
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(ranges, vec![0..3, 3..6, 6..10]);
+    }
+
+    #[test]
+    fn test_weights_scale_ownership() {
+        let weighted = vec![("member-0".to_string(), 1), ("member-1".to_string(), 3)];
+
+        let mut p = Partitioner::new(PartitionStrategy::HashMod);
+        p.update_weighted("member-1", &weighted);
+        assert_eq!(p.shard_range(8), 2..8);
+
+        for strategy in [PartitionStrategy::HashMod, PartitionStrategy::Rendezvous] {
+            let mut p = Partitioner::new(strategy);
+            p.update_weighted("member-1", &weighted);
+            let owned = (0..1000).filter(|k| p.owns(&k.to_string())).count();
+            assert!((650..850).contains(&owned), "{:?} owns {}", strategy, owned);
+        }
+
+        // a draining member gives everything up
+        let mut p = Partitioner::new(PartitionStrategy::Rendezvous);
+        p.update_weighted(
+            "member-0",
+            &[("member-0".to_string(), 0), ("member-1".to_string(), 1)],
+        );
+        assert!(!p.owns("key"));
+        assert_eq!(p.shard_range(8), 0..0);
+    }
+
+    #[test]
+    fn test_rendezvous_moves_only_leaving_keys() {
+        let mut before = Partitioner::new(PartitionStrategy::Rendezvous);
+        before.update("member-0", &members(4));
+        let mut after = before.clone();
+        after.update("member-0", &members(3));
+
+        for key in 0..100 {
+            let key = key.to_string();
+            if before.owner(&key) != Some("member-3") {
+                assert_eq!(before.owner(&key), after.owner(&key));
+            }
+        }
+    }
+}
@@ -0,0 +1,199 @@
+use std::{
+    future::Future,
+    marker::PhantomData,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use plugin::{PluginHandle, QueueTask};
+use serde::{de::DeserializeOwned, Serialize};
+use tokio_context::context::Context;
+
+const DEFAULT_VISIBILITY: Duration = Duration::from_secs(30);
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A job taken off a `TaskQueue`, hidden from other consumers until it is
+/// acked, nacked or its visibility timeout runs out.
+#[derive(Debug)]
+pub struct Claimed<T> {
+    pub job: T,
+    // 1 on the first delivery, higher on redelivery.
+    pub attempts: u32,
+    task: QueueTask,
+}
+
+/// Typed view of a named queue stored by the registry plugin. Delivery is
+/// at-least-once, so jobs should be idempotent.
+#[derive(Debug, Clone)]
+pub struct TaskQueue<T> {
+    name: String,
+    visibility: Duration,
+    poll_interval: Duration,
+    // None uses the default plugin.
+    plugin: Option<PluginHandle>,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T> TaskQueue<T>
+where
+    T: Serialize + DeserializeOwned,
+{
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            visibility: DEFAULT_VISIBILITY,
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            plugin: None,
+            _marker: PhantomData,
+        }
+    }
+
+    // how long a claimed job stays hidden before it is handed out again.
+    pub fn visibility(mut self, visibility: Duration) -> Self {
+        self.visibility = visibility;
+        self
+    }
+
+    // how long `consume` waits when the queue is empty.
+    pub fn poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    // stores the jobs through `plugin` instead of the default plugin.
+    pub fn plugin(mut self, plugin: PluginHandle) -> Self {
+        self.plugin = Some(plugin);
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn handle(&self) -> anyhow::Result<&PluginHandle> {
+        match &self.plugin {
+            Some(plugin) => Ok(plugin),
+            None => Ok(plugin::default_plugin()?),
+        }
+    }
+
+    pub async fn enqueue(&self, job: &T) -> anyhow::Result<String> {
+        self.handle()?
+            .enqueue(&self.name, serde_json::to_string(job)?)
+            .await
+    }
+
+    // hidden from consumers until `at`, a time in the past is due at once.
+    pub async fn enqueue_at(&self, job: &T, at: SystemTime) -> anyhow::Result<String> {
+        let at = at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        self.handle()?
+            .enqueue_at(&self.name, serde_json::to_string(job)?, at)
+            .await
+    }
+
+    pub async fn enqueue_after(&self, job: &T, delay: Duration) -> anyhow::Result<String> {
+        self.enqueue_at(job, SystemTime::now() + delay).await
+    }
+
+    pub async fn claim(&self) -> anyhow::Result<Option<Claimed<T>>> {
+        loop {
+            let task = match self.handle()?.claim(&self.name, self.visibility).await? {
+                Some(task) => task,
+                None => return Ok(None),
+            };
+
+            match serde_json::from_str(&task.payload) {
+                Ok(job) => {
+                    return Ok(Some(Claimed {
+                        job,
+                        attempts: task.attempts,
+                        task,
+                    }))
+                }
+                Err(e) => {
+                    // would be redelivered forever, drop it.
+                    tracing::error!(
+                        queue = %self.name,
+                        task = %task.id,
+                        error = ?e,
+                        "queue dropped an undecodable task"
+                    );
+                    self.ack_task(&task).await?;
+                }
+            }
+        }
+    }
+
+    // false when the visibility timeout expired and the job was claimed again.
+    pub async fn ack(&self, claimed: &Claimed<T>) -> anyhow::Result<bool> {
+        self.ack_task(&claimed.task).await
+    }
+
+    // hand the job back for immediate redelivery.
+    pub async fn nack(&self, claimed: &Claimed<T>) -> anyhow::Result<bool> {
+        self.nack_task(&claimed.task).await
+    }
+
+    async fn ack_task(&self, task: &QueueTask) -> anyhow::Result<bool> {
+        self.handle()?.ack(task).await
+    }
+
+    async fn nack_task(&self, task: &QueueTask) -> anyhow::Result<bool> {
+        self.handle()?.nack(task).await
+    }
+
+    // claims jobs until the context is cancelled, acking the ones `f`
+    // completes and nacking the ones it fails.
+    pub async fn consume<F, Fut>(&self, mut ctx: Context, mut f: F)
+    where
+        F: FnMut(T) -> Fut,
+        Fut: Future<Output = anyhow::Result<()>>,
+    {
+        loop {
+            let claimed = tokio::select! {
+                claimed = self.claim() => claimed,
+                _ = ctx.done() => return,
+            };
+
+            let Claimed {
+                job,
+                attempts,
+                task,
+            } = match claimed {
+                Ok(Some(claimed)) => claimed,
+                Ok(None) => {
+                    tokio::select! {
+                        _ = tokio::time::sleep(self.poll_interval) => continue,
+                        _ = ctx.done() => return,
+                    }
+                }
+                Err(e) => {
+                    tracing::error!(queue = %self.name, error = ?e, "queue claim failed");
+                    tokio::select! {
+                        _ = tokio::time::sleep(self.poll_interval) => continue,
+                        _ = ctx.done() => return,
+                    }
+                }
+            };
+
+            let done = match f(job).await {
+                Ok(()) => self.ack_task(&task).await,
+                Err(e) => {
+                    tracing::warn!(
+                        queue = %self.name,
+                        task = %task.id,
+                        attempts,
+                        error = ?e,
+                        "queue task failed"
+                    );
+                    self.nack_task(&task).await
+                }
+            };
+            if let Err(e) = done {
+                tracing::error!(queue = %self.name, task = %task.id, error = ?e, "queue task settle failed");
+            }
+        }
+    }
+}
@@ -0,0 +1,215 @@
+use std::{str::FromStr, sync::Arc, time::Duration};
+
+use chrono::Utc;
+use futures::future::BoxFuture;
+use tokio::time::Instant;
+use tokio_context::context::Context;
+use tracing::Instrument;
+
+use super::{ExecutorMetrics, PartitionStrategy, Partitioner};
+use crate::Register;
+
+pub type JobFn = Arc<dyn Fn() -> BoxFuture<'static, anyhow::Result<()>> + Send + Sync>;
+
+#[derive(Debug, Clone)]
+pub enum Schedule {
+    // fires every `Duration`, the first time one period after start.
+    Interval(Duration),
+    // standard cron expression with seconds, e.g. "0 */5 * * * *", in UTC.
+    Cron(Box<cron::Schedule>),
+}
+
+impl Schedule {
+    pub fn cron(expr: &str) -> anyhow::Result<Self> {
+        Ok(Schedule::Cron(Box::new(cron::Schedule::from_str(expr)?)))
+    }
+
+    fn next_after(&self, now: Instant) -> Option<Instant> {
+        match self {
+            Schedule::Interval(period) => Some(now + *period),
+            Schedule::Cron(schedule) => {
+                let next = schedule.upcoming(Utc).next()?;
+                let wait = (next - Utc::now()).to_std().unwrap_or_default();
+                Some(now + wait)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobOwner {
+    // the member with the lowest id fires the job.
+    Leader,
+    // the job name is rendezvous hashed onto the members, spreads jobs out.
+    Hashed,
+}
+
+/// A job declared by an `Executor`, fired by exactly one instance of the
+/// group each time its schedule comes due.
+#[derive(Clone)]
+pub struct Job {
+    pub name: String,
+    pub schedule: Schedule,
+    pub owner: JobOwner,
+    pub run: JobFn,
+}
+
+impl Job {
+    pub fn new<F, Fut>(name: &str, schedule: Schedule, f: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: std::future::Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        Self {
+            name: name.to_string(),
+            schedule,
+            owner: JobOwner::Hashed,
+            run: Arc::new(move || Box::pin(f())),
+        }
+    }
+
+    pub fn owner(mut self, owner: JobOwner) -> Self {
+        self.owner = owner;
+        self
+    }
+}
+
+impl std::fmt::Debug for Job {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Job")
+            .field("name", &self.name)
+            .field("schedule", &self.schedule)
+            .field("owner", &self.owner)
+            .finish()
+    }
+}
+
+pub struct Scheduler {
+    group: String,
+    jobs: Vec<Job>,
+}
+
+impl Scheduler {
+    pub fn new(group: &str, jobs: Vec<Job>) -> Self {
+        Self {
+            group: group.to_string(),
+            jobs,
+        }
+    }
+
+    // runs until the context is cancelled, even when there is nothing to
+    // schedule, so it can be raced against the executor.
+    pub async fn run(self, mut ctx: Context, register: &Register) {
+        if self.jobs.is_empty() {
+            ctx.done().await;
+            return;
+        }
+
+        let now = Instant::now();
+        let mut due: Vec<Option<Instant>> = self
+            .jobs
+            .iter()
+            .map(|job| job.schedule.next_after(now))
+            .collect();
+        let mut partitioner = Partitioner::new(PartitionStrategy::Rendezvous);
+        let metrics = ExecutorMetrics::new(&self.group);
+
+        loop {
+            let next = match due.iter().flatten().min() {
+                Some(next) => *next,
+                None => {
+                    ctx.done().await;
+                    return;
+                }
+            };
+
+            tokio::select! {
+                _ = tokio::time::sleep_until(next) => {},
+                _ = ctx.done() => return,
+            }
+
+            // membership is looked up when something fires, so a member that
+            // left is never picked as owner for long.
+            if let Err(e) = partitioner.refresh(register, &self.group).await {
+                tracing::warn!(group = %self.group, error = ?e, "scheduler refresh members failed");
+            }
+
+            let now = Instant::now();
+            for (job, due) in self.jobs.iter().zip(due.iter_mut()) {
+                if !due.is_some_and(|at| at <= now) {
+                    continue;
+                }
+                *due = job.schedule.next_after(now);
+
+                if !owns(&partitioner, job) {
+                    continue;
+                }
+
+                let run = job.run.clone();
+                let name = job.name.clone();
+                let metrics = metrics.clone();
+                let span =
+                    tracing::info_span!("executor.iteration", group = %self.group, job = %name);
+                tokio::spawn(
+                    async move {
+                        tracing::debug!(job = %name, "scheduled job fire");
+                        metrics.iterations.inc();
+                        if let Err(e) = run().await {
+                            metrics.failures.inc();
+                            tracing::error!(job = %name, error = ?e, "scheduled job failed");
+                        }
+                    }
+                    .instrument(span),
+                );
+            }
+        }
+    }
+}
+
+fn owns(partitioner: &Partitioner, job: &Job) -> bool {
+    match job.owner {
+        JobOwner::Leader => partitioner.is_leader(),
+        JobOwner::Hashed => partitioner.owns(&job.name),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cron_expression() {
+        assert!(Schedule::cron("0 */5 * * * *").is_ok());
+        assert!(Schedule::cron("not a cron").is_err());
+    }
+
+    #[test]
+    fn single_owner_per_job() {
+        let members: Vec<String> = (0..3).map(|i| format!("member-{}", i)).collect();
+        let jobs = [
+            Job::new(
+                "compact",
+                Schedule::Interval(Duration::from_secs(1)),
+                || async { Ok(()) },
+            ),
+            Job::new(
+                "report",
+                Schedule::Interval(Duration::from_secs(1)),
+                || async { Ok(()) },
+            )
+            .owner(JobOwner::Leader),
+        ];
+
+        for job in jobs.iter() {
+            let owners = members
+                .iter()
+                .filter(|id| {
+                    let mut p = Partitioner::new(PartitionStrategy::Rendezvous);
+                    p.update(id, &members);
+                    owns(&p, job)
+                })
+                .count();
+            assert_eq!(owners, 1);
+        }
+    }
+}
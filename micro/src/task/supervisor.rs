@@ -0,0 +1,178 @@
+use std::{any::Any, panic::AssertUnwindSafe, time::Duration};
+
+use futures::FutureExt;
+use tokio::{sync::watch, time::Instant};
+use tokio_context::context::Context;
+use tracing::Instrument;
+
+use super::{Executor, ExecutorMetrics};
+use crate::Register;
+
+/// How `backend_service_run` reacts when `Executor::start` returns an error
+/// or panics. Returning `Ok` always ends the service.
+#[derive(Debug, Clone)]
+pub struct SupervisionPolicy {
+    // None restarts forever, Some(0) gives up on the first failure.
+    pub max_restarts: Option<u32>,
+    // first backoff, doubled on every consecutive failure.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    // a run that lasted at least this long resets the backoff and the
+    // restart count.
+    pub reset_after: Duration,
+}
+
+impl Default for SupervisionPolicy {
+    fn default() -> Self {
+        Self {
+            max_restarts: None,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            reset_after: Duration::from_secs(60),
+        }
+    }
+}
+
+impl SupervisionPolicy {
+    // give up (and deregister) on the first failure.
+    pub fn never() -> Self {
+        Self {
+            max_restarts: Some(0),
+            ..Default::default()
+        }
+    }
+
+    // None once the restarts are exhausted.
+    pub fn backoff(&self, restarts: u32) -> Option<Duration> {
+        if self.max_restarts.is_some_and(|max| restarts >= max) {
+            return None;
+        }
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(restarts));
+        Some(backoff.min(self.max_backoff))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Exit {
+    Finished,
+    GaveUp,
+    // the executor wound down after a stop request.
+    Stopped,
+}
+
+// resolves once `true` is sent, never if the sender is dropped without it.
+pub(crate) async fn stopped(stop: &mut watch::Receiver<bool>) {
+    if stop.wait_for(|stop| *stop).await.is_err() {
+        futures::future::pending::<()>().await;
+    }
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    if let Some(s) = panic.downcast_ref::<&str>() {
+        return s.to_string();
+    }
+    if let Some(s) = panic.downcast_ref::<String>() {
+        return s.clone();
+    }
+    "unknown panic".to_string()
+}
+
+pub(crate) async fn supervise<'a, T>(
+    e: &mut T,
+    register: &Register,
+    policy: &SupervisionPolicy,
+    mut stop: watch::Receiver<bool>,
+) -> Exit
+where
+    T: Executor<'a> + Send + Sync,
+{
+    let group = e.group();
+    let metrics = ExecutorMetrics::new(&group);
+    let mut restarts = 0;
+    loop {
+        let started = Instant::now();
+        let (ctx, handle) = Context::new();
+        let span = tracing::info_span!("executor.run", group = %group, restarts);
+        let run = AssertUnwindSafe(e.start(ctx, register))
+            .catch_unwind()
+            .instrument(span);
+        tokio::pin!(run);
+
+        let result = tokio::select! {
+            result = &mut run => result,
+            _ = stopped(&mut stop) => {
+                // no new work from here on, the caller bounds how long the
+                // executor gets to finish or checkpoint what it holds.
+                handle.cancel();
+                let _ = run.await;
+                return Exit::Stopped;
+            }
+        };
+
+        let reason = match result {
+            Ok(Ok(())) => return Exit::Finished,
+            Ok(Err(err)) => format!("{:?}", err),
+            Err(panic) => format!("panic: {}", panic_message(panic.as_ref())),
+        };
+        metrics.failures.inc();
+
+        if started.elapsed() >= policy.reset_after {
+            restarts = 0;
+        }
+
+        match policy.backoff(restarts) {
+            Some(backoff) => {
+                tracing::warn!(
+                    group = %group,
+                    reason = %reason,
+                    restart = restarts + 1,
+                    backoff = ?backoff,
+                    "backend service failed, restarting"
+                );
+                metrics.set_backoff(backoff);
+                tokio::select! {
+                    _ = tokio::time::sleep(backoff) => {},
+                    _ = stopped(&mut stop) => {
+                        metrics.set_backoff(Duration::ZERO);
+                        return Exit::Stopped;
+                    }
+                }
+                metrics.set_backoff(Duration::ZERO);
+                metrics.restarts.inc();
+                restarts += 1;
+            }
+            None => {
+                tracing::error!(
+                    group = %group,
+                    reason = %reason,
+                    restarts,
+                    "backend service failed, giving up"
+                );
+                return Exit::GaveUp;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_until_capped() {
+        let policy = SupervisionPolicy {
+            max_restarts: Some(8),
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(10),
+            ..Default::default()
+        };
+
+        assert_eq!(policy.backoff(0), Some(Duration::from_secs(1)));
+        assert_eq!(policy.backoff(3), Some(Duration::from_secs(8)));
+        assert_eq!(policy.backoff(4), Some(Duration::from_secs(10)));
+        assert_eq!(policy.backoff(8), None);
+        assert_eq!(SupervisionPolicy::never().backoff(0), None);
+    }
+}
@@ -0,0 +1,347 @@
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    net::{SocketAddr, TcpListener},
+    sync::Arc,
+};
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use tokio_context::context::Context;
+
+use super::{ExecutorMetrics, Job, OneShotFn, OneShotHandler};
+
+// the gateway forwards `/tasks/{group}/...` here untouched.
+pub(crate) const TRIGGER_PATH: &str = "/tasks/";
+
+struct Triggers {
+    group: String,
+    jobs: HashMap<String, Job>,
+    one_shots: HashMap<String, OneShotFn>,
+}
+
+fn json(status: StatusCode, value: serde_json::Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .body(Body::from(value.to_string()))
+        .unwrap()
+}
+
+fn not_found(what: &str) -> Response<Body> {
+    json(
+        StatusCode::NOT_FOUND,
+        serde_json::json!({ "error": format!("{} not found", what) }),
+    )
+}
+
+impl Triggers {
+    fn new(group: &str, jobs: Vec<Job>, one_shots: Vec<OneShotHandler>) -> Self {
+        Self {
+            group: group.to_string(),
+            jobs: jobs
+                .into_iter()
+                .map(|job| (job.name.clone(), job))
+                .collect(),
+            one_shots: one_shots.into_iter().map(|h| (h.name, h.run)).collect(),
+        }
+    }
+
+    fn describe(&self, job: &Job) -> serde_json::Value {
+        serde_json::json!({
+            "name": job.name,
+            "schedule": format!("{:?}", job.schedule),
+            "owner": format!("{:?}", job.owner),
+        })
+    }
+
+    fn inspect(&self) -> Response<Body> {
+        let metrics = ExecutorMetrics::new(&self.group);
+        let mut jobs = self
+            .jobs
+            .values()
+            .map(|job| self.describe(job))
+            .collect::<Vec<_>>();
+        jobs.sort_by_key(|job| job["name"].as_str().unwrap_or_default().to_string());
+        let mut one_shots = self.one_shots.keys().cloned().collect::<Vec<_>>();
+        one_shots.sort();
+
+        json(
+            StatusCode::OK,
+            serde_json::json!({
+                "group": self.group,
+                "jobs": jobs,
+                "one_shots": one_shots,
+                "metrics": {
+                    "iterations": metrics.iterations.get(),
+                    "failures": metrics.failures.get(),
+                    "restarts": metrics.restarts.get(),
+                    "backoff_millis": metrics.backoff.get(),
+                    "members": metrics.members.get(),
+                    "shards": metrics.shards.get(),
+                },
+            }),
+        )
+    }
+
+    // runs the job on this instance right away, ownership is not checked:
+    // an operator trigger is explicit.
+    async fn trigger(&self, name: &str, body: Body) -> Response<Body> {
+        if let Some(job) = self.jobs.get(name) {
+            let run = job.run.clone();
+            let job_name = name.to_string();
+            tokio::spawn(async move {
+                if let Err(e) = run().await {
+                    tracing::error!(job = %job_name, error = ?e, "triggered job failed");
+                }
+            });
+            return json(
+                StatusCode::ACCEPTED,
+                serde_json::json!({ "triggered": name }),
+            );
+        }
+
+        let run = match self.one_shots.get(name) {
+            Some(run) => run.clone(),
+            None => return not_found(name),
+        };
+        let payload = match hyper::body::to_bytes(body).await {
+            Ok(bytes) if bytes.is_empty() => serde_json::Value::Null,
+            Ok(bytes) => match serde_json::from_slice(&bytes) {
+                Ok(payload) => payload,
+                Err(e) => {
+                    return json(
+                        StatusCode::BAD_REQUEST,
+                        serde_json::json!({ "error": e.to_string() }),
+                    )
+                }
+            },
+            Err(e) => {
+                return json(
+                    StatusCode::BAD_REQUEST,
+                    serde_json::json!({ "error": e.to_string() }),
+                )
+            }
+        };
+
+        match run(payload).await {
+            Ok(()) => json(StatusCode::OK, serde_json::json!({ "done": name })),
+            Err(e) => json(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                serde_json::json!({ "error": format!("{:?}", e) }),
+            ),
+        }
+    }
+
+    async fn serve(&self, req: Request<Body>) -> Response<Body> {
+        let path = req.uri().path().to_string();
+        let rest = match path.strip_prefix(TRIGGER_PATH) {
+            Some(rest) => rest.trim_end_matches('/'),
+            None => return not_found(&path),
+        };
+        let (group, name) = match rest.split_once('/') {
+            Some((group, name)) => (group, Some(name)),
+            None => (rest, None),
+        };
+        if group != self.group {
+            return not_found(group);
+        }
+
+        match (req.method().clone(), name) {
+            (Method::GET, None) => self.inspect(),
+            (Method::GET, Some(name)) => match self.jobs.get(name) {
+                Some(job) => json(StatusCode::OK, self.describe(job)),
+                None if self.one_shots.contains_key(name) => json(
+                    StatusCode::OK,
+                    serde_json::json!({ "name": name, "one_shot": true }),
+                ),
+                None => not_found(name),
+            },
+            (Method::POST, Some(name)) => self.trigger(name, req.into_body()).await,
+            _ => json(
+                StatusCode::METHOD_NOT_ALLOWED,
+                serde_json::json!({ "error": "use GET to inspect, POST to trigger" }),
+            ),
+        }
+    }
+}
+
+// the listener for the triggers of `group`, none when `addr` cannot be
+// bound, e.g. another executor in the process already took it.
+pub(crate) fn bind_triggers(group: &str, addr: SocketAddr) -> Option<TcpListener> {
+    let listener = TcpListener::bind(addr).and_then(|listener| {
+        listener.set_nonblocking(true)?;
+        Ok(listener)
+    });
+    match listener {
+        Ok(listener) => Some(listener),
+        Err(e) => {
+            tracing::error!(
+                group,
+                addr = %addr,
+                error = ?e,
+                "backend service trigger bind failed"
+            );
+            None
+        }
+    }
+}
+
+// serves the trigger endpoint until the context is cancelled.
+pub(crate) async fn serve_triggers(
+    mut ctx: Context,
+    listener: TcpListener,
+    group: &str,
+    jobs: Vec<Job>,
+    one_shots: Vec<OneShotHandler>,
+) {
+    let triggers = Arc::new(Triggers::new(group, jobs, one_shots));
+
+    let make_svc = make_service_fn(move |_| {
+        let triggers = triggers.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let triggers = triggers.clone();
+                async move { Ok::<_, Infallible>(triggers.serve(req).await) }
+            }))
+        }
+    });
+
+    let addr = listener.local_addr().ok();
+    let server = match Server::from_tcp(listener) {
+        Ok(server) => server,
+        Err(e) => {
+            tracing::error!(group, error = ?e, "backend service trigger listen failed");
+            ctx.done().await;
+            return;
+        }
+    };
+
+    tracing::info!(group, addr = ?addr, "backend service triggers listening");
+
+    if let Err(e) = server
+        .serve(make_svc)
+        .with_graceful_shutdown(async move { ctx.done().await })
+        .await
+    {
+        tracing::error!(group, error = ?e, "backend service trigger server failed");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::task::Schedule;
+
+    fn triggers(ran: mpsc::UnboundedSender<String>) -> Triggers {
+        let job = Job::new("compact", Schedule::Interval(Duration::from_secs(3600)), {
+            let ran = ran.clone();
+            move || {
+                let ran = ran.clone();
+                async move {
+                    ran.send("compact".to_string())?;
+                    Ok(())
+                }
+            }
+        });
+        let one_shot = OneShotHandler::new("reindex", move |index: String| {
+            let ran = ran.clone();
+            async move {
+                ran.send(index)?;
+                Ok(())
+            }
+        });
+        Triggers::new("jobs", vec![job], vec![one_shot])
+    }
+
+    fn request(method: Method, path: &str, body: &str) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri(path)
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    async fn body_json(resp: Response<Body>) -> serde_json::Value {
+        let bytes = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    #[tokio::test]
+    async fn inspects_the_group() {
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let triggers = triggers(tx);
+
+        let resp = triggers
+            .serve(request(Method::GET, "/tasks/jobs/", ""))
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = body_json(resp).await;
+        assert_eq!(body["group"], "jobs");
+        assert_eq!(body["jobs"][0]["name"], "compact");
+        assert_eq!(body["one_shots"], serde_json::json!(["reindex"]));
+
+        let resp = triggers
+            .serve(request(Method::GET, "/tasks/jobs/reindex", ""))
+            .await;
+        assert_eq!(body_json(resp).await["one_shot"], true);
+    }
+
+    #[tokio::test]
+    async fn triggers_jobs_and_one_shots() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let triggers = triggers(tx);
+
+        let resp = triggers
+            .serve(request(Method::POST, "/tasks/jobs/compact", ""))
+            .await;
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+        assert_eq!(rx.recv().await.unwrap(), "compact");
+
+        let resp = triggers
+            .serve(request(Method::POST, "/tasks/jobs/reindex", "\"users\""))
+            .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(rx.recv().await.unwrap(), "users");
+
+        let resp = triggers
+            .serve(request(Method::POST, "/tasks/jobs/reindex", "{not json"))
+            .await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_other_groups_and_methods() {
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let triggers = triggers(tx);
+
+        let resp = triggers
+            .serve(request(Method::POST, "/tasks/mail/compact", ""))
+            .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let resp = triggers
+            .serve(request(Method::POST, "/tasks/jobs/missing", ""))
+            .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let resp = triggers
+            .serve(request(Method::DELETE, "/tasks/jobs/compact", ""))
+            .await;
+        assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED);
+        let resp = triggers
+            .serve(request(Method::POST, "/tasks/jobs", ""))
+            .await;
+        assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[test]
+    fn a_taken_address_is_not_bound_twice() {
+        let first = bind_triggers("jobs", "127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = first.local_addr().unwrap();
+        assert!(bind_triggers("mail", addr).is_none());
+    }
+}
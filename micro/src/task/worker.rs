@@ -0,0 +1,272 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use futures::future::BoxFuture;
+use tokio::sync::Semaphore;
+use tokio_context::context::Context;
+use tracing::Instrument;
+
+use super::{Executor, ExecutorMetrics, Job, SupervisionPolicy, DEFAULT_DRAIN_TIMEOUT};
+use crate::Register;
+
+const DEFAULT_CONCURRENCY: usize = 1;
+const DEFAULT_IDLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Per-item logic of a backend service; spawning, bounding and draining the
+/// workers is left to the `WorkerExecutor` built around it.
+pub trait Worker: Send + Sync + 'static {
+    type Item: Send + 'static;
+
+    // the next item to work on, None when there is nothing to do right now.
+    fn next(&self) -> BoxFuture<'_, anyhow::Result<Option<Self::Item>>>;
+
+    fn process(&self, item: Self::Item) -> BoxFuture<'_, anyhow::Result<()>>;
+}
+
+pub struct ExecutorBuilder {
+    group: String,
+    concurrency: usize,
+    idle_interval: Duration,
+    weight: u32,
+    drain_timeout: Duration,
+    trigger_addr: Option<SocketAddr>,
+    jobs: Vec<Job>,
+    supervision: SupervisionPolicy,
+}
+
+impl ExecutorBuilder {
+    pub fn new(group: &str) -> Self {
+        Self {
+            group: group.to_string(),
+            concurrency: DEFAULT_CONCURRENCY,
+            idle_interval: DEFAULT_IDLE_INTERVAL,
+            weight: 1,
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            trigger_addr: None,
+            jobs: vec![],
+            supervision: SupervisionPolicy::default(),
+        }
+    }
+
+    // how many items are processed at the same time, at least 1.
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    // how long to wait before asking again after `next` returned None.
+    pub fn idle_interval(mut self, idle_interval: Duration) -> Self {
+        self.idle_interval = idle_interval;
+        self
+    }
+
+    // see `Executor::weight`.
+    pub fn weight(mut self, weight: u32) -> Self {
+        self.weight = weight;
+        self
+    }
+
+    // how long items in flight get to finish on shutdown.
+    pub fn drain_timeout(mut self, drain_timeout: Duration) -> Self {
+        self.drain_timeout = drain_timeout;
+        self
+    }
+
+    // see `Executor::trigger_addr`, instead of the configured one.
+    pub fn trigger_addr(mut self, trigger_addr: SocketAddr) -> Self {
+        self.trigger_addr = Some(trigger_addr);
+        self
+    }
+
+    pub fn job(mut self, job: Job) -> Self {
+        self.jobs.push(job);
+        self
+    }
+
+    pub fn supervision(mut self, supervision: SupervisionPolicy) -> Self {
+        self.supervision = supervision;
+        self
+    }
+
+    pub fn build<W: Worker>(self, worker: W) -> WorkerExecutor<W> {
+        WorkerExecutor {
+            group: self.group,
+            concurrency: self.concurrency,
+            idle_interval: self.idle_interval,
+            weight: self.weight,
+            drain_timeout: self.drain_timeout,
+            trigger_addr: self.trigger_addr,
+            jobs: self.jobs,
+            supervision: self.supervision,
+            worker: Arc::new(worker),
+        }
+    }
+}
+
+pub struct WorkerExecutor<W> {
+    group: String,
+    concurrency: usize,
+    idle_interval: Duration,
+    weight: u32,
+    drain_timeout: Duration,
+    trigger_addr: Option<SocketAddr>,
+    jobs: Vec<Job>,
+    supervision: SupervisionPolicy,
+    worker: Arc<W>,
+}
+
+impl<W: Worker> WorkerExecutor<W> {
+    async fn run(&self, mut ctx: Context) -> anyhow::Result<()> {
+        let permits = Arc::new(Semaphore::new(self.concurrency));
+        let metrics = ExecutorMetrics::new(&self.group);
+
+        loop {
+            let permit = tokio::select! {
+                permit = permits.clone().acquire_owned() => permit?,
+                _ = ctx.done() => break,
+            };
+
+            let item = tokio::select! {
+                item = self.worker.next() => item?,
+                _ = ctx.done() => break,
+            };
+
+            let item = match item {
+                Some(item) => item,
+                None => {
+                    drop(permit);
+                    tokio::select! {
+                        _ = tokio::time::sleep(self.idle_interval) => continue,
+                        _ = ctx.done() => break,
+                    }
+                }
+            };
+
+            let worker = self.worker.clone();
+            let metrics = metrics.clone();
+            let group = self.group.clone();
+            let span = tracing::info_span!("executor.iteration", group = %group);
+            tokio::spawn(
+                async move {
+                    metrics.iterations.inc();
+                    if let Err(e) = worker.process(item).await {
+                        metrics.failures.inc();
+                        tracing::error!(group = %group, error = ?e, "backend service process failed");
+                    }
+                    drop(permit);
+                }
+                .instrument(span),
+            );
+        }
+
+        // stop taking items, but let the ones in flight finish.
+        let _ = permits.acquire_many(self.concurrency as u32).await;
+        Ok(())
+    }
+}
+
+impl<'a, W: Worker> Executor<'a> for WorkerExecutor<W> {
+    fn group(&self) -> String {
+        self.group.clone()
+    }
+
+    fn weight(&self) -> u32 {
+        self.weight
+    }
+
+    fn drain_timeout(&self) -> Duration {
+        self.drain_timeout
+    }
+
+    fn trigger_addr(&self) -> Option<SocketAddr> {
+        self.trigger_addr
+            .or_else(|| crate::config::current().trigger_addr(&self.group))
+    }
+
+    fn jobs(&self) -> Vec<Job> {
+        self.jobs.clone()
+    }
+
+    fn supervision(&self) -> SupervisionPolicy {
+        self.supervision.clone()
+    }
+
+    fn start<'b>(
+        &'b mut self,
+        ctx: Context,
+        _register: &'b Register,
+    ) -> BoxFuture<'b, anyhow::Result<()>>
+    where
+        'a: 'b,
+    {
+        Box::pin(self.run(ctx))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::sync::Notify;
+
+    struct Counting {
+        left: AtomicUsize,
+        running: AtomicUsize,
+        peak: AtomicUsize,
+        done: AtomicUsize,
+        // signalled when `next` runs out of items.
+        exhausted: Notify,
+    }
+
+    impl Worker for Counting {
+        type Item = ();
+
+        fn next(&self) -> BoxFuture<'_, anyhow::Result<Option<()>>> {
+            Box::pin(async move {
+                let left = self.left.load(Ordering::SeqCst);
+                if left == 0 {
+                    self.exhausted.notify_one();
+                    return Ok(None);
+                }
+                self.left.store(left - 1, Ordering::SeqCst);
+                Ok(Some(()))
+            })
+        }
+
+        fn process(&self, _: ()) -> BoxFuture<'_, anyhow::Result<()>> {
+            Box::pin(async move {
+                let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+                self.peak.fetch_max(running, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                self.running.fetch_sub(1, Ordering::SeqCst);
+                self.done.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn bounded_concurrency_and_drain() {
+        let e = ExecutorBuilder::new("worker-test")
+            .concurrency(3)
+            .idle_interval(Duration::from_millis(5))
+            .build(Counting {
+                left: AtomicUsize::new(12),
+                running: AtomicUsize::new(0),
+                peak: AtomicUsize::new(0),
+                done: AtomicUsize::new(0),
+                exhausted: Notify::new(),
+            });
+
+        let (ctx, h) = Context::new();
+        let worker = e.worker.clone();
+        let run = tokio::spawn(async move { e.run(ctx).await });
+
+        // every item is taken, some still in flight: the drain finishes them.
+        worker.exhausted.notified().await;
+        h.cancel();
+        run.await.unwrap().unwrap();
+
+        assert_eq!(worker.done.load(Ordering::SeqCst), 12);
+        assert_eq!(worker.peak.load(Ordering::SeqCst), 3);
+    }
+}
@@ -0,0 +1,152 @@
+use hyper::http::{HeaderMap, HeaderName, HeaderValue};
+use opentelemetry::propagation::{Extractor, Injector};
+use opentelemetry::trace::TraceError;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{propagation::TraceContextPropagator, trace, Resource};
+use tracing::Subscriber;
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::{layer::SubscriberExt, registry::LookupSpan, util::SubscriberInitExt};
+
+use crate::config::TelemetryConfig;
+
+#[derive(Debug, thiserror::Error)]
+pub enum TelemetryError {
+    #[error("start otlp exporter: {0}")]
+    Exporter(#[from] TraceError),
+    #[error("install tracing subscriber: {0}")]
+    Subscriber(#[from] tracing_subscriber::util::TryInitError),
+}
+
+/// Flushes the spans still buffered when dropped, keep it until the process
+/// is about to exit. Processes installing `layer` themselves make one with
+/// `TelemetryGuard::default()`.
+#[must_use]
+#[derive(Debug, Default)]
+pub struct TelemetryGuard(());
+
+impl Drop for TelemetryGuard {
+    fn drop(&mut self) {
+        opentelemetry::global::shutdown_tracer_provider();
+    }
+}
+
+/// A layer exporting the `tracing` spans of the gateway, the plugins and the
+/// executors to `config.otlp_endpoint`, for processes that compose their own
+/// subscriber. None when no endpoint is configured.
+pub fn layer<S>(
+    config: &TelemetryConfig,
+) -> Result<Option<OpenTelemetryLayer<S, trace::Tracer>>, TelemetryError>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    let endpoint = match &config.otlp_endpoint {
+        Some(endpoint) => endpoint,
+        None => return Ok(None),
+    };
+
+    opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(
+            trace::config().with_resource(Resource::new(vec![KeyValue::new(
+                "service.name",
+                service_name(config),
+            )])),
+        )
+        .install_batch(opentelemetry_sdk::runtime::Tokio)?;
+
+    Ok(Some(tracing_opentelemetry::layer().with_tracer(tracer)))
+}
+
+/// Installs a global subscriber with only the OTLP layer, from the config
+/// passed to `config::init`. Must be called inside the tokio runtime.
+pub fn init() -> Result<TelemetryGuard, TelemetryError> {
+    let layer = layer(&crate::config::current().telemetry)?;
+    tracing_subscriber::registry().with(layer).try_init()?;
+    Ok(TelemetryGuard(()))
+}
+
+fn service_name(config: &TelemetryConfig) -> String {
+    if let Some(name) = &config.service_name {
+        return name.clone();
+    }
+    std::env::args()
+        .next()
+        .as_deref()
+        .and_then(|arg0| std::path::Path::new(arg0).file_name())
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "crossgate".to_string())
+}
+
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(key), Ok(value)) = (
+            HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            self.0.insert(key, value);
+        }
+    }
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|k| k.as_str()).collect()
+    }
+}
+
+/// Writes the `traceparent` of the current span, so that the upstream joins
+/// the trace of the request.
+pub fn inject(headers: &mut HeaderMap) {
+    let cx = tracing::Span::current().context();
+    opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&cx, &mut HeaderInjector(headers))
+    });
+}
+
+/// Continues the trace a request arrived with in `span`, e.g. in a web
+/// service behind the gateway.
+pub fn extract(span: &tracing::Span, headers: &HeaderMap) {
+    let cx = opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderExtractor(headers))
+    });
+    span.set_parent(cx);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn traceparent_round_trips() {
+        opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+
+        let mut headers = HeaderMap::new();
+        let traceparent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        headers.insert("traceparent", HeaderValue::from_static(traceparent));
+
+        let cx = opentelemetry::global::get_text_map_propagator(|propagator| {
+            propagator.extract(&HeaderExtractor(&headers))
+        });
+        let mut out = HeaderMap::new();
+        opentelemetry::global::get_text_map_propagator(|propagator| {
+            propagator.inject_context(&cx, &mut HeaderInjector(&mut out))
+        });
+        assert_eq!(out["traceparent"], traceparent);
+    }
+}
@@ -0,0 +1,38 @@
+use super::run_web_service_with;
+use crate::{Service, ServiceError};
+
+/// Serves `router` on `Service::addr` with `run_web_service_with`: bound,
+/// registered once ready and drained gracefully on ctrl-c.
+pub async fn run_axum_service<S: Service>(s: &S, router: axum::Router) -> Result<(), ServiceError> {
+    let mut failed = None;
+    let failure = &mut failed;
+    run_web_service_with(s, |addr, shutdown| async move {
+        let server = match axum::Server::try_bind(&addr) {
+            Ok(server) => server,
+            Err(source) => {
+                *failure = Some(ServiceError::Bind {
+                    addr: addr.to_string(),
+                    source,
+                });
+                return;
+            }
+        };
+
+        if let Err(source) = server
+            .serve(router.into_make_service())
+            .with_graceful_shutdown(shutdown)
+            .await
+        {
+            *failure = Some(ServiceError::Serve {
+                addr: addr.to_string(),
+                source,
+            });
+        }
+    })
+    .await?;
+
+    match failed {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
@@ -0,0 +1,331 @@
+use std::{collections::HashMap, future::Future, net::SocketAddr, sync::Arc, time::Duration};
+
+use futures::future::BoxFuture;
+
+use super::port_bound;
+use crate::{default_strict, AdvertiseAddr, LoadBalancerAlgorithm, Service};
+
+// a readiness or liveness check, see `Service::ready` and `Service::live`.
+pub type ProbeFn = Arc<dyn Fn() -> BoxFuture<'static, bool> + Send + Sync>;
+
+fn probe<F, Fut>(f: F) -> ProbeFn
+where
+    F: Fn() -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = bool> + Send + 'static,
+{
+    Arc::new(move || Box::pin(f()))
+}
+
+/// One name a web service is exposed under. Every name is registered on its
+/// own, with its own load balancing, weight, version and metadata.
+#[derive(Debug, Clone)]
+pub struct ServiceName {
+    pub name: String,
+    // None falls back to `Service::lab`.
+    pub lba: Option<LoadBalancerAlgorithm>,
+    pub weight: u32,
+    pub version: String,
+    pub metadata: HashMap<String, String>,
+}
+
+impl ServiceName {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            lba: None,
+            weight: 1,
+            version: "".to_string(),
+            metadata: HashMap::new(),
+        }
+    }
+
+    pub fn lba(mut self, lba: LoadBalancerAlgorithm) -> Self {
+        self.lba = Some(lba);
+        self
+    }
+
+    pub fn weight(mut self, weight: u32) -> Self {
+        self.weight = weight;
+        self
+    }
+
+    pub fn version(mut self, version: &str) -> Self {
+        self.version = version.to_string();
+        self
+    }
+
+    pub fn metadata(mut self, key: &str, value: &str) -> Self {
+        self.metadata.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    // pins this name alone to `addr`.
+    pub fn strict(self, addr: &str) -> Self {
+        self.lba(LoadBalancerAlgorithm::Strict(addr.to_string()))
+    }
+}
+
+/// Another port of the same process, picked by the gateway for requests of
+/// its protocol, e.g. `grpc`.
+#[derive(Debug, Clone)]
+pub struct ServiceEndpoint {
+    pub name: String,
+    pub protocol: String,
+    pub addr: SocketAddr,
+}
+
+impl ServiceEndpoint {
+    pub fn new(name: &str, protocol: &str, addr: SocketAddr) -> Self {
+        Self {
+            name: name.to_string(),
+            protocol: protocol.to_ascii_lowercase(),
+            addr,
+        }
+    }
+}
+
+/// Set when the service serves https, the gateway then forwards with
+/// certificate verification.
+#[derive(Debug, Clone, Default)]
+pub struct TlsConfig {
+    // name the certificate is issued for, when it is not the advertised host.
+    pub sni: Option<String>,
+}
+
+impl TlsConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn sni(mut self, sni: &str) -> Self {
+        self.sni = Some(sni.to_string());
+        self
+    }
+}
+
+pub struct WebServiceBuilder {
+    addr: SocketAddr,
+    names: Vec<ServiceName>,
+    endpoints: Vec<ServiceEndpoint>,
+    advertise: AdvertiseAddr,
+    tls: Option<TlsConfig>,
+    strict: Option<String>,
+    ready: Option<ProbeFn>,
+    live: Option<ProbeFn>,
+    liveness_interval: Duration,
+}
+
+impl WebServiceBuilder {
+    pub fn new(addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            names: vec![],
+            endpoints: vec![],
+            advertise: AdvertiseAddr::default(),
+            tls: None,
+            strict: None,
+            ready: None,
+            live: None,
+            liveness_interval: Duration::from_secs(5),
+        }
+    }
+
+    // shorthand for `expose(ServiceName::new(name))`.
+    pub fn name(self, name: &str) -> Self {
+        self.expose(ServiceName::new(name))
+    }
+
+    pub fn expose(mut self, name: ServiceName) -> Self {
+        self.names.push(name);
+        self
+    }
+
+    pub fn endpoint(mut self, name: &str, protocol: &str, addr: SocketAddr) -> Self {
+        self.endpoints
+            .push(ServiceEndpoint::new(name, protocol, addr));
+        self
+    }
+
+    pub fn advertise(mut self, advertise: AdvertiseAddr) -> Self {
+        self.advertise = advertise;
+        self
+    }
+
+    pub fn tls(mut self, tls: TlsConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    // pins every name without its own `lba` to `addr`, instead of the
+    // configured lb.strict.
+    pub fn strict(mut self, addr: &str) -> Self {
+        self.strict = Some(addr.to_string());
+        self
+    }
+
+    // checked after the port is bound, e.g. for caches to be warm.
+    pub fn ready<F, Fut>(mut self, f: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = bool> + Send + 'static,
+    {
+        self.ready = Some(probe(f));
+        self
+    }
+
+    pub fn live<F, Fut>(mut self, f: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = bool> + Send + 'static,
+    {
+        self.live = Some(probe(f));
+        self
+    }
+
+    pub fn liveness_interval(mut self, liveness_interval: Duration) -> Self {
+        self.liveness_interval = liveness_interval;
+        self
+    }
+
+    pub fn build(self) -> WebService {
+        WebService {
+            addr: self.addr,
+            names: self.names,
+            endpoints: self.endpoints,
+            advertise: self.advertise,
+            tls: self.tls,
+            strict: self.strict,
+            ready: self.ready,
+            live: self.live,
+            liveness_interval: self.liveness_interval,
+        }
+    }
+}
+
+/// A `Service` made with `WebServiceBuilder`.
+#[derive(Clone)]
+pub struct WebService {
+    addr: SocketAddr,
+    names: Vec<ServiceName>,
+    endpoints: Vec<ServiceEndpoint>,
+    advertise: AdvertiseAddr,
+    tls: Option<TlsConfig>,
+    strict: Option<String>,
+    ready: Option<ProbeFn>,
+    live: Option<ProbeFn>,
+    liveness_interval: Duration,
+}
+
+impl std::fmt::Debug for WebService {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WebService")
+            .field("addr", &self.addr)
+            .field("names", &self.names)
+            .field("endpoints", &self.endpoints)
+            .field("advertise", &self.advertise)
+            .field("tls", &self.tls)
+            .field("strict", &self.strict)
+            .finish()
+    }
+}
+
+impl Service for WebService {
+    fn name(&self) -> String {
+        self.names
+            .iter()
+            .map(|n| n.name.as_str())
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    fn names(&self) -> Vec<ServiceName> {
+        self.names.clone()
+    }
+
+    fn endpoints(&self) -> Vec<ServiceEndpoint> {
+        self.endpoints.clone()
+    }
+
+    fn advertise(&self) -> AdvertiseAddr {
+        self.advertise.clone()
+    }
+
+    fn tls(&self) -> Option<TlsConfig> {
+        self.tls.clone()
+    }
+
+    fn strict_address(&self) -> Option<String> {
+        self.strict.clone().or_else(default_strict)
+    }
+
+    fn ready(&self) -> BoxFuture<'_, bool> {
+        Box::pin(async move {
+            if !port_bound(self.addr).await {
+                return false;
+            }
+            match &self.ready {
+                Some(ready) => ready().await,
+                None => true,
+            }
+        })
+    }
+
+    fn live(&self) -> BoxFuture<'_, bool> {
+        match &self.live {
+            Some(live) => live(),
+            None => Box::pin(async { true }),
+        }
+    }
+
+    fn liveness_interval(&self) -> Duration {
+        self.liveness_interval
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Legacy;
+
+    impl Service for Legacy {
+        fn name(&self) -> String {
+            "user,order".to_string()
+        }
+
+        fn addr(&self) -> SocketAddr {
+            "127.0.0.1:8080".parse().unwrap()
+        }
+    }
+
+    #[test]
+    fn names_from_builder_and_legacy_name() {
+        let s = WebServiceBuilder::new("127.0.0.1:8080".parse().unwrap())
+            .expose(
+                ServiceName::new("user")
+                    .lba(LoadBalancerAlgorithm::Random)
+                    .weight(3)
+                    .version("v2")
+                    .metadata("zone", "a"),
+            )
+            .name("order")
+            .build();
+
+        assert_eq!(s.name(), "user,order");
+        let names = s.names();
+        assert_eq!(names[0].weight, 3);
+        assert_eq!(names[0].version, "v2");
+        assert_eq!(names[0].metadata["zone"], "a");
+        assert!(names[1].lba.is_none());
+
+        let names = Legacy.names();
+        assert_eq!(
+            names.iter().map(|n| n.name.as_str()).collect::<Vec<_>>(),
+            ["user", "order"]
+        );
+    }
+}
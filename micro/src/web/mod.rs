@@ -1,30 +1,268 @@
-use crossbeam::sync::WaitGroup;
+#[cfg(feature = "axum")]
+mod axum;
+mod builder;
+
+#[cfg(feature = "axum")]
+pub use self::axum::run_axum_service;
+pub use builder::{
+    ProbeFn, ServiceEndpoint, ServiceName, TlsConfig, WebService, WebServiceBuilder,
+};
+
+use crate::{Register, Service, ServiceError};
 use futures::future::BoxFuture;
-use plugin::{get_plugin_type, PluginType::Mongodb};
-use std::net::SocketAddr;
-use tokio_context::context::Context;
+use net::{Phase, Shutdown};
+use plugin::{HealthStatus, PluginHandle, ServiceHealth};
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
 
 pub type ServerRunFn = for<'a> fn(addr: &'a SocketAddr) -> BoxFuture<'a, ()>;
 
-pub async fn web_service_run<'a>(addr: &'a SocketAddr, srf: ServerRunFn) {
-    let (ctx, handle) = Context::new();
-    let wg = WaitGroup::new();
-
-    let t = ::std::env::var("REGISTER_TYPE").unwrap_or_else(|_| Mongodb.as_str().into());
+const READY_POLL_INTERVAL: Duration = Duration::from_millis(500);
 
-    plugin::init_plugin(
-        ctx,
-        wg.clone(),
+async fn init_plugin(shutdown: Shutdown) -> Result<PluginHandle, ServiceError> {
+    Ok(plugin::init_plugin_with(
+        shutdown,
         plugin::ServiceType::WebService,
-        get_plugin_type(&t),
+        crate::config::current().plugin_config(),
     )
-    .await;
-
-    tokio::select! {
-        _ = srf(addr) => {},
-        _ = tokio::signal::ctrl_c() => {
-            handle.cancel();
-            wg.wait();
-        },
+    .await?)
+}
+
+pub async fn web_service_run(
+    addr: &SocketAddr,
+    srf: ServerRunFn,
+) -> Result<(), ServiceError> {
+    let shutdown = Shutdown::new();
+
+    init_plugin(shutdown.clone()).await?;
+
+    let serve = async {
+        tokio::select! {
+            // the server is gone, withdraw the registration too.
+            _ = srf(addr) => shutdown.trigger(),
+            _ = shutdown.reached(Phase::StopAccepting) => {},
+        }
+    };
+    tokio::join!(serve, shutdown.run());
+    Ok(())
+}
+
+// true once something accepts connections on the port of `addr`.
+pub(crate) async fn port_bound(addr: SocketAddr) -> bool {
+    let mut addr = addr;
+    match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => addr.set_ip(Ipv4Addr::LOCALHOST.into()),
+        IpAddr::V6(ip) if ip.is_unspecified() => addr.set_ip(Ipv6Addr::LOCALHOST.into()),
+        _ => {}
+    }
+    tokio::net::TcpStream::connect(addr).await.is_ok()
+}
+
+fn health(status: HealthStatus, detail: &str) -> ServiceHealth {
+    ServiceHealth {
+        status,
+        lag: 0,
+        last_success: 0,
+        detail: detail.into(),
+    }
+}
+
+// registers `s` once it is ready, then follows its liveness and the
+// registry errors until the process ends.
+async fn register_when_ready<S: Service>(s: &S, registered: &AtomicBool, r: &Register) {
+    while !s.ready().await {
+        tokio::time::sleep(READY_POLL_INTERVAL).await;
+    }
+
+    // registered with the workload identity, once there is one.
+    #[cfg(feature = "spiffe")]
+    let svids = match crate::spiffe::start() {
+        true => {
+            let mut svids = crate::spiffe::watch_svid();
+            let _ = svids.wait_for(Option::is_some).await;
+            Some(svids)
+        }
+        false => None,
+    };
+
+    let errors = match r.plugin() {
+        Ok(plugin) => plugin.watch_registry_errors(),
+        Err(_) => plugin::watch_registry_errors(),
+    };
+    if let Err(e) = r.register_web_service(s).await {
+        tracing::error!(service = %s.name(), error = ?e, "register service failed");
+        s.on_registry_error(format!("{:?}", e)).await;
+        return;
+    }
+    registered.store(true, Ordering::SeqCst);
+    tracing::info!(service = %s.name(), "web service ready, registered");
+    s.on_registered().await;
+
+    #[cfg(feature = "spiffe")]
+    let identity = follow_identity(s, r, svids);
+    #[cfg(not(feature = "spiffe"))]
+    let identity = async {};
+    tokio::join!(
+        follow_liveness(s, r),
+        follow_registry_errors(s, errors),
+        identity
+    );
+}
+
+// signs the registrations again with each rotated svid, the gateway stops
+// trusting them once the previous one expires.
+#[cfg(feature = "spiffe")]
+async fn follow_identity<S: Service>(
+    s: &S,
+    r: &Register,
+    svids: Option<tokio::sync::watch::Receiver<Option<std::sync::Arc<crate::Svid>>>>,
+) {
+    let mut svids = match svids {
+        Some(svids) => svids,
+        None => return,
+    };
+    while svids.changed().await.is_ok() {
+        if let Err(e) = r.refresh_identity(s).await {
+            tracing::error!(service = %s.name(), error = ?e, "refresh registration identity failed");
+            s.on_registry_error(format!("{:?}", e)).await;
+        }
+    }
+}
+
+async fn follow_liveness<S: Service>(s: &S, r: &Register) {
+    let mut live = true;
+    loop {
+        tokio::time::sleep(s.liveness_interval()).await;
+        // while draining the register holds the result back until draining
+        // is turned off.
+        if s.live().await == live {
+            continue;
+        }
+        live = !live;
+
+        // the gateway skips unhealthy instances, so this withdraws the
+        // registration without losing it.
+        let status = match live {
+            true => health(HealthStatus::Healthy, "live"),
+            false => health(HealthStatus::Unhealthy, "liveness check failed"),
+        };
+        tracing::warn!(
+            service = %s.name(),
+            status = ?status.status,
+            "web service liveness changed"
+        );
+        for name in s.names() {
+            if let Err(e) = r.report_health(&name.name, status.clone()).await {
+                tracing::error!(service = %name.name, error = ?e, "report service health failed");
+                s.on_registry_error(format!("{:?}", e)).await;
+            }
+        }
+    }
+}
+
+async fn follow_registry_errors<S: Service>(s: &S, mut errors: broadcast::Receiver<String>) {
+    loop {
+        match errors.recv().await {
+            Ok(error) => s.on_registry_error(error).await,
+            Err(RecvError::Lagged(n)) => {
+                s.on_registry_error(format!("{} registry errors missed", n))
+                    .await
+            }
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
+
+/// Like `web_service_run`, but registers `s` itself once `Service::ready`
+/// says so, and withdraws it while `Service::live` fails. Only a plugin that
+/// cannot start fails it, registry errors go to `Service::on_registry_error`.
+pub async fn run_web_service<S: Service>(s: &S, srf: ServerRunFn) -> Result<(), ServiceError> {
+    run_web_service_with(s, |addr, shutdown| async move {
+        tokio::select! {
+            _ = srf(&addr) => {},
+            _ = shutdown => {},
+        }
+    })
+    .await
+}
+
+/// `run_web_service` for servers that do not fit a `ServerRunFn`. `serve`
+/// gets the address to bind and a future that resolves once the instance is
+/// marked draining on ctrl-c, after which it should finish the requests in
+/// flight and return. The registration is withdrawn after that.
+pub async fn run_web_service_with<S, F, Fut>(s: &S, serve: F) -> Result<(), ServiceError>
+where
+    S: Service,
+    F: FnOnce(SocketAddr, BoxFuture<'static, ()>) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let shutdown = Shutdown::new();
+
+    let r = Register::new(init_plugin(shutdown.clone()).await?);
+
+    let registered = AtomicBool::new(false);
+    let stopping = shutdown.guard(Phase::StopAccepting);
+    let draining = shutdown.guard(Phase::Drain);
+    let stop = shutdown.clone();
+    let server = serve(
+        s.addr(),
+        Box::pin(async move { stop.reached(Phase::Drain).await }),
+    );
+
+    let lifecycle = async {
+        tokio::pin!(server);
+        let stopped = tokio::select! {
+            _ = &mut server => {
+                shutdown.trigger();
+                true
+            },
+            _ = async {
+                register_when_ready(s, &registered, &r).await;
+                futures::future::pending::<()>().await
+            } => false,
+            _ = shutdown.reached(Phase::StopAccepting) => false,
+        };
+
+        // marked draining first so the gateway stops sending, then the
+        // server drains.
+        if registered.load(Ordering::SeqCst) {
+            if let Err(e) = r.set_draining(true).await {
+                tracing::error!(service = %s.name(), error = ?e, "mark service draining failed");
+            }
+        }
+        drop(stopping);
+        if !stopped {
+            tokio::select! {
+                _ = server => {},
+                _ = shutdown.reached(Phase::Deregister) => {},
+            }
+        }
+        drop(draining);
+
+        shutdown.reached(Phase::Exit).await;
+        if registered.load(Ordering::SeqCst) {
+            s.on_deregistered().await;
+        }
+    };
+    tokio::join!(lifecycle, shutdown.run());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn port_bound_follows_listener() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let addr = SocketAddr::from(([0, 0, 0, 0], port));
+
+        assert!(port_bound(addr).await);
+        drop(listener);
+        assert!(!port_bound(addr).await);
     }
 }
@@ -0,0 +1,79 @@
+use std::convert::Infallible;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server};
+use net::proxied_request;
+
+const CLIENT_IP: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 7));
+
+// what a browser behind another proxy sends.
+fn request(uri: &str) -> Request<Body> {
+    Request::get(uri)
+        .header("host", "gateway.example.com")
+        .header("user-agent", "Mozilla/5.0 (X11; Linux x86_64)")
+        .header("accept", "application/json")
+        .header("accept-encoding", "gzip, deflate, br")
+        .header("cookie", "session=0123456789abcdef")
+        .header("x-request-id", "4bf92f3577b34da6")
+        .header("x-forwarded-for", "203.0.113.9")
+        .header("connection", "keep-alive")
+        .body(Body::empty())
+        .unwrap()
+}
+
+// the rewrite of every forwarded request, onto a bare upstream address as
+// the gateway does and onto one with a path and query to merge.
+fn rewrite(c: &mut Criterion) {
+    let mut group = c.benchmark_group("rewrite");
+    group.bench_function("authority", |b| {
+        b.iter_batched(
+            || request("/t/ums/user/1?fields=name,email"),
+            |req| proxied_request(CLIENT_IP, "http://10.0.0.2:8080", req).unwrap(),
+            BatchSize::SmallInput,
+        )
+    });
+    group.bench_function("merged_query", |b| {
+        b.iter_batched(
+            || request("/t/ums/user/1?fields=name,email"),
+            |req| proxied_request(CLIENT_IP, "http://10.0.0.2:8080/v2?tenant=a", req).unwrap(),
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+// requests through the shared proxy client to an upstream on loopback.
+fn round_trip(c: &mut Criterion) {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let upstream = rt.block_on(async {
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service_fn(
+            |_| async {
+                Ok::<_, Infallible>(service_fn(|_| async {
+                    Ok::<_, Infallible>(Response::new(Body::from("ok")))
+                }))
+            },
+        ));
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        format!("http://{}", addr)
+    });
+    let proxy = net::get_proxy_client();
+
+    let mut group = c.benchmark_group("round_trip");
+    group.throughput(Throughput::Elements(1));
+    group.bench_function("get", |b| {
+        b.to_async(&rt).iter(|| async {
+            let res = proxy
+                .call(CLIENT_IP, &upstream, request("/t/ums/user/1"))
+                .await
+                .unwrap();
+            hyper::body::to_bytes(res.into_body()).await.unwrap()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, rewrite, round_trip);
+criterion_main!(benches);
@@ -0,0 +1,35 @@
+use lazy_static::lazy_static;
+use prometheus::{core::Collector, HistogramOpts, HistogramVec, IntCounterVec, Opts};
+
+// published in the process-wide prometheus registry, next to the ones of
+// micro and plugin.
+fn register<C: Collector + Clone + 'static>(c: C) -> C {
+    if let Err(e) = prometheus::default_registry().register(Box::new(c.clone())) {
+        tracing::error!(error = ?e, "register proxy metrics failed");
+    }
+    c
+}
+
+lazy_static! {
+    // by upstream status code, `error` when there was no response.
+    pub(crate) static ref UPSTREAM_REQUESTS: IntCounterVec = register(
+        IntCounterVec::new(
+            Opts::new(
+                "crossgate_proxy_upstream_requests_total",
+                "Requests forwarded to upstreams",
+            ),
+            &["status"],
+        )
+        .unwrap()
+    );
+    pub(crate) static ref UPSTREAM_SECONDS: HistogramVec = register(
+        HistogramVec::new(
+            HistogramOpts::new(
+                "crossgate_proxy_upstream_duration_seconds",
+                "Time until the upstream response headers arrived",
+            ),
+            &["status"],
+        )
+        .unwrap()
+    );
+}
@@ -1,17 +1,59 @@
+mod metrics;
+mod mtls;
 mod proxy;
-pub use proxy::{call, ProxyError, ReverseProxy};
+pub use mtls::{mtls_proxy_client, MtlsError, MtlsIdentity};
+pub use proxy::{call, proxied_request, ProxyError, ReverseProxy};
 
 use hyper::client::HttpConnector;
 
 use hyper::Client;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+pub type HttpsConnector = hyper_rustls::HttpsConnector<HttpConnector>;
 
 #[inline]
 pub fn get_proxy_client() -> &'static ReverseProxy<HttpConnector> {
     &CLIENT
 }
 
+// for backends serving https, verified against the system roots. `sni` is
+// the name the certificate is checked against, by default the host of the
+// forward uri.
+pub fn get_tls_proxy_client(sni: Option<&str>) -> ReverseProxy<HttpsConnector> {
+    let mut clients = TLS_CLIENTS.lock().unwrap();
+    if let Some(client) = clients.get(sni.unwrap_or_default()) {
+        return client.clone();
+    }
+
+    let builder = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_native_roots()
+        .https_only();
+    let builder = match sni {
+        Some(sni) => builder.with_server_name(sni.to_string()),
+        None => builder,
+    };
+    let mut http = connector();
+    http.enforce_http(false);
+    let client =
+        ReverseProxy::new(Client::builder().build(builder.enable_http1().wrap_connector(http)));
+    clients.insert(sni.unwrap_or_default().to_string(), client.clone());
+    client
+}
+
+// requests are small writes on kept-alive connections, Nagle would hold them
+// back until the previous one is acked.
+fn connector() -> HttpConnector {
+    let mut connector = HttpConnector::new();
+    connector.set_nodelay(true);
+    connector
+}
+
 use lazy_static::lazy_static;
 
 lazy_static! {
-    static ref CLIENT: ReverseProxy<HttpConnector> = ReverseProxy::new(Client::new());
+    static ref CLIENT: ReverseProxy<HttpConnector> =
+        ReverseProxy::new(Client::builder().build(connector()));
+    static ref TLS_CLIENTS: Mutex<HashMap<String, ReverseProxy<HttpsConnector>>> =
+        Mutex::new(HashMap::new());
 }
@@ -1,13 +1,17 @@
 use hyper::client::connect::Connect;
 use hyper::header::{HeaderMap, HeaderName, HeaderValue, HOST};
 use hyper::http::header::{InvalidHeaderValue, ToStrError};
-use hyper::http::uri::InvalidUri;
+use hyper::http::uri::{InvalidUri, InvalidUriParts, Parts, PathAndQuery, Scheme};
 use hyper::upgrade::OnUpgrade;
-use hyper::{body::Body, Client, Error, Request, Response, StatusCode};
+use hyper::{body::Body, Client, Error, Request, Response, StatusCode, Uri};
 use lazy_static::lazy_static;
+use std::fmt::Write;
 use std::net::IpAddr;
+use std::time::Instant;
 use tokio::io::copy_bidirectional;
 
+use super::metrics;
+
 lazy_static! {
     static ref TE_HEADER: HeaderName = HeaderName::from_static("te");
     static ref CONNECTION_HEADER: HeaderName = HeaderName::from_static("connection");
@@ -30,24 +34,22 @@ lazy_static! {
     static ref X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
 }
 
-#[derive(Debug)]
+#[derive(Debug, thiserror::Error)]
 pub enum ProxyError {
-    InvalidUri(InvalidUri),
-    HyperError(Error),
+    #[error("invalid forward uri: {0}")]
+    InvalidUri(#[from] InvalidUri),
+    #[error("invalid forward uri: {0}")]
+    InvalidUriParts(#[from] InvalidUriParts),
+    #[error("backend request failed: {0}")]
+    HyperError(#[from] Error),
+    #[error("invalid forwarding header")]
     ForwardHeaderError,
+    #[error("upgrade failed: {0}")]
     UpgradeError(String),
-}
-
-impl From<Error> for ProxyError {
-    fn from(err: Error) -> ProxyError {
-        ProxyError::HyperError(err)
-    }
-}
-
-impl From<InvalidUri> for ProxyError {
-    fn from(err: InvalidUri) -> ProxyError {
-        ProxyError::InvalidUri(err)
-    }
+    #[error("forward uri {0} has no host")]
+    MissingHost(String),
+    #[error("connect failed: {0}")]
+    Connect(#[from] std::io::Error),
 }
 
 impl From<ToStrError> for ProxyError {
@@ -69,93 +71,94 @@ fn remove_hop_headers(headers: &mut HeaderMap) {
 }
 
 fn get_upgrade_type(headers: &HeaderMap) -> Option<String> {
-    #[allow(clippy::blocks_in_if_conditions)]
-    if headers
+    // headers that are not visible ascii mean no upgrade.
+    let upgrade = headers
         .get(&*CONNECTION_HEADER)
-        .map(|value| {
-            value
-                .to_str()
-                .unwrap()
-                .split(',')
-                .any(|e| e.trim() == *UPGRADE_HEADER)
-        })
-        .unwrap_or(false)
-    {
-        if let Some(upgrade_value) = headers.get(&*UPGRADE_HEADER) {
-            return Some(upgrade_value.to_str().unwrap().to_owned());
-        }
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.split(',').any(|e| e.trim() == *UPGRADE_HEADER));
+    if !upgrade {
+        return None;
     }
 
-    None
+    headers
+        .get(&*UPGRADE_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_owned())
 }
 
+// `connection` itself is a hop header, taking it out spares a copy; run it
+// before `remove_hop_headers`.
 fn remove_connection_headers(headers: &mut HeaderMap) {
-    if headers.get(&*CONNECTION_HEADER).is_some() {
-        let value = headers.get(&*CONNECTION_HEADER).cloned().unwrap();
-
-        for name in value.to_str().unwrap().split(',') {
-            if !name.trim().is_empty() {
-                headers.remove(name.trim());
-            }
+    let value = match headers.remove(&*CONNECTION_HEADER) {
+        Some(value) => value,
+        None => return,
+    };
+
+    for name in value.to_str().unwrap_or_default().split(',') {
+        if !name.trim().is_empty() {
+            headers.remove(name.trim());
         }
     }
 }
 
 fn create_proxied_response<B>(mut response: Response<B>) -> Response<B> {
-    remove_hop_headers(response.headers_mut());
     remove_connection_headers(response.headers_mut());
+    remove_hop_headers(response.headers_mut());
 
     response
 }
 
-fn forward_uri<B>(forward_url: &str, req: &Request<B>) -> String {
-    let split_url = forward_url.split('?').collect::<Vec<&str>>();
-
-    let mut base_url: &str = split_url.get(0).unwrap_or(&"");
-    let forward_url_query: &str = split_url.get(1).unwrap_or(&"");
-
-    let path2 = req.uri().path();
-
-    if base_url.ends_with('/') {
-        let mut path1_chars = base_url.chars();
-        path1_chars.next_back();
-
-        base_url = path1_chars.as_str();
+// `forward_url` with the path and query of `req`. The gateway forwards onto
+// bare `scheme://authority` addresses, those reuse the request's path and
+// query as they are; a forward url with a path or query of its own is merged
+// into a new string.
+fn forward_uri<B>(forward_url: &str, req: &Request<B>) -> Result<Uri, ProxyError> {
+    let (base_url, forward_url_query) = forward_url.split_once('?').unwrap_or((forward_url, ""));
+    let base_url = base_url.strip_suffix('/').unwrap_or(base_url);
+    let request_query = req.uri().query().unwrap_or("");
+
+    let path_and_query = req.uri().path_and_query();
+    // an empty query is dropped below, so such requests take the slow path.
+    let empty_query = path_and_query.is_some_and(|p| p.as_str().ends_with('?'));
+    if let Some((scheme, authority)) = base_url.split_once("://") {
+        if !authority.contains('/') && forward_url_query.is_empty() && !empty_query {
+            let mut parts = Parts::default();
+            parts.scheme = Some(match scheme {
+                "http" => Scheme::HTTP,
+                "https" => Scheme::HTTPS,
+                scheme => scheme.parse()?,
+            });
+            parts.authority = Some(authority.parse()?);
+            parts.path_and_query = Some(
+                path_and_query
+                    .cloned()
+                    .unwrap_or_else(|| PathAndQuery::from_static("/")),
+            );
+            return Ok(Uri::from_parts(parts)?);
+        }
     }
 
-    let total_length = base_url.len()
-        + path2.len()
-        + 1
-        + forward_url_query.len()
-        + req.uri().query().map(|e| e.len()).unwrap_or(0);
-
-    let mut url = String::with_capacity(total_length);
-
+    let mut url = String::with_capacity(
+        base_url.len() + req.uri().path().len() + 2 + forward_url_query.len() + request_query.len(),
+    );
     url.push_str(base_url);
-    url.push_str(path2);
+    url.push_str(req.uri().path());
 
-    if !forward_url_query.is_empty() || req.uri().query().map(|e| !e.is_empty()).unwrap_or(false) {
+    if !forward_url_query.is_empty() || !request_query.is_empty() {
         url.push('?');
         url.push_str(forward_url_query);
 
         if forward_url_query.is_empty() {
-            url.push_str(req.uri().query().unwrap_or(""));
+            url.push_str(request_query);
         } else {
-            let request_query_items = req.uri().query().unwrap_or("").split('&').map(|el| {
-                let parts = el.split('=').collect::<Vec<&str>>();
-                (parts[0], if parts.len() > 1 { parts[1] } else { "" })
-            });
-
-            let forward_query_items = forward_url_query
-                .split('&')
-                .map(|el| {
-                    let parts = el.split('=').collect::<Vec<&str>>();
-                    parts[0]
-                })
-                .collect::<Vec<_>>();
-
-            for (key, value) in request_query_items {
-                if !forward_query_items.iter().any(|e| e == &key) {
+            // the keys of the forward url win over those of the request.
+            for item in request_query.split('&').filter(|item| !item.is_empty()) {
+                let mut kv = item.split('=');
+                let (key, value) = (kv.next().unwrap_or(""), kv.next().unwrap_or(""));
+                if !forward_url_query
+                    .split('&')
+                    .any(|e| e.split('=').next() == Some(key))
+                {
                     url.push('&');
                     url.push_str(key);
                     url.push('=');
@@ -164,18 +167,15 @@ fn forward_uri<B>(forward_url: &str, req: &Request<B>) -> String {
             }
 
             if url.ends_with('&') {
-                let mut parts = url.chars();
-                parts.next_back();
-
-                url = parts.as_str().to_string();
+                url.pop();
             }
         }
     }
 
-    url.parse().unwrap()
+    Ok(url.parse()?)
 }
 
-async fn create_proxied_request<B>(
+fn create_proxied_request<B>(
     client_ip: IpAddr,
     forward_url: &str,
     mut request: Request<B>,
@@ -184,25 +184,22 @@ async fn create_proxied_request<B>(
     let contains_te_trailers_value = request
         .headers()
         .get(&*TE_HEADER)
-        .map(|value| {
-            value
-                .to_str()
-                .unwrap()
-                .split(',')
-                .any(|e| e.trim() == *TRAILERS_HEADER)
-        })
-        .unwrap_or(false);
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.split(',').any(|e| e.trim() == *TRAILERS_HEADER));
 
-    let uri: hyper::Uri = forward_uri(forward_url, &request).parse()?;
+    let uri = forward_uri(forward_url, &request)?;
 
+    let host = uri
+        .host()
+        .ok_or_else(|| ProxyError::MissingHost(uri.to_string()))?;
     request
         .headers_mut()
-        .insert(HOST, HeaderValue::from_str(uri.host().unwrap())?);
+        .insert(HOST, HeaderValue::from_str(host)?);
 
     *request.uri_mut() = uri;
 
-    remove_hop_headers(request.headers_mut());
     remove_connection_headers(request.headers_mut());
+    remove_hop_headers(request.headers_mut());
 
     if contains_te_trailers_value {
         request
@@ -213,7 +210,7 @@ async fn create_proxied_request<B>(
     if let Some(value) = upgrade_type {
         request
             .headers_mut()
-            .insert(&*UPGRADE_HEADER, value.parse().unwrap());
+            .insert(&*UPGRADE_HEADER, value.parse()?);
         request
             .headers_mut()
             .insert(&*CONNECTION_HEADER, HeaderValue::from_static("UPGRADE"));
@@ -222,29 +219,68 @@ async fn create_proxied_request<B>(
     // Add forwarding information in the headers
     match request.headers_mut().entry(&*X_FORWARDED_FOR) {
         hyper::header::Entry::Vacant(entry) => {
-            entry.insert(client_ip.to_string().parse()?);
+            entry.insert(HeaderValue::try_from(client_ip.to_string())?);
         }
 
-        hyper::header::Entry::Occupied(entry) => {
-            let client_ip_str = client_ip.to_string();
-            let mut addr =
-                String::with_capacity(entry.get().as_bytes().len() + 2 + client_ip_str.len());
-
-            addr.push_str(std::str::from_utf8(entry.get().as_bytes()).unwrap());
-            addr.push(',');
-            addr.push(' ');
-            addr.push_str(&client_ip_str);
+        hyper::header::Entry::Occupied(mut entry) => {
+            // room for ", " and an ipv6 address.
+            let mut addr = String::with_capacity(entry.get().len() + 2 + 39);
+            addr.push_str(entry.get().to_str()?);
+            let _ = write!(addr, ", {}", client_ip);
+            entry.insert(HeaderValue::try_from(addr)?);
         }
     }
 
     Ok(request)
 }
 
-pub async fn call<'a, T: Connect + Clone + Send + Sync + 'static>(
+/// The request `call` sends upstream: its uri moved onto `forward_uri`, the
+/// hop-by-hop headers removed and `client_ip` added to x-forwarded-for. For
+/// callers sending it with a client of their own.
+pub fn proxied_request<B>(
+    client_ip: IpAddr,
+    forward_uri: &str,
+    request: Request<B>,
+) -> Result<Request<B>, ProxyError> {
+    let upgrade_type = get_upgrade_type(request.headers());
+    create_proxied_request(client_ip, forward_uri, request, upgrade_type.as_ref())
+}
+
+#[tracing::instrument(
+    name = "proxy.upstream",
+    skip(request, client),
+    fields(method = %request.method(), status),
+    err
+)]
+pub async fn call<T: Connect + Clone + Send + Sync + 'static>(
+    client_ip: IpAddr,
+    forward_uri: &str,
+    request: Request<Body>,
+    client: &Client<T>,
+) -> anyhow::Result<Response<Body>, ProxyError> {
+    let started = Instant::now();
+    let res = forward(client_ip, forward_uri, request, client).await;
+
+    let code = res.as_ref().map(|response| response.status());
+    let status = match &code {
+        Ok(code) => code.as_str(),
+        Err(_) => "error",
+    };
+    tracing::Span::current().record("status", status);
+    metrics::UPSTREAM_REQUESTS
+        .with_label_values(&[status])
+        .inc();
+    metrics::UPSTREAM_SECONDS
+        .with_label_values(&[status])
+        .observe(started.elapsed().as_secs_f64());
+    res
+}
+
+async fn forward<T: Connect + Clone + Send + Sync + 'static>(
     client_ip: IpAddr,
     forward_uri: &str,
     mut request: Request<Body>,
-    client: &'a Client<T>,
+    client: &Client<T>,
 ) -> anyhow::Result<Response<Body>, ProxyError> {
     let request_upgrade_type = get_upgrade_type(request.headers());
     let request_upgraded = request.extensions_mut().remove::<OnUpgrade>();
@@ -254,8 +290,7 @@ pub async fn call<'a, T: Connect + Clone + Send + Sync + 'static>(
         forward_uri,
         request,
         request_upgrade_type.as_ref(),
-    )
-    .await?;
+    )?;
 
     let mut response = client.request(proxied_request).await?;
 
@@ -267,16 +302,29 @@ pub async fn call<'a, T: Connect + Clone + Send + Sync + 'static>(
                 let mut response_upgraded = response
                     .extensions_mut()
                     .remove::<OnUpgrade>()
-                    .expect("response does not have an upgrade extension")
+                    .ok_or_else(|| {
+                        ProxyError::UpgradeError(
+                            "response does not have an upgrade extension".to_string(),
+                        )
+                    })?
                     .await?;
 
+                // the client already has its response, failures can only be
+                // logged.
                 tokio::spawn(async move {
-                    let mut request_upgraded =
-                        request_upgraded.await.expect("failed to upgrade request");
-
-                    copy_bidirectional(&mut response_upgraded, &mut request_upgraded)
-                        .await
-                        .expect("coping between upgraded connections failed");
+                    let mut request_upgraded = match request_upgraded.await {
+                        Ok(upgraded) => upgraded,
+                        Err(e) => {
+                            tracing::error!(error = %e, "failed to upgrade request");
+                            return;
+                        }
+                    };
+
+                    if let Err(e) =
+                        copy_bidirectional(&mut response_upgraded, &mut request_upgraded).await
+                    {
+                        tracing::warn!(error = %e, "copying between upgraded connections failed");
+                    }
                 });
 
                 Ok(response)
@@ -306,6 +354,11 @@ impl<T: Connect + Clone + Send + Sync + 'static> ReverseProxy<T> {
         Self { client }
     }
 
+    // the client underneath, for requests that are not proxied.
+    pub fn client(&self) -> &Client<T> {
+        &self.client
+    }
+
     pub async fn call(
         &self,
         client_ip: IpAddr,
@@ -315,3 +368,45 @@ impl<T: Connect + Clone + Send + Sync + 'static> ReverseProxy<T> {
         call::<T>(client_ip, forward_uri, request, &self.client).await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(uri: &str) -> Request<()> {
+        Request::get(uri)
+            .header("connection", "keep-alive, x-hop")
+            .header("x-hop", "1")
+            .header("x-forwarded-for", "203.0.113.9")
+            .body(())
+            .unwrap()
+    }
+
+    #[test]
+    fn rewrites_onto_forward_url() {
+        let ip = IpAddr::from([10, 0, 0, 7]);
+        for (forward, uri, want) in [
+            (
+                "http://10.0.0.2:8080",
+                "/a/b?x=1",
+                "http://10.0.0.2:8080/a/b?x=1",
+            ),
+            ("http://10.0.0.2:8080/", "/a?", "http://10.0.0.2:8080/a"),
+            (
+                "http://h/v2?x=2&t=a",
+                "/a?x=1&y=3",
+                "http://h/v2/a?x=2&t=a&y=3",
+            ),
+            ("http://h?t=a", "/a", "http://h/a?t=a"),
+        ] {
+            let req = proxied_request(ip, forward, request(uri)).unwrap();
+            assert_eq!(req.uri(), want);
+        }
+
+        let req = proxied_request(ip, "http://h:80", request("/")).unwrap();
+        assert_eq!(req.headers()[HOST], "h");
+        assert_eq!(req.headers()[&*X_FORWARDED_FOR], "203.0.113.9, 10.0.0.7");
+        assert!(!req.headers().contains_key("x-hop"));
+        assert!(!req.headers().contains_key(&*CONNECTION_HEADER));
+    }
+}
@@ -1,26 +1,20 @@
-#![feature(type_alias_impl_trait)]
-
 pub mod http;
+#[cfg(feature = "quic")]
+pub mod quic;
+pub mod shutdown;
 pub mod tcp;
+pub mod udp;
 
 pub use http::*;
+pub use shutdown::{Phase, Shutdown, ShutdownGuard};
 pub use tcp::*;
 
-use std::error::Error;
-
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, thiserror::Error)]
 pub enum NetError {
+    #[error("internal error: {0}")]
     InternalError(String),
 }
 
-impl Error for NetError {}
-
-impl std::fmt::Display for NetError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "NetError")
-    }
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -0,0 +1,349 @@
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures::Future;
+use quinn::{Connecting, Endpoint, RecvStream, SendStream};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::{broadcast, mpsc, Semaphore};
+
+use crate::{Connection, Handle, Handler, Phase, ServerConfig, Shutdown};
+
+/// One bidirectional QUIC stream, wrapped so that it can back a `Connection`.
+#[derive(Debug)]
+pub struct QuicStream {
+    send: SendStream,
+    recv: RecvStream,
+}
+
+impl QuicStream {
+    pub fn new(send: SendStream, recv: RecvStream) -> Self {
+        Self { send, recv }
+    }
+}
+
+impl AsyncRead for QuicStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.recv).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for QuicStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.send)
+            .poll_write(cx, buf)
+            .map_err(std::io::Error::from)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.send).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.send).poll_shutdown(cx)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct QuicConfig {
+    // `max_connections` bounds the streams served at once, `idle_timeout`,
+    // `max_buffer_size`, `heartbeat` and `rate_limit` apply per stream.
+    pub server: ServerConfig,
+    // serve streams before the handshake completes. 0-RTT data can be
+    // replayed by an attacker, so only enable it for idempotent protocols.
+    pub accept_0rtt: bool,
+}
+
+/// Serves every bidirectional stream opened by a peer with `h`, the same
+/// way `tcp::run` serves every accepted socket. The endpoint carries the tls
+/// configuration, 0-RTT data is refused unless `QuicConfig::accept_0rtt` is set.
+pub async fn run(endpoint: Endpoint, h: impl Handle, shutdown: impl Future) {
+    run_with_config(endpoint, h, shutdown, QuicConfig::default()).await
+}
+
+// like `tcp::run_with_shutdown`.
+pub async fn run_with_shutdown(
+    endpoint: Endpoint,
+    h: impl Handle,
+    shutdown: &Shutdown,
+    config: QuicConfig,
+) {
+    let _draining = shutdown.guard(Phase::Drain);
+    let config = QuicConfig {
+        server: ServerConfig {
+            drain_timeout: shutdown.phase_deadline(Phase::Drain),
+            ..config.server
+        },
+        ..config
+    };
+    run_with_config(endpoint, h, shutdown.reached(Phase::StopAccepting), config).await
+}
+
+pub async fn run_with_config(
+    endpoint: Endpoint,
+    h: impl Handle,
+    shutdown: impl Future,
+    config: QuicConfig,
+) {
+    let QuicConfig {
+        server: config,
+        accept_0rtt,
+    } = config;
+    let (notify_shutdown, _) = broadcast::channel(16);
+    let (shutdown_complete_tx, mut shutdown_complete_rx) = mpsc::channel::<()>(1);
+    let limit_streams = Arc::new(Semaphore::new(config.max_connections));
+
+    let serve = async {
+        while let Some(connecting) = endpoint.accept().await {
+            let h = h.clone();
+            let config = config.clone();
+            // the connection only listens for shutdown and hands a completion
+            // sender to its streams, so it never holds up the drain itself.
+            let mut shutdown = notify_shutdown.subscribe();
+            let shutdown_complete_tx = shutdown_complete_tx.downgrade();
+            let limit_streams = limit_streams.clone();
+
+            // a slow handshake must not hold up the peers behind it.
+            tokio::spawn(async move {
+                let conn = match handshake(connecting, accept_0rtt).await {
+                    Ok(conn) => conn,
+                    Err(err) => {
+                        tracing::error!(error = ?err, "quic handshake failed");
+                        return;
+                    }
+                };
+                let addr = conn.remote_address();
+
+                loop {
+                    let accepted = tokio::select! {
+                        accepted = conn.accept_bi() => accepted,
+                        _ = shutdown.recv() => return,
+                    };
+                    let (send, recv) = match accepted {
+                        Ok(stream) => stream,
+                        Err(quinn::ConnectionError::ApplicationClosed(_)) => return,
+                        Err(quinn::ConnectionError::LocallyClosed) => return,
+                        Err(err) => {
+                            tracing::error!(addr = ?addr, error = ?err, "quic connection failed");
+                            return;
+                        }
+                    };
+                    let permit = match limit_streams.clone().acquire_owned().await {
+                        Ok(permit) => permit,
+                        Err(_) => return,
+                    };
+                    // the server finished draining.
+                    let shutdown_complete_tx = match shutdown_complete_tx.upgrade() {
+                        Some(tx) => tx,
+                        None => return,
+                    };
+
+                    let mut connection =
+                        Connection::from_stream(QuicStream::new(send, recv), Some(addr));
+                    connection.set_idle_timeout(config.idle_timeout);
+                    connection.set_max_buffer_size(config.max_buffer_size);
+                    if let Some(heartbeat) = &config.heartbeat {
+                        connection.enable_heartbeat(heartbeat);
+                    }
+                    if let Some(rate_limit) = &config.rate_limit {
+                        connection.set_rate_limit(rate_limit);
+                    }
+
+                    let handler = Handler {
+                        inner: h.clone(),
+                        connection,
+                        shutdown: shutdown.resubscribe(),
+                        _shutdown_complete: shutdown_complete_tx,
+                    };
+
+                    tokio::spawn(async move {
+                        if let Err(err) = handler.run().await {
+                            tracing::error!(addr = ?addr, error = ?err, "quic stream failed");
+                        }
+                        drop(permit);
+                    });
+                }
+            });
+        }
+    };
+
+    tokio::select! {
+        _ = serve => {},
+        _ = shutdown => {tracing::info!("shutdown")},
+    }
+
+    // refuse new connections while the existing streams drain.
+    endpoint.set_server_config(None);
+    drop(shutdown_complete_tx);
+
+    let drained = tokio::time::timeout(config.drain_timeout, shutdown_complete_rx.recv())
+        .await
+        .is_ok();
+    if !drained {
+        tracing::warn!("drain timeout exceeded, closing remaining streams");
+        drop(notify_shutdown);
+    }
+    // also ends the connections idling without a stream.
+    endpoint.close(0u32.into(), b"shutdown");
+    if !drained {
+        let _ = shutdown_complete_rx.recv().await;
+    }
+}
+
+async fn handshake(
+    connecting: Connecting,
+    accept_0rtt: bool,
+) -> Result<quinn::Connection, quinn::ConnectionError> {
+    if !accept_0rtt {
+        return connecting.await;
+    }
+    match connecting.into_0rtt() {
+        Ok((conn, _)) => Ok(conn),
+        Err(connecting) => connecting.await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{tcp::middleware::BoxHandleFuture, ConnectionError, MsgPackFrame};
+
+    // reads frames until the peer goes away.
+    #[derive(Clone)]
+    struct Drain;
+
+    impl Handle for Drain {
+        type HandleFuture<'a> = BoxHandleFuture<'a>;
+
+        fn handle<'r>(self, conn: &'r mut Connection) -> Self::HandleFuture<'r> {
+            Box::pin(async move {
+                while conn
+                    .read_frame(&MsgPackFrame::<String>::empty())
+                    .await?
+                    .is_some()
+                {}
+                Err(ConnectionError::Fin)
+            })
+        }
+    }
+
+    #[derive(Clone)]
+    struct Echo;
+
+    impl Handle for Echo {
+        type HandleFuture<'a> = BoxHandleFuture<'a>;
+
+        fn handle<'r>(self, conn: &'r mut Connection) -> Self::HandleFuture<'r> {
+            Box::pin(async move {
+                let prototype = MsgPackFrame::<String>::empty();
+                while let Some(frame) = conn.read_frame(&prototype).await? {
+                    conn.write_frame(frame).await?;
+                }
+                Err(ConnectionError::Fin)
+            })
+        }
+    }
+
+    fn server_endpoint() -> (Endpoint, rustls::Certificate) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let der = rustls::Certificate(cert.serialize_der().unwrap());
+        let key = rustls::PrivateKey(cert.serialize_private_key_der());
+        let server_config = quinn::ServerConfig::with_single_cert(vec![der.clone()], key).unwrap();
+        let server = Endpoint::server(server_config, "127.0.0.1:0".parse().unwrap()).unwrap();
+        (server, der)
+    }
+
+    fn client_endpoint(der: &rustls::Certificate) -> Endpoint {
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(der).unwrap();
+        let tls = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let mut client = Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        client.set_default_client_config(quinn::ClientConfig::new(Arc::new(tls)));
+        client
+    }
+
+    #[tokio::test]
+    async fn shuts_down_with_a_client_connected() {
+        let (server, der) = server_endpoint();
+        let addr = server.local_addr().unwrap();
+
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let config = QuicConfig {
+            server: ServerConfig {
+                drain_timeout: Duration::from_millis(100),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let run = tokio::spawn(run_with_config(server, Drain, stopped, config));
+
+        let client = client_endpoint(&der);
+        let conn = client.connect(addr, "localhost").unwrap().await.unwrap();
+        let (send, recv) = conn.open_bi().await.unwrap();
+        let mut stream = Connection::from_stream(QuicStream::new(send, recv), Some(addr));
+        stream
+            .write_frame(MsgPackFrame::new("crossgate".to_string()))
+            .await
+            .unwrap();
+
+        // the stream is still open when the server is told to stop.
+        stop.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), run)
+            .await
+            .expect("the server did not shut down")
+            .unwrap();
+        drop(conn);
+    }
+
+    #[tokio::test]
+    async fn a_stalled_handshake_does_not_block_others() {
+        let (server, der) = server_endpoint();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(run(server, Echo, futures::future::pending::<()>()));
+
+        // forwards the first client packet and nothing back, so this
+        // handshake never completes.
+        let proxy = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = proxy.local_addr().unwrap();
+        let stalled = client_endpoint(&der);
+        let _connecting = stalled.connect(proxy_addr, "localhost").unwrap();
+        let mut buf = vec![0; 65535];
+        let (n, _) = proxy.recv_from(&mut buf).await.unwrap();
+        proxy.send_to(&buf[..n], addr).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let client = client_endpoint(&der);
+        let echoed = tokio::time::timeout(Duration::from_secs(2), async {
+            let conn = client.connect(addr, "localhost").unwrap().await.unwrap();
+            let (send, recv) = conn.open_bi().await.unwrap();
+            let mut stream = Connection::from_stream(QuicStream::new(send, recv), Some(addr));
+            stream
+                .write_frame(MsgPackFrame::new("crossgate".to_string()))
+                .await
+                .unwrap();
+            stream
+                .read_frame(&MsgPackFrame::<String>::empty())
+                .await
+                .unwrap()
+        })
+        .await
+        .expect("the stalled handshake held up the accept loop");
+        assert_eq!(
+            echoed.and_then(|frame| frame.into_inner()),
+            Some("crossgate".to_string())
+        );
+    }
+}
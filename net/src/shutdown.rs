@@ -0,0 +1,199 @@
+use std::{sync::Arc, time::Duration};
+
+use tokio::sync::watch;
+
+use crate::DEFAULT_DRAIN_TIMEOUT;
+
+pub const DEFAULT_PHASE_DEADLINE: Duration = Duration::from_secs(10);
+
+/// The phases of a shutdown, in the order they run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Phase {
+    // listeners close and registrations are marked draining, so that peers
+    // stop sending new work.
+    StopAccepting,
+    // in-flight requests, connections and jobs finish.
+    Drain,
+    // registrations are withdrawn from the registry.
+    Deregister,
+    // the last cleanups before the process exits.
+    Exit,
+}
+
+impl Phase {
+    pub const ALL: [Phase; 4] = [
+        Phase::StopAccepting,
+        Phase::Drain,
+        Phase::Deregister,
+        Phase::Exit,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Phase::StopAccepting => "stop_accepting",
+            Phase::Drain => "drain",
+            Phase::Deregister => "deregister",
+            Phase::Exit => "exit",
+        }
+    }
+}
+
+type Pending = Arc<[watch::Sender<usize>; 4]>;
+
+/// Coordinates the shutdown of everything in a process: servers, executors
+/// and registrations wait for the phase they act in with `reached`, and hold
+/// a `guard` of it until they are done. `run` starts the phases on ctrl-c or
+/// `trigger`, and moves to the next one once the guards of the current one
+/// are dropped or its deadline passes. Clones share the state.
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    // None until shutdown starts, then the phase being run.
+    phase: Arc<watch::Sender<Option<Phase>>>,
+    // guards held per phase.
+    pending: Pending,
+    deadlines: [Duration; 4],
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let mut deadlines = [DEFAULT_PHASE_DEADLINE; 4];
+        deadlines[Phase::Drain as usize] = DEFAULT_DRAIN_TIMEOUT;
+        Self {
+            phase: Arc::new(watch::channel(None).0),
+            pending: Arc::new(std::array::from_fn(|_| watch::channel(0).0)),
+            deadlines,
+        }
+    }
+
+    // how long `phase` may wait for its guards, set it before handing out
+    // clones.
+    pub fn deadline(mut self, phase: Phase, deadline: Duration) -> Self {
+        self.deadlines[phase as usize] = deadline;
+        self
+    }
+
+    pub fn phase_deadline(&self, phase: Phase) -> Duration {
+        self.deadlines[phase as usize]
+    }
+
+    /// Keeps `phase` from ending until the guard is dropped or the deadline
+    /// of the phase passes.
+    pub fn guard(&self, phase: Phase) -> ShutdownGuard {
+        self.pending[phase as usize].send_modify(|n| *n += 1);
+        ShutdownGuard {
+            pending: self.pending.clone(),
+            phase,
+        }
+    }
+
+    // starts the shutdown, e.g. when a server failed; later calls do nothing.
+    pub fn trigger(&self) {
+        self.phase.send_if_modified(|phase| match phase {
+            None => {
+                *phase = Some(Phase::StopAccepting);
+                true
+            }
+            Some(_) => false,
+        });
+    }
+
+    // the phase being run, None before shutdown starts.
+    pub fn phase(&self) -> Option<Phase> {
+        *self.phase.borrow()
+    }
+
+    /// Resolves once shutdown has reached `phase` or a later one.
+    pub async fn reached(&self, phase: Phase) {
+        let mut rx = self.phase.subscribe();
+        // the sender lives in `self`, the wait cannot fail.
+        let _ = rx.wait_for(|p| p.is_some_and(|p| p >= phase)).await;
+    }
+
+    /// Waits for ctrl-c or `trigger`, then runs the phases in order. Returns
+    /// once the last one is over; only one task should drive it.
+    pub async fn run(&self) {
+        tokio::select! {
+            res = tokio::signal::ctrl_c() => match res {
+                Ok(()) => {
+                    tracing::info!("ctrl-c received");
+                    self.trigger();
+                }
+                Err(e) => {
+                    tracing::error!(error = %e, "listen for ctrl-c failed");
+                    self.reached(Phase::StopAccepting).await;
+                }
+            },
+            _ = self.reached(Phase::StopAccepting) => {},
+        }
+
+        for phase in Phase::ALL {
+            self.phase.send_replace(Some(phase));
+            tracing::info!(phase = phase.as_str(), "shutdown phase");
+
+            let mut pending = self.pending[phase as usize].subscribe();
+            let deadline = self.deadlines[phase as usize];
+            if tokio::time::timeout(deadline, pending.wait_for(|n| *n == 0))
+                .await
+                .is_err()
+            {
+                tracing::warn!(
+                    phase = phase.as_str(),
+                    pending = *pending.borrow(),
+                    "shutdown phase deadline exceeded, moving on"
+                );
+            }
+        }
+    }
+}
+
+/// Returned by `Shutdown::guard`, releases its phase when dropped.
+#[must_use]
+#[derive(Debug)]
+pub struct ShutdownGuard {
+    pending: Pending,
+    phase: Phase,
+}
+
+impl Drop for ShutdownGuard {
+    fn drop(&mut self) {
+        self.pending[self.phase as usize].send_modify(|n| *n -= 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[tokio::test]
+    async fn phases_run_in_order() {
+        let shutdown = Shutdown::new().deadline(Phase::Exit, Duration::from_millis(50));
+        let seen = Arc::new(Mutex::new(vec![]));
+
+        for phase in [Phase::Deregister, Phase::Drain] {
+            let (s, seen) = (shutdown.clone(), seen.clone());
+            let guard = shutdown.guard(phase);
+            tokio::spawn(async move {
+                s.reached(phase).await;
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                seen.lock().unwrap().push(phase);
+                drop(guard);
+            });
+        }
+        // never released, the deadline moves past it.
+        let _stuck = shutdown.guard(Phase::Exit);
+
+        assert_eq!(shutdown.phase(), None);
+        shutdown.trigger();
+        shutdown.run().await;
+
+        assert_eq!(shutdown.phase(), Some(Phase::Exit));
+        assert_eq!(*seen.lock().unwrap(), [Phase::Drain, Phase::Deregister]);
+    }
+}
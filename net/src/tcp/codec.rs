@@ -0,0 +1,114 @@
+use bytes::{BufMut, BytesMut};
+use std::io;
+use tokio_util::codec;
+
+use crate::{Decoded, Decoder, Frame, FrameDecoder, FrameError};
+
+fn into_io_error(e: FrameError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e))
+}
+
+/// Exposes a `Frame` implementation as a tokio-util `Decoder`/`Encoder`,
+/// e.g. to use it with `Framed`.
+#[derive(Debug, Clone)]
+pub struct FrameCodec<F> {
+    inner: FrameDecoder<F>,
+}
+
+impl<F: Frame> FrameCodec<F> {
+    pub fn new(prototype: F) -> Self {
+        Self {
+            inner: FrameDecoder::new(prototype),
+        }
+    }
+}
+
+impl<F: Frame> codec::Decoder for FrameCodec<F> {
+    type Item = F;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<F>, io::Error> {
+        match self.inner.decode(src) {
+            Ok(Decoded::Item(item)) => Ok(Some(item)),
+            Ok(Decoded::NeedMore(_)) => Ok(None),
+            Err(e) => Err(into_io_error(e)),
+        }
+    }
+}
+
+impl<F: Frame> codec::Encoder<F> for FrameCodec<F> {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: F, dst: &mut BytesMut) -> Result<(), io::Error> {
+        item.write(&mut dst.writer()).map_err(into_io_error)
+    }
+}
+
+/// Drives a tokio-util `Decoder` through `Connection::read_decoded`, so
+/// existing codecs can be used inside a `Handle`.
+#[derive(Debug, Clone)]
+pub struct CodecDecoder<D> {
+    inner: D,
+}
+
+impl<D> CodecDecoder<D> {
+    pub fn new(inner: D) -> Self {
+        Self { inner }
+    }
+
+    pub fn into_inner(self) -> D {
+        self.inner
+    }
+}
+
+impl<D> Decoder for CodecDecoder<D>
+where
+    D: codec::Decoder + Send,
+    D::Error: std::fmt::Debug,
+{
+    type Item = D::Item;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> anyhow::Result<Decoded<D::Item>, FrameError> {
+        match self.inner.decode(buf) {
+            Ok(Some(item)) => Ok(Decoded::Item(item)),
+            Ok(None) => Ok(Decoded::NeedMore(None)),
+            Err(e) => Err(FrameError::ParseError(format!("{:?}", e))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MsgPackFrame;
+    use tokio_util::codec::{Decoder as _, Encoder as _, LinesCodec};
+
+    #[test]
+    fn test_frame_codec_roundtrip() {
+        let mut codec = FrameCodec::new(MsgPackFrame::<String>::empty());
+        let mut buf = BytesMut::new();
+        codec
+            .encode(MsgPackFrame::new("crossgate".to_string()), &mut buf)
+            .unwrap();
+
+        let frame = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(frame.into_inner(), Some("crossgate".to_string()));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_codec_decoder() {
+        let mut decoder = CodecDecoder::new(LinesCodec::new());
+        let mut buf = BytesMut::from("cross");
+        assert!(matches!(
+            Decoder::decode(&mut decoder, &mut buf),
+            Ok(Decoded::NeedMore(None))
+        ));
+
+        buf.extend_from_slice(b"gate\n");
+        match Decoder::decode(&mut decoder, &mut buf) {
+            Ok(Decoded::Item(line)) => assert_eq!(line, "crossgate"),
+            _ => panic!("expect decoded line"),
+        }
+    }
+}
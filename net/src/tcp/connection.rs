@@ -1,39 +1,157 @@
-use bytes::{Buf, BytesMut};
-use std::error::Error;
-use tokio::io::{AsyncReadExt, AsyncWriteExt, BufWriter};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::collections::VecDeque;
+use std::io::IoSlice;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter};
 use tokio::net::TcpStream;
+use tokio::sync::{mpsc, watch};
 
+use super::heartbeat::{self, Heartbeat, HeartbeatConfig};
+use super::ratelimit::{RateLimitAction, RateLimitConfig, RateLimiter};
 use crate::FrameError;
 
-#[derive(Debug, Clone)]
+// upper bound of buffered, not yet parsed bytes per connection.
+pub const DEFAULT_MAX_BUFFER_SIZE: usize = 8 * 1024 * 1024;
+// frames pushed to a connection that wait for the handler to pick them up.
+const PUSH_CHANNEL_CAPACITY: usize = 128;
+
+enum Wake {
+    Tick,
+    Push(Option<Bytes>),
+    Drain,
+}
+
+#[derive(Debug, Clone, thiserror::Error)]
 pub enum ConnectionError {
+    #[error("frame incomplete")]
     FrameIncomplete,
-    FrameError(FrameError),
+    #[error("invalid frame: {0}")]
+    FrameError(#[source] FrameError),
+    #[error("io error: {0}")]
     IoError(String),
+    #[error("connection idle timeout")]
+    IdleTimeout,
+    // the peer did not answer `max_missed` pings in a row.
+    #[error("peer missed its heartbeats")]
+    HeartbeatTimeout,
+    // the frame rate limit was exceeded and never refills, or a push found
+    // the queue full; try again later.
+    #[error("frame rate limit exceeded, throttled")]
+    Throttled,
+    // the frame rate limit was exceeded and the connection must be closed.
+    #[error("frame rate limit exceeded, closing")]
+    RateLimited,
+    // the peer sent more than `max_buffer_size` bytes without completing a frame.
+    #[error("frame larger than the {0} bytes buffer")]
+    FrameTooLarge(usize),
+    #[error("connection finished")]
     Fin,
-    Other(crate::NetError),
+    #[error(transparent)]
+    Other(#[from] crate::NetError),
 }
+/// Byte stream a `Connection` can be built on, e.g. a `TcpStream` or a QUIC stream.
+pub trait AsyncStream: AsyncRead + AsyncWrite + Send + Sync + Unpin + std::fmt::Debug {}
 
-impl Error for ConnectionError {}
+impl<T> AsyncStream for T where T: AsyncRead + AsyncWrite + Send + Sync + Unpin + std::fmt::Debug {}
 
-impl std::fmt::Display for ConnectionError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "ConnectionError")
-    }
-}
 #[derive(Debug)]
 pub struct Connection {
     // sufficient for our needs.
-    pub(crate) stream: BufWriter<TcpStream>,
+    pub(crate) stream: BufWriter<Box<dyn AsyncStream>>,
+    pub(crate) peer_addr: Option<SocketAddr>,
     // The buffer for reading frames.
     pub(crate) rb: BytesMut,
+    // The buffer frames are serialized into, reused between writes.
+    pub(crate) wb: BytesMut,
+    // close the connection when no bytes arrive within this duration.
+    pub(crate) idle_timeout: Option<Duration>,
+    pub(crate) max_buffer_size: usize,
+    pub(crate) heartbeat: Option<Heartbeat>,
+    // with heartbeat enabled, the data byte of the frame being decoded was consumed.
+    pub(crate) decoding_frame: bool,
+    pub(crate) rate_limiter: Option<RateLimiter>,
+    // serialized frames pushed from outside the handler, see `ConnectionHandle`.
+    pub(crate) push_tx: Option<mpsc::Sender<Bytes>>,
+    pub(crate) push_rx: Option<mpsc::Receiver<Bytes>>,
+    // flips to true when the server drains, reads then stop between frames.
+    pub(crate) drain: Option<watch::Receiver<bool>>,
 }
 
 impl Connection {
     pub fn new(stream: TcpStream) -> Self {
+        let peer_addr = stream.peer_addr().ok();
+        Self::from_stream(stream, peer_addr)
+    }
+
+    pub fn from_stream<S: AsyncStream + 'static>(stream: S, peer_addr: Option<SocketAddr>) -> Self {
         Self {
-            stream: BufWriter::new(stream),
+            stream: BufWriter::new(Box::new(stream)),
+            peer_addr,
             rb: BytesMut::with_capacity(4 * 1024),
+            wb: BytesMut::with_capacity(4 * 1024),
+            idle_timeout: None,
+            max_buffer_size: DEFAULT_MAX_BUFFER_SIZE,
+            heartbeat: None,
+            decoding_frame: false,
+            rate_limiter: None,
+            push_tx: None,
+            push_rx: None,
+            drain: None,
+        }
+    }
+
+    pub fn with_options(
+        stream: TcpStream,
+        options: &super::SocketOptions,
+    ) -> Result<Self, ConnectionError> {
+        options
+            .apply(&stream)
+            .map_err(|e| ConnectionError::IoError(e.to_string()))?;
+        Ok(Self::new(stream))
+    }
+
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    pub fn set_idle_timeout(&mut self, idle_timeout: Option<Duration>) {
+        self.idle_timeout = idle_timeout;
+    }
+
+    pub fn set_max_buffer_size(&mut self, max_buffer_size: usize) {
+        self.max_buffer_size = max_buffer_size;
+    }
+
+    pub fn enable_heartbeat(&mut self, config: &HeartbeatConfig) {
+        self.heartbeat = Some(Heartbeat::new(config));
+    }
+
+    pub fn set_rate_limit(&mut self, config: &RateLimitConfig) {
+        self.rate_limiter = Some(RateLimiter::new(config));
+    }
+
+    // throttled, waits until the bucket refilled.
+    async fn check_rate_limit(&mut self) -> Result<(), ConnectionError> {
+        loop {
+            let limiter = match self.rate_limiter.as_mut() {
+                Some(limiter) => limiter,
+                None => return Ok(()),
+            };
+            if limiter.check() {
+                return Ok(());
+            }
+            match (limiter.action, limiter.refill_in()) {
+                (RateLimitAction::Throttle, Some(wait)) => tokio::time::sleep(wait).await,
+                (RateLimitAction::Throttle, None) => return Err(ConnectionError::Throttled),
+                (RateLimitAction::Close, _) => return Err(ConnectionError::RateLimited),
+            }
+        }
+    }
+
+    fn consume_rate_limit(&mut self) {
+        if let Some(limiter) = self.rate_limiter.as_mut() {
+            limiter.consume();
         }
     }
 
@@ -41,26 +159,244 @@ impl Connection {
         &mut self,
         frame: &F,
     ) -> Result<Option<F>, ConnectionError> {
+        self.check_rate_limit().await?;
+
         loop {
             match self.parse_frame(frame).await {
-                Ok(item) => return Ok(item),
+                Ok(item) => {
+                    self.consume_rate_limit();
+                    return Ok(item);
+                }
                 Err(e) => match e {
                     ConnectionError::FrameIncomplete => {} // continue
                     _ => return Err(e),
                 },
             }
 
-            if let Err(e) = self.stream.read_buf(&mut self.rb).await {
+            self.fill_read_buf(None).await?;
+        }
+    }
+
+    pub async fn read_decoded<D: super::Decoder>(
+        &mut self,
+        decoder: &mut D,
+    ) -> Result<Option<D::Item>, ConnectionError> {
+        self.check_rate_limit().await?;
+
+        loop {
+            if self.heartbeat.is_some() && !self.decoding_frame {
+                self.take_control_frames().await?;
+                if self.rb.is_empty() {
+                    self.fill_read_buf(None).await?;
+                    continue;
+                }
+                self.rb.advance(1);
+                self.decoding_frame = true;
+            }
+
+            let needed = match decoder.decode(&mut self.rb) {
+                Ok(super::Decoded::Item(item)) => {
+                    self.decoding_frame = false;
+                    self.consume_rate_limit();
+                    return Ok(Some(item));
+                }
+                Ok(super::Decoded::NeedMore(needed)) => needed,
+                Err(crate::FrameError::Exit) => return Err(ConnectionError::Fin),
+                Err(e) => return Err(ConnectionError::FrameError(e)),
+            };
+
+            self.fill_read_buf(needed).await?;
+        }
+    }
+
+    // read more bytes into `rb`, `needed` is a hint used to reserve space up front.
+    async fn fill_read_buf(&mut self, needed: Option<usize>) -> Result<(), ConnectionError> {
+        if self.rb.is_empty() && !self.decoding_frame && self.draining() {
+            return Err(ConnectionError::Fin);
+        }
+        let remaining = self.max_buffer_size.saturating_sub(self.rb.len());
+        if remaining == 0 || needed.is_some_and(|n| n > remaining) {
+            return Err(ConnectionError::FrameTooLarge(self.rb.len()));
+        }
+        if let Some(needed) = needed {
+            self.rb.reserve(needed);
+        }
+        let mut rb = (&mut self.rb).limit(remaining);
+
+        let idle_timeout = self.idle_timeout;
+        let stream = &mut self.stream;
+        let read = async move {
+            match idle_timeout {
+                Some(idle_timeout) => {
+                    match tokio::time::timeout(idle_timeout, stream.read_buf(&mut rb)).await {
+                        Ok(res) => res.map_err(|e| ConnectionError::IoError(e.to_string())),
+                        Err(_) => Err(ConnectionError::IdleTimeout),
+                    }
+                }
+                None => stream
+                    .read_buf(&mut rb)
+                    .await
+                    .map_err(|e| ConnectionError::IoError(e.to_string())),
+            }
+        };
+
+        // while waiting for the peer, also serve heartbeat ticks and pushed frames.
+        let heartbeat = &mut self.heartbeat;
+        let tick = async move {
+            match heartbeat {
+                Some(heartbeat) => heartbeat.interval.tick().await,
+                None => futures::future::pending().await,
+            }
+        };
+        let push_rx = &mut self.push_rx;
+        let push = async move {
+            match push_rx {
+                Some(push_rx) => push_rx.recv().await,
+                None => futures::future::pending().await,
+            }
+        };
+
+        let drain = &mut self.drain;
+        let drained = async move {
+            match drain {
+                Some(drain) if !*drain.borrow() => {
+                    if drain.changed().await.is_err() {
+                        futures::future::pending::<()>().await
+                    }
+                }
+                _ => futures::future::pending().await,
+            }
+        };
+
+        let wake = tokio::select! {
+            res = read => {
+                // zero bytes read: the peer closed its side of the stream.
+                return match res? {
+                    0 if self.rb.is_empty() => Err(ConnectionError::Fin),
+                    0 => Err(ConnectionError::IoError(
+                        "connection reset by peer".to_string(),
+                    )),
+                    _ => Ok(()),
+                };
+            }
+            _ = tick => Wake::Tick,
+            pushed = push => Wake::Push(pushed),
+            _ = drained => Wake::Drain,
+        };
+
+        match wake {
+            Wake::Tick => {
+                if let Some(heartbeat) = self.heartbeat.as_mut() {
+                    if heartbeat.missed >= heartbeat.max_missed {
+                        return Err(ConnectionError::HeartbeatTimeout);
+                    }
+                    heartbeat.missed += 1;
+                }
+                self.write_control(heartbeat::PING).await
+            }
+            Wake::Push(Some(pushed)) => self.write_pushed(&pushed).await,
+            Wake::Push(None) | Wake::Drain => Ok(()),
+        }
+    }
+
+    fn draining(&self) -> bool {
+        self.drain.as_ref().is_some_and(|drain| *drain.borrow())
+    }
+
+    // frames pushed from outside the handler arrive serialized.
+    async fn write_pushed(&mut self, pushed: &[u8]) -> Result<(), ConnectionError> {
+        if self.heartbeat.is_some() {
+            if let Err(e) = self.stream.write_all(&[heartbeat::DATA]).await {
                 return Err(ConnectionError::IoError(e.to_string()));
             }
         }
+        if let Err(e) = self.stream.write_all(pushed).await {
+            return Err(ConnectionError::IoError(e.to_string()));
+        }
+        if let Err(e) = self.stream.flush().await {
+            return Err(ConnectionError::IoError(e.to_string()));
+        }
+        Ok(())
+    }
+
+    // a writer other tasks can push frames through, the channel is created on first use.
+    pub fn handle(&mut self) -> super::ConnectionHandle {
+        if let Some(push_tx) = &self.push_tx {
+            return super::ConnectionHandle {
+                tx: push_tx.clone(),
+            };
+        }
+        let (push_tx, push_rx) = mpsc::channel(PUSH_CHANNEL_CAPACITY);
+        self.push_tx = Some(push_tx.clone());
+        self.push_rx = Some(push_rx);
+        super::ConnectionHandle { tx: push_tx }
+    }
+
+    // write the frames already queued through a `ConnectionHandle` without waiting for more.
+    pub async fn flush_pushed(&mut self) -> Result<usize, ConnectionError> {
+        let mut n = 0;
+        loop {
+            let pushed = match self.push_rx.as_mut().map(|push_rx| push_rx.try_recv()) {
+                Some(Ok(pushed)) => pushed,
+                _ => return Ok(n),
+            };
+            self.write_pushed(&pushed).await?;
+            n += 1;
+        }
+    }
+
+    // handle the ping/pong frames at the head of the read buffer.
+    async fn take_control_frames(&mut self) -> Result<(), ConnectionError> {
+        while let Some(&control) = self.rb.first() {
+            match control {
+                heartbeat::DATA => break,
+                heartbeat::PING => {
+                    self.rb.advance(1);
+                    self.write_control(heartbeat::PONG).await?;
+                }
+                heartbeat::PONG => {
+                    self.rb.advance(1);
+                    if let Some(heartbeat) = self.heartbeat.as_mut() {
+                        heartbeat.missed = 0;
+                    }
+                }
+                _ => {
+                    return Err(ConnectionError::FrameError(FrameError::ParseError(
+                        format!("unknown control byte {}", control),
+                    )))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn write_control(&mut self, control: u8) -> Result<(), ConnectionError> {
+        if let Err(e) = self.stream.write_all(&[control]).await {
+            return Err(ConnectionError::IoError(e.to_string()));
+        }
+        if let Err(e) = self.stream.flush().await {
+            return Err(ConnectionError::IoError(e.to_string()));
+        }
+        Ok(())
     }
 
     async fn parse_frame<F: super::Frame>(
         &mut self,
         frame: &F,
     ) -> Result<Option<F>, ConnectionError> {
-        let mut buf = std::io::Cursor::new(&self.rb[..]);
+        // skip the data byte, it is consumed together with the complete frame.
+        let offset = match self.heartbeat {
+            Some(_) => {
+                self.take_control_frames().await?;
+                if self.rb.is_empty() {
+                    return Err(ConnectionError::FrameIncomplete);
+                }
+                1
+            }
+            None => 0,
+        };
+
+        let mut buf = std::io::Cursor::new(&self.rb[offset..]);
         let res = match frame.read(&mut buf) {
             Ok(item) => Ok(Some(item)),
             Err(e) => match e {
@@ -69,16 +405,22 @@ impl Connection {
                 _ => Err(ConnectionError::FrameError(e)),
             },
         };
-        self.rb.advance(buf.position() as usize);
+        let consumed = buf.position() as usize;
+        if offset == 0 || !matches!(res, Err(ConnectionError::FrameIncomplete)) {
+            self.rb.advance(offset + consumed);
+        }
         res
     }
 
     pub async fn write_frame<F: super::Frame>(&mut self, frame: F) -> Result<(), ConnectionError> {
-        let mut buf = std::io::Cursor::new(vec![]);
-        if let Err(e) = frame.write(&mut buf) {
+        self.wb.clear();
+        if self.heartbeat.is_some() {
+            self.wb.put_u8(heartbeat::DATA);
+        }
+        if let Err(e) = frame.write(&mut (&mut self.wb).writer()) {
             return Err(ConnectionError::FrameError(e));
         }
-        if let Err(e) = self.stream.write_all(&mut buf.get_ref()).await {
+        if let Err(e) = self.stream.write_all(&self.wb).await {
             return Err(ConnectionError::IoError(e.to_string()));
         }
         if let Err(e) = self.stream.flush().await {
@@ -86,4 +428,128 @@ impl Connection {
         }
         Ok(())
     }
-}
\ No newline at end of file
+
+    // write an item with a tokio-util encoder, see `CodecDecoder` for the read side.
+    pub async fn write_encoded<T, E>(
+        &mut self,
+        encoder: &mut E,
+        item: T,
+    ) -> Result<(), ConnectionError>
+    where
+        E: tokio_util::codec::Encoder<T>,
+        E::Error: std::fmt::Debug,
+    {
+        self.wb.clear();
+        if self.heartbeat.is_some() {
+            self.wb.put_u8(heartbeat::DATA);
+        }
+        if let Err(e) = encoder.encode(item, &mut self.wb) {
+            return Err(ConnectionError::FrameError(FrameError::ParseError(
+                format!("{:?}", e),
+            )));
+        }
+        if let Err(e) = self.stream.write_all(&self.wb).await {
+            return Err(ConnectionError::IoError(e.to_string()));
+        }
+        if let Err(e) = self.stream.flush().await {
+            return Err(ConnectionError::IoError(e.to_string()));
+        }
+        Ok(())
+    }
+
+    // serialize every frame first, then hand them to the socket with
+    // vectored writes and a single flush.
+    pub async fn write_frames<F, I>(&mut self, frames: I) -> Result<(), ConnectionError>
+    where
+        F: super::Frame,
+        I: IntoIterator<Item = F>,
+    {
+        let mut segments = VecDeque::new();
+        for frame in frames {
+            if self.heartbeat.is_some() {
+                self.wb.put_u8(heartbeat::DATA);
+            }
+            if let Err(e) = frame.write(&mut (&mut self.wb).writer()) {
+                self.wb.clear();
+                return Err(ConnectionError::FrameError(e));
+            }
+            segments.push_back(self.wb.split().freeze());
+        }
+
+        if let Err(e) = self.write_all_vectored(&mut segments).await {
+            return Err(ConnectionError::IoError(e.to_string()));
+        }
+        if let Err(e) = self.stream.flush().await {
+            return Err(ConnectionError::IoError(e.to_string()));
+        }
+        Ok(())
+    }
+
+    async fn write_all_vectored(&mut self, segments: &mut VecDeque<Bytes>) -> std::io::Result<()> {
+        while !segments.is_empty() {
+            let n = {
+                let slices = segments
+                    .iter()
+                    .map(|segment| IoSlice::new(segment))
+                    .collect::<Vec<IoSlice>>();
+                self.stream.write_vectored(&slices).await?
+            };
+            if n == 0 {
+                return Err(std::io::ErrorKind::WriteZero.into());
+            }
+
+            let mut n = n;
+            while n > 0 {
+                match segments.front_mut() {
+                    Some(front) if front.len() <= n => {
+                        n -= front.len();
+                        segments.pop_front();
+                    }
+                    Some(front) => {
+                        front.advance(n);
+                        n = 0;
+                    }
+                    None => break,
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MsgPackFrame;
+
+    #[tokio::test]
+    async fn test_read_frame_after_peer_closed() {
+        let (client, server) = tokio::io::duplex(64);
+        let mut conn = Connection::from_stream(server, None);
+        drop(client);
+
+        let res = tokio::time::timeout(
+            Duration::from_secs(1),
+            conn.read_frame(&MsgPackFrame::<String>::empty()),
+        )
+        .await
+        .expect("read loop kept spinning after eof");
+        assert!(matches!(res, Err(ConnectionError::Fin)));
+    }
+
+    #[tokio::test]
+    async fn test_read_frame_peer_closed_mid_frame() {
+        let (mut client, server) = tokio::io::duplex(64);
+        let mut conn = Connection::from_stream(server, None);
+        client.write_all(&[0x00, 0x00, 0x00]).await.unwrap();
+        drop(client);
+
+        let res = tokio::time::timeout(
+            Duration::from_secs(1),
+            conn.read_frame(&MsgPackFrame::<String>::empty()),
+        )
+        .await
+        .expect("read loop kept spinning after eof");
+        assert!(matches!(res, Err(ConnectionError::IoError(_))));
+    }
+}
@@ -0,0 +1,49 @@
+use bytes::{Buf, BytesMut};
+
+use crate::{Frame, FrameError};
+
+#[derive(Debug)]
+pub enum Decoded<T> {
+    Item(T),
+    // more bytes are required, optionally how many at least.
+    NeedMore(Option<usize>),
+}
+
+/// Stateful counterpart of `Frame::read`: a decoder consumes bytes from the
+/// read buffer as it goes and may remember partial progress (for example a
+/// parsed header) between calls instead of re-parsing from the start.
+pub trait Decoder: Send {
+    type Item;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> anyhow::Result<Decoded<Self::Item>, FrameError>;
+}
+
+/// Compatibility shim, drives an existing `Frame` implementation through the
+/// `Decoder` interface.
+#[derive(Debug, Clone)]
+pub struct FrameDecoder<F> {
+    prototype: F,
+}
+
+impl<F: Frame> FrameDecoder<F> {
+    pub fn new(prototype: F) -> Self {
+        Self { prototype }
+    }
+}
+
+impl<F: Frame> Decoder for FrameDecoder<F> {
+    type Item = F;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> anyhow::Result<Decoded<F>, FrameError> {
+        let mut cursor = std::io::Cursor::new(&buf[..]);
+        match self.prototype.read(&mut cursor) {
+            Ok(item) => {
+                let n = cursor.position() as usize;
+                buf.advance(n);
+                Ok(Decoded::Item(item))
+            }
+            Err(FrameError::Incomplete) => Ok(Decoded::NeedMore(None)),
+            Err(e) => Err(e),
+        }
+    }
+}
@@ -1,9 +1,13 @@
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, thiserror::Error)]
 pub enum FrameError {
+    #[error("parse error: {0}")]
     ParseError(String),
+    #[error("frame incomplete")]
     Incomplete,
+    #[error("frame stream finished")]
     Exit,
-    Other(crate::NetError),
+    #[error(transparent)]
+    Other(#[from] crate::NetError),
 }
 
 pub trait Frame: Send + Sync + Clone + 'static {
@@ -1,5 +1,5 @@
 use crate::{Connection, ConnectionError};
-use tokio::sync::broadcast;
+use tokio::sync::{broadcast, mpsc};
 
 pub trait Handle: Sync + Send + Clone + 'static {
     type HandleFuture<'a>: futures::Future<Output = Result<(), ConnectionError>> + Send + Sync
@@ -16,6 +16,8 @@ where
     pub(crate) inner: H,
     pub(crate) connection: Connection,
     pub(crate) shutdown: broadcast::Receiver<()>,
+    // dropped together with the handler, lets the server know it has finished.
+    pub(crate) _shutdown_complete: mpsc::Sender<()>,
 }
 
 impl<H> Handler<H>
@@ -24,11 +26,26 @@ where
 {
     pub(crate) fn run<'a>(mut self) -> impl futures::Future<Output = anyhow::Result<()>> + 'a {
         async move {
+            // the block only captures the fields it uses, hold the sender
+            // explicitly so the server sees the handler finish.
+            let _shutdown_complete = self._shutdown_complete;
             tokio::select! {
                 res = self.inner.handle(&mut self.connection) => {
                     if let Err(ConnectionError::Fin) = res {
                             return Ok(());
                     }
+                    if let Err(ConnectionError::IdleTimeout) = res {
+                        tracing::debug!("connection closed after idle timeout");
+                        return Ok(());
+                    }
+                    if let Err(ConnectionError::HeartbeatTimeout) = res {
+                        tracing::debug!("connection closed after missed heartbeats");
+                        return Ok(());
+                    }
+                    if let Err(ConnectionError::RateLimited) = res {
+                        tracing::warn!("connection closed after exceeding the frame rate limit");
+                        return Ok(());
+                    }
                     return res.map_err(|e| e.into());
                 },
                 _ = self.shutdown.recv() => {Ok(())},
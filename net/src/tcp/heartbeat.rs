@@ -0,0 +1,112 @@
+use std::time::Duration;
+use tokio::time::{Instant, Interval, MissedTickBehavior};
+
+// with heartbeat enabled every message on the wire starts with one of these.
+pub(crate) const DATA: u8 = 0;
+pub(crate) const PING: u8 = 1;
+pub(crate) const PONG: u8 = 2;
+
+/// Protocol level keepalive, both peers must enable it since it changes the
+/// wire format: every frame is prefixed by a control byte.
+#[derive(Debug, Clone)]
+pub struct HeartbeatConfig {
+    // a ping is sent every interval while the connection waits for data.
+    pub interval: Duration,
+    // close the connection after this many pings went unanswered.
+    pub max_missed: u32,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(15),
+            max_missed: 3,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Heartbeat {
+    pub(crate) interval: Interval,
+    pub(crate) missed: u32,
+    pub(crate) max_missed: u32,
+}
+
+impl Heartbeat {
+    pub(crate) fn new(config: &HeartbeatConfig) -> Self {
+        let mut interval =
+            tokio::time::interval_at(Instant::now() + config.interval, config.interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        Self {
+            interval,
+            missed: 0,
+            max_missed: config.max_missed,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Connection, ConnectionError, MsgPackFrame};
+    use tokio::net::{TcpListener, TcpStream};
+
+    async fn pair(config: &HeartbeatConfig) -> (Connection, Connection) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+
+        let mut server = Connection::new(server);
+        let mut client = Connection::new(client);
+        server.enable_heartbeat(config);
+        client.enable_heartbeat(config);
+        (server, client)
+    }
+
+    #[tokio::test]
+    async fn test_heartbeat_frames_are_filtered() {
+        let config = HeartbeatConfig {
+            interval: Duration::from_millis(10),
+            max_missed: 3,
+        };
+        let (mut server, mut client) = pair(&config).await;
+
+        let peer = tokio::spawn(async move {
+            // answer pings for a while, then send data
+            let _ = tokio::time::timeout(
+                Duration::from_millis(100),
+                client.read_frame(&MsgPackFrame::<String>::empty()),
+            )
+            .await;
+            client
+                .write_frame(MsgPackFrame::new("crossgate".to_string()))
+                .await
+                .unwrap();
+            client
+        });
+
+        let frame = server
+            .read_frame(&MsgPackFrame::<String>::empty())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(frame.into_inner(), Some("crossgate".to_string()));
+        let _ = peer.await;
+    }
+
+    #[tokio::test]
+    async fn test_heartbeat_timeout() {
+        let config = HeartbeatConfig {
+            interval: Duration::from_millis(10),
+            max_missed: 2,
+        };
+        // the client never reads, so the pings stay unanswered
+        let (mut server, _client) = pair(&config).await;
+
+        let res = server.read_frame(&MsgPackFrame::<String>::empty()).await;
+        assert!(matches!(res, Err(ConnectionError::HeartbeatTimeout)));
+    }
+}
@@ -0,0 +1,164 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+};
+
+use crate::{Connection, ConnectionError, ConnectionHandle, Frame};
+
+struct Member {
+    handle: ConnectionHandle,
+    tags: HashSet<String>,
+}
+
+/// Registry of live connections that frames can be broadcast to.
+///
+/// A joined connection writes broadcast frames while its handler waits in
+/// `read_frame`/`read_decoded`; connections that went away are pruned on the
+/// next broadcast.
+#[derive(Clone, Default)]
+pub struct ConnectionHub {
+    members: Arc<Mutex<HashMap<u64, Member>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl ConnectionHub {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn join(&self, conn: &mut Connection) -> u64 {
+        self.join_handle(conn.handle())
+    }
+
+    // register a connection by handle, e.g. from a task outside its handler.
+    pub fn join_handle(&self, handle: ConnectionHandle) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let member = Member {
+            handle,
+            tags: HashSet::new(),
+        };
+        self.members.lock().unwrap().insert(id, member);
+        id
+    }
+
+    pub fn leave(&self, id: u64) {
+        self.members.lock().unwrap().remove(&id);
+    }
+
+    pub fn tag(&self, id: u64, tag: &str) {
+        if let Some(member) = self.members.lock().unwrap().get_mut(&id) {
+            member.tags.insert(tag.to_string());
+        }
+    }
+
+    pub fn untag(&self, id: u64, tag: &str) {
+        if let Some(member) = self.members.lock().unwrap().get_mut(&id) {
+            member.tags.remove(tag);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.members.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // the handle of member `id`, None once it left.
+    pub fn get(&self, id: u64) -> Option<ConnectionHandle> {
+        self.members
+            .lock()
+            .unwrap()
+            .get(&id)
+            .map(|member| member.handle.clone())
+    }
+
+    // returns the number of connections the frame was queued for.
+    pub fn broadcast<F: Frame>(&self, frame: &F) -> Result<usize, ConnectionError> {
+        self.send(frame, |_| true)
+    }
+
+    pub fn broadcast_tagged<F: Frame>(
+        &self,
+        tag: &str,
+        frame: &F,
+    ) -> Result<usize, ConnectionError> {
+        self.send(frame, |member| member.tags.contains(tag))
+    }
+
+    fn send<F: Frame>(
+        &self,
+        frame: &F,
+        filter: impl Fn(&Member) -> bool,
+    ) -> Result<usize, ConnectionError> {
+        let payload = ConnectionHandle::serialize(frame)?;
+
+        let mut members = self.members.lock().unwrap();
+        members.retain(|_, member| !member.handle.is_closed());
+
+        let mut sent = 0;
+        for (id, member) in members.iter().filter(|(_, member)| filter(member)) {
+            // a slow client must not stall the broadcast, it misses the frame instead.
+            match member.handle.try_push_bytes(payload.clone()) {
+                Ok(_) => sent += 1,
+                Err(e) => {
+                    tracing::warn!(id, error = ?e, "hub connection dropped a broadcast frame")
+                }
+            }
+        }
+        Ok(sent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MsgPackFrame;
+    use std::time::Duration;
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[tokio::test]
+    async fn test_broadcast_tagged() {
+        let hub = ConnectionHub::new();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut clients = vec![];
+        for i in 0..2 {
+            let client = TcpStream::connect(addr).await.unwrap();
+            let (stream, _) = listener.accept().await.unwrap();
+
+            let mut conn = Connection::new(stream);
+            let id = hub.join(&mut conn);
+            if i == 0 {
+                hub.tag(id, "ops");
+            }
+            // the handler waits for frames, pushed frames are written meanwhile
+            tokio::spawn(async move {
+                let _ = conn.read_frame(&MsgPackFrame::<String>::empty()).await;
+            });
+            clients.push(Connection::new(client));
+        }
+
+        let sent = hub
+            .broadcast_tagged("ops", &MsgPackFrame::new("alert".to_string()))
+            .unwrap();
+        assert_eq!(sent, 1);
+
+        let frame = clients[0]
+            .read_frame(&MsgPackFrame::<String>::empty())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(frame.into_inner(), Some("alert".to_string()));
+
+        let res = tokio::time::timeout(
+            Duration::from_millis(50),
+            clients[1].read_frame(&MsgPackFrame::<String>::empty()),
+        )
+        .await;
+        assert!(res.is_err());
+    }
+}
@@ -1,10 +1,27 @@
-use crate::{Connection, Handle, Handler};
-use log;
-use tokio::{net::TcpListener, sync::broadcast};
+use crate::{Connection, Handle, Handler, HeartbeatConfig, RateLimitConfig, SocketOptions};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{broadcast, mpsc, watch, Semaphore},
+};
+
+// accept backoff starts at 1s and doubles up to this value before giving up.
+const MAX_ACCEPT_BACKOFF: u64 = 64;
 
 pub struct Listener {
     pub(crate) listener: TcpListener,
     pub(crate) notify_shutdown: broadcast::Sender<()>,
+    // tells handlers to finish the frame at hand and close.
+    pub(crate) notify_drain: watch::Sender<bool>,
+    // bounds the number of connections served at the same time.
+    pub(crate) limit_connections: Arc<Semaphore>,
+    pub(crate) idle_timeout: Option<Duration>,
+    pub(crate) socket_options: SocketOptions,
+    pub(crate) max_buffer_size: usize,
+    pub(crate) heartbeat: Option<HeartbeatConfig>,
+    pub(crate) rate_limit: Option<RateLimitConfig>,
+    // cloned into every handler, see `run_with_config` for the drain logic.
+    pub(crate) shutdown_complete_tx: mpsc::Sender<()>,
 }
 
 impl Listener {
@@ -13,18 +30,59 @@ impl Listener {
         H: Handle,
     {
         loop {
-             let (stream, addr) = self.listener.accept().await?;
-                let  handler = Handler {
-                    inner: h.clone(),
-                    connection: Connection::new(stream),
-                    shutdown: self.notify_shutdown.subscribe(),
-                };
-
-                tokio::spawn(async move {
-                    if let Err(err) = handler.run().await {
-                        log::error!("connection client {:?} error {:?}", addr.to_string(), err);
+            // wait for a slot before accepting, the backlog stays in the kernel.
+            let permit = self.limit_connections.clone().acquire_owned().await?;
+
+            let (stream, addr) = self.accept().await?;
+            let mut connection = match Connection::with_options(stream, &self.socket_options) {
+                Ok(connection) => connection,
+                Err(err) => {
+                    tracing::error!(addr = ?addr, error = ?err, "connection socket option failed");
+                    continue;
+                }
+            };
+            connection.set_idle_timeout(self.idle_timeout);
+            connection.set_max_buffer_size(self.max_buffer_size);
+            if let Some(heartbeat) = &self.heartbeat {
+                connection.enable_heartbeat(heartbeat);
+            }
+            if let Some(rate_limit) = &self.rate_limit {
+                connection.set_rate_limit(rate_limit);
+            }
+            connection.drain = Some(self.notify_drain.subscribe());
+
+            let handler = Handler {
+                inner: h.clone(),
+                connection,
+                shutdown: self.notify_shutdown.subscribe(),
+                _shutdown_complete: self.shutdown_complete_tx.clone(),
+            };
+
+            tokio::spawn(async move {
+                if let Err(err) = handler.run().await {
+                    tracing::error!(addr = %addr, error = ?err, "connection failed");
+                }
+                drop(permit);
+            });
+        }
+    }
+
+    async fn accept(&mut self) -> anyhow::Result<(TcpStream, SocketAddr)> {
+        let mut backoff = 1;
+
+        loop {
+            match self.listener.accept().await {
+                Ok(accepted) => return Ok(accepted),
+                Err(err) => {
+                    if backoff > MAX_ACCEPT_BACKOFF {
+                        return Err(err.into());
                     }
-                });
+                    tracing::warn!(error = ?err, retry_secs = backoff, "accept failed");
+                }
+            }
+
+            tokio::time::sleep(Duration::from_secs(backoff)).await;
+            backoff *= 2;
         }
     }
 }
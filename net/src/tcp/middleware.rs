@@ -0,0 +1,316 @@
+use std::pin::Pin;
+use std::sync::{
+    atomic::{AtomicU64, AtomicUsize, Ordering},
+    Arc,
+};
+use std::time::Instant;
+
+use crate::{Connection, ConnectionError, Frame, Handle, NetError, RateLimitConfig};
+
+pub type BoxHandleFuture<'a> =
+    Pin<Box<dyn futures::Future<Output = Result<(), ConnectionError>> + Send + Sync + 'a>>;
+
+/// Wraps a `Handle` with another one, analogous to tower's `Layer`.
+pub trait Layer<H> {
+    type Handle;
+
+    fn layer(&self, inner: H) -> Self::Handle;
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Identity;
+
+impl<H> Layer<H> for Identity {
+    type Handle = H;
+
+    fn layer(&self, inner: H) -> H {
+        inner
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Stack<Inner, Outer> {
+    inner: Inner,
+    outer: Outer,
+}
+
+impl<H, Inner, Outer> Layer<H> for Stack<Inner, Outer>
+where
+    Inner: Layer<H>,
+    Outer: Layer<Inner::Handle>,
+{
+    type Handle = Outer::Handle;
+
+    fn layer(&self, h: H) -> Self::Handle {
+        self.outer.layer(self.inner.layer(h))
+    }
+}
+
+/// Builds a middleware chain, the first added layer is the outermost one.
+///
+/// ```ignore
+/// let h = HandleBuilder::new()
+///     .layer(LogLayer)
+///     .layer(RateLimitLayer::new(config))
+///     .handle(my_handle);
+/// net::tcp::run(listener, h, shutdown).await;
+/// ```
+#[derive(Debug, Clone)]
+pub struct HandleBuilder<L> {
+    layer: L,
+}
+
+impl Default for HandleBuilder<Identity> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HandleBuilder<Identity> {
+    pub fn new() -> Self {
+        Self { layer: Identity }
+    }
+}
+
+impl<L> HandleBuilder<L> {
+    pub fn layer<T>(self, layer: T) -> HandleBuilder<Stack<T, L>> {
+        HandleBuilder {
+            layer: Stack {
+                inner: layer,
+                outer: self.layer,
+            },
+        }
+    }
+
+    pub fn handle<H>(&self, h: H) -> L::Handle
+    where
+        L: Layer<H>,
+    {
+        self.layer.layer(h)
+    }
+}
+
+// logging
+
+#[derive(Debug, Clone, Default)]
+pub struct LogLayer;
+
+impl<H: Handle> Layer<H> for LogLayer {
+    type Handle = Log<H>;
+
+    fn layer(&self, inner: H) -> Log<H> {
+        Log { inner }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Log<H> {
+    inner: H,
+}
+
+impl<H: Handle> Handle for Log<H> {
+    type HandleFuture<'a> = BoxHandleFuture<'a>;
+
+    fn handle<'r>(self, conn: &'r mut Connection) -> Self::HandleFuture<'r> {
+        Box::pin(async move {
+            let peer = conn.peer_addr();
+            let start = Instant::now();
+            tracing::debug!(peer = ?peer, "connection open");
+
+            let res = self.inner.handle(conn).await;
+            match &res {
+                Ok(_) | Err(ConnectionError::Fin) => {
+                    tracing::debug!(peer = ?peer, elapsed = ?start.elapsed(), "connection closed")
+                }
+                Err(e) => tracing::warn!(
+                    peer = ?peer,
+                    elapsed = ?start.elapsed(),
+                    error = ?e,
+                    "connection failed"
+                ),
+            }
+            res
+        })
+    }
+}
+
+// metrics
+
+#[derive(Debug, Clone, Default)]
+pub struct HandleMetrics {
+    active: Arc<AtomicUsize>,
+    total: Arc<AtomicU64>,
+    failed: Arc<AtomicU64>,
+}
+
+impl HandleMetrics {
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total.load(Ordering::Relaxed)
+    }
+
+    pub fn failed(&self) -> u64 {
+        self.failed.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MetricsLayer {
+    metrics: HandleMetrics,
+}
+
+impl MetricsLayer {
+    pub fn new(metrics: HandleMetrics) -> Self {
+        Self { metrics }
+    }
+}
+
+impl<H: Handle> Layer<H> for MetricsLayer {
+    type Handle = Metrics<H>;
+
+    fn layer(&self, inner: H) -> Metrics<H> {
+        Metrics {
+            inner,
+            metrics: self.metrics.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Metrics<H> {
+    inner: H,
+    metrics: HandleMetrics,
+}
+
+impl<H: Handle> Handle for Metrics<H> {
+    type HandleFuture<'a> = BoxHandleFuture<'a>;
+
+    fn handle<'r>(self, conn: &'r mut Connection) -> Self::HandleFuture<'r> {
+        Box::pin(async move {
+            let metrics = self.metrics;
+            metrics.total.fetch_add(1, Ordering::Relaxed);
+            metrics.active.fetch_add(1, Ordering::Relaxed);
+
+            let res = self.inner.handle(conn).await;
+
+            metrics.active.fetch_sub(1, Ordering::Relaxed);
+            if !matches!(res, Ok(_) | Err(ConnectionError::Fin)) {
+                metrics.failed.fetch_add(1, Ordering::Relaxed);
+            }
+            res
+        })
+    }
+}
+
+// auth on first frame
+
+/// Reads the first frame of every connection and passes it to `auth`, the
+/// connection is closed when it returns false. The inner handle only sees
+/// the frames after the auth frame.
+#[derive(Debug, Clone)]
+pub struct AuthLayer<F, A> {
+    prototype: F,
+    auth: A,
+}
+
+impl<F, A> AuthLayer<F, A>
+where
+    F: Frame,
+    A: Fn(&F) -> bool + Send + Sync + Clone + 'static,
+{
+    pub fn new(prototype: F, auth: A) -> Self {
+        Self { prototype, auth }
+    }
+}
+
+impl<H, F, A> Layer<H> for AuthLayer<F, A>
+where
+    H: Handle,
+    F: Frame,
+    A: Fn(&F) -> bool + Send + Sync + Clone + 'static,
+{
+    type Handle = Auth<H, F, A>;
+
+    fn layer(&self, inner: H) -> Self::Handle {
+        Auth {
+            inner,
+            prototype: self.prototype.clone(),
+            auth: self.auth.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Auth<H, F, A> {
+    inner: H,
+    prototype: F,
+    auth: A,
+}
+
+impl<H, F, A> Handle for Auth<H, F, A>
+where
+    H: Handle,
+    F: Frame,
+    A: Fn(&F) -> bool + Send + Sync + Clone + 'static,
+{
+    type HandleFuture<'a> = BoxHandleFuture<'a>;
+
+    fn handle<'r>(self, conn: &'r mut Connection) -> Self::HandleFuture<'r> {
+        Box::pin(async move {
+            let authorized = match conn.read_frame(&self.prototype).await? {
+                Some(frame) => (self.auth)(&frame),
+                None => false,
+            };
+            if !authorized {
+                return Err(ConnectionError::Other(NetError::InternalError(
+                    "connection is not authorized".into(),
+                )));
+            }
+            self.inner.handle(conn).await
+        })
+    }
+}
+
+// rate limiting
+
+#[derive(Debug, Clone)]
+pub struct RateLimitLayer {
+    config: RateLimitConfig,
+}
+
+impl RateLimitLayer {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self { config }
+    }
+}
+
+impl<H: Handle> Layer<H> for RateLimitLayer {
+    type Handle = RateLimit<H>;
+
+    fn layer(&self, inner: H) -> RateLimit<H> {
+        RateLimit {
+            inner,
+            config: self.config.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RateLimit<H> {
+    inner: H,
+    config: RateLimitConfig,
+}
+
+impl<H: Handle> Handle for RateLimit<H> {
+    type HandleFuture<'a> = BoxHandleFuture<'a>;
+
+    fn handle<'r>(self, conn: &'r mut Connection) -> Self::HandleFuture<'r> {
+        Box::pin(async move {
+            conn.set_rate_limit(&self.config);
+            self.inner.handle(conn).await
+        })
+    }
+}
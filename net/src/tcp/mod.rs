@@ -4,15 +4,48 @@ pub use listener::Listener;
 mod frame;
 pub use frame::{Frame, FrameError};
 
+mod heartbeat;
+pub use heartbeat::HeartbeatConfig;
+
+mod ratelimit;
+pub use ratelimit::{RateLimitAction, RateLimitConfig};
+
+mod decoder;
+pub use decoder::{Decoded, Decoder, FrameDecoder};
+
+mod codec;
+pub use codec::{CodecDecoder, FrameCodec};
+
+mod msgpack;
+pub use msgpack::{MsgPackDecoder, MsgPackFrame, MAX_MSGPACK_FRAME_LENGTH};
+
 mod connection;
-pub use connection::{Connection, ConnectionError};
+pub use connection::{AsyncStream, Connection, ConnectionError, DEFAULT_MAX_BUFFER_SIZE};
+
+mod socket;
+pub use socket::{KeepaliveOptions, SocketOptions};
 
 mod server;
-pub use server::run;
+pub use server::{
+    run, run_with_config, run_with_shutdown, ServerConfig, DEFAULT_DRAIN_TIMEOUT,
+    DEFAULT_MAX_CONNECTIONS,
+};
+
+mod push;
+pub use push::ConnectionHandle;
+
+mod hub;
+pub use hub::ConnectionHub;
+
+mod mux;
+pub use mux::{MuxClient, MuxFrame, MuxHandler, MuxKind, MuxService};
 
 mod handler;
 pub use handler::{Handle, Handler};
 
+pub mod middleware;
+pub use middleware::{HandleBuilder, Layer};
+
 pub enum NetError {
     Other(crate::NetError),
 }
@@ -0,0 +1,219 @@
+use bytes::{Buf, BytesMut};
+use serde::{de::DeserializeOwned, Serialize};
+use std::marker::PhantomData;
+
+use crate::{Decoded, Decoder, Frame, FrameError};
+
+// length prefix (u32, big endian) in front of every encoded message.
+const HEADER_LENGTH: usize = 4;
+// refuse messages larger than this, a corrupt header must not make us buffer forever.
+pub const MAX_MSGPACK_FRAME_LENGTH: usize = 16 * 1024 * 1024;
+
+/// MessagePack encoded frame, the payload is prefixed with its length.
+///
+/// Use `MsgPackFrame::empty()` as the prototype passed to `Connection::read_frame`.
+#[derive(Debug, Clone)]
+pub struct MsgPackFrame<T> {
+    value: Option<T>,
+}
+
+impl<T> MsgPackFrame<T> {
+    pub fn new(value: T) -> Self {
+        Self { value: Some(value) }
+    }
+
+    pub fn empty() -> Self {
+        Self { value: None }
+    }
+
+    pub fn get(&self) -> Option<&T> {
+        self.value.as_ref()
+    }
+
+    pub fn into_inner(self) -> Option<T> {
+        self.value
+    }
+}
+
+impl<T> Default for MsgPackFrame<T> {
+    fn default() -> Self {
+        Self::empty()
+    }
+}
+
+impl<T> Frame for MsgPackFrame<T>
+where
+    T: Serialize + DeserializeOwned + Send + Sync + Clone + 'static,
+{
+    fn read(&self, buf: &mut std::io::Cursor<&[u8]>) -> anyhow::Result<Self, FrameError> {
+        let start = buf.position();
+
+        if buf.remaining() < HEADER_LENGTH {
+            return Err(FrameError::Incomplete);
+        }
+        let length = buf.get_u32() as usize;
+        if length > MAX_MSGPACK_FRAME_LENGTH {
+            return Err(FrameError::ParseError(format!(
+                "msgpack frame length {} exceeds limit {}",
+                length, MAX_MSGPACK_FRAME_LENGTH
+            )));
+        }
+        if buf.remaining() < length {
+            // nothing must be consumed until the whole frame is buffered
+            buf.set_position(start);
+            return Err(FrameError::Incomplete);
+        }
+
+        let begin = buf.position() as usize;
+        let payload = &buf.get_ref()[begin..begin + length];
+        let value = rmp_serde::from_slice::<T>(payload)
+            .map_err(|e| FrameError::ParseError(e.to_string()))?;
+        buf.advance(length);
+
+        Ok(Self::new(value))
+    }
+
+    fn write<W>(&self, w: &mut W) -> anyhow::Result<(), FrameError>
+    where
+        W: std::io::Write,
+    {
+        let value = match &self.value {
+            Some(value) => value,
+            None => return Err(FrameError::ParseError("msgpack frame is empty".into())),
+        };
+
+        let payload =
+            rmp_serde::to_vec_named(value).map_err(|e| FrameError::ParseError(e.to_string()))?;
+        if payload.len() > MAX_MSGPACK_FRAME_LENGTH {
+            return Err(FrameError::ParseError(format!(
+                "msgpack frame length {} exceeds limit {}",
+                payload.len(),
+                MAX_MSGPACK_FRAME_LENGTH
+            )));
+        }
+
+        w.write_all(&(payload.len() as u32).to_be_bytes())
+            .and_then(|_| w.write_all(&payload))
+            .map_err(|e| FrameError::ParseError(e.to_string()))
+    }
+}
+
+/// Streaming decoder for the `MsgPackFrame` wire format, remembers the
+/// parsed length prefix while waiting for the payload.
+#[derive(Debug)]
+pub struct MsgPackDecoder<T> {
+    length: Option<usize>,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T> MsgPackDecoder<T> {
+    pub fn new() -> Self {
+        Self {
+            length: None,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T> Default for MsgPackDecoder<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: DeserializeOwned> Decoder for MsgPackDecoder<T> {
+    type Item = T;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> anyhow::Result<Decoded<T>, FrameError> {
+        let length = match self.length {
+            Some(length) => length,
+            None => {
+                if buf.len() < HEADER_LENGTH {
+                    return Ok(Decoded::NeedMore(Some(HEADER_LENGTH - buf.len())));
+                }
+                let length = buf.get_u32() as usize;
+                if length > MAX_MSGPACK_FRAME_LENGTH {
+                    return Err(FrameError::ParseError(format!(
+                        "msgpack frame length {} exceeds limit {}",
+                        length, MAX_MSGPACK_FRAME_LENGTH
+                    )));
+                }
+                self.length = Some(length);
+                length
+            }
+        };
+
+        if buf.len() < length {
+            return Ok(Decoded::NeedMore(Some(length - buf.len())));
+        }
+
+        self.length = None;
+        let payload = buf.split_to(length);
+        rmp_serde::from_slice::<T>(&payload)
+            .map(Decoded::Item)
+            .map_err(|e| FrameError::ParseError(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_msgpack_roundtrip() {
+        let mut value = HashMap::new();
+        value.insert("service".to_string(), 1u32);
+
+        let mut w = vec![];
+        MsgPackFrame::new(value.clone()).write(&mut w).unwrap();
+
+        let mut buf = std::io::Cursor::new(&w[..]);
+        let frame = MsgPackFrame::<HashMap<String, u32>>::empty()
+            .read(&mut buf)
+            .unwrap();
+        assert_eq!(frame.into_inner(), Some(value));
+        assert_eq!(buf.position() as usize, w.len());
+    }
+
+    #[test]
+    fn test_msgpack_incomplete() {
+        let mut w = vec![];
+        MsgPackFrame::new("crossgate".to_string())
+            .write(&mut w)
+            .unwrap();
+
+        let mut buf = std::io::Cursor::new(&w[..w.len() - 1]);
+        let res = MsgPackFrame::<String>::empty().read(&mut buf);
+        assert!(matches!(res, Err(FrameError::Incomplete)));
+        assert_eq!(buf.position(), 0);
+    }
+
+    #[test]
+    fn test_msgpack_decoder_partial() {
+        let mut w = vec![];
+        MsgPackFrame::new("crossgate".to_string())
+            .write(&mut w)
+            .unwrap();
+
+        let mut decoder = MsgPackDecoder::<String>::new();
+        let mut buf = BytesMut::from(&w[..2]);
+        assert!(matches!(
+            decoder.decode(&mut buf),
+            Ok(Decoded::NeedMore(Some(2)))
+        ));
+
+        buf.extend_from_slice(&w[2..6]);
+        assert!(matches!(
+            decoder.decode(&mut buf),
+            Ok(Decoded::NeedMore(Some(_)))
+        ));
+
+        buf.extend_from_slice(&w[6..]);
+        match decoder.decode(&mut buf) {
+            Ok(Decoded::Item(v)) => assert_eq!(v, "crossgate"),
+            _ => panic!("expect decoded item"),
+        }
+        assert!(buf.is_empty());
+    }
+}
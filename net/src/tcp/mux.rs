@@ -0,0 +1,337 @@
+use bytes::Buf;
+use std::collections::HashMap;
+use std::pin::Pin;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{Connection, ConnectionError, Frame, FrameError, Handle};
+
+// correlation id (u64) + kind (u8) in front of every multiplexed frame.
+const HEADER_LENGTH: usize = 9;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MuxKind {
+    Request,
+    Response,
+    // the service failed, the frame carries no payload.
+    Error,
+}
+
+impl MuxKind {
+    fn as_u8(&self) -> u8 {
+        match self {
+            MuxKind::Request => 0,
+            MuxKind::Response => 1,
+            MuxKind::Error => 2,
+        }
+    }
+
+    fn from_u8(v: u8) -> Result<Self, FrameError> {
+        match v {
+            0 => Ok(MuxKind::Request),
+            1 => Ok(MuxKind::Response),
+            2 => Ok(MuxKind::Error),
+            _ => Err(FrameError::ParseError(format!("unknown mux kind {}", v))),
+        }
+    }
+}
+
+/// Wraps any `Frame` with a correlation id so that many requests can be
+/// in flight on one connection and responses may come back out of order.
+#[derive(Debug, Clone)]
+pub struct MuxFrame<F> {
+    pub id: u64,
+    pub kind: MuxKind,
+    pub inner: Option<F>,
+}
+
+impl<F: Frame> MuxFrame<F> {
+    pub fn request(id: u64, inner: F) -> Self {
+        Self {
+            id,
+            kind: MuxKind::Request,
+            inner: Some(inner),
+        }
+    }
+
+    pub fn response(id: u64, inner: F) -> Self {
+        Self {
+            id,
+            kind: MuxKind::Response,
+            inner: Some(inner),
+        }
+    }
+
+    pub fn error(id: u64) -> Self {
+        Self {
+            id,
+            kind: MuxKind::Error,
+            inner: None,
+        }
+    }
+
+    // prototype used by `Connection::read_frame`, the inner prototype decodes the payload.
+    fn prototype(inner: F) -> Self {
+        Self {
+            id: 0,
+            kind: MuxKind::Request,
+            inner: Some(inner),
+        }
+    }
+}
+
+impl<F: Frame> Frame for MuxFrame<F> {
+    fn read(&self, buf: &mut std::io::Cursor<&[u8]>) -> anyhow::Result<Self, FrameError> {
+        let start = buf.position();
+
+        if buf.remaining() < HEADER_LENGTH {
+            return Err(FrameError::Incomplete);
+        }
+        let id = buf.get_u64();
+        let kind = MuxKind::from_u8(buf.get_u8())?;
+
+        if kind == MuxKind::Error {
+            return Ok(Self {
+                id,
+                kind,
+                inner: None,
+            });
+        }
+
+        let prototype = match &self.inner {
+            Some(prototype) => prototype,
+            None => return Err(FrameError::ParseError("mux prototype is empty".into())),
+        };
+
+        match prototype.read(buf) {
+            Ok(inner) => Ok(Self {
+                id,
+                kind,
+                inner: Some(inner),
+            }),
+            Err(FrameError::Incomplete) => {
+                // the header must be parsed again once the payload is complete
+                buf.set_position(start);
+                Err(FrameError::Incomplete)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn write<W>(&self, w: &mut W) -> anyhow::Result<(), FrameError>
+    where
+        W: std::io::Write,
+    {
+        w.write_all(&self.id.to_be_bytes())
+            .and_then(|_| w.write_all(&[self.kind.as_u8()]))
+            .map_err(|e| FrameError::ParseError(e.to_string()))?;
+
+        match &self.inner {
+            Some(inner) if self.kind != MuxKind::Error => inner.write(w),
+            _ => Ok(()),
+        }
+    }
+}
+
+type Pending<F> = oneshot::Sender<Result<F, ConnectionError>>;
+
+/// Client side of the multiplexing protocol, cheap to clone and share
+/// between tasks; a background task owns the connection.
+#[derive(Clone)]
+pub struct MuxClient<F> {
+    tx: mpsc::Sender<(F, Pending<F>)>,
+}
+
+impl<F: Frame> MuxClient<F> {
+    pub fn new(connection: Connection, prototype: F) -> Self {
+        let (tx, rx) = mpsc::channel(1024);
+        tokio::spawn(Self::drive(connection, prototype, rx));
+        Self { tx }
+    }
+
+    pub async fn call(&self, request: F) -> Result<F, ConnectionError> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+
+        if self.tx.send((request, resp_tx)).await.is_err() {
+            return Err(ConnectionError::Fin);
+        }
+
+        match resp_rx.await {
+            Ok(res) => res,
+            Err(_) => Err(ConnectionError::Fin),
+        }
+    }
+
+    async fn drive(
+        mut connection: Connection,
+        prototype: F,
+        mut rx: mpsc::Receiver<(F, Pending<F>)>,
+    ) {
+        let prototype = MuxFrame::prototype(prototype);
+        let mut pending: HashMap<u64, Pending<F>> = HashMap::new();
+        let mut next_id: u64 = 0;
+
+        let err = loop {
+            tokio::select! {
+                req = rx.recv() => {
+                    let (request, resp_tx) = match req {
+                        Some(req) => req,
+                        // every client handle is gone
+                        None => return,
+                    };
+
+                    next_id = next_id.wrapping_add(1);
+                    if let Err(e) = connection.write_frame(MuxFrame::request(next_id, request)).await {
+                        let _ = resp_tx.send(Err(e.clone()));
+                        break e;
+                    }
+                    pending.insert(next_id, resp_tx);
+                },
+                resp = connection.read_frame(&prototype) => {
+                    let frame = match resp {
+                        Ok(Some(frame)) => frame,
+                        Ok(None) => continue,
+                        Err(e) => break e,
+                    };
+
+                    let resp_tx = match pending.remove(&frame.id) {
+                        Some(resp_tx) => resp_tx,
+                        None => {
+                            tracing::warn!(id = frame.id, "mux response for unknown request");
+                            continue;
+                        }
+                    };
+
+                    let res = match (frame.kind, frame.inner) {
+                        (MuxKind::Response, Some(inner)) => Ok(inner),
+                        _ => Err(ConnectionError::Other(crate::NetError::InternalError(
+                            format!("mux request {} failed", frame.id),
+                        ))),
+                    };
+                    let _ = resp_tx.send(res);
+                },
+            }
+        };
+
+        for (_, resp_tx) in pending.drain() {
+            let _ = resp_tx.send(Err(err.clone()));
+        }
+    }
+}
+
+/// Request handler for the server side of the multiplexing protocol.
+pub trait MuxService<F: Frame>: Send + Sync + Clone + 'static {
+    type Future: futures::Future<Output = Result<F, ConnectionError>> + Send + 'static;
+
+    fn call(&self, request: F) -> Self::Future;
+}
+
+/// `Handle` that serves multiplexed requests concurrently, responses are
+/// written back in completion order.
+#[derive(Clone)]
+pub struct MuxHandler<S, F> {
+    service: S,
+    prototype: F,
+}
+
+impl<S, F> MuxHandler<S, F>
+where
+    S: MuxService<F>,
+    F: Frame,
+{
+    pub fn new(service: S, prototype: F) -> Self {
+        Self { service, prototype }
+    }
+}
+
+impl<S, F> Handle for MuxHandler<S, F>
+where
+    S: MuxService<F>,
+    F: Frame,
+{
+    type HandleFuture<'a> =
+        Pin<Box<dyn futures::Future<Output = Result<(), ConnectionError>> + Send + Sync + 'a>>;
+
+    fn handle<'r>(self, conn: &'r mut Connection) -> Self::HandleFuture<'r> {
+        Box::pin(async move {
+            let prototype = MuxFrame::prototype(self.prototype);
+            let (tx, mut rx) = mpsc::channel::<MuxFrame<F>>(1024);
+
+            loop {
+                tokio::select! {
+                    req = conn.read_frame(&prototype) => {
+                        let frame = match req? {
+                            Some(frame) => frame,
+                            None => continue,
+                        };
+
+                        let request = match (frame.kind, frame.inner) {
+                            (MuxKind::Request, Some(request)) => request,
+                            _ => {
+                                tracing::warn!(id = frame.id, "mux server got a non request frame");
+                                continue;
+                            }
+                        };
+
+                        let id = frame.id;
+                        let tx = tx.clone();
+                        let call = self.service.call(request);
+                        tokio::spawn(async move {
+                            let resp = match call.await {
+                                Ok(resp) => MuxFrame::response(id, resp),
+                                Err(e) => {
+                                    tracing::error!(id, error = ?e, "mux request failed");
+                                    MuxFrame::error(id)
+                                }
+                            };
+                            let _ = tx.send(resp).await;
+                        });
+                    },
+                    Some(resp) = rx.recv() => {
+                        conn.write_frame(resp).await?;
+                    },
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MsgPackFrame;
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[derive(Clone)]
+    struct Echo;
+
+    impl MuxService<MsgPackFrame<String>> for Echo {
+        type Future = futures::future::Ready<Result<MsgPackFrame<String>, ConnectionError>>;
+
+        fn call(&self, request: MsgPackFrame<String>) -> Self::Future {
+            futures::future::ready(Ok(request))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mux_concurrent_calls() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(crate::run(
+            listener,
+            MuxHandler::new(Echo, MsgPackFrame::empty()),
+            futures::future::pending::<()>(),
+        ));
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let client = MuxClient::new(Connection::new(stream), MsgPackFrame::<String>::empty());
+
+        let calls = (0..16).map(|i| {
+            let client = client.clone();
+            async move {
+                let resp = client.call(MsgPackFrame::new(i.to_string())).await.unwrap();
+                assert_eq!(resp.into_inner(), Some(i.to_string()));
+            }
+        });
+        futures::future::join_all(calls).await;
+    }
+}
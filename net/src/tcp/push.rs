@@ -0,0 +1,49 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use tokio::sync::mpsc;
+
+use crate::{ConnectionError, Frame};
+
+/// Cloneable writer for a single connection, usable from tasks outside the
+/// per-connection handler. Pushed frames are written while the handler waits
+/// in `read_frame`/`read_decoded`, or on `Connection::flush_pushed`.
+#[derive(Debug, Clone)]
+pub struct ConnectionHandle {
+    pub(crate) tx: mpsc::Sender<Bytes>,
+}
+
+impl ConnectionHandle {
+    pub(crate) fn serialize<F: Frame>(frame: &F) -> Result<Bytes, ConnectionError> {
+        let mut buf = BytesMut::new().writer();
+        frame.write(&mut buf).map_err(ConnectionError::FrameError)?;
+        Ok(buf.into_inner().freeze())
+    }
+
+    // waits for room in the push queue.
+    pub async fn push<F: Frame>(&self, frame: &F) -> Result<(), ConnectionError> {
+        self.push_bytes(Self::serialize(frame)?).await
+    }
+
+    // returns `ConnectionError::Throttled` instead of waiting when the queue is full.
+    pub fn try_push<F: Frame>(&self, frame: &F) -> Result<(), ConnectionError> {
+        self.try_push_bytes(Self::serialize(frame)?)
+    }
+
+    pub(crate) async fn push_bytes(&self, payload: Bytes) -> Result<(), ConnectionError> {
+        self.tx
+            .send(payload)
+            .await
+            .map_err(|_| ConnectionError::Fin)
+    }
+
+    pub(crate) fn try_push_bytes(&self, payload: Bytes) -> Result<(), ConnectionError> {
+        self.tx.try_send(payload).map_err(|e| match e {
+            mpsc::error::TrySendError::Full(_) => ConnectionError::Throttled,
+            mpsc::error::TrySendError::Closed(_) => ConnectionError::Fin,
+        })
+    }
+
+    // the connection went away.
+    pub fn is_closed(&self) -> bool {
+        self.tx.is_closed()
+    }
+}
@@ -0,0 +1,121 @@
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimitAction {
+    // `read_frame` waits until the bucket refilled, the connection stays open;
+    // with a rate of 0 it never does and `ConnectionError::Throttled` is returned.
+    Throttle,
+    // the connection is closed with `ConnectionError::RateLimited`.
+    Close,
+}
+
+/// Token bucket limiting the frames read from a single connection.
+#[derive(Debug, Clone)]
+pub struct RateLimitConfig {
+    pub frames_per_second: u32,
+    // frames that may be read in a burst above the steady rate.
+    pub burst: u32,
+    pub action: RateLimitAction,
+}
+
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    tokens: f64,
+    capacity: f64,
+    rate: f64,
+    last: Instant,
+    pub(crate) action: RateLimitAction,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(config: &RateLimitConfig) -> Self {
+        let capacity = config.burst.max(1) as f64;
+        Self {
+            tokens: capacity,
+            capacity,
+            rate: config.frames_per_second as f64,
+            last: Instant::now(),
+            action: config.action,
+        }
+    }
+
+    // refill the bucket and report whether a frame may be read.
+    pub(crate) fn check(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.tokens >= 1.0
+    }
+
+    pub(crate) fn consume(&mut self) {
+        self.tokens -= 1.0;
+    }
+
+    // until the next frame may be read, None if the bucket never refills.
+    pub(crate) fn refill_in(&self) -> Option<Duration> {
+        if self.rate <= 0.0 {
+            return None;
+        }
+        Some(Duration::from_secs_f64(
+            (1.0 - self.tokens).max(0.0) / self.rate,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter_burst() {
+        let mut limiter = RateLimiter::new(&RateLimitConfig {
+            frames_per_second: 1,
+            burst: 2,
+            action: RateLimitAction::Throttle,
+        });
+
+        assert!(limiter.check());
+        limiter.consume();
+        assert!(limiter.check());
+        limiter.consume();
+        assert!(!limiter.check());
+    }
+
+    #[tokio::test]
+    async fn test_throttled_connection_survives() {
+        use crate::{Connection, MsgPackFrame};
+        use tokio::net::{TcpListener, TcpStream};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let mut server = Connection::new(server);
+        let mut client = Connection::new(client);
+        server.set_rate_limit(&RateLimitConfig {
+            frames_per_second: 20,
+            burst: 1,
+            action: RateLimitAction::Throttle,
+        });
+
+        for i in 0..3 {
+            client
+                .write_frame(MsgPackFrame::new(i.to_string()))
+                .await
+                .unwrap();
+        }
+        let start = Instant::now();
+        for i in 0..3 {
+            let frame = server
+                .read_frame(&MsgPackFrame::<String>::empty())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(frame.into_inner(), Some(i.to_string()));
+        }
+        // the two frames past the burst waited for a token each.
+        assert!(start.elapsed() >= Duration::from_millis(90));
+    }
+}
@@ -1,22 +1,212 @@
 use super::Listener;
-use crate::Handle;
+use crate::{
+    Handle, HeartbeatConfig, Phase, RateLimitConfig, Shutdown, SocketOptions,
+    DEFAULT_MAX_BUFFER_SIZE,
+};
 use futures::Future;
-use tokio::{net::TcpListener, sync::broadcast};
+use std::{sync::Arc, time::Duration};
+use tokio::{
+    net::TcpListener,
+    sync::{broadcast, mpsc, watch, Semaphore},
+};
+
+pub const DEFAULT_MAX_CONNECTIONS: usize = 1024;
+pub const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    // maximum number of connections handled concurrently.
+    pub max_connections: usize,
+    // close connections that have not received any bytes for this duration.
+    pub idle_timeout: Option<Duration>,
+    // how long handlers may finish their current frame after shutdown is
+    // requested before their connections are closed.
+    pub drain_timeout: Duration,
+    // applied to every accepted connection.
+    pub socket_options: SocketOptions,
+    // abort connections buffering more than this many unparsed bytes.
+    pub max_buffer_size: usize,
+    // ping idle peers and close the ones that stop answering.
+    pub heartbeat: Option<HeartbeatConfig>,
+    // frames per second accepted from a single connection.
+    pub rate_limit: Option<RateLimitConfig>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            idle_timeout: None,
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            socket_options: SocketOptions::default(),
+            max_buffer_size: DEFAULT_MAX_BUFFER_SIZE,
+            heartbeat: None,
+            rate_limit: None,
+        }
+    }
+}
 
 pub async fn run<'a>(listener: TcpListener, h: impl Handle, shutdown: impl Future) {
+    run_with_config(listener, h, shutdown, ServerConfig::default()).await
+}
+
+// stops accepting when `shutdown` does, and holds its drain phase until the
+// connections are closed. `config.drain_timeout` is replaced by the deadline
+// of that phase.
+pub async fn run_with_shutdown(
+    listener: TcpListener,
+    h: impl Handle,
+    shutdown: &Shutdown,
+    config: ServerConfig,
+) {
+    let _draining = shutdown.guard(Phase::Drain);
+    let config = ServerConfig {
+        drain_timeout: shutdown.phase_deadline(Phase::Drain),
+        ..config
+    };
+    run_with_config(listener, h, shutdown.reached(Phase::StopAccepting), config).await
+}
+
+pub async fn run_with_config(
+    listener: TcpListener,
+    h: impl Handle,
+    shutdown: impl Future,
+    config: ServerConfig,
+) {
     let (notify_shutdown, _) = broadcast::channel(16);
+    let (notify_drain, _) = watch::channel(false);
+    let (shutdown_complete_tx, mut shutdown_complete_rx) = mpsc::channel(1);
 
     let mut server = Listener {
         listener,
         notify_shutdown,
+        notify_drain,
+        limit_connections: Arc::new(Semaphore::new(config.max_connections)),
+        idle_timeout: config.idle_timeout,
+        socket_options: config.socket_options.clone(),
+        max_buffer_size: config.max_buffer_size,
+        heartbeat: config.heartbeat.clone(),
+        rate_limit: config.rate_limit.clone(),
+        shutdown_complete_tx,
     };
 
     tokio::select! {
-        _ = server.run(h) => {},
-        _ = shutdown => {log::info!("shutdown !!")},
+        res = server.run(h) => {
+            if let Err(err) = res {
+                tracing::error!(error = ?err, "listener failed");
+            }
+        },
+        _ = shutdown => {tracing::info!("shutdown")},
     }
 
-    // let Listener {
-    //     notify_shutdown, ..
-    // } = server;
+    let Listener {
+        listener,
+        notify_shutdown,
+        notify_drain,
+        shutdown_complete_tx,
+        ..
+    } = server;
+    // stop accepting while the existing connections drain.
+    drop(listener);
+    drop(shutdown_complete_tx);
+
+    // handlers close once the frame at hand is done, idle ones right away.
+    // the receiver yields `None` once every handler has dropped its sender.
+    notify_drain.send_replace(true);
+    if tokio::time::timeout(config.drain_timeout, shutdown_complete_rx.recv())
+        .await
+        .is_err()
+    {
+        tracing::warn!("drain timeout exceeded, closing remaining connections");
+        drop(notify_shutdown);
+        let _ = shutdown_complete_rx.recv().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Connection, ConnectionError, Frame, MsgPackFrame};
+    use std::pin::Pin;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+        sync::oneshot,
+        task::JoinHandle,
+    };
+
+    #[derive(Clone)]
+    struct Echo;
+
+    impl Handle for Echo {
+        type HandleFuture<'a> =
+            Pin<Box<dyn Future<Output = Result<(), ConnectionError>> + Send + Sync + 'a>>;
+
+        fn handle<'r>(self, conn: &'r mut Connection) -> Self::HandleFuture<'r> {
+            Box::pin(async move {
+                let prototype = MsgPackFrame::<String>::empty();
+                loop {
+                    if let Some(frame) = conn.read_frame(&prototype).await? {
+                        conn.write_frame(frame).await?;
+                    }
+                }
+            })
+        }
+    }
+
+    async fn serve() -> (std::net::SocketAddr, oneshot::Sender<()>, JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = oneshot::channel::<()>();
+        let config = ServerConfig {
+            drain_timeout: Duration::from_secs(30),
+            ..Default::default()
+        };
+        let server = tokio::spawn(run_with_config(listener, Echo, rx, config));
+        (addr, tx, server)
+    }
+
+    #[tokio::test]
+    async fn idle_connections_do_not_delay_shutdown() {
+        let (addr, shutdown, server) = serve().await;
+        let mut idle = TcpStream::connect(addr).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        shutdown.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(1), server)
+            .await
+            .expect("shutdown waited for the drain timeout")
+            .unwrap();
+        assert_eq!(idle.read(&mut [0; 8]).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn frames_in_flight_finish_before_close() {
+        let (addr, shutdown, server) = serve().await;
+        let mut bytes = vec![];
+        MsgPackFrame::new("in flight".to_string())
+            .write(&mut bytes)
+            .unwrap();
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(&bytes[..3]).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        shutdown.send(()).unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        stream.write_all(&bytes[3..]).await.unwrap();
+
+        let mut conn = Connection::new(stream);
+        let echoed = conn
+            .read_frame(&MsgPackFrame::<String>::empty())
+            .await
+            .unwrap();
+        assert_eq!(
+            echoed.and_then(|frame| frame.into_inner()),
+            Some("in flight".to_string())
+        );
+        tokio::time::timeout(Duration::from_secs(1), server)
+            .await
+            .expect("shutdown waited for the drain timeout")
+            .unwrap();
+    }
 }
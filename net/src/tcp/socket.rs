@@ -0,0 +1,53 @@
+use socket2::{SockRef, TcpKeepalive};
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+#[derive(Debug, Clone)]
+pub struct KeepaliveOptions {
+    // idle time before the first probe is sent.
+    pub time: Duration,
+    // interval between probes, os default when unset.
+    pub interval: Option<Duration>,
+    // probes sent before the connection is dropped, os default when unset.
+    pub retries: Option<u32>,
+}
+
+/// Options applied to every accepted stream before it is wrapped in a `Connection`.
+#[derive(Debug, Clone, Default)]
+pub struct SocketOptions {
+    // disable Nagle's algorithm, framed request/response protocols usually want this.
+    pub nodelay: bool,
+    pub keepalive: Option<KeepaliveOptions>,
+    pub recv_buffer_size: Option<usize>,
+    pub send_buffer_size: Option<usize>,
+}
+
+impl SocketOptions {
+    pub fn apply(&self, stream: &TcpStream) -> std::io::Result<()> {
+        stream.set_nodelay(self.nodelay)?;
+
+        let socket = SockRef::from(stream);
+
+        if let Some(keepalive) = &self.keepalive {
+            let mut params = TcpKeepalive::new().with_time(keepalive.time);
+            #[cfg(not(target_os = "openbsd"))]
+            if let Some(interval) = keepalive.interval {
+                params = params.with_interval(interval);
+            }
+            #[cfg(not(any(target_os = "openbsd", target_os = "windows")))]
+            if let Some(retries) = keepalive.retries {
+                params = params.with_retries(retries);
+            }
+            socket.set_tcp_keepalive(&params)?;
+        }
+
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+
+        Ok(())
+    }
+}
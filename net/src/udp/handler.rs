@@ -0,0 +1,17 @@
+use bytes::Bytes;
+use std::net::SocketAddr;
+
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum UdpError {
+    #[error("io error: {0}")]
+    IoError(String),
+    #[error(transparent)]
+    Other(#[from] crate::NetError),
+}
+
+// datagram in --> optional datagram out, the reply is sent back to the peer.
+pub trait DatagramHandle: Sync + Send + Clone + 'static {
+    type HandleFuture: futures::Future<Output = Result<Option<Bytes>, UdpError>> + Send;
+
+    fn handle(self, peer: SocketAddr, datagram: Bytes) -> Self::HandleFuture;
+}
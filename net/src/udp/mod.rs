@@ -0,0 +1,5 @@
+mod handler;
+pub use handler::{DatagramHandle, UdpError};
+
+mod server;
+pub use server::{run, run_with_config, run_with_shutdown, UdpConfig, DEFAULT_MAX_DATAGRAM_SIZE};
@@ -0,0 +1,191 @@
+use super::DatagramHandle;
+use crate::{Phase, Shutdown};
+use bytes::{Bytes, BytesMut};
+use futures::Future;
+use std::{sync::Arc, time::Duration};
+use tokio::{
+    net::UdpSocket,
+    sync::{broadcast, mpsc, Semaphore},
+};
+
+// largest payload of an ipv4 udp datagram.
+pub const DEFAULT_MAX_DATAGRAM_SIZE: usize = 65507;
+
+#[derive(Debug, Clone)]
+pub struct UdpConfig {
+    pub max_datagram_size: usize,
+    // maximum number of datagrams handled concurrently.
+    pub max_inflight: usize,
+    // how long in-flight handlers may keep running after shutdown is requested.
+    pub drain_timeout: Duration,
+}
+
+impl Default for UdpConfig {
+    fn default() -> Self {
+        Self {
+            max_datagram_size: DEFAULT_MAX_DATAGRAM_SIZE,
+            max_inflight: crate::DEFAULT_MAX_CONNECTIONS,
+            drain_timeout: crate::DEFAULT_DRAIN_TIMEOUT,
+        }
+    }
+}
+
+struct Server {
+    socket: Arc<UdpSocket>,
+    notify_shutdown: broadcast::Sender<()>,
+    limit_inflight: Arc<Semaphore>,
+    shutdown_complete_tx: mpsc::Sender<()>,
+    max_datagram_size: usize,
+}
+
+impl Server {
+    async fn run<H>(&mut self, h: H) -> anyhow::Result<()>
+    where
+        H: DatagramHandle,
+    {
+        let mut buf = BytesMut::zeroed(self.max_datagram_size);
+
+        loop {
+            let permit = self.limit_inflight.clone().acquire_owned().await?;
+
+            // errors like an icmp port unreachable for an earlier reply only
+            // concern that peer, the socket keeps serving everyone else.
+            let (n, peer) = match self.socket.recv_from(&mut buf[..]).await {
+                Ok(received) => received,
+                Err(err) => {
+                    tracing::warn!(error = ?err, "datagram receive failed");
+                    continue;
+                }
+            };
+            let datagram = Bytes::copy_from_slice(&buf[..n]);
+
+            let h = h.clone();
+            let socket = self.socket.clone();
+            let mut shutdown = self.notify_shutdown.subscribe();
+            let shutdown_complete = self.shutdown_complete_tx.clone();
+
+            tokio::spawn(async move {
+                tokio::select! {
+                    res = h.handle(peer, datagram) => match res {
+                        Ok(Some(reply)) => {
+                            if let Err(err) = socket.send_to(&reply, peer).await {
+                                tracing::error!(peer = ?peer, error = ?err, "datagram reply failed");
+                            }
+                        }
+                        Ok(None) => {}
+                        Err(err) => tracing::error!(peer = ?peer, error = ?err, "datagram failed"),
+                    },
+                    _ = shutdown.recv() => {},
+                }
+                drop(shutdown_complete);
+                drop(permit);
+            });
+        }
+    }
+}
+
+pub async fn run(socket: UdpSocket, h: impl DatagramHandle, shutdown: impl Future) {
+    run_with_config(socket, h, shutdown, UdpConfig::default()).await
+}
+
+// like `tcp::run_with_shutdown`.
+pub async fn run_with_shutdown(
+    socket: UdpSocket,
+    h: impl DatagramHandle,
+    shutdown: &Shutdown,
+    config: UdpConfig,
+) {
+    let _draining = shutdown.guard(Phase::Drain);
+    let config = UdpConfig {
+        drain_timeout: shutdown.phase_deadline(Phase::Drain),
+        ..config
+    };
+    run_with_config(socket, h, shutdown.reached(Phase::StopAccepting), config).await
+}
+
+pub async fn run_with_config(
+    socket: UdpSocket,
+    h: impl DatagramHandle,
+    shutdown: impl Future,
+    config: UdpConfig,
+) {
+    let (notify_shutdown, _) = broadcast::channel(16);
+    let (shutdown_complete_tx, mut shutdown_complete_rx) = mpsc::channel(1);
+
+    let mut server = Server {
+        socket: Arc::new(socket),
+        notify_shutdown,
+        limit_inflight: Arc::new(Semaphore::new(config.max_inflight)),
+        shutdown_complete_tx,
+        max_datagram_size: config.max_datagram_size,
+    };
+
+    tokio::select! {
+        res = server.run(h) => {
+            if let Err(err) = res {
+                tracing::error!(error = ?err, "udp server failed");
+            }
+        },
+        _ = shutdown => {tracing::info!("shutdown")},
+    }
+
+    let Server {
+        notify_shutdown,
+        shutdown_complete_tx,
+        ..
+    } = server;
+    drop(shutdown_complete_tx);
+
+    // same drain logic as the tcp server.
+    if tokio::time::timeout(config.drain_timeout, shutdown_complete_rx.recv())
+        .await
+        .is_err()
+    {
+        tracing::warn!("drain timeout exceeded, dropping remaining datagrams");
+        drop(notify_shutdown);
+        let _ = shutdown_complete_rx.recv().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::udp::UdpError;
+
+    #[derive(Clone)]
+    struct Echo;
+
+    impl DatagramHandle for Echo {
+        type HandleFuture = futures::future::Ready<Result<Option<Bytes>, UdpError>>;
+
+        fn handle(self, _peer: std::net::SocketAddr, datagram: Bytes) -> Self::HandleFuture {
+            futures::future::ready(Ok(Some(datagram)))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_recv_error_keeps_serving() {
+        let gone = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let gone_addr = gone.local_addr().unwrap();
+        drop(gone);
+
+        // a connected socket reports the icmp port unreachable on its next receive.
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        socket.connect(gone_addr).await.unwrap();
+        socket.send(b"ping").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        tokio::spawn(run(socket, Echo, futures::future::pending::<()>()));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let client = UdpSocket::bind(gone_addr).await.unwrap();
+        client.send_to(b"hello", addr).await.unwrap();
+        let mut buf = [0; 16];
+        let (n, _) = tokio::time::timeout(Duration::from_secs(1), client.recv_from(&mut buf))
+            .await
+            .expect("server stopped after the receive error")
+            .unwrap();
+        assert_eq!(&buf[..n], b"hello");
+    }
+}
@@ -1,112 +1,712 @@
 use futures::lock::Mutex;
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
 use url::Url;
 
-use crossbeam::sync::WaitGroup;
-use rs_consul::{Config, Consul, RegisterEntityPayload, RegisterEntityService};
-use tokio_context::context::Context;
+use hyper::{Body, Method, Request, Response};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
-use crate::{async_trait, ServiceContent};
+use net::{Phase, Shutdown};
+
+use crate::handle::{watch_span, Events};
+use crate::{
+    async_trait, ConnectCerts, HealthStatus, PluginConfig, PluginError, ServiceContent,
+    ServiceHealth,
+};
 use crate::{Plugin, Synchronize};
 
+// the ttl check of a registration fails unless renewed within TTL, consul
+// removes the registration a minute after that.
+const TTL: Duration = Duration::from_secs(15);
+const RENEW: Duration = Duration::from_secs(5);
+const DEREGISTER_AFTER: &str = "1m";
+// how long a blocking query waits for a change.
+const WAIT: &str = "30s";
+const RETRY: Duration = Duration::from_secs(1);
+
+// the registration key and the content, split over meta values as those
+// take 512 bytes at most.
+const META_KEY: &str = "crossgate-key";
+const META_CONTENT: &str = "crossgate-content-";
+const META_VALUE_MAX: usize = 512;
+
+// where registrations of each service type are told apart.
+fn tag(r#type: i32) -> &'static str {
+    match r#type {
+        2 => "backend",
+        3 => "gateway",
+        _ => "web",
+    }
+}
+
+// consul service names are dns labels, e.g. `t-ums` for `/t/ums`; the key
+// itself is in the meta.
+fn service_name(key: &str) -> String {
+    let name = key
+        .trim_matches('/')
+        .chars()
+        .map(|c| match c.is_ascii_alphanumeric() || c == '-' {
+            true => c,
+            false => '-',
+        })
+        .collect::<String>();
+    match name.is_empty() {
+        true => "crossgate".to_string(),
+        false => name,
+    }
+}
+
+// the status the ttl check of a registration reports.
+fn check_status(health: Option<&ServiceHealth>) -> (&'static str, String) {
+    match health {
+        None => ("passing", String::new()),
+        Some(health) => match health.status {
+            HealthStatus::Healthy => ("passing", health.detail.clone()),
+            HealthStatus::Degraded => ("warning", health.detail.clone()),
+            HealthStatus::Unhealthy | HealthStatus::Draining => ("critical", health.detail.clone()),
+        },
+    }
+}
+
+// `value` in pieces of at most `max` bytes, cut between chars.
+fn split(value: &str, max: usize) -> Vec<&str> {
+    let mut pieces = vec![];
+    let mut rest = value;
+    while !rest.is_empty() {
+        let mut end = rest.len().min(max);
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        let (piece, tail) = rest.split_at(end);
+        pieces.push(piece);
+        rest = tail;
+    }
+    pieces
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct AgentService {
+    #[serde(rename = "ID")]
+    id: String,
+    name: String,
+    address: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    port: Option<u16>,
+    tags: Vec<String>,
+    meta: HashMap<String, String>,
+    weights: Weights,
+    check: AgentCheck,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct Weights {
+    passing: u32,
+    warning: u32,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct AgentCheck {
+    #[serde(rename = "CheckID")]
+    check_id: String,
+    name: String,
+    #[serde(rename = "TTL")]
+    ttl: String,
+    status: &'static str,
+    deregister_critical_service_after: &'static str,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct CheckUpdate {
+    status: &'static str,
+    output: String,
+}
+
+// an instance in the answer of /v1/health/service, with the checks of its
+// service and node.
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct HealthEntry {
+    service: HealthService,
+    #[serde(default)]
+    checks: Vec<HealthCheck>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct HealthService {
+    #[serde(rename = "ID")]
+    id: String,
+    #[serde(default)]
+    meta: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct HealthCheck {
+    status: String,
+    #[serde(default)]
+    output: String,
+}
+
+// what this process registered, by consul service id.
+#[derive(Debug, Clone)]
+struct Own {
+    key: String,
+    content: ServiceContent,
+}
+
+impl Own {
+    fn id(&self) -> String {
+        let id = format!(
+            "{}-{}-{}",
+            tag(self.content.r#type),
+            service_name(&self.key),
+            self.content.addr
+        );
+        id.replace(
+            |c: char| !c.is_ascii_alphanumeric() && c != '-' && c != '.',
+            "-",
+        )
+    }
+
+    fn check_id(&self) -> String {
+        format!("service:{}", self.id())
+    }
+
+    fn registration(&self) -> anyhow::Result<AgentService> {
+        let content = serde_json::to_string(&self.content)?;
+        let mut meta = HashMap::from([(META_KEY.to_string(), self.key.clone())]);
+        for (i, piece) in split(&content, META_VALUE_MAX).into_iter().enumerate() {
+            meta.insert(format!("{}{}", META_CONTENT, i), piece.to_string());
+        }
+
+        let addr = &self.content.addr;
+        let (address, port) = match addr.rsplit_once(':').map(|(h, p)| (h, p.parse::<u16>())) {
+            Some((host, Ok(port))) => (
+                host.trim_matches(|c: char| c == '[' || c == ']'),
+                Some(port),
+            ),
+            _ => (addr.as_str(), None),
+        };
+        let (status, _) = check_status(self.content.health.as_ref());
+        Ok(AgentService {
+            id: self.id(),
+            name: service_name(&self.key),
+            address: address.to_string(),
+            port,
+            tags: vec![tag(self.content.r#type).to_string()],
+            meta,
+            // consul takes no zero weights, the content keeps the real one.
+            weights: Weights {
+                passing: self.content.weight.max(1),
+                warning: 1,
+            },
+            check: AgentCheck {
+                check_id: self.check_id(),
+                name: format!("{} ttl", self.key),
+                ttl: format!("{}s", TTL.as_secs()),
+                status,
+                deregister_critical_service_after: DEREGISTER_AFTER,
+            },
+        })
+    }
+}
+
+// the registration of an instance listed under `key`, None for those of
+// other keys sharing the service name or not registered by crossgate. A
+// failing check makes it unhealthy unless it is draining already.
+fn content_of(key: &str, entry: &HealthEntry) -> Option<(String, ServiceContent)> {
+    let meta = &entry.service.meta;
+    if meta.get(META_KEY).map(String::as_str) != Some(key) {
+        return None;
+    }
+    let mut content = String::new();
+    for i in 0.. {
+        match meta.get(&format!("{}{}", META_CONTENT, i)) {
+            Some(piece) => content.push_str(piece),
+            None => break,
+        }
+    }
+    let mut content = match serde_json::from_str::<ServiceContent>(&content) {
+        Ok(content) => content,
+        Err(e) => {
+            tracing::error!(id = %entry.service.id, error = %e, "consul skipped an invalid registration");
+            return None;
+        }
+    };
+
+    let worst = |status: &str| entry.checks.iter().find(|check| check.status == status);
+    let reported = content.health.as_ref().map(|health| health.status);
+    let failed = match (worst("critical"), worst("warning"), reported) {
+        (_, _, Some(HealthStatus::Draining)) => None,
+        (Some(check), _, _) => Some((HealthStatus::Unhealthy, check)),
+        (None, Some(check), None | Some(HealthStatus::Healthy)) => {
+            Some((HealthStatus::Degraded, check))
+        }
+        _ => None,
+    };
+    if let Some((status, check)) = failed {
+        content.health = Some(ServiceHealth {
+            status,
+            lag: 0,
+            last_success: 0,
+            detail: check.output.clone(),
+        });
+    }
+    Some((entry.service.id.clone(), content))
+}
+
+// the instances of each watched (type, key), by service id.
+type Watched = HashMap<(i32, String), Vec<(String, ServiceContent)>>;
+
 #[derive(Debug, Clone)]
 pub struct ConsulPlugin {
-    cache: Arc<Mutex<HashMap<String, ServiceContent>>>,
-    client: Arc<Consul>,
+    inner: Arc<Mutex<HashMap<String, Own>>>,
+    // blocking queries keep them current once looked up.
+    cache: Arc<Mutex<Watched>>,
+    address: String,
+    token: Option<String>,
+    events: Events,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct CaRoots {
+    roots: Vec<CaRoot>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct CaRoot {
+    root_cert: String,
+    #[serde(default)]
+    intermediate_certs: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct LeafCert {
+    #[serde(rename = "CertPEM")]
+    cert_pem: String,
+    #[serde(rename = "PrivateKeyPEM")]
+    private_key_pem: String,
+    valid_before: String,
 }
 
 impl ConsulPlugin {
-    pub(super) async fn new() -> Self {
-        dotenv::dotenv().ok();
+    pub(super) async fn new(config: &PluginConfig, events: Events) -> Result<Self, PluginError> {
         // consul://http://localhost:8500
-        let uri = std::env::var("REGISTER_ADDR").expect("REGISTER_ADDR is not set");
+        let (method, host, port) = Self::validation_parse_uri(config.required_addr()?)?;
 
-        let (method, host, port) = Self::validation_parse_uri(&uri);
-        let config = Config {
+        Ok(ConsulPlugin {
+            inner: Arc::new(Mutex::new(HashMap::new())),
+            cache: Arc::new(Mutex::new(HashMap::new())),
             address: format!("{}://{}:{}", method, host, port),
-            ..Default::default()
+            token: config.password.clone(),
+            events,
+        })
+    }
+
+    // a request to the local agent, failing unless it answers with success.
+    async fn request(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<Vec<u8>>,
+    ) -> anyhow::Result<Response<Body>> {
+        let mut req = Request::builder()
+            .method(method.clone())
+            .uri(format!("{}{}", self.address, path))
+            .body(body.map_or_else(Body::empty, Body::from))?;
+        if let Some(token) = &self.token {
+            req.headers_mut().insert("x-consul-token", token.parse()?);
+        }
+        let res = match self.address.starts_with("https://") {
+            true => {
+                net::get_tls_proxy_client(None)
+                    .client()
+                    .request(req)
+                    .await?
+            }
+            false => net::get_proxy_client().client().request(req).await?,
         };
+        if !res.status().is_success() {
+            return Err(PluginError::Http(format!(
+                "{} {} answered {}",
+                method,
+                path,
+                res.status()
+            ))
+            .into());
+        }
+        Ok(res)
+    }
 
-        ConsulPlugin {
-            cache: Arc::new(Mutex::new(HashMap::new())),
-            client: Arc::new(Consul::new(config)),
+    async fn agent_get<T: DeserializeOwned>(&self, path: &str) -> anyhow::Result<T> {
+        let res = self.request(Method::GET, path, None).await?;
+        let body = hyper::body::to_bytes(res.into_body()).await?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    async fn agent_put<T: Serialize>(&self, path: &str, body: Option<&T>) -> anyhow::Result<()> {
+        let body = body.map(serde_json::to_vec).transpose()?;
+        self.request(Method::PUT, path, body).await?;
+        Ok(())
+    }
+
+    fn validation_parse_uri(uri: &str) -> Result<(String, String, u16), PluginError> {
+        let invalid = || PluginError::Config(format!("consul address `{}` is not valid", uri));
+        let url = match uri.strip_prefix("consul://") {
+            Some(url) => url,
+            None => {
+                return Err(PluginError::Config(format!(
+                    "consul address `{}` must start with consul://",
+                    uri
+                )))
+            }
+        };
+        let url = Url::parse(url).map_err(|_| invalid())?;
+        match (url.host(), url.port()) {
+            (Some(host), Some(port)) => Ok((url.scheme().to_string(), host.to_string(), port)),
+            _ => Err(invalid()),
         }
     }
 
-    fn validation_parse_uri(uri: &str) -> (String, String, u16) {
-        if !uri.starts_with("consul://") {
-            panic!("REGISTER_ADDR must start with consul://");
+    async fn register(&self, own: &Own) -> anyhow::Result<()> {
+        self.agent_put("/v1/agent/service/register", Some(&own.registration()?))
+            .await
+    }
+
+    // passes the ttl check with the reported health; an agent that lost the
+    // registration, e.g. after a restart, gets it again.
+    #[tracing::instrument(name = "plugin.renew", skip_all, fields(backend = "consul"))]
+    async fn renew(&self) {
+        for own in self.inner.lock().await.values() {
+            let (status, output) = check_status(own.content.health.as_ref());
+            let path = format!("/v1/agent/check/update/{}", own.check_id());
+            let update = CheckUpdate { status, output };
+            if self.agent_put(&path, Some(&update)).await.is_ok() {
+                continue;
+            }
+            if let Err(e) = self.register(own).await {
+                tracing::error!(service = %own.key, error = %e, "consul register failed");
+                self.events
+                    .registry_error(format!("{} renewal: {}", own.key, e));
+            }
         }
-        if let Ok(issue_list_url) = Url::parse(&uri["consul://".len()..]) {
-            if let Some(host) = issue_list_url.host() {
-                if let Some(port) = issue_list_url.port() {
-                    return (issue_list_url.scheme().to_string(), host.to_string(), port);
+    }
+
+    async fn unregister(&self) {
+        for own in self.inner.lock().await.values() {
+            let path = format!("/v1/agent/service/deregister/{}", own.id());
+            if let Err(e) = self.agent_put::<()>(&path, None).await {
+                tracing::error!(service = %own.key, error = %e, "consul unregister failed");
+            }
+        }
+    }
+
+    // the instances of `key` with `r#type` and the index to block on for
+    // the next change, `index` 0 answers at once.
+    async fn health(
+        &self,
+        key: &str,
+        r#type: i32,
+        index: u64,
+    ) -> anyhow::Result<(u64, Vec<(String, ServiceContent)>)> {
+        let mut path = format!(
+            "/v1/health/service/{}?tag={}",
+            service_name(key),
+            tag(r#type)
+        );
+        if index > 0 {
+            path.push_str(&format!("&index={}&wait={}", index, WAIT));
+        }
+        let res = self.request(Method::GET, &path, None).await?;
+        let next = res
+            .headers()
+            .get("x-consul-index")
+            .and_then(|v| v.to_str().ok()?.parse::<u64>().ok())
+            .unwrap_or(0);
+        let body = hyper::body::to_bytes(res.into_body()).await?;
+        let mut instances = serde_json::from_slice::<Vec<HealthEntry>>(&body)?
+            .iter()
+            .filter_map(|entry| content_of(key, entry))
+            .collect::<Vec<_>>();
+        instances.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok((next, instances))
+    }
+
+    // the instances of `key` with `r#type`, watched from the first lookup
+    // on.
+    async fn registered(
+        &self,
+        key: &str,
+        r#type: i32,
+    ) -> anyhow::Result<Vec<(String, ServiceContent)>> {
+        let watched = (r#type, key.to_string());
+        if let Some(instances) = self.cache.lock().await.get(&watched) {
+            return Ok(instances.clone());
+        }
+
+        let (index, instances) = self.health(key, r#type, 0).await?;
+        if let Entry::Vacant(entry) = self.cache.lock().await.entry(watched) {
+            entry.insert(instances.clone());
+            tokio::spawn(self.clone().watch(key.to_string(), r#type, index));
+        }
+        Ok(instances)
+    }
+
+    // follows the instances of `key` with blocking queries for as long as
+    // the process lives.
+    async fn watch(self, key: String, r#type: i32, mut index: u64) {
+        let watched = format!("{}{}", tag(r#type), key);
+        loop {
+            let (next, instances) = match self.health(&key, r#type, index.max(1)).await {
+                Ok(answer) => answer,
+                Err(e) => {
+                    tracing::error!(watched = %watched, error = %e, "consul watch failed");
+                    self.events
+                        .registry_error(format!("watch {}: {}", watched, e));
+                    tokio::time::sleep(RETRY).await;
+                    continue;
                 }
+            };
+            // an index that went back starts over, as consul advises.
+            index = match next < index {
+                true => 0,
+                false => next,
+            };
+
+            // compared as json, the contents have no equality of their own.
+            let current = serde_json::to_value(&instances).ok();
+            let mut cache = self.cache.lock().await;
+            let _span = watch_span("consul", &watched, instances.len()).entered();
+            let previous = cache
+                .insert((r#type, key.clone()), instances)
+                .and_then(|previous| serde_json::to_value(previous).ok());
+            if r#type == 2 && previous != current {
+                self.events.backend_change(Some(key.clone()));
             }
         }
+    }
 
-        panic!("REGISTER_ADDR is not valid");
+    // updates the registrations of this process under `key` and registers
+    // them again when `publish`.
+    async fn update(
+        &self,
+        key: &str,
+        publish: bool,
+        update: impl Fn(&mut ServiceContent),
+    ) -> anyhow::Result<()> {
+        let mut inner = self.inner.lock().await;
+        for own in inner.values_mut().filter(|own| own.key == key) {
+            update(&mut own.content);
+            if publish {
+                self.register(own).await?;
+            }
+        }
+        Ok(())
+    }
+
+    // renews the registrations of this process until `shutdown` deregisters.
+    fn keep_registered(&self, shutdown: Shutdown) {
+        let deregistered = shutdown.guard(Phase::Deregister);
+        let plugin = self.clone();
+        tokio::spawn(async move {
+            let renew = async {
+                loop {
+                    tokio::time::sleep(RENEW).await;
+                    plugin.renew().await;
+                }
+            };
+            tokio::select! {
+                _ = renew => {},
+                _ = shutdown.reached(Phase::Deregister) => {
+                    plugin.unregister().await;
+                    drop(deregistered);
+                },
+            }
+        });
     }
 }
 
 #[async_trait]
 impl Plugin for ConsulPlugin {
     async fn register_service(&self, key: &str, sc: ServiceContent) -> anyhow::Result<()> {
-        let entity = RegisterEntityPayload {
-            ID: None,
-            Node: sc.addr.clone(),
-            Address: sc.addr.to_string(),
-            Datacenter: None,
-            TaggedAddresses: Default::default(),
-            NodeMeta: Default::default(),
-            Service: Some(RegisterEntityService {
-                ID: None,
-                Service: sc.service.clone(),
-                Tags: vec![key.to_string(), sc.lba],
-                TaggedAddresses: Default::default(),
-                Meta: Default::default(),
-                Port: Some(0),
-                Namespace: None,
-            }),
-            Check: None,
-            SkipNodeUpdate: None,
+        let own = Own {
+            key: key.to_string(),
+            content: sc,
         };
+        self.register(&own).await?;
+        let r#type = own.content.r#type;
+        self.inner.lock().await.insert(own.id(), own);
+
+        // members of a group learn about each other through the watch.
+        if r#type == 2 {
+            self.registered(key, 2).await?;
+        }
+        Ok(())
+    }
+
+    async fn get_web_service(&self, key: &str) -> anyhow::Result<Vec<ServiceContent>> {
+        Ok(self
+            .registered(key, 1)
+            .await?
+            .into_iter()
+            .map(|(_, content)| content)
+            .collect())
+    }
+
+    async fn get_backend_service(&self, key: &str) -> anyhow::Result<(String, Vec<String>)> {
+        let self_id = self
+            .inner
+            .lock()
+            .await
+            .iter()
+            .find(|(_, own)| own.key == key && own.content.r#type == 2)
+            .map(|(id, _)| id.clone())
+            .unwrap_or_default();
+        let members = self.registered(key, 2).await?;
+        Ok((self_id, members.into_iter().map(|(id, _)| id).collect()))
+    }
+
+    async fn get_gateways(&self, key: &str) -> anyhow::Result<Vec<ServiceContent>> {
+        Ok(self
+            .registered(key, 3)
+            .await?
+            .into_iter()
+            .map(|(_, content)| content)
+            .collect())
+    }
 
-        Ok(self.client.register_entity(&entity).await?)
+    async fn list_backend_service(
+        &self,
+        key: &str,
+    ) -> anyhow::Result<Vec<(String, ServiceContent)>> {
+        self.registered(key, 2).await
     }
 
-    async fn get_web_service(&self, _key: &str) -> anyhow::Result<Vec<ServiceContent>> {
-        todo!("ConsulPlugin::get_web_service")
+    async fn report_health(&self, key: &str, health: ServiceHealth) -> anyhow::Result<()> {
+        self.update(key, true, |content| content.health = Some(health.clone()))
+            .await
     }
 
-    async fn get_backend_service(&self, _key: &str) -> anyhow::Result<(String, Vec<String>)> {
-        todo!("ConsulPlugin::get_backend_service")
+    async fn set_metadata(
+        &self,
+        key: &str,
+        metadata: HashMap<String, String>,
+    ) -> anyhow::Result<()> {
+        self.update(key, true, |content| {
+            content.metadata.extend(metadata.clone())
+        })
+        .await
+    }
+
+    async fn set_weight(&self, key: &str, weight: u32) -> anyhow::Result<()> {
+        self.update(key, true, |content| content.weight = weight)
+            .await
+    }
+
+    // from the Connect ca of the local agent, which also signs and renews
+    // the leaf.
+    async fn connect_certs(&self, service: &str) -> anyhow::Result<ConnectCerts> {
+        let roots: CaRoots = self.agent_get("/v1/agent/connect/ca/roots").await?;
+        let leaf: LeafCert = self
+            .agent_get(&format!("/v1/agent/connect/ca/leaf/{}", service))
+            .await?;
+
+        Ok(ConnectCerts {
+            cert: leaf.cert_pem,
+            key: leaf.private_key_pem,
+            roots: roots
+                .roots
+                .into_iter()
+                .flat_map(|r| std::iter::once(r.root_cert).chain(r.intermediate_certs))
+                .collect(),
+            valid_before: leaf.valid_before,
+        })
     }
 }
 
 #[async_trait]
 impl Synchronize for ConsulPlugin {
-    async fn gateway_service_handle(&mut self) {
-        todo!()
+    async fn gateway_service_handle(&mut self, shutdown: Shutdown) {
+        self.keep_registered(shutdown);
     }
-    async fn backend_service_handle(&mut self, _ctx: Context, _wg: WaitGroup) {
-        todo!()
+
+    async fn backend_service_handle(&mut self, shutdown: Shutdown) {
+        self.keep_registered(shutdown);
     }
-    async fn web_service_handle(&mut self, _ctx: Context, _wg: WaitGroup) {
-        todo!()
+
+    async fn web_service_handle(&mut self, shutdown: Shutdown) {
+        self.keep_registered(shutdown);
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
 
     #[test]
     fn test_parse_uri() {
         let uri = "consul://https://localhost:8500";
-        let (method, host, port) = super::ConsulPlugin::validation_parse_uri(uri);
+        let (method, host, port) = super::ConsulPlugin::validation_parse_uri(uri).unwrap();
         assert_eq!(method, "https");
         assert_eq!(host, "localhost");
         assert_eq!(port, 8500);
+
+        assert!(super::ConsulPlugin::validation_parse_uri("http://localhost:8500").is_err());
+        assert!(super::ConsulPlugin::validation_parse_uri("consul://localhost").is_err());
+    }
+
+    #[test]
+    fn registrations_round_trip() {
+        let own = Own {
+            key: "/t/ums".to_string(),
+            content: ServiceContent {
+                service: "/t/ums".to_string(),
+                addr: "10.0.0.1:8080".to_string(),
+                weight: 0,
+                // longer than one meta value.
+                metadata: HashMap::from([("signature".to_string(), "é".repeat(400))]),
+                ..Default::default()
+            },
+        };
+        let registration = own.registration().unwrap();
+        assert_eq!(registration.name, "t-ums");
+        assert_eq!(registration.address, "10.0.0.1");
+        assert_eq!(registration.port, Some(8080));
+        assert_eq!(registration.weights.passing, 1);
+        assert!(registration
+            .meta
+            .values()
+            .all(|v| v.len() <= META_VALUE_MAX));
+
+        let entry = |status: &str| HealthEntry {
+            service: HealthService {
+                id: registration.id.clone(),
+                meta: registration.meta.clone(),
+            },
+            checks: vec![HealthCheck {
+                status: status.to_string(),
+                output: "ttl expired".to_string(),
+            }],
+        };
+        let (id, content) = content_of("/t/ums", &entry("passing")).unwrap();
+        assert_eq!(id, own.id());
+        assert_eq!(content.weight, 0);
+        assert_eq!(content.metadata, own.content.metadata);
+        assert!(content.health.is_none());
+
+        let (_, content) = content_of("/t/ums", &entry("critical")).unwrap();
+        assert_eq!(content.health.unwrap().status, HealthStatus::Unhealthy);
+        // another key behind the same service name.
+        assert!(content_of("/t-ums", &entry("passing")).is_none());
     }
 }
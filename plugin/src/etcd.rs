@@ -1,60 +1,96 @@
 use std::{collections::HashMap, sync::Arc};
 
-use crate::{async_trait, Plugin, ServiceContent, Synchronize};
-use crossbeam::sync::WaitGroup;
-use etcd_client::{Client, GetOptions, PutOptions, WatchOptions};
+use crate::handle::{watch_span, Events};
+use crate::queue::{new_id, now_millis};
+use crate::{
+    async_trait, Plugin, PluginConfig, PluginError, QueueTask, ServiceContent, ServiceHealth,
+    Synchronize,
+};
+use etcd_client::{
+    Client, Compare, CompareOp, ConnectOptions, GetOptions, KeyValue, PutOptions, Txn, TxnOp,
+    WatchOptions,
+};
 use futures::lock::Mutex;
-use tokio_context::context::Context;
+use net::{Phase, Shutdown};
+use tracing::Instrument;
 
 pub(super) const LEASE: i64 = 3;
 pub(super) const WEB_SERVICE: &str = "/web/service";
 pub(super) const BACKEND_SERVICE: &str = "/backend/service";
+pub(super) const GATEWAY_SERVICE: &str = "/gateway/service";
+pub(super) const QUEUE: &str = "/queue";
+
+// where registrations of each service type live.
+fn prefix(r#type: i32) -> &'static str {
+    match r#type {
+        2 => BACKEND_SERVICE,
+        3 => GATEWAY_SERVICE,
+        _ => WEB_SERVICE,
+    }
+}
+
+// the etcd key of a registration made under `key`.
+fn service_key(key: &str, sc: &ServiceContent) -> String {
+    format!("{}{}", prefix(sc.r#type), key)
+}
+
+// the etcd prefix of the gateways registered for `service`.
+fn gateways_prefix(service: &str) -> String {
+    format!("{}{}/", GATEWAY_SERVICE, service)
+}
+
+// the key and registration of a watched entry.
+fn decode(kv: &KeyValue) -> anyhow::Result<(String, ServiceContent)> {
+    Ok((
+        kv.key_str()?.to_string(),
+        serde_json::from_str(kv.value_str()?)?,
+    ))
+}
 
 #[derive(Clone)]
 pub struct EtcdPlugin {
     inner: Arc<Mutex<HashMap<String, ServiceContent>>>,
     cache: Arc<Mutex<HashMap<String, Vec<ServiceContent>>>>,
     client: Client,
+    events: Events,
 }
 
 impl EtcdPlugin {
-    pub(super) async fn new() -> Self {
-        dotenv::dotenv().ok();
+    pub(super) async fn new(config: &PluginConfig, events: Events) -> Result<Self, PluginError> {
         // etcd://http://node1:2379,http://node2:2379
-        let uri = std::env::var("REGISTER_ADDR").expect("REGISTER_ADDR is not set");
+        let endpoints = Self::validation_parse_uri(config.required_addr()?)?;
 
-        let endpoints = Self::validation_parse_uri(&uri);
-        let client = Client::connect(endpoints, None)
-            .await
-            .expect("etcd connect failed");
+        let mut options = ConnectOptions::new();
+        if let (Some(username), Some(password)) = (&config.username, &config.password) {
+            options = options.with_user(username, password);
+        }
+        if let Some(connect_timeout) = config.connect_timeout {
+            options = options.with_connect_timeout(connect_timeout);
+        }
+        let client = Client::connect(endpoints, Some(options)).await?;
 
-        Self {
+        Ok(Self {
             inner: Arc::new(Mutex::new(HashMap::new())),
             cache: Arc::new(Mutex::new(HashMap::new())),
             client,
-        }
+            events,
+        })
     }
 
-    fn validation_parse_uri(uri: &str) -> Vec<String> {
-        if !uri.starts_with("etcd://") {
-            panic!("REGISTER_ADDR must start with etcd://");
+    fn validation_parse_uri(uri: &str) -> Result<Vec<String>, PluginError> {
+        match uri.strip_prefix("etcd://") {
+            Some(endpoints) => Ok(endpoints.split(",").map(|s| s.to_string()).collect()),
+            None => Err(PluginError::Config(format!(
+                "etcd address `{}` must start with etcd://",
+                uri
+            ))),
         }
-        return uri["etcd://".len()..]
-            .split(",")
-            .map(|s| s.to_string())
-            .collect::<Vec<String>>();
     }
 
     async fn register(&self, key: &str, sc: &ServiceContent) -> anyhow::Result<()> {
-        let mut service: String = "".into();
-
-        if sc.r#type == 1 {
-            service = format!("{}{}", WEB_SERVICE, key);
-        } else if sc.r#type == 2 {
-            service = format!("{}{}", BACKEND_SERVICE, key);
-        }
+        let service = service_key(key, sc);
 
-        log::debug!("start register service: {}", service.clone());
+        tracing::debug!(service = %service, "start register service");
 
         match self.client.clone().lease_grant(LEASE, None).await {
             Ok(resp) => {
@@ -69,7 +105,7 @@ impl EtcdPlugin {
                         )
                         .await
                     {
-                        log::debug!("register service: {} done", service);
+                        tracing::debug!(service = %service, "register service done");
                         return Ok(());
                     }
                 }
@@ -82,22 +118,63 @@ impl EtcdPlugin {
         }
     }
 
-    async fn unregister(&self) -> anyhow::Result<()> {
+    #[tracing::instrument(name = "plugin.renew", skip_all, fields(backend = "etcd"))]
+    async fn renew(&self) {
+        tracing::debug!("auto register");
+
         let inner = self.inner.lock().await;
 
         for (key, sc) in inner.iter() {
-            let mut service: String = "".into();
-            if sc.r#type == 1 {
-                service = format!("{}{}", WEB_SERVICE, key);
-            } else if sc.r#type == 2 {
-                service = format!("{}{}", BACKEND_SERVICE, key);
+            if let Err(e) = self.register(key, sc).await {
+                tracing::error!(service = %key, error = %e, "etcd register failed");
+                self.events
+                    .registry_error(format!("{} renewal: {}", key, e));
             }
+        }
+    }
+
+    async fn unregister(&self) -> anyhow::Result<()> {
+        let inner = self.inner.lock().await;
 
-            let _ = self.client.clone().delete(service.clone(), None).await;
+        for (key, sc) in inner.iter() {
+            let service = service_key(key, sc);
+            let _ = self.client.clone().delete(service, None).await;
         }
 
         Ok(())
     }
+
+    fn queue_key(queue: &str, id: &str) -> String {
+        format!("{}/{}/{}", QUEUE, queue, id)
+    }
+
+    // the stored task, only if it is still held by `task.lease`.
+    async fn leased(&self, task: &QueueTask) -> anyhow::Result<Option<KeyValue>> {
+        let resp = self
+            .client
+            .clone()
+            .get(Self::queue_key(&task.queue, &task.id), None)
+            .await?;
+
+        Ok(resp.kvs().first().cloned().filter(|kv| {
+            serde_json::from_slice::<QueueTask>(kv.value())
+                .map(|current| current.lease == task.lease)
+                .unwrap_or(false)
+        }))
+    }
+
+    // put/delete `kv` unless someone else changed it since it was read.
+    async fn swap(&self, kv: &KeyValue, op: TxnOp) -> anyhow::Result<bool> {
+        let txn = Txn::new()
+            .when(vec![Compare::mod_revision(
+                kv.key(),
+                CompareOp::Equal,
+                kv.mod_revision(),
+            )])
+            .and_then(vec![op]);
+
+        Ok(self.client.clone().txn(txn).await?.succeeded())
+    }
 }
 
 #[async_trait]
@@ -133,8 +210,12 @@ impl Plugin for EtcdPlugin {
             return Ok(resp
                 .kvs()
                 .iter()
-                .map(|kv| {
-                    serde_json::from_str::<ServiceContent>(kv.value_str().unwrap_or("{}")).unwrap()
+                .filter_map(|kv| match decode(kv) {
+                    Ok((_, content)) => Some(content),
+                    Err(e) => {
+                        tracing::error!(error = %e, "etcd skipped an invalid registration");
+                        None
+                    }
                 })
                 .collect::<Vec<ServiceContent>>());
         }
@@ -142,59 +223,201 @@ impl Plugin for EtcdPlugin {
     }
 
     async fn get_backend_service(&self, _key: &str) -> anyhow::Result<(String, Vec<String>)> {
-        todo!("EtcdPlugin::get_backend_service")
+        Err(PluginError::Unsupported("backend services on etcd").into())
+    }
+
+    async fn get_gateways(&self, key: &str) -> anyhow::Result<Vec<ServiceContent>> {
+        let resp = self
+            .client
+            .clone()
+            .get(
+                gateways_prefix(key),
+                Some(GetOptions::default().with_prefix()),
+            )
+            .await?;
+
+        Ok(resp
+            .kvs()
+            .iter()
+            .filter_map(|kv| serde_json::from_slice::<ServiceContent>(kv.value()).ok())
+            .collect())
+    }
+
+    async fn list_backend_service(
+        &self,
+        key: &str,
+    ) -> anyhow::Result<Vec<(String, ServiceContent)>> {
+        let resp = self
+            .client
+            .clone()
+            .get(
+                format!("{}{}/", BACKEND_SERVICE, key),
+                Some(GetOptions::default().with_prefix()),
+            )
+            .await?;
+
+        Ok(resp
+            .kvs()
+            .iter()
+            .filter_map(|kv| {
+                let content = serde_json::from_slice::<ServiceContent>(kv.value()).ok()?;
+                Some((kv.key_str().ok()?.to_string(), content))
+            })
+            .collect())
+    }
+
+    async fn set_weight(&self, key: &str, weight: u32) -> anyhow::Result<()> {
+        let mut inner = self.inner.lock().await;
+        for (_, sc) in inner.iter_mut().filter(|(_, sc)| sc.service.eq(key)) {
+            sc.weight = weight;
+        }
+        Ok(())
+    }
+
+    async fn set_metadata(
+        &self,
+        key: &str,
+        metadata: HashMap<String, String>,
+    ) -> anyhow::Result<()> {
+        let mut inner = self.inner.lock().await;
+        for (k, sc) in inner.iter_mut().filter(|(_, sc)| sc.service.eq(key)) {
+            sc.metadata.extend(metadata.clone());
+            self.register(k, sc).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn report_health(&self, key: &str, health: ServiceHealth) -> anyhow::Result<()> {
+        let mut inner = self.inner.lock().await;
+        for (k, sc) in inner.iter_mut().filter(|(_, sc)| sc.service.eq(key)) {
+            sc.health = Some(health.clone());
+            self.register(k, sc).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn enqueue_at(
+        &self,
+        queue: &str,
+        payload: String,
+        visible_at: u64,
+    ) -> anyhow::Result<String> {
+        let task = QueueTask {
+            id: new_id(),
+            queue: queue.to_string(),
+            payload,
+            attempts: 0,
+            lease: "".into(),
+            visible_at,
+        };
+
+        self.client
+            .clone()
+            .put(
+                Self::queue_key(queue, &task.id),
+                serde_json::to_vec(&task)?,
+                None,
+            )
+            .await?;
+
+        Ok(task.id)
+    }
+
+    async fn claim(
+        &self,
+        queue: &str,
+        visibility: std::time::Duration,
+    ) -> anyhow::Result<Option<QueueTask>> {
+        let now = now_millis();
+        // ids are time ordered, so keys come back oldest first.
+        let resp = self
+            .client
+            .clone()
+            .get(
+                format!("{}/{}/", QUEUE, queue),
+                Some(GetOptions::default().with_prefix()),
+            )
+            .await?;
+
+        for kv in resp.kvs() {
+            let mut task = match serde_json::from_slice::<QueueTask>(kv.value()) {
+                Ok(task) if task.visible_at <= now => task,
+                _ => continue,
+            };
+            task.attempts += 1;
+            task.lease = new_id();
+            task.visible_at = now + visibility.as_millis() as u64;
+
+            // lost the race to another consumer, try the next one.
+            if self
+                .swap(kv, TxnOp::put(kv.key(), serde_json::to_vec(&task)?, None))
+                .await?
+            {
+                return Ok(Some(task));
+            }
+        }
+
+        Ok(None)
+    }
+
+    async fn ack(&self, task: &QueueTask) -> anyhow::Result<bool> {
+        match self.leased(task).await? {
+            Some(kv) => self.swap(&kv, TxnOp::delete(kv.key(), None)).await,
+            None => Ok(false),
+        }
+    }
+
+    async fn nack(&self, task: &QueueTask) -> anyhow::Result<bool> {
+        match self.leased(task).await? {
+            Some(kv) => {
+                let task = QueueTask {
+                    visible_at: now_millis(),
+                    ..task.clone()
+                };
+                self.swap(&kv, TxnOp::put(kv.key(), serde_json::to_vec(&task)?, None))
+                    .await
+            }
+            None => Ok(false),
+        }
     }
 }
 
 #[async_trait]
 impl Synchronize for EtcdPlugin {
-    async fn gateway_service_handle(&mut self) {
-        let _self = self.clone();
+    // web services are read from etcd on demand, the gateway only keeps
+    // its own registration alive.
+    async fn gateway_service_handle(&mut self, shutdown: Shutdown) {
+        let deregistered = shutdown.guard(Phase::Deregister);
+        let self_cp0 = self.clone();
+        let self_cp1 = self.clone();
 
         let block = async move {
-            match _self
-                .client
-                .clone()
-                .watch(
-                    format!("{}", WEB_SERVICE,),
-                    Some(WatchOptions::default().with_prefix()),
-                )
-                .await
-            {
-                Ok((_, mut stream)) => {
-                    while let Ok(Some(resp)) = stream.message().await {
-                        for event in resp.events().iter() {
-                            match event.event_type() {
-                                etcd_client::EventType::Put => {
-                                    let kv = event.kv().unwrap();
-                                    let key = kv.key_str().unwrap();
-                                    let value = kv.value_str().unwrap();
-                                    let mut inner = _self.inner.lock().await;
-                                    inner.insert(
-                                        key.to_string(),
-                                        serde_json::from_str(value).unwrap(),
-                                    );
-                                }
-                                etcd_client::EventType::Delete => {
-                                    let kv = event.kv().unwrap();
-                                    let key = kv.key_str().unwrap();
-                                    let mut inner = _self.inner.lock().await;
-                                    inner.remove(key);
-                                }
-                            }
-                        }
-                    }
-                }
-                Err(e) => {
-                    panic!("etcd watch failed: {}", e.to_string());
+            // auto register every lease-1s
+            let block0 = async move {
+                loop {
+                    tokio::time::sleep(tokio::time::Duration::from_secs((LEASE - 1) as u64)).await;
+
+                    self_cp0.renew().await;
                 }
+            };
+
+            tokio::select! {
+                _ = block0 => {},
+                _ = shutdown.reached(Phase::Deregister) => {
+                    if self_cp1.unregister().await.is_err() {
+                        tracing::error!("etcd unregister failed");
+                    }
+                    drop(deregistered);
+                },
             }
         };
 
         tokio::spawn(block);
     }
-    async fn backend_service_handle(&mut self, ctx: Context, wg: WaitGroup) {
-        let mut ctx = ctx;
+    async fn backend_service_handle(&mut self, shutdown: Shutdown) {
+        let deregistered = shutdown.guard(Phase::Deregister);
         let self_cp0 = self.clone();
         let self_cp1 = self.clone();
         let self_cp2 = self.clone();
@@ -203,20 +426,9 @@ impl Synchronize for EtcdPlugin {
             // auto register every lease-1s
             let block0 = async move {
                 loop {
-                    tokio::time::sleep(tokio::time::Duration::from_secs(
-                        (LEASE - 1 as i64).try_into().unwrap(),
-                    ))
-                    .await;
+                    tokio::time::sleep(tokio::time::Duration::from_secs((LEASE - 1) as u64)).await;
 
-                    log::debug!("auto register");
-
-                    let inner = self_cp0.inner.lock().await;
-
-                    for (key, sc) in inner.iter() {
-                        if let Err(e) = self_cp0.register(key, sc).await {
-                            panic!("etcd register failed: {}", e.to_string());
-                        }
-                    }
+                    self_cp0.renew().await;
                 }
             };
 
@@ -232,25 +444,35 @@ impl Synchronize for EtcdPlugin {
                 {
                     Ok((_, mut stream)) => {
                         while let Ok(Some(resp)) = stream.message().await {
-                            for event in resp.events().iter() {
-                                match event.event_type() {
-                                    etcd_client::EventType::Put => {
-                                        let kv = event.kv().unwrap();
-                                        let key = kv.key_str().unwrap();
-                                        let value = kv.value_str().unwrap();
-                                        let mut inner = self_cp2.inner.lock().await;
-                                        inner.insert(
-                                            key.to_string(),
-                                            serde_json::from_str(value).unwrap(),
-                                        );
+                            let span = watch_span("etcd", BACKEND_SERVICE, resp.events().len());
+                            async {
+                                for event in resp.events().iter() {
+                                    if let etcd_client::EventType::Put = event.event_type() {
+                                        match event.kv().map(decode) {
+                                            Some(Ok((key, content))) => {
+                                                self_cp2.inner.lock().await.insert(key, content);
+                                            }
+                                            Some(Err(e)) => tracing::error!(
+                                                error = %e,
+                                                "etcd watch skipped an invalid entry"
+                                            ),
+                                            None => {}
+                                        }
                                     }
-                                    etcd_client::EventType::Delete => todo!(),
+                                    self_cp2.events.backend_change(None);
                                 }
                             }
+                            .instrument(span)
+                            .await;
                         }
                     }
                     Err(e) => {
-                        panic!("etcd watch failed: {}", e.to_string());
+                        tracing::error!(watched = BACKEND_SERVICE, error = %e, "etcd watch failed");
+                        self_cp2
+                            .events
+                            .registry_error(format!("watch {}: {}", BACKEND_SERVICE, e));
+                        // renewals go on without the watch.
+                        futures::future::pending::<()>().await;
                     }
                 }
             };
@@ -258,11 +480,11 @@ impl Synchronize for EtcdPlugin {
             tokio::select! {
                 _ = block0 => {},
                 _ = block1 => {},
-                _ = ctx.done() => {
+                _ = shutdown.reached(Phase::Deregister) => {
                     if let Err(_) = self_cp1.unregister().await{} {
-                        log::error!("etcd unregister failed");
+                        tracing::error!("etcd unregister failed");
                     }
-                    drop(wg.clone());
+                    drop(deregistered);
                 },
             }
         };
@@ -270,8 +492,8 @@ impl Synchronize for EtcdPlugin {
         tokio::spawn(block);
     }
 
-    async fn web_service_handle(&mut self, ctx: Context, wg: WaitGroup) {
-        let mut ctx = ctx;
+    async fn web_service_handle(&mut self, shutdown: Shutdown) {
+        let deregistered = shutdown.guard(Phase::Deregister);
         let self_cp0 = self.clone();
         let self_cp1 = self.clone();
 
@@ -279,30 +501,19 @@ impl Synchronize for EtcdPlugin {
             // auto register every lease-1s
             let block0 = async move {
                 loop {
-                    tokio::time::sleep(tokio::time::Duration::from_secs(
-                        (LEASE - 1 as i64).try_into().unwrap(),
-                    ))
-                    .await;
+                    tokio::time::sleep(tokio::time::Duration::from_secs((LEASE - 1) as u64)).await;
 
-                    log::debug!("auto register");
-
-                    let inner = self_cp0.inner.lock().await;
-
-                    for (key, sc) in inner.iter() {
-                        if let Err(e) = self_cp0.register(key, sc).await {
-                            panic!("etcd register failed: {}", e.to_string());
-                        }
-                    }
+                    self_cp0.renew().await;
                 }
             };
 
             tokio::select! {
                 _ = block0 => {},
-                _ = ctx.done() => {
+                _ = shutdown.reached(Phase::Deregister) => {
                     if let Err(_) = self_cp1.unregister().await{} {
-                        log::error!("etcd unregister failed");
+                        tracing::error!("etcd unregister failed");
                     }
-                    drop(wg.clone());
+                    drop(deregistered);
                 },
             }
         };
@@ -310,3 +521,33 @@ impl Synchronize for EtcdPlugin {
         tokio::spawn(block);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gateways_are_listed_apart_from_web_services() {
+        let gateway = ServiceContent {
+            service: "/t/gateway".to_string(),
+            addr: "10.0.0.1:8080".to_string(),
+            r#type: 3,
+            ..Default::default()
+        };
+        // the key `register_service` files the gateway under.
+        let key = format!("{}/{}", gateway.service, gateway.addr);
+        assert_eq!(
+            service_key(&key, &gateway),
+            "/gateway/service/t/gateway/10.0.0.1:8080"
+        );
+        assert!(service_key(&key, &gateway).starts_with(&gateways_prefix("/t/gateway")));
+        assert!(!service_key(&key, &gateway).starts_with(&gateways_prefix("/t/gate")));
+        assert!(!service_key(&key, &gateway).starts_with(WEB_SERVICE));
+
+        let web = ServiceContent {
+            r#type: 1,
+            ..gateway
+        };
+        assert!(!service_key(&key, &web).starts_with(GATEWAY_SERVICE));
+    }
+}
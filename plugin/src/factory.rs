@@ -0,0 +1,180 @@
+// backends that live outside this crate, registered by name before the
+// first handle is made and picked by `get_plugin_type` like the built in
+// ones.
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, RwLock};
+
+use futures::future::BoxFuture;
+use once_cell::sync::Lazy;
+
+use crate::{get_plugin_type, Events, Plugin, PluginConfig, PluginError, PluginType};
+
+type Factory = Arc<
+    dyn Fn(
+            PluginConfig,
+            Events,
+        ) -> BoxFuture<'static, Result<Box<dyn Plugin + Send + Sync>, PluginError>>
+        + Send
+        + Sync,
+>;
+
+// by lowercase name, which is leaked to keep `PluginType` copy; there are
+// only a few and they live as long as the process.
+static FACTORIES: Lazy<RwLock<HashMap<&'static str, Factory>>> = Lazy::new(Default::default);
+
+/// Makes `name` a registry type: `get_plugin_type(name)` returns
+/// `PluginType::External(name)` and handles of that type are built by
+/// `factory` from their config. The events passed along are where the
+/// plugin publishes what its watches see. Fails when `name` is a built in
+/// type or already registered.
+pub fn register_plugin_factory<F, Fut, P>(name: &str, factory: F) -> Result<(), PluginError>
+where
+    F: Fn(PluginConfig, Events) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<P, PluginError>> + Send + 'static,
+    P: Plugin + Send + Sync + 'static,
+{
+    let name = name.to_lowercase();
+    // unknown names fall back to mongodb.
+    let builtin = match get_plugin_type(&name) {
+        PluginType::Mongodb => name == "mongodb",
+        PluginType::External(_) => false,
+        _ => true,
+    };
+    if name.is_empty() || builtin {
+        return Err(PluginError::Config(format!(
+            "`{}` cannot name an external plugin, it is built in",
+            name
+        )));
+    }
+    let mut factories = FACTORIES.write().unwrap();
+    if factories.contains_key(name.as_str()) {
+        return Err(PluginError::Config(format!(
+            "a plugin named `{}` is already registered",
+            name
+        )));
+    }
+    let factory: Factory = Arc::new(move |config, events| {
+        let plugin = factory(config, events);
+        Box::pin(async move {
+            let plugin: Box<dyn Plugin + Send + Sync> = Box::new(plugin.await?);
+            Ok(plugin)
+        })
+    });
+    factories.insert(Box::leak(name.into_boxed_str()), factory);
+    Ok(())
+}
+
+// the registered name equal to `name`, already lowercase.
+pub(crate) fn registered(name: &str) -> Option<&'static str> {
+    FACTORIES
+        .read()
+        .unwrap()
+        .get_key_value(name)
+        .map(|(name, _)| *name)
+}
+
+/// Whether `name` is a registry type registered by `register_plugin_factory`.
+pub fn has_plugin_factory(name: &str) -> bool {
+    registered(&name.to_lowercase()).is_some()
+}
+
+pub(crate) async fn build(
+    name: &str,
+    config: &PluginConfig,
+    events: Events,
+) -> Result<Box<dyn Plugin + Send + Sync>, PluginError> {
+    let factory = FACTORIES.read().unwrap().get(name).cloned();
+    match factory {
+        Some(factory) => factory(config.clone(), events).await,
+        None => Err(PluginError::Config(format!(
+            "no plugin named `{}` is registered",
+            name
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::{async_trait, PluginHandle, ServiceContent, ServiceType, Shutdown, Synchronize};
+
+    // what a downstream crate would write.
+    struct StaticPlugin {
+        addr: String,
+        registered: Mutex<Vec<String>>,
+        events: Events,
+    }
+
+    #[async_trait]
+    impl Synchronize for StaticPlugin {
+        async fn gateway_service_handle(&mut self, _shutdown: Shutdown) {}
+        async fn backend_service_handle(&mut self, _shutdown: Shutdown) {}
+        async fn web_service_handle(&mut self, _shutdown: Shutdown) {}
+    }
+
+    #[async_trait]
+    impl Plugin for StaticPlugin {
+        async fn register_service(&self, key: &str, _sc: ServiceContent) -> anyhow::Result<()> {
+            self.registered.lock().unwrap().push(key.to_string());
+            self.events.backend_change(Some(key.to_string()));
+            Ok(())
+        }
+
+        async fn get_web_service(&self, _key: &str) -> anyhow::Result<Vec<ServiceContent>> {
+            Ok(vec![ServiceContent {
+                addr: self.addr.clone(),
+                ..Default::default()
+            }])
+        }
+
+        async fn get_backend_service(&self, _key: &str) -> anyhow::Result<(String, Vec<String>)> {
+            Ok((String::new(), self.registered.lock().unwrap().clone()))
+        }
+    }
+
+    #[tokio::test]
+    async fn external_plugins_are_built_by_name() {
+        register_plugin_factory("Static", |config: PluginConfig, events| async move {
+            Ok(StaticPlugin {
+                addr: config.addr,
+                registered: Mutex::new(vec![]),
+                events,
+            })
+        })
+        .unwrap();
+        assert!(has_plugin_factory("static"));
+        assert_eq!(get_plugin_type("STATIC"), PluginType::External("static"));
+        assert!(register_plugin_factory("static", |_, _| async {
+            Err::<StaticPlugin, _>(PluginError::NotInitialized)
+        })
+        .is_err());
+        assert!(register_plugin_factory("etcd", |_, _| async {
+            Err::<StaticPlugin, _>(PluginError::NotInitialized)
+        })
+        .is_err());
+
+        let handle = PluginHandle::new(
+            Shutdown::new(),
+            ServiceType::WebService,
+            PluginConfig::new(get_plugin_type("static"), "10.0.0.1:80"),
+        )
+        .await
+        .unwrap();
+        let web = handle.get_web_service("/web").await.unwrap();
+        assert_eq!(web[0].addr, "10.0.0.1:80");
+
+        let mut changes = handle.watch_backend_changes();
+        handle
+            .register_service("/jobs", ServiceContent::default())
+            .await
+            .unwrap();
+        assert_eq!(changes.recv().await.unwrap().as_deref(), Some("/jobs"));
+
+        let unknown = PluginConfig::new(PluginType::External("missing"), "");
+        let err = PluginHandle::new(Shutdown::new(), ServiceType::WebService, unknown).await;
+        assert!(err.is_err());
+    }
+}
@@ -0,0 +1,345 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::lock::Mutex;
+use net::Shutdown;
+use notify::{EventKind, RecursiveMode, Watcher};
+use serde::Deserialize;
+use tokio::sync::mpsc;
+
+use crate::async_trait;
+use crate::handle::{watch_span, Events};
+use crate::queue::MemoryQueue;
+use crate::{NamedEndpoint, PluginConfig, PluginError, QueueTask, ServiceContent};
+
+// writes that follow a change within this long are read with it, editors
+// often write a file in several steps.
+const SETTLE: Duration = Duration::from_millis(200);
+
+// an instance in the file, its address alone or with what it registers.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Entry {
+    Addr(String),
+    Instance(Instance),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Instance {
+    addr: String,
+    #[serde(default)]
+    lba: String,
+    #[serde(default = "crate::default_weight")]
+    weight: u32,
+    #[serde(default)]
+    version: String,
+    #[serde(default)]
+    metadata: HashMap<String, String>,
+    #[serde(default)]
+    endpoints: Vec<NamedEndpoint>,
+    #[serde(default)]
+    tls: bool,
+    #[serde(default)]
+    sni: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ServicesFile {
+    services: HashMap<String, Vec<Entry>>,
+}
+
+/// The instances of each web service in a services file of the file
+/// plugin, yaml or json:
+///
+/// ```yaml
+/// services:
+///   /t/ums: ["10.0.0.1:8080", "10.0.0.2:8080"]
+///   /t/order:
+///     - { addr: "10.0.1.1:8080", weight: 2, version: v2 }
+/// ```
+pub fn read_services_file(path: &str) -> Result<HashMap<String, Vec<ServiceContent>>, PluginError> {
+    let source = std::fs::read_to_string(path)
+        .map_err(|e| PluginError::Config(format!("read {}: {}", path, e)))?;
+    let file = serde_yaml::from_str::<ServicesFile>(&source)
+        .map_err(|e| PluginError::Config(format!("{}: {}", path, e)))?;
+
+    let mut services = HashMap::new();
+    for (service, entries) in file.services {
+        let contents = entries
+            .into_iter()
+            .map(|entry| {
+                let instance = match entry {
+                    Entry::Addr(addr) => Instance {
+                        addr,
+                        lba: String::new(),
+                        weight: crate::default_weight(),
+                        version: String::new(),
+                        metadata: HashMap::new(),
+                        endpoints: vec![],
+                        tls: false,
+                        sni: String::new(),
+                    },
+                    Entry::Instance(instance) => instance,
+                };
+                ServiceContent {
+                    service: service.clone(),
+                    lba: instance.lba,
+                    addr: instance.addr,
+                    r#type: 1,
+                    weight: instance.weight,
+                    version: instance.version,
+                    metadata: instance.metadata,
+                    endpoints: instance.endpoints,
+                    tls: instance.tls,
+                    sni: instance.sni,
+                    ..Default::default()
+                }
+            })
+            .collect();
+        services.insert(service, contents);
+    }
+    Ok(services)
+}
+
+/// A registry for deployments without one: the web services are those of
+/// a services file, see `read_services_file`, read again whenever it
+/// changes. Registrations are not kept anywhere, task queues live in memory
+/// like with the none plugin.
+#[derive(Clone)]
+pub struct FilePlugin {
+    path: String,
+    cache: Arc<Mutex<HashMap<String, Vec<ServiceContent>>>>,
+    queue: Arc<MemoryQueue>,
+    events: Events,
+}
+
+impl FilePlugin {
+    pub(super) async fn new(config: &PluginConfig, events: Events) -> Result<Self, PluginError> {
+        // file:///etc/crossgate/services.yaml
+        let addr = config.required_addr()?;
+        let path = match addr.strip_prefix("file://") {
+            Some(path) if !path.is_empty() => path.to_string(),
+            _ => {
+                return Err(PluginError::Config(format!(
+                    "file address `{}` must be file:// and a path",
+                    addr
+                )))
+            }
+        };
+        let plugin = Self {
+            cache: Arc::new(Mutex::new(read_services_file(&path)?)),
+            path,
+            queue: Arc::new(MemoryQueue::default()),
+            events,
+        };
+        plugin.watch()?;
+        Ok(plugin)
+    }
+
+    // the previous services stay when the file cannot be read.
+    async fn reload(&self) {
+        match read_services_file(&self.path) {
+            Ok(services) => {
+                let mut cache = self.cache.lock().await;
+                let _span = watch_span("file", &self.path, services.len()).entered();
+                tracing::info!(path = %self.path, services = services.len(), "services file reloaded");
+                *cache = services;
+            }
+            Err(e) => {
+                tracing::error!(path = %self.path, error = %e, "services file not reloaded");
+                self.events.registry_error(e.to_string());
+            }
+        }
+    }
+
+    // reloads on every change of the file. Its directory is watched, as
+    // editors and config maps replace the file rather than write to it.
+    fn watch(&self) -> Result<(), PluginError> {
+        let path = PathBuf::from(&self.path);
+        let name = path.file_name().map(|name| name.to_os_string());
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => Path::new(".").to_path_buf(),
+        };
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let watcher = notify::recommended_watcher(move |event| {
+            let _ = tx.send(event);
+        })
+        .and_then(|mut watcher| {
+            watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+            Ok(watcher)
+        })
+        .map_err(|e| PluginError::Config(format!("watch {}: {}", self.path, e)))?;
+
+        let plugin = self.clone();
+        tokio::spawn(async move {
+            // watches for as long as it lives.
+            let _watcher = watcher;
+            while let Some(event) = rx.recv().await {
+                let changed = match event {
+                    Ok(event) => {
+                        !matches!(event.kind, EventKind::Access(_))
+                            && event.paths.iter().any(|p| p.file_name() == name.as_deref())
+                    }
+                    Err(e) => {
+                        tracing::error!(path = %plugin.path, error = %e, "services file watch failed");
+                        plugin
+                            .events
+                            .registry_error(format!("watch {}: {}", plugin.path, e));
+                        false
+                    }
+                };
+                if !changed {
+                    continue;
+                }
+                tokio::time::sleep(SETTLE).await;
+                while rx.try_recv().is_ok() {}
+                plugin.reload().await;
+            }
+        });
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl super::Plugin for FilePlugin {
+    async fn register_service(
+        &self,
+        _key: &str,
+        _service_content: ServiceContent,
+    ) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn get_web_service(&self, key: &str) -> anyhow::Result<Vec<ServiceContent>> {
+        Ok(self
+            .cache
+            .lock()
+            .await
+            .get(key)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    async fn get_backend_service(&self, _key: &str) -> anyhow::Result<(String, Vec<String>)> {
+        Ok((String::new(), vec![]))
+    }
+
+    async fn get_gateways(&self, _key: &str) -> anyhow::Result<Vec<ServiceContent>> {
+        Ok(vec![])
+    }
+
+    async fn list_backend_service(
+        &self,
+        _key: &str,
+    ) -> anyhow::Result<Vec<(String, ServiceContent)>> {
+        Ok(vec![])
+    }
+
+    async fn report_health(&self, _key: &str, _health: super::ServiceHealth) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn set_weight(&self, _key: &str, _weight: u32) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn set_metadata(
+        &self,
+        _key: &str,
+        _metadata: HashMap<String, String>,
+    ) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn enqueue_at(
+        &self,
+        queue: &str,
+        payload: String,
+        visible_at: u64,
+    ) -> anyhow::Result<String> {
+        Ok(self.queue.enqueue_at(queue, payload, visible_at).await)
+    }
+
+    async fn claim(&self, queue: &str, visibility: Duration) -> anyhow::Result<Option<QueueTask>> {
+        Ok(self.queue.claim(queue, visibility).await)
+    }
+
+    async fn ack(&self, task: &QueueTask) -> anyhow::Result<bool> {
+        Ok(self.queue.ack(task).await)
+    }
+
+    async fn nack(&self, task: &QueueTask) -> anyhow::Result<bool> {
+        Ok(self.queue.nack(task).await)
+    }
+}
+
+#[async_trait]
+impl super::Synchronize for FilePlugin {
+    // nothing to renew or withdraw.
+    async fn gateway_service_handle(&mut self, _shutdown: Shutdown) {}
+    async fn backend_service_handle(&mut self, _shutdown: Shutdown) {}
+    async fn web_service_handle(&mut self, _shutdown: Shutdown) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn services_follow_the_file() {
+        let dir = std::env::temp_dir().join(format!("crossgate-file-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("services.yaml");
+        std::fs::write(
+            &path,
+            "services:\n  /t/ums: [\"10.0.0.1:8080\"]\n  /t/order:\n    - { addr: \"10.0.1.1:8080\", weight: 2 }\n",
+        )
+        .unwrap();
+
+        let config = PluginConfig::new(
+            crate::PluginType::File,
+            &format!("file://{}", path.display()),
+        );
+        let plugin = FilePlugin::new(&config, Events::new()).await.unwrap();
+        let lookup = |service: &'static str| {
+            let plugin = plugin.clone();
+            async move {
+                use crate::Plugin;
+                plugin.get_web_service(service).await.unwrap()
+            }
+        };
+        assert_eq!(lookup("/t/ums").await[0].addr, "10.0.0.1:8080");
+        assert_eq!(lookup("/t/order").await[0].weight, 2);
+
+        // a broken file keeps what was read before.
+        std::fs::write(&path, "services: [").unwrap();
+        tokio::time::sleep(SETTLE * 3).await;
+        assert_eq!(lookup("/t/ums").await.len(), 1);
+
+        // json is yaml too, the file may be replaced.
+        let next = dir.join("next.json");
+        std::fs::write(
+            &next,
+            r#"{"services": {"/t/ums": ["10.0.0.1:8080", "10.0.0.2:8080"]}}"#,
+        )
+        .unwrap();
+        std::fs::rename(&next, &path).unwrap();
+        let mut found = 0;
+        for _ in 0..50 {
+            found = lookup("/t/ums").await.len();
+            if found == 2 {
+                break;
+            }
+            tokio::time::sleep(SETTLE).await;
+        }
+        assert_eq!(found, 2);
+        assert!(lookup("/t/order").await.is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
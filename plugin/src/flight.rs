@@ -0,0 +1,135 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::broadcast;
+
+// the result of a lookup as the waiting callers get it, errors as text.
+type Outcome<T> = Result<T, String>;
+
+type InFlight<T> = Arc<Mutex<HashMap<String, broadcast::Sender<Outcome<T>>>>>;
+
+/// Lookups of one handle in flight, by key: callers asking for a key while
+/// a lookup of it is on its way wait for that one and share its result
+/// instead of asking the backend themselves.
+pub(crate) struct Flights<T> {
+    in_flight: InFlight<T>,
+}
+
+impl<T> Clone for Flights<T> {
+    fn clone(&self) -> Self {
+        Self {
+            in_flight: self.in_flight.clone(),
+        }
+    }
+}
+
+impl<T> Default for Flights<T> {
+    fn default() -> Self {
+        Self {
+            in_flight: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+// removes the flight when the first lookup is done or dropped; the waiting
+// callers then look up on their own.
+struct Flight<T> {
+    in_flight: InFlight<T>,
+    key: Option<String>,
+}
+
+impl<T> Flight<T> {
+    fn land(mut self, outcome: Outcome<T>) {
+        let tx = match self.key.take() {
+            Some(key) => self.in_flight.lock().unwrap().remove(&key),
+            None => None,
+        };
+        if let Some(tx) = tx {
+            // no one may be waiting.
+            let _ = tx.send(outcome);
+        }
+    }
+}
+
+impl<T> Drop for Flight<T> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.in_flight.lock().unwrap().remove(&key);
+        }
+    }
+}
+
+impl<T: Clone> Flights<T> {
+    /// Looks `key` up with `lookup`, unless a lookup of it is already in
+    /// flight; true with the result when it was shared.
+    pub(crate) async fn run<F, Fut>(&self, key: &str, lookup: F) -> (anyhow::Result<T>, bool)
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = anyhow::Result<T>>,
+    {
+        let waiting = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            match in_flight.get(key) {
+                Some(tx) => Some(tx.subscribe()),
+                None => {
+                    in_flight.insert(key.to_string(), broadcast::channel(1).0);
+                    None
+                }
+            }
+        };
+
+        if let Some(mut rx) = waiting {
+            return match rx.recv().await {
+                Ok(outcome) => (outcome.map_err(anyhow::Error::msg), true),
+                Err(_) => (lookup().await, false),
+            };
+        }
+
+        let flight = Flight {
+            in_flight: self.in_flight.clone(),
+            key: Some(key.to_string()),
+        };
+        let res = lookup().await;
+        flight.land(match &res {
+            Ok(v) => Ok(v.clone()),
+            Err(e) => Err(format!("{:#}", e)),
+        });
+        (res, false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn concurrent_lookups_share_one_query() {
+        let flights = Flights::<Vec<String>>::default();
+        let queries = Arc::new(AtomicUsize::new(0));
+        let lookup = |queries: Arc<AtomicUsize>| async move {
+            queries.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            Ok(vec!["10.0.0.1:80".to_string()])
+        };
+
+        let results = futures::future::join_all(
+            (0..100).map(|_| flights.run("/t/ums", || lookup(queries.clone()))),
+        )
+        .await;
+        assert_eq!(queries.load(Ordering::SeqCst), 1);
+        assert_eq!(results.iter().filter(|(_, shared)| *shared).count(), 99);
+        assert!(results
+            .iter()
+            .all(|(res, _)| res.as_ref().unwrap() == &["10.0.0.1:80"]));
+
+        // done, the next one queries again.
+        let (res, shared) = flights
+            .run("/t/ums", || async { anyhow::bail!("registry down") })
+            .await;
+        assert!(!shared && res.is_err());
+        assert!(flights.in_flight.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,1011 @@
+// Discovery without a datastore: the processes form a cluster of their own
+// and gossip what each one registered. Failures are detected the SWIM way,
+// each interval one member is pinged directly, then through a few others,
+// and suspected when neither answers; a suspect that is alive refutes by
+// raising its incarnation, one that does not is dead after a while.
+// Membership changes ride along the pings, the registrations themselves are
+// exchanged by push-pull syncs over tcp on the same port, with a random
+// member every interval and with a few at once on a change.
+use std::collections::{BTreeSet, HashMap};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use net::{Phase, Shutdown};
+use rand::seq::SliceRandom;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::{oneshot, watch};
+
+use crate::handle::{watch_span, Events};
+use crate::queue::new_id;
+use crate::{
+    async_trait, Plugin, PluginConfig, PluginError, ServiceContent, ServiceHealth, Synchronize,
+};
+
+const PROBE_INTERVAL: Duration = Duration::from_secs(1);
+// how long a direct ping has before the indirect ones are sent.
+const PROBE_TIMEOUT: Duration = Duration::from_millis(400);
+const INDIRECT_PROBES: usize = 3;
+const SUSPECT_TIMEOUT: Duration = Duration::from_secs(5);
+// dead members are remembered this long, so that old news of them being
+// alive does not bring them back.
+const DEAD_RETAIN: Duration = Duration::from_secs(30);
+const SYNC_INTERVAL: Duration = Duration::from_secs(2);
+// the members a change is pushed to at once.
+const FANOUT: usize = 3;
+const MAX_PIGGYBACK: usize = 8;
+const MAX_DATAGRAM: usize = 65_507;
+const MAX_FRAME: u32 = 16 << 20;
+const IO_TIMEOUT: Duration = Duration::from_secs(2);
+
+// in the order of precedence at the same incarnation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Status {
+    Alive,
+    Suspect,
+    Dead,
+}
+
+// what is gossiped of a member, without its registrations. `version` counts
+// the changes of those, a newer one is synced from the member.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Header {
+    id: String,
+    addr: SocketAddr,
+    incarnation: u64,
+    status: Status,
+    version: u64,
+}
+
+// a member and what it registered, by key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Node {
+    header: Header,
+    registrations: Vec<(String, ServiceContent)>,
+}
+
+impl Node {
+    fn routable(&self) -> bool {
+        self.header.status != Status::Dead
+    }
+
+    // the keys of its backend service registrations.
+    fn backend_keys(&self) -> impl Iterator<Item = &String> {
+        self.registrations
+            .iter()
+            .filter(|(_, content)| content.r#type == 2)
+            .map(|(key, _)| key)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum Datagram {
+    Ping {
+        seq: u64,
+        from: Header,
+        updates: Vec<Header>,
+    },
+    Ack {
+        seq: u64,
+        from: Header,
+        updates: Vec<Header>,
+    },
+    // asks to ping `target` and to ack `seq` for it.
+    PingReq {
+        seq: u64,
+        from: Header,
+        target: SocketAddr,
+        updates: Vec<Header>,
+    },
+}
+
+// what a push-pull sync sends both ways.
+#[derive(Debug, Serialize, Deserialize)]
+struct Sync {
+    nodes: Vec<Node>,
+}
+
+struct Member {
+    node: Node,
+    // when its status last changed.
+    since: Instant,
+}
+
+#[derive(Default)]
+struct Applied {
+    // backend services whose members changed.
+    changed: BTreeSet<String>,
+    // the registrations are older than the header says.
+    stale: bool,
+}
+
+struct State {
+    me: Node,
+    members: HashMap<String, Member>,
+    // headers still to piggyback, and how many more times.
+    broadcasts: Vec<(Header, usize)>,
+    probes: Vec<String>,
+}
+
+impl State {
+    fn new(me: Header) -> Self {
+        Self {
+            me: Node {
+                header: me,
+                registrations: vec![],
+            },
+            members: HashMap::new(),
+            broadcasts: vec![],
+            probes: vec![],
+        }
+    }
+
+    fn broadcast(&mut self, header: Header) {
+        // enough rounds to reach everyone with high probability.
+        let times = 3 * (usize::BITS - (self.members.len() + 1).leading_zeros()) as usize;
+        self.broadcasts.retain(|(h, _)| h.id != header.id);
+        self.broadcasts.push((header, times));
+    }
+
+    fn piggyback(&mut self) -> Vec<Header> {
+        self.broadcasts
+            .sort_by_key(|(_, times)| std::cmp::Reverse(*times));
+        let updates = self
+            .broadcasts
+            .iter_mut()
+            .take(MAX_PIGGYBACK)
+            .map(|(header, times)| {
+                *times -= 1;
+                header.clone()
+            })
+            .collect();
+        self.broadcasts.retain(|(_, times)| *times > 0);
+        updates
+    }
+
+    // the own registrations changed.
+    fn changed(&mut self) {
+        self.me.header.version += 1;
+        let header = self.me.header.clone();
+        self.broadcast(header);
+    }
+
+    // merges what another member says of `header.id`, with its
+    // registrations if they came along.
+    fn apply(
+        &mut self,
+        header: Header,
+        registrations: Option<Vec<(String, ServiceContent)>>,
+    ) -> Applied {
+        let mut applied = Applied::default();
+        if header.id == self.me.header.id {
+            // suspected or declared dead while alive: refute.
+            let me = &mut self.me.header;
+            if me.status == Status::Alive
+                && header.status != Status::Alive
+                && header.incarnation >= me.incarnation
+            {
+                me.incarnation = header.incarnation + 1;
+                let header = me.clone();
+                self.broadcast(header);
+            }
+            return applied;
+        }
+
+        let member = match self.members.get_mut(&header.id) {
+            Some(member) => member,
+            None => {
+                if header.status == Status::Dead {
+                    return applied;
+                }
+                applied.stale = registrations.is_none() && header.version > 0;
+                let node = Node {
+                    header: header.clone(),
+                    registrations: registrations.unwrap_or_default(),
+                };
+                applied.changed.extend(node.backend_keys().cloned());
+                self.members.insert(
+                    header.id.clone(),
+                    Member {
+                        node,
+                        since: Instant::now(),
+                    },
+                );
+                self.broadcast(header);
+                return applied;
+            }
+        };
+
+        let known = &member.node.header;
+        let newer = (header.incarnation, header.status) > (known.incarnation, known.status);
+        if newer {
+            if (header.status == Status::Dead) != (known.status == Status::Dead) {
+                applied.changed.extend(member.node.backend_keys().cloned());
+            }
+            let known = &mut member.node.header;
+            known.incarnation = header.incarnation;
+            known.status = header.status;
+            known.addr = header.addr;
+            member.since = Instant::now();
+        }
+        if header.version > member.node.header.version {
+            match registrations {
+                Some(registrations) => {
+                    applied.changed.extend(member.node.backend_keys().cloned());
+                    member.node.registrations = registrations;
+                    member.node.header.version = header.version;
+                    applied.changed.extend(member.node.backend_keys().cloned());
+                }
+                None => applied.stale = true,
+            }
+        }
+        if newer {
+            self.broadcast(header);
+        }
+        applied
+    }
+
+    // suspects past their timeout die, the dead are forgotten after a while.
+    fn expire(&mut self) -> BTreeSet<String> {
+        let mut changed = BTreeSet::new();
+        let mut died = vec![];
+        for member in self.members.values_mut() {
+            if member.node.header.status == Status::Suspect
+                && member.since.elapsed() > SUSPECT_TIMEOUT
+            {
+                member.node.header.status = Status::Dead;
+                member.since = Instant::now();
+                changed.extend(member.node.backend_keys().cloned());
+                died.push(member.node.header.clone());
+            }
+        }
+        for header in died {
+            tracing::warn!(member = %header.id, addr = %header.addr, "gossip member dead");
+            self.broadcast(header);
+        }
+        self.members.retain(|_, member| {
+            member.node.header.status != Status::Dead || member.since.elapsed() < DEAD_RETAIN
+        });
+        changed
+    }
+
+    fn suspect(&mut self, id: &str) {
+        if let Some(member) = self.members.get_mut(id) {
+            if member.node.header.status == Status::Alive {
+                member.node.header.status = Status::Suspect;
+                member.since = Instant::now();
+                let header = member.node.header.clone();
+                tracing::warn!(member = %header.id, addr = %header.addr, "gossip member suspected");
+                self.broadcast(header);
+            }
+        }
+    }
+
+    // the members still thought alive, in a random order.
+    fn live(&self) -> Vec<Header> {
+        let mut live = self
+            .members
+            .values()
+            .filter(|m| m.node.routable())
+            .map(|m| m.node.header.clone())
+            .collect::<Vec<_>>();
+        live.shuffle(&mut rand::thread_rng());
+        live
+    }
+
+    // round robin over the members in an order shuffled every round.
+    fn next_probe(&mut self) -> Option<Header> {
+        loop {
+            if self.probes.is_empty() {
+                self.probes = self.live().into_iter().map(|h| h.id).collect();
+            }
+            let id = self.probes.pop()?;
+            match self.members.get(&id) {
+                Some(member) if member.node.routable() => return Some(member.node.header.clone()),
+                _ => continue,
+            }
+        }
+    }
+
+    fn nodes(&self) -> Vec<Node> {
+        std::iter::once(self.me.clone())
+            .chain(self.members.values().map(|m| m.node.clone()))
+            .collect()
+    }
+
+    // the registrations of `key` with `r#type` of the live members, this
+    // process included, by member id and address.
+    fn registered(&self, key: &str, r#type: i32) -> Vec<(String, ServiceContent)> {
+        let mut registered = std::iter::once(&self.me)
+            .chain(self.members.values().map(|m| &m.node))
+            .filter(|node| node.routable())
+            .flat_map(|node| {
+                node.registrations
+                    .iter()
+                    .filter(|(k, content)| k == key && content.r#type == r#type)
+                    .map(|(_, content)| {
+                        (registration_id(&node.header.id, content), content.clone())
+                    })
+            })
+            .collect::<Vec<_>>();
+        registered.sort_by(|a, b| a.0.cmp(&b.0));
+        registered
+    }
+}
+
+fn registration_id(member: &str, content: &ServiceContent) -> String {
+    format!("{}/{}", member, content.addr)
+}
+
+// gossip://0.0.0.0:7946?seeds=10.0.0.1:7946,10.0.0.2:7946&advertise=10.0.0.5:7946
+fn parse_addr(addr: &str) -> Result<(SocketAddr, Option<SocketAddr>, Vec<String>), PluginError> {
+    let invalid =
+        |why: &str| PluginError::Config(format!("gossip address `{}` is not valid: {}", addr, why));
+    let rest = addr
+        .strip_prefix("gossip://")
+        .ok_or_else(|| invalid("it must start with gossip://"))?;
+    let (bind, query) = rest.split_once('?').unwrap_or((rest, ""));
+    let bind = bind
+        .parse::<SocketAddr>()
+        .map_err(|_| invalid("the address to bind is not an ip and a port"))?;
+
+    let (mut advertise, mut seeds) = (None, vec![]);
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        match name {
+            "seeds" => seeds.extend(
+                value
+                    .split(',')
+                    .filter(|seed| !seed.is_empty())
+                    .map(str::to_string),
+            ),
+            "advertise" => {
+                advertise = Some(
+                    value
+                        .parse::<SocketAddr>()
+                        .map_err(|_| invalid("advertise is not an ip and a port"))?,
+                )
+            }
+            _ => return Err(invalid(&format!("unknown parameter `{}`", name))),
+        }
+    }
+    if let Some(seed) = seeds.iter().find(|seed| seed.rsplit_once(':').is_none()) {
+        return Err(invalid(&format!("seed `{}` has no port", seed)));
+    }
+    Ok((bind, advertise, seeds))
+}
+
+// where the others reach `bind`: itself unless unspecified, else the local
+// address that routes to the first seed there is.
+async fn advertised(bind: SocketAddr, seeds: &[String]) -> SocketAddr {
+    if !bind.ip().is_unspecified() {
+        return bind;
+    }
+    for seed in seeds {
+        let seed = match tokio::net::lookup_host(seed)
+            .await
+            .ok()
+            .and_then(|mut a| a.next())
+        {
+            Some(seed) => seed,
+            None => continue,
+        };
+        // connecting a udp socket sends nothing.
+        let local = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).and_then(|socket| {
+            socket.connect(seed)?;
+            socket.local_addr()
+        });
+        if let Ok(local) = local {
+            return SocketAddr::new(local.ip(), bind.port());
+        }
+    }
+    SocketAddr::new(Ipv4Addr::LOCALHOST.into(), bind.port())
+}
+
+async fn write_frame<T: Serialize>(stream: &mut TcpStream, value: &T) -> anyhow::Result<()> {
+    let frame = serde_json::to_vec(value)?;
+    stream.write_u32(frame.len() as u32).await?;
+    stream.write_all(&frame).await?;
+    Ok(())
+}
+
+async fn read_frame<T: DeserializeOwned>(stream: &mut TcpStream) -> anyhow::Result<T> {
+    let len = stream.read_u32().await?;
+    if len > MAX_FRAME {
+        anyhow::bail!("gossip frame of {} bytes is too large", len);
+    }
+    let mut frame = vec![0; len as usize];
+    stream.read_exact(&mut frame).await?;
+    Ok(serde_json::from_slice(&frame)?)
+}
+
+struct Inner {
+    state: Mutex<State>,
+    // taken once the member left, closed when the receive loop stops.
+    udp: RwLock<Option<Arc<UdpSocket>>>,
+    // true once the member left, the loops stop then.
+    stop: watch::Sender<bool>,
+    seeds: Vec<String>,
+    // the probes waiting for their ack, by sequence number.
+    acks: Mutex<HashMap<u64, oneshot::Sender<()>>>,
+    seq: AtomicU64,
+    events: Events,
+}
+
+#[derive(Clone)]
+pub struct GossipPlugin {
+    inner: Arc<Inner>,
+}
+
+impl GossipPlugin {
+    pub(super) async fn new(config: &PluginConfig, events: Events) -> Result<Self, PluginError> {
+        let (bind, advertise, seeds) = parse_addr(config.required_addr()?)?;
+        let cannot_bind =
+            |e: std::io::Error| PluginError::Config(format!("gossip cannot bind {}: {}", bind, e));
+        let udp = UdpSocket::bind(bind).await.map_err(cannot_bind)?;
+        // the same port for tcp when it was left to the system.
+        let bind = udp.local_addr().map_err(cannot_bind)?;
+        let tcp = TcpListener::bind(bind).await.map_err(cannot_bind)?;
+        let addr = match advertise {
+            Some(addr) => addr,
+            None => advertised(bind, &seeds).await,
+        };
+
+        let me = Header {
+            id: new_id(),
+            addr,
+            incarnation: 0,
+            status: Status::Alive,
+            version: 0,
+        };
+        tracing::info!(member = %me.id, addr = %addr, "gossip member started");
+        let plugin = Self {
+            inner: Arc::new(Inner {
+                state: Mutex::new(State::new(me)),
+                udp: RwLock::new(Some(Arc::new(udp))),
+                stop: watch::channel(false).0,
+                seeds,
+                acks: Mutex::new(HashMap::new()),
+                seq: AtomicU64::new(0),
+                events,
+            }),
+        };
+
+        tokio::spawn(plugin.clone().receive());
+        tokio::spawn(plugin.clone().accept(tcp));
+        tokio::spawn(plugin.clone().probe_loop());
+        tokio::spawn(plugin.clone().sync_loop());
+        plugin.join().await;
+        Ok(plugin)
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.inner.state.lock().unwrap()
+    }
+
+    fn udp(&self) -> Option<Arc<UdpSocket>> {
+        self.inner.udp.read().unwrap().clone()
+    }
+
+    // stops the loops, which drop the sockets as they return.
+    fn stop(&self) {
+        self.inner.stop.send_replace(true);
+        self.inner.udp.write().unwrap().take();
+    }
+
+    fn publish(&self, changed: BTreeSet<String>) {
+        for key in changed {
+            self.inner.events.backend_change(Some(key));
+        }
+    }
+
+    // syncs with the seeds until one answers; none may be up yet, the sync
+    // loop tries them again while there are no members.
+    async fn join(&self) {
+        for seed in &self.inner.seeds {
+            match self.sync(seed).await {
+                Ok(()) => return,
+                Err(e) => tracing::warn!(seed = %seed, error = %e, "gossip seed not reached"),
+            }
+        }
+    }
+
+    async fn send(&self, to: SocketAddr, datagram: &Datagram) {
+        let Some(udp) = self.udp() else {
+            return;
+        };
+        let sent = match serde_json::to_vec(datagram) {
+            Ok(bytes) if bytes.len() <= MAX_DATAGRAM => udp.send_to(&bytes, to).await.map(|_| ()),
+            Ok(bytes) => {
+                tracing::error!(bytes = bytes.len(), "gossip datagram too large");
+                return;
+            }
+            Err(e) => {
+                tracing::error!(error = %e, "gossip datagram not encoded");
+                return;
+            }
+        };
+        if let Err(e) = sent {
+            tracing::debug!(to = %to, error = %e, "gossip datagram not sent");
+        }
+    }
+
+    // merges the sender and the updates of a datagram.
+    fn heard(&self, from: Header, updates: Vec<Header>) {
+        let mut stale = vec![];
+        let mut changed = BTreeSet::new();
+        {
+            let mut state = self.state();
+            for header in std::iter::once(from).chain(updates) {
+                let addr = header.addr;
+                let applied = state.apply(header, None);
+                changed.extend(applied.changed);
+                if applied.stale {
+                    stale.push(addr);
+                }
+            }
+        }
+        self.publish(changed);
+        for addr in stale {
+            let plugin = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = plugin.sync(&addr.to_string()).await {
+                    tracing::debug!(member = %addr, error = %e, "gossip sync failed");
+                }
+            });
+        }
+    }
+
+    async fn receive(self) {
+        let Some(udp) = self.udp() else {
+            return;
+        };
+        let mut stop = self.inner.stop.subscribe();
+        let mut buf = vec![0; MAX_DATAGRAM];
+        loop {
+            let received = tokio::select! {
+                received = udp.recv_from(&mut buf) => received,
+                _ = stop.wait_for(|stopped| *stopped) => return,
+            };
+            let (len, src) = match received {
+                Ok(received) => received,
+                Err(e) => {
+                    tracing::debug!(error = %e, "gossip receive failed");
+                    continue;
+                }
+            };
+            let datagram = match serde_json::from_slice::<Datagram>(&buf[..len]) {
+                Ok(datagram) => datagram,
+                Err(e) => {
+                    tracing::debug!(from = %src, error = %e, "gossip skipped an invalid datagram");
+                    continue;
+                }
+            };
+            match datagram {
+                Datagram::Ping { seq, from, updates } => {
+                    self.heard(from, updates);
+                    let ack = {
+                        let mut state = self.state();
+                        Datagram::Ack {
+                            seq,
+                            from: state.me.header.clone(),
+                            updates: state.piggyback(),
+                        }
+                    };
+                    self.send(src, &ack).await;
+                }
+                Datagram::Ack { seq, from, updates } => {
+                    self.heard(from, updates);
+                    if let Some(acked) = self.inner.acks.lock().unwrap().remove(&seq) {
+                        let _ = acked.send(());
+                    }
+                }
+                Datagram::PingReq {
+                    seq,
+                    from,
+                    target,
+                    updates,
+                } => {
+                    self.heard(from, updates);
+                    let plugin = self.clone();
+                    tokio::spawn(async move {
+                        if plugin.ping(target, PROBE_TIMEOUT).await {
+                            let ack = {
+                                let mut state = plugin.state();
+                                Datagram::Ack {
+                                    seq,
+                                    from: state.me.header.clone(),
+                                    updates: state.piggyback(),
+                                }
+                            };
+                            plugin.send(src, &ack).await;
+                        }
+                    });
+                }
+            }
+        }
+    }
+
+    fn expect_ack(&self) -> (u64, oneshot::Receiver<()>) {
+        let seq = self.inner.seq.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        self.inner.acks.lock().unwrap().insert(seq, tx);
+        (seq, rx)
+    }
+
+    async fn ping(&self, target: SocketAddr, timeout: Duration) -> bool {
+        let (seq, acked) = self.expect_ack();
+        let ping = {
+            let mut state = self.state();
+            Datagram::Ping {
+                seq,
+                from: state.me.header.clone(),
+                updates: state.piggyback(),
+            }
+        };
+        self.send(target, &ping).await;
+        let acked = tokio::time::timeout(timeout, acked).await.is_ok();
+        self.inner.acks.lock().unwrap().remove(&seq);
+        acked
+    }
+
+    // pings `target` directly, then through others; false when neither
+    // answered within the interval.
+    async fn probe(&self, target: &Header) -> bool {
+        let (seq, acked) = self.expect_ack();
+        let (ping, relays) = {
+            let mut state = self.state();
+            let ping = Datagram::Ping {
+                seq,
+                from: state.me.header.clone(),
+                updates: state.piggyback(),
+            };
+            let relays = state
+                .live()
+                .into_iter()
+                .filter(|h| h.id != target.id)
+                .take(INDIRECT_PROBES)
+                .collect::<Vec<_>>();
+            (ping, relays)
+        };
+        self.send(target.addr, &ping).await;
+        tokio::pin!(acked);
+        if tokio::time::timeout(PROBE_TIMEOUT, &mut acked)
+            .await
+            .is_ok()
+        {
+            return true;
+        }
+
+        for relay in relays {
+            let req = {
+                let mut state = self.state();
+                Datagram::PingReq {
+                    seq,
+                    from: state.me.header.clone(),
+                    target: target.addr,
+                    updates: state.piggyback(),
+                }
+            };
+            self.send(relay.addr, &req).await;
+        }
+        let acked = tokio::time::timeout(PROBE_INTERVAL - PROBE_TIMEOUT, acked)
+            .await
+            .is_ok();
+        self.inner.acks.lock().unwrap().remove(&seq);
+        acked
+    }
+
+    async fn probe_loop(self) {
+        let mut stop = self.inner.stop.subscribe();
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(PROBE_INTERVAL) => {}
+                _ = stop.wait_for(|stopped| *stopped) => return,
+            }
+            let (changed, target) = {
+                let mut state = self.state();
+                (state.expire(), state.next_probe())
+            };
+            self.publish(changed);
+            if let Some(target) = target {
+                if !self.probe(&target).await {
+                    self.state().suspect(&target.id);
+                }
+            }
+        }
+    }
+
+    // exchanges everything known with `addr`.
+    async fn sync(&self, addr: &str) -> anyhow::Result<()> {
+        let exchange = async {
+            let mut stream = TcpStream::connect(addr).await?;
+            let nodes = self.state().nodes();
+            write_frame(&mut stream, &Sync { nodes }).await?;
+            read_frame::<Sync>(&mut stream).await
+        };
+        let sync = tokio::time::timeout(IO_TIMEOUT, exchange).await??;
+        self.merge(sync);
+        Ok(())
+    }
+
+    fn merge(&self, sync: Sync) {
+        let span = watch_span("gossip", "members", sync.nodes.len());
+        let _entered = span.enter();
+        let mut changed = BTreeSet::new();
+        {
+            let mut state = self.state();
+            for node in sync.nodes {
+                changed.extend(state.apply(node.header, Some(node.registrations)).changed);
+            }
+        }
+        self.publish(changed);
+    }
+
+    async fn accept(self, tcp: TcpListener) {
+        let mut stop = self.inner.stop.subscribe();
+        loop {
+            let accepted = tokio::select! {
+                accepted = tcp.accept() => accepted,
+                _ = stop.wait_for(|stopped| *stopped) => return,
+            };
+            let mut stream = match accepted {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    tracing::debug!(error = %e, "gossip accept failed");
+                    continue;
+                }
+            };
+            let plugin = self.clone();
+            tokio::spawn(async move {
+                let answer = async {
+                    let sync = read_frame::<Sync>(&mut stream).await?;
+                    plugin.merge(sync);
+                    let nodes = plugin.state().nodes();
+                    write_frame(&mut stream, &Sync { nodes }).await
+                };
+                match tokio::time::timeout(IO_TIMEOUT, answer).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => tracing::debug!(error = %e, "gossip sync not answered"),
+                    Err(_) => tracing::debug!("gossip sync timed out"),
+                }
+            });
+        }
+    }
+
+    // push-pull with a random member, or with the seeds while there is
+    // none.
+    async fn sync_loop(self) {
+        let mut stop = self.inner.stop.subscribe();
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(SYNC_INTERVAL) => {}
+                _ = stop.wait_for(|stopped| *stopped) => return,
+            }
+            let member = self.state().live().into_iter().next();
+            match member {
+                Some(member) => {
+                    if let Err(e) = self.sync(&member.addr.to_string()).await {
+                        tracing::debug!(member = %member.id, error = %e, "gossip sync failed");
+                    }
+                }
+                None => self.join().await,
+            }
+        }
+    }
+
+    // pushes the own state to a few members at once.
+    async fn push(&self) {
+        let members = self.state().live();
+        let syncs = members
+            .iter()
+            .take(FANOUT)
+            .map(|member| self.sync_with(member));
+        futures::future::join_all(syncs).await;
+    }
+
+    async fn sync_with(&self, member: &Header) {
+        if let Err(e) = self.sync(&member.addr.to_string()).await {
+            tracing::debug!(member = %member.id, error = %e, "gossip sync failed");
+        }
+    }
+
+    async fn update(&self, key: &str, update: impl Fn(&mut ServiceContent)) -> anyhow::Result<()> {
+        {
+            let mut state = self.state();
+            for (_, content) in state.me.registrations.iter_mut().filter(|(k, _)| k == key) {
+                update(content);
+            }
+            state.changed();
+        }
+        self.push().await;
+        Ok(())
+    }
+
+    // leaves the cluster once `shutdown` deregisters: declared dead by
+    // itself, its registrations are dropped at once. Nothing is gossiped
+    // after that last push.
+    fn leave_on(&self, shutdown: Shutdown) {
+        let deregistered = shutdown.guard(Phase::Deregister);
+        let plugin = self.clone();
+        tokio::spawn(async move {
+            shutdown.reached(Phase::Deregister).await;
+            {
+                let mut state = plugin.state();
+                let me = &mut state.me.header;
+                me.incarnation += 1;
+                me.status = Status::Dead;
+                let header = me.clone();
+                state.broadcast(header);
+            }
+            plugin.push().await;
+            plugin.stop();
+            tracing::info!("gossip member left");
+            drop(deregistered);
+        });
+    }
+}
+
+#[async_trait]
+impl Plugin for GossipPlugin {
+    async fn register_service(&self, key: &str, sc: ServiceContent) -> anyhow::Result<()> {
+        {
+            let mut state = self.state();
+            let registrations = &mut state.me.registrations;
+            registrations.retain(|(k, content)| !(k == key && content.addr == sc.addr));
+            registrations.push((key.to_string(), sc));
+            state.changed();
+        }
+        self.push().await;
+        Ok(())
+    }
+
+    async fn get_web_service(&self, key: &str) -> anyhow::Result<Vec<ServiceContent>> {
+        Ok(self
+            .state()
+            .registered(key, 1)
+            .into_iter()
+            .map(|(_, content)| content)
+            .collect())
+    }
+
+    async fn get_backend_service(&self, key: &str) -> anyhow::Result<(String, Vec<String>)> {
+        let state = self.state();
+        let self_id = state
+            .me
+            .registrations
+            .iter()
+            .find(|(k, content)| k == key && content.r#type == 2)
+            .map(|(_, content)| registration_id(&state.me.header.id, content))
+            .unwrap_or_default();
+        let members = state.registered(key, 2);
+        Ok((self_id, members.into_iter().map(|(id, _)| id).collect()))
+    }
+
+    async fn get_gateways(&self, key: &str) -> anyhow::Result<Vec<ServiceContent>> {
+        Ok(self
+            .state()
+            .registered(key, 3)
+            .into_iter()
+            .map(|(_, content)| content)
+            .collect())
+    }
+
+    async fn list_backend_service(
+        &self,
+        key: &str,
+    ) -> anyhow::Result<Vec<(String, ServiceContent)>> {
+        Ok(self.state().registered(key, 2))
+    }
+
+    async fn report_health(&self, key: &str, health: ServiceHealth) -> anyhow::Result<()> {
+        self.update(key, |content| content.health = Some(health.clone()))
+            .await
+    }
+
+    async fn set_metadata(
+        &self,
+        key: &str,
+        metadata: HashMap<String, String>,
+    ) -> anyhow::Result<()> {
+        self.update(key, |content| content.metadata.extend(metadata.clone()))
+            .await
+    }
+
+    async fn set_weight(&self, key: &str, weight: u32) -> anyhow::Result<()> {
+        self.update(key, |content| content.weight = weight).await
+    }
+}
+
+#[async_trait]
+impl Synchronize for GossipPlugin {
+    async fn gateway_service_handle(&mut self, shutdown: Shutdown) {
+        self.leave_on(shutdown);
+    }
+
+    async fn backend_service_handle(&mut self, shutdown: Shutdown) {
+        self.leave_on(shutdown);
+    }
+
+    async fn web_service_handle(&mut self, shutdown: Shutdown) {
+        self.leave_on(shutdown);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn suspicion_is_refuted() {
+        let header = |id: &str, incarnation, status| Header {
+            id: id.to_string(),
+            addr: "127.0.0.1:7946".parse().unwrap(),
+            incarnation,
+            status,
+            version: 0,
+        };
+        let mut state = State::new(header("me", 0, Status::Alive));
+        state.apply(header("other", 0, Status::Alive), None);
+        state.apply(header("other", 0, Status::Suspect), None);
+        assert_eq!(state.members["other"].node.header.status, Status::Suspect);
+        // older news does not override.
+        state.apply(header("other", 0, Status::Alive), None);
+        assert_eq!(state.members["other"].node.header.status, Status::Suspect);
+        state.apply(header("other", 1, Status::Alive), None);
+        assert_eq!(state.members["other"].node.header.status, Status::Alive);
+
+        state.apply(header("me", 0, Status::Suspect), None);
+        assert_eq!(state.me.header.incarnation, 1);
+        assert_eq!(state.me.header.status, Status::Alive);
+    }
+
+    #[tokio::test]
+    async fn registrations_spread_and_leave() {
+        let start = |addr: String| async move {
+            let config = PluginConfig::new(crate::PluginType::Gossip, &addr);
+            GossipPlugin::new(&config, Events::new()).await.unwrap()
+        };
+        let a = start("gossip://127.0.0.1:0".to_string()).await;
+        let seed = a.state().me.header.addr;
+        let b = start(format!("gossip://127.0.0.1:0?seeds={}", seed)).await;
+        let mut changes = b.inner.events.backend_changes.subscribe();
+
+        let shutdown = Shutdown::new();
+        let mut leaving = a.clone();
+        leaving.backend_service_handle(shutdown.clone()).await;
+        let content = ServiceContent {
+            service: "/jobs".to_string(),
+            addr: "127.0.0.1:9000".to_string(),
+            r#type: 2,
+            ..Default::default()
+        };
+        a.register_service("/jobs", content).await.unwrap();
+        assert_eq!(changes.recv().await.unwrap().as_deref(), Some("/jobs"));
+        let (_, members) = b.get_backend_service("/jobs").await.unwrap();
+        assert_eq!(members.len(), 1);
+
+        tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move { shutdown.run().await }
+        });
+        shutdown.trigger();
+        assert_eq!(changes.recv().await.unwrap().as_deref(), Some("/jobs"));
+        assert!(b.list_backend_service("/jobs").await.unwrap().is_empty());
+
+        // the port is free again once the loops stopped.
+        let rebound = async {
+            while UdpSocket::bind(seed).await.is_err() || TcpListener::bind(seed).await.is_err() {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(2), rebound)
+            .await
+            .unwrap();
+    }
+}
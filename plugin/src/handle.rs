@@ -0,0 +1,363 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use net::Shutdown;
+use tokio::sync::broadcast;
+
+use crate::flight::Flights;
+use crate::metrics;
+use crate::secrets;
+use crate::{
+    ConnectCerts, ConsulPlugin, EtcdPlugin, FilePlugin, GossipPlugin, MongodbPlugin, NonePlugin,
+    Plugin, PluginConfig, PluginError, PluginType, QueueTask, ServiceContent, ServiceHealth,
+    ServiceType,
+};
+
+/// What the background tasks of one plugin instance publish, e.g. the watch
+/// of an external plugin. Handed to its factory.
+#[derive(Debug, Clone)]
+pub struct Events {
+    // the service whose backend registrations changed, None when the watcher
+    // cannot tell which one.
+    pub(crate) backend_changes: broadcast::Sender<Option<String>>,
+    pub(crate) registry_errors: broadcast::Sender<String>,
+}
+
+impl Events {
+    pub(crate) fn new() -> Self {
+        Self {
+            backend_changes: broadcast::channel(64).0,
+            registry_errors: broadcast::channel(64).0,
+        }
+    }
+
+    pub fn backend_change(&self, service: Option<String>) {
+        // no receivers is fine
+        let _ = self.backend_changes.send(service);
+    }
+
+    pub fn registry_error(&self, error: String) {
+        metrics::REGISTRY_ERRORS.inc();
+        let _ = self.registry_errors.send(error);
+    }
+}
+
+// counts a batch of watch events and returns its span, so that cache
+// updates show up in the traces next to the requests they affect.
+pub(crate) fn watch_span(backend: &str, watched: &str, events: usize) -> tracing::Span {
+    metrics::WATCH_EVENTS
+        .with_label_values(&[backend])
+        .inc_by(events as u64);
+    tracing::info_span!("plugin.watch", backend, watched, events)
+}
+
+/// One connection to a registry backend together with the background tasks
+/// it runs. Clones share the connection; separate handles are isolated from
+/// each other, e.g. two backends in one process or one per test.
+#[derive(Clone)]
+pub struct PluginHandle {
+    plugin: Arc<dyn Plugin + Send + Sync>,
+    kind: PluginType,
+    events: Events,
+    // keys registered through this handle, so that they can be updated
+    // together.
+    registered: Arc<Mutex<BTreeSet<String>>>,
+    // lookups in flight, that concurrent callers of the same key share.
+    web_lookups: Flights<Vec<ServiceContent>>,
+    backend_lookups: Flights<(String, Vec<String>)>,
+}
+
+impl std::fmt::Debug for PluginHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PluginHandle")
+            .field("kind", &self.kind)
+            .field("registered", &self.registered_services())
+            .finish()
+    }
+}
+
+impl PluginHandle {
+    /// Connects to the backend of `config` and starts what `st` needs, e.g.
+    /// the renewal of registrations, until `shutdown` deregisters. Fails when the
+    /// backend cannot be reached; later failures of the background tasks are
+    /// published on `watch_registry_errors`.
+    pub async fn new(
+        shutdown: Shutdown,
+        st: ServiceType,
+        config: PluginConfig,
+    ) -> Result<Self, PluginError> {
+        Self::start(shutdown, st, config, Events::new()).await
+    }
+
+    pub(crate) async fn start(
+        shutdown: Shutdown,
+        st: ServiceType,
+        mut config: PluginConfig,
+        events: Events,
+    ) -> Result<Self, PluginError> {
+        let mut lease = None;
+        if let Some(provider) = config.secrets.clone() {
+            let credentials = provider
+                .fetch()
+                .await
+                .map_err(|e| PluginError::Secrets(format!("{:#}", e)))?;
+            config.username = credentials.username.or(config.username);
+            config.password = credentials.password.or(config.password);
+            lease = credentials.lease.map(|lease| (provider, lease));
+        }
+
+        let mut plugin: Box<dyn Plugin + Send + Sync + 'static> = match config.r#type {
+            PluginType::Mongodb => Box::new(MongodbPlugin::new(&config, events.clone()).await?),
+            PluginType::None => Box::new(NonePlugin::new().await),
+            PluginType::Etcd => Box::new(EtcdPlugin::new(&config, events.clone()).await?),
+            PluginType::Consul => Box::new(ConsulPlugin::new(&config, events.clone()).await?),
+            PluginType::File => Box::new(FilePlugin::new(&config, events.clone()).await?),
+            PluginType::Gossip => Box::new(GossipPlugin::new(&config, events.clone()).await?),
+            #[cfg(feature = "kubernetes")]
+            PluginType::Kubernetes => {
+                Box::new(crate::KubernetesPlugin::new(&config, events.clone()).await?)
+            }
+            #[cfg(not(feature = "kubernetes"))]
+            PluginType::Kubernetes => {
+                return Err(PluginError::Config(
+                    "the kubernetes plugin requires the kubernetes feature".to_string(),
+                ))
+            }
+            #[cfg(feature = "postgres")]
+            PluginType::Postgres => {
+                Box::new(crate::PostgresPlugin::new(&config, events.clone()).await?)
+            }
+            #[cfg(not(feature = "postgres"))]
+            PluginType::Postgres => {
+                return Err(PluginError::Config(
+                    "the postgres plugin requires the postgres feature".to_string(),
+                ))
+            }
+            PluginType::External(name) => {
+                crate::factory::build(name, &config, events.clone()).await?
+            }
+            PluginType::Mdns => return Err(PluginError::Unsupported("mdns as a registry")),
+        };
+
+        if let Some((provider, lease)) = lease {
+            secrets::keep_renewed(provider, lease, events.clone(), shutdown.clone());
+        }
+
+        // async task run...
+        match st {
+            ServiceType::ApiGateway => {
+                plugin.gateway_service_handle(shutdown).await;
+            }
+            ServiceType::BackendService => {
+                plugin.backend_service_handle(shutdown).await;
+            }
+            ServiceType::WebService => {
+                plugin.web_service_handle(shutdown).await;
+            }
+        }
+
+        tracing::info!(backend = config.r#type.as_str(), "plugin init success");
+
+        Ok(Self {
+            plugin: Arc::from(plugin),
+            kind: config.r#type,
+            events,
+            registered: Arc::new(Mutex::new(BTreeSet::new())),
+            web_lookups: Flights::default(),
+            backend_lookups: Flights::default(),
+        })
+    }
+
+    #[tracing::instrument(
+        name = "plugin.register",
+        skip(self, service_content),
+        fields(backend = self.kind.as_str()),
+        err
+    )]
+    pub async fn register_service(
+        &self,
+        key: &str,
+        service_content: ServiceContent,
+    ) -> anyhow::Result<()> {
+        let res = self.plugin.register_service(key, service_content).await;
+        metrics::observe(self.kind.as_str(), "register", &res);
+        res?;
+        self.registered.lock().unwrap().insert(key.to_string());
+        Ok(())
+    }
+
+    // counts what reached the backend, and what was shared apart.
+    fn observe_lookup<T>(&self, op: &str, res: &anyhow::Result<T>, shared: bool) {
+        match shared {
+            true => metrics::COALESCED
+                .with_label_values(&[self.kind.as_str(), op])
+                .inc(),
+            false => metrics::observe(self.kind.as_str(), op, res),
+        }
+    }
+
+    pub fn registered_services(&self) -> Vec<String> {
+        self.registered.lock().unwrap().iter().cloned().collect()
+    }
+
+    #[tracing::instrument(
+        name = "plugin.get_web_service",
+        skip(self),
+        fields(backend = self.kind.as_str()),
+        err
+    )]
+    pub async fn get_web_service(&self, k: &str) -> anyhow::Result<Vec<ServiceContent>> {
+        let (res, shared) = self
+            .web_lookups
+            .run(k, || self.plugin.get_web_service(k))
+            .await;
+        self.observe_lookup("get_web_service", &res, shared);
+        res
+    }
+
+    #[tracing::instrument(
+        name = "plugin.get_backend_service",
+        skip(self),
+        fields(backend = self.kind.as_str()),
+        err
+    )]
+    pub async fn get_backend_service(&self, k: &str) -> anyhow::Result<(String, Vec<String>)> {
+        let (res, shared) = self
+            .backend_lookups
+            .run(k, || self.plugin.get_backend_service(k))
+            .await;
+        self.observe_lookup("get_backend_service", &res, shared);
+        res
+    }
+
+    #[tracing::instrument(
+        name = "plugin.get_gateways",
+        skip(self),
+        fields(backend = self.kind.as_str()),
+        err
+    )]
+    pub async fn get_gateways(&self, k: &str) -> anyhow::Result<Vec<ServiceContent>> {
+        let res = self.plugin.get_gateways(k).await;
+        metrics::observe(self.kind.as_str(), "get_gateways", &res);
+        res
+    }
+
+    #[tracing::instrument(
+        name = "plugin.list_backend_service",
+        skip(self),
+        fields(backend = self.kind.as_str()),
+        err
+    )]
+    pub async fn list_backend_service(
+        &self,
+        k: &str,
+    ) -> anyhow::Result<Vec<(String, ServiceContent)>> {
+        let res = self.plugin.list_backend_service(k).await;
+        metrics::observe(self.kind.as_str(), "list_backend_service", &res);
+        res
+    }
+
+    pub async fn report_health(&self, k: &str, health: ServiceHealth) -> anyhow::Result<()> {
+        self.plugin.report_health(k, health).await
+    }
+
+    pub async fn set_weight(&self, k: &str, weight: u32) -> anyhow::Result<()> {
+        self.plugin.set_weight(k, weight).await
+    }
+
+    pub async fn set_metadata(
+        &self,
+        k: &str,
+        metadata: HashMap<String, String>,
+    ) -> anyhow::Result<()> {
+        self.plugin.set_metadata(k, metadata).await
+    }
+
+    pub async fn enqueue(&self, queue: &str, payload: String) -> anyhow::Result<String> {
+        self.plugin.enqueue(queue, payload).await
+    }
+
+    pub async fn enqueue_at(
+        &self,
+        queue: &str,
+        payload: String,
+        visible_at: u64,
+    ) -> anyhow::Result<String> {
+        self.plugin.enqueue_at(queue, payload, visible_at).await
+    }
+
+    pub async fn claim(
+        &self,
+        queue: &str,
+        visibility: Duration,
+    ) -> anyhow::Result<Option<QueueTask>> {
+        self.plugin.claim(queue, visibility).await
+    }
+
+    pub async fn ack(&self, task: &QueueTask) -> anyhow::Result<bool> {
+        self.plugin.ack(task).await
+    }
+
+    pub async fn nack(&self, task: &QueueTask) -> anyhow::Result<bool> {
+        self.plugin.nack(task).await
+    }
+
+    pub async fn connect_certs(&self, service: &str) -> anyhow::Result<ConnectCerts> {
+        let res = self.plugin.connect_certs(service).await;
+        metrics::observe(self.kind.as_str(), "connect_certs", &res);
+        res
+    }
+
+    // fed by the watch machinery of this handle; may lag, callers should
+    // re-read the members when it does.
+    pub fn watch_backend_changes(&self) -> broadcast::Receiver<Option<String>> {
+        self.events.backend_changes.subscribe()
+    }
+
+    // failures of the renewal and watch loops of this handle.
+    pub fn watch_registry_errors(&self) -> broadcast::Receiver<String> {
+        self.events.registry_errors.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn handles_are_isolated() {
+        let start = || async {
+            PluginHandle::new(
+                Shutdown::new(),
+                ServiceType::BackendService,
+                PluginConfig::new(PluginType::None, ""),
+            )
+            .await
+            .unwrap()
+        };
+        let (a, b) = (start().await, start().await);
+
+        a.register_service("/a", ServiceContent::default())
+            .await
+            .unwrap();
+        assert_eq!(a.registered_services(), ["/a"]);
+        assert!(b.registered_services().is_empty());
+
+        a.enqueue("jobs", "1".to_string()).await.unwrap();
+        assert!(a
+            .claim("jobs", Duration::from_secs(1))
+            .await
+            .unwrap()
+            .is_some());
+        assert!(b
+            .claim("jobs", Duration::from_secs(1))
+            .await
+            .unwrap()
+            .is_none());
+
+        let mut errors = b.watch_registry_errors();
+        a.events.registry_error("a failed".to_string());
+        assert!(errors.try_recv().is_err());
+    }
+}
@@ -0,0 +1,612 @@
+// Discovery inside a cluster, without a registry of its own: web services
+// are the Services annotated with `crossgate.io/service`, served by the
+// ready endpoints of their EndpointSlices. What registers itself, e.g. the
+// gateways, backend service members or web services outside the cluster, is
+// kept in `Registration` objects, see `plugin/kubernetes.yaml` for the
+// custom resource definition and the role the processes need.
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::{lock::Mutex, FutureExt, StreamExt};
+use k8s_openapi::api::core::v1::Service;
+use k8s_openapi::api::discovery::v1::EndpointSlice;
+use kube::api::{
+    Api, ApiResource, DeleteParams, DynamicObject, GroupVersionKind, Patch, PatchParams,
+};
+use kube::runtime::reflector::{self, store::Writer, Store};
+use kube::runtime::{watcher, WatchStreamExt};
+use kube::{Client, Resource, ResourceExt};
+use net::{Phase, Shutdown};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::handle::{watch_span, Events};
+use crate::queue::{new_id, now_millis};
+use crate::{
+    async_trait, HealthStatus, NamedEndpoint, Plugin, PluginConfig, PluginError, ServiceContent,
+    ServiceHealth, Synchronize,
+};
+
+// the annotations of a Service, `SERVICE` is the name it is looked up by,
+// e.g. `/t/ums`.
+const SERVICE: &str = "crossgate.io/service";
+const LBA: &str = "crossgate.io/lba";
+const VERSION: &str = "crossgate.io/version";
+const WEIGHT: &str = "crossgate.io/weight";
+// the port serving http, by default the one named http or the only one.
+const PORT: &str = "crossgate.io/port";
+// `true` when the port serves https, or the name to verify it against.
+const TLS: &str = "crossgate.io/tls";
+// the label an EndpointSlice names its Service in.
+const SLICE_SERVICE: &str = "kubernetes.io/service-name";
+const FIELD_MANAGER: &str = "crossgate";
+
+// registrations are applied again every `RENEW` and ignored once they were
+// not for `TTL`, e.g. after a crash.
+const RENEW: Duration = Duration::from_secs(10);
+const TTL: Duration = Duration::from_secs(30);
+// how long a lookup waits for the watches to list what there is.
+const SYNC_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn registration_resource() -> ApiResource {
+    ApiResource::from_gvk_with_plural(
+        &GroupVersionKind::gvk("crossgate.io", "v1", "Registration"),
+        "registrations",
+    )
+}
+
+fn now_secs() -> u64 {
+    now_millis() / 1000
+}
+
+// the spec of a `Registration` object.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Registration {
+    content: ServiceContent,
+    // unix secs, renewed before.
+    expires: u64,
+}
+
+// a registration of this process and the object it is kept in.
+#[derive(Debug, Clone)]
+struct Own {
+    name: String,
+    content: ServiceContent,
+}
+
+fn join_host_port(host: &str, port: i32) -> String {
+    match host.contains(':') {
+        true => format!("[{}]:{}", host, port),
+        false => format!("{}:{}", host, port),
+    }
+}
+
+// what the endpoints of `slices` serve as `service`, the ones that are not
+// ready carry a health the gateway does not route to.
+fn contents_of(service: &Service, slices: &[Arc<EndpointSlice>]) -> Vec<ServiceContent> {
+    let annotations = service.annotations();
+    let name = match annotations.get(SERVICE) {
+        Some(name) => name,
+        None => return vec![],
+    };
+    let port_name = annotations.get(PORT).map_or("http", String::as_str);
+    let tls = annotations
+        .get(TLS)
+        .filter(|tls| !tls.is_empty() && *tls != "false");
+
+    let mut contents = vec![];
+    for slice in slices {
+        let ports = slice.ports.as_deref().unwrap_or_default();
+        let http = match ports.iter().find(|p| p.name.as_deref() == Some(port_name)) {
+            Some(port) => port,
+            None if ports.len() == 1 => &ports[0],
+            None => continue,
+        };
+        let http_port = match http.port {
+            Some(port) => port,
+            None => continue,
+        };
+
+        for endpoint in &slice.endpoints {
+            let conditions = endpoint.conditions.clone().unwrap_or_default();
+            let health = match (conditions.ready, conditions.serving, conditions.terminating) {
+                // unset is ready.
+                (Some(true) | None, _, _) => None,
+                (_, Some(true), Some(true)) => Some(HealthStatus::Draining),
+                _ => Some(HealthStatus::Unhealthy),
+            };
+            let mut metadata = HashMap::new();
+            if let Some(zone) = &endpoint.zone {
+                metadata.insert("zone".to_string(), zone.clone());
+            }
+
+            for address in &endpoint.addresses {
+                let endpoints = ports
+                    .iter()
+                    .filter(|p| p.port.is_some() && p.port != Some(http_port))
+                    .map(|p| {
+                        let name = p.name.clone().unwrap_or_default();
+                        NamedEndpoint {
+                            protocol: p.app_protocol.clone().unwrap_or_else(|| name.clone()),
+                            name,
+                            addr: join_host_port(address, p.port.unwrap_or_default()),
+                        }
+                    })
+                    .collect();
+                contents.push(ServiceContent {
+                    service: name.clone(),
+                    lba: annotations.get(LBA).cloned().unwrap_or_default(),
+                    addr: join_host_port(address, http_port),
+                    health: health.map(|status| ServiceHealth {
+                        status,
+                        lag: 0,
+                        last_success: 0,
+                        detail: "endpoint not ready".to_string(),
+                    }),
+                    weight: annotations
+                        .get(WEIGHT)
+                        .and_then(|w| w.parse().ok())
+                        .unwrap_or(1),
+                    version: annotations.get(VERSION).cloned().unwrap_or_default(),
+                    metadata: metadata.clone(),
+                    endpoints,
+                    tls: tls.is_some(),
+                    sni: tls
+                        .filter(|tls| *tls != "true")
+                        .cloned()
+                        .unwrap_or_default(),
+                    ..Default::default()
+                });
+            }
+        }
+    }
+    contents
+}
+
+// `registered` in place of what the slices found at its address, unless
+// the endpoint there is not ready.
+fn merge(contents: &mut Vec<ServiceContent>, registered: ServiceContent) {
+    match contents.iter_mut().find(|c| c.addr == registered.addr) {
+        Some(found) if found.health.is_none() => *found = registered,
+        Some(_) => {}
+        None => contents.push(registered),
+    }
+}
+
+async fn synced<K>(store: &Store<K>, watched: &str) -> anyhow::Result<()>
+where
+    K: Resource + Clone + 'static,
+    K::DynamicType: Eq + Hash + Clone,
+{
+    match tokio::time::timeout(SYNC_TIMEOUT, store.wait_until_ready()).await {
+        Ok(Ok(())) => Ok(()),
+        _ => Err(anyhow::anyhow!(
+            "kubernetes {} not listed within {:?}",
+            watched,
+            SYNC_TIMEOUT
+        )),
+    }
+}
+
+// keeps the store of `writer` up to date with what `api` lists, `changed`
+// gets every object added, modified or deleted.
+fn watch<K>(
+    api: Api<K>,
+    writer: Writer<K>,
+    events: Events,
+    watched: &'static str,
+    changed: impl Fn(&K) + Send + 'static,
+) where
+    K: Resource + Clone + DeserializeOwned + Debug + Send + Sync + 'static,
+    K::DynamicType: Eq + Hash + Clone + Send + Sync,
+{
+    let stream = reflector::reflector(
+        writer,
+        watcher(api, watcher::Config::default()).default_backoff(),
+    );
+    tokio::spawn(async move {
+        futures::pin_mut!(stream);
+        while let Some(event) = stream.next().await {
+            match event {
+                Ok(
+                    watcher::Event::Apply(object)
+                    | watcher::Event::InitApply(object)
+                    | watcher::Event::Delete(object),
+                ) => {
+                    let _span = watch_span("kubernetes", watched, 1).entered();
+                    changed(&object);
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    tracing::error!(watched, error = %e, "kubernetes watch failed");
+                    events.registry_error(format!("watch {}: {}", watched, e));
+                }
+            }
+        }
+    });
+}
+
+#[derive(Clone)]
+pub struct KubernetesPlugin {
+    client: Client,
+    namespace: String,
+    resource: ApiResource,
+    services: Store<Service>,
+    slices: Store<EndpointSlice>,
+    registrations: Store<DynamicObject>,
+    // the registrations of this process, by service and address.
+    inner: Arc<Mutex<HashMap<String, Own>>>,
+    events: Events,
+}
+
+impl KubernetesPlugin {
+    pub(super) async fn new(config: &PluginConfig, events: Events) -> Result<Self, PluginError> {
+        // kubernetes://namespace, by default the one of the pod or the
+        // kubeconfig context.
+        let namespace = match config.addr.strip_prefix("kubernetes://") {
+            Some(namespace) => namespace,
+            None if config.addr.is_empty() => "",
+            None => {
+                return Err(PluginError::Config(format!(
+                    "kubernetes address `{}` must start with kubernetes://",
+                    config.addr
+                )))
+            }
+        };
+        let mut kube_config = kube::Config::infer()
+            .await
+            .map_err(|e| PluginError::Config(format!("kubernetes: {}", e)))?;
+        if let Some(connect_timeout) = config.connect_timeout {
+            kube_config.connect_timeout = Some(connect_timeout);
+        }
+        let client = Client::try_from(kube_config)?;
+        let namespace = match namespace {
+            "" => client.default_namespace().to_string(),
+            namespace => namespace.to_string(),
+        };
+        let resource = registration_resource();
+
+        let (services, services_writer) = reflector::store();
+        watch(
+            Api::<Service>::namespaced(client.clone(), &namespace),
+            services_writer,
+            events.clone(),
+            "services",
+            |_| {},
+        );
+        let (slices, slices_writer) = reflector::store();
+        watch(
+            Api::<EndpointSlice>::namespaced(client.clone(), &namespace),
+            slices_writer,
+            events.clone(),
+            "endpointslices",
+            |_| {},
+        );
+        let registrations_writer = Writer::new(resource.clone());
+        let registrations = registrations_writer.as_reader();
+        let changes = events.clone();
+        watch(
+            Api::namespaced_with(client.clone(), &namespace, &resource),
+            registrations_writer,
+            events.clone(),
+            "registrations",
+            move |object: &DynamicObject| {
+                if let Some(registration) = Self::decode(object) {
+                    if registration.content.r#type == 2 {
+                        changes.backend_change(Some(registration.content.service));
+                    }
+                }
+            },
+        );
+
+        Ok(Self {
+            client,
+            namespace,
+            resource,
+            services,
+            slices,
+            registrations,
+            inner: Arc::new(Mutex::new(HashMap::new())),
+            events,
+        })
+    }
+
+    fn decode(object: &DynamicObject) -> Option<Registration> {
+        serde_json::from_value(object.data.get("spec")?.clone()).ok()
+    }
+
+    fn api(&self) -> Api<DynamicObject> {
+        Api::namespaced_with(self.client.clone(), &self.namespace, &self.resource)
+    }
+
+    async fn apply(&self, own: &Own) -> anyhow::Result<()> {
+        let registration = Registration {
+            content: own.content.clone(),
+            expires: now_secs() + TTL.as_secs(),
+        };
+        let object = DynamicObject::new(&own.name, &self.resource)
+            .data(serde_json::json!({ "spec": registration }));
+        self.api()
+            .patch(
+                &own.name,
+                &PatchParams::apply(FIELD_MANAGER).force(),
+                &Patch::Apply(&object),
+            )
+            .await?;
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "plugin.renew", skip_all, fields(backend = "kubernetes"))]
+    async fn renew(&self) {
+        for own in self.inner.lock().await.values() {
+            if let Err(e) = self.apply(own).await {
+                tracing::error!(service = %own.content.service, error = %e, "kubernetes register failed");
+                self.events
+                    .registry_error(format!("{} renewal: {}", own.content.service, e));
+            }
+        }
+    }
+
+    async fn unregister(&self) {
+        for own in self.inner.lock().await.values() {
+            if let Err(e) = self.api().delete(&own.name, &DeleteParams::default()).await {
+                tracing::error!(service = %own.content.service, error = %e, "kubernetes unregister failed");
+            }
+        }
+    }
+
+    // the unexpired registrations of `key` with `r#type`, by object name.
+    async fn registered(
+        &self,
+        key: &str,
+        r#type: i32,
+    ) -> anyhow::Result<Vec<(String, ServiceContent)>> {
+        synced(&self.registrations, "registrations").await?;
+        let now = now_secs();
+        let mut registered = self
+            .registrations
+            .state()
+            .iter()
+            .filter_map(|object| Some((object.name_any(), Self::decode(object)?)))
+            .filter(|(_, r)| {
+                r.expires > now && r.content.service == key && r.content.r#type == r#type
+            })
+            .map(|(name, r)| (name, r.content))
+            .collect::<Vec<_>>();
+        registered.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(registered)
+    }
+
+    // updates the registrations of this process under `key` and applies
+    // them when `publish`.
+    async fn update(
+        &self,
+        key: &str,
+        publish: bool,
+        update: impl Fn(&mut ServiceContent),
+    ) -> anyhow::Result<()> {
+        let mut inner = self.inner.lock().await;
+        for own in inner.values_mut().filter(|own| own.content.service == key) {
+            update(&mut own.content);
+            if publish {
+                self.apply(own).await?;
+            }
+        }
+        Ok(())
+    }
+
+    // renews the registrations of this process until `shutdown` deregisters.
+    fn keep_registered(&self, shutdown: Shutdown) {
+        let deregistered = shutdown.guard(Phase::Deregister);
+        let plugin = self.clone();
+        tokio::spawn(async move {
+            let renew = async {
+                loop {
+                    tokio::time::sleep(RENEW).await;
+                    plugin.renew().await;
+                }
+            };
+            tokio::select! {
+                _ = renew => {},
+                _ = shutdown.reached(Phase::Deregister) => {
+                    plugin.unregister().await;
+                    drop(deregistered);
+                },
+            }
+        });
+    }
+}
+
+#[async_trait]
+impl Plugin for KubernetesPlugin {
+    async fn register_service(&self, key: &str, sc: ServiceContent) -> anyhow::Result<()> {
+        let own = {
+            let mut inner = self.inner.lock().await;
+            let own = inner
+                .entry(format!("{}/{}", key, sc.addr))
+                .or_insert_with(|| Own {
+                    name: format!("crossgate-{}", new_id()),
+                    content: sc.clone(),
+                });
+            own.content = sc;
+            own.clone()
+        };
+        self.apply(&own).await
+    }
+
+    async fn get_web_service(&self, key: &str) -> anyhow::Result<Vec<ServiceContent>> {
+        synced(&self.services, "services").await?;
+        synced(&self.slices, "endpointslices").await?;
+        let slices = self.slices.state();
+
+        let mut contents = vec![];
+        for service in self.services.state() {
+            if service.annotations().get(SERVICE).map(String::as_str) != Some(key) {
+                continue;
+            }
+            let name = service.name_any();
+            let served = slices
+                .iter()
+                .filter(|slice| slice.labels().get(SLICE_SERVICE) == Some(&name))
+                .cloned()
+                .collect::<Vec<_>>();
+            contents.extend(contents_of(&service, &served));
+        }
+
+        // registrations only count once they are listed, the custom
+        // resource may not even be installed.
+        if let Some(Ok(())) = self.registrations.wait_until_ready().now_or_never() {
+            for (_, registered) in self.registered(key, 1).await? {
+                merge(&mut contents, registered);
+            }
+        }
+        Ok(contents)
+    }
+
+    async fn get_backend_service(&self, key: &str) -> anyhow::Result<(String, Vec<String>)> {
+        let self_id = self
+            .inner
+            .lock()
+            .await
+            .values()
+            .find(|own| own.content.service == key && own.content.r#type == 2)
+            .map(|own| own.name.clone())
+            .unwrap_or_default();
+        let members = self.registered(key, 2).await?;
+        Ok((self_id, members.into_iter().map(|(name, _)| name).collect()))
+    }
+
+    async fn get_gateways(&self, key: &str) -> anyhow::Result<Vec<ServiceContent>> {
+        Ok(self
+            .registered(key, 3)
+            .await?
+            .into_iter()
+            .map(|(_, content)| content)
+            .collect())
+    }
+
+    async fn list_backend_service(
+        &self,
+        key: &str,
+    ) -> anyhow::Result<Vec<(String, ServiceContent)>> {
+        self.registered(key, 2).await
+    }
+
+    async fn report_health(&self, key: &str, health: ServiceHealth) -> anyhow::Result<()> {
+        self.update(key, true, |content| content.health = Some(health.clone()))
+            .await
+    }
+
+    async fn set_metadata(
+        &self,
+        key: &str,
+        metadata: HashMap<String, String>,
+    ) -> anyhow::Result<()> {
+        self.update(key, true, |content| {
+            content.metadata.extend(metadata.clone())
+        })
+        .await
+    }
+
+    async fn set_weight(&self, key: &str, weight: u32) -> anyhow::Result<()> {
+        // published by the next renewal.
+        self.update(key, false, |content| content.weight = weight)
+            .await
+    }
+}
+
+#[async_trait]
+impl Synchronize for KubernetesPlugin {
+    async fn gateway_service_handle(&mut self, shutdown: Shutdown) {
+        self.keep_registered(shutdown);
+    }
+
+    async fn backend_service_handle(&mut self, shutdown: Shutdown) {
+        self.keep_registered(shutdown);
+    }
+
+    async fn web_service_handle(&mut self, shutdown: Shutdown) {
+        self.keep_registered(shutdown);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use k8s_openapi::api::discovery::v1::{Endpoint, EndpointConditions, EndpointPort};
+    use kube::api::ObjectMeta;
+
+    #[test]
+    fn annotated_services_are_served_by_their_slices() {
+        let service = Service {
+            metadata: ObjectMeta {
+                name: Some("ums".into()),
+                annotations: Some(
+                    [
+                        (SERVICE.to_string(), "/t/ums".to_string()),
+                        (WEIGHT.to_string(), "3".to_string()),
+                    ]
+                    .into_iter()
+                    .collect(),
+                ),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let endpoint = |address: &str, ready: bool, terminating: bool| Endpoint {
+            addresses: vec![address.to_string()],
+            conditions: Some(EndpointConditions {
+                ready: Some(ready),
+                serving: Some(ready || terminating),
+                terminating: Some(terminating),
+            }),
+            zone: Some("eu-1a".into()),
+            ..Default::default()
+        };
+        let port = |name: &str, port: i32| EndpointPort {
+            name: Some(name.into()),
+            port: Some(port),
+            app_protocol: None,
+            protocol: None,
+        };
+        let slice = Arc::new(EndpointSlice {
+            address_type: "IPv4".into(),
+            endpoints: vec![
+                endpoint("10.0.0.1", true, false),
+                endpoint("10.0.0.2", false, true),
+                endpoint("10.0.0.3", false, false),
+            ],
+            ports: Some(vec![port("grpc", 9000), port("http", 8080)]),
+            ..Default::default()
+        });
+
+        let mut contents = contents_of(&service, &[slice]);
+        let addrs = contents.iter().map(|c| c.addr.as_str()).collect::<Vec<_>>();
+        assert_eq!(addrs, ["10.0.0.1:8080", "10.0.0.2:8080", "10.0.0.3:8080"]);
+        assert_eq!(contents[0].service, "/t/ums");
+        assert_eq!(contents[0].weight, 3);
+        assert!(contents[0].health.is_none());
+        assert_eq!(contents[0].metadata["zone"], "eu-1a");
+        assert_eq!(contents[0].endpoints[0].addr, "10.0.0.1:9000");
+        assert_eq!(contents[0].endpoints[0].protocol, "grpc");
+        let status = |c: &ServiceContent| c.health.as_ref().map(|h| h.status);
+        assert_eq!(status(&contents[1]), Some(HealthStatus::Draining));
+        assert_eq!(status(&contents[2]), Some(HealthStatus::Unhealthy));
+
+        let registered = |addr: &str| ServiceContent {
+            service: "/t/ums".into(),
+            addr: addr.into(),
+            version: "v2".into(),
+            ..Default::default()
+        };
+        merge(&mut contents, registered("10.0.0.1:8080"));
+        merge(&mut contents, registered("10.0.0.3:8080"));
+        merge(&mut contents, registered("192.168.1.9:8080"));
+        assert_eq!(contents[0].version, "v2");
+        assert_eq!(contents[2].version, "");
+        assert_eq!(contents.len(), 4);
+    }
+}
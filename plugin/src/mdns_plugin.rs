@@ -14,7 +14,7 @@ impl Mdns {
 // #[crate::async_trait]
 // impl crate::Plugin for Mdns {
 //     async fn set(&mut self, k: &str, val: crate::Content) -> Result<(), crate::PluginError> {
-//         log::info!("set key {},val {:?}", k, val);
+//         tracing::info!("set key {},val {:?}", k, val);
 //         Ok(())
 //     }
 //     async fn get(&self, k: &str) -> Result<Vec<crate::Content>, crate::PluginError> {
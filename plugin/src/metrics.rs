@@ -0,0 +1,72 @@
+use once_cell::sync::Lazy;
+use prometheus::{core::Collector, IntCounter, IntCounterVec, Opts};
+
+// published in the process-wide prometheus registry, next to the ones of
+// micro and net.
+fn register<C: Collector + Clone + 'static>(c: C) -> C {
+    if let Err(e) = prometheus::default_registry().register(Box::new(c.clone())) {
+        tracing::error!(error = ?e, "register plugin metrics failed");
+    }
+    c
+}
+
+// registry backend calls by backend, operation and result (ok or error).
+pub(crate) static OPERATIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new(
+                "crossgate_plugin_operations_total",
+                "Registry backend calls",
+            ),
+            &["backend", "op", "result"],
+        )
+        .unwrap(),
+    )
+});
+
+// failed renewals and watches, everything reported on the registry error
+// channels.
+pub(crate) static REGISTRY_ERRORS: Lazy<IntCounter> = Lazy::new(|| {
+    register(
+        IntCounter::new(
+            "crossgate_plugin_registry_errors_total",
+            "Failures of the renewal and watch loops",
+        )
+        .unwrap(),
+    )
+});
+
+// lookups answered by one already in flight for the same key.
+pub(crate) static COALESCED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new(
+                "crossgate_plugin_coalesced_total",
+                "Lookups sharing the result of one in flight",
+            ),
+            &["backend", "op"],
+        )
+        .unwrap(),
+    )
+});
+
+pub(crate) static WATCH_EVENTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new(
+                "crossgate_plugin_watch_events_total",
+                "Registry changes seen by the watchers",
+            ),
+            &["backend"],
+        )
+        .unwrap(),
+    )
+});
+
+pub(crate) fn observe<T>(backend: &str, op: &str, result: &anyhow::Result<T>) {
+    let result = match result {
+        Ok(_) => "ok",
+        Err(_) => "error",
+    };
+    OPERATIONS.with_label_values(&[backend, op, result]).inc();
+}
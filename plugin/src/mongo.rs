@@ -1,18 +1,25 @@
-use crossbeam::sync::WaitGroup;
 use futures::{lock::Mutex, TryStreamExt};
 use serde::{Deserialize, Serialize};
 use std::{collections::HashMap, sync::Arc};
-use tokio_context::context::Context;
+use net::{Phase, Shutdown};
+use tracing::Instrument;
 
 use crate::async_trait;
 use mongodb::{
     bson::{doc, oid::ObjectId},
     change_stream::{self, event::ChangeStreamEvent},
-    options::{ChangeStreamOptions, FindOptions, FullDocumentType, IndexOptions, UpdateOptions},
+    options::{
+        ChangeStreamOptions, Credential, FindOneAndUpdateOptions, FindOptions, FullDocumentType,
+        IndexOptions, ReturnDocument, UpdateOptions,
+    },
     Client, IndexModel,
 };
 
-use crate::{Plugin, ServiceContent, Synchronize};
+use crate::handle::{watch_span, Events};
+use crate::queue::new_id;
+use crate::{
+    Plugin, PluginConfig, PluginError, QueueTask, ServiceContent, ServiceHealth, Synchronize,
+};
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 struct MongoContent {
@@ -29,8 +36,33 @@ impl PartialEq for MongoContent {
     }
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct MongoTask {
+    #[serde(rename(serialize = "_id", deserialize = "_id"))]
+    id: String,
+    queue: String,
+    payload: String,
+    attempts: u32,
+    lease: String,
+    visible_at: mongodb::bson::DateTime,
+}
+
+impl From<MongoTask> for QueueTask {
+    fn from(t: MongoTask) -> Self {
+        QueueTask {
+            id: t.id,
+            queue: t.queue,
+            payload: t.payload,
+            attempts: t.attempts,
+            lease: t.lease,
+            visible_at: t.visible_at.timestamp_millis() as u64,
+        }
+    }
+}
+
 static SCHEMA_NAME: &str = "crossgate";
 static COLLECTION_NAME: &str = "discovery";
+static QUEUE_COLLECTION_NAME: &str = "queue";
 
 #[derive(Debug, Clone)]
 pub struct MongodbPlugin {
@@ -42,22 +74,29 @@ pub struct MongodbPlugin {
     collection: String,
 
     client: Client,
+    events: Events,
 }
 
 impl MongodbPlugin {
-    pub(super) async fn new() -> Self {
-        dotenv::dotenv().ok();
-        let uri = std::env::var("REGISTER_ADDR").expect("REGISTER_ADDR is not set");
-
-        let client = match mongodb::options::ClientOptions::parse_with_resolver_config(
-            &uri,
+    pub(super) async fn new(config: &PluginConfig, events: Events) -> Result<Self, PluginError> {
+        let mut options = mongodb::options::ClientOptions::parse_with_resolver_config(
+            config.required_addr()?,
             mongodb::options::ResolverConfig::cloudflare(),
         )
-        .await
-        {
-            Ok(options) => Client::with_options(options).unwrap(),
-            Err(e) => panic!("{:?}", e),
-        };
+        .await?;
+        // credentials in the uri win over the configured ones.
+        if options.credential.is_none() && config.username.is_some() {
+            options.credential = Some(
+                Credential::builder()
+                    .username(config.username.clone())
+                    .password(config.password.clone())
+                    .build(),
+            );
+        }
+        if config.connect_timeout.is_some() {
+            options.connect_timeout = config.connect_timeout;
+        }
+        let client = Client::with_options(options)?;
 
         let mut s = Self {
             inner: Arc::new(Mutex::new(vec![])),
@@ -67,11 +106,12 @@ impl MongodbPlugin {
             collection: COLLECTION_NAME.to_string(),
 
             client,
+            events,
         };
 
         s.init().await;
 
-        s
+        Ok(s)
     }
 
     #[inline]
@@ -90,6 +130,16 @@ impl MongodbPlugin {
                 None,
             )
             .await;
+
+        let _ = self
+            .queue_collection()
+            .create_index(
+                IndexModel::builder()
+                    .keys(doc! { "queue":1, "visible_at":1 })
+                    .build(),
+                None,
+            )
+            .await;
     }
 
     #[inline]
@@ -99,6 +149,13 @@ impl MongodbPlugin {
             .collection(&self.collection)
     }
 
+    #[inline]
+    fn queue_collection(&self) -> mongodb::Collection<MongoTask> {
+        self.client
+            .database(&self.schema)
+            .collection(QUEUE_COLLECTION_NAME)
+    }
+
     #[inline]
     async fn update_cache(&mut self, key: String, c: &MongoContent) {
         let mut cache = self.cache.lock().await;
@@ -122,13 +179,15 @@ impl MongodbPlugin {
         }
     }
 
-    #[inline]
+    #[tracing::instrument(name = "plugin.renew", skip_all, fields(backend = "mongodb"))]
     async fn service_content_renewal(&mut self) {
         let contents = self.inner.lock().await;
         for c in contents.clone().iter() {
             let id = c.id.clone();
             if let Err(e) = self.service_content_apply(&id, &c.content).await {
-                log::error!("{:?}", e);
+                tracing::error!(service = %c.content.service, error = ?e, "mongodb renewal failed");
+                self.events
+                    .registry_error(format!("{} renewal: {}", c.content.service, e));
             }
         }
     }
@@ -154,7 +213,7 @@ impl MongodbPlugin {
                     None,
                 )
                 .await
-                .map_err(|e| crate::PluginError::Error(e.to_string()))?;
+                .map_err(crate::PluginError::Mongo)?;
         } else {
             self.group_collection()
                 .update_one(
@@ -165,12 +224,13 @@ impl MongodbPlugin {
                         "$set":
                         {
                             "time": mongodb::bson::DateTime::now(),
+                            "weight": content.weight as i64,
                         },
                     },
                     UpdateOptions::builder().upsert(false).build(),
                 )
                 .await
-                .map_err(|e| crate::PluginError::Error(e.to_string()))?;
+                .map_err(crate::PluginError::Mongo)?;
         }
 
         Ok(())
@@ -190,13 +250,9 @@ impl MongodbPlugin {
                 FindOptions::builder().sort(doc! { "_id": -1 }).build(),
             )
             .await
-            .map_err(|e| crate::PluginError::Error(e.to_string()))?;
+            .map_err(crate::PluginError::Mongo)?;
 
-        while let Some(doc) = cursor
-            .try_next()
-            .await
-            .map_err(|e| crate::PluginError::Error(e.to_string()))?
-        {
+        while let Some(doc) = cursor.try_next().await.map_err(crate::PluginError::Mongo)? {
             let key = if doc.content.service.eq("") {
                 doc.id.clone()
             } else {
@@ -239,11 +295,13 @@ impl MongodbPlugin {
     async fn service_unset(&mut self) {
         let contents = self.inner.lock().await;
         for c in contents.iter() {
-            self.group_collection()
+            if let Err(e) = self
+                .group_collection()
                 .delete_one(doc! {"_id":c.id.clone()}, None)
                 .await
-                .map_err(|e| log::error!("unset service {:?}", e))
-                .unwrap();
+            {
+                tracing::error!(service = %c.content.service, error = ?e, "mongodb unset service failed");
+            }
         }
     }
 }
@@ -265,6 +323,10 @@ impl Plugin for MongodbPlugin {
         self.list_service_content(k, 1).await
     }
 
+    async fn get_gateways(&self, k: &str) -> anyhow::Result<Vec<ServiceContent>> {
+        self.list_service_content(k, 3).await
+    }
+
     async fn get_backend_service(&self, k: &str) -> anyhow::Result<(String, Vec<String>)> {
         let mut self_id: String = "".into();
         let inner = self.inner.lock().await;
@@ -287,25 +349,185 @@ impl Plugin for MongodbPlugin {
 
         Ok((self_id, results))
     }
+
+    async fn list_backend_service(&self, k: &str) -> anyhow::Result<Vec<(String, ServiceContent)>> {
+        Ok(self
+            .list_mongo_content(k.to_string(), 2)
+            .await?
+            .into_iter()
+            .map(|mc| (mc.id, mc.content))
+            .collect())
+    }
+
+    async fn set_weight(&self, k: &str, weight: u32) -> anyhow::Result<()> {
+        let mut inner = self.inner.lock().await;
+        for c in inner.iter_mut().filter(|c| c.content.service.eq(k)) {
+            c.content.weight = weight;
+        }
+        Ok(())
+    }
+
+    async fn set_metadata(&self, k: &str, metadata: HashMap<String, String>) -> anyhow::Result<()> {
+        let mut inner = self.inner.lock().await;
+        for c in inner.iter_mut().filter(|c| c.content.service.eq(k)) {
+            c.content.metadata.extend(metadata.clone());
+
+            // renewal only touches `time`, so publish it right away.
+            self.group_collection()
+                .update_one(
+                    doc! { "_id": c.id.clone() },
+                    doc! { "$set": { "metadata": mongodb::bson::to_bson(&c.content.metadata)? } },
+                    None,
+                )
+                .await
+                .map_err(crate::PluginError::Mongo)?;
+        }
+
+        Ok(())
+    }
+
+    async fn report_health(&self, k: &str, health: ServiceHealth) -> anyhow::Result<()> {
+        let mut inner = self.inner.lock().await;
+        for c in inner.iter_mut().filter(|c| c.content.service.eq(k)) {
+            c.content.health = Some(health.clone());
+
+            // renewal only touches `time`, so publish it right away.
+            self.group_collection()
+                .update_one(
+                    doc! { "_id": c.id.clone() },
+                    doc! { "$set": { "health": mongodb::bson::to_bson(&health)? } },
+                    None,
+                )
+                .await
+                .map_err(crate::PluginError::Mongo)?;
+        }
+
+        Ok(())
+    }
+
+    async fn enqueue_at(
+        &self,
+        queue: &str,
+        payload: String,
+        visible_at: u64,
+    ) -> anyhow::Result<String> {
+        let id = new_id();
+        self.queue_collection()
+            .insert_one(
+                MongoTask {
+                    id: id.clone(),
+                    queue: queue.to_string(),
+                    payload,
+                    attempts: 0,
+                    lease: "".into(),
+                    visible_at: mongodb::bson::DateTime::from_millis(visible_at as i64),
+                },
+                None,
+            )
+            .await
+            .map_err(crate::PluginError::Mongo)?;
+
+        Ok(id)
+    }
+
+    async fn claim(
+        &self,
+        queue: &str,
+        visibility: std::time::Duration,
+    ) -> anyhow::Result<Option<QueueTask>> {
+        let now = mongodb::bson::DateTime::now();
+        let visible_at = mongodb::bson::DateTime::from_millis(
+            now.timestamp_millis() + visibility.as_millis() as i64,
+        );
+
+        // the filter and the update run atomically, two consumers never get
+        // the same lease.
+        let task = self
+            .queue_collection()
+            .find_one_and_update(
+                doc! { "queue": queue, "visible_at": { "$lte": now } },
+                doc! {
+                    "$set": { "visible_at": visible_at, "lease": new_id() },
+                    "$inc": { "attempts": 1 },
+                },
+                FindOneAndUpdateOptions::builder()
+                    .sort(doc! { "visible_at": 1 })
+                    .return_document(ReturnDocument::After)
+                    .build(),
+            )
+            .await
+            .map_err(crate::PluginError::Mongo)?;
+
+        Ok(task.map(Into::into))
+    }
+
+    async fn ack(&self, task: &QueueTask) -> anyhow::Result<bool> {
+        let result = self
+            .queue_collection()
+            .delete_one(doc! { "_id": &task.id, "lease": &task.lease }, None)
+            .await
+            .map_err(crate::PluginError::Mongo)?;
+
+        Ok(result.deleted_count == 1)
+    }
+
+    async fn nack(&self, task: &QueueTask) -> anyhow::Result<bool> {
+        let result = self
+            .queue_collection()
+            .update_one(
+                doc! { "_id": &task.id, "lease": &task.lease },
+                doc! { "$set": { "visible_at": mongodb::bson::DateTime::now() } },
+                None,
+            )
+            .await
+            .map_err(crate::PluginError::Mongo)?;
+
+        Ok(result.matched_count == 1)
+    }
 }
 
 #[async_trait]
 impl Synchronize for MongodbPlugin {
-    async fn gateway_service_handle(&mut self) {
+    async fn gateway_service_handle(&mut self, shutdown: Shutdown) {
         let mut s = self.clone();
+        let mut own = self.clone();
+        let deregistered = shutdown.guard(Phase::Deregister);
+
+        // the gateway's own registration.
+        tokio::spawn(async move {
+            let block = async {
+                loop {
+                    tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
+                    own.service_content_renewal().await;
+                }
+            };
+            tokio::select! {
+                _ = block => {},
+                _ = shutdown.reached(Phase::Deregister) => {
+                    own.service_unset().await;
+                    drop(deregistered);
+                },
+            }
+        });
 
         let block = async move {
             let option = ChangeStreamOptions::builder()
                 .full_document(Some(FullDocumentType::UpdateLookup))
                 .build();
 
-            let mut stream = s.group_collection().watch(None, option).await.unwrap();
+            let mut stream = match s.group_collection().watch(None, option).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    tracing::error!(watched = %s.collection, error = %e, "mongodb watch failed");
+                    s.events
+                        .registry_error(format!("watch {}: {}", s.collection, e));
+                    return;
+                }
+            };
 
-            while let Ok(Some(evt)) = stream
-                .try_next()
-                .await
-                .map_err(|e| log::error!("watch error :{:?}", e.to_string()))
-            {
+            while let Ok(Some(evt)) = stream.try_next().await.map_err(
+                |e| tracing::error!(watched = %s.collection, error = %e, "mongodb watch failed"),
+            ) {
                 let ChangeStreamEvent::<MongoContent> {
                     operation_type,
                     full_document,
@@ -313,32 +535,37 @@ impl Synchronize for MongodbPlugin {
                     ..
                 } = evt;
 
-                match operation_type {
-                    change_stream::event::OperationType::Insert
-                    | change_stream::event::OperationType::Update
-                    | change_stream::event::OperationType::Replace => {
-                        if let Some(c) = full_document {
-                            s.update_cache(c.content.service.clone(), &c).await;
+                let span = watch_span("mongodb", &s.collection, 1);
+                async {
+                    match operation_type {
+                        change_stream::event::OperationType::Insert
+                        | change_stream::event::OperationType::Update
+                        | change_stream::event::OperationType::Replace => {
+                            if let Some(c) = full_document {
+                                s.update_cache(c.content.service.clone(), &c).await;
+                            }
                         }
-                    }
-                    change_stream::event::OperationType::Delete => {
-                        if let Some(c) = document_key {
-                            if let Ok(key) = c.get_str("_id") {
-                                s.remove_cache(&key).await;
+                        change_stream::event::OperationType::Delete => {
+                            if let Some(c) = document_key {
+                                if let Ok(key) = c.get_str("_id") {
+                                    s.remove_cache(key).await;
+                                }
                             }
                         }
+                        _ => {}
                     }
-                    _ => {}
                 }
+                .instrument(span)
+                .await;
             }
         };
 
         tokio::spawn(block);
     }
 
-    async fn web_service_handle(&mut self, ctx: Context, wg: WaitGroup) {
+    async fn web_service_handle(&mut self, shutdown: Shutdown) {
         let mut s = self.clone();
-        let mut ctx = ctx;
+        let deregistered = shutdown.guard(Phase::Deregister);
 
         tokio::spawn(async move {
             let block = async {
@@ -349,17 +576,17 @@ impl Synchronize for MongodbPlugin {
             };
             tokio::select! {
                 _ = block => {},
-                _ = ctx.done() => {
+                _ = shutdown.reached(Phase::Deregister) => {
                     s.service_unset().await;
-                    drop(wg.clone());
+                    drop(deregistered);
                 },
             }
         });
     }
 
-    async fn backend_service_handle(&mut self, ctx: Context, wg: WaitGroup) {
+    async fn backend_service_handle(&mut self, shutdown: Shutdown) {
         let mongodb = self.clone();
-        let mut ctx = ctx;
+        let deregistered = shutdown.guard(Phase::Deregister);
         let mut _self = self.clone();
 
         let block = async move {
@@ -377,41 +604,78 @@ impl Synchronize for MongodbPlugin {
                     .full_document(Some(FullDocumentType::UpdateLookup))
                     .build();
 
-                let mut stream = s.group_collection().watch(None, option).await.unwrap();
+                let mut stream = match s.group_collection().watch(None, option).await {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        tracing::error!(watched = %s.collection, error = %e, "mongodb watch failed");
+                        s.events
+                            .registry_error(format!("watch {}: {}", s.collection, e));
+                        // renewals go on without the watch.
+                        return futures::future::pending().await;
+                    }
+                };
+
+                loop {
+                    let evt = match stream.try_next().await {
+                        Ok(Some(evt)) => evt,
+                        Ok(None) => break,
+                        Err(e) => {
+                            tracing::error!(watched = %s.collection, error = %e, "mongodb watch failed");
+                            s.events
+                                .registry_error(format!("watch {}: {}", s.collection, e));
+                            break;
+                        }
+                    };
 
-                while let Some(evt) = stream.try_next().await.unwrap() {
                     let ChangeStreamEvent::<MongoContent> {
                         operation_type,
                         full_document,
                         document_key,
+                        update_description,
                         ..
                     } = evt;
 
-                    match operation_type {
-                        change_stream::event::OperationType::Insert
-                        | change_stream::event::OperationType::Update
-                        | change_stream::event::OperationType::Replace => {
-                            if let Some(c) = full_document {
-                                s.update_cache(c.content.service.clone(), &c).await;
+                    // renewals only bump `time`, anything else is news to watchers.
+                    let changed = operation_type != change_stream::event::OperationType::Update
+                        || update_description
+                            .is_some_and(|d| d.updated_fields.keys().any(|k| k != "time"));
+
+                    let span = watch_span("mongodb", &s.collection, 1);
+                    async {
+                        match operation_type {
+                            change_stream::event::OperationType::Insert
+                            | change_stream::event::OperationType::Update
+                            | change_stream::event::OperationType::Replace => {
+                                if let Some(c) = full_document {
+                                    s.update_cache(c.content.service.clone(), &c).await;
+                                    if changed {
+                                        s.events.backend_change(Some(c.content.service));
+                                    }
+                                }
                             }
-                        }
-                        change_stream::event::OperationType::Delete => {
-                            if let Some(c) = document_key {
-                                if let Ok(key) = c.get_str("_id") {
-                                    s.remove_cache(&key).await;
+                            change_stream::event::OperationType::Delete => {
+                                if let Some(c) = document_key {
+                                    if let Ok(key) = c.get_str("_id") {
+                                        s.remove_cache(key).await;
+                                        // only the id is left, the service is unknown
+                                        s.events.backend_change(None);
+                                    }
                                 }
                             }
+                            _ => {}
                         }
-                        _ => {}
                     }
+                    .instrument(span)
+                    .await;
                 }
+                futures::future::pending::<()>().await;
             };
             tokio::select! {
                 _ = block0 => {},
                 _ = block1 => {},
-                _ = ctx.done() => {
+                _ = shutdown.reached(Phase::Deregister) => {
                     _self.service_unset().await;
-                    drop(wg.clone());
+                    drop(deregistered);
                 },
             }
         };
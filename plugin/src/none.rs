@@ -1,11 +1,18 @@
+use std::time::Duration;
+
 use crate::async_trait;
-use crossbeam::sync::WaitGroup;
-use tokio_context::context::Context;
+use crate::queue::MemoryQueue;
+use crate::QueueTask;
+use net::Shutdown;
 
-pub struct NonePlugin;
+pub struct NonePlugin {
+    queue: MemoryQueue,
+}
 impl NonePlugin {
     pub(super) async fn new() -> Self {
-        Self {}
+        Self {
+            queue: MemoryQueue::default(),
+        }
     }
 }
 
@@ -26,27 +33,60 @@ impl super::Plugin for NonePlugin {
     async fn get_backend_service(&self, _key: &str) -> anyhow::Result<(String, Vec<String>)> {
         Box::pin(async move { Ok((String::new(), vec![])) }).await
     }
+
+    async fn get_gateways(&self, _key: &str) -> anyhow::Result<Vec<super::ServiceContent>> {
+        Ok(vec![])
+    }
+
+    async fn list_backend_service(
+        &self,
+        _key: &str,
+    ) -> anyhow::Result<Vec<(String, super::ServiceContent)>> {
+        Ok(vec![])
+    }
+
+    async fn report_health(&self, _key: &str, _health: super::ServiceHealth) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn set_weight(&self, _key: &str, _weight: u32) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn set_metadata(
+        &self,
+        _key: &str,
+        _metadata: std::collections::HashMap<String, String>,
+    ) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn enqueue_at(
+        &self,
+        queue: &str,
+        payload: String,
+        visible_at: u64,
+    ) -> anyhow::Result<String> {
+        Ok(self.queue.enqueue_at(queue, payload, visible_at).await)
+    }
+
+    async fn claim(&self, queue: &str, visibility: Duration) -> anyhow::Result<Option<QueueTask>> {
+        Ok(self.queue.claim(queue, visibility).await)
+    }
+
+    async fn ack(&self, task: &QueueTask) -> anyhow::Result<bool> {
+        Ok(self.queue.ack(task).await)
+    }
+
+    async fn nack(&self, task: &QueueTask) -> anyhow::Result<bool> {
+        Ok(self.queue.nack(task).await)
+    }
 }
 
 #[async_trait]
 impl super::Synchronize for NonePlugin {
-    async fn gateway_service_handle(&mut self) {}
-    async fn backend_service_handle(&mut self, ctx: Context, wg: WaitGroup) {
-        let mut ctx = ctx;
-        tokio::spawn(async move {
-            tokio::select! {
-                _ = async move {
-                    // tokio sleep 10s
-                    loop {
-                        tokio::time::sleep(tokio::time::Duration::from_secs(10)).await;
-                    }
-                } =>{},
-                _ = ctx.done() => {
-                    drop(wg.clone());
-                    return;
-                }
-            }
-        });
-    }
-    async fn web_service_handle(&mut self, _ctx: Context, _wg: WaitGroup) {}
+    async fn gateway_service_handle(&mut self, _shutdown: Shutdown) {}
+    // nothing to renew or withdraw.
+    async fn backend_service_handle(&mut self, _shutdown: Shutdown) {}
+    async fn web_service_handle(&mut self, _shutdown: Shutdown) {}
 }
@@ -0,0 +1,163 @@
+use std::{
+    collections::BTreeMap,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use futures::lock::Mutex;
+
+/// A task claimed from a queue. It stays invisible to other consumers until
+/// `visible_at`, after which it is handed out again unless acked.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+pub struct QueueTask {
+    pub id: String,
+    pub queue: String,
+    pub payload: String,
+    // number of times the task was claimed, including this one.
+    pub attempts: u32,
+    // changes on every claim, ack/nack with a stale lease are ignored.
+    pub lease: String,
+    // unix millis
+    pub visible_at: u64,
+}
+
+static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+// time ordered, unique within the process and very likely across processes.
+pub(crate) fn new_id() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    format!(
+        "{:024}{:08x}{:06}",
+        nanos,
+        std::process::id(),
+        SEQUENCE.fetch_add(1, Ordering::Relaxed) % 1_000_000
+    )
+}
+
+// in-process queue backing the none plugin, handy for local runs and tests.
+#[derive(Default)]
+pub(crate) struct MemoryQueue {
+    tasks: Mutex<BTreeMap<(String, String), QueueTask>>,
+}
+
+impl MemoryQueue {
+    pub(crate) async fn enqueue_at(&self, queue: &str, payload: String, visible_at: u64) -> String {
+        let id = new_id();
+        let task = QueueTask {
+            id: id.clone(),
+            queue: queue.to_string(),
+            payload,
+            attempts: 0,
+            lease: "".into(),
+            visible_at,
+        };
+        self.tasks
+            .lock()
+            .await
+            .insert((queue.to_string(), id.clone()), task);
+        id
+    }
+
+    pub(crate) async fn claim(&self, queue: &str, visibility: Duration) -> Option<QueueTask> {
+        let now = now_millis();
+        let mut tasks = self.tasks.lock().await;
+        let task = tasks
+            .values_mut()
+            .filter(|task| task.queue == queue && task.visible_at <= now)
+            .min_by_key(|task| task.visible_at)?;
+
+        task.attempts += 1;
+        task.lease = new_id();
+        task.visible_at = now + visibility.as_millis() as u64;
+        Some(task.clone())
+    }
+
+    pub(crate) async fn ack(&self, task: &QueueTask) -> bool {
+        let mut tasks = self.tasks.lock().await;
+        let key = (task.queue.clone(), task.id.clone());
+        match tasks.get(&key) {
+            Some(current) if current.lease == task.lease => {
+                tasks.remove(&key);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub(crate) async fn nack(&self, task: &QueueTask) -> bool {
+        let mut tasks = self.tasks.lock().await;
+        match tasks.get_mut(&(task.queue.clone(), task.id.clone())) {
+            Some(current) if current.lease == task.lease => {
+                current.visible_at = now_millis();
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    #[test]
+    fn claim_hides_task_until_visibility_expires() {
+        block_on(async {
+            let q = MemoryQueue::default();
+            q.enqueue_at("mail", "a".into(), now_millis()).await;
+
+            let task = q.claim("mail", Duration::from_millis(20)).await.unwrap();
+            assert_eq!(task.attempts, 1);
+            assert!(q.claim("mail", Duration::from_millis(20)).await.is_none());
+            assert!(q.claim("other", Duration::from_millis(20)).await.is_none());
+
+            std::thread::sleep(Duration::from_millis(30));
+            let again = q.claim("mail", Duration::from_millis(20)).await.unwrap();
+            assert_eq!(again.attempts, 2);
+
+            // the first lease is stale now
+            assert!(!q.ack(&task).await);
+            assert!(q.ack(&again).await);
+            assert!(q.claim("mail", Duration::ZERO).await.is_none());
+        });
+    }
+
+    #[test]
+    fn nack_makes_task_visible_again() {
+        block_on(async {
+            let q = MemoryQueue::default();
+            q.enqueue_at("mail", "a".into(), now_millis()).await;
+
+            let task = q.claim("mail", Duration::from_secs(60)).await.unwrap();
+            assert!(q.nack(&task).await);
+            assert_eq!(
+                q.claim("mail", Duration::from_secs(60)).await.unwrap().id,
+                task.id
+            );
+        });
+    }
+
+    #[test]
+    fn delayed_task_is_hidden_until_due() {
+        block_on(async {
+            let q = MemoryQueue::default();
+            q.enqueue_at("mail", "later".into(), now_millis() + 20)
+                .await;
+            assert!(q.claim("mail", Duration::ZERO).await.is_none());
+
+            std::thread::sleep(Duration::from_millis(30));
+            assert!(q.claim("mail", Duration::ZERO).await.is_some());
+        });
+    }
+}
@@ -0,0 +1,266 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use hyper::{Body, Method, Request};
+use net::{Phase, Shutdown};
+use serde_json::Value;
+
+use crate::handle::Events;
+use crate::{async_trait, PluginError};
+
+// renewals start this far into a lease, and are tried again this often when
+// one fails.
+const RENEW_AT: f64 = 2.0 / 3.0;
+const RETRY: Duration = Duration::from_secs(5);
+
+/// How long registry credentials are valid, when they come with a lease.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lease {
+    pub id: String,
+    pub duration: Duration,
+    pub renewable: bool,
+}
+
+/// Registry credentials fetched from a secrets provider: the username and
+/// password, or the acl token as the password for consul.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Credentials {
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub lease: Option<Lease>,
+}
+
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .field("lease", &self.lease)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Where a plugin gets its registry credentials from instead of plain
+/// config, before it connects; leased credentials are renewed until the
+/// process exits.
+#[async_trait]
+pub trait SecretsProvider: Send + Sync + std::fmt::Debug {
+    async fn fetch(&self) -> anyhow::Result<Credentials>;
+
+    // extends `lease`, its duration from now on.
+    async fn renew(&self, _lease: &Lease) -> anyhow::Result<Duration> {
+        Err(PluginError::Unsupported("lease renewal").into())
+    }
+}
+
+/// Reads the credentials from a HashiCorp Vault secret at `path`, e.g. a kv
+/// secret at `secret/data/crossgate/registry` or dynamic database
+/// credentials at `database/creds/registry`, and renews their lease.
+#[derive(Clone)]
+pub struct VaultProvider {
+    addr: String,
+    token: String,
+    path: String,
+    username_key: String,
+    password_key: String,
+}
+
+impl std::fmt::Debug for VaultProvider {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("VaultProvider")
+            .field("addr", &self.addr)
+            .field("path", &self.path)
+            .finish_non_exhaustive()
+    }
+}
+
+impl VaultProvider {
+    // `addr` is the url of vault, e.g. https://vault:8200.
+    pub fn new(addr: &str, token: &str, path: &str) -> Self {
+        Self {
+            addr: addr.trim_end_matches('/').to_string(),
+            token: token.to_string(),
+            path: path.trim_matches('/').to_string(),
+            username_key: "username".to_string(),
+            password_key: "password".to_string(),
+        }
+    }
+
+    /// The fields of the secret the username and password are in.
+    pub fn keys(mut self, username: &str, password: &str) -> Self {
+        self.username_key = username.to_string();
+        self.password_key = password.to_string();
+        self
+    }
+
+    // env VAULT_ADDR, VAULT_TOKEN and REGISTER_VAULT_PATH, None without a
+    // path or token.
+    pub fn from_env() -> Option<Self> {
+        let env = |key: &str| std::env::var(key).ok().filter(|v| !v.is_empty());
+        let addr = env("VAULT_ADDR").unwrap_or_else(|| "http://127.0.0.1:8200".to_string());
+        Some(Self::new(
+            &addr,
+            &env("VAULT_TOKEN")?,
+            &env("REGISTER_VAULT_PATH")?,
+        ))
+    }
+
+    async fn call(&self, method: Method, path: &str, body: Option<Value>) -> anyhow::Result<Value> {
+        let req = Request::builder()
+            .method(method)
+            .uri(format!("{}/v1/{}", self.addr, path))
+            .header("x-vault-token", &self.token)
+            .body(body.map_or_else(Body::empty, |b| Body::from(b.to_string())))?;
+        let res = match self.addr.starts_with("https://") {
+            true => {
+                net::get_tls_proxy_client(None)
+                    .client()
+                    .request(req)
+                    .await?
+            }
+            false => net::get_proxy_client().client().request(req).await?,
+        };
+        let status = res.status();
+        let body = hyper::body::to_bytes(res.into_body()).await?;
+        if !status.is_success() {
+            return Err(PluginError::Http(format!("vault {} answered {}", path, status)).into());
+        }
+        Ok(serde_json::from_slice(&body)?)
+    }
+}
+
+// the credentials in a vault answer, kv version 2 keeps them one level
+// deeper.
+fn credentials(answer: &Value, username_key: &str, password_key: &str) -> Credentials {
+    let data = &answer["data"];
+    let fields = match data.get("metadata").is_some() && data["data"].is_object() {
+        true => &data["data"],
+        false => data,
+    };
+    let field = |key: &str| fields.get(key).and_then(Value::as_str).map(str::to_string);
+    let lease = match (
+        answer["lease_id"].as_str(),
+        answer["lease_duration"].as_u64(),
+    ) {
+        (Some(id), Some(secs)) if !id.is_empty() && secs > 0 => Some(Lease {
+            id: id.to_string(),
+            duration: Duration::from_secs(secs),
+            renewable: answer["renewable"].as_bool().unwrap_or(false),
+        }),
+        _ => None,
+    };
+    Credentials {
+        username: field(username_key),
+        password: field(password_key),
+        lease,
+    }
+}
+
+#[async_trait]
+impl SecretsProvider for VaultProvider {
+    async fn fetch(&self) -> anyhow::Result<Credentials> {
+        let answer = self.call(Method::GET, &self.path, None).await?;
+        let credentials = credentials(&answer, &self.username_key, &self.password_key);
+        if credentials.username.is_none() && credentials.password.is_none() {
+            anyhow::bail!(
+                "vault secret {} has neither {} nor {}",
+                self.path,
+                self.username_key,
+                self.password_key
+            );
+        }
+        Ok(credentials)
+    }
+
+    async fn renew(&self, lease: &Lease) -> anyhow::Result<Duration> {
+        let body = serde_json::json!({
+            "lease_id": lease.id,
+            "increment": lease.duration.as_secs(),
+        });
+        let answer = self
+            .call(Method::PUT, "sys/leases/renew", Some(body))
+            .await?;
+        Ok(Duration::from_secs(
+            answer["lease_duration"].as_u64().unwrap_or_default(),
+        ))
+    }
+}
+
+// renews `lease` before it runs out until the process exits, or until vault
+// will not extend it any longer; failures are published on `events`.
+pub(crate) fn keep_renewed(
+    provider: Arc<dyn SecretsProvider>,
+    lease: Lease,
+    events: Events,
+    shutdown: Shutdown,
+) {
+    if !lease.renewable {
+        return;
+    }
+    // every renewal asks for as long as the first lease.
+    let requested = lease.duration;
+    tokio::spawn(async move {
+        let mut wait = lease.duration.mul_f64(RENEW_AT);
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(wait) => {}
+                _ = shutdown.reached(Phase::Exit) => return,
+            }
+            match provider.renew(&lease).await {
+                Ok(duration) if duration.is_zero() => return,
+                Ok(duration) => {
+                    if duration < requested {
+                        tracing::warn!(
+                            expires_in = ?duration,
+                            "registry credentials cannot be renewed much longer"
+                        );
+                    }
+                    wait = duration.mul_f64(RENEW_AT);
+                }
+                Err(e) => {
+                    tracing::error!(error = %e, "renew registry credentials failed");
+                    events.registry_error(format!("renew registry credentials: {}", e));
+                    wait = RETRY;
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kv_and_dynamic_secrets() {
+        let kv = serde_json::json!({
+            "lease_id": "",
+            "lease_duration": 0,
+            "data": {
+                "data": { "user": "registry", "pass": "s3cret" },
+                "metadata": { "version": 3 },
+            },
+        });
+        let found = credentials(&kv, "user", "pass");
+        assert_eq!(found.username.as_deref(), Some("registry"));
+        assert_eq!(found.password.as_deref(), Some("s3cret"));
+        assert!(found.lease.is_none());
+        assert!(!format!("{:?}", found).contains("s3cret"));
+
+        let dynamic = serde_json::json!({
+            "lease_id": "database/creds/registry/abc",
+            "lease_duration": 3600,
+            "renewable": true,
+            "data": { "username": "v-token-registry", "password": "p" },
+        });
+        let found = credentials(&dynamic, "username", "password");
+        assert_eq!(found.username.as_deref(), Some("v-token-registry"));
+        assert_eq!(
+            found.lease,
+            Some(Lease {
+                id: "database/creds/registry/abc".to_string(),
+                duration: Duration::from_secs(3600),
+                renewable: true,
+            })
+        );
+    }
+}
@@ -0,0 +1,119 @@
+//! The api gateway as a program: `crossgate --config gateway.toml` serves
+//! what the file declares, see `crossgate_rs::config::Config` for its format
+//! and `crossgate_rs::gateway::MIDDLEWARE` for the middleware it can name.
+
+use std::process::ExitCode;
+
+use crossgate_rs::gateway;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+
+const DEFAULT_CONFIG: &str = "crossgate.toml";
+
+const USAGE: &str = "usage: crossgate [-c|--config <path>] [--check]
+       crossgate config check [<path>]
+
+  -c, --config <path>  toml or yaml config, default $CROSSGATE_CONFIG or crossgate.toml
+      --check          validate the config and the files it names, and exit;
+                       as does config check
+  -h, --help           print this
+  -V, --version        print the version
+
+RUST_LOG sets the log filter, info by default.";
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    Run(String),
+    Check(String),
+    Help,
+    Version,
+}
+
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Command, String> {
+    let mut config = std::env::var("CROSSGATE_CONFIG")
+        .ok()
+        .filter(|v| !v.is_empty())
+        .unwrap_or_else(|| DEFAULT_CONFIG.to_string());
+    let mut check = false;
+
+    let mut args = args.into_iter().peekable();
+    // `config check` takes the path without -c too.
+    let subcommand = args.peek().is_some_and(|arg| arg == "config");
+    if subcommand {
+        args.next();
+        match args.next().as_deref() {
+            Some("check") => check = true,
+            Some(other) => return Err(format!("unknown config command `{}`", other)),
+            None => return Err("config needs a command, check".to_string()),
+        }
+    }
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-c" | "--config" => {
+                config = args.next().ok_or_else(|| format!("{} needs a path", arg))?
+            }
+            "--check" => check = true,
+            "-h" | "--help" => return Ok(Command::Help),
+            "-V" | "--version" => return Ok(Command::Version),
+            _ => match arg.strip_prefix("--config=") {
+                Some(path) => config = path.to_string(),
+                None if subcommand && !arg.starts_with('-') => config = arg,
+                None => return Err(format!("unknown argument `{}`", arg)),
+            },
+        }
+    }
+
+    match check {
+        true => Ok(Command::Check(config)),
+        false => Ok(Command::Run(config)),
+    }
+}
+
+async fn run(path: &str) -> crossgate_rs::Result<()> {
+    let config = gateway::load(path)?;
+
+    let subscriber = tracing_subscriber::registry()
+        .with(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
+        .with(tracing_subscriber::fmt::layer());
+    #[cfg(feature = "otel")]
+    let subscriber = subscriber.with(crossgate_rs::micro::telemetry::layer(&config.telemetry)?);
+    #[cfg(feature = "otel")]
+    let _telemetry = crossgate_rs::micro::telemetry::TelemetryGuard::default();
+    subscriber.init();
+
+    tracing::info!(config = path, "crossgate starting");
+    gateway::run(config).await
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let command = match parse_args(std::env::args().skip(1)) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("crossgate: {}\n\n{}", e, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+
+    let res = match command {
+        Command::Help => {
+            println!("{}", USAGE);
+            return ExitCode::SUCCESS;
+        }
+        Command::Version => {
+            println!("crossgate {}", env!("CARGO_PKG_VERSION"));
+            return ExitCode::SUCCESS;
+        }
+        Command::Check(path) => gateway::load(&path)
+            .map(|_| println!("{}: ok", path))
+            .map_err(Into::into),
+        Command::Run(path) => run(&path).await,
+    };
+
+    match res {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("crossgate: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
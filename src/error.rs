@@ -0,0 +1,77 @@
+use micro::config::ConfigError;
+use micro::{RegisterError, ServiceError, TransportError};
+use net::udp::UdpError;
+use net::{ConnectionError, FrameError, NetError, ProxyError};
+use plugin::PluginError;
+
+/// Any error of the crossgate crates, with the crate level error as its
+/// source. Matching on it tells where something failed, `{:#}` or the source
+/// chain tells why.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Net(#[from] NetError),
+    #[error(transparent)]
+    Connection(#[from] ConnectionError),
+    #[error(transparent)]
+    Frame(#[from] FrameError),
+    #[error(transparent)]
+    Udp(#[from] UdpError),
+    #[error(transparent)]
+    Proxy(#[from] ProxyError),
+    #[error(transparent)]
+    Plugin(#[from] PluginError),
+    #[error(transparent)]
+    Register(#[from] RegisterError),
+    #[error(transparent)]
+    Service(#[from] ServiceError),
+    #[error(transparent)]
+    Transport(#[from] TransportError),
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+    #[cfg(feature = "otel")]
+    #[error(transparent)]
+    Telemetry(#[from] micro::telemetry::TelemetryError),
+    // errors of the `anyhow::Result` apis, see `Error::from_anyhow`.
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+impl Error {
+    // the crate level error behind an `anyhow::Error`, when there is one.
+    pub fn from_anyhow(err: anyhow::Error) -> Self {
+        let err = match err.downcast::<PluginError>() {
+            Ok(e) => return Error::Plugin(e),
+            Err(err) => err,
+        };
+        let err = match err.downcast::<RegisterError>() {
+            Ok(e) => return Error::Register(e),
+            Err(err) => err,
+        };
+        let err = match err.downcast::<ProxyError>() {
+            Ok(e) => return Error::Proxy(e),
+            Err(err) => err,
+        };
+        match err.downcast::<NetError>() {
+            Ok(e) => Error::Net(e),
+            Err(err) => Error::Other(err),
+        }
+    }
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_anyhow_finds_the_crate_error() {
+        let err = anyhow::Error::from(PluginError::NotInitialized);
+        assert!(matches!(Error::from_anyhow(err), Error::Plugin(_)));
+
+        let err = Error::from_anyhow(anyhow::anyhow!("boom"));
+        assert_eq!(err.to_string(), "boom");
+        assert!(matches!(err, Error::Other(_)));
+    }
+}
@@ -0,0 +1,397 @@
+use std::path::Path;
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use micro::audit::{self, AuditEvent, AuditKind};
+use micro::auth::Credential;
+use micro::config::{self, Config, ConfigError, ConfigIssue};
+use micro::{Intercepter, IntercepterType};
+
+use crate::Result;
+
+pub const HEALTH_PATH: &str = "/healthz";
+pub const CAPTURES_PATH: &str = "/debug/captures";
+pub const COLORS_PATH: &str = "/admin/colors";
+pub const REVOCATIONS_PATH: &str = "/admin/revocations";
+
+/// An `Intercepter` answering `HEALTH_PATH` with 200 while the gateway runs,
+/// for the probes of load balancers and orchestrators.
+pub fn health<'a>(
+    req: &'a mut Request<Body>,
+    res: &'a mut Response<Body>,
+) -> BoxFuture<'a, IntercepterType> {
+    Box::pin(async move {
+        if req.uri().path() != HEALTH_PATH {
+            return IntercepterType::Next;
+        }
+        *res = Response::new(Body::from("ok"));
+        IntercepterType::Interrupt
+    })
+}
+
+/// An `Intercepter` answering `CAPTURES_PATH` with the requests
+/// `gateway.capture` selected, as json; see `micro::captures`. Only for
+/// those signing in with `gateway.admin.basic_auth`, the bodies may hold
+/// personal data.
+pub fn captures<'a>(
+    req: &'a mut Request<Body>,
+    res: &'a mut Response<Body>,
+) -> BoxFuture<'a, IntercepterType> {
+    Box::pin(async move {
+        if req.uri().path() != CAPTURES_PATH {
+            return IntercepterType::Next;
+        }
+        *res = match micro::auth::check_admin(req) {
+            Ok(()) => json(StatusCode::OK, &micro::captures()),
+            Err(e) => e.response(),
+        };
+        IntercepterType::Interrupt
+    })
+}
+
+fn json(status: StatusCode, value: &impl serde::Serialize) -> Response<Body> {
+    match serde_json::to_vec(value) {
+        Ok(json) => Response::builder()
+            .status(status)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(json))
+            .unwrap(),
+        Err(e) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Body::from(e.to_string()))
+            .unwrap(),
+    }
+}
+
+fn query<'a>(req: &'a Request<Body>, key: &str) -> Option<&'a str> {
+    req.uri()
+        .query()?
+        .split('&')
+        .map(|kv| kv.split_once('=').unwrap_or((kv, "")))
+        .find(|(k, _)| *k == key)
+        .map(|(_, v)| v)
+}
+
+fn admin_change(req: &Request<Body>, action: &str) -> AuditEvent {
+    AuditEvent::new(AuditKind::AdminChange, action).request(req)
+}
+
+fn colors_response(req: &Request<Body>) -> Response<Body> {
+    if req.method() == Method::GET {
+        return json(StatusCode::OK, &micro::active_colors());
+    }
+
+    let service = match query(req, "service") {
+        Some(service) if !service.is_empty() => service,
+        _ => return json(StatusCode::BAD_REQUEST, &"service is required"),
+    };
+    match (req.method(), query(req, "color"), query(req, "rollback")) {
+        (&Method::POST, Some(color), None) if !color.is_empty() => {
+            let switch = micro::set_active_color(service, color);
+            audit::record(
+                admin_change(req, "set_color")
+                    .detail("service", service)
+                    .detail("color", color),
+            );
+            json(StatusCode::OK, &switch)
+        }
+        (&Method::POST, None, Some(_)) => match micro::rollback_color(service) {
+            Some(switch) => {
+                audit::record(
+                    admin_change(req, "rollback_color")
+                        .detail("service", service)
+                        .detail("color", &switch.active),
+                );
+                json(StatusCode::OK, &switch)
+            }
+            None => json(StatusCode::CONFLICT, &"no color to roll back to"),
+        },
+        (&Method::DELETE, None, None) => {
+            micro::clear_active_color(service);
+            audit::record(admin_change(req, "clear_color").detail("service", service));
+            json(StatusCode::OK, &micro::active_colors())
+        }
+        _ => json(
+            StatusCode::BAD_REQUEST,
+            &"POST ?service=&color= or ?service=&rollback, DELETE ?service=",
+        ),
+    }
+}
+
+/// An `Intercepter` switching services between their blue and green
+/// instances at `COLORS_PATH`: GET lists the active colors, POST
+/// `?service=/t/ums&color=green` switches, POST `?service=/t/ums&rollback`
+/// switches back and DELETE `?service=/t/ums` routes to all instances
+/// again. Only for those signing in with `gateway.admin.basic_auth`.
+pub fn colors<'a>(
+    req: &'a mut Request<Body>,
+    res: &'a mut Response<Body>,
+) -> BoxFuture<'a, IntercepterType> {
+    Box::pin(async move {
+        if req.uri().path() != COLORS_PATH {
+            return IntercepterType::Next;
+        }
+        *res = match micro::auth::check_admin(req) {
+            Ok(()) => colors_response(req),
+            Err(e) => e.response(),
+        };
+        IntercepterType::Interrupt
+    })
+}
+
+/// An `Intercepter` refusing requests whose bearer token or api key was
+/// revoked and, with `gateway.auth.oidc`, those without a valid token; see
+/// `micro::auth`.
+pub fn auth<'a>(
+    req: &'a mut Request<Body>,
+    res: &'a mut Response<Body>,
+) -> BoxFuture<'a, IntercepterType> {
+    Box::pin(async move {
+        match micro::auth::check(req).await {
+            Ok(()) => IntercepterType::Next,
+            Err(e) => {
+                *res = e.response();
+                IntercepterType::Interrupt
+            }
+        }
+    })
+}
+
+/// An `Intercepter` refusing with 422 the request bodies that do not match
+/// the `policy.schema` of their route; see `micro::schema`.
+pub fn schema<'a>(
+    req: &'a mut Request<Body>,
+    res: &'a mut Response<Body>,
+) -> BoxFuture<'a, IntercepterType> {
+    Box::pin(async move {
+        match micro::schema::check(req).await {
+            Ok(()) => IntercepterType::Next,
+            Err(e) => {
+                *res = e.response();
+                IntercepterType::Interrupt
+            }
+        }
+    })
+}
+
+/// An `Intercepter` refusing with 401 the requests to routes with
+/// `policy.hmac` not signed by a client of `gateway.auth.hmac`; see
+/// `micro::auth::sign`.
+pub fn hmac<'a>(
+    req: &'a mut Request<Body>,
+    res: &'a mut Response<Body>,
+) -> BoxFuture<'a, IntercepterType> {
+    Box::pin(async move {
+        match micro::auth::check_signature(req).await {
+            Ok(()) => IntercepterType::Next,
+            Err(e) => {
+                *res = e.response();
+                IntercepterType::Interrupt
+            }
+        }
+    })
+}
+
+#[derive(serde::Deserialize)]
+struct Revocation {
+    token: Option<String>,
+    api_key: Option<String>,
+    ttl_secs: Option<u64>,
+}
+
+async fn revocations_response(req: &mut Request<Body>) -> Response<Body> {
+    if req.method() != Method::POST {
+        return json(StatusCode::METHOD_NOT_ALLOWED, &"POST a token or api_key");
+    }
+    let body = match hyper::body::to_bytes(std::mem::take(req.body_mut())).await {
+        Ok(body) => body,
+        Err(e) => return json(StatusCode::BAD_REQUEST, &e.to_string()),
+    };
+    let revocation = match serde_json::from_slice::<Revocation>(&body) {
+        Ok(revocation) => revocation,
+        Err(e) => return json(StatusCode::BAD_REQUEST, &e.to_string()),
+    };
+    let credential = match (revocation.token, revocation.api_key) {
+        (Some(token), None) => Credential::Bearer(token),
+        (None, Some(key)) => Credential::ApiKey(key),
+        _ => {
+            return json(
+                StatusCode::BAD_REQUEST,
+                &"one of token or api_key is required",
+            )
+        }
+    };
+    let ttl = revocation.ttl_secs.map(Duration::from_secs);
+    match micro::auth::revoke(&credential, ttl).await {
+        Ok(()) => {
+            let mut event = admin_change(req, "revoke").detail("credential", credential.id());
+            if let Some(ttl) = ttl {
+                event = event.detail("ttl_secs", ttl.as_secs());
+            }
+            audit::record(event);
+            json(StatusCode::OK, &credential.id())
+        }
+        Err(e) => json(StatusCode::SERVICE_UNAVAILABLE, &e.to_string()),
+    }
+}
+
+/// An `Intercepter` revoking credentials at `REVOCATIONS_PATH` for the auth
+/// middleware, e.g. from the logout of a service: POST
+/// `{"token": "..."}` or `{"api_key": "..."}`, with `ttl_secs` for how long.
+/// Only for those signing in with `gateway.admin.basic_auth`.
+pub fn revocations<'a>(
+    req: &'a mut Request<Body>,
+    res: &'a mut Response<Body>,
+) -> BoxFuture<'a, IntercepterType> {
+    Box::pin(async move {
+        if req.uri().path() != REVOCATIONS_PATH {
+            return IntercepterType::Next;
+        }
+        *res = match micro::auth::check_admin(req) {
+            Ok(()) => revocations_response(req).await,
+            Err(e) => e.response(),
+        };
+        IntercepterType::Interrupt
+    })
+}
+
+/// What `gateway.middleware` can name.
+pub const MIDDLEWARE: &[(&str, Intercepter)] = &[
+    ("health", health),
+    ("metrics", crate::metrics::intercept),
+    ("captures", captures),
+    ("colors", colors),
+    ("auth", auth),
+    ("revocations", revocations),
+    ("schema", schema),
+    ("hmac", hmac),
+];
+
+fn intercepter(name: &str) -> Option<Intercepter> {
+    MIDDLEWARE
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, intercepter)| *intercepter)
+}
+
+fn check(name: &str, config: &Config) -> Result<(), ConfigError> {
+    let known = MIDDLEWARE
+        .iter()
+        .map(|(n, _)| *n)
+        .collect::<Vec<_>>()
+        .join(", ");
+    let issues = config
+        .gateway
+        .middleware
+        .iter()
+        .enumerate()
+        .filter(|(_, m)| intercepter(m).is_none())
+        .map(|(i, m)| ConfigIssue {
+            line: None,
+            field: format!("gateway.middleware[{}]", i),
+            message: format!("`{}` is not one of {}", m, known),
+        })
+        .collect::<Vec<_>>();
+
+    if issues.is_empty() {
+        return Ok(());
+    }
+    Err(ConfigError::Invalid {
+        path: name.to_string(),
+        issues,
+    })
+}
+
+/// Reads and checks the config at `path` as `crossgate config check` does,
+/// every issue with the line it is on: `Config::load`, which validates the
+/// routes, the registry address and the tls files, plus the checks only the
+/// gateway needs, e.g. that every middleware exists.
+pub fn load(path: impl AsRef<Path>) -> Result<Config, ConfigError> {
+    let config = Config::load(&path)?;
+    check(&path.as_ref().display().to_string(), &config)?;
+    Ok(config)
+}
+
+/// Runs the gateway `config` declares until ctrl-c: its listen addresses,
+/// routes, registry, tls and middleware. What the `crossgate` binary runs.
+pub async fn run(config: Config) -> Result<()> {
+    check("gateway config", &config)?;
+    // used for the life of the process.
+    let intercepters: &'static [Intercepter] = config
+        .gateway
+        .middleware
+        .iter()
+        .filter_map(|m| intercepter(m))
+        .collect::<Vec<_>>()
+        .leak();
+
+    config::init(config);
+    micro::run_api_server_from_config(intercepters, None).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use micro::config::Format;
+
+    #[tokio::test]
+    async fn middleware_by_name() {
+        let source = "[gateway]\nmiddleware = [\"health\", \"sessions\"]\n";
+        let config = Config::parse("a.toml", source, Format::Toml).unwrap();
+        match check("a.toml", &config) {
+            Err(ConfigError::Invalid { issues, .. }) => {
+                assert_eq!(issues.len(), 1);
+                assert_eq!(issues[0].field, "gateway.middleware[1]");
+            }
+            other => panic!("{:?}", other),
+        }
+
+        let health = intercepter("health").unwrap();
+        let mut req = Request::get(HEALTH_PATH).body(Body::empty()).unwrap();
+        let mut res = Response::new(Body::empty());
+        assert!(matches!(
+            health(&mut req, &mut res).await,
+            IntercepterType::Interrupt
+        ));
+        let mut req = Request::get("/t/ums").body(Body::empty()).unwrap();
+        assert!(matches!(
+            health(&mut req, &mut res).await,
+            IntercepterType::Next
+        ));
+    }
+
+    #[tokio::test]
+    async fn admin_endpoints_need_credentials() {
+        let mut req = Request::get(CAPTURES_PATH).body(Body::empty()).unwrap();
+        let mut res = Response::new(Body::empty());
+        assert!(matches!(
+            captures(&mut req, &mut res).await,
+            IntercepterType::Interrupt
+        ));
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let mut req = Request::post(REVOCATIONS_PATH)
+            .body(Body::from(r#"{"token": "abc"}"#))
+            .unwrap();
+        let mut res = Response::new(Body::empty());
+        assert!(matches!(
+            revocations(&mut req, &mut res).await,
+            IntercepterType::Interrupt
+        ));
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let mut req = Request::post(format!("{}?service=/t/ums&color=green", COLORS_PATH))
+            .body(Body::empty())
+            .unwrap();
+        let mut res = Response::new(Body::empty());
+        assert!(matches!(
+            colors(&mut req, &mut res).await,
+            IntercepterType::Interrupt
+        ));
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        assert!(!micro::active_colors().contains_key("/t/ums"));
+    }
+}
@@ -1,6 +1,9 @@
-// #![feature(generic_associated_types)]
-#![feature(type_alias_impl_trait)]
-
 pub use micro;
+pub use micro::config;
 pub use net;
 pub use plugin;
+
+mod error;
+pub mod gateway;
+pub mod metrics;
+pub use error::{Error, Result};
@@ -0,0 +1,112 @@
+use std::{convert::Infallible, net::SocketAddr};
+
+use futures::future::BoxFuture;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use micro::{IntercepterType, MetricsRegistry, ServiceError};
+use prometheus::{Encoder, TextEncoder};
+
+pub const METRICS_PATH: &str = "/metrics";
+
+/// The process-wide registry plugin, micro and net publish into, e.g. the
+/// registry backend calls, the gateway requests, the upstream latencies and
+/// the executor metrics. Register the metrics of the application here to have
+/// them served alongside.
+pub fn registry() -> &'static prometheus::Registry {
+    // micro's own registry joins on first use.
+    MetricsRegistry::global();
+    prometheus::default_registry()
+}
+
+// everything in `registry()` in the prometheus text format.
+pub fn render() -> Result<String, prometheus::Error> {
+    let mut buf = vec![];
+    TextEncoder::new().encode(&registry().gather(), &mut buf)?;
+    Ok(String::from_utf8_lossy(&buf).into_owned())
+}
+
+fn response() -> Response<Body> {
+    match render() {
+        Ok(text) => Response::builder()
+            .header(hyper::header::CONTENT_TYPE, prometheus::TEXT_FORMAT)
+            .body(Body::from(text))
+            .unwrap(),
+        Err(e) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Body::from(e.to_string()))
+            .unwrap(),
+    }
+}
+
+/// An `Intercepter` answering `METRICS_PATH` on the gateway itself, put it
+/// first in the intercepters passed to `run_api_server`.
+pub fn intercept<'a>(
+    req: &'a mut Request<Body>,
+    res: &'a mut Response<Body>,
+) -> BoxFuture<'a, IntercepterType> {
+    Box::pin(async move {
+        if req.uri().path() != METRICS_PATH {
+            return IntercepterType::Next;
+        }
+        *res = response();
+        IntercepterType::Interrupt
+    })
+}
+
+/// Serves `METRICS_PATH` on a port of its own, e.g. for a backend service
+/// without an http server.
+pub async fn serve(addr: SocketAddr) -> Result<(), ServiceError> {
+    let server = Server::try_bind(&addr).map_err(|source| ServiceError::Bind {
+        addr: addr.to_string(),
+        source,
+    })?;
+
+    let make_svc = make_service_fn(|_| async {
+        Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+            Ok::<_, Infallible>(match req.uri().path() {
+                METRICS_PATH => response(),
+                _ => Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .body(Body::empty())
+                    .unwrap(),
+            })
+        }))
+    });
+
+    server
+        .serve(make_svc)
+        .await
+        .map_err(|source| ServiceError::Serve {
+            addr: addr.to_string(),
+            source,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn serves_every_crate_in_one_registry() {
+        MetricsRegistry::global()
+            .counter("crossgate_test_runs_total", &[("kind", "a")])
+            .add(2);
+        MetricsRegistry::global()
+            .gauge("crossgate_test_members", &[])
+            .set(3);
+
+        let text = render().unwrap();
+        assert!(text.contains("# TYPE crossgate_test_runs_total counter"));
+        assert!(text.contains("crossgate_test_runs_total{kind=\"a\"} 2"));
+        assert!(text.contains("crossgate_test_members 3"));
+
+        let mut req = Request::get("/metrics").body(Body::empty()).unwrap();
+        let mut res = Response::new(Body::empty());
+        assert!(matches!(
+            intercept(&mut req, &mut res).await,
+            IntercepterType::Interrupt
+        ));
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("crossgate_test_members"));
+    }
+}